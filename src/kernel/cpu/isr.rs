@@ -19,6 +19,8 @@ extern "C" {
 
     fn initiateAPIC();
     fn syscallHandler(cpu: *mut AsmPassedInterrupt);
+    fn signalsFault(cpu: *mut AsmPassedInterrupt, signal: usize);
+    fn signalsPendingHandleIrq(cpu: *mut AsmPassedInterrupt);
 
     static asm_isr_redirect_table: [u64; 256];
     fn isr255();
@@ -41,6 +43,12 @@ const ANSI_CYAN: &str = "\x1b[36m";
 const ANSI_RESET: &str = "\x1b[0m";
 
 const SCHED_PAGE_FAULT_MAGIC_ADDRESS: u64 = 0xDEADBEEF;
+
+const SIGILL: usize = 4;
+const SIGTRAP: usize = 5;
+const SIGBUS: usize = 7;
+const SIGFPE: usize = 8;
+const SIGSEGV: usize = 11;
 const KERNEL_TASK_ID: i32 = 0;
 
 // ======================================================
@@ -128,6 +136,18 @@ static EXCEPTIONS: [&[u8]; 32] = [
 // Utilities
 // ======================================================
 
+// What a userspace process gets for an exception, 0 if it's fatal anyway
+fn exception_signal(int_no: u64) -> usize {
+    match int_no {
+        0 | 16 | 19 => SIGFPE,
+        1 | 3 => SIGTRAP,
+        6 | 7 => SIGILL,
+        17 => SIGBUS,
+        4 | 5 | 10 | 11 | 12 | 13 | 14 => SIGSEGV,
+        _ => 0,
+    }
+}
+
 unsafe fn register_dump(regs: *mut AsmPassedInterrupt) {
     let r = &*regs;
     debugf(
@@ -212,6 +232,12 @@ pub unsafe extern "C" fn handle_interrupt(rsp: u64) {
         outportb(0x20, 0x20);
         apicWrite(0xB0, 0);
 
+        // coming from userspace, the task's own context: that's where core
+        // dumps happen, the scheduler can't write them
+        if (*cpu).cs & 3 == 3 && tasksInitiated && !currentTask.is_null() {
+            signalsPendingHandleIrq(cpu);
+        }
+
         let mut node = dsIrqHandler.firstObject;
         while !node.is_null() {
             let h = node as *mut IrqHandler;
@@ -233,7 +259,13 @@ pub unsafe extern "C" fn handle_interrupt(rsp: u64) {
         return;
     }
 
-    // Exceptions
+    // Exceptions in userspace are the process' problem, not ours
+    let signal = exception_signal(int_no);
+    if (*cpu).cs & 3 == 3 && signal != 0 && tasksInitiated && !currentTask.is_null() {
+        signalsFault(cpu, signal);
+        return;
+    }
+
     register_dump(cpu);
     debugf(FORMAT.as_ptr(), EXCEPTIONS[int_no as usize].as_ptr());
     panic();
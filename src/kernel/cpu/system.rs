@@ -197,8 +197,11 @@ pub fn panic() -> ! {
     unsafe {
         debugf(b"[kernel] Kernel panic triggered!\n\0".as_ptr());
         asm!("cli");
+        // no interrupts from here on, the keyboard gets polled so the
+        // scrollback can still be looked through
         loop {
-            asm!("hlt");
+            crate::kb::kb_panic_poll();
+            core::hint::spin_loop();
         }
     }
 }
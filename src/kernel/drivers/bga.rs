@@ -0,0 +1,407 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::ptr::{addr_of, read_volatile, write_volatile};
+
+use crate::drm::{drmRegisterBackend, DrmBackend};
+
+//
+// ===== Externs =====
+//
+
+extern "C" {
+    fn debugf(fmt: *const u8, ...) -> i32;
+
+    fn inportw(port: u16) -> u16;
+    fn outportw(port: u16, val: u16);
+
+    fn malloc(size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    fn GetGeneralDevice(dev: *const PCIdevice, out: *mut PCIgeneralDevice);
+    fn VirtualMap(virt: usize, phys: usize, flags: u64);
+
+    static bootloader: BootloaderInfo;
+    static mut fb: Framebuffer;
+}
+
+//
+// ===== Structs =====
+//
+
+#[repr(C)]
+pub struct BootloaderInfo {
+    pub hhdmOffset: usize,
+}
+
+#[repr(C)]
+pub struct PCIdevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub command: u16,
+    pub status: u16,
+}
+
+#[repr(C)]
+pub struct PCIgeneralDevice {
+    pub bar: [u32; 6],
+    pub cardBusCISPtr: u32,
+    pub system_id: u16,
+    pub system_vendor_id: u16,
+    pub expROMaddr: u32,
+    pub capabilitiesPtr: u8,
+    pub interruptLine: u8,
+    pub interruptPIN: u8,
+    pub minGrant: u8,
+    pub maxLatency: u8,
+}
+
+#[repr(C)]
+pub struct Framebuffer {
+    pub virt: *mut u8,
+    pub phys: usize,
+    pub width: usize,
+    pub height: usize,
+    pub pitch: usize,
+
+    pub red_shift: u32,
+    pub red_size: u32,
+    pub green_shift: u32,
+    pub green_size: u32,
+    pub blue_shift: u32,
+    pub blue_size: u32,
+
+    pub bpp: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BgaMode {
+    pub width: u32,
+    pub height: u32,
+}
+
+#[repr(C)]
+pub struct Bga {
+    pub exists: bool,
+    pub vramPhys: usize,
+    pub vram: *mut u8,
+    pub vramSize: usize,
+    pub maxWidth: u32,
+    pub maxHeight: u32,
+
+    // lines the device scans out of, page 0 being the visible mode
+    pub virtHeight: u32,
+    pub yOffset: u32,
+
+    // the dispi registers behind BAR2 on QEMU, null means port I/O
+    pub mmio: *mut u16,
+
+    pub modes: [BgaMode; BGA_MAX_MODES],
+    pub modeCount: usize,
+}
+
+//
+// ===== Constants =====
+//
+
+// Bochs VBE "dispi" interface, as found on QEMU -vga std, Bochs and VirtualBox
+const VBE_DISPI_IOPORT_INDEX: u16 = 0x01CE;
+const VBE_DISPI_IOPORT_DATA: u16 = 0x01CF;
+
+// QEMU's std-VGA mirrors the registers in its MMIO BAR, 16 bits apart
+const BGA_MMIO_DISPI: usize = 0x500;
+
+const VBE_DISPI_INDEX_ID: u16 = 0x0;
+const VBE_DISPI_INDEX_XRES: u16 = 0x1;
+const VBE_DISPI_INDEX_YRES: u16 = 0x2;
+const VBE_DISPI_INDEX_BPP: u16 = 0x3;
+const VBE_DISPI_INDEX_ENABLE: u16 = 0x4;
+const VBE_DISPI_INDEX_VIRT_WIDTH: u16 = 0x6;
+const VBE_DISPI_INDEX_VIRT_HEIGHT: u16 = 0x7;
+const VBE_DISPI_INDEX_X_OFFSET: u16 = 0x8;
+const VBE_DISPI_INDEX_Y_OFFSET: u16 = 0x9;
+const VBE_DISPI_INDEX_VIDEO_MEMORY_64K: u16 = 0xA;
+
+const VBE_DISPI_ID0: u16 = 0xB0C0;
+const VBE_DISPI_ID4: u16 = 0xB0C4;
+const VBE_DISPI_ID5: u16 = 0xB0C5;
+
+const VBE_DISPI_DISABLED: u16 = 0x00;
+const VBE_DISPI_ENABLED: u16 = 0x01;
+const VBE_DISPI_GETCAPS: u16 = 0x02;
+const VBE_DISPI_LFB_ENABLED: u16 = 0x40;
+
+// VRAM size when the device is too old to tell
+const BGA_DEFAULT_VRAM: usize = 4 * 1024 * 1024;
+
+pub const BGA_MAX_MODES: usize = 24;
+
+// the usual VESA/CVT sizes, whatever fits the device gets offered
+const BGA_STANDARD_MODES: [(u32, u32); 17] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1152, 864),
+    (1280, 720),
+    (1280, 768),
+    (1280, 800),
+    (1280, 1024),
+    (1366, 768),
+    (1440, 900),
+    (1600, 900),
+    (1600, 1200),
+    (1680, 1050),
+    (1920, 1080),
+    (1920, 1200),
+    (2560, 1440),
+    (2560, 1600),
+];
+
+const BLOCK_SIZE: usize = 4096;
+
+const PF_RW: u64 = 1 << 1;
+const PF_CACHE_DISABLE: u64 = 1 << 4;
+const PF_PWT: u64 = 1 << 3;
+const PF_PAT: u64 = 1 << 7;
+const PF_CACHE_WC: u64 = PF_PAT | PF_PWT;
+
+#[no_mangle]
+pub static mut BGA: Bga = Bga {
+    exists: false,
+    vramPhys: 0,
+    vram: core::ptr::null_mut(),
+    vramSize: 0,
+    maxWidth: 0,
+    maxHeight: 0,
+    virtHeight: 0,
+    yOffset: 0,
+    mmio: core::ptr::null_mut(),
+    modes: [BgaMode { width: 0, height: 0 }; BGA_MAX_MODES],
+    modeCount: 0,
+};
+
+static mut BGA_DRM: DrmBackend = DrmBackend {
+    name: b"Bochs/QEMU standard VGA\0".as_ptr(),
+    maxWidth: 0,
+    maxHeight: 0,
+    vramSize: 0,
+    setMode: bga_drm_set_mode,
+    pan: Some(bga_drm_pan),
+    update: None,
+};
+
+//
+// ===== Registers =====
+//
+
+unsafe fn bga_read(index: u16) -> u16 {
+    if !BGA.mmio.is_null() {
+        return read_volatile(BGA.mmio.add(index as usize));
+    }
+    outportw(VBE_DISPI_IOPORT_INDEX, index);
+    inportw(VBE_DISPI_IOPORT_DATA)
+}
+
+unsafe fn bga_write(index: u16, value: u16) {
+    if !BGA.mmio.is_null() {
+        write_volatile(BGA.mmio.add(index as usize), value);
+        return;
+    }
+    outportw(VBE_DISPI_IOPORT_INDEX, index);
+    outportw(VBE_DISPI_IOPORT_DATA, value);
+}
+
+pub fn bga_detect(device: &PCIdevice) -> bool {
+    // QEMU/Bochs std-VGA and VirtualBox's VGA
+    (device.vendor_id == 0x1234 && device.device_id == 0x1111)
+        || (device.vendor_id == 0x80EE && device.device_id == 0xBEEF)
+}
+
+//
+// ===== Mode setting =====
+//
+
+fn bga_mode_fits(width: u32, height: u32) -> bool {
+    unsafe {
+        width != 0
+            && height != 0
+            && width <= BGA.maxWidth
+            && height <= BGA.maxHeight
+            && width as usize * height as usize * 4 <= BGA.vramSize
+    }
+}
+
+unsafe fn bga_modes_build(bootWidth: u32, bootHeight: u32) {
+    BGA.modeCount = 0;
+    if bga_mode_fits(bootWidth, bootHeight) {
+        BGA.modes[0] = BgaMode { width: bootWidth, height: bootHeight };
+        BGA.modeCount = 1;
+    }
+
+    for &(width, height) in BGA_STANDARD_MODES.iter() {
+        if BGA.modeCount >= BGA_MAX_MODES {
+            break;
+        }
+        if !bga_mode_fits(width, height) || bgaModeSupported(width, height) {
+            continue;
+        }
+        BGA.modes[BGA.modeCount] = BgaMode { width, height };
+        BGA.modeCount += 1;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bgaModeSupported(width: u32, height: u32) -> bool {
+    BGA.modes[..BGA.modeCount]
+        .iter()
+        .any(|mode| mode.width == width && mode.height == height)
+}
+
+// 32bpp, with the virtual height covering as much of VRAM as the registers
+// take so panning never needs another mode set
+#[no_mangle]
+pub unsafe extern "C" fn bgaSetMode(width: u32, height: u32) -> bool {
+    if !BGA.exists || !bgaModeSupported(width, height) {
+        return false;
+    }
+
+    let pitch = width as usize * 4;
+    let virtHeight = core::cmp::min(BGA.vramSize / pitch, 0xFFFF) as u16;
+
+    bga_write(VBE_DISPI_INDEX_ENABLE, VBE_DISPI_DISABLED);
+    bga_write(VBE_DISPI_INDEX_XRES, width as u16);
+    bga_write(VBE_DISPI_INDEX_YRES, height as u16);
+    bga_write(VBE_DISPI_INDEX_BPP, 32);
+    bga_write(VBE_DISPI_INDEX_ENABLE, VBE_DISPI_ENABLED | VBE_DISPI_LFB_ENABLED);
+    bga_write(VBE_DISPI_INDEX_VIRT_WIDTH, width as u16);
+    bga_write(VBE_DISPI_INDEX_VIRT_HEIGHT, virtHeight);
+    bga_write(VBE_DISPI_INDEX_X_OFFSET, 0);
+    bga_write(VBE_DISPI_INDEX_Y_OFFSET, 0);
+
+    if bga_read(VBE_DISPI_INDEX_XRES) != width as u16
+        || bga_read(VBE_DISPI_INDEX_YRES) != height as u16
+    {
+        return false;
+    }
+
+    // the device clamps what doesn't fit
+    BGA.virtHeight = bga_read(VBE_DISPI_INDEX_VIRT_HEIGHT) as u32;
+    BGA.yOffset = 0;
+
+    fb.virt = BGA.vram;
+    fb.phys = BGA.vramPhys;
+    fb.width = width as usize;
+    fb.height = height as usize;
+    fb.pitch = pitch;
+    fb.bpp = 32;
+    fb.red_shift = 16;
+    fb.red_size = 8;
+    fb.green_shift = 8;
+    fb.green_size = 8;
+    fb.blue_shift = 0;
+    fb.blue_size = 8;
+    true
+}
+
+// Scans out from `line` on, within the virtual height
+#[no_mangle]
+pub unsafe extern "C" fn bgaPan(line: u32) -> bool {
+    if !BGA.exists || line + fb.height as u32 > BGA.virtHeight {
+        return false;
+    }
+    bga_write(VBE_DISPI_INDEX_Y_OFFSET, line as u16);
+    BGA.yOffset = line;
+    true
+}
+
+unsafe fn bga_drm_set_mode(width: u32, height: u32) -> bool {
+    bgaSetMode(width, height)
+}
+
+unsafe fn bga_drm_pan(line: u32) -> bool {
+    bgaPan(line)
+}
+
+//
+// ===== Initialization =====
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn initiateBGA(device: *const PCIdevice) {
+    if !bga_detect(&*device) || BGA.exists {
+        return;
+    }
+
+    let id = bga_read(VBE_DISPI_INDEX_ID);
+    if !(VBE_DISPI_ID0..=VBE_DISPI_ID5).contains(&id) {
+        return;
+    }
+
+    debugf(b"[pci::bga] Bochs VBE graphics adapter detected!\n\0".as_ptr());
+
+    let details = malloc(core::mem::size_of::<PCIgeneralDevice>()) as *mut PCIgeneralDevice;
+    GetGeneralDevice(device, details);
+    BGA.vramPhys = ((*details).bar[0] & !0xf) as usize;
+    let mmio = (*details).bar[2];
+    free(details as *mut u8);
+
+    // VirtualBox only has the ports, QEMU's std-VGA also has a memory BAR
+    if (*device).vendor_id == 0x1234 && mmio != 0 && mmio & 1 == 0 {
+        let phys = (mmio & !0xf) as usize;
+        VirtualMap(bootloader.hhdmOffset + phys, phys, PF_RW | PF_CACHE_DISABLE);
+        BGA.mmio = (bootloader.hhdmOffset + phys + BGA_MMIO_DISPI) as *mut u16;
+    }
+
+    BGA.vramSize = if id >= VBE_DISPI_ID4 {
+        bga_read(VBE_DISPI_INDEX_VIDEO_MEMORY_64K) as usize * 64 * 1024
+    } else {
+        BGA_DEFAULT_VRAM
+    };
+
+    // with GETCAPS set, the resolution registers read back the limits
+    let enable = bga_read(VBE_DISPI_INDEX_ENABLE);
+    bga_write(VBE_DISPI_INDEX_ENABLE, enable | VBE_DISPI_GETCAPS);
+    BGA.maxWidth = bga_read(VBE_DISPI_INDEX_XRES) as u32;
+    BGA.maxHeight = bga_read(VBE_DISPI_INDEX_YRES) as u32;
+    bga_write(VBE_DISPI_INDEX_ENABLE, enable);
+
+    BGA.vram = (bootloader.hhdmOffset + BGA.vramPhys) as *mut u8;
+    let mut page = 0;
+    while page < BGA.vramSize {
+        VirtualMap(
+            bootloader.hhdmOffset + BGA.vramPhys + page,
+            BGA.vramPhys + page,
+            PF_RW | PF_CACHE_WC,
+        );
+        page += BLOCK_SIZE;
+    }
+    BGA.exists = true;
+
+    // the mode Limine left us in carries on, now through our mapping
+    let boot = fb.phys == BGA.vramPhys && fb.bpp == 32;
+    bga_modes_build(
+        if boot { fb.width as u32 } else { 0 },
+        if boot { fb.height as u32 } else { 0 },
+    );
+    if boot {
+        bgaSetMode(fb.width as u32, fb.height as u32);
+    }
+
+    debugf(
+        b"[pci::bga] %s, %ldKB VRAM, up to %dx%d, %ld modes\n\0".as_ptr(),
+        if BGA.mmio.is_null() { b"ports\0".as_ptr() } else { b"mmio\0".as_ptr() },
+        BGA.vramSize / 1024,
+        BGA.maxWidth,
+        BGA.maxHeight,
+        BGA.modeCount,
+    );
+
+    BGA_DRM.maxWidth = BGA.maxWidth;
+    BGA_DRM.maxHeight = BGA.maxHeight;
+    BGA_DRM.vramSize = BGA.vramSize;
+    drmRegisterBackend(addr_of!(BGA_DRM));
+}
@@ -7,6 +7,7 @@ use core::mem::size_of;
 use crate::ahci::{ahci, ahci_read, ahci_write, AHCI_BYTES_PER_PRDT, AHCI_PRDTS};
 use crate::pci::{PCI, PCI_DRIVER_AHCI};
 use crate::system::{LinkedListSearch, IS_ALIGNED};
+use crate::usb_storage::usb_storage_bytes;
 use crate::malloc::{malloc, free};
 
 pub const SECTOR_SIZE: usize = 512;
@@ -75,7 +76,11 @@ pub fn disk_bytes(target_address: *mut u8, lba: u32, sector_count: usize, write:
     unsafe {
         let browse = LinkedListSearch(&crate::system::DS_PCI, Some(disk_bytes_cb), null_mut());
         if browse.is_null() {
-            // zero memory if no AHCI disk found
+            // no AHCI disk, maybe there's a USB one
+            if usb_storage_bytes(target_address, lba, sector_count, write) {
+                return;
+            }
+            // zero memory if no disk found
             core::ptr::write_bytes(target_address, 0, sector_count * SECTOR_SIZE);
             return;
         }
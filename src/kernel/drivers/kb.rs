@@ -1,8 +1,11 @@
 #![no_std]
 #![no_main]
 
-use core::ptr::{read_volatile, write_volatile};
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::cmp::min;
+use core::ptr::copy_nonoverlapping;
+
+use crate::keymap::*;
+use crate::vt::VT_COUNT;
 
 /// ---------------------------
 /// PORT I/O
@@ -20,45 +23,175 @@ unsafe fn inportb(port: u16) -> u8 {
 /// ---------------------------
 /// Constants & Scancodes
 /// ---------------------------
-const SCANCODE_ENTER: u8 = 0x1C;
-const SCANCODE_BACK: u8 = 0x0E;
 const SCANCODE_SHIFT: u8 = 0x2A;
-const SCANCODE_CAPS: u8 = 0x3A;
+const SCANCODE_RSHIFT: u8 = 0x36;
+const SCANCODE_HOME: u8 = 0x47;
+const SCANCODE_PGUP: u8 = 0x49;
+const SCANCODE_END: u8 = 0x4F;
+const SCANCODE_PGDN: u8 = 0x51;
+
+const SCANCODE_E0: u8 = 0xE0;
+const SCANCODE_E1: u8 = 0xE1;
+const SCANCODE_ACK: u8 = 0xFA;
+const SCANCODE_RESEND: u8 = 0xFE;
+const SCANCODE_OVERRUN: u8 = 0xFF;
+
+const KB_CMD_LEDS: u8 = 0xED;
 
 const CHARACTER_ENTER: char = '\n';
 const CHARACTER_BACK: char = '\x08';
 
-static CHARACTER_TABLE: [u8; 128] = [
-    0, 27, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0',
-    b'-', b'=', 0, 9, b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i',
-    b'o', b'p', b'[', b']', 0, 0, b'a', b's', b'd', b'f', b'g', b'h',
-    b'j', b'k', b'l', b';', b'\'', b'`', 0, b'\\', b'z', b'x', b'c',
-    b'v', b'b', b'n', b'm', b',', b'.', b'/', 0, b'*', 0, b' ', 0, 0,
-    0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x1B,0,0,0,0,0,0,0,0,0,0,0x0E,
-    0x1C,0,0,0,0,0,0,0,'/',0,0,0,0,0,0,0,0,0,0,0,0,0,0x1E,0x1F,0x20,0x21,
-    0x22,0x23,0x24,0x25,0x26,0x27,0x28,0,0,0,0,0,0,0,0x2C
-];
-
-static SHIFTED_CHARACTER_TABLE: [u8; 128] = [
-    0,27,b'!',b'@',b'#',b'$',b'%',b'^',b'&',b'*',b'(',b')',
-    b'_',b'+',0,9,b'Q',b'W',b'E',b'R',b'T',b'Y',b'U',b'I',
-    b'O',b'P',b'{',b'}',0,0,b'A',b'S',b'D',b'F',b'G',b'H',
-    b'J',b'K',b'L',b':',b'"',b'~',0,b'|',b'Z',b'X',b'C',b'V',
-    b'B',b'N',b'M',b'<',b'>',b'?',0,b'*',0,b' ',0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0x1B,0,0,0,0,0,0,0,0,0,0,0x0E,0x1C,0,0,0,0,0,0,0, b'?',0,0,0,0,0,0,0,0,0,0,0,0,0x1E,0x1F,0x20,0x21,0x22,0x23,0x24,0x25,0x26,0x27,0x28,0,0,0,0,0,0,0,0x2C
-];
+// evdev keycodes past the ones set 1 scancodes map to directly
+const KEY_SYSRQ: u8 = 99;
+const KEY_PAUSE: u8 = 119;
+
+// E0 xx, by xx
+static E0_KEYCODES: [u8; 128] = {
+    let mut map = [0u8; 128];
+    map[0x10] = 165; // previous song
+    map[0x19] = 163; // next song
+    map[0x1C] = 96; // keypad enter
+    map[0x1D] = 97; // right ctrl
+    map[0x20] = 113; // mute
+    map[0x21] = 140; // calculator
+    map[0x22] = 164; // play/pause
+    map[0x24] = 166; // stop
+    map[0x2E] = 114; // volume down
+    map[0x30] = 115; // volume up
+    map[0x32] = 172; // www home
+    map[0x35] = 98; // keypad /
+    map[0x37] = KEY_SYSRQ; // print screen
+    map[0x38] = 100; // right alt
+    map[0x46] = KEY_PAUSE; // ctrl+break
+    map[0x47] = 102; // home
+    map[0x48] = 103; // up
+    map[0x49] = 104; // page up
+    map[0x4B] = 105; // left
+    map[0x4D] = 106; // right
+    map[0x4F] = 107; // end
+    map[0x50] = 108; // down
+    map[0x51] = 109; // page down
+    map[0x52] = 110; // insert
+    map[0x53] = 111; // delete
+    map[0x5B] = 125; // left meta
+    map[0x5C] = 126; // right meta
+    map[0x5D] = 127; // menu
+    map[0x5E] = 116; // power
+    map[0x5F] = 142; // sleep
+    map[0x63] = 143; // wake
+    map
+};
+
+// Keyboard modes (KDSKBMODE), per virtual terminal
+pub const K_RAW: i32 = 0;
+pub const K_XLATE: i32 = 1;
+pub const K_MEDIUMRAW: i32 = 2;
+pub const K_UNICODE: i32 = 3;
+pub const K_OFF: i32 = 4;
+
+// KDSKBMETA
+pub const K_METABIT: i32 = 3;
+pub const K_ESCPREFIX: i32 = 4;
+
+// Lock flags (KDGKBLED) and LEDs (KDGETLED), both in the bit order the
+// keyboard takes them in
+const K_SCROLLLOCK: u8 = 0x01;
+const K_NUMLOCK: u8 = 0x02;
+const K_CAPSLOCK: u8 = 0x04;
+
+pub const KDGETLED: u64 = 0x4B31;
+pub const KDSETLED: u64 = 0x4B32;
+pub const KDGKBTYPE: u64 = 0x4B33;
+pub const KDGKBLED: u64 = 0x4B64;
+pub const KDSKBLED: u64 = 0x4B65;
+
+const KB_101: u8 = 0x02;
+
+const EFAULT: isize = 14;
+const EINVAL: isize = 22;
+const ENOTTY: isize = 25;
+
+#[inline]
+const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+// evdev
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_MSC: u16 = 0x04;
+const EV_LED: u16 = 0x11;
+
+const SYN_REPORT: u16 = 0;
+const MSC_SCAN: u16 = 0x04;
+
+const LED_NUML: u16 = 0x00;
+const LED_CAPSL: u16 = 0x01;
+const LED_SCROLLL: u16 = 0x02;
+
+/// ---------------------------
+/// Kernel structs
+/// ---------------------------
+#[repr(C)]
+pub struct InputId {
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+#[repr(C)]
+pub struct DevInputEvent {
+    pub inputid: InputId,
+    pub eventBit: extern "C" fn(*mut OpenFile, u64, *mut u8) -> usize,
+}
+
+#[repr(C)]
+pub struct OpenFile;
 
 /// ---------------------------
 /// Keyboard State
 /// ---------------------------
-static mut SHIFTED: bool = false;
-static mut CAPSLOCKED: bool = false;
+
+// what's left of an E0 / E1 sequence
+static mut PREFIX: u8 = 0;
+static mut E1_BYTES: [u8; 2] = [0; 2];
+static mut E1_COUNT: usize = 0;
+
+// keys held, per KT_SHIFT value
+static mut SHIFT_DOWN: [u8; NR_SHIFT as usize] = [0; NR_SHIFT as usize];
+static mut SHIFT_STATE: u32 = 0;
+// AltGr lock and friends (KT_LOCK), sticky modifiers (KT_SLOCK)
+static mut LOCK_STATE: u32 = 0;
+static mut SLOCK_STATE: u32 = 0;
+// K_CAPSLOCK | K_NUMLOCK | K_SCROLLLOCK
+static mut LOCKS: u8 = 0;
+
+// what the LEDs show, and what KDSETLED forced them to (or -1)
+static mut LEDS: u8 = 0;
+static mut LEDS_FORCED: i16 = -1;
+// LED byte waiting for the keyboard to acknowledge KB_CMD_LEDS
+static mut LEDS_PENDING: i16 = -1;
+
+// pending dead key or compose character
+static mut DIACR: u32 = 0;
+static mut COMPOSE_NEXT: bool = false;
+
+// Alt+keypad number, -1 when none is being typed
+static mut NPADCH: i32 = -1;
+
 static mut KB_BUFF: *mut u8 = core::ptr::null_mut();
 static mut KB_CURR: usize = 0;
 static mut KB_MAX: usize = 0;
 static mut KB_TASK_ID: u32 = 0;
 
+static mut KB_EVENT: *mut DevInputEvent = core::ptr::null_mut();
+
+// Shift, for the panic path
+static mut PANIC_SHIFTED: bool = false;
+
 /// Bitmap for key pressed/released tracking
-const EVDEV_INTERNAL_SIZE: usize = 16;
+const EVDEV_INTERNAL_SIZE: usize = 32;
 static mut EVDEV_INTERNAL: [u8; EVDEV_INTERNAL_SIZE] = [0; EVDEV_INTERNAL_SIZE];
 
 /// ---------------------------
@@ -92,43 +225,487 @@ unsafe fn kb_write(port: u16, value: u8) {
 }
 
 /// ---------------------------
-/// Handle key press/release
+/// External kernel APIs
 /// ---------------------------
-pub unsafe fn handle_kb_event() -> u8 {
-    let scan_code = kb_read();
+extern "C" {
+    static vtActive: usize;
+    static vtLast: usize;
 
-    // Shift key
-    if scan_code == SCANCODE_SHIFT && (scan_code & 0x80) == 0 {
-        SHIFTED = true;
-        return 0;
-    } else if scan_code == SCANCODE_SHIFT && (scan_code & 0x80) != 0 {
-        SHIFTED = false;
-        return 0;
+    fn vtInput(c: u8);
+    fn vtActivate(target: usize) -> usize;
+    fn vtCursorKey(key: u8);
+    fn vtScrollback(pages: i32);
+    fn vtScrollbackPanic(pages: i32);
+    fn vtKeyboardMode() -> i32;
+    fn vtKeyboardMeta() -> i32;
+
+    fn ioApicRedirect(irq: u8, level: bool) -> u8;
+    fn registerIRQhandler(irq: u8, handler: extern "C" fn());
+
+    fn devInputEventSetup(name: *const u8) -> *mut DevInputEvent;
+    fn inputGenerateEvent(dev: *mut DevInputEvent, etype: u16, code: u16, value: i32);
+}
+
+/// ---------------------------
+/// Scancodes to keycodes
+/// ---------------------------
+
+/// Set 1 bytes in, (keycode, pressed, scancode) out once a key is complete
+unsafe fn decode_scancode(scan_code: u8) -> Option<(u8, bool, u32)> {
+    match PREFIX {
+        SCANCODE_E1 => {
+            // Pause is E1 1D 45 on press and E1 9D C5 on release, nothing
+            // else uses E1
+            E1_BYTES[E1_COUNT] = scan_code;
+            E1_COUNT += 1;
+            if E1_COUNT < 2 {
+                return None;
+            }
+            PREFIX = 0;
+            let [first, second] = E1_BYTES;
+            if first & 0x7f != 0x1D || second & 0x7f != 0x45 {
+                return None;
+            }
+            let scan = 0xE1_0000 | (first as u32) << 8 | second as u32;
+            Some((KEY_PAUSE, second & 0x80 == 0, scan))
+        }
+        SCANCODE_E0 => {
+            PREFIX = 0;
+            let code = scan_code & 0x7f;
+            // the fake shifts sent around the grey keys with Num Lock on
+            if code == SCANCODE_SHIFT || code == SCANCODE_RSHIFT {
+                return None;
+            }
+            let keycode = E0_KEYCODES[code as usize];
+            if keycode == 0 {
+                return None;
+            }
+            Some((keycode, scan_code & 0x80 == 0, 0xE000 | code as u32))
+        }
+        _ => {
+            match scan_code {
+                SCANCODE_E0 | SCANCODE_E1 => {
+                    PREFIX = scan_code;
+                    E1_COUNT = 0;
+                    return None;
+                }
+                0 | SCANCODE_OVERRUN | SCANCODE_RESEND => return None,
+                _ => {}
+            }
+            let code = scan_code & 0x7f;
+            let keycode = match code {
+                0x54 => KEY_SYSRQ, // Alt+Print Screen
+                0x01..=0x53 | 0x56..=0x58 => code,
+                _ => return None,
+            };
+            Some((keycode, scan_code & 0x80 == 0, code as u32))
+        }
+    }
+}
+
+/// ---------------------------
+/// LEDs
+/// ---------------------------
+unsafe fn leds_update() {
+    let leds = if LEDS_FORCED >= 0 { LEDS_FORCED as u8 } else { LOCKS };
+    if leds == LEDS {
+        return;
+    }
+
+    let changed = leds ^ LEDS;
+    LEDS = leds;
+    for (bit, code) in [(K_NUMLOCK, LED_NUML), (K_CAPSLOCK, LED_CAPSL), (K_SCROLLLOCK, LED_SCROLLL)] {
+        if changed & bit != 0 {
+            inputGenerateEvent(KB_EVENT, EV_LED, code, (leds & bit != 0) as i32);
+        }
+    }
+    inputGenerateEvent(KB_EVENT, EV_SYN, SYN_REPORT, 0);
+
+    // the mask goes out once the keyboard acknowledges the command, see
+    // handle_kb_event()
+    LEDS_PENDING = leds as i16;
+    kb_write(0x60, KB_CMD_LEDS);
+}
+
+unsafe fn lock_toggle(lock: u8) {
+    LOCKS ^= lock;
+    leds_update();
+}
+
+/// ---------------------------
+/// Output
+/// ---------------------------
+unsafe fn put_byte(c: u8) {
+    // the kernel shell reads directly, everyone else goes through the tty
+    // of the terminal on screen
+    if !KB_BUFF.is_null() {
+        kb_write_char(match c {
+            b'\r' => CHARACTER_ENTER as u8,
+            127 => CHARACTER_BACK as u8,
+            _ => c,
+        });
+        return;
     }
+    vtInput(c);
+}
 
-    if (scan_code as usize) < CHARACTER_TABLE.len() && (scan_code & 0x80) == 0 {
-        let character = if SHIFTED || CAPSLOCKED {
-            SHIFTED_CHARACTER_TABLE[scan_code as usize]
-        } else {
-            CHARACTER_TABLE[scan_code as usize]
-        };
+unsafe fn put_bytes(bytes: &[u8]) {
+    for &c in bytes {
+        put_byte(c);
+    }
+}
 
-        if character != 0 {
-            return character;
+/// A character, UTF-8 encoded in K_UNICODE and Latin-1 otherwise
+unsafe fn put_unicode(codepoint: u32) {
+    if vtKeyboardMode() != K_UNICODE || !KB_BUFF.is_null() {
+        if codepoint < 0x100 {
+            put_byte(codepoint as u8);
         }
+        return;
+    }
+
+    let mut buff = [0u8; 4];
+    if let Some(c) = char::from_u32(codepoint) {
+        put_bytes(c.encode_utf8(&mut buff).as_bytes());
+    }
+}
+
+/// Combines the pending dead key with `c`, if the two make anything
+unsafe fn handle_diacr(c: u32) -> u32 {
+    let diacr = DIACR;
+    DIACR = 0;
+
+    if let Some(result) = keymap_diacr(diacr, c) {
+        return result;
+    }
+    // Space or the same accent again gives the accent itself
+    if c == b' ' as u32 || c == diacr {
+        return diacr;
+    }
+    put_unicode(diacr);
+    c
+}
+
+unsafe fn put_character(mut c: u32) {
+    if DIACR != 0 {
+        c = handle_diacr(c);
+    }
+    if COMPOSE_NEXT {
+        COMPOSE_NEXT = false;
+        DIACR = c;
+        return;
+    }
+    put_unicode(c);
+}
+
+unsafe fn dead_key(c: u32) {
+    DIACR = if DIACR != 0 { handle_diacr(c) } else { c };
+}
+
+unsafe fn put_meta(c: u8) {
+    if vtKeyboardMeta() == K_METABIT {
+        put_byte(c | 0x80);
+    } else {
+        put_byte(0x1B);
+        put_byte(c);
+    }
+}
 
-        match scan_code {
-            SCANCODE_ENTER => return CHARACTER_ENTER as u8,
-            SCANCODE_BACK => return CHARACTER_BACK as u8,
-            SCANCODE_CAPS => {
-                CAPSLOCKED = !CAPSLOCKED;
-                return 0;
+/// Cursor keys are escape sequences, which depend on the terminal's mode
+unsafe fn put_cursor(value: u8) {
+    let key = match value {
+        K_DOWN => b'B',
+        K_LEFT => b'D',
+        K_RIGHT => b'C',
+        K_UP => b'A',
+        K_HOME => b'H',
+        K_END => b'F',
+        _ => return,
+    };
+    if KB_BUFF.is_null() {
+        vtCursorKey(key);
+    }
+}
+
+unsafe fn put_func(value: u8) {
+    put_bytes(keymap_func(value));
+}
+
+/// ---------------------------
+/// Keysym handlers
+/// ---------------------------
+unsafe fn key_spec(value: u8, repeat: bool) {
+    match value {
+        K_ENTER => {
+            if DIACR != 0 {
+                put_unicode(DIACR);
+                DIACR = 0;
             }
+            put_byte(b'\r');
+        }
+        K_LASTCONS => {
+            vtActivate(vtLast);
+        }
+        K_CAPS if !repeat => lock_toggle(K_CAPSLOCK),
+        K_CAPSON if !repeat => {
+            LOCKS |= K_CAPSLOCK;
+            leds_update();
+        }
+        K_NUM | K_BARENUMLOCK if !repeat => lock_toggle(K_NUMLOCK),
+        // there's no flow control to stop, just the LED
+        K_HOLD if !repeat => lock_toggle(K_SCROLLLOCK),
+        K_SCROLLBACK => vtScrollback(1),
+        K_SCROLLFORW => vtScrollback(-1),
+        K_SCROLLTOP => vtScrollback(i32::MAX),
+        K_SCROLLBOTTOM => vtScrollback(i32::MIN),
+        K_COMPOSE => COMPOSE_NEXT = true,
+        K_DECRCONSOLE => {
+            vtActivate((vtActive + VT_COUNT - 1) % VT_COUNT);
+        }
+        K_INCRCONSOLE => {
+            vtActivate((vtActive + 1) % VT_COUNT);
+        }
+        _ => {}
+    }
+}
+
+unsafe fn key_pad(value: u8) {
+    const PAD_CHARS: &[u8] = b"0123456789+-*/\r,.?()";
+
+    // without Num Lock (or with Shift) the digits move the cursor
+    let numbers = (LOCKS & K_NUMLOCK != 0) != (SHIFT_STATE & (1 << KG_SHIFT) != 0);
+    if !numbers {
+        match value {
+            0 => return put_func(K_INSERT),
+            1 => return put_cursor(K_END),
+            2 => return put_cursor(K_DOWN),
+            3 => return put_func(K_PGDN),
+            4 => return put_cursor(K_LEFT),
+            5 => return put_bytes(b"\x1b[G"),
+            6 => return put_cursor(K_RIGHT),
+            7 => return put_cursor(K_HOME),
+            8 => return put_cursor(K_UP),
+            9 => return put_func(K_PGUP),
+            K_PDOT => return put_func(K_REMOVE),
             _ => {}
         }
     }
 
-    0
+    match value {
+        K_PPLUSMINUS => put_unicode(0xb1),
+        _ => {
+            if let Some(&c) = PAD_CHARS.get(value as usize) {
+                put_byte(c);
+            }
+        }
+    }
+}
+
+unsafe fn key_ascii(value: u8) {
+    let (base, digit) = if value < 10 { (10, value as i32) } else { (16, value as i32 - 10) };
+    NPADCH = if NPADCH < 0 { digit } else { NPADCH * base + digit };
+}
+
+unsafe fn key_shift(value: u8, down: bool, repeat: bool) {
+    let value = if value == KG_CAPSSHIFT { KG_SHIFT } else { value };
+    let slot = &mut SHIFT_DOWN[value as usize];
+    if down {
+        if !repeat {
+            *slot = slot.saturating_add(1);
+        }
+    } else if *slot > 0 {
+        *slot -= 1;
+    }
+
+    if *slot > 0 {
+        SHIFT_STATE |= 1 << value;
+    } else {
+        SHIFT_STATE &= !(1 << value);
+    }
+
+    // letting go of Alt finishes an Alt+keypad number
+    if !down && NPADCH >= 0 && SHIFT_STATE & ((1 << KG_ALT) | (1 << KG_ALTGR)) == 0 {
+        put_unicode((NPADCH & 0xffff) as u32);
+        NPADCH = -1;
+    }
+}
+
+/// Runs a key through the keymap of the terminal on screen
+unsafe fn kb_translate(keycode: u8, down: bool, repeat: bool) {
+    // the kernel shell always gets characters
+    let mode = vtKeyboardMode();
+    let raw = KB_BUFF.is_null() && mode != K_XLATE && mode != K_UNICODE;
+
+    let table = ((SHIFT_STATE | SLOCK_STATE) ^ LOCK_STATE) as usize;
+    let entry = match keymap_entry(table, keycode) {
+        Some(entry) => entry,
+        // modifiers still have to be let go of with no table for them
+        None => match keymap_entry(0, keycode) {
+            Some(entry) if keymap_typed(entry) && ktyp(keymap_keysym(entry)) == KT_SHIFT => entry,
+            _ => return,
+        },
+    };
+
+    if !keymap_typed(entry) {
+        if down && !raw {
+            put_character(entry as u32);
+        }
+        return;
+    }
+
+    let keysym = keymap_keysym(entry);
+    let mut ktype = ktyp(keysym);
+    let mut value = kval(keysym);
+
+    if raw && ktype != KT_SHIFT {
+        return;
+    }
+
+    if ktype == KT_LETTER {
+        ktype = KT_LATIN;
+        if LOCKS & K_CAPSLOCK != 0 {
+            if let Some(other) = keymap_entry(table ^ (1 << KG_SHIFT), keycode) {
+                if keymap_typed(other) {
+                    value = kval(keymap_keysym(other));
+                }
+            }
+        }
+    }
+
+    if ktype != KT_SLOCK && ktype != KT_SHIFT && down {
+        SLOCK_STATE = 0;
+    }
+
+    if ktype == KT_SHIFT {
+        return key_shift(value, down, repeat);
+    }
+    if !down {
+        return;
+    }
+
+    match ktype {
+        KT_LATIN => put_character(value as u32),
+        KT_FN => put_func(value),
+        KT_SPEC => key_spec(value, repeat),
+        KT_PAD => key_pad(value),
+        KT_DEAD => dead_key(RET_DIACR[value as usize] as u32),
+        KT_DEAD2 => dead_key(value as u32),
+        KT_CONS => {
+            vtActivate(value as usize);
+        }
+        KT_CUR => put_cursor(value),
+        KT_META => put_meta(value),
+        KT_ASCII => key_ascii(value),
+        KT_LOCK if !repeat => LOCK_STATE ^= 1 << value,
+        KT_SLOCK if !repeat => SLOCK_STATE ^= 1 << value,
+        _ => {}
+    }
+}
+
+/// Keycodes for K_MEDIUMRAW, the high bit meaning a release
+unsafe fn put_mediumraw(keycode: u8, down: bool) {
+    let up = if down { 0 } else { 0x80 };
+    if keycode < 0x80 {
+        put_byte(keycode | up);
+        return;
+    }
+    put_byte(up);
+    put_byte((keycode >> 7) | 0x80);
+    put_byte(keycode | 0x80);
+}
+
+/// ---------------------------
+/// Handle key press/release
+/// ---------------------------
+pub unsafe fn handle_kb_event() {
+    let scan_code = kb_read();
+
+    if scan_code == SCANCODE_ACK && PREFIX == 0 {
+        if LEDS_PENDING >= 0 {
+            kb_write(0x60, LEDS_PENDING as u8);
+            LEDS_PENDING = -1;
+        }
+        return;
+    }
+
+    let mode = vtKeyboardMode();
+    if mode == K_RAW && KB_BUFF.is_null() {
+        vtInput(scan_code);
+    }
+
+    let Some((keycode, down, scan)) = decode_scancode(scan_code) else {
+        return;
+    };
+
+    let repeat = down && bitmap_get(&EVDEV_INTERNAL, keycode as usize);
+    bitmap_set(&mut EVDEV_INTERNAL, keycode as usize, down);
+
+    inputGenerateEvent(KB_EVENT, EV_MSC, MSC_SCAN, scan as i32);
+    inputGenerateEvent(KB_EVENT, EV_KEY, keycode as u16, if repeat { 2 } else { down as i32 });
+    inputGenerateEvent(KB_EVENT, EV_SYN, SYN_REPORT, 0);
+
+    kb_keycode(keycode, down, repeat);
+}
+
+/// Feeds a key to the consoles, for keyboards that aren't behind the i8042
+/// too. Those have no scancodes, so K_RAW gets nothing from them.
+pub unsafe fn kb_keycode(keycode: u8, down: bool, repeat: bool) {
+    if vtKeyboardMode() == K_MEDIUMRAW && KB_BUFF.is_null() {
+        put_mediumraw(keycode, down);
+    }
+
+    kb_translate(keycode, down, repeat);
+}
+
+/// What the LEDs should show, as K_SCROLLLOCK | K_NUMLOCK | K_CAPSLOCK
+pub fn kb_leds() -> u8 {
+    unsafe { LEDS }
+}
+
+/// ---------------------------
+/// Panic path
+/// ---------------------------
+
+/// Half screens to scroll back for a key, 0 if it isn't a scrollback one
+unsafe fn scrollback_pages(scan_code: u8) -> i32 {
+    if !PANIC_SHIFTED {
+        return 0;
+    }
+    match scan_code {
+        SCANCODE_PGUP => 1,
+        SCANCODE_PGDN => -1,
+        SCANCODE_HOME => i32::MAX,
+        SCANCODE_END => i32::MIN,
+        _ => 0,
+    }
+}
+
+/// Polled with interrupts off after a panic, so the scrollback can still be
+/// looked through. Everything but Shift and the scrollback keys is dropped,
+/// keymaps included.
+pub unsafe fn kb_panic_poll() {
+    let status = inportb(0x64);
+    if status & 1 == 0 {
+        return;
+    }
+    let scan_code = inportb(0x60);
+    // mouse data
+    if status & 0x20 != 0 {
+        return;
+    }
+
+    match scan_code {
+        SCANCODE_SHIFT | SCANCODE_RSHIFT => PANIC_SHIFTED = true,
+        _ if scan_code == SCANCODE_SHIFT | 0x80 || scan_code == SCANCODE_RSHIFT | 0x80 => {
+            PANIC_SHIFTED = false
+        }
+        _ => {
+            let pages = scrollback_pages(scan_code);
+            if pages != 0 {
+                vtScrollbackPanic(pages);
+            }
+        }
+    }
 }
 
 /// ---------------------------
@@ -146,26 +723,155 @@ pub unsafe fn kb_write_char(c: u8) {
     KB_CURR += 1;
 }
 
+/// ---------------------------
+/// ioctls
+/// ---------------------------
+
+/// LED and lock ioctls, the keyboard being shared by all terminals
+pub unsafe fn kb_ioctl(request: u64, arg: *mut u8) -> usize {
+    match request {
+        KDGKBTYPE => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *arg = KB_101;
+            0
+        }
+        KDGETLED => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *arg = LEDS;
+            0
+        }
+        KDSETLED => {
+            // 0xff (or anything with high bits) hands the LEDs back to the
+            // locks
+            let leds = arg as usize;
+            LEDS_FORCED = if leds & !0x07 != 0 { -1 } else { leds as i16 };
+            leds_update();
+            0
+        }
+        KDGKBLED => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *arg = LOCKS;
+            0
+        }
+        KDSKBLED => {
+            let locks = arg as usize;
+            if locks & !0x77 != 0 {
+                return err(EINVAL);
+            }
+            LOCKS = (locks & 0x07) as u8;
+            leds_update();
+            0
+        }
+        _ => err(ENOTTY),
+    }
+}
+
+/// ---------------------------
+/// evdev ioctls
+/// ---------------------------
+#[no_mangle]
+pub extern "C" fn kb_event_bit(_fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    unsafe {
+        let number = (request & 0xff) as usize;
+        let size = ((request >> 16) & 0x3fff) as usize;
+
+        let mut map = [0u8; EVDEV_INTERNAL_SIZE];
+        match number {
+            0x20 => {
+                for ev in [EV_SYN, EV_KEY, EV_MSC, EV_LED] {
+                    bitmap_set(&mut map, ev as usize, true);
+                }
+            }
+            0x21 => {
+                // 0x20 + EV_KEY
+                for keycode in 0x01..=0x58 {
+                    bitmap_set(&mut map, keycode, keycode != 0x54 && keycode != 0x55);
+                }
+                for &keycode in E0_KEYCODES.iter().filter(|&&keycode| keycode != 0) {
+                    bitmap_set(&mut map, keycode as usize, true);
+                }
+            }
+            0x24 => bitmap_set(&mut map, MSC_SCAN as usize, true), // 0x20 + EV_MSC
+            0x31 => {
+                // 0x20 + EV_LED
+                for led in [LED_NUML, LED_CAPSL, LED_SCROLLL] {
+                    bitmap_set(&mut map, led as usize, true);
+                }
+            }
+            // EVIOCGKEY
+            0x18 => map = EVDEV_INTERNAL,
+            // EVIOCGLED
+            0x19 => {
+                bitmap_set(&mut map, LED_NUML as usize, LEDS & K_NUMLOCK != 0);
+                bitmap_set(&mut map, LED_CAPSL as usize, LEDS & K_CAPSLOCK != 0);
+                bitmap_set(&mut map, LED_SCROLLL as usize, LEDS & K_SCROLLLOCK != 0);
+            }
+            _ => return 0,
+        }
+
+        let len = min(map.len(), size);
+        copy_nonoverlapping(map.as_ptr(), arg, len);
+        len
+    }
+}
+
 /// ---------------------------
 /// IRQ Handler
 /// ---------------------------
-pub unsafe fn kb_irq() {
-    let out = handle_kb_event();
-    if out == 0 || KB_BUFF.is_null() {
-        return;
+pub extern "C" fn kb_irq() {
+    unsafe {
+        handle_kb_event();
     }
-    kb_write_char(out);
 }
 
 /// ---------------------------
 /// Initialize Keyboard
 /// ---------------------------
 pub unsafe fn initiate_kb() {
+    static NAME: &[u8] = b"AT Translated Set 2 keyboard\0";
+
+    KB_EVENT = devInputEventSetup(NAME.as_ptr());
+    (*KB_EVENT).inputid = InputId {
+        bustype: 0x11, // BUS_I8042
+        vendor: 0x0001,
+        product: 0x0001,
+        version: 0xab41,
+    };
+    (*KB_EVENT).eventBit = kb_event_bit;
+
+    initiate_keymap();
+
     kb_write(0x64, 0xAE); // enable keyboard
     inportb(0x60);        // clear buffer
-    SHIFTED = false;
-    CAPSLOCKED = false;
+    PREFIX = 0;
+    SHIFT_DOWN = [0; NR_SHIFT as usize];
+    SHIFT_STATE = 0;
+    LOCK_STATE = 0;
+    SLOCK_STATE = 0;
+    LOCKS = 0;
+    DIACR = 0;
+    COMPOSE_NEXT = false;
+    NPADCH = -1;
     KB_BUFF = core::ptr::null_mut();
     KB_CURR = 0;
     KB_MAX = 0;
+
+    // turn off whatever the firmware left lit, before there's an interrupt
+    // handler to wait on the acknowledgements
+    LEDS = 0;
+    LEDS_FORCED = -1;
+    LEDS_PENDING = -1;
+    kb_write(0x60, KB_CMD_LEDS);
+    kb_read();
+    kb_write(0x60, LEDS);
+    kb_read();
+
+    let irq = ioApicRedirect(1, false);
+    registerIRQhandler(irq, kb_irq);
 }
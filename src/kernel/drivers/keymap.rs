@@ -0,0 +1,615 @@
+#![no_std]
+#![allow(non_camel_case_types)]
+
+use core::ptr::copy_nonoverlapping;
+
+//
+// Console keymaps, laid out the way Linux has them so loadkeys/dumpkeys
+// work through the KD*KB* ioctls: one table per modifier combination, each
+// mapping a keycode to a keysym. The interpreter is in kb.rs
+//
+
+//
+// Constants
+//
+
+pub const NR_KEYS: usize = 256;
+pub const MAX_NR_KEYMAPS: usize = 256;
+pub const MAX_NR_FUNC: usize = 256;
+pub const MAX_DIACR: usize = 256;
+
+// what a single function key string may hold, terminator included
+const FUNC_STRING_MAX: usize = 64;
+// kbsentry.kb_string
+const KB_STRING_MAX: usize = 512;
+
+// keysym types
+pub const KT_LATIN: u8 = 0;
+pub const KT_FN: u8 = 1;
+pub const KT_SPEC: u8 = 2;
+pub const KT_PAD: u8 = 3;
+pub const KT_DEAD: u8 = 4;
+pub const KT_CONS: u8 = 5;
+pub const KT_CUR: u8 = 6;
+pub const KT_SHIFT: u8 = 7;
+pub const KT_META: u8 = 8;
+pub const KT_ASCII: u8 = 9;
+pub const KT_LOCK: u8 = 10;
+pub const KT_LETTER: u8 = 11;
+pub const KT_SLOCK: u8 = 12;
+pub const KT_DEAD2: u8 = 13;
+const NR_TYPES: u8 = 14;
+
+// largest value for each type, anything past it is rejected by KDSKBENT
+static MAX_VALS: [u8; NR_TYPES as usize] = [
+    255,                          // KT_LATIN
+    (MAX_NR_FUNC - 1) as u8,      // KT_FN
+    K_SPEC_MAX,                   // KT_SPEC
+    NR_PAD - 1,                   // KT_PAD
+    NR_DEAD - 1,                  // KT_DEAD
+    255,                          // KT_CONS
+    K_CUR_MAX,                    // KT_CUR
+    NR_SHIFT - 1,                 // KT_SHIFT
+    255,                          // KT_META
+    NR_ASCII - 1,                 // KT_ASCII
+    NR_LOCK - 1,                  // KT_LOCK
+    255,                          // KT_LETTER
+    NR_LOCK - 1,                  // KT_SLOCK
+    255,                          // KT_DEAD2
+];
+
+// KT_SPEC
+pub const K_HOLE: u16 = k(KT_SPEC, 0);
+pub const K_ENTER: u8 = 1;
+pub const K_LASTCONS: u8 = 6;
+pub const K_CAPS: u8 = 7;
+pub const K_NUM: u8 = 8;
+pub const K_HOLD: u8 = 9;
+pub const K_SCROLLFORW: u8 = 10;
+pub const K_SCROLLBACK: u8 = 11;
+pub const K_CAPSON: u8 = 13;
+pub const K_COMPOSE: u8 = 14;
+pub const K_DECRCONSOLE: u8 = 16;
+pub const K_INCRCONSOLE: u8 = 17;
+pub const K_BARENUMLOCK: u8 = 19;
+// local additions, Shift+Home/End
+pub const K_SCROLLTOP: u8 = 20;
+pub const K_SCROLLBOTTOM: u8 = 21;
+const K_SPEC_MAX: u8 = K_SCROLLBOTTOM;
+// returned by KDGKBENT for a table that doesn't exist
+const K_NOSUCHMAP: u16 = k(KT_SPEC, 127);
+
+// KT_FN
+pub const K_F1: u8 = 0;
+pub const K_FIND: u8 = 20;
+pub const K_INSERT: u8 = 21;
+pub const K_REMOVE: u8 = 22;
+pub const K_SELECT: u8 = 23;
+pub const K_PGUP: u8 = 24;
+pub const K_PGDN: u8 = 25;
+pub const K_PAUSE: u8 = 29;
+
+// KT_PAD
+pub const K_P0: u8 = 0;
+pub const K_PPLUS: u8 = 10;
+pub const K_PMINUS: u8 = 11;
+pub const K_PSTAR: u8 = 12;
+pub const K_PSLASH: u8 = 13;
+pub const K_PENTER: u8 = 14;
+pub const K_PCOMMA: u8 = 15;
+pub const K_PDOT: u8 = 16;
+pub const K_PPLUSMINUS: u8 = 17;
+pub const K_PPARENL: u8 = 18;
+pub const K_PPARENR: u8 = 19;
+const NR_PAD: u8 = 20;
+
+// KT_DEAD, indexes RET_DIACR
+pub const RET_DIACR: [u8; 6] = [b'`', b'\'', b'^', b'~', b'"', b','];
+const NR_DEAD: u8 = RET_DIACR.len() as u8;
+
+// KT_CUR, Home and End being local additions
+pub const K_DOWN: u8 = 0;
+pub const K_LEFT: u8 = 1;
+pub const K_RIGHT: u8 = 2;
+pub const K_UP: u8 = 3;
+pub const K_HOME: u8 = 4;
+pub const K_END: u8 = 5;
+const K_CUR_MAX: u8 = K_END;
+
+// KT_SHIFT / KT_LOCK / KT_SLOCK, the bit each one has in a table's index
+pub const KG_SHIFT: u8 = 0;
+pub const KG_ALTGR: u8 = 1;
+pub const KG_CTRL: u8 = 2;
+pub const KG_ALT: u8 = 3;
+pub const KG_SHIFTL: u8 = 4;
+pub const KG_SHIFTR: u8 = 5;
+pub const KG_CTRLL: u8 = 6;
+pub const KG_CTRLR: u8 = 7;
+pub const KG_CAPSSHIFT: u8 = 8;
+pub const NR_SHIFT: u8 = 9;
+const NR_LOCK: u8 = 8;
+
+// KT_ASCII, Alt+keypad digits (decimal 0..9, hex 10..25)
+const NR_ASCII: u8 = 26;
+
+// ioctls
+pub const KDGKBENT: u64 = 0x4B46;
+pub const KDSKBENT: u64 = 0x4B47;
+pub const KDGKBSENT: u64 = 0x4B48;
+pub const KDSKBSENT: u64 = 0x4B49;
+pub const KDGKBDIACR: u64 = 0x4B4A;
+pub const KDSKBDIACR: u64 = 0x4B4B;
+pub const KDGKBDIACRUC: u64 = 0x4BFA;
+pub const KDSKBDIACRUC: u64 = 0x4BFB;
+
+const EFAULT: isize = 14;
+const EINVAL: isize = 22;
+const ENOSPC: isize = 28;
+const ENOTTY: isize = 25;
+
+#[inline]
+const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+// Keysym as userspace sees it
+#[inline]
+pub const fn k(ktype: u8, value: u8) -> u16 {
+    ((ktype as u16) << 8) | value as u16
+}
+
+#[inline]
+pub const fn ktyp(keysym: u16) -> u8 {
+    (keysym >> 8) as u8
+}
+
+#[inline]
+pub const fn kval(keysym: u16) -> u8 {
+    keysym as u8
+}
+
+// Tables hold keysyms xor 0xF000 (Linux's U()): typed ones end up at
+// 0xF000 and above, everything below is a plain unicode code point
+#[inline]
+const fn u(keysym: u16) -> u16 {
+    keysym ^ 0xF000
+}
+
+//
+// Structures (Linux ABI)
+//
+
+#[repr(C)]
+pub struct kbentry {
+    pub kb_table: u8,
+    pub kb_index: u8,
+    pub kb_value: u16,
+}
+
+#[repr(C)]
+pub struct kbsentry {
+    pub kb_func: u8,
+    pub kb_string: [u8; KB_STRING_MAX],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct kbdiacr {
+    pub diacr: u8,
+    pub base: u8,
+    pub result: u8,
+}
+
+#[repr(C)]
+pub struct kbdiacrs {
+    pub kb_cnt: u32,
+    pub kbdiacr: [kbdiacr; MAX_DIACR],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct kbdiacruc {
+    pub diacr: u32,
+    pub base: u32,
+    pub result: u32,
+}
+
+#[repr(C)]
+pub struct kbdiacrsuc {
+    pub kb_cnt: u32,
+    pub kbdiacruc: [kbdiacruc; MAX_DIACR],
+}
+
+//
+// State
+//
+
+// All of it static, so loading a keymap never needs the heap. Entries are
+// single u16 writes, a keymap the interrupt sees half loaded is no worse than
+// what loadkeys leaves behind between its calls anyway
+static mut KEYMAPS: [[u16; NR_KEYS]; MAX_NR_KEYMAPS] = [[0; NR_KEYS]; MAX_NR_KEYMAPS];
+static mut KEYMAP_PRESENT: [bool; MAX_NR_KEYMAPS] = [false; MAX_NR_KEYMAPS];
+
+// NUL terminated
+static mut FUNC_STRINGS: [[u8; FUNC_STRING_MAX]; MAX_NR_FUNC] = [[0; FUNC_STRING_MAX]; MAX_NR_FUNC];
+
+static mut DIACRS: [kbdiacruc; MAX_DIACR] = [kbdiacruc { diacr: 0, base: 0, result: 0 }; MAX_DIACR];
+static mut DIACR_COUNT: usize = 0;
+
+//
+// Default (US) keymap
+//
+
+// keycodes 0..=58 without and with Shift, 0 where it isn't a character
+static PLAIN: [u8; 59] = [
+    0, 27, b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', b'-', b'=', 127, 9,
+    b'q', b'w', b'e', b'r', b't', b'y', b'u', b'i', b'o', b'p', b'[', b']', 0, 0,
+    b'a', b's', b'd', b'f', b'g', b'h', b'j', b'k', b'l', b';', b'\'', b'`', 0, b'\\',
+    b'z', b'x', b'c', b'v', b'b', b'n', b'm', b',', b'.', b'/', 0, 0, 0, b' ', 0,
+];
+
+static SHIFTED: [u8; 59] = [
+    0, 27, b'!', b'@', b'#', b'$', b'%', b'^', b'&', b'*', b'(', b')', b'_', b'+', 127, 9,
+    b'Q', b'W', b'E', b'R', b'T', b'Y', b'U', b'I', b'O', b'P', b'{', b'}', 0, 0,
+    b'A', b'S', b'D', b'F', b'G', b'H', b'J', b'K', b'L', b':', b'"', b'~', 0, b'|',
+    b'Z', b'X', b'C', b'V', b'B', b'N', b'M', b'<', b'>', b'?', 0, 0, 0, b' ', 0,
+];
+
+// Everything that isn't a character, the same in every default table
+fn default_special(keycode: usize) -> u16 {
+    match keycode {
+        28 => k(KT_SPEC, K_ENTER),
+        29 => k(KT_SHIFT, KG_CTRL),
+        42 | 54 => k(KT_SHIFT, KG_SHIFT),
+        55 => k(KT_PAD, K_PSTAR),
+        56 => k(KT_SHIFT, KG_ALT),
+        58 => k(KT_SPEC, K_CAPS),
+        59..=68 => k(KT_FN, K_F1 + (keycode - 59) as u8),
+        69 => k(KT_SPEC, K_NUM),
+        70 => k(KT_SPEC, K_HOLD),
+        71 => k(KT_PAD, K_P0 + 7),
+        72 => k(KT_PAD, K_P0 + 8),
+        73 => k(KT_PAD, K_P0 + 9),
+        74 => k(KT_PAD, K_PMINUS),
+        75 => k(KT_PAD, K_P0 + 4),
+        76 => k(KT_PAD, K_P0 + 5),
+        77 => k(KT_PAD, K_P0 + 6),
+        78 => k(KT_PAD, K_PPLUS),
+        79 => k(KT_PAD, K_P0 + 1),
+        80 => k(KT_PAD, K_P0 + 2),
+        81 => k(KT_PAD, K_P0 + 3),
+        82 => k(KT_PAD, K_P0),
+        83 => k(KT_PAD, K_PDOT),
+        86 => k(KT_LATIN, b'<'),
+        87 => k(KT_FN, K_F1 + 10),
+        88 => k(KT_FN, K_F1 + 11),
+        96 => k(KT_PAD, K_PENTER),
+        97 => k(KT_SHIFT, KG_CTRL),
+        98 => k(KT_PAD, K_PSLASH),
+        // no AltGr symbols on a US layout, so right Alt is just Alt
+        100 => k(KT_SHIFT, KG_ALT),
+        102 => k(KT_CUR, K_HOME),
+        103 => k(KT_CUR, K_UP),
+        104 => k(KT_FN, K_PGUP),
+        105 => k(KT_CUR, K_LEFT),
+        106 => k(KT_CUR, K_RIGHT),
+        107 => k(KT_CUR, K_END),
+        108 => k(KT_CUR, K_DOWN),
+        109 => k(KT_FN, K_PGDN),
+        110 => k(KT_FN, K_INSERT),
+        111 => k(KT_FN, K_REMOVE),
+        117 => k(KT_LATIN, b'='),
+        119 => k(KT_FN, K_PAUSE),
+        // the menu key
+        127 => k(KT_SPEC, K_COMPOSE),
+        _ => K_HOLE,
+    }
+}
+
+fn default_entry(table: usize, keycode: usize) -> u16 {
+    let shift = table & (1 << KG_SHIFT) != 0;
+    let ctrl = table & (1 << KG_CTRL) != 0;
+    let alt = table & (1 << KG_ALT) != 0;
+
+    let mut keysym = if keycode < PLAIN.len() && PLAIN[keycode] != 0 {
+        let c = if shift { SHIFTED[keycode] } else { PLAIN[keycode] };
+        if c.is_ascii_alphabetic() {
+            k(KT_LETTER, c)
+        } else {
+            k(KT_LATIN, c)
+        }
+    } else if keycode == 86 && shift {
+        k(KT_LATIN, b'>')
+    } else {
+        default_special(keycode)
+    };
+
+    if shift && !ctrl && !alt {
+        keysym = match keycode {
+            102 => k(KT_SPEC, K_SCROLLTOP),
+            104 => k(KT_SPEC, K_SCROLLBACK),
+            107 => k(KT_SPEC, K_SCROLLBOTTOM),
+            109 => k(KT_SPEC, K_SCROLLFORW),
+            _ => keysym,
+        };
+    }
+
+    if ctrl && (ktyp(keysym) == KT_LATIN || ktyp(keysym) == KT_LETTER) {
+        let c = kval(keysym);
+        keysym = match c {
+            b'a'..=b'z' | b'A'..=b'Z' => k(KT_LATIN, c & 0x1f),
+            b'2' | b'@' | b' ' => k(KT_LATIN, 0),
+            b'3' | b'[' | b'{' => k(KT_LATIN, 27),
+            b'4' | b'\\' | b'|' => k(KT_LATIN, 28),
+            b'5' | b']' | b'}' => k(KT_LATIN, 29),
+            b'6' | b'^' | b'~' | b'`' => k(KT_LATIN, 30),
+            b'7' | b'-' | b'_' | b'/' | b'?' => k(KT_LATIN, 31),
+            b'8' => k(KT_LATIN, 127),
+            127 => k(KT_LATIN, 8),
+            _ => keysym,
+        };
+    }
+
+    if alt {
+        keysym = match ktyp(keysym) {
+            KT_LATIN | KT_LETTER if kval(keysym) < 0x80 => k(KT_META, kval(keysym)),
+            // Alt+Fn, and Ctrl+Alt+Fn out of X
+            KT_FN if kval(keysym) < 12 => k(KT_CONS, kval(keysym)),
+            KT_CUR if kval(keysym) == K_LEFT => k(KT_SPEC, K_DECRCONSOLE),
+            KT_CUR if kval(keysym) == K_RIGHT => k(KT_SPEC, K_INCRCONSOLE),
+            KT_PAD if kval(keysym) <= K_P0 + 9 => k(KT_ASCII, kval(keysym)),
+            _ => keysym,
+        };
+    }
+
+    keysym
+}
+
+// What xterm sends, which is what the console says it is
+static DEFAULT_FUNCS: [(u8, &[u8]); 18] = [
+    (0, b"\x1bOP"),
+    (1, b"\x1bOQ"),
+    (2, b"\x1bOR"),
+    (3, b"\x1bOS"),
+    (4, b"\x1b[15~"),
+    (5, b"\x1b[17~"),
+    (6, b"\x1b[18~"),
+    (7, b"\x1b[19~"),
+    (8, b"\x1b[20~"),
+    (9, b"\x1b[21~"),
+    (10, b"\x1b[23~"),
+    (11, b"\x1b[24~"),
+    (K_FIND, b"\x1b[1~"),
+    (K_INSERT, b"\x1b[2~"),
+    (K_REMOVE, b"\x1b[3~"),
+    (K_SELECT, b"\x1b[4~"),
+    (K_PGUP, b"\x1b[5~"),
+    (K_PGDN, b"\x1b[6~"),
+];
+
+// Upper case letters with their accent, the lower case ones being 0x20 up
+static DEFAULT_ACCENTS: [(u8, u8, u8); 30] = [
+    (b'`', b'A', 0xc0), (b'`', b'E', 0xc8), (b'`', b'I', 0xcc), (b'`', b'O', 0xd2), (b'`', b'U', 0xd9),
+    (b'\'', b'A', 0xc1), (b'\'', b'E', 0xc9), (b'\'', b'I', 0xcd), (b'\'', b'O', 0xd3), (b'\'', b'U', 0xda),
+    (b'\'', b'Y', 0xdd),
+    (b'^', b'A', 0xc2), (b'^', b'E', 0xca), (b'^', b'I', 0xce), (b'^', b'O', 0xd4), (b'^', b'U', 0xdb),
+    (b'~', b'A', 0xc3), (b'~', b'N', 0xd1), (b'~', b'O', 0xd5),
+    (b'"', b'A', 0xc4), (b'"', b'E', 0xcb), (b'"', b'I', 0xcf), (b'"', b'O', 0xd6), (b'"', b'U', 0xdc),
+    (b',', b'C', 0xc7),
+    (b'*', b'A', 0xc5),
+    (b'/', b'O', 0xd8),
+    (b'E', b'A', 0xc6),
+    (b'-', b'D', 0xd0),
+    (b'|', b'P', 0xde),
+];
+
+unsafe fn func_set(index: usize, string: &[u8]) -> bool {
+    if string.len() >= FUNC_STRING_MAX {
+        return false;
+    }
+    let slot = &mut FUNC_STRINGS[index];
+    // cut it short while it's being written, the interrupt might look at it
+    slot[0] = 0;
+    if string.is_empty() {
+        return true;
+    }
+    slot[1..string.len()].copy_from_slice(&string[1..]);
+    slot[string.len()] = 0;
+    slot[0] = string[0];
+    true
+}
+
+unsafe fn diacr_add(diacr: u32, base: u32, result: u32) {
+    if DIACR_COUNT < MAX_DIACR {
+        DIACRS[DIACR_COUNT] = kbdiacruc { diacr, base, result };
+        DIACR_COUNT += 1;
+    }
+}
+
+pub unsafe fn initiate_keymap() {
+    for table in 0..MAX_NR_KEYMAPS {
+        KEYMAP_PRESENT[table] = false;
+    }
+
+    // plain, Shift, Ctrl and Alt in any combination
+    for &table in &[0usize, 1, 4, 5, 8, 9, 12, 13] {
+        for keycode in 0..NR_KEYS {
+            KEYMAPS[table][keycode] = u(default_entry(table, keycode));
+        }
+        KEYMAP_PRESENT[table] = true;
+    }
+
+    for func in 0..MAX_NR_FUNC {
+        FUNC_STRINGS[func][0] = 0;
+    }
+    for &(func, string) in DEFAULT_FUNCS.iter() {
+        func_set(func as usize, string);
+    }
+    func_set(K_PAUSE as usize, b"\x1b[P");
+
+    DIACR_COUNT = 0;
+    for &(diacr, base, result) in DEFAULT_ACCENTS.iter() {
+        diacr_add(diacr as u32, base as u32, result as u32);
+        diacr_add(diacr as u32, (base + 0x20) as u32, (result + 0x20) as u32);
+    }
+    diacr_add(b'"' as u32, b'y' as u32, 0xff);
+    diacr_add(b's' as u32, b's' as u32, 0xdf);
+}
+
+//
+// Lookups, for the interrupt handler
+//
+
+// The raw table entry (see u()), None when that table isn't allocated
+pub unsafe fn keymap_entry(table: usize, keycode: u8) -> Option<u16> {
+    if table >= MAX_NR_KEYMAPS || !KEYMAP_PRESENT[table] {
+        return None;
+    }
+    Some(KEYMAPS[table][keycode as usize])
+}
+
+// Whether an entry is a typed keysym, or else a unicode character
+#[inline]
+pub const fn keymap_typed(entry: u16) -> bool {
+    entry >= 0xF000
+}
+
+#[inline]
+pub const fn keymap_keysym(entry: u16) -> u16 {
+    u(entry)
+}
+
+pub unsafe fn keymap_func(index: u8) -> &'static [u8] {
+    let slot = &FUNC_STRINGS[index as usize];
+    let len = slot.iter().position(|&c| c == 0).unwrap_or(0);
+    &slot[..len]
+}
+
+pub unsafe fn keymap_diacr(diacr: u32, base: u32) -> Option<u32> {
+    DIACRS[..DIACR_COUNT]
+        .iter()
+        .find(|entry| entry.diacr == diacr && entry.base == base)
+        .map(|entry| entry.result)
+}
+
+//
+// ioctls
+//
+
+// `unicode` is whether the terminal is in K_UNICODE, the only mode where
+// plain code points make sense as keysyms
+pub unsafe fn keymap_ioctl(request: u64, arg: *mut u8, unicode: bool) -> usize {
+    if arg.is_null() {
+        return err(EFAULT);
+    }
+
+    match request {
+        KDGKBENT => {
+            let entry = arg as *mut kbentry;
+            let table = (*entry).kb_table as usize;
+            let index = (*entry).kb_index;
+            (*entry).kb_value = match keymap_entry(table, index) {
+                Some(value) => u(value),
+                None if index != 0 => K_HOLE,
+                None => K_NOSUCHMAP,
+            };
+            0
+        }
+        KDSKBENT => {
+            let entry = arg as *const kbentry;
+            let table = (*entry).kb_table as usize;
+            let index = (*entry).kb_index as usize;
+            let value = (*entry).kb_value;
+
+            // entry 0 set to K_NOSUCHMAP drops the whole table, except for
+            // the plain one
+            if index == 0 && value == K_NOSUCHMAP {
+                if table != 0 {
+                    KEYMAP_PRESENT[table] = false;
+                }
+                return 0;
+            }
+
+            if ktyp(value) < NR_TYPES {
+                if kval(value) > MAX_VALS[ktyp(value) as usize] {
+                    return err(EINVAL);
+                }
+            } else if !unicode {
+                return err(EINVAL);
+            }
+
+            if !KEYMAP_PRESENT[table] {
+                KEYMAPS[table] = [u(K_HOLE); NR_KEYS];
+                KEYMAP_PRESENT[table] = true;
+            }
+            KEYMAPS[table][index] = u(value);
+            0
+        }
+        KDGKBSENT => {
+            let entry = arg as *mut kbsentry;
+            let string = keymap_func((*entry).kb_func);
+            let len = string.len().min(KB_STRING_MAX - 1);
+            copy_nonoverlapping(string.as_ptr(), (*entry).kb_string.as_mut_ptr(), len);
+            (*entry).kb_string[len] = 0;
+            0
+        }
+        KDSKBSENT => {
+            let entry = arg as *const kbsentry;
+            let string = &(*entry).kb_string;
+            let len = string.iter().position(|&c| c == 0).unwrap_or(KB_STRING_MAX);
+            if !func_set((*entry).kb_func as usize, &string[..len]) {
+                return err(ENOSPC);
+            }
+            0
+        }
+        KDGKBDIACR => {
+            // only what fits in Latin-1
+            let out = arg as *mut kbdiacrs;
+            let mut count = 0;
+            for entry in DIACRS[..DIACR_COUNT].iter() {
+                if entry.diacr > 0xff || entry.base > 0xff || entry.result > 0xff {
+                    continue;
+                }
+                (*out).kbdiacr[count] = kbdiacr {
+                    diacr: entry.diacr as u8,
+                    base: entry.base as u8,
+                    result: entry.result as u8,
+                };
+                count += 1;
+            }
+            (*out).kb_cnt = count as u32;
+            0
+        }
+        KDSKBDIACR => {
+            let input = arg as *const kbdiacrs;
+            let count = (*input).kb_cnt as usize;
+            if count > MAX_DIACR {
+                return err(EINVAL);
+            }
+            DIACR_COUNT = 0;
+            for entry in (&(*input).kbdiacr)[..count].iter() {
+                diacr_add(entry.diacr as u32, entry.base as u32, entry.result as u32);
+            }
+            0
+        }
+        KDGKBDIACRUC => {
+            let out = arg as *mut kbdiacrsuc;
+            (&mut (*out).kbdiacruc)[..DIACR_COUNT].copy_from_slice(&DIACRS[..DIACR_COUNT]);
+            (*out).kb_cnt = DIACR_COUNT as u32;
+            0
+        }
+        KDSKBDIACRUC => {
+            let input = arg as *const kbdiacrsuc;
+            let count = (*input).kb_cnt as usize;
+            if count > MAX_DIACR {
+                return err(EINVAL);
+            }
+            DIACR_COUNT = 0;
+            for entry in (&(*input).kbdiacruc)[..count].iter() {
+                diacr_add(entry.diacr, entry.base, entry.result);
+            }
+            0
+        }
+        _ => err(ENOTTY),
+    }
+}
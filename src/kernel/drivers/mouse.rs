@@ -1,5 +1,7 @@
 #![no_std]
 
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
 use core::cmp::min;
 use core::ptr::{copy_nonoverlapping, write_bytes};
 
@@ -38,6 +40,43 @@ const MOUSE_WRITE: u8 = 0xD4;
 
 const MOUSE_TIMEOUT: u32 = 100_000;
 
+const MOUSE_CMD_SET_SAMPLE_RATE: u8 = 0xF3;
+const MOUSE_CMD_GET_ID: u8 = 0xF2;
+
+//
+// Device ids returned by 0xF2 after the IntelliMouse knock sequences
+//
+
+const MOUSE_ID_STANDARD: u8 = 0x00;
+const MOUSE_ID_INTELLIMOUSE: u8 = 0x03;
+const MOUSE_ID_EXPLORER: u8 = 0x04;
+
+//
+// VMware backdoor (also implemented by QEMU's vmport)
+//
+
+const VMWARE_MAGIC: u32 = 0x564D_5868;
+const VMWARE_PORT: u16 = 0x5658;
+
+const VMWARE_CMD_GETVERSION: u32 = 10;
+const VMWARE_CMD_ABSPOINTER_DATA: u32 = 39;
+const VMWARE_CMD_ABSPOINTER_STATUS: u32 = 40;
+const VMWARE_CMD_ABSPOINTER_COMMAND: u32 = 41;
+
+const VMMOUSE_CMD_ENABLE: u32 = 0x4541_4552;
+const VMMOUSE_CMD_DISABLE: u32 = 0x0000_00F5;
+const VMMOUSE_CMD_REQUEST_ABSOLUTE: u32 = 0x5342_4152;
+const VMMOUSE_VERSION_ID: u32 = 0x3442_554A;
+
+const VMMOUSE_STATUS_ERROR: u32 = 0xFFFF_0000;
+const VMMOUSE_RELATIVE_PACKET: u32 = 0x0001_0000;
+
+const VMMOUSE_LEFT_BUTTON: u32 = 0x20;
+const VMMOUSE_RIGHT_BUTTON: u32 = 0x10;
+const VMMOUSE_MIDDLE_BUTTON: u32 = 0x08;
+
+const VMMOUSE_MAX: i32 = 0xFFFF;
+
 //
 // evdev constants (assumed same values as C)
 //
@@ -49,9 +88,19 @@ const EV_ABS: u16 = 0x03;
 
 const REL_X: u16 = 0x00;
 const REL_Y: u16 = 0x01;
+const REL_WHEEL: u16 = 0x08;
+
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
 
 const BTN_LEFT: u16 = 0x110;
 const BTN_RIGHT: u16 = 0x111;
+const BTN_MIDDLE: u16 = 0x112;
+const BTN_SIDE: u16 = 0x113;
+const BTN_EXTRA: u16 = 0x114;
+
+// Packet button bit n maps to BUTTONS[n]
+const BUTTONS: [u16; 5] = [BTN_LEFT, BTN_RIGHT, BTN_MIDDLE, BTN_SIDE, BTN_EXTRA];
 
 const SYN_REPORT: u16 = 0;
 
@@ -101,17 +150,22 @@ extern "C" {
 //
 
 static mut MOUSE_CYCLE: u8 = 0;
-static mut MOUSE1: u8 = 0;
-static mut MOUSE2: u8 = 0;
+static mut MOUSE_PACKET: [u8; 4] = [0; 4];
+static mut MOUSE_ID: u8 = MOUSE_ID_STANDARD;
 
 static mut GX: i32 = 0;
 static mut GY: i32 = 0;
 
-static mut CLICKED_LEFT: bool = false;
-static mut CLICKED_RIGHT: bool = false;
+// Button bitmask in BUTTONS order, per device
+static mut MOUSE_HELD: u8 = 0;
+static mut VMMOUSE_HELD: u8 = 0;
 
 static mut MOUSE_EVENT: *mut DevInputEvent = core::ptr::null_mut();
 
+static mut VMMOUSE_ACTIVE: bool = false;
+static mut VMMOUSE_ABS: [i32; 2] = [0; 2];
+static mut VMMOUSE_EVENT: *mut DevInputEvent = core::ptr::null_mut();
+
 //
 // ===== Low-level helpers =====
 //
@@ -147,76 +201,245 @@ unsafe fn mouse_read() -> u8 {
     inportb(MOUSE_PORT)
 }
 
+// Sends a byte to the mouse and returns its ack
+unsafe fn mouse_command(value: u8) -> u8 {
+    mouse_write(value);
+    mouse_read()
+}
+
+unsafe fn mouse_set_rate(rate: u8) {
+    mouse_command(MOUSE_CMD_SET_SAMPLE_RATE);
+    mouse_command(rate);
+}
+
+// Plays a sample rate "knock" and returns the id the mouse reports after it
+unsafe fn mouse_knock(rates: [u8; 3]) -> u8 {
+    for rate in rates {
+        mouse_set_rate(rate);
+    }
+    mouse_command(MOUSE_CMD_GET_ID);
+    mouse_read()
+}
+
+// IntelliMouse (wheel) and IntelliMouse Explorer (wheel + buttons 4/5)
+// handshake. Mice that don't know the sequences just keep reporting id 0.
+unsafe fn mouse_identify() -> u8 {
+    let mut id = MOUSE_ID_STANDARD;
+
+    if mouse_knock([200, 100, 80]) == MOUSE_ID_INTELLIMOUSE {
+        id = MOUSE_ID_INTELLIMOUSE;
+        if mouse_knock([200, 200, 80]) == MOUSE_ID_EXPLORER {
+            id = MOUSE_ID_EXPLORER;
+        }
+    }
+
+    mouse_set_rate(100);
+    id
+}
+
+fn mouse_packet_size() -> u8 {
+    unsafe {
+        if MOUSE_ID >= MOUSE_ID_INTELLIMOUSE {
+            4
+        } else {
+            3
+        }
+    }
+}
+
+// Emits EV_KEY for every button whose state differs from `held`
+unsafe fn mouse_buttons(dev: *mut DevInputEvent, held: *mut u8, pressed: u8) {
+    let changed = *held ^ pressed;
+    for (bit, code) in BUTTONS.iter().enumerate() {
+        if changed & (1 << bit) != 0 {
+            inputGenerateEvent(dev, EV_KEY, *code, ((pressed >> bit) & 1) as i32);
+        }
+    }
+    *held = pressed;
+}
+
+//
+// ===== VMware backdoor (VMMouse) =====
+//
+
+// Returns eax, ebx, ecx, edx. rbx belongs to LLVM, so swap it in and out by hand.
+unsafe fn vmware_backdoor(command: u32, arg: u32) -> [u32; 4] {
+    let eax: u32;
+    let ebx: u64;
+    let ecx: u32;
+    let edx: u32;
+
+    asm!(
+        "xchg {b}, rbx",
+        "in eax, dx",
+        "xchg {b}, rbx",
+        b = inout(reg) arg as u64 => ebx,
+        inout("eax") VMWARE_MAGIC => eax,
+        inout("ecx") command => ecx,
+        inout("edx") VMWARE_PORT as u32 => edx,
+        options(nostack, preserves_flags),
+    );
+
+    [eax, ebx as u32, ecx, edx]
+}
+
+unsafe fn vmmouse_detect() -> bool {
+    // Only touch the backdoor port under a hypervisor
+    if __cpuid(1).ecx & (1 << 31) == 0 {
+        return false;
+    }
+
+    let regs = vmware_backdoor(VMWARE_CMD_GETVERSION, !VMWARE_MAGIC);
+    regs[1] == VMWARE_MAGIC && regs[0] != 0xFFFF_FFFF
+}
+
+// Switches the host side into absolute mode. The enable command queues a
+// single version word which has to be read back before any motion data.
+unsafe fn vmmouse_enable() -> bool {
+    vmware_backdoor(VMWARE_CMD_ABSPOINTER_COMMAND, VMMOUSE_CMD_ENABLE);
+
+    let status = vmware_backdoor(VMWARE_CMD_ABSPOINTER_STATUS, 0)[0];
+    if status & 0xFFFF == 0 {
+        vmware_backdoor(VMWARE_CMD_ABSPOINTER_COMMAND, VMMOUSE_CMD_DISABLE);
+        return false;
+    }
+
+    let version = vmware_backdoor(VMWARE_CMD_ABSPOINTER_DATA, 1)[0];
+    if version != VMMOUSE_VERSION_ID {
+        vmware_backdoor(VMWARE_CMD_ABSPOINTER_COMMAND, VMMOUSE_CMD_DISABLE);
+        return false;
+    }
+
+    vmware_backdoor(VMWARE_CMD_ABSPOINTER_COMMAND, VMMOUSE_CMD_REQUEST_ABSOLUTE);
+    true
+}
+
+// Drains the host queue. The PS/2 packet that got us here is only a doorbell.
+unsafe fn vmmouse_poll() {
+    loop {
+        let status = vmware_backdoor(VMWARE_CMD_ABSPOINTER_STATUS, 0)[0];
+        if status & VMMOUSE_STATUS_ERROR == VMMOUSE_STATUS_ERROR {
+            // Host lost sync, restart the protocol
+            vmware_backdoor(VMWARE_CMD_ABSPOINTER_COMMAND, VMMOUSE_CMD_DISABLE);
+            VMMOUSE_ACTIVE = vmmouse_enable();
+            return;
+        }
+        if status & 0xFFFF < 4 {
+            return;
+        }
+
+        let data = vmware_backdoor(VMWARE_CMD_ABSPOINTER_DATA, 4);
+        let flags = data[0];
+
+        let mut pressed = 0u8;
+        if flags & VMMOUSE_LEFT_BUTTON != 0 {
+            pressed |= 1 << 0;
+        }
+        if flags & VMMOUSE_RIGHT_BUTTON != 0 {
+            pressed |= 1 << 1;
+        }
+        if flags & VMMOUSE_MIDDLE_BUTTON != 0 {
+            pressed |= 1 << 2;
+        }
+        let wheel = -(data[3] as u8 as i8 as i32);
+
+        let dev = if flags & VMMOUSE_RELATIVE_PACKET != 0 {
+            mouse_buttons(MOUSE_EVENT, core::ptr::addr_of_mut!(MOUSE_HELD), pressed);
+            inputGenerateEvent(MOUSE_EVENT, EV_REL, REL_X, data[1] as i32);
+            inputGenerateEvent(MOUSE_EVENT, EV_REL, REL_Y, -(data[2] as i32));
+            MOUSE_EVENT
+        } else {
+            mouse_buttons(
+                VMMOUSE_EVENT,
+                core::ptr::addr_of_mut!(VMMOUSE_HELD),
+                pressed,
+            );
+            VMMOUSE_ABS = [data[1] as i32, data[2] as i32];
+            inputGenerateEvent(VMMOUSE_EVENT, EV_ABS, ABS_X, VMMOUSE_ABS[0]);
+            inputGenerateEvent(VMMOUSE_EVENT, EV_ABS, ABS_Y, VMMOUSE_ABS[1]);
+            VMMOUSE_EVENT
+        };
+
+        if wheel != 0 {
+            inputGenerateEvent(dev, EV_REL, REL_WHEEL, wheel);
+        }
+        inputGenerateEvent(dev, EV_SYN, SYN_REPORT, 0);
+    }
+}
+
 //
 // ===== IRQ handler =====
 //
 
-#[no_mangle]
-pub extern "C" fn mouse_irq() {
-    unsafe {
-        let byte = mouse_read();
+unsafe fn mouse_packet() {
+    let flags = MOUSE_PACKET[0];
 
-        match MOUSE_CYCLE {
-            0 => {
-                // Sync byte
-                if byte & (1 << 3) == 0 {
-                    MOUSE_CYCLE = 0;
-                    return;
-                }
-                MOUSE1 = byte;
-            }
-            1 => {
-                MOUSE2 = byte;
-            }
-            _ => {
-                let mouse3 = byte;
+    // 9-bit deltas, sign bits live in the first byte
+    let x = MOUSE_PACKET[1] as i32 - (((flags as i32) << 4) & 0x100);
+    let y = MOUSE_PACKET[2] as i32 - (((flags as i32) << 3) & 0x100);
 
-                let x = MOUSE2 as i8 as i32;
-                let y = mouse3 as i8 as i32;
+    GX += x;
+    GY -= y;
 
-                GX += x;
-                GY -= y;
+    if GX < 0 {
+        GX = 0;
+    }
+    if GY < 0 {
+        GY = 0;
+    }
+    if GX >= fb.width {
+        GX = fb.width - 1;
+    }
+    if GY >= fb.height {
+        GY = fb.height - 1;
+    }
 
-                if GX < 0 {
-                    GX = 0;
-                }
-                if GY < 0 {
-                    GY = 0;
-                }
-                if GX >= fb.width {
-                    GX = fb.width - 1;
-                }
-                if GY >= fb.height {
-                    GY = fb.height - 1;
-                }
+    let mut pressed = flags & 0x07;
+    let mut wheel = 0;
+    match MOUSE_ID {
+        MOUSE_ID_INTELLIMOUSE => {
+            wheel = MOUSE_PACKET[3] as i8 as i32;
+        }
+        MOUSE_ID_EXPLORER => {
+            // Low nibble is a 4-bit signed wheel delta, bits 4/5 are buttons 4/5
+            wheel = ((MOUSE_PACKET[3] << 4) as i8 >> 4) as i32;
+            pressed |= (MOUSE_PACKET[3] >> 1) & 0x18;
+        }
+        _ => {}
+    }
 
-                let left = (MOUSE1 & 0x01) != 0;
-                let right = (MOUSE1 & 0x02) != 0;
+    mouse_buttons(MOUSE_EVENT, core::ptr::addr_of_mut!(MOUSE_HELD), pressed);
 
-                if CLICKED_LEFT && !left {
-                    inputGenerateEvent(MOUSE_EVENT, EV_KEY, BTN_LEFT, 0);
-                }
-                if !CLICKED_LEFT && left {
-                    inputGenerateEvent(MOUSE_EVENT, EV_KEY, BTN_LEFT, 1);
-                }
+    inputGenerateEvent(MOUSE_EVENT, EV_REL, REL_X, x);
+    inputGenerateEvent(MOUSE_EVENT, EV_REL, REL_Y, -y);
+    if wheel != 0 {
+        inputGenerateEvent(MOUSE_EVENT, EV_REL, REL_WHEEL, -wheel);
+    }
+    inputGenerateEvent(MOUSE_EVENT, EV_SYN, SYN_REPORT, 0);
+}
 
-                if CLICKED_RIGHT && !right {
-                    inputGenerateEvent(MOUSE_EVENT, EV_KEY, BTN_RIGHT, 0);
-                }
-                if !CLICKED_RIGHT && right {
-                    inputGenerateEvent(MOUSE_EVENT, EV_KEY, BTN_RIGHT, 1);
-                }
+#[no_mangle]
+pub extern "C" fn mouse_irq() {
+    unsafe {
+        let byte = mouse_read();
 
-                CLICKED_LEFT = left;
-                CLICKED_RIGHT = right;
+        // Sync byte
+        if MOUSE_CYCLE == 0 && byte & (1 << 3) == 0 {
+            return;
+        }
 
-                inputGenerateEvent(MOUSE_EVENT, EV_REL, REL_X, x);
-                inputGenerateEvent(MOUSE_EVENT, EV_REL, REL_Y, -y);
-                inputGenerateEvent(MOUSE_EVENT, EV_SYN, SYN_REPORT, 0);
-            }
+        MOUSE_PACKET[MOUSE_CYCLE as usize] = byte;
+        MOUSE_CYCLE += 1;
+        if MOUSE_CYCLE < mouse_packet_size() {
+            return;
         }
+        MOUSE_CYCLE = 0;
 
-        MOUSE_CYCLE = (MOUSE_CYCLE + 1) % 3;
+        if VMMOUSE_ACTIVE {
+            vmmouse_poll();
+        } else {
+            mouse_packet();
+        }
     }
 }
 
@@ -232,6 +455,22 @@ unsafe fn bitmap_set(map: &mut [u8], bit: usize) {
     }
 }
 
+unsafe fn ioctl_bits(out: usize, arg: *mut u8, size: usize) -> usize {
+    let len = min(core::mem::size_of::<usize>(), size);
+    copy_nonoverlapping(&out as *const _ as *const u8, arg, len);
+    len
+}
+
+unsafe fn ioctl_keys(buttons: usize, arg: *mut u8, size: usize) -> usize {
+    let mut map = [0u8; 96];
+    for code in BUTTONS.iter().take(buttons) {
+        bitmap_set(&mut map, *code as usize);
+    }
+    let len = min(map.len(), size);
+    copy_nonoverlapping(map.as_ptr(), arg, len);
+    len
+}
+
 //
 // ===== evdev ioctl handler =====
 //
@@ -247,42 +486,58 @@ pub extern "C" fn mouse_event_bit(
         let size = ((request >> 16) & 0x3fff) as usize;
 
         match number {
-            0x20 => {
-                let out: usize = (1 << EV_SYN) | (1 << EV_KEY) | (1 << EV_REL);
-                let len = min(core::mem::size_of::<usize>(), size);
-                copy_nonoverlapping(
-                    &out as *const _ as *const u8,
-                    arg,
-                    len,
-                );
-                len
+            0x20 => ioctl_bits((1 << EV_SYN) | (1 << EV_KEY) | (1 << EV_REL), arg, size),
+
+            n if n == 0x20 + EV_REL as usize => {
+                let mut out: usize = (1 << REL_X) | (1 << REL_Y);
+                if MOUSE_ID >= MOUSE_ID_INTELLIMOUSE {
+                    out |= 1 << REL_WHEEL;
+                }
+                ioctl_bits(out, arg, size)
             }
 
-            0x20 + EV_REL as usize => {
-                let out: usize = (1 << REL_X) | (1 << REL_Y);
-                let len = min(core::mem::size_of::<usize>(), size);
-                copy_nonoverlapping(
-                    &out as *const _ as *const u8,
-                    arg,
-                    len,
-                );
-                len
+            n if n == 0x20 + EV_KEY as usize => {
+                let buttons = if MOUSE_ID == MOUSE_ID_EXPLORER { 5 } else { 3 };
+                ioctl_keys(buttons, arg, size)
             }
 
-            0x20 + EV_KEY as usize => {
-                let mut map = [0u8; 96];
-                bitmap_set(&mut map, BTN_LEFT as usize);
-                bitmap_set(&mut map, BTN_RIGHT as usize);
-                let len = min(map.len(), size);
-                copy_nonoverlapping(map.as_ptr(), arg, len);
-                len
+            _ => 0,
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn vmmouse_event_bit(
+    _fd: *mut OpenFile,
+    request: u64,
+    arg: *mut u8,
+) -> usize {
+    unsafe {
+        let number = (request & 0xff) as usize;
+        let size = ((request >> 16) & 0x3fff) as usize;
+
+        match number {
+            0x20 => ioctl_bits(
+                (1 << EV_SYN) | (1 << EV_KEY) | (1 << EV_REL) | (1 << EV_ABS),
+                arg,
+                size,
+            ),
+
+            n if n == 0x20 + EV_REL as usize => ioctl_bits(1 << REL_WHEEL, arg, size),
+
+            n if n == 0x20 + EV_ABS as usize => {
+                ioctl_bits((1 << ABS_X) | (1 << ABS_Y), arg, size)
             }
 
-            0x40 + EV_ABS as usize => {
+            n if n == 0x20 + EV_KEY as usize => ioctl_keys(3, arg, size),
+
+            // EVIOCGABS(ABS_X / ABS_Y)
+            n if n == 0x40 + ABS_X as usize || n == 0x40 + ABS_Y as usize => {
                 let info = arg as *mut InputAbsInfo;
                 write_bytes(info, 0, 1);
+                (*info).value = VMMOUSE_ABS[n - 0x40];
                 (*info).minimum = 0;
-                (*info).maximum = fb.width;
+                (*info).maximum = VMMOUSE_MAX;
                 0
             }
 
@@ -325,12 +580,28 @@ pub unsafe fn initiate_mouse() {
     outportb(0x60, status);
 
     // Defaults
-    mouse_write(0xF6);
-    mouse_read();
+    mouse_command(0xF6);
+
+    // Wheel / extra buttons, changes the packet size to 4
+    MOUSE_ID = mouse_identify();
+
+    // Absolute pointer under VMware / QEMU
+    if vmmouse_detect() && vmmouse_enable() {
+        static VMMOUSE_NAME: &[u8] = b"VMware VMMouse\0";
+
+        VMMOUSE_EVENT = devInputEventSetup(VMMOUSE_NAME.as_ptr());
+        (*VMMOUSE_EVENT).inputid = InputId {
+            bustype: 0x05,   // BUS_PS2
+            vendor: 0x15ad,  // VMware
+            product: 0x0001,
+            version: 0x0100,
+        };
+        (*VMMOUSE_EVENT).eventBit = vmmouse_event_bit;
+        VMMOUSE_ACTIVE = true;
+    }
 
     // Enable mouse
-    mouse_write(0xF4);
-    mouse_read();
+    mouse_command(0xF4);
 
     let irq = ioApicRedirect(12, false);
     registerIRQhandler(irq, mouse_irq);
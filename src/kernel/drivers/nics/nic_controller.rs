@@ -28,7 +28,8 @@ extern "C" {
     fn sendE1000(nic: *mut NIC, data: *const u8, size: u32);
 
     // lwIP
-    fn tcpip_init(init: extern "C" fn(*mut core::ffi::c_void), arg: *mut core::ffi::c_void);
+    fn tcpip_init(init: unsafe extern "C" fn(*mut core::ffi::c_void), arg: *mut core::ffi::c_void);
+    fn tcpip_callback(f: unsafe extern "C" fn(*mut core::ffi::c_void), ctx: *mut core::ffi::c_void) -> err_t;
     fn tcpip_input(p: *mut pbuf, netif: *mut netif) -> err_t;
     fn netif_add(
         netif: *mut netif,
@@ -45,10 +46,30 @@ extern "C" {
     fn dhcp_supplied_address(netif: *mut netif) -> bool;
     fn sys_check_timeouts();
     fn etharp_output(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip4_addr) -> err_t;
+    fn ethip6_output(netif: *mut netif, p: *mut pbuf, ipaddr: *const ip6_addr) -> err_t;
+    fn netif_create_ip6_linklocal_address(netif: *mut netif, from_mac_48bit: u8);
+    fn igmp_start(netif: *mut netif) -> err_t;
 
     fn pbuf_alloc(layer: u8, len: u16, r#type: u8) -> *mut pbuf;
 
+    // loopback.rs
+    fn loopbackInitInThread(arg: *mut core::ffi::c_void);
+
+    // netlink.rs
+    fn netlinkInit();
+
+    // eth.rs, ipv4.rs (the in-tree stack)
+    fn netEthWants(packet: *const u8, len: u16) -> bool;
+    fn netEthSnoops(packet: *const u8, len: u16) -> bool;
+    fn netHelperStart();
+    fn netIpv4Register(driver: *mut core::ffi::c_void, mac: *const u8, mtu: u16);
+
+    // packet.rs
+    fn packetTap(netif: *mut netif, frame: *const u8, len: u16, outgoing: bool);
+    fn firewallOutput(netif: *mut netif, frame: *mut u8, len: u32) -> bool;
+
     static mut selectedNIC: *mut NIC;
+    static mut netif_default: *mut netif;
     static mut dsPCI: LinkedList;
 }
 
@@ -67,6 +88,8 @@ pub const NETIF_FLAG_BROADCAST: u8 = 1 << 0;
 pub const NETIF_FLAG_ETHARP: u8 = 1 << 1;
 pub const NETIF_FLAG_ETHERNET: u8 = 1 << 2;
 pub const NETIF_FLAG_LINK_UP: u8 = 1 << 3;
+pub const NETIF_FLAG_IGMP: u8 = 1 << 5;
+pub const NETIF_FLAG_MLD6: u8 = 1 << 6;
 
 pub const ETHARP_HWADDR_LEN: u8 = 6;
 
@@ -75,18 +98,56 @@ pub struct ip4_addr {
     pub addr: u32,
 }
 
+#[repr(C)]
+pub struct ip6_addr {
+    pub addr: [u32; 4],
+    pub zone: u8,
+}
+
+pub const LWIP_IPV6_NUM_ADDRESSES: usize = 4;
+
+/// ip_addr_t: a dual-stack union (ip6 is the bigger member) & its type
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ip_addr_t {
+    pub addr: [u32; 4],
+    pub zone: u8,
+    _pad: [u8; 3],
+    pub ty: u8,
+}
+
+/// struct netif up to rs_count, laid out as lwIP has it with IPv6,
+/// LWIP_IPV6_AUTOCONFIG and the DHCP, ACD, IGMP & MLD6 client_data slots
 #[repr(C)]
 pub struct netif {
-    pub state: *mut core::ffi::c_void,
-    pub name: [u8; 2],
     pub next: *mut netif,
+
+    pub ip_addr: ip_addr_t,
+    pub netmask: ip_addr_t,
+    pub gw: ip_addr_t,
+
+    pub ip6_addr: [ip_addr_t; LWIP_IPV6_NUM_ADDRESSES],
+    pub ip6_addr_state: [u8; LWIP_IPV6_NUM_ADDRESSES],
+    pub ip6_addr_valid_life: [u32; LWIP_IPV6_NUM_ADDRESSES],
+    pub ip6_addr_pref_life: [u32; LWIP_IPV6_NUM_ADDRESSES],
+
+    pub input: Option<extern "C" fn(*mut pbuf, *mut netif) -> err_t>,
     pub output: Option<extern "C" fn(*mut netif, *mut pbuf, *const ip4_addr) -> err_t>,
     pub linkoutput: Option<extern "C" fn(*mut netif, *mut pbuf) -> err_t>,
-    pub input: extern "C" fn(*mut pbuf, *mut netif) -> err_t,
-    pub hwaddr_len: u8,
-    pub hwaddr: [u8; 6],
+    pub output_ip6: Option<extern "C" fn(*mut netif, *mut pbuf, *const ip6_addr) -> err_t>,
+
+    pub state: *mut core::ffi::c_void,
+    pub client_data: [*mut core::ffi::c_void; 4],
+
     pub mtu: u16,
+    pub mtu6: u16,
+    pub hwaddr: [u8; 6],
+    pub hwaddr_len: u8,
     pub flags: u8,
+    pub name: [u8; 2],
+    pub num: u8,
+    pub ip6_autoconfig_enabled: u8,
+    pub rs_count: u8,
 }
 
 #[repr(C)]
@@ -158,6 +219,11 @@ pub extern "C" fn lwipDummyInit(_netif: *mut netif) -> err_t {
 pub extern "C" fn initiateNetworking() {
     unsafe {
         selectedNIC = null_mut();
+
+        // the stack (and lo) comes up regardless of whether a NIC shows up
+        tcpip_init(loopbackInitInThread, null_mut());
+        netlinkInit();
+        netHelperStart();
         debugf(b"[networking] Ready to scan for NICs..\n\0".as_ptr());
     }
 }
@@ -189,6 +255,12 @@ pub extern "C" fn lwipOutput(netif: *mut netif, p: *mut pbuf) -> err_t {
             panic();
         }
 
+        // OUTPUT/POSTROUTING, which may also rewrite the source for SNAT
+        if !firewallOutput(netif, buf, total as u32) {
+            free(buf);
+            return ERR_OK;
+        }
+
         let pci = LinkedListSearch(&mut dsPCI, lwipOutputCb, netif);
         if pci.is_null() {
             debugf(b"[nics] Couldn't find netif!\n\0".as_ptr());
@@ -239,19 +311,36 @@ pub extern "C" fn lwipInitInThread(arg: *mut core::ffi::c_void) {
         );
 
         netif.output = Some(etharp_output);
+        netif.output_ip6 = Some(ethip6_output);
         netif.linkoutput = Some(lwipOutput);
-        netif_set_default(netif);
+        // route.rs picks the interface per destination, the default netif
+        // is just the last resort, so the first NIC keeps it
+        if netif_default.is_null() {
+            netif_set_default(netif);
+        }
 
         netif.hwaddr_len = ETHARP_HWADDR_LEN;
         netif.hwaddr = (*nic).MAC;
         netif.mtu = (*nic).mtu;
+        netif.mtu6 = (*nic).mtu;
         netif.flags = NETIF_FLAG_BROADCAST
             | NETIF_FLAG_ETHARP
             | NETIF_FLAG_ETHERNET
-            | NETIF_FLAG_LINK_UP;
+            | NETIF_FLAG_LINK_UP
+            | NETIF_FLAG_IGMP
+            | NETIF_FLAG_MLD6;
 
         netif_set_up(netif);
 
+        // netif_add() only starts IGMP for netifs that had the flag already
+        igmp_start(netif);
+
+        // IPv6: link-local address off the MAC, global ones through SLAAC
+        // once a router advertisement shows up (router solicitations go out
+        // on their own after netif_set_up())
+        netif_create_ip6_linklocal_address(netif, 1);
+        netif.ip6_autoconfig_enabled = 1;
+
         if dhcp_start(netif) != ERR_OK {
             debugf(b"[nic::lwip] DHCP failed!\n\0".as_ptr());
             panic();
@@ -274,7 +363,8 @@ pub extern "C" fn initiateNIC(device: *mut PCIdevice) {
             || initiateRTL8169(device)
             || initiateE1000(device)
         {
-            tcpip_init(lwipInitInThread, selectedNIC as *mut _);
+            netIpv4Register(selectedNIC as *mut _, (*selectedNIC).MAC.as_ptr(), (*selectedNIC).mtu);
+            tcpip_callback(lwipInitInThread, selectedNIC as *mut _);
         }
     }
 }
@@ -309,6 +399,8 @@ struct netPacketHeader {
 #[no_mangle]
 pub extern "C" fn sendPacketRaw(nic: *mut NIC, data: *const u8, size: u32) {
     unsafe {
+        packetTap(&mut (*nic).lwip, data, size as u16, true);
+
         match (*nic).r#type {
             NE2000 => sendNe2000(nic, data, size),
             RTL8139 => sendRTL8139(nic, data, size),
@@ -339,22 +431,65 @@ static mut netQueue: [QueuePacket; QUEUE_MAX] = unsafe {
 static netQueueRead: AtomicI32 = AtomicI32::new(0);
 static netQueueWrite: AtomicI32 = AtomicI32::new(0);
 
+// what the in-tree stack takes (netEthWants()) or wants a copy of
+// (netEthSnoops()), for its helper thread
+static mut nativeQueue: [QueuePacket; QUEUE_MAX] = unsafe {
+    core::mem::MaybeUninit::zeroed().assume_init()
+};
+
+static nativeQueueRead: AtomicI32 = AtomicI32::new(0);
+static nativeQueueWrite: AtomicI32 = AtomicI32::new(0);
+
+unsafe fn queuePush(queue: *mut QueuePacket, read: &AtomicI32, write: &AtomicI32,
+                    nic: *mut NIC, packet: *const u8, len: u16) {
+    let w = write.load(Ordering::Relaxed) as usize;
+    let r = read.load(Ordering::Acquire) as usize;
+
+    if (w + 1) % QUEUE_MAX == r {
+        debugf(b"[netqueue] Packet dropped!\n\0".as_ptr());
+        return;
+    }
+
+    let slot = &mut *queue.add(w);
+    slot.nic = nic;
+    slot.packetLength = len;
+    memcpy(slot.buff.as_mut_ptr(), packet, len as usize);
+
+    write.store(((w + 1) % QUEUE_MAX) as i32, Ordering::Release);
+}
+
 #[no_mangle]
 pub extern "C" fn netQueueAdd(nic: *mut NIC, packet: *const u8, len: u16) {
     unsafe {
-        let write = netQueueWrite.load(Ordering::Relaxed) as usize;
-        let read = netQueueRead.load(Ordering::Relaxed) as usize;
+        if netEthWants(packet, len) {
+            queuePush(nativeQueue.as_mut_ptr(), &nativeQueueRead, &nativeQueueWrite, nic, packet, len);
+        } else {
+            if netEthSnoops(packet, len) {
+                queuePush(nativeQueue.as_mut_ptr(), &nativeQueueRead, &nativeQueueWrite, nic, packet, len);
+            }
+            queuePush(netQueue.as_mut_ptr(), &netQueueRead, &netQueueWrite, nic, packet, len);
+        }
 
-        if (write + 1) % QUEUE_MAX == read {
-            debugf(b"[netqueue] Packet dropped!\n\0".as_ptr());
-            return;
+        packetTap(&mut (*nic).lwip, packet, len, false);
+    }
+}
+
+/// Takes the oldest frame off the in-tree stack's queue into `buff` (room
+/// for a QueuePacket's worth), 0 when there's none
+#[no_mangle]
+pub extern "C" fn nativeQueuePop(nic: *mut *mut NIC, buff: *mut u8) -> u16 {
+    unsafe {
+        let r = nativeQueueRead.load(Ordering::Relaxed) as usize;
+        if r == nativeQueueWrite.load(Ordering::Acquire) as usize {
+            return 0;
         }
 
-        let slot = &mut netQueue[write];
-        slot.nic = nic;
-        slot.packetLength = len;
-        memcpy(slot.buff.as_mut_ptr(), packet, len as usize);
+        let slot = &nativeQueue[r];
+        *nic = slot.nic;
+        memcpy(buff, slot.buff.as_ptr(), slot.packetLength as usize);
+        let len = slot.packetLength;
 
-        netQueueWrite.store(((write + 1) % QUEUE_MAX) as i32, Ordering::Release);
+        nativeQueueRead.store(((r + 1) % QUEUE_MAX) as i32, Ordering::Release);
+        len
     }
 }
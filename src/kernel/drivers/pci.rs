@@ -20,6 +20,10 @@ extern "C" {
     fn initiateNIC(dev: *const PCIdevice);
     fn initiateAHCI(dev: *const PCIdevice);
     fn initiateVMWareSvga2(dev: *const PCIdevice);
+    fn initiateBGA(dev: *const PCIdevice);
+    fn initiateXHCI(dev: *const PCIdevice);
+    fn initiateHDA(dev: *const PCIdevice);
+    fn initiateAC97(dev: *const PCIdevice);
 
     static mut dsPCI: LinkedList;
 }
@@ -40,6 +44,14 @@ const PCI_DEVICE_GENERAL: u8 = 0x0;
 const PCI_CLASS_CODE_NETWORK_CONTROLLER: u8 = 0x02;
 const PCI_CLASS_CODE_MASS_STORAGE_CONTROLLER: u8 = 0x01;
 const PCI_CLASS_CODE_DISPLAY_CONTROLLER: u8 = 0x03;
+const PCI_CLASS_CODE_MULTIMEDIA_CONTROLLER: u8 = 0x04;
+const PCI_CLASS_CODE_SERIAL_BUS_CONTROLLER: u8 = 0x0C;
+
+const PCI_SUBCLASS_USB: u8 = 0x03;
+const PCI_PROG_IF_XHCI: u8 = 0x30;
+
+const PCI_SUBCLASS_AUDIO: u8 = 0x01;
+const PCI_SUBCLASS_HDA: u8 = 0x03;
 
 //
 // PCI register offsets
@@ -65,12 +77,13 @@ const PCI_PRIMARY_BUS_NUM: u8 = 0x18;
 // ===== Helper macros (functions in Rust) =====
 //
 
+// EXPORT_BYTE(): the first (lower) byte of a config word, or the second
 #[inline]
-fn export_byte(word: u16, high: bool) -> u8 {
-    if high {
-        (word >> 8) as u8
-    } else {
+fn export_byte(word: u16, first: bool) -> u8 {
+    if first {
         (word & 0xFF) as u8
+    } else {
+        (word >> 8) as u8
     }
 }
 
@@ -302,6 +315,19 @@ pub unsafe fn initiate_pci() {
                     }
                     PCI_CLASS_CODE_DISPLAY_CONTROLLER => {
                         initiateVMWareSvga2(device);
+                        initiateBGA(device);
+                    }
+                    PCI_CLASS_CODE_MULTIMEDIA_CONTROLLER => match (*device).subclass_id {
+                        PCI_SUBCLASS_HDA => initiateHDA(device),
+                        PCI_SUBCLASS_AUDIO => initiateAC97(device),
+                        _ => {}
+                    },
+                    PCI_CLASS_CODE_SERIAL_BUS_CONTROLLER => {
+                        if (*device).subclass_id == PCI_SUBCLASS_USB
+                            && (*device).progIF == PCI_PROG_IF_XHCI
+                        {
+                            initiateXHCI(device);
+                        }
                     }
                     _ => {}
                 }
@@ -0,0 +1,381 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::ffi::c_void;
+use core::ptr::write_bytes;
+
+use crate::sound::{
+    soundPeriodElapsed, soundRegisterBackend, SoundBackend, SoundStream, SNDRV_PCM_RATE_11025,
+    SNDRV_PCM_RATE_16000, SNDRV_PCM_RATE_22050, SNDRV_PCM_RATE_32000, SNDRV_PCM_RATE_44100,
+    SNDRV_PCM_RATE_48000, SNDRV_PCM_RATE_8000,
+};
+
+//
+// ================= Externs =================
+//
+
+extern "C" {
+    fn debugf(fmt: *const u8, ...) -> i32;
+
+    fn malloc(size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    fn lookupPCIdevice(dev: *const PCIdevice) -> *mut PCI;
+    fn setupPCIdeviceDriver(pci: *mut PCI, driver: u32, category: u32);
+    fn GetGeneralDevice(dev: *const PCIdevice, out: *mut PCIgeneralDevice);
+    fn ConfigReadWord(bus: u8, slot: u8, func: u8, offset: u8) -> u16;
+    fn ConfigWriteDword(bus: u8, slot: u8, func: u8, offset: u8, val: u32);
+
+    fn ioApicPciRegister(dev: *const PCIdevice, info: *mut PCIgeneralDevice) -> u8;
+    fn registerIRQhandler(irq: u8, handler: extern "C" fn(*mut AsmPassedInterrupt))
+        -> *mut c_void;
+
+    fn VirtualAllocatePhysicallyContiguous(pages: usize) -> *mut u8;
+    fn VirtualToPhysical(addr: usize) -> usize;
+
+    fn inportb(port: u16) -> u8;
+    fn outportb(port: u16, value: u8);
+    fn inportw(port: u16) -> u16;
+    fn outportw(port: u16, value: u16);
+    fn inportl(port: u16) -> u32;
+    fn outportl(port: u16, value: u32);
+
+    fn sleep(ms: u32);
+
+    static timerTicks: u64;
+}
+
+//
+// ================= Basic structs =================
+//
+
+#[repr(C)]
+pub struct PCIdevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub command: u16,
+    pub status: u16,
+}
+
+#[repr(C)]
+pub struct PCIgeneralDevice {
+    pub bar: [u32; 6],
+    pub cardBusCISPtr: u32,
+    pub system_id: u16,
+    pub system_vendor_id: u16,
+    pub expROMaddr: u32,
+    pub capabilitiesPtr: u8,
+    pub interruptLine: u8,
+    pub interruptPIN: u8,
+    pub minGrant: u8,
+    pub maxLatency: u8,
+}
+
+#[repr(C)]
+pub struct PCI {
+    _ll: usize,
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub name: *mut u8,
+    pub driver: u32,
+    pub category: u32,
+    pub extra: *mut c_void,
+    pub irqHandler: *mut c_void,
+}
+
+#[repr(C)]
+pub struct AsmPassedInterrupt;
+
+//
+// ================= Constants =================
+//
+
+pub const PCI_DRIVER_AC97: u32 = 7;
+pub const PCI_DRIVER_CATEGORY_SOUND: u32 = 4;
+
+const BLOCK_SIZE: usize = 4096;
+
+// mixer (NAM, BAR0)
+const NAM_RESET: u16 = 0x00;
+const NAM_MASTER_VOLUME: u16 = 0x02;
+const NAM_PCM_OUT_VOLUME: u16 = 0x18;
+const NAM_EXT_AUDIO_ID: u16 = 0x28;
+const NAM_EXT_AUDIO_CTRL: u16 = 0x2A;
+const NAM_FRONT_DAC_RATE: u16 = 0x2C;
+
+const EXT_AUDIO_VRA: u16 = 1 << 0;
+
+// bus master (NABM, BAR1): the PCM out box and global registers
+const PO_BDBAR: u16 = 0x10;
+const PO_CIV: u16 = 0x14;
+const PO_LVI: u16 = 0x15;
+const PO_SR: u16 = 0x16;
+const PO_PICB: u16 = 0x18;
+const PO_CR: u16 = 0x1B;
+const GLOB_CNT: u16 = 0x2C;
+const GLOB_STA: u16 = 0x30;
+
+const SR_LVBCI: u16 = 1 << 2;
+const SR_BCIS: u16 = 1 << 3;
+const SR_FIFOE: u16 = 1 << 4;
+const SR_CLEAR: u16 = SR_LVBCI | SR_BCIS | SR_FIFOE;
+
+const CR_RPBM: u8 = 1 << 0;
+const CR_RR: u8 = 1 << 1;
+const CR_IOCE: u8 = 1 << 4;
+
+const GLOB_CNT_COLD_RESET: u32 = 1 << 1;
+const GLOB_STA_CODEC_READY: u32 = 1 << 8;
+
+// 0 attenuation, unmuted; PCM out a bit below to leave headroom
+const VOLUME_MAX: u16 = 0x0000;
+const VOLUME_PCM: u16 = 0x0808;
+
+// the box always walks all 32 descriptors, periods repeat over them
+const BDL_ENTRIES: usize = 32;
+const BDL_IOC: u16 = 1 << 15;
+
+const RESET_TIMEOUT: u64 = 100;
+
+//
+// ================= Controller =================
+//
+
+#[repr(C)]
+pub struct Ac97BdlEntry {
+    pub address: u32,
+    // in 16-bit samples
+    pub samples: u16,
+    pub flags: u16,
+}
+
+#[repr(C)]
+pub struct Ac97 {
+    pub nam: u16,
+    pub nabm: u16,
+    pub pci: *mut PCI,
+
+    pub vra: bool,
+    pub bdl: *mut Ac97BdlEntry,
+    pub bdlPhys: usize,
+
+    pub periods: usize,
+    pub periodBytes: usize,
+}
+
+static mut AC97: *mut Ac97 = core::ptr::null_mut();
+
+static mut AC97_BACKEND: SoundBackend = SoundBackend {
+    id: b"ICH\0".as_ptr(),
+    driver: b"ICH\0".as_ptr(),
+    name: b"Intel 82801AA-ICH\0".as_ptr(),
+    rates: SNDRV_PCM_RATE_48000,
+    channelsMin: 2,
+    channelsMax: 2,
+    periodBytesAlign: 4,
+    periodBytesMax: 64 * 1024,
+    periodsMax: BDL_ENTRIES as u32,
+    periodsPow2: true,
+    prepare: ac97_prepare,
+    start: ac97_start,
+    stop: ac97_stop,
+    position: ac97_position,
+};
+
+//
+// ================= Stream =================
+//
+
+unsafe fn ac97_reset_box(ac97: *mut Ac97) -> bool {
+    outportb((*ac97).nabm + PO_CR, 0);
+    outportb((*ac97).nabm + PO_CR, CR_RR);
+    let start = timerTicks;
+    while inportb((*ac97).nabm + PO_CR) & CR_RR != 0 {
+        if timerTicks > start + RESET_TIMEOUT {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    outportw((*ac97).nabm + PO_SR, SR_CLEAR);
+    true
+}
+
+unsafe fn ac97_prepare(stream: *const SoundStream) -> bool {
+    let ac97 = AC97;
+
+    ac97_stop();
+    if !ac97_reset_box(ac97) {
+        debugf(b"[ac97] PCM out didn't reset!\n\0".as_ptr());
+        return false;
+    }
+
+    if (*ac97).vra {
+        outportw((*ac97).nam + NAM_FRONT_DAC_RATE, (*stream).rate as u16);
+        if inportw((*ac97).nam + NAM_FRONT_DAC_RATE) as u32 != (*stream).rate {
+            return false;
+        }
+    } else if (*stream).rate != 48000 {
+        return false;
+    }
+
+    // the period count divides 32, so the ring lines up every lap
+    let periods = (*stream).periods as usize;
+    for i in 0..BDL_ENTRIES {
+        let entry = &mut *(*ac97).bdl.add(i);
+        entry.address = ((*stream).ringPhys + (i % periods) * (*stream).periodBytes) as u32;
+        entry.samples = ((*stream).periodBytes / 2) as u16;
+        entry.flags = BDL_IOC;
+    }
+    (*ac97).periods = periods;
+    (*ac97).periodBytes = (*stream).periodBytes;
+
+    outportl((*ac97).nabm + PO_BDBAR, (*ac97).bdlPhys as u32);
+    outportb((*ac97).nabm + PO_LVI, (BDL_ENTRIES - 1) as u8);
+    true
+}
+
+unsafe fn ac97_start() {
+    let ac97 = AC97;
+    // keep the last valid index one behind, so the box never stops on it
+    let civ = inportb((*ac97).nabm + PO_CIV);
+    outportb((*ac97).nabm + PO_LVI, civ.wrapping_sub(1) & (BDL_ENTRIES as u8 - 1));
+    outportw((*ac97).nabm + PO_SR, SR_CLEAR);
+    outportb((*ac97).nabm + PO_CR, CR_RPBM | CR_IOCE);
+}
+
+unsafe fn ac97_stop() {
+    let ac97 = AC97;
+    outportb((*ac97).nabm + PO_CR, inportb((*ac97).nabm + PO_CR) & !(CR_RPBM | CR_IOCE));
+}
+
+unsafe fn ac97_position() -> usize {
+    let ac97 = AC97;
+    if (*ac97).periods == 0 {
+        return 0;
+    }
+    let civ = inportb((*ac97).nabm + PO_CIV) as usize;
+    let remaining = (inportw((*ac97).nabm + PO_PICB) as usize * 2).min((*ac97).periodBytes);
+    (civ % (*ac97).periods) * (*ac97).periodBytes + (*ac97).periodBytes - remaining
+}
+
+extern "C" fn ac97_irq(_regs: *mut AsmPassedInterrupt) {
+    unsafe {
+        let ac97 = AC97;
+        if ac97.is_null() {
+            return;
+        }
+
+        let status = inportw((*ac97).nabm + PO_SR);
+        if status & SR_CLEAR == 0 {
+            return;
+        }
+        outportw((*ac97).nabm + PO_SR, status & SR_CLEAR);
+
+        if status & SR_BCIS != 0 {
+            let civ = inportb((*ac97).nabm + PO_CIV);
+            outportb((*ac97).nabm + PO_LVI, civ.wrapping_sub(1) & (BDL_ENTRIES as u8 - 1));
+            soundPeriodElapsed();
+        }
+    }
+}
+
+//
+// ================= Initialization =================
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn initiateAC97(device: *const PCIdevice) {
+    // one card is all the sound core drives
+    if !AC97.is_null() {
+        return;
+    }
+
+    let details = malloc(core::mem::size_of::<PCIgeneralDevice>()) as *mut PCIgeneralDevice;
+    GetGeneralDevice(device, details);
+    if (*details).bar[0] & 1 == 0 || (*details).bar[1] & 1 == 0 {
+        debugf(b"[ac97] Mixer/bus master aren't in I/O space!\n\0".as_ptr());
+        free(details as *mut u8);
+        return;
+    }
+
+    // I/O space and bus mastering
+    let (bus, slot, function) = ((*device).bus, (*device).slot, (*device).function);
+    let command = ConfigReadWord(bus, slot, function, 0x04) | 0b101;
+    let status = ConfigReadWord(bus, slot, function, 0x06);
+    ConfigWriteDword(bus, slot, function, 0x04, ((status as u32) << 16) | command as u32);
+
+    let ac97 = malloc(core::mem::size_of::<Ac97>()) as *mut Ac97;
+    write_bytes(ac97, 0, 1);
+    (*ac97).nam = ((*details).bar[0] & !0b11) as u16;
+    (*ac97).nabm = ((*details).bar[1] & !0b11) as u16;
+
+    // out of cold reset, then wait for the codec
+    outportl((*ac97).nabm + GLOB_CNT, GLOB_CNT_COLD_RESET);
+    sleep(20);
+    let start = timerTicks;
+    while inportl((*ac97).nabm + GLOB_STA) & GLOB_STA_CODEC_READY == 0 {
+        if timerTicks > start + RESET_TIMEOUT {
+            debugf(b"[ac97] Codec isn't ready!\n\0".as_ptr());
+            free(details as *mut u8);
+            free(ac97 as *mut u8);
+            return;
+        }
+        core::hint::spin_loop();
+    }
+
+    outportw((*ac97).nam + NAM_RESET, 0);
+    outportw((*ac97).nam + NAM_MASTER_VOLUME, VOLUME_MAX);
+    outportw((*ac97).nam + NAM_PCM_OUT_VOLUME, VOLUME_PCM);
+
+    if inportw((*ac97).nam + NAM_EXT_AUDIO_ID) & EXT_AUDIO_VRA != 0 {
+        let control = inportw((*ac97).nam + NAM_EXT_AUDIO_CTRL);
+        outportw((*ac97).nam + NAM_EXT_AUDIO_CTRL, control | EXT_AUDIO_VRA);
+        (*ac97).vra = inportw((*ac97).nam + NAM_EXT_AUDIO_CTRL) & EXT_AUDIO_VRA != 0;
+    }
+    if (*ac97).vra {
+        AC97_BACKEND.rates = SNDRV_PCM_RATE_8000
+            | SNDRV_PCM_RATE_11025
+            | SNDRV_PCM_RATE_16000
+            | SNDRV_PCM_RATE_22050
+            | SNDRV_PCM_RATE_32000
+            | SNDRV_PCM_RATE_44100
+            | SNDRV_PCM_RATE_48000;
+    }
+
+    // the descriptor list and the sound ring both need 32-bit addresses
+    let bdl = VirtualAllocatePhysicallyContiguous(1);
+    write_bytes(bdl, 0, BLOCK_SIZE);
+    (*ac97).bdl = bdl as *mut Ac97BdlEntry;
+    (*ac97).bdlPhys = VirtualToPhysical(bdl as usize);
+
+    let pci = lookupPCIdevice(device);
+    (*ac97).pci = pci;
+    if !pci.is_null() {
+        setupPCIdeviceDriver(pci, PCI_DRIVER_AC97, PCI_DRIVER_CATEGORY_SOUND);
+        (*pci).extra = ac97 as *mut c_void;
+    }
+    AC97 = ac97;
+
+    if !ac97_reset_box(ac97) {
+        debugf(b"[ac97] PCM out didn't reset!\n\0".as_ptr());
+    }
+
+    let irq = ioApicPciRegister(device, details);
+    let handler = registerIRQhandler(irq, ac97_irq);
+    if !pci.is_null() {
+        (*pci).irqHandler = handler;
+    }
+    free(details as *mut u8);
+
+    debugf(
+        b"[ac97] Codec up, variable rate %s\n\0".as_ptr(),
+        if (*ac97).vra { b"yes\0".as_ptr() } else { b"no\0".as_ptr() },
+    );
+    soundRegisterBackend(core::ptr::addr_of!(AC97_BACKEND));
+}
@@ -0,0 +1,825 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::ffi::c_void;
+use core::ptr::{read_volatile, write_bytes, write_volatile};
+
+use crate::sound::{
+    soundPeriodElapsed, soundRegisterBackend, SoundBackend, SoundStream, SNDRV_PCM_RATE_11025,
+    SNDRV_PCM_RATE_16000, SNDRV_PCM_RATE_176400, SNDRV_PCM_RATE_192000, SNDRV_PCM_RATE_22050,
+    SNDRV_PCM_RATE_32000, SNDRV_PCM_RATE_44100, SNDRV_PCM_RATE_48000, SNDRV_PCM_RATE_8000,
+    SNDRV_PCM_RATE_88200, SNDRV_PCM_RATE_96000,
+};
+
+//
+// ================= Externs =================
+//
+
+extern "C" {
+    fn debugf(fmt: *const u8, ...) -> i32;
+
+    fn malloc(size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    fn lookupPCIdevice(dev: *const PCIdevice) -> *mut PCI;
+    fn setupPCIdeviceDriver(pci: *mut PCI, driver: u32, category: u32);
+    fn GetGeneralDevice(dev: *const PCIdevice, out: *mut PCIgeneralDevice);
+    fn ConfigReadWord(bus: u8, slot: u8, func: u8, offset: u8) -> u16;
+    fn ConfigWriteDword(bus: u8, slot: u8, func: u8, offset: u8, val: u32);
+
+    fn ioApicPciRegister(dev: *const PCIdevice, info: *mut PCIgeneralDevice) -> u8;
+    fn registerIRQhandler(irq: u8, handler: extern "C" fn(*mut AsmPassedInterrupt))
+        -> *mut c_void;
+
+    fn VirtualAllocatePhysicallyContiguous(pages: usize) -> *mut u8;
+    fn VirtualToPhysical(addr: usize) -> usize;
+    fn VirtualMap(virt: usize, phys: usize, flags: u64);
+
+    fn sleep(ms: u32);
+
+    static bootloader: BootloaderInfo;
+    static timerTicks: u64;
+}
+
+//
+// ================= Basic structs =================
+//
+
+#[repr(C)]
+pub struct BootloaderInfo {
+    pub hhdmOffset: usize,
+}
+
+#[repr(C)]
+pub struct PCIdevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub command: u16,
+    pub status: u16,
+}
+
+#[repr(C)]
+pub struct PCIgeneralDevice {
+    pub bar: [u32; 6],
+    pub cardBusCISPtr: u32,
+    pub system_id: u16,
+    pub system_vendor_id: u16,
+    pub expROMaddr: u32,
+    pub capabilitiesPtr: u8,
+    pub interruptLine: u8,
+    pub interruptPIN: u8,
+    pub minGrant: u8,
+    pub maxLatency: u8,
+}
+
+#[repr(C)]
+pub struct PCI {
+    _ll: usize,
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub name: *mut u8,
+    pub driver: u32,
+    pub category: u32,
+    pub extra: *mut c_void,
+    pub irqHandler: *mut c_void,
+}
+
+#[repr(C)]
+pub struct AsmPassedInterrupt;
+
+//
+// ================= Constants =================
+//
+
+pub const PCI_DRIVER_HDA: u32 = 6;
+pub const PCI_DRIVER_CATEGORY_SOUND: u32 = 4;
+
+const BLOCK_SIZE: usize = 4096;
+
+const PF_RW: u64 = 1 << 1;
+const PF_CACHE_DISABLE: u64 = 1 << 4;
+
+// controller registers
+const GCAP: usize = 0x00;
+const GCTL: usize = 0x08;
+const STATESTS: usize = 0x0E;
+const INTCTL: usize = 0x20;
+const INTSTS: usize = 0x24;
+const CORBLBASE: usize = 0x40;
+const CORBUBASE: usize = 0x44;
+const CORBWP: usize = 0x48;
+const CORBRP: usize = 0x4A;
+const CORBCTL: usize = 0x4C;
+const CORBSIZE: usize = 0x4E;
+const RIRBLBASE: usize = 0x50;
+const RIRBUBASE: usize = 0x54;
+const RIRBWP: usize = 0x58;
+const RINTCNT: usize = 0x5A;
+const RIRBCTL: usize = 0x5C;
+const RIRBSTS: usize = 0x5D;
+const RIRBSIZE: usize = 0x5E;
+const SD_BASE: usize = 0x80;
+const SD_SIZE: usize = 0x20;
+
+const GCTL_CRST: u32 = 1 << 0;
+const INTCTL_GIE: u32 = 1 << 31;
+const CORBRP_RST: u16 = 1 << 15;
+const RIRBWP_RST: u16 = 1 << 15;
+const CORBCTL_RUN: u8 = 1 << 1;
+const RIRBCTL_RUN: u8 = 1 << 1;
+const RIRBSTS_CLEAR: u8 = 0x05;
+
+// stream descriptor registers
+const SD_CTL: usize = 0x00;
+const SD_STS: usize = 0x03;
+const SD_LPIB: usize = 0x04;
+const SD_CBL: usize = 0x08;
+const SD_LVI: usize = 0x0C;
+const SD_FMT: usize = 0x12;
+const SD_BDPL: usize = 0x18;
+const SD_BDPU: usize = 0x1C;
+
+const SD_CTL_SRST: u32 = 1 << 0;
+const SD_CTL_RUN: u32 = 1 << 1;
+const SD_CTL_IOCE: u32 = 1 << 2;
+const SD_STS_BCIS: u8 = 1 << 2;
+const SD_STS_FIFOE: u8 = 1 << 3;
+const SD_STS_DESE: u8 = 1 << 4;
+
+const STREAM_TAG: u32 = 1;
+
+// verbs
+const VERB_GET_PARAM: u32 = 0xF00;
+const VERB_GET_CONN_LIST: u32 = 0xF02;
+const VERB_GET_CONFIG_DEFAULT: u32 = 0xF1C;
+const VERB_SET_CONN_SELECT: u32 = 0x701;
+const VERB_SET_POWER_STATE: u32 = 0x705;
+const VERB_SET_STREAM: u32 = 0x706;
+const VERB_SET_PIN_CTL: u32 = 0x707;
+const VERB_SET_EAPD: u32 = 0x70C;
+const VERB_SET_FORMAT: u32 = 0x2;
+const VERB_SET_AMP: u32 = 0x3;
+
+const PARAM_NODE_COUNT: u32 = 0x04;
+const PARAM_FG_TYPE: u32 = 0x05;
+const PARAM_WIDGET_CAPS: u32 = 0x09;
+const PARAM_PCM: u32 = 0x0A;
+const PARAM_PIN_CAPS: u32 = 0x0C;
+const PARAM_IN_AMP_CAPS: u32 = 0x0D;
+const PARAM_CONN_LIST_LEN: u32 = 0x0E;
+const PARAM_OUT_AMP_CAPS: u32 = 0x12;
+
+const FG_TYPE_AUDIO: u32 = 0x01;
+
+const WIDGET_OUTPUT: u32 = 0x0;
+const WIDGET_MIXER: u32 = 0x2;
+const WIDGET_PIN: u32 = 0x4;
+
+const WCAP_IN_AMP: u32 = 1 << 1;
+const WCAP_OUT_AMP: u32 = 1 << 2;
+const WCAP_AMP_OVERRIDE: u32 = 1 << 3;
+const WCAP_CONN_LIST: u32 = 1 << 8;
+const WCAP_POWER: u32 = 1 << 10;
+
+const PINCAP_OUT: u32 = 1 << 4;
+const PINCAP_EAPD: u32 = 1 << 16;
+
+const PIN_CTL_OUT: u32 = 0x40;
+const PIN_CTL_HP: u32 = 0x80;
+const EAPD_ENABLE: u32 = 0x02;
+
+const AMP_SET_OUTPUT: u32 = 1 << 15;
+const AMP_SET_INPUT: u32 = 1 << 14;
+const AMP_SET_LEFT_RIGHT: u32 = 0b11 << 12;
+
+// default device, config default bits 20..23
+const DEVICE_LINE_OUT: u32 = 0x0;
+const DEVICE_SPEAKER: u32 = 0x1;
+const DEVICE_HP_OUT: u32 = 0x2;
+const CONNECTIVITY_NONE: u32 = 0x1;
+
+const PCM_16BIT: u32 = 1 << 17;
+
+const COMMAND_TIMEOUT: u64 = 100;
+const PATH_DEPTH: usize = 8;
+
+// the BDL may hold up to 256, every period gets one
+const BDL_ENTRIES: u32 = 32;
+
+// HDA PCM rate bits 0..10, in SNDRV_PCM_RATE_* terms
+const HDA_RATES: [u32; 11] = [
+    SNDRV_PCM_RATE_8000,
+    SNDRV_PCM_RATE_11025,
+    SNDRV_PCM_RATE_16000,
+    SNDRV_PCM_RATE_22050,
+    SNDRV_PCM_RATE_32000,
+    SNDRV_PCM_RATE_44100,
+    SNDRV_PCM_RATE_48000,
+    SNDRV_PCM_RATE_88200,
+    SNDRV_PCM_RATE_96000,
+    SNDRV_PCM_RATE_176400,
+    SNDRV_PCM_RATE_192000,
+];
+
+//
+// ================= Controller =================
+//
+
+#[repr(C)]
+pub struct BdlEntry {
+    pub address: u64,
+    pub length: u32,
+    // bit 0: interrupt on completion
+    pub flags: u32,
+}
+
+#[repr(C)]
+pub struct Hda {
+    pub mmio: usize,
+    pub pci: *mut PCI,
+
+    pub corb: *mut u32,
+    pub corbEntries: u16,
+    pub rirb: *mut u64,
+    pub rirbEntries: u16,
+    pub rirbRead: u16,
+
+    // the output path: codec address, converter, pin
+    pub codec: u32,
+    pub dac: u32,
+    pub pin: u32,
+
+    // first output stream descriptor, and its bit in INTCTL/INTSTS
+    pub sd: usize,
+    pub sdIndex: u32,
+    pub bdl: *mut BdlEntry,
+    pub bdlPhys: usize,
+}
+
+static mut HDA: *mut Hda = core::ptr::null_mut();
+
+static mut HDA_BACKEND: SoundBackend = SoundBackend {
+    id: b"Intel\0".as_ptr(),
+    driver: b"HDA-Intel\0".as_ptr(),
+    name: b"HDA Intel\0".as_ptr(),
+    rates: 0,
+    channelsMin: 2,
+    channelsMax: 2,
+    periodBytesAlign: 128,
+    periodBytesMax: 64 * 1024,
+    periodsMax: BDL_ENTRIES,
+    periodsPow2: false,
+    prepare: hda_prepare,
+    start: hda_start,
+    stop: hda_stop,
+    position: hda_position,
+};
+
+//
+// ================= Registers =================
+//
+
+unsafe fn rd8(hda: *mut Hda, reg: usize) -> u8 {
+    read_volatile(((*hda).mmio + reg) as *const u8)
+}
+
+unsafe fn wr8(hda: *mut Hda, reg: usize, value: u8) {
+    write_volatile(((*hda).mmio + reg) as *mut u8, value)
+}
+
+unsafe fn rd16(hda: *mut Hda, reg: usize) -> u16 {
+    read_volatile(((*hda).mmio + reg) as *const u16)
+}
+
+unsafe fn wr16(hda: *mut Hda, reg: usize, value: u16) {
+    write_volatile(((*hda).mmio + reg) as *mut u16, value)
+}
+
+unsafe fn rd32(hda: *mut Hda, reg: usize) -> u32 {
+    read_volatile(((*hda).mmio + reg) as *const u32)
+}
+
+unsafe fn wr32(hda: *mut Hda, reg: usize, value: u32) {
+    write_volatile(((*hda).mmio + reg) as *mut u32, value)
+}
+
+unsafe fn wait_bits(hda: *mut Hda, reg: usize, mask: u32, set: bool, ms: u64) -> bool {
+    let start = timerTicks;
+    while (rd32(hda, reg) & mask != 0) != set {
+        if timerTicks > start + ms {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+unsafe fn wait_bits16(hda: *mut Hda, reg: usize, mask: u16, set: bool, ms: u64) -> bool {
+    let start = timerTicks;
+    while (rd16(hda, reg) & mask != 0) != set {
+        if timerTicks > start + ms {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+unsafe fn hda_map(phys: usize, length: usize) -> usize {
+    let virt = bootloader.hhdmOffset + phys;
+    let mut page = phys & !(BLOCK_SIZE - 1);
+    while page < phys + length {
+        VirtualMap(bootloader.hhdmOffset + page, page, PF_RW | PF_CACHE_DISABLE);
+        page += BLOCK_SIZE;
+    }
+    virt
+}
+
+unsafe fn dma_page(phys: &mut usize) -> *mut u8 {
+    let virt = VirtualAllocatePhysicallyContiguous(1);
+    write_bytes(virt, 0, BLOCK_SIZE);
+    *phys = VirtualToPhysical(virt as usize);
+    virt
+}
+
+unsafe fn hda_reset(hda: *mut Hda) -> bool {
+    wr32(hda, GCTL, rd32(hda, GCTL) & !GCTL_CRST);
+    if !wait_bits(hda, GCTL, GCTL_CRST, false, 100) {
+        return false;
+    }
+    wr32(hda, GCTL, rd32(hda, GCTL) | GCTL_CRST);
+    if !wait_bits(hda, GCTL, GCTL_CRST, true, 100) {
+        return false;
+    }
+
+    // codecs have 521us to ask for an address after reset
+    sleep(2);
+    true
+}
+
+//
+// ================= Codec commands =================
+//
+
+// Largest ring size the CORB/RIRB size register offers
+fn ring_entries(size: u8) -> (u8, u16) {
+    if size & (1 << 6) != 0 {
+        (0b10, 256)
+    } else if size & (1 << 5) != 0 {
+        (0b01, 16)
+    } else {
+        (0b00, 2)
+    }
+}
+
+unsafe fn hda_setup_rings(hda: *mut Hda) -> bool {
+    wr8(hda, CORBCTL, 0);
+    wr8(hda, RIRBCTL, 0);
+
+    // one page: the CORB (1KiB) and then the RIRB (2KiB)
+    let mut phys = 0;
+    let page = dma_page(&mut phys);
+    (*hda).corb = page as *mut u32;
+    (*hda).rirb = page.add(2048) as *mut u64;
+
+    let (corbSize, corbEntries) = ring_entries(rd8(hda, CORBSIZE));
+    wr8(hda, CORBSIZE, (rd8(hda, CORBSIZE) & !0b11) | corbSize);
+    (*hda).corbEntries = corbEntries;
+    wr32(hda, CORBLBASE, phys as u32);
+    wr32(hda, CORBUBASE, (phys as u64 >> 32) as u32);
+    wr16(hda, CORBWP, 0);
+    wr16(hda, CORBRP, CORBRP_RST);
+    wait_bits16(hda, CORBRP, CORBRP_RST, true, 10);
+    wr16(hda, CORBRP, 0);
+    if !wait_bits16(hda, CORBRP, CORBRP_RST, false, 10) {
+        return false;
+    }
+
+    let (rirbSize, rirbEntries) = ring_entries(rd8(hda, RIRBSIZE));
+    wr8(hda, RIRBSIZE, (rd8(hda, RIRBSIZE) & !0b11) | rirbSize);
+    (*hda).rirbEntries = rirbEntries;
+    wr32(hda, RIRBLBASE, (phys + 2048) as u32);
+    wr32(hda, RIRBUBASE, ((phys + 2048) as u64 >> 32) as u32);
+    wr16(hda, RIRBWP, RIRBWP_RST);
+    wr16(hda, RINTCNT, 0xff);
+    (*hda).rirbRead = 0;
+
+    wr8(hda, CORBCTL, CORBCTL_RUN);
+    wr8(hda, RIRBCTL, RIRBCTL_RUN);
+    true
+}
+
+// Sends one 20-bit verb (with its payload) to a node, polls for the answer
+unsafe fn hda_command(hda: *mut Hda, codec: u32, nid: u32, verb: u32) -> Option<u32> {
+    let write = (rd16(hda, CORBWP) & 0xff).wrapping_add(1) % (*hda).corbEntries;
+    write_volatile((*hda).corb.add(write as usize), (codec << 28) | (nid << 20) | verb);
+    wr16(hda, CORBWP, write);
+
+    let start = timerTicks;
+    while rd16(hda, RIRBWP) & 0xff == (*hda).rirbRead {
+        if timerTicks > start + COMMAND_TIMEOUT {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+
+    (*hda).rirbRead = ((*hda).rirbRead + 1) % (*hda).rirbEntries;
+    let response = read_volatile((*hda).rirb.add((*hda).rirbRead as usize));
+    // the response count would otherwise stop the CORB at RINTCNT
+    wr8(hda, RIRBSTS, RIRBSTS_CLEAR);
+    Some(response as u32)
+}
+
+unsafe fn hda_param(hda: *mut Hda, codec: u32, nid: u32, param: u32) -> u32 {
+    hda_command(hda, codec, nid, (VERB_GET_PARAM << 8) | param).unwrap_or(0)
+}
+
+unsafe fn hda_set(hda: *mut Hda, codec: u32, nid: u32, verb: u32, payload: u32) {
+    hda_command(hda, codec, nid, (verb << 8) | payload);
+}
+
+// Connection list entries of a widget, ranges expanded
+unsafe fn hda_connections(hda: *mut Hda, codec: u32, nid: u32, out: &mut [u32; 16]) -> usize {
+    let info = hda_param(hda, codec, nid, PARAM_CONN_LIST_LEN);
+    let length = (info & 0x7f) as usize;
+    let long = info & (1 << 7) != 0;
+    let (perResponse, bits) = if long { (2, 16) } else { (4, 8) };
+    let rangeFlag = 1 << (bits - 1);
+    let entryMask = rangeFlag - 1;
+
+    let mut count = 0;
+    let mut previous = 0;
+    let mut response = 0;
+    for i in 0..length {
+        if i % perResponse == 0 {
+            response = hda_command(hda, codec, nid, (VERB_GET_CONN_LIST << 8) | i as u32).unwrap_or(0);
+        }
+        let entry = (response >> ((i % perResponse) * bits)) & ((1 << bits) - 1);
+        let value = entry & entryMask;
+        let first = if entry & rangeFlag != 0 && previous != 0 { previous + 1 } else { value };
+        for node in first..=value {
+            if count == out.len() {
+                return count;
+            }
+            out[count] = node;
+            count += 1;
+        }
+        previous = value;
+    }
+    count
+}
+
+//
+// ================= Widget graph =================
+//
+
+fn widget_type(caps: u32) -> u32 {
+    (caps >> 20) & 0xf
+}
+
+// Amp caps of a widget, or the function group's defaults
+unsafe fn hda_amp_caps(hda: *mut Hda, codec: u32, afg: u32, nid: u32, caps: u32, param: u32) -> u32 {
+    if caps & WCAP_AMP_OVERRIDE != 0 {
+        hda_param(hda, codec, nid, param)
+    } else {
+        hda_param(hda, codec, afg, param)
+    }
+}
+
+// Depth first from the pin towards an output converter, fills in the path
+unsafe fn hda_find_dac(
+    hda: *mut Hda,
+    codec: u32,
+    nid: u32,
+    path: &mut [(u32, usize); PATH_DEPTH],
+    depth: usize,
+) -> Option<usize> {
+    let caps = hda_param(hda, codec, nid, PARAM_WIDGET_CAPS);
+    path[depth] = (nid, 0);
+    if widget_type(caps) == WIDGET_OUTPUT {
+        return Some(depth + 1);
+    }
+    if depth + 1 == PATH_DEPTH || caps & WCAP_CONN_LIST == 0 {
+        return None;
+    }
+
+    let mut connections = [0; 16];
+    let count = hda_connections(hda, codec, nid, &mut connections);
+    for (index, &next) in connections[..count].iter().enumerate() {
+        if path[..depth].iter().any(|&(node, _)| node == next) {
+            continue;
+        }
+        path[depth].1 = index;
+        if let Some(length) = hda_find_dac(hda, codec, next, path, depth + 1) {
+            return Some(length);
+        }
+    }
+    None
+}
+
+// Powers, selects and unmutes every widget from the converter to the pin
+unsafe fn hda_enable_path(hda: *mut Hda, codec: u32, afg: u32, path: &[(u32, usize)]) {
+    for &(nid, input) in path {
+        let caps = hda_param(hda, codec, nid, PARAM_WIDGET_CAPS);
+        if caps & WCAP_POWER != 0 {
+            hda_set(hda, codec, nid, VERB_SET_POWER_STATE, 0);
+        }
+
+        let kind = widget_type(caps);
+        if kind != WIDGET_OUTPUT && kind != WIDGET_MIXER {
+            hda_set(hda, codec, nid, VERB_SET_CONN_SELECT, input as u32);
+        }
+
+        // 0dB is the offset step, anything above it only clips
+        if caps & WCAP_IN_AMP != 0 && kind != WIDGET_OUTPUT {
+            let amp = hda_amp_caps(hda, codec, afg, nid, caps, PARAM_IN_AMP_CAPS);
+            let gain = amp & 0x7f;
+            let payload = AMP_SET_INPUT | AMP_SET_LEFT_RIGHT | ((input as u32) << 8) | gain;
+            hda_command(hda, codec, nid, (VERB_SET_AMP << 16) | payload);
+        }
+        if caps & WCAP_OUT_AMP != 0 {
+            let amp = hda_amp_caps(hda, codec, afg, nid, caps, PARAM_OUT_AMP_CAPS);
+            let gain = amp & 0x7f;
+            let payload = AMP_SET_OUTPUT | AMP_SET_LEFT_RIGHT | gain;
+            hda_command(hda, codec, nid, (VERB_SET_AMP << 16) | payload);
+        }
+    }
+}
+
+// Looks through a codec's audio function group for the best output pin
+// (line out, then speaker, then headphones) with a way to a converter
+unsafe fn hda_probe_codec(hda: *mut Hda, codec: u32) -> bool {
+    let groups = hda_param(hda, codec, 0, PARAM_NODE_COUNT);
+    let mut afg = 0;
+    for fg in ((groups >> 16) & 0xff)..((groups >> 16) & 0xff) + (groups & 0xff) {
+        if hda_param(hda, codec, fg, PARAM_FG_TYPE) & 0xff == FG_TYPE_AUDIO {
+            afg = fg;
+            break;
+        }
+    }
+    if afg == 0 {
+        return false;
+    }
+    hda_set(hda, codec, afg, VERB_SET_POWER_STATE, 0);
+
+    let widgets = hda_param(hda, codec, afg, PARAM_NODE_COUNT);
+    let first = (widgets >> 16) & 0xff;
+    let mut best: Option<(u32, u32, [(u32, usize); PATH_DEPTH], usize)> = None;
+    for nid in first..first + (widgets & 0xff) {
+        let caps = hda_param(hda, codec, nid, PARAM_WIDGET_CAPS);
+        if widget_type(caps) != WIDGET_PIN {
+            continue;
+        }
+        if hda_param(hda, codec, nid, PARAM_PIN_CAPS) & PINCAP_OUT == 0 {
+            continue;
+        }
+
+        let config = hda_command(hda, codec, nid, VERB_GET_CONFIG_DEFAULT << 8).unwrap_or(0);
+        let device = (config >> 20) & 0xf;
+        if (config >> 30) == CONNECTIVITY_NONE
+            || (device != DEVICE_LINE_OUT && device != DEVICE_SPEAKER && device != DEVICE_HP_OUT)
+        {
+            continue;
+        }
+        if let Some((rank, _, _, _)) = best {
+            if rank <= device {
+                continue;
+            }
+        }
+
+        let mut path = [(0, 0); PATH_DEPTH];
+        if let Some(length) = hda_find_dac(hda, codec, nid, &mut path, 0) {
+            best = Some((device, nid, path, length));
+        }
+    }
+
+    let Some((device, pin, path, length)) = best else {
+        return false;
+    };
+    let dac = path[length - 1].0;
+    hda_enable_path(hda, codec, afg, &path[..length]);
+
+    let pinCtl = if device == DEVICE_HP_OUT { PIN_CTL_OUT | PIN_CTL_HP } else { PIN_CTL_OUT };
+    hda_set(hda, codec, pin, VERB_SET_PIN_CTL, pinCtl);
+    if hda_param(hda, codec, pin, PARAM_PIN_CAPS) & PINCAP_EAPD != 0 {
+        hda_set(hda, codec, pin, VERB_SET_EAPD, EAPD_ENABLE);
+    }
+
+    // the converter's own rates, or the function group's
+    let mut pcm = hda_param(hda, codec, dac, PARAM_PCM);
+    if pcm == 0 {
+        pcm = hda_param(hda, codec, afg, PARAM_PCM);
+    }
+    if pcm != 0 && pcm & PCM_16BIT == 0 {
+        return false;
+    }
+    let mut rates = 0;
+    for (bit, &rate) in HDA_RATES.iter().enumerate() {
+        if pcm & (1 << bit) != 0 {
+            rates |= rate;
+        }
+    }
+    if rates == 0 {
+        rates = SNDRV_PCM_RATE_44100 | SNDRV_PCM_RATE_48000;
+    }
+    HDA_BACKEND.rates = rates;
+
+    (*hda).codec = codec;
+    (*hda).dac = dac;
+    (*hda).pin = pin;
+    debugf(
+        b"[hda] Codec %d: pin %d -> converter %d over %d widgets\n\0".as_ptr(),
+        codec,
+        pin,
+        dac,
+        length as u32,
+    );
+    true
+}
+
+//
+// ================= Stream =================
+//
+
+// SDnFMT/converter format: base rate, multiplier, divisor, 16 bits
+fn hda_format(rate: u32, channels: u32) -> Option<u32> {
+    let (base, baseBit) = if rate % 11025 == 0 { (44100, 1 << 14) } else { (48000, 0) };
+    for mult in 1..=4 {
+        for div in 1..=8 {
+            if base * mult == rate * div {
+                return Some(baseBit | ((mult - 1) << 11) | ((div - 1) << 8) | (1 << 4) | (channels - 1));
+            }
+        }
+    }
+    None
+}
+
+unsafe fn sd_ctl(hda: *mut Hda) -> u32 {
+    // the top byte is SDnSTS, write-one-to-clear
+    rd32(hda, (*hda).sd + SD_CTL) & 0x00ff_ffff
+}
+
+unsafe fn hda_prepare(stream: *const SoundStream) -> bool {
+    let hda = HDA;
+    let Some(format) = hda_format((*stream).rate, (*stream).channels) else {
+        return false;
+    };
+
+    hda_stop();
+    wr32(hda, (*hda).sd + SD_CTL, sd_ctl(hda) | SD_CTL_SRST);
+    wait_bits(hda, (*hda).sd + SD_CTL, SD_CTL_SRST, true, 10);
+    wr32(hda, (*hda).sd + SD_CTL, sd_ctl(hda) & !SD_CTL_SRST);
+    if !wait_bits(hda, (*hda).sd + SD_CTL, SD_CTL_SRST, false, 10) {
+        debugf(b"[hda] Stream didn't reset!\n\0".as_ptr());
+        return false;
+    }
+
+    // one buffer descriptor per period, each interrupting when done
+    for i in 0..(*stream).periods as usize {
+        let entry = &mut *(*hda).bdl.add(i);
+        entry.address = ((*stream).ringPhys + i * (*stream).periodBytes) as u64;
+        entry.length = (*stream).periodBytes as u32;
+        entry.flags = 1;
+    }
+
+    wr32(hda, (*hda).sd + SD_BDPL, (*hda).bdlPhys as u32);
+    wr32(hda, (*hda).sd + SD_BDPU, ((*hda).bdlPhys as u64 >> 32) as u32);
+    wr32(hda, (*hda).sd + SD_CBL, (*stream).bytes as u32);
+    wr16(hda, (*hda).sd + SD_LVI, ((*stream).periods - 1) as u16);
+    wr16(hda, (*hda).sd + SD_FMT, format as u16);
+    wr32(hda, (*hda).sd + SD_CTL, (sd_ctl(hda) & !(0xf << 20)) | (STREAM_TAG << 20));
+
+    hda_command(hda, (*hda).codec, (*hda).dac, (VERB_SET_FORMAT << 16) | format);
+    hda_set(hda, (*hda).codec, (*hda).dac, VERB_SET_STREAM, STREAM_TAG << 4);
+    true
+}
+
+unsafe fn hda_start() {
+    let hda = HDA;
+    wr8(hda, (*hda).sd + SD_STS, SD_STS_BCIS | SD_STS_FIFOE | SD_STS_DESE);
+    wr32(hda, (*hda).sd + SD_CTL, sd_ctl(hda) | SD_CTL_RUN | SD_CTL_IOCE);
+}
+
+unsafe fn hda_stop() {
+    let hda = HDA;
+    wr32(hda, (*hda).sd + SD_CTL, sd_ctl(hda) & !(SD_CTL_RUN | SD_CTL_IOCE));
+    wr8(hda, (*hda).sd + SD_STS, SD_STS_BCIS | SD_STS_FIFOE | SD_STS_DESE);
+}
+
+unsafe fn hda_position() -> usize {
+    rd32(HDA, (*HDA).sd + SD_LPIB) as usize
+}
+
+extern "C" fn hda_irq(_regs: *mut AsmPassedInterrupt) {
+    unsafe {
+        let hda = HDA;
+        if hda.is_null() || rd32(hda, INTSTS) & (1 << (*hda).sdIndex) == 0 {
+            return;
+        }
+
+        let status = rd8(hda, (*hda).sd + SD_STS);
+        wr8(hda, (*hda).sd + SD_STS, status);
+        if status & SD_STS_BCIS != 0 {
+            soundPeriodElapsed();
+        }
+    }
+}
+
+//
+// ================= Initialization =================
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn initiateHDA(device: *const PCIdevice) {
+    // one card is all the sound core drives
+    if !HDA.is_null() {
+        return;
+    }
+
+    let details = malloc(core::mem::size_of::<PCIgeneralDevice>()) as *mut PCIgeneralDevice;
+    GetGeneralDevice(device, details);
+
+    let bar0 = (*details).bar[0];
+    let mut phys = (bar0 & !0xf) as usize;
+    if (bar0 >> 1) & 0b11 == 0b10 {
+        phys |= ((*details).bar[1] as usize) << 32;
+    }
+
+    // memory space and bus mastering
+    let (bus, slot, function) = ((*device).bus, (*device).slot, (*device).function);
+    let command = ConfigReadWord(bus, slot, function, 0x04) | 0b110;
+    let status = ConfigReadWord(bus, slot, function, 0x06);
+    ConfigWriteDword(bus, slot, function, 0x04, ((status as u32) << 16) | command as u32);
+
+    let hda = malloc(core::mem::size_of::<Hda>()) as *mut Hda;
+    write_bytes(hda, 0, 1);
+    (*hda).mmio = hda_map(phys, BLOCK_SIZE * 4);
+
+    if !hda_reset(hda) {
+        debugf(b"[hda] Controller didn't reset!\n\0".as_ptr());
+        free(details as *mut u8);
+        free(hda as *mut u8);
+        return;
+    }
+    if !hda_setup_rings(hda) {
+        debugf(b"[hda] Couldn't set up the CORB/RIRB!\n\0".as_ptr());
+        free(details as *mut u8);
+        free(hda as *mut u8);
+        return;
+    }
+
+    let codecs = rd16(hda, STATESTS);
+    wr16(hda, STATESTS, codecs);
+    let mut found = false;
+    for codec in 0..15 {
+        if codecs & (1 << codec) != 0 && hda_probe_codec(hda, codec) {
+            found = true;
+            break;
+        }
+    }
+    if !found {
+        debugf(b"[hda] No codec with an output pin!\n\0".as_ptr());
+        free(details as *mut u8);
+        free(hda as *mut u8);
+        return;
+    }
+
+    // output streams come after the input ones
+    let gcap = rd16(hda, GCAP) as u32;
+    let inputs = (gcap >> 8) & 0xf;
+    if (gcap >> 12) & 0xf == 0 {
+        debugf(b"[hda] No output streams!\n\0".as_ptr());
+        free(details as *mut u8);
+        free(hda as *mut u8);
+        return;
+    }
+    (*hda).sdIndex = inputs;
+    (*hda).sd = SD_BASE + inputs as usize * SD_SIZE;
+    let mut bdlPhys = 0;
+    (*hda).bdl = dma_page(&mut bdlPhys) as *mut BdlEntry;
+    (*hda).bdlPhys = bdlPhys;
+
+    let pci = lookupPCIdevice(device);
+    (*hda).pci = pci;
+    if !pci.is_null() {
+        setupPCIdeviceDriver(pci, PCI_DRIVER_HDA, PCI_DRIVER_CATEGORY_SOUND);
+        (*pci).extra = hda as *mut c_void;
+    }
+    HDA = hda;
+
+    let irq = ioApicPciRegister(device, details);
+    let handler = registerIRQhandler(irq, hda_irq);
+    if !pci.is_null() {
+        (*pci).irqHandler = handler;
+    }
+    free(details as *mut u8);
+
+    wr32(hda, INTCTL, INTCTL_GIE | (1 << (*hda).sdIndex));
+    soundRegisterBackend(core::ptr::addr_of!(HDA_BACKEND));
+}
@@ -0,0 +1,1705 @@
+// Sound core: one ALSA-compatible playback PCM (/dev/snd/pcmC0D0p) and its
+// control device (/dev/snd/controlC0)
+// Copyright (C) 2025 kevin dan mathew
+
+#![no_std]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![allow(dead_code)]
+
+use core::cmp::min;
+use core::ffi::c_void;
+use core::ptr::{copy_nonoverlapping, null, null_mut, write_bytes};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// A single card with a single playback stream, interleaved S16_LE. The audio
+// lives in one physically contiguous ring the hardware loops over, cut into
+// periods; the controller interrupts at the end of each one and the driver
+// reports back through soundPeriodElapsed(). Writers (write()/WRITEI_FRAMES
+// or SYNC_PTR after drawing into an mmap()ed ring) move appl_ptr, the
+// hardware moves hw_ptr, and the usual ALSA rules decide about starting,
+// underruns and draining.
+
+// --------------------------------
+// Constants
+// --------------------------------
+
+const SNDRV_PCM_VERSION: i32 = (2 << 16) | (0 << 8) | 15;
+const SNDRV_CTL_VERSION: i32 = (2 << 16) | (0 << 8) | 8;
+
+const SNDRV_PCM_IOCTL_BASE: u64 = 0x41; // 'A'
+const SNDRV_CTL_IOCTL_BASE: u64 = 0x55; // 'U'
+
+const SNDRV_PCM_IOCTL_PVERSION: usize = 0x00;
+const SNDRV_PCM_IOCTL_INFO: usize = 0x01;
+const SNDRV_PCM_IOCTL_TSTAMP: usize = 0x02;
+const SNDRV_PCM_IOCTL_TTSTAMP: usize = 0x03;
+const SNDRV_PCM_IOCTL_USER_PVERSION: usize = 0x04;
+const SNDRV_PCM_IOCTL_HW_REFINE: usize = 0x10;
+const SNDRV_PCM_IOCTL_HW_PARAMS: usize = 0x11;
+const SNDRV_PCM_IOCTL_HW_FREE: usize = 0x12;
+const SNDRV_PCM_IOCTL_SW_PARAMS: usize = 0x13;
+const SNDRV_PCM_IOCTL_STATUS: usize = 0x20;
+const SNDRV_PCM_IOCTL_DELAY: usize = 0x21;
+const SNDRV_PCM_IOCTL_HWSYNC: usize = 0x22;
+const SNDRV_PCM_IOCTL_SYNC_PTR: usize = 0x23;
+const SNDRV_PCM_IOCTL_STATUS_EXT: usize = 0x24;
+const SNDRV_PCM_IOCTL_CHANNEL_INFO: usize = 0x32;
+const SNDRV_PCM_IOCTL_PREPARE: usize = 0x40;
+const SNDRV_PCM_IOCTL_RESET: usize = 0x41;
+const SNDRV_PCM_IOCTL_START: usize = 0x42;
+const SNDRV_PCM_IOCTL_DROP: usize = 0x43;
+const SNDRV_PCM_IOCTL_DRAIN: usize = 0x44;
+const SNDRV_PCM_IOCTL_PAUSE: usize = 0x45;
+const SNDRV_PCM_IOCTL_REWIND: usize = 0x46;
+const SNDRV_PCM_IOCTL_RESUME: usize = 0x47;
+const SNDRV_PCM_IOCTL_XRUN: usize = 0x48;
+const SNDRV_PCM_IOCTL_FORWARD: usize = 0x49;
+const SNDRV_PCM_IOCTL_WRITEI_FRAMES: usize = 0x50;
+const SNDRV_PCM_IOCTL_READI_FRAMES: usize = 0x51;
+const SNDRV_PCM_IOCTL_WRITEN_FRAMES: usize = 0x52;
+const SNDRV_PCM_IOCTL_READN_FRAMES: usize = 0x53;
+const SNDRV_PCM_IOCTL_LINK: usize = 0x60;
+const SNDRV_PCM_IOCTL_UNLINK: usize = 0x61;
+
+const SNDRV_CTL_IOCTL_PVERSION: usize = 0x00;
+const SNDRV_CTL_IOCTL_CARD_INFO: usize = 0x01;
+const SNDRV_CTL_IOCTL_ELEM_LIST: usize = 0x10;
+const SNDRV_CTL_IOCTL_ELEM_INFO: usize = 0x11;
+const SNDRV_CTL_IOCTL_ELEM_READ: usize = 0x12;
+const SNDRV_CTL_IOCTL_ELEM_WRITE: usize = 0x13;
+const SNDRV_CTL_IOCTL_SUBSCRIBE_EVENTS: usize = 0x16;
+const SNDRV_CTL_IOCTL_HWDEP_NEXT_DEVICE: usize = 0x20;
+const SNDRV_CTL_IOCTL_PCM_NEXT_DEVICE: usize = 0x30;
+const SNDRV_CTL_IOCTL_PCM_INFO: usize = 0x31;
+const SNDRV_CTL_IOCTL_PCM_PREFER_SUBDEVICE: usize = 0x32;
+const SNDRV_CTL_IOCTL_RAWMIDI_NEXT_DEVICE: usize = 0x40;
+const SNDRV_CTL_IOCTL_POWER: usize = 0xd0;
+const SNDRV_CTL_IOCTL_POWER_STATE: usize = 0xd1;
+
+const SNDRV_PCM_STATE_OPEN: i32 = 0;
+const SNDRV_PCM_STATE_SETUP: i32 = 1;
+const SNDRV_PCM_STATE_PREPARED: i32 = 2;
+const SNDRV_PCM_STATE_RUNNING: i32 = 3;
+const SNDRV_PCM_STATE_XRUN: i32 = 4;
+const SNDRV_PCM_STATE_DRAINING: i32 = 5;
+const SNDRV_PCM_STATE_PAUSED: i32 = 6;
+
+const SNDRV_PCM_STREAM_PLAYBACK: i32 = 0;
+
+const SNDRV_PCM_HW_PARAM_ACCESS: usize = 0;
+const SNDRV_PCM_HW_PARAM_FORMAT: usize = 1;
+const SNDRV_PCM_HW_PARAM_SUBFORMAT: usize = 2;
+const SNDRV_PCM_HW_PARAM_SAMPLE_BITS: usize = 8;
+const SNDRV_PCM_HW_PARAM_FRAME_BITS: usize = 9;
+const SNDRV_PCM_HW_PARAM_CHANNELS: usize = 10;
+const SNDRV_PCM_HW_PARAM_RATE: usize = 11;
+const SNDRV_PCM_HW_PARAM_PERIOD_TIME: usize = 12;
+const SNDRV_PCM_HW_PARAM_PERIOD_SIZE: usize = 13;
+const SNDRV_PCM_HW_PARAM_PERIOD_BYTES: usize = 14;
+const SNDRV_PCM_HW_PARAM_PERIODS: usize = 15;
+const SNDRV_PCM_HW_PARAM_BUFFER_TIME: usize = 16;
+const SNDRV_PCM_HW_PARAM_BUFFER_SIZE: usize = 17;
+const SNDRV_PCM_HW_PARAM_BUFFER_BYTES: usize = 18;
+const SNDRV_PCM_HW_PARAM_TICK_TIME: usize = 19;
+const SNDRV_PCM_HW_PARAM_FIRST_INTERVAL: usize = 8;
+const SNDRV_PCM_HW_PARAM_LAST_INTERVAL: usize = 19;
+
+const SNDRV_PCM_ACCESS_MMAP_INTERLEAVED: u32 = 0;
+const SNDRV_PCM_ACCESS_RW_INTERLEAVED: u32 = 3;
+const SNDRV_PCM_FORMAT_S16_LE: u32 = 2;
+const SNDRV_PCM_SUBFORMAT_STD: u32 = 0;
+
+const SNDRV_PCM_INFO_MMAP: u32 = 0x0000_0001;
+const SNDRV_PCM_INFO_MMAP_VALID: u32 = 0x0000_0002;
+const SNDRV_PCM_INFO_INTERLEAVED: u32 = 0x0000_0100;
+const SNDRV_PCM_INFO_BLOCK_TRANSFER: u32 = 0x0001_0000;
+const SNDRV_PCM_INFO_PAUSE: u32 = 0x0008_0000;
+
+const SNDRV_PCM_SYNC_PTR_HWSYNC: u32 = 1 << 0;
+const SNDRV_PCM_SYNC_PTR_APPL: u32 = 1 << 1;
+const SNDRV_PCM_SYNC_PTR_AVAIL_MIN: u32 = 1 << 2;
+
+const SNDRV_PCM_TSTAMP_TYPE_LAST: u32 = 2;
+
+const SNDRV_CTL_POWER_D0: i32 = 0;
+
+// snd_interval flag bits
+const SND_INTERVAL_OPENMIN: u32 = 1 << 0;
+const SND_INTERVAL_OPENMAX: u32 = 1 << 1;
+const SND_INTERVAL_INTEGER: u32 = 1 << 2;
+const SND_INTERVAL_EMPTY: u32 = 1 << 3;
+
+// mmap() offsets for the data ring, and the status/control pages we don't
+// offer (alsa-lib falls back to SYNC_PTR)
+const SNDRV_PCM_MMAP_OFFSET_DATA: usize = 0x0000_0000;
+
+// SNDRV_PCM_RATE_* bits, in order
+pub const SOUND_RATES: [u32; 13] = [
+    5512, 8000, 11025, 16000, 22050, 32000, 44100, 48000, 64000, 88200, 96000, 176400, 192000,
+];
+
+pub const SNDRV_PCM_RATE_8000: u32 = 1 << 1;
+pub const SNDRV_PCM_RATE_11025: u32 = 1 << 2;
+pub const SNDRV_PCM_RATE_16000: u32 = 1 << 3;
+pub const SNDRV_PCM_RATE_22050: u32 = 1 << 4;
+pub const SNDRV_PCM_RATE_32000: u32 = 1 << 5;
+pub const SNDRV_PCM_RATE_44100: u32 = 1 << 6;
+pub const SNDRV_PCM_RATE_48000: u32 = 1 << 7;
+pub const SNDRV_PCM_RATE_88200: u32 = 1 << 9;
+pub const SNDRV_PCM_RATE_96000: u32 = 1 << 10;
+pub const SNDRV_PCM_RATE_176400: u32 = 1 << 11;
+pub const SNDRV_PCM_RATE_192000: u32 = 1 << 12;
+
+// the ring, allocated once; what AC'97 can address is the limit (32-bit)
+const SOUND_RING_PAGES: usize = 32;
+const SOUND_RING_BYTES: usize = SOUND_RING_PAGES * PAGE_SIZE;
+const SOUND_PERIOD_BYTES_MIN: u32 = 256;
+const SOUND_SAMPLE_BITS: u32 = 16;
+
+const PAGE_SIZE: usize = 4096;
+
+const PF_RW: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_SHARED: u64 = 1 << 9;
+
+const MAP_FIXED: i32 = 0x10;
+
+const O_NONBLOCK: u32 = 0x800;
+const EPOLLOUT: i32 = 0x004;
+const EPOLLERR: i32 = 0x008;
+
+const ENOENT: isize = 2;
+const EINTR: isize = 4;
+const EIO: isize = 5;
+const ENXIO: isize = 6;
+const EAGAIN: isize = 11;
+const EBUSY: isize = 16;
+const ENODEV: isize = 19;
+const EINVAL: isize = 22;
+const ENOTTY: isize = 25;
+const EPIPE: isize = 32;
+const ENOSYS: isize = 38;
+const EBADFD: isize = 77;
+
+#[inline]
+const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+// --------------------------------
+// ALSA uapi structures
+// --------------------------------
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+#[repr(C)]
+pub struct snd_pcm_info {
+    pub device: u32,
+    pub subdevice: u32,
+    pub stream: i32,
+    pub card: i32,
+    pub id: [u8; 64],
+    pub name: [u8; 80],
+    pub subname: [u8; 32],
+    pub dev_class: i32,
+    pub dev_subclass: i32,
+    pub subdevices_count: u32,
+    pub subdevices_avail: u32,
+    pub sync: [u8; 16],
+    pub reserved: [u8; 64],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct snd_mask {
+    pub bits: [u32; 8],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq)]
+pub struct snd_interval {
+    pub min: u32,
+    pub max: u32,
+    // openmin:1, openmax:1, integer:1, empty:1
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct snd_pcm_hw_params {
+    pub flags: u32,
+    pub masks: [snd_mask; 3],
+    pub mres: [snd_mask; 5],
+    pub intervals: [snd_interval; 12],
+    pub ires: [snd_interval; 9],
+    pub rmask: u32,
+    pub cmask: u32,
+    pub info: u32,
+    pub msbits: u32,
+    pub rate_num: u32,
+    pub rate_den: u32,
+    pub fifo_size: u64,
+    pub reserved: [u8; 64],
+}
+
+#[repr(C)]
+pub struct snd_pcm_sw_params {
+    pub tstamp_mode: i32,
+    pub period_step: u32,
+    pub sleep_min: u32,
+    pub avail_min: u64,
+    pub xfer_align: u64,
+    pub start_threshold: u64,
+    pub stop_threshold: u64,
+    pub silence_threshold: u64,
+    pub silence_size: u64,
+    pub boundary: u64,
+    pub proto: u32,
+    pub tstamp_type: u32,
+    pub reserved: [u8; 56],
+}
+
+#[repr(C)]
+pub struct snd_pcm_status {
+    pub state: i32,
+    pub _pad: i32,
+    pub trigger_tstamp: timespec,
+    pub tstamp: timespec,
+    pub appl_ptr: u64,
+    pub hw_ptr: u64,
+    pub delay: i64,
+    pub avail: u64,
+    pub avail_max: u64,
+    pub overrange: u64,
+    pub suspended_state: i32,
+    pub audio_tstamp_data: u32,
+    pub audio_tstamp: timespec,
+    pub driver_tstamp: timespec,
+    pub audio_tstamp_accuracy: u32,
+    pub reserved: [u8; 20],
+}
+
+#[repr(C)]
+pub struct snd_pcm_mmap_status {
+    pub state: i32,
+    pub pad1: i32,
+    pub hw_ptr: u64,
+    pub tstamp: timespec,
+    pub suspended_state: i32,
+    pub audio_tstamp: timespec,
+}
+
+#[repr(C)]
+pub struct snd_pcm_mmap_control {
+    pub appl_ptr: u64,
+    pub avail_min: u64,
+}
+
+#[repr(C)]
+pub struct snd_pcm_sync_ptr {
+    pub flags: u32,
+    pub s: snd_pcm_mmap_status,
+    pub _sres: [u8; 8],
+    pub c: snd_pcm_mmap_control,
+    pub _cres: [u8; 48],
+}
+
+#[repr(C)]
+pub struct snd_xferi {
+    pub result: i64,
+    pub buf: *const u8,
+    pub frames: u64,
+}
+
+#[repr(C)]
+pub struct snd_ctl_card_info {
+    pub card: i32,
+    pub pad: i32,
+    pub id: [u8; 16],
+    pub driver: [u8; 16],
+    pub name: [u8; 32],
+    pub longname: [u8; 80],
+    pub reserved_: [u8; 16],
+    pub mixername: [u8; 80],
+    pub components: [u8; 128],
+}
+
+#[repr(C)]
+pub struct snd_ctl_elem_list {
+    pub offset: u32,
+    pub space: u32,
+    pub used: u32,
+    pub count: u32,
+    pub pids: u64,
+    pub reserved: [u8; 50],
+}
+
+// --------------------------------
+// Kernel structures
+// --------------------------------
+
+#[repr(C)]
+pub struct Spinlock {
+    _priv: u32,
+}
+
+#[repr(C)]
+pub struct TaskInfoPagedir {
+    pub LOCK_PD: Spinlock,
+    pub utilizedBy: i32,
+
+    pub heap_start: u64,
+    pub heap_end: u64,
+
+    pub mmap_start: u64,
+    pub mmap_end: u64,
+}
+
+#[repr(C)]
+pub struct Task {
+    pub id: u64,
+    pub infoPd: *mut TaskInfoPagedir,
+}
+
+#[repr(C)]
+pub struct OpenFile {
+    pub flags: u32,
+    pub dir: *mut c_void,
+}
+
+#[repr(C)]
+pub struct VfsHandlers {
+    pub open: Option<unsafe extern "C" fn(*mut u8, i32, i32, *mut OpenFile, *mut *mut u8) -> usize>,
+    pub duplicate: Option<unsafe extern "C" fn(*mut OpenFile, *mut OpenFile) -> bool>,
+    pub close: Option<unsafe extern "C" fn(*mut OpenFile) -> bool>,
+    pub read: Option<unsafe extern "C" fn(*mut OpenFile, *mut u8, usize) -> usize>,
+    pub write: Option<unsafe extern "C" fn(*mut OpenFile, *const u8, usize) -> usize>,
+    pub internalPoll: Option<unsafe extern "C" fn(*mut OpenFile, i32) -> i32>,
+    pub ioctl: Option<unsafe extern "C" fn(*mut OpenFile, u64, *mut u8) -> usize>,
+    pub mmap: Option<unsafe extern "C" fn(usize, usize, i32, i32, *mut OpenFile, usize) -> usize>,
+    pub reportKey: Option<unsafe extern "C" fn(*mut OpenFile) -> usize>,
+    pub stat: Option<unsafe extern "C" fn()>,
+}
+
+// --------------------------------
+// External kernel APIs
+// --------------------------------
+
+extern "C" {
+    static mut currentTask: *mut Task;
+    static timerTicks: u64;
+
+    fn spinlockAcquire(lock: *mut Spinlock);
+    fn spinlockRelease(lock: *mut Spinlock);
+
+    fn VirtualAllocatePhysicallyContiguous(pages: usize) -> *mut u8;
+    fn VirtualToPhysical(addr: usize) -> usize;
+    fn VirtualMap(virt: usize, phys: usize, flags: u64);
+
+    fn handControl();
+    fn signalsPendingQuick(task: *mut Task) -> bool;
+    fn pollInstanceRing(key: usize, events: i32);
+
+    fn fakefsFstat();
+    fn debugf(fmt: *const u8, ...) -> i32;
+}
+
+// --------------------------------
+// Drivers
+// --------------------------------
+
+// Where the hardware plays from, handed to the driver on PREPARE
+#[repr(C)]
+pub struct SoundStream {
+    pub ring: *mut u8,
+    pub ringPhys: usize,
+    // the ALSA buffer, periods * periodBytes
+    pub bytes: usize,
+    pub periodBytes: usize,
+    pub periods: u32,
+    pub rate: u32,
+    pub channels: u32,
+}
+
+// What an audio driver hands to the sound core. Samples are always S16_LE,
+// interleaved.
+#[repr(C)]
+pub struct SoundBackend {
+    // NUL-terminated: card id, driver name (alsa-lib picks its config by
+    // it) and a human readable one
+    pub id: *const u8,
+    pub driver: *const u8,
+    pub name: *const u8,
+
+    // SNDRV_PCM_RATE_* bits
+    pub rates: u32,
+    pub channelsMin: u32,
+    pub channelsMax: u32,
+
+    // limits of the controller's buffer descriptor lists
+    pub periodBytesAlign: u32,
+    pub periodBytesMax: u32,
+    pub periodsMax: u32,
+    pub periodsPow2: bool,
+
+    // programs the stream (not running yet)
+    pub prepare: unsafe fn(stream: *const SoundStream) -> bool,
+    // starts/stops DMA where it left off, stop() may run in an interrupt
+    pub start: unsafe fn(),
+    pub stop: unsafe fn(),
+    // bytes into the ring the hardware has gotten to
+    pub position: unsafe fn() -> usize,
+}
+
+// --------------------------------
+// PCM state
+// --------------------------------
+
+#[repr(C)]
+pub struct SoundPcm {
+    pub state: i32,
+    pub opens: usize,
+
+    pub stream: SoundStream,
+    pub access: u32,
+    pub frameBytes: usize,
+    pub periodSize: u64,
+    pub bufferSize: u64,
+    pub boundary: u64,
+
+    // frames, never wrapped here; userspace sees them modulo the boundary
+    pub hwPtr: u64,
+    pub hwBase: u64,
+    pub applPtr: u64,
+    pub availMax: u64,
+
+    pub tstampMode: i32,
+    pub tstampType: u32,
+    pub availMin: u64,
+    pub startThreshold: u64,
+    pub stopThreshold: u64,
+    pub silenceThreshold: u64,
+    pub silenceSize: u64,
+
+    pub triggerTstamp: u64,
+}
+
+// one rule of the hw_params dependency graph, target = f(a, b, k)
+#[derive(Clone, Copy)]
+enum SoundRule {
+    Mul(usize, usize, usize),
+    Div(usize, usize, usize),
+    MulDivK(usize, usize, usize, u32),
+    MulKDiv(usize, usize, u32, usize),
+}
+
+static mut soundBackend: *const SoundBackend = null();
+static mut soundPcm: SoundPcm = unsafe { core::mem::zeroed() };
+static mut soundRing: *mut u8 = null_mut();
+static mut soundRingPhys: usize = 0;
+
+// Interrupts can't wait for the lock, they leave a note for whoever has it
+static SOUND_BUSY: AtomicBool = AtomicBool::new(false);
+static SOUND_PENDING: AtomicBool = AtomicBool::new(false);
+
+// what the kernel's snd_pcm_hw_constraints_init() sets up, minus formats
+const SOUND_RULES: [SoundRule; 19] = [
+    SoundRule::Div(SNDRV_PCM_HW_PARAM_SAMPLE_BITS, SNDRV_PCM_HW_PARAM_FRAME_BITS, SNDRV_PCM_HW_PARAM_CHANNELS),
+    SoundRule::Mul(SNDRV_PCM_HW_PARAM_FRAME_BITS, SNDRV_PCM_HW_PARAM_SAMPLE_BITS, SNDRV_PCM_HW_PARAM_CHANNELS),
+    SoundRule::MulKDiv(SNDRV_PCM_HW_PARAM_FRAME_BITS, SNDRV_PCM_HW_PARAM_PERIOD_BYTES, 8, SNDRV_PCM_HW_PARAM_PERIOD_SIZE),
+    SoundRule::MulKDiv(SNDRV_PCM_HW_PARAM_FRAME_BITS, SNDRV_PCM_HW_PARAM_BUFFER_BYTES, 8, SNDRV_PCM_HW_PARAM_BUFFER_SIZE),
+    SoundRule::Div(SNDRV_PCM_HW_PARAM_CHANNELS, SNDRV_PCM_HW_PARAM_FRAME_BITS, SNDRV_PCM_HW_PARAM_SAMPLE_BITS),
+    SoundRule::MulKDiv(SNDRV_PCM_HW_PARAM_RATE, SNDRV_PCM_HW_PARAM_PERIOD_SIZE, 1_000_000, SNDRV_PCM_HW_PARAM_PERIOD_TIME),
+    SoundRule::MulKDiv(SNDRV_PCM_HW_PARAM_RATE, SNDRV_PCM_HW_PARAM_BUFFER_SIZE, 1_000_000, SNDRV_PCM_HW_PARAM_BUFFER_TIME),
+    SoundRule::Div(SNDRV_PCM_HW_PARAM_PERIODS, SNDRV_PCM_HW_PARAM_BUFFER_SIZE, SNDRV_PCM_HW_PARAM_PERIOD_SIZE),
+    SoundRule::Div(SNDRV_PCM_HW_PARAM_PERIOD_SIZE, SNDRV_PCM_HW_PARAM_BUFFER_SIZE, SNDRV_PCM_HW_PARAM_PERIODS),
+    SoundRule::MulKDiv(SNDRV_PCM_HW_PARAM_PERIOD_SIZE, SNDRV_PCM_HW_PARAM_PERIOD_BYTES, 8, SNDRV_PCM_HW_PARAM_FRAME_BITS),
+    SoundRule::MulDivK(SNDRV_PCM_HW_PARAM_PERIOD_SIZE, SNDRV_PCM_HW_PARAM_PERIOD_TIME, SNDRV_PCM_HW_PARAM_RATE, 1_000_000),
+    SoundRule::Mul(SNDRV_PCM_HW_PARAM_BUFFER_SIZE, SNDRV_PCM_HW_PARAM_PERIOD_SIZE, SNDRV_PCM_HW_PARAM_PERIODS),
+    SoundRule::MulKDiv(SNDRV_PCM_HW_PARAM_BUFFER_SIZE, SNDRV_PCM_HW_PARAM_BUFFER_BYTES, 8, SNDRV_PCM_HW_PARAM_FRAME_BITS),
+    SoundRule::MulDivK(SNDRV_PCM_HW_PARAM_BUFFER_SIZE, SNDRV_PCM_HW_PARAM_BUFFER_TIME, SNDRV_PCM_HW_PARAM_RATE, 1_000_000),
+    SoundRule::MulDivK(SNDRV_PCM_HW_PARAM_PERIOD_BYTES, SNDRV_PCM_HW_PARAM_PERIOD_SIZE, SNDRV_PCM_HW_PARAM_FRAME_BITS, 8),
+    SoundRule::MulDivK(SNDRV_PCM_HW_PARAM_BUFFER_BYTES, SNDRV_PCM_HW_PARAM_BUFFER_SIZE, SNDRV_PCM_HW_PARAM_FRAME_BITS, 8),
+    SoundRule::MulKDiv(SNDRV_PCM_HW_PARAM_PERIOD_TIME, SNDRV_PCM_HW_PARAM_PERIOD_SIZE, 1_000_000, SNDRV_PCM_HW_PARAM_RATE),
+    SoundRule::MulKDiv(SNDRV_PCM_HW_PARAM_BUFFER_TIME, SNDRV_PCM_HW_PARAM_BUFFER_SIZE, 1_000_000, SNDRV_PCM_HW_PARAM_RATE),
+    SoundRule::Mul(SNDRV_PCM_HW_PARAM_BUFFER_BYTES, SNDRV_PCM_HW_PARAM_PERIOD_BYTES, SNDRV_PCM_HW_PARAM_PERIODS),
+];
+
+// --------------------------------
+// Helpers
+// --------------------------------
+
+unsafe fn soundLock() {
+    while SOUND_BUSY.swap(true, Ordering::Acquire) {
+        handControl();
+    }
+}
+
+unsafe fn soundUnlock() {
+    if SOUND_PENDING.swap(false, Ordering::AcqRel) {
+        soundUpdate();
+    }
+    SOUND_BUSY.store(false, Ordering::Release);
+}
+
+// Copies a C string into a fixed uapi field, always terminated
+unsafe fn soundCopyName(dst: &mut [u8], src: *const u8) {
+    let mut i = 0;
+    while i + 1 < dst.len() && !src.is_null() && *src.add(i) != 0 {
+        dst[i] = *src.add(i);
+        i += 1;
+    }
+    dst[i] = 0;
+}
+
+fn soundTimespec(ms: u64) -> timespec {
+    timespec {
+        tv_sec: (ms / 1000) as i64,
+        tv_nsec: ((ms % 1000) * 1_000_000) as i64,
+    }
+}
+
+// Frames userspace may write before catching up with the hardware
+unsafe fn soundAvail() -> u64 {
+    let pcm = &soundPcm;
+    (pcm.hwPtr + pcm.bufferSize).saturating_sub(pcm.applPtr)
+}
+
+// Zeroes frames of the ring, from an (unwrapped) position on
+unsafe fn soundSilence(from: u64, mut frames: u64) {
+    let pcm = &soundPcm;
+    let mut offset = from % pcm.bufferSize;
+    while frames > 0 {
+        let chunk = min(frames, pcm.bufferSize - offset);
+        write_bytes(
+            pcm.stream.ring.add(offset as usize * pcm.frameBytes),
+            0,
+            chunk as usize * pcm.frameBytes,
+        );
+        frames -= chunk;
+        offset = 0;
+    }
+}
+
+unsafe fn soundStart() {
+    ((*soundBackend).start)();
+    soundPcm.state = SNDRV_PCM_STATE_RUNNING;
+    soundPcm.triggerTstamp = timerTicks;
+}
+
+unsafe fn soundStop(state: i32) {
+    if soundPcm.state == SNDRV_PCM_STATE_RUNNING || soundPcm.state == SNDRV_PCM_STATE_DRAINING {
+        ((*soundBackend).stop)();
+    }
+    soundPcm.state = state;
+    soundPcm.triggerTstamp = timerTicks;
+}
+
+// Catches hw_ptr up with the hardware, and acts on underruns and the end of
+// a drain (lock held)
+unsafe fn soundUpdate() {
+    let pcm = &mut soundPcm;
+    if soundBackend.is_null()
+        || (pcm.state != SNDRV_PCM_STATE_RUNNING && pcm.state != SNDRV_PCM_STATE_DRAINING)
+    {
+        return;
+    }
+
+    let position = ((*soundBackend).position)() % pcm.stream.bytes;
+    let mut hw = pcm.hwBase + (position / pcm.frameBytes) as u64;
+    if hw < pcm.hwPtr {
+        // only a real wrap counts, not a position read that lags behind
+        if pcm.hwPtr - hw < pcm.bufferSize / 2 {
+            return;
+        }
+        pcm.hwBase += pcm.bufferSize;
+        hw += pcm.bufferSize;
+    }
+    pcm.hwPtr = hw;
+
+    let avail = soundAvail();
+    if avail > pcm.availMax {
+        pcm.availMax = avail;
+    }
+
+    if pcm.state == SNDRV_PCM_STATE_DRAINING {
+        if pcm.hwPtr >= pcm.applPtr {
+            soundStop(SNDRV_PCM_STATE_SETUP);
+        }
+    } else if avail >= pcm.stopThreshold {
+        soundStop(SNDRV_PCM_STATE_XRUN);
+    }
+}
+
+// Userspace's view of a pointer back into ours, the closest one to `near`
+fn soundUnwrap(value: u64, near: u64, boundary: u64) -> u64 {
+    let mut unwrapped = near - near % boundary + value;
+    if unwrapped > near + boundary / 2 && unwrapped >= boundary {
+        unwrapped -= boundary;
+    } else if unwrapped + boundary / 2 < near {
+        unwrapped += boundary;
+    }
+    unwrapped
+}
+
+// --------------------------------
+// hw_params refinement
+// --------------------------------
+
+fn ivMake(min: u32, max: u32, integer: bool) -> snd_interval {
+    snd_interval {
+        min,
+        max,
+        flags: if integer { SND_INTERVAL_INTEGER } else { 0 },
+    }
+}
+
+#[inline]
+fn ivFlag(i: &snd_interval, flag: u32) -> bool {
+    i.flags & flag != 0
+}
+
+fn ivEmpty(i: &snd_interval) -> bool {
+    ivFlag(i, SND_INTERVAL_EMPTY)
+        || i.min > i.max
+        || (i.min == i.max && ivFlag(i, SND_INTERVAL_OPENMIN | SND_INTERVAL_OPENMAX))
+}
+
+fn ivSingle(i: &snd_interval) -> bool {
+    !ivEmpty(i)
+        && (i.min == i.max
+            || (i.min + 1 == i.max && (ivFlag(i, SND_INTERVAL_OPENMIN) || ivFlag(i, SND_INTERVAL_OPENMAX))))
+}
+
+fn ivValue(i: &snd_interval) -> u32 {
+    if ivFlag(i, SND_INTERVAL_OPENMIN) && !ivFlag(i, SND_INTERVAL_OPENMAX) {
+        i.max
+    } else {
+        i.min
+    }
+}
+
+fn ivSet(i: &mut snd_interval, flag: u32, on: bool) {
+    if on {
+        i.flags |= flag;
+    } else {
+        i.flags &= !flag;
+    }
+}
+
+// a * b / c, saturated, with the remainder
+fn ivMulDiv(a: u32, b: u32, c: u32) -> (u32, bool) {
+    if c == 0 {
+        return (u32::MAX, false);
+    }
+    let n = a as u64 * b as u64;
+    let q = n / c as u64;
+    if q > u32::MAX as u64 {
+        return (u32::MAX, false);
+    }
+    (q as u32, n % c as u64 != 0)
+}
+
+// Narrows `i` down to `v`, the way snd_interval_refine() does
+fn ivRefine(i: &mut snd_interval, v: &snd_interval) -> Result<bool, ()> {
+    if ivEmpty(i) {
+        return Err(());
+    }
+
+    let mut changed = false;
+    if i.min < v.min {
+        i.min = v.min;
+        ivSet(i, SND_INTERVAL_OPENMIN, ivFlag(v, SND_INTERVAL_OPENMIN));
+        changed = true;
+    } else if i.min == v.min && !ivFlag(i, SND_INTERVAL_OPENMIN) && ivFlag(v, SND_INTERVAL_OPENMIN) {
+        ivSet(i, SND_INTERVAL_OPENMIN, true);
+        changed = true;
+    }
+    if i.max > v.max {
+        i.max = v.max;
+        ivSet(i, SND_INTERVAL_OPENMAX, ivFlag(v, SND_INTERVAL_OPENMAX));
+        changed = true;
+    } else if i.max == v.max && !ivFlag(i, SND_INTERVAL_OPENMAX) && ivFlag(v, SND_INTERVAL_OPENMAX) {
+        ivSet(i, SND_INTERVAL_OPENMAX, true);
+        changed = true;
+    }
+    if !ivFlag(i, SND_INTERVAL_INTEGER) && ivFlag(v, SND_INTERVAL_INTEGER) {
+        ivSet(i, SND_INTERVAL_INTEGER, true);
+        changed = true;
+    }
+
+    if ivFlag(i, SND_INTERVAL_INTEGER) {
+        if ivFlag(i, SND_INTERVAL_OPENMIN) {
+            i.min = i.min.saturating_add(1);
+            ivSet(i, SND_INTERVAL_OPENMIN, false);
+        }
+        if ivFlag(i, SND_INTERVAL_OPENMAX) {
+            if i.max == 0 {
+                i.flags |= SND_INTERVAL_EMPTY;
+                return Err(());
+            }
+            i.max -= 1;
+            ivSet(i, SND_INTERVAL_OPENMAX, false);
+        }
+    } else if !ivFlag(i, SND_INTERVAL_OPENMIN | SND_INTERVAL_OPENMAX) && i.min == i.max {
+        ivSet(i, SND_INTERVAL_INTEGER, true);
+    }
+
+    if ivEmpty(i) {
+        i.flags |= SND_INTERVAL_EMPTY;
+        return Err(());
+    }
+    Ok(changed)
+}
+
+fn ivApply(rule: SoundRule, ivs: &[snd_interval; 12]) -> (usize, snd_interval) {
+    let at = |index: usize| ivs[index - SNDRV_PCM_HW_PARAM_FIRST_INTERVAL];
+    let mut c = snd_interval { min: 0, max: 0, flags: 0 };
+    match rule {
+        SoundRule::Mul(target, a, b) => {
+            let (a, b) = (at(a), at(b));
+            c.min = (a.min as u64 * b.min as u64).min(u32::MAX as u64) as u32;
+            c.max = (a.max as u64 * b.max as u64).min(u32::MAX as u64) as u32;
+            ivSet(&mut c, SND_INTERVAL_OPENMIN, ivFlag(&a, SND_INTERVAL_OPENMIN) || ivFlag(&b, SND_INTERVAL_OPENMIN));
+            ivSet(&mut c, SND_INTERVAL_OPENMAX, ivFlag(&a, SND_INTERVAL_OPENMAX) || ivFlag(&b, SND_INTERVAL_OPENMAX));
+            ivSet(&mut c, SND_INTERVAL_INTEGER, ivFlag(&a, SND_INTERVAL_INTEGER) && ivFlag(&b, SND_INTERVAL_INTEGER));
+            (target, c)
+        }
+        SoundRule::Div(target, a, b) => {
+            let (a, b) = (at(a), at(b));
+            let (low, r) = ivMulDiv(a.min, 1, b.max);
+            c.min = low;
+            ivSet(&mut c, SND_INTERVAL_OPENMIN, r || ivFlag(&a, SND_INTERVAL_OPENMIN) || ivFlag(&b, SND_INTERVAL_OPENMAX));
+            if b.min > 0 {
+                let (high, r) = ivMulDiv(a.max, 1, b.min);
+                c.max = if r { high.saturating_add(1) } else { high };
+                ivSet(&mut c, SND_INTERVAL_OPENMAX, r || ivFlag(&a, SND_INTERVAL_OPENMAX) || ivFlag(&b, SND_INTERVAL_OPENMIN));
+            } else {
+                c.max = u32::MAX;
+            }
+            (target, c)
+        }
+        SoundRule::MulDivK(target, a, b, k) => {
+            let (a, b) = (at(a), at(b));
+            let (low, r) = ivMulDiv(a.min, b.min, k);
+            c.min = low;
+            ivSet(&mut c, SND_INTERVAL_OPENMIN, r || ivFlag(&a, SND_INTERVAL_OPENMIN) || ivFlag(&b, SND_INTERVAL_OPENMIN));
+            let (high, r) = ivMulDiv(a.max, b.max, k);
+            c.max = if r { high.saturating_add(1) } else { high };
+            ivSet(&mut c, SND_INTERVAL_OPENMAX, r || ivFlag(&a, SND_INTERVAL_OPENMAX) || ivFlag(&b, SND_INTERVAL_OPENMAX));
+            (target, c)
+        }
+        SoundRule::MulKDiv(target, a, k, b) => {
+            let (a, b) = (at(a), at(b));
+            let (low, r) = ivMulDiv(a.min, k, b.max);
+            c.min = low;
+            ivSet(&mut c, SND_INTERVAL_OPENMIN, r || ivFlag(&a, SND_INTERVAL_OPENMIN) || ivFlag(&b, SND_INTERVAL_OPENMAX));
+            if b.min > 0 {
+                let (high, r) = ivMulDiv(a.max, k, b.min);
+                c.max = if r { high.saturating_add(1) } else { high };
+                ivSet(&mut c, SND_INTERVAL_OPENMAX, r || ivFlag(&a, SND_INTERVAL_OPENMAX) || ivFlag(&b, SND_INTERVAL_OPENMIN));
+            } else {
+                c.max = u32::MAX;
+            }
+            (target, c)
+        }
+    }
+}
+
+#[inline]
+fn ivIndex(param: usize) -> usize {
+    param - SNDRV_PCM_HW_PARAM_FIRST_INTERVAL
+}
+
+// Keeps only the bits we support, false if nothing's left
+fn maskRefine(mask: &mut snd_mask, allowed: u32) -> bool {
+    mask.bits[0] &= allowed;
+    for bits in mask.bits[1..].iter_mut() {
+        *bits = 0;
+    }
+    mask.bits[0] != 0
+}
+
+// Snaps the discrete constraints intervals can't express: supported rates,
+// period alignment and power-of-two period counts
+unsafe fn soundSnap(ivs: &mut [snd_interval; 12]) -> Result<bool, ()> {
+    let backend = &*soundBackend;
+    let mut changed = false;
+
+    let rate = ivs[ivIndex(SNDRV_PCM_HW_PARAM_RATE)];
+    let mut low = 0;
+    let mut high = 0;
+    for (bit, &value) in SOUND_RATES.iter().enumerate() {
+        if backend.rates & (1 << bit) == 0 {
+            continue;
+        }
+        let aboveMin = value > rate.min || (value == rate.min && !ivFlag(&rate, SND_INTERVAL_OPENMIN));
+        let belowMax = value < rate.max || (value == rate.max && !ivFlag(&rate, SND_INTERVAL_OPENMAX));
+        if aboveMin && belowMax {
+            if low == 0 {
+                low = value;
+            }
+            high = value;
+        }
+    }
+    if low == 0 {
+        return Err(());
+    }
+    changed |= ivRefine(&mut ivs[ivIndex(SNDRV_PCM_HW_PARAM_RATE)], &ivMake(low, high, true))?;
+
+    let align = backend.periodBytesAlign.max(1);
+    let bytes = ivs[ivIndex(SNDRV_PCM_HW_PARAM_PERIOD_BYTES)];
+    let low = bytes.min.div_ceil(align) * align;
+    let high = bytes.max / align * align;
+    changed |= ivRefine(&mut ivs[ivIndex(SNDRV_PCM_HW_PARAM_PERIOD_BYTES)], &ivMake(low, high, true))?;
+
+    if backend.periodsPow2 {
+        let periods = ivs[ivIndex(SNDRV_PCM_HW_PARAM_PERIODS)];
+        let low = periods.min.max(1).next_power_of_two();
+        let high = if periods.max == 0 { 0 } else { 1 << (31 - periods.max.leading_zeros()) };
+        changed |= ivRefine(&mut ivs[ivIndex(SNDRV_PCM_HW_PARAM_PERIODS)], &ivMake(low, high, true))?;
+    }
+    Ok(changed)
+}
+
+// Cuts hw_params down to what the hardware can do, like SNDRV_PCM_IOCTL_HW_REFINE
+unsafe fn soundRefine(params: &mut snd_pcm_hw_params) -> Result<(), ()> {
+    let backend = &*soundBackend;
+    let original = *params;
+
+    if !maskRefine(
+        &mut params.masks[SNDRV_PCM_HW_PARAM_ACCESS],
+        (1 << SNDRV_PCM_ACCESS_MMAP_INTERLEAVED) | (1 << SNDRV_PCM_ACCESS_RW_INTERLEAVED),
+    ) || !maskRefine(&mut params.masks[SNDRV_PCM_HW_PARAM_FORMAT], 1 << SNDRV_PCM_FORMAT_S16_LE)
+        || !maskRefine(&mut params.masks[SNDRV_PCM_HW_PARAM_SUBFORMAT], 1 << SNDRV_PCM_SUBFORMAT_STD)
+    {
+        return Err(());
+    }
+
+    let periodBytesMax = min(backend.periodBytesMax, (SOUND_RING_BYTES / 2) as u32);
+    let caps: [(usize, snd_interval); 10] = [
+        (SNDRV_PCM_HW_PARAM_SAMPLE_BITS, ivMake(SOUND_SAMPLE_BITS, SOUND_SAMPLE_BITS, true)),
+        (
+            SNDRV_PCM_HW_PARAM_FRAME_BITS,
+            ivMake(SOUND_SAMPLE_BITS * backend.channelsMin, SOUND_SAMPLE_BITS * backend.channelsMax, true),
+        ),
+        (SNDRV_PCM_HW_PARAM_CHANNELS, ivMake(backend.channelsMin, backend.channelsMax, true)),
+        (SNDRV_PCM_HW_PARAM_RATE, ivMake(SOUND_RATES[0], SOUND_RATES[SOUND_RATES.len() - 1], true)),
+        (SNDRV_PCM_HW_PARAM_PERIOD_SIZE, ivMake(1, u32::MAX, true)),
+        (SNDRV_PCM_HW_PARAM_PERIOD_BYTES, ivMake(SOUND_PERIOD_BYTES_MIN, periodBytesMax, true)),
+        (SNDRV_PCM_HW_PARAM_PERIODS, ivMake(2, backend.periodsMax, true)),
+        (SNDRV_PCM_HW_PARAM_BUFFER_SIZE, ivMake(1, u32::MAX, true)),
+        (SNDRV_PCM_HW_PARAM_BUFFER_BYTES, ivMake(SOUND_PERIOD_BYTES_MIN * 2, SOUND_RING_BYTES as u32, true)),
+        (SNDRV_PCM_HW_PARAM_TICK_TIME, ivMake(0, u32::MAX, false)),
+    ];
+    for (param, cap) in caps.iter() {
+        ivRefine(&mut params.intervals[ivIndex(*param)], cap)?;
+    }
+
+    // settle the rules, then the discrete bits, until nothing moves
+    for _ in 0..16 {
+        for _ in 0..64 {
+            let mut changed = false;
+            for rule in SOUND_RULES.iter() {
+                let (target, value) = ivApply(*rule, &params.intervals);
+                changed |= ivRefine(&mut params.intervals[ivIndex(target)], &value)?;
+            }
+            if !changed {
+                break;
+            }
+        }
+        if !soundSnap(&mut params.intervals)? {
+            break;
+        }
+    }
+
+    params.cmask = 0;
+    for i in 0..3 {
+        if params.masks[i].bits != original.masks[i].bits {
+            params.cmask |= 1 << i;
+        }
+    }
+    for i in SNDRV_PCM_HW_PARAM_FIRST_INTERVAL..=SNDRV_PCM_HW_PARAM_LAST_INTERVAL {
+        if params.intervals[ivIndex(i)] != original.intervals[ivIndex(i)] {
+            params.cmask |= 1 << i;
+        }
+    }
+    params.rmask = 0;
+
+    params.info = SNDRV_PCM_INFO_MMAP
+        | SNDRV_PCM_INFO_MMAP_VALID
+        | SNDRV_PCM_INFO_INTERLEAVED
+        | SNDRV_PCM_INFO_BLOCK_TRANSFER
+        | SNDRV_PCM_INFO_PAUSE;
+    params.msbits = SOUND_SAMPLE_BITS;
+    let rate = params.intervals[ivIndex(SNDRV_PCM_HW_PARAM_RATE)];
+    if ivSingle(&rate) {
+        params.rate_num = ivValue(&rate);
+        params.rate_den = 1;
+    } else {
+        params.rate_num = 0;
+        params.rate_den = 0;
+    }
+    params.fifo_size = 0;
+    Ok(())
+}
+
+// Narrows everything down to single values, snd_pcm_hw_params_choose() order
+unsafe fn soundChoose(params: &mut snd_pcm_hw_params) -> Result<(), ()> {
+    for mask in params.masks.iter_mut() {
+        let lowest = mask.bits[0] & mask.bits[0].wrapping_neg();
+        mask.bits[0] = lowest;
+    }
+
+    let order: [(usize, bool); 5] = [
+        (SNDRV_PCM_HW_PARAM_CHANNELS, false),
+        (SNDRV_PCM_HW_PARAM_RATE, false),
+        (SNDRV_PCM_HW_PARAM_PERIOD_TIME, false),
+        (SNDRV_PCM_HW_PARAM_BUFFER_SIZE, true),
+        (SNDRV_PCM_HW_PARAM_TICK_TIME, false),
+    ];
+    for (param, last) in order.iter() {
+        soundRefine(params)?;
+        let interval = &mut params.intervals[ivIndex(*param)];
+        if ivSingle(interval) {
+            continue;
+        }
+        let value = if *last {
+            if ivFlag(interval, SND_INTERVAL_OPENMAX) { interval.max - 1 } else { interval.max }
+        } else if ivFlag(interval, SND_INTERVAL_OPENMIN) {
+            interval.min + 1
+        } else {
+            interval.min
+        };
+        ivRefine(interval, &ivMake(value, value, true))?;
+    }
+    soundRefine(params)
+}
+
+// --------------------------------
+// PCM ioctls
+// --------------------------------
+
+unsafe fn soundPcmInfo(info: *mut snd_pcm_info) -> usize {
+    if (*info).device != 0 || (*info).subdevice != 0 || (*info).stream != SNDRV_PCM_STREAM_PLAYBACK {
+        return err(ENOENT);
+    }
+    let backend = &*soundBackend;
+
+    write_bytes(info, 0, 1);
+    (*info).stream = SNDRV_PCM_STREAM_PLAYBACK;
+    soundCopyName(&mut (*info).id, backend.driver);
+    soundCopyName(&mut (*info).name, backend.name);
+    soundCopyName(&mut (*info).subname, b"subdevice #0\0".as_ptr());
+    (*info).subdevices_count = 1;
+    (*info).subdevices_avail = if soundPcm.opens > 0 { 0 } else { 1 };
+    0
+}
+
+unsafe fn soundHwParams(params: *mut snd_pcm_hw_params) -> usize {
+    let pcm = &mut soundPcm;
+    if pcm.state != SNDRV_PCM_STATE_OPEN
+        && pcm.state != SNDRV_PCM_STATE_SETUP
+        && pcm.state != SNDRV_PCM_STATE_PREPARED
+    {
+        return err(EBADFD);
+    }
+
+    let mut chosen = *params;
+    if soundChoose(&mut chosen).is_err() {
+        return err(EINVAL);
+    }
+    let value = |param: usize| ivValue(&chosen.intervals[ivIndex(param)]);
+
+    pcm.access = chosen.masks[SNDRV_PCM_HW_PARAM_ACCESS].bits[0].trailing_zeros();
+    pcm.stream.channels = value(SNDRV_PCM_HW_PARAM_CHANNELS);
+    pcm.stream.rate = value(SNDRV_PCM_HW_PARAM_RATE);
+    pcm.stream.periods = value(SNDRV_PCM_HW_PARAM_PERIODS);
+    pcm.stream.periodBytes = value(SNDRV_PCM_HW_PARAM_PERIOD_BYTES) as usize;
+    pcm.stream.bytes = pcm.stream.periodBytes * pcm.stream.periods as usize;
+    pcm.stream.ring = soundRing;
+    pcm.stream.ringPhys = soundRingPhys;
+    pcm.frameBytes = (pcm.stream.channels * SOUND_SAMPLE_BITS / 8) as usize;
+    pcm.periodSize = value(SNDRV_PCM_HW_PARAM_PERIOD_SIZE) as u64;
+    pcm.bufferSize = value(SNDRV_PCM_HW_PARAM_BUFFER_SIZE) as u64;
+
+    // the same boundary alsa-lib works out on its own
+    pcm.boundary = pcm.bufferSize;
+    while pcm.boundary * 2 <= i64::MAX as u64 - pcm.bufferSize {
+        pcm.boundary *= 2;
+    }
+
+    pcm.tstampMode = 0;
+    pcm.tstampType = 0;
+    pcm.availMin = pcm.periodSize;
+    pcm.startThreshold = 1;
+    pcm.stopThreshold = pcm.bufferSize;
+    pcm.silenceThreshold = 0;
+    pcm.silenceSize = 0;
+    pcm.hwPtr = 0;
+    pcm.hwBase = 0;
+    pcm.applPtr = 0;
+    pcm.availMax = 0;
+    pcm.state = SNDRV_PCM_STATE_SETUP;
+
+    *params = chosen;
+    0
+}
+
+unsafe fn soundSwParams(params: *mut snd_pcm_sw_params) -> usize {
+    let pcm = &mut soundPcm;
+    if pcm.state == SNDRV_PCM_STATE_OPEN {
+        return err(EBADFD);
+    }
+    if (*params).tstamp_mode < 0
+        || (*params).tstamp_mode > 1
+        || (*params).tstamp_type > SNDRV_PCM_TSTAMP_TYPE_LAST
+        || (*params).avail_min == 0
+        || (*params).silence_size > pcm.bufferSize
+        || (*params).silence_threshold > pcm.bufferSize
+    {
+        return err(EINVAL);
+    }
+
+    pcm.tstampMode = (*params).tstamp_mode;
+    pcm.tstampType = (*params).tstamp_type;
+    pcm.availMin = (*params).avail_min;
+    pcm.startThreshold = (*params).start_threshold;
+    pcm.stopThreshold = (*params).stop_threshold;
+    pcm.silenceThreshold = (*params).silence_threshold;
+    pcm.silenceSize = (*params).silence_size;
+    (*params).boundary = pcm.boundary;
+
+    if pcm.state == SNDRV_PCM_STATE_RUNNING && soundAvail() >= pcm.stopThreshold {
+        soundStop(SNDRV_PCM_STATE_XRUN);
+    }
+    0
+}
+
+unsafe fn soundStatus(status: *mut snd_pcm_status) -> usize {
+    soundUpdate();
+    let pcm = &mut soundPcm;
+
+    write_bytes(status, 0, 1);
+    (*status).state = pcm.state;
+    (*status).trigger_tstamp = soundTimespec(pcm.triggerTstamp);
+    (*status).tstamp = soundTimespec(timerTicks);
+    (*status).appl_ptr = pcm.applPtr % pcm.boundary.max(1);
+    (*status).hw_ptr = pcm.hwPtr % pcm.boundary.max(1);
+    let avail = soundAvail();
+    (*status).avail = avail;
+    if pcm.state == SNDRV_PCM_STATE_RUNNING || pcm.state == SNDRV_PCM_STATE_DRAINING {
+        (*status).delay = pcm.bufferSize as i64 - avail as i64;
+    }
+    (*status).avail_max = pcm.availMax.max(avail);
+    pcm.availMax = 0;
+    (*status).audio_tstamp = (*status).tstamp;
+    (*status).driver_tstamp = (*status).tstamp;
+    0
+}
+
+unsafe fn soundSyncPtr(sync: *mut snd_pcm_sync_ptr) -> usize {
+    let pcm = &mut soundPcm;
+    if pcm.state == SNDRV_PCM_STATE_OPEN {
+        return err(EBADFD);
+    }
+
+    if (*sync).flags & SNDRV_PCM_SYNC_PTR_HWSYNC != 0 {
+        soundUpdate();
+    }
+    if (*sync).flags & SNDRV_PCM_SYNC_PTR_APPL == 0 {
+        // someone drawing straight into the mmap()ed ring
+        let appl = soundUnwrap((*sync).c.appl_ptr, pcm.applPtr, pcm.boundary);
+        if (*sync).c.appl_ptr >= pcm.boundary || appl > pcm.hwPtr + pcm.bufferSize {
+            return err(EINVAL);
+        }
+        pcm.applPtr = appl;
+    }
+    if (*sync).flags & SNDRV_PCM_SYNC_PTR_AVAIL_MIN == 0 && (*sync).c.avail_min != 0 {
+        pcm.availMin = (*sync).c.avail_min;
+    }
+
+    (*sync).s.state = pcm.state;
+    (*sync).s.hw_ptr = pcm.hwPtr % pcm.boundary;
+    (*sync).s.tstamp = soundTimespec(timerTicks);
+    (*sync).s.suspended_state = 0;
+    (*sync).s.audio_tstamp = (*sync).s.tstamp;
+    (*sync).c.appl_ptr = pcm.applPtr % pcm.boundary;
+    (*sync).c.avail_min = pcm.availMin;
+    0
+}
+
+unsafe fn soundPrepare() -> usize {
+    let pcm = &mut soundPcm;
+    if pcm.state == SNDRV_PCM_STATE_OPEN {
+        return err(EBADFD);
+    }
+    soundStop(SNDRV_PCM_STATE_SETUP);
+
+    write_bytes(pcm.stream.ring, 0, pcm.stream.bytes);
+    if !((*soundBackend).prepare)(&pcm.stream) {
+        return err(EIO);
+    }
+    pcm.hwPtr = 0;
+    pcm.hwBase = 0;
+    pcm.applPtr = 0;
+    pcm.availMax = 0;
+    pcm.state = SNDRV_PCM_STATE_PREPARED;
+    0
+}
+
+// Waits for the hardware to play out what's queued (lock held, dropped while
+// waiting)
+unsafe fn soundDrain(nonblock: bool) -> usize {
+    let pcm = &mut soundPcm;
+    match pcm.state {
+        SNDRV_PCM_STATE_OPEN => return err(EBADFD),
+        SNDRV_PCM_STATE_PREPARED => {
+            if pcm.applPtr == pcm.hwPtr {
+                pcm.state = SNDRV_PCM_STATE_SETUP;
+                return 0;
+            }
+            soundStart();
+        }
+        SNDRV_PCM_STATE_PAUSED => {
+            soundStart();
+        }
+        SNDRV_PCM_STATE_XRUN => {
+            pcm.state = SNDRV_PCM_STATE_SETUP;
+            return 0;
+        }
+        SNDRV_PCM_STATE_SETUP => return 0,
+        _ => {}
+    }
+
+    if pcm.state == SNDRV_PCM_STATE_RUNNING {
+        // what's past the end would otherwise play again until we notice
+        let queued = pcm.applPtr.saturating_sub(pcm.hwPtr);
+        soundSilence(pcm.applPtr, pcm.bufferSize - min(queued, pcm.bufferSize));
+        pcm.state = SNDRV_PCM_STATE_DRAINING;
+    }
+    if nonblock {
+        return err(EAGAIN);
+    }
+
+    while soundPcm.state == SNDRV_PCM_STATE_DRAINING {
+        soundUnlock();
+        if signalsPendingQuick(currentTask) {
+            soundLock();
+            return err(EINTR);
+        }
+        handControl();
+        soundLock();
+        soundUpdate();
+    }
+    0
+}
+
+unsafe fn soundPause(push: bool) -> usize {
+    let pcm = &mut soundPcm;
+    if push && pcm.state == SNDRV_PCM_STATE_RUNNING {
+        ((*soundBackend).stop)();
+        pcm.state = SNDRV_PCM_STATE_PAUSED;
+    } else if !push && pcm.state == SNDRV_PCM_STATE_PAUSED {
+        soundStart();
+    } else {
+        return err(EBADFD);
+    }
+    pcm.triggerTstamp = timerTicks;
+    0
+}
+
+// REWIND / FORWARD: moves appl_ptr, says by how much
+unsafe fn soundMove(frames: *mut u64, forward: bool) -> usize {
+    let pcm = &mut soundPcm;
+    if pcm.state != SNDRV_PCM_STATE_PREPARED
+        && pcm.state != SNDRV_PCM_STATE_RUNNING
+        && pcm.state != SNDRV_PCM_STATE_PAUSED
+    {
+        return err(EBADFD);
+    }
+    soundUpdate();
+
+    let moved = if forward {
+        min(*frames, soundAvail())
+    } else {
+        min(*frames, pcm.applPtr.saturating_sub(pcm.hwPtr))
+    };
+    if forward {
+        pcm.applPtr += moved;
+    } else {
+        pcm.applPtr -= moved;
+    }
+    *frames = moved;
+    0
+}
+
+// The actual playback path, WRITEI_FRAMES and write() (lock held, dropped
+// while waiting for room)
+unsafe fn soundWrite(nonblock: bool, buf: *const u8, frames: u64) -> Result<u64, usize> {
+    match soundPcm.state {
+        SNDRV_PCM_STATE_PREPARED | SNDRV_PCM_STATE_RUNNING => {}
+        SNDRV_PCM_STATE_XRUN => return Err(err(EPIPE)),
+        _ => return Err(err(EBADFD)),
+    }
+    if soundPcm.access != SNDRV_PCM_ACCESS_RW_INTERLEAVED {
+        return Err(err(EINVAL));
+    }
+
+    let mut done = 0;
+    while done < frames {
+        soundUpdate();
+        let pcm = &mut soundPcm;
+        if pcm.state == SNDRV_PCM_STATE_XRUN {
+            return if done > 0 { Ok(done) } else { Err(err(EPIPE)) };
+        }
+        if pcm.state != SNDRV_PCM_STATE_PREPARED && pcm.state != SNDRV_PCM_STATE_RUNNING {
+            return if done > 0 { Ok(done) } else { Err(err(EBADFD)) };
+        }
+
+        let avail = soundAvail();
+        if avail == 0 || (avail < pcm.availMin && avail < frames - done) {
+            // a full buffer that never reaches the start threshold would
+            // otherwise wait forever
+            if pcm.state == SNDRV_PCM_STATE_PREPARED {
+                soundStart();
+                continue;
+            }
+            if nonblock {
+                return if done > 0 { Ok(done) } else { Err(err(EAGAIN)) };
+            }
+            soundUnlock();
+            if signalsPendingQuick(currentTask) {
+                soundLock();
+                return if done > 0 { Ok(done) } else { Err(err(EINTR)) };
+            }
+            handControl();
+            soundLock();
+            continue;
+        }
+
+        let offset = pcm.applPtr % pcm.bufferSize;
+        let chunk = min(min(avail, frames - done), pcm.bufferSize - offset);
+        copy_nonoverlapping(
+            buf.add(done as usize * pcm.frameBytes),
+            pcm.stream.ring.add(offset as usize * pcm.frameBytes),
+            chunk as usize * pcm.frameBytes,
+        );
+        pcm.applPtr += chunk;
+        done += chunk;
+
+        if pcm.state == SNDRV_PCM_STATE_PREPARED
+            && pcm.applPtr - pcm.hwPtr >= min(pcm.startThreshold, pcm.bufferSize)
+        {
+            soundStart();
+        }
+    }
+    Ok(done)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn soundPcmIoctl(fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    let nonblock = (*fd).flags & O_NONBLOCK != 0;
+
+    if (request >> 8) & 0xff != SNDRV_PCM_IOCTL_BASE {
+        return err(ENOTTY);
+    }
+
+    soundLock();
+    let ret = match (request & 0xff) as usize {
+        SNDRV_PCM_IOCTL_PVERSION => {
+            *(arg as *mut i32) = SNDRV_PCM_VERSION;
+            0
+        }
+        SNDRV_PCM_IOCTL_INFO => soundPcmInfo(arg as *mut snd_pcm_info),
+        SNDRV_PCM_IOCTL_TSTAMP | SNDRV_PCM_IOCTL_TTSTAMP | SNDRV_PCM_IOCTL_USER_PVERSION => 0,
+
+        SNDRV_PCM_IOCTL_HW_REFINE => {
+            if soundRefine(&mut *(arg as *mut snd_pcm_hw_params)).is_err() {
+                err(EINVAL)
+            } else {
+                0
+            }
+        }
+        SNDRV_PCM_IOCTL_HW_PARAMS => soundHwParams(arg as *mut snd_pcm_hw_params),
+        SNDRV_PCM_IOCTL_HW_FREE => match soundPcm.state {
+            SNDRV_PCM_STATE_RUNNING | SNDRV_PCM_STATE_DRAINING | SNDRV_PCM_STATE_PAUSED => err(EBADFD),
+            _ => {
+                soundPcm.state = SNDRV_PCM_STATE_OPEN;
+                0
+            }
+        },
+        SNDRV_PCM_IOCTL_SW_PARAMS => soundSwParams(arg as *mut snd_pcm_sw_params),
+
+        SNDRV_PCM_IOCTL_STATUS | SNDRV_PCM_IOCTL_STATUS_EXT => soundStatus(arg as *mut snd_pcm_status),
+        SNDRV_PCM_IOCTL_DELAY => {
+            soundUpdate();
+            match soundPcm.state {
+                SNDRV_PCM_STATE_XRUN => err(EPIPE),
+                SNDRV_PCM_STATE_OPEN | SNDRV_PCM_STATE_SETUP => err(EBADFD),
+                _ => {
+                    *(arg as *mut i64) = soundPcm.applPtr.saturating_sub(soundPcm.hwPtr) as i64;
+                    0
+                }
+            }
+        }
+        SNDRV_PCM_IOCTL_HWSYNC => {
+            soundUpdate();
+            if soundPcm.state == SNDRV_PCM_STATE_XRUN { err(EPIPE) } else { 0 }
+        }
+        SNDRV_PCM_IOCTL_SYNC_PTR => soundSyncPtr(arg as *mut snd_pcm_sync_ptr),
+        SNDRV_PCM_IOCTL_CHANNEL_INFO => err(EINVAL),
+
+        SNDRV_PCM_IOCTL_PREPARE => soundPrepare(),
+        SNDRV_PCM_IOCTL_RESET => match soundPcm.state {
+            SNDRV_PCM_STATE_PREPARED | SNDRV_PCM_STATE_RUNNING | SNDRV_PCM_STATE_PAUSED => {
+                soundUpdate();
+                soundPcm.applPtr = soundPcm.hwPtr;
+                0
+            }
+            _ => err(EBADFD),
+        },
+        SNDRV_PCM_IOCTL_START => {
+            if soundPcm.state != SNDRV_PCM_STATE_PREPARED {
+                err(EBADFD)
+            } else {
+                soundStart();
+                0
+            }
+        }
+        SNDRV_PCM_IOCTL_DROP => {
+            if soundPcm.state == SNDRV_PCM_STATE_OPEN {
+                err(EBADFD)
+            } else {
+                if soundPcm.state == SNDRV_PCM_STATE_PAUSED {
+                    soundPcm.state = SNDRV_PCM_STATE_RUNNING;
+                }
+                soundStop(SNDRV_PCM_STATE_SETUP);
+                0
+            }
+        }
+        SNDRV_PCM_IOCTL_DRAIN => soundDrain(nonblock),
+        SNDRV_PCM_IOCTL_PAUSE => soundPause(arg as usize != 0),
+        SNDRV_PCM_IOCTL_REWIND => soundMove(arg as *mut u64, false),
+        SNDRV_PCM_IOCTL_FORWARD => soundMove(arg as *mut u64, true),
+        SNDRV_PCM_IOCTL_RESUME => err(ENOSYS),
+        SNDRV_PCM_IOCTL_XRUN => {
+            if soundPcm.state == SNDRV_PCM_STATE_RUNNING || soundPcm.state == SNDRV_PCM_STATE_PREPARED {
+                soundStop(SNDRV_PCM_STATE_XRUN);
+                0
+            } else {
+                err(EBADFD)
+            }
+        }
+
+        SNDRV_PCM_IOCTL_WRITEI_FRAMES => {
+            let xfer = arg as *mut snd_xferi;
+            (*xfer).result = 0;
+            match soundWrite(nonblock, (*xfer).buf, (*xfer).frames) {
+                Ok(done) => {
+                    (*xfer).result = done as i64;
+                    0
+                }
+                Err(code) => code,
+            }
+        }
+        SNDRV_PCM_IOCTL_READI_FRAMES
+        | SNDRV_PCM_IOCTL_WRITEN_FRAMES
+        | SNDRV_PCM_IOCTL_READN_FRAMES => err(EINVAL),
+        SNDRV_PCM_IOCTL_LINK | SNDRV_PCM_IOCTL_UNLINK => err(ENOSYS),
+
+        _ => err(ENOTTY),
+    };
+    soundUnlock();
+    ret
+}
+
+// --------------------------------
+// /dev/snd/pcmC0D0p handlers
+// --------------------------------
+
+#[no_mangle]
+pub unsafe extern "C" fn soundPcmOpen(
+    _filename: *mut u8,
+    _flags: i32,
+    _mode: i32,
+    _fd: *mut OpenFile,
+    _sym: *mut *mut u8,
+) -> usize {
+    if soundBackend.is_null() {
+        return err(ENODEV);
+    }
+
+    soundLock();
+    // one stream, one user: no software mixing down here
+    if soundPcm.opens > 0 {
+        soundUnlock();
+        return err(EBUSY);
+    }
+    soundPcm.opens = 1;
+    soundPcm.state = SNDRV_PCM_STATE_OPEN;
+    soundUnlock();
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn soundPcmWrite(fd: *mut OpenFile, buf: *const u8, len: usize) -> usize {
+    soundLock();
+    if soundPcm.frameBytes == 0 {
+        soundUnlock();
+        return err(EBADFD);
+    }
+    let frames = (len / soundPcm.frameBytes) as u64;
+    let ret = match soundWrite((*fd).flags & O_NONBLOCK != 0, buf, frames) {
+        Ok(done) => done as usize * soundPcm.frameBytes,
+        Err(code) => code,
+    };
+    soundUnlock();
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn soundPcmInternalPoll(_fd: *mut OpenFile, events: i32) -> i32 {
+    soundLock();
+    soundUpdate();
+    let ret = match soundPcm.state {
+        SNDRV_PCM_STATE_PREPARED | SNDRV_PCM_STATE_RUNNING | SNDRV_PCM_STATE_PAUSED => {
+            if soundAvail() >= soundPcm.availMin {
+                events & EPOLLOUT
+            } else {
+                0
+            }
+        }
+        SNDRV_PCM_STATE_DRAINING => 0,
+        _ => (events & EPOLLOUT) | EPOLLERR,
+    };
+    soundUnlock();
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn soundPcmReportKey(_fd: *mut OpenFile) -> usize {
+    core::ptr::addr_of!(soundPcm) as usize
+}
+
+// The data ring for MMAP_INTERLEAVED, the status/control pages are left to
+// SYNC_PTR
+#[no_mangle]
+pub unsafe extern "C" fn soundPcmMmap(
+    addr: usize,
+    length: usize,
+    _prot: i32,
+    flags: i32,
+    _fd: *mut OpenFile,
+    pgoffset: usize,
+) -> usize {
+    soundLock();
+    if pgoffset != SNDRV_PCM_MMAP_OFFSET_DATA
+        || soundPcm.state == SNDRV_PCM_STATE_OPEN
+        || length > soundPcm.stream.bytes.div_ceil(PAGE_SIZE) * PAGE_SIZE
+    {
+        soundUnlock();
+        return err(ENXIO);
+    }
+
+    let pages = length.div_ceil(PAGE_SIZE);
+    let pd = (*currentTask).infoPd;
+    let virt = if flags & MAP_FIXED != 0 {
+        addr
+    } else {
+        spinlockAcquire(&mut (*pd).LOCK_PD);
+        let virt = (*pd).mmap_end as usize;
+        (*pd).mmap_end += (pages * PAGE_SIZE) as u64;
+        spinlockRelease(&mut (*pd).LOCK_PD);
+        virt
+    };
+
+    for i in 0..pages {
+        VirtualMap(virt + i * PAGE_SIZE, soundRingPhys + i * PAGE_SIZE, PF_RW | PF_USER | PF_SHARED);
+    }
+    soundUnlock();
+    virt
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn soundPcmDuplicate(_orig: *mut OpenFile, _new: *mut OpenFile) -> bool {
+    soundLock();
+    soundPcm.opens += 1;
+    soundUnlock();
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn soundPcmClose(_fd: *mut OpenFile) -> bool {
+    soundLock();
+    soundPcm.opens -= 1;
+    if soundPcm.opens == 0 {
+        if soundPcm.state == SNDRV_PCM_STATE_PAUSED {
+            soundPcm.state = SNDRV_PCM_STATE_RUNNING;
+        }
+        soundStop(SNDRV_PCM_STATE_OPEN);
+        soundPcm.frameBytes = 0;
+    }
+    soundUnlock();
+    true
+}
+
+// --------------------------------
+// /dev/snd/controlC0 handlers
+// --------------------------------
+
+unsafe fn soundCardInfo(info: *mut snd_ctl_card_info) -> usize {
+    let backend = &*soundBackend;
+    write_bytes(info, 0, 1);
+    (*info).card = 0;
+    soundCopyName(&mut (*info).id, backend.id);
+    soundCopyName(&mut (*info).driver, backend.driver);
+    soundCopyName(&mut (*info).name, backend.name);
+    soundCopyName(&mut (*info).longname, backend.name);
+    soundCopyName(&mut (*info).mixername, backend.name);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn soundControlIoctl(_fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    if (request >> 8) & 0xff != SNDRV_CTL_IOCTL_BASE {
+        return err(ENOTTY);
+    }
+
+    match (request & 0xff) as usize {
+        SNDRV_CTL_IOCTL_PVERSION => {
+            *(arg as *mut i32) = SNDRV_CTL_VERSION;
+            0
+        }
+        SNDRV_CTL_IOCTL_CARD_INFO => soundCardInfo(arg as *mut snd_ctl_card_info),
+
+        // no mixer elements
+        SNDRV_CTL_IOCTL_ELEM_LIST => {
+            let list = arg as *mut snd_ctl_elem_list;
+            (*list).used = 0;
+            (*list).count = 0;
+            0
+        }
+        SNDRV_CTL_IOCTL_ELEM_INFO | SNDRV_CTL_IOCTL_ELEM_READ | SNDRV_CTL_IOCTL_ELEM_WRITE => {
+            err(ENOENT)
+        }
+        SNDRV_CTL_IOCTL_SUBSCRIBE_EVENTS => {
+            let subscribe = arg as *mut i32;
+            if *subscribe < 0 {
+                *subscribe = 0;
+            }
+            0
+        }
+
+        SNDRV_CTL_IOCTL_PCM_NEXT_DEVICE => {
+            let device = arg as *mut i32;
+            *device = if *device < 0 { 0 } else { -1 };
+            0
+        }
+        SNDRV_CTL_IOCTL_PCM_INFO => {
+            soundLock();
+            let ret = soundPcmInfo(arg as *mut snd_pcm_info);
+            soundUnlock();
+            ret
+        }
+        SNDRV_CTL_IOCTL_PCM_PREFER_SUBDEVICE => 0,
+        SNDRV_CTL_IOCTL_HWDEP_NEXT_DEVICE | SNDRV_CTL_IOCTL_RAWMIDI_NEXT_DEVICE => {
+            *(arg as *mut i32) = -1;
+            0
+        }
+        SNDRV_CTL_IOCTL_POWER => 0,
+        SNDRV_CTL_IOCTL_POWER_STATE => {
+            *(arg as *mut i32) = SNDRV_CTL_POWER_D0;
+            0
+        }
+
+        _ => err(ENOTTY),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn soundControlOpen(
+    _filename: *mut u8,
+    _flags: i32,
+    _mode: i32,
+    _fd: *mut OpenFile,
+    _sym: *mut *mut u8,
+) -> usize {
+    if soundBackend.is_null() {
+        return err(ENODEV);
+    }
+    0
+}
+
+// --------------------------------
+// Driver interface
+// --------------------------------
+
+#[no_mangle]
+pub unsafe extern "C" fn soundRegisterBackend(backend: *const SoundBackend) {
+    // card 0 is whoever shows up first
+    if !soundBackend.is_null() {
+        return;
+    }
+
+    soundRing = VirtualAllocatePhysicallyContiguous(SOUND_RING_PAGES);
+    if soundRing.is_null() {
+        debugf(b"[sound] Couldn't allocate the DMA ring!\n\0".as_ptr());
+        return;
+    }
+    write_bytes(soundRing, 0, SOUND_RING_BYTES);
+    soundRingPhys = VirtualToPhysical(soundRing as usize);
+    soundBackend = backend;
+
+    debugf(b"[sound] Using %s for /dev/snd/pcmC0D0p\n\0".as_ptr(), (*backend).name);
+}
+
+// From the driver's interrupt handler, every time a period got played
+#[no_mangle]
+pub unsafe extern "C" fn soundPeriodElapsed() {
+    if SOUND_BUSY.swap(true, Ordering::Acquire) {
+        SOUND_PENDING.store(true, Ordering::Release);
+    } else {
+        soundUpdate();
+        SOUND_BUSY.store(false, Ordering::Release);
+    }
+    pollInstanceRing(core::ptr::addr_of!(soundPcm) as usize, EPOLLOUT);
+}
+
+// --------------------------------
+// Registration
+// --------------------------------
+
+#[no_mangle]
+pub static handleSndPcm: VfsHandlers = VfsHandlers {
+    open: Some(soundPcmOpen),
+    duplicate: Some(soundPcmDuplicate),
+    close: Some(soundPcmClose),
+    read: None,
+    write: Some(soundPcmWrite),
+    internalPoll: Some(soundPcmInternalPoll),
+    ioctl: Some(soundPcmIoctl),
+    mmap: Some(soundPcmMmap),
+    reportKey: Some(soundPcmReportKey),
+    stat: Some(fakefsFstat),
+};
+
+#[no_mangle]
+pub static handleSndControl: VfsHandlers = VfsHandlers {
+    open: Some(soundControlOpen),
+    duplicate: None,
+    close: None,
+    read: None,
+    write: None,
+    internalPoll: None,
+    ioctl: Some(soundControlIoctl),
+    mmap: None,
+    reportKey: None,
+    stat: Some(fakefsFstat),
+};
@@ -0,0 +1,749 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::ptr::{null_mut, read_unaligned, write_bytes};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::usb_hid::{usb_hid_attach, usb_hid_detach, usb_hid_tick};
+use crate::usb_storage::{usb_storage_attach, usb_storage_detach};
+use crate::xhci::*;
+
+//
+// ================= Externs =================
+//
+
+extern "C" {
+    fn debugf(fmt: *const u8, ...) -> i32;
+
+    fn malloc(size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    fn VirtualAllocatePhysicallyContiguous(pages: usize) -> *mut u8;
+    fn VirtualToPhysical(addr: usize) -> usize;
+
+    fn spinlockAcquire(lock: *mut Spinlock);
+    fn spinlockRelease(lock: *mut Spinlock);
+
+    fn taskCreateKernel(entry: u64, arg: u64) -> *mut Task;
+    fn taskNameKernel(task: *mut Task, name: *const u8, len: usize);
+    fn sleep(ms: u32);
+
+    static timerTicks: u64;
+}
+
+#[repr(C)]
+pub struct Task;
+
+//
+// ================= Constants =================
+//
+
+const BLOCK_SIZE: usize = 4096;
+
+// bmRequestType
+pub const USB_DIR_IN: u8 = 0x80;
+pub const USB_TYPE_CLASS: u8 = 0x20;
+pub const USB_RECIP_INTERFACE: u8 = 0x01;
+pub const USB_RECIP_ENDPOINT: u8 = 0x02;
+pub const USB_RECIP_OTHER: u8 = 0x03;
+
+// standard requests
+pub const USB_REQ_GET_STATUS: u8 = 0;
+pub const USB_REQ_CLEAR_FEATURE: u8 = 1;
+pub const USB_REQ_SET_FEATURE: u8 = 3;
+pub const USB_REQ_GET_DESCRIPTOR: u8 = 6;
+pub const USB_REQ_SET_CONFIGURATION: u8 = 9;
+const USB_REQ_SET_HUB_DEPTH: u8 = 12;
+
+pub const USB_FEATURE_ENDPOINT_HALT: u16 = 0;
+
+// descriptor types
+pub const USB_DT_DEVICE: u8 = 1;
+pub const USB_DT_CONFIG: u8 = 2;
+pub const USB_DT_INTERFACE: u8 = 4;
+pub const USB_DT_ENDPOINT: u8 = 5;
+pub const USB_DT_HID: u8 = 0x21;
+pub const USB_DT_REPORT: u8 = 0x22;
+const USB_DT_HUB: u8 = 0x29;
+const USB_DT_SS_HUB: u8 = 0x2a;
+
+// interface classes
+const USB_CLASS_HID: u8 = 3;
+const USB_CLASS_MASS_STORAGE: u8 = 8;
+const USB_CLASS_HUB: u8 = 9;
+
+pub const USB_ENDPOINT_BULK: u8 = 2;
+pub const USB_ENDPOINT_INTERRUPT: u8 = 3;
+
+// hub port features and status
+const HUB_PORT_CONNECTION: u16 = 0;
+const HUB_PORT_RESET: u16 = 4;
+const HUB_PORT_POWER: u16 = 8;
+const HUB_C_PORT_CONNECTION: u16 = 16;
+
+const HUB_STATUS_CONNECTION: u16 = 1 << 0;
+const HUB_STATUS_ENABLE: u16 = 1 << 1;
+const HUB_STATUS_LOW_SPEED: u16 = 1 << 9;
+const HUB_STATUS_HIGH_SPEED: u16 = 1 << 10;
+const HUB_CHANGE_RESET: u16 = 1 << 4;
+const HUB_CHANGE_BH_RESET: u16 = 1 << 5;
+
+// route strings have room for five tiers of hubs, four bits a port
+const USB_MAX_DEPTH: u8 = 5;
+const USB_HUB_MAX_PORTS: usize = 15;
+
+// how often the usb thread looks around
+const USB_THREAD_PERIOD: u32 = 10;
+
+//
+// ================= Descriptors =================
+//
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct UsbSetup {
+    pub bmRequestType: u8,
+    pub bRequest: u8,
+    pub wValue: u16,
+    pub wIndex: u16,
+    pub wLength: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct UsbDeviceDescriptor {
+    pub bLength: u8,
+    pub bDescriptorType: u8,
+    pub bcdUSB: u16,
+    pub bDeviceClass: u8,
+    pub bDeviceSubClass: u8,
+    pub bDeviceProtocol: u8,
+    pub bMaxPacketSize0: u8,
+    pub idVendor: u16,
+    pub idProduct: u16,
+    pub bcdDevice: u16,
+    pub iManufacturer: u8,
+    pub iProduct: u8,
+    pub iSerialNumber: u8,
+    pub bNumConfigurations: u8,
+}
+
+#[repr(C)]
+pub struct UsbInterfaceDescriptor {
+    pub bLength: u8,
+    pub bDescriptorType: u8,
+    pub bInterfaceNumber: u8,
+    pub bAlternateSetting: u8,
+    pub bNumEndpoints: u8,
+    pub bInterfaceClass: u8,
+    pub bInterfaceSubClass: u8,
+    pub bInterfaceProtocol: u8,
+    pub iInterface: u8,
+}
+
+#[repr(C)]
+pub struct UsbEndpointDescriptor {
+    pub bLength: u8,
+    pub bDescriptorType: u8,
+    pub bEndpointAddress: u8,
+    pub bmAttributes: u8,
+    pub wMaxPacketSize: [u8; 2],
+    pub bInterval: u8,
+}
+
+//
+// ================= Devices =================
+//
+
+pub struct UsbDevice {
+    pub hc: *mut Xhci,
+    pub slot: u8,
+    pub speed: u8,
+
+    // where it is: root hub port, route string through hubs and how many
+    // of those are in the way
+    pub rootPort: u8,
+    pub route: u32,
+    pub depth: u8,
+    pub parent: *mut UsbDevice,
+    pub port: u8,
+
+    // transaction translator, for low/full speed devices behind a high
+    // speed hub
+    pub ttSlot: u8,
+    pub ttPort: u8,
+
+    pub maxPacket0: u16,
+    pub LOCK_CONTROL: Spinlock,
+    // bounce page for control transfers
+    pub dma: *mut u8,
+    pub dmaPhys: usize,
+
+    pub descriptor: UsbDeviceDescriptor,
+    pub config: *mut u8,
+    pub configLength: usize,
+
+    pub hub: *mut UsbHub,
+    // unplugged, whatever still holds on to it should let go
+    pub gone: bool,
+
+    next: *mut UsbDevice,
+}
+
+pub struct UsbHub {
+    pub dev: *mut UsbDevice,
+    ports: u8,
+    superSpeed: bool,
+    powerDelay: u32,
+
+    status: *mut u8,
+    transfer: UsbTransfer,
+    changed: AtomicBool,
+
+    children: [*mut UsbDevice; USB_HUB_MAX_PORTS + 1],
+}
+
+static mut USB_DEVICES: *mut UsbDevice = null_mut();
+static mut LOCK_USB_DEVICES: Spinlock = Spinlock { locked: 0 };
+static mut USB_THREAD: bool = false;
+
+//
+// ================= Control requests =================
+//
+
+pub unsafe fn usb_control(
+    dev: *mut UsbDevice,
+    requestType: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    data: *mut u8,
+) -> Result<usize, u8> {
+    if (*dev).gone {
+        return Err(CC_TIMEOUT);
+    }
+
+    let setup = UsbSetup {
+        bmRequestType: requestType,
+        bRequest: request,
+        wValue: value,
+        wIndex: index,
+        wLength: length,
+    };
+
+    spinlockAcquire(&mut (*dev).LOCK_CONTROL);
+    let ret = xhci_control(dev, &setup, data);
+    spinlockRelease(&mut (*dev).LOCK_CONTROL);
+    ret
+}
+
+pub unsafe fn usb_get_descriptor(dev: *mut UsbDevice, kind: u8, index: u8, out: *mut u8, length: u16) -> bool {
+    let value = ((kind as u16) << 8) | index as u16;
+    match usb_control(dev, USB_DIR_IN, USB_REQ_GET_DESCRIPTOR, value, 0, length, out) {
+        Ok(actual) => actual >= 2,
+        Err(_) => false,
+    }
+}
+
+pub unsafe fn usb_clear_halt(dev: *mut UsbDevice, endpoint: u8) -> bool {
+    let ret = usb_control(
+        dev,
+        USB_RECIP_ENDPOINT,
+        USB_REQ_CLEAR_FEATURE,
+        USB_FEATURE_ENDPOINT_HALT,
+        endpoint as u16,
+        0,
+        null_mut(),
+    );
+    // the controller's side of the endpoint has to start over too
+    xhci_reset_endpoint(dev, endpoint) && ret.is_ok()
+}
+
+// Next endpoint descriptor of the given kind after the interface, looking
+// no further than the next interface
+pub unsafe fn usb_find_endpoint(
+    iface: *const UsbInterfaceDescriptor,
+    end: *const u8,
+    kind: u8,
+    input: bool,
+) -> *const UsbEndpointDescriptor {
+    let mut ptr = (iface as *const u8).add((*iface).bLength as usize);
+    while ptr.add(2) <= end && *ptr >= 2 {
+        match *ptr.add(1) {
+            USB_DT_INTERFACE => break,
+            USB_DT_ENDPOINT => {
+                let ep = ptr as *const UsbEndpointDescriptor;
+                if (*ep).bmAttributes & 0b11 == kind && ((*ep).bEndpointAddress & 0x80 != 0) == input {
+                    return ep;
+                }
+            }
+            _ => {}
+        }
+        ptr = ptr.add(*ptr as usize);
+    }
+    core::ptr::null()
+}
+
+// Class specific descriptor between an interface and its endpoints
+pub unsafe fn usb_find_class_descriptor(iface: *const UsbInterfaceDescriptor, end: *const u8, kind: u8) -> *const u8 {
+    let mut ptr = (iface as *const u8).add((*iface).bLength as usize);
+    while ptr.add(2) <= end && *ptr >= 2 {
+        match *ptr.add(1) {
+            USB_DT_INTERFACE => break,
+            found if found == kind => return ptr,
+            _ => {}
+        }
+        ptr = ptr.add(*ptr as usize);
+    }
+    core::ptr::null()
+}
+
+//
+// ================= Enumeration =================
+//
+
+unsafe fn usb_device_list_add(dev: *mut UsbDevice) {
+    spinlockAcquire(&mut LOCK_USB_DEVICES);
+    (*dev).next = USB_DEVICES;
+    USB_DEVICES = dev;
+    spinlockRelease(&mut LOCK_USB_DEVICES);
+}
+
+unsafe fn usb_device_list_remove(dev: *mut UsbDevice) {
+    spinlockAcquire(&mut LOCK_USB_DEVICES);
+    let mut browse = &mut USB_DEVICES as *mut *mut UsbDevice;
+    while !(*browse).is_null() {
+        if *browse == dev {
+            *browse = (*dev).next;
+            break;
+        }
+        browse = &mut (**browse).next;
+    }
+    spinlockRelease(&mut LOCK_USB_DEVICES);
+}
+
+// Hands every interface of the active configuration to its class driver
+unsafe fn usb_configure(dev: *mut UsbDevice) {
+    let config = (*dev).config;
+    let end = config.add((*dev).configLength);
+
+    let mut ptr = config;
+    while ptr.add(2) <= end && *ptr >= 2 {
+        if *ptr.add(1) == USB_DT_INTERFACE {
+            let iface = ptr as *const UsbInterfaceDescriptor;
+            if (*iface).bAlternateSetting == 0 {
+                match (*iface).bInterfaceClass {
+                    USB_CLASS_HUB => usb_hub_attach(dev, iface, end),
+                    USB_CLASS_HID => usb_hid_attach(dev, iface, end),
+                    USB_CLASS_MASS_STORAGE => usb_storage_attach(dev, iface, end),
+                    _ => {}
+                }
+            }
+        }
+        ptr = ptr.add(*ptr as usize);
+    }
+}
+
+// Addresses and configures a newly connected (and reset) device
+unsafe fn usb_enumerate(
+    hc: *mut Xhci,
+    parent: *mut UsbDevice,
+    port: u8,
+    speed: u8,
+) -> *mut UsbDevice {
+    let Some(slot) = xhci_enable_slot(hc) else {
+        return null_mut();
+    };
+
+    let dev = malloc(core::mem::size_of::<UsbDevice>()) as *mut UsbDevice;
+    write_bytes(dev, 0, 1);
+    (*dev).hc = hc;
+    (*dev).slot = slot;
+    (*dev).speed = speed;
+    (*dev).parent = parent;
+    (*dev).port = port;
+    (*dev).dma = VirtualAllocatePhysicallyContiguous(1);
+    (*dev).dmaPhys = VirtualToPhysical((*dev).dma as usize);
+
+    if parent.is_null() {
+        (*dev).rootPort = port;
+    } else {
+        (*dev).rootPort = (*parent).rootPort;
+        (*dev).depth = (*parent).depth + 1;
+        (*dev).route = (*parent).route | ((port.min(15) as u32) << (4 * (*parent).depth));
+
+        if speed == USB_SPEED_LOW || speed == USB_SPEED_FULL {
+            if (*parent).speed == USB_SPEED_HIGH {
+                (*dev).ttSlot = (*parent).slot;
+                (*dev).ttPort = port;
+            } else {
+                (*dev).ttSlot = (*parent).ttSlot;
+                (*dev).ttPort = (*parent).ttPort;
+            }
+        }
+    }
+
+    (*dev).maxPacket0 = default_max_packet(speed);
+    if !xhci_address_device(dev) {
+        debugf(b"[usb] Couldn't address device on port %d\n\0".as_ptr(), port as u32);
+        return usb_abandon(dev);
+    }
+
+    // the first 8 bytes are enough for EP0's real packet size
+    let descriptor = &mut (*dev).descriptor as *mut UsbDeviceDescriptor as *mut u8;
+    if !usb_get_descriptor(dev, USB_DT_DEVICE, 0, descriptor, 8) {
+        debugf(b"[usb] Device on port %d doesn't answer\n\0".as_ptr(), port as u32);
+        return usb_abandon(dev);
+    }
+    let maxPacket0 = if speed >= USB_SPEED_SUPER {
+        1 << (*dev).descriptor.bMaxPacketSize0.min(15)
+    } else {
+        (*dev).descriptor.bMaxPacketSize0 as u16
+    };
+    if maxPacket0 != (*dev).maxPacket0 && maxPacket0 >= 8 {
+        (*dev).maxPacket0 = maxPacket0;
+        xhci_update_ep0(dev);
+    }
+
+    let size = core::mem::size_of::<UsbDeviceDescriptor>() as u16;
+    if !usb_get_descriptor(dev, USB_DT_DEVICE, 0, descriptor, size) {
+        return usb_abandon(dev);
+    }
+
+    // configuration header first, for how long the whole thing is
+    let mut header = [0u8; 9];
+    if !usb_get_descriptor(dev, USB_DT_CONFIG, 0, header.as_mut_ptr(), 9) {
+        return usb_abandon(dev);
+    }
+    let total = (u16::from_le_bytes([header[2], header[3]]) as usize).clamp(9, BLOCK_SIZE);
+    (*dev).config = malloc(total);
+    if !usb_get_descriptor(dev, USB_DT_CONFIG, 0, (*dev).config, total as u16) {
+        return usb_abandon(dev);
+    }
+    (*dev).configLength = total;
+
+    let value = header[5];
+    if usb_control(dev, 0, USB_REQ_SET_CONFIGURATION, value as u16, 0, 0, null_mut()).is_err() {
+        debugf(b"[usb] Couldn't configure device on port %d\n\0".as_ptr(), port as u32);
+        return usb_abandon(dev);
+    }
+
+    let desc = (*dev).descriptor;
+    let (vendor, product) = (desc.idVendor, desc.idProduct);
+    debugf(
+        b"[usb] Device %04x:%04x, slot %d, speed %d, route %x\n\0".as_ptr(),
+        vendor as u32,
+        product as u32,
+        slot as u32,
+        speed as u32,
+        (*dev).route,
+    );
+
+    usb_device_list_add(dev);
+    usb_configure(dev);
+    dev
+}
+
+// Gives up on a device that never got anywhere
+unsafe fn usb_release(dev: *mut UsbDevice) {
+    (*dev).gone = true;
+    xhci_disable_slot((*dev).hc, (*dev).slot);
+    if !(*dev).config.is_null() {
+        free((*dev).config);
+        (*dev).config = null_mut();
+    }
+}
+
+unsafe fn usb_abandon(dev: *mut UsbDevice) -> *mut UsbDevice {
+    usb_release(dev);
+    free(dev as *mut u8);
+    null_mut()
+}
+
+// Unplugged: class drivers let go, along with everything behind it if
+// it's a hub. The struct itself stays, drivers might still look at it
+unsafe fn usb_detach(dev: *mut UsbDevice) {
+    if dev.is_null() || (*dev).gone {
+        return;
+    }
+    (*dev).gone = true;
+
+    let hub = (*dev).hub;
+    if !hub.is_null() {
+        for port in 1..=(*hub).ports as usize {
+            usb_detach((*hub).children[port]);
+            (*hub).children[port] = null_mut();
+        }
+    }
+
+    usb_hid_detach(dev);
+    usb_storage_detach(dev);
+
+    debugf(b"[usb] Device in slot %d detached\n\0".as_ptr(), (*dev).slot as u32);
+    usb_device_list_remove(dev);
+    usb_release(dev);
+}
+
+unsafe fn usb_root_device(hc: *mut Xhci, port: u8) -> *mut UsbDevice {
+    spinlockAcquire(&mut LOCK_USB_DEVICES);
+    let mut browse = USB_DEVICES;
+    while !browse.is_null() {
+        if (*browse).hc == hc && (*browse).parent.is_null() && (*browse).rootPort == port {
+            break;
+        }
+        browse = (*browse).next;
+    }
+    spinlockRelease(&mut LOCK_USB_DEVICES);
+    browse
+}
+
+// Something on a root hub port
+pub unsafe fn usb_root_attach(hc: *mut Xhci, port: u8) {
+    if let Some(speed) = xhci_port_reset(hc, port) {
+        usb_enumerate(hc, null_mut(), port, speed);
+    }
+}
+
+//
+// ================= Hubs =================
+//
+
+unsafe fn hub_feature(hub: *mut UsbHub, set: bool, feature: u16, port: u8) -> bool {
+    let request = if set { USB_REQ_SET_FEATURE } else { USB_REQ_CLEAR_FEATURE };
+    usb_control((*hub).dev, USB_TYPE_CLASS | USB_RECIP_OTHER, request, feature, port as u16, 0, null_mut())
+        .is_ok()
+}
+
+// (status, change)
+unsafe fn hub_port_status(hub: *mut UsbHub, port: u8) -> Option<(u16, u16)> {
+    let mut out = [0u8; 4];
+    let requestType = USB_DIR_IN | USB_TYPE_CLASS | USB_RECIP_OTHER;
+    usb_control((*hub).dev, requestType, USB_REQ_GET_STATUS, 0, port as u16, 4, out.as_mut_ptr()).ok()?;
+    Some((u16::from_le_bytes([out[0], out[1]]), u16::from_le_bytes([out[2], out[3]])))
+}
+
+unsafe fn hub_port_reset(hub: *mut UsbHub, port: u8) -> Option<u8> {
+    hub_feature(hub, true, HUB_PORT_RESET, port);
+
+    let start = timerTicks;
+    loop {
+        sleep(10);
+        let (status, change) = hub_port_status(hub, port)?;
+        if change & (HUB_CHANGE_RESET | HUB_CHANGE_BH_RESET) != 0 || timerTicks > start + 500 {
+            // reset recovery
+            sleep(10);
+            if status & HUB_STATUS_ENABLE == 0 {
+                return None;
+            }
+            return Some(if (*hub).superSpeed {
+                USB_SPEED_SUPER
+            } else if status & HUB_STATUS_LOW_SPEED != 0 {
+                USB_SPEED_LOW
+            } else if status & HUB_STATUS_HIGH_SPEED != 0 {
+                USB_SPEED_HIGH
+            } else {
+                USB_SPEED_FULL
+            });
+        }
+    }
+}
+
+// Acknowledges everything a port says changed, attaching or detaching
+// whatever's behind it on a connection change
+unsafe fn hub_port_check(hub: *mut UsbHub, port: u8) {
+    let Some((status, change)) = hub_port_status(hub, port) else {
+        return;
+    };
+
+    for bit in 1..=6u16 {
+        if change & (1 << bit) == 0 {
+            continue;
+        }
+        // enable, suspend, over current and reset are 16 + bit, the
+        // super speed ones are numbered their own way
+        let feature = match bit {
+            5 => 29,
+            6 => 25,
+            _ => HUB_C_PORT_CONNECTION + bit,
+        };
+        hub_feature(hub, false, feature, port);
+    }
+
+    if change & (1 << HUB_PORT_CONNECTION) == 0 {
+        return;
+    }
+    hub_feature(hub, false, HUB_C_PORT_CONNECTION, port);
+
+    let child = (*hub).children[port as usize];
+    if !child.is_null() {
+        usb_detach(child);
+        (*hub).children[port as usize] = null_mut();
+    }
+
+    if status & HUB_STATUS_CONNECTION == 0 {
+        return;
+    }
+
+    let dev = (*hub).dev;
+    if (*dev).depth + 1 >= USB_MAX_DEPTH {
+        debugf(b"[usb] Hubs nested too deep, ignoring port %d\n\0".as_ptr(), port as u32);
+        return;
+    }
+
+    // debounce
+    sleep(100);
+    if let Some(speed) = hub_port_reset(hub, port) {
+        (*hub).children[port as usize] = usb_enumerate((*dev).hc, dev, port, speed);
+    }
+}
+
+// Status change endpoint, each bit past the first being a port
+unsafe fn hub_status_callback(transfer: *mut UsbTransfer) {
+    let hub = (*transfer).context as *mut UsbHub;
+    (*hub).changed.store(true, Ordering::Release);
+}
+
+unsafe fn usb_hub_attach(dev: *mut UsbDevice, iface: *const UsbInterfaceDescriptor, end: *const u8) {
+    if !(*dev).hub.is_null() {
+        return;
+    }
+
+    let superSpeed = (*dev).speed >= USB_SPEED_SUPER;
+    if superSpeed {
+        let requestType = USB_TYPE_CLASS;
+        usb_control(dev, requestType, USB_REQ_SET_HUB_DEPTH, (*dev).depth as u16, 0, 0, null_mut()).ok();
+    }
+
+    let mut desc = [0u8; 12];
+    let kind = if superSpeed { USB_DT_SS_HUB } else { USB_DT_HUB };
+    let requestType = USB_DIR_IN | USB_TYPE_CLASS;
+    let value = (kind as u16) << 8;
+    if usb_control(dev, requestType, USB_REQ_GET_DESCRIPTOR, value, 0, desc.len() as u16, desc.as_mut_ptr()).is_err() {
+        debugf(b"[usb] Couldn't read hub descriptor\n\0".as_ptr());
+        return;
+    }
+
+    let ports = desc[2].min(USB_HUB_MAX_PORTS as u8);
+    let characteristics = u16::from_le_bytes([desc[3], desc[4]]);
+    let thinkTime = ((characteristics >> 5) & 0b11) as u8;
+    let multiTT = (*dev).descriptor.bDeviceProtocol == 2;
+    if !xhci_configure_hub(dev, ports, thinkTime, multiTT) {
+        return;
+    }
+
+    let endpoint = usb_find_endpoint(iface, end, USB_ENDPOINT_INTERRUPT, true);
+    if endpoint.is_null() || !xhci_open_endpoint(dev, endpoint) {
+        debugf(b"[usb] Hub has no status endpoint\n\0".as_ptr());
+        return;
+    }
+
+    let hub = malloc(core::mem::size_of::<UsbHub>()) as *mut UsbHub;
+    write_bytes(hub, 0, 1);
+    (*hub).dev = dev;
+    (*hub).ports = ports;
+    (*hub).superSpeed = superSpeed;
+    (*hub).powerDelay = desc[5] as u32 * 2;
+    (*hub).status = VirtualAllocatePhysicallyContiguous(1);
+    (*hub).transfer = UsbTransfer::new();
+    (*hub).transfer.dev = dev;
+    (*hub).transfer.endpoint = (*endpoint).bEndpointAddress;
+    (*hub).transfer.buffer = (*hub).status;
+    (*hub).transfer.length = (ports as usize / 8 + 1).min(u16::from_le_bytes((*endpoint).wMaxPacketSize) as usize);
+    (*hub).transfer.callback = Some(hub_status_callback);
+    (*hub).transfer.context = hub as *mut core::ffi::c_void;
+    (*dev).hub = hub;
+
+    debugf(b"[usb] Hub with %d ports\n\0".as_ptr(), ports as u32);
+
+    for port in 1..=ports {
+        hub_feature(hub, true, HUB_PORT_POWER, port);
+    }
+    sleep((*hub).powerDelay.max(20));
+
+    // whatever is plugged in already
+    for port in 1..=ports {
+        hub_port_check(hub, port);
+    }
+    xhci_queue(&mut (*hub).transfer);
+}
+
+unsafe fn usb_hub_poll(hub: *mut UsbHub) {
+    if !(*hub).changed.swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    if (*hub).transfer.code == CC_SUCCESS || (*hub).transfer.code == CC_SHORT_PACKET {
+        let bitmap = read_unaligned((*hub).status as *const u16);
+        for port in 1..=(*hub).ports {
+            if bitmap & (1 << port) != 0 {
+                hub_port_check(hub, port);
+            }
+        }
+    } else if (*hub).transfer.code == CC_STALL {
+        usb_clear_halt((*hub).dev, (*hub).transfer.endpoint);
+    }
+
+    if !(*(*hub).dev).gone {
+        xhci_queue(&mut (*hub).transfer);
+    }
+}
+
+//
+// ================= usb thread =================
+//
+
+// Hotplug, on the root ports and on hubs. Enumerating takes control
+// transfers that wait, so none of it can happen in the interrupt handler
+extern "C" fn usb_thread() {
+    unsafe {
+        let mut changes = [0u32; 8];
+        loop {
+            for &hc in xhci_controllers() {
+                xhci_port_changes(hc, &mut changes);
+                for port in 1..=(*hc).maxPorts {
+                    let index = port as usize - 1;
+                    if changes[index / 32] & (1 << (index % 32)) == 0 {
+                        continue;
+                    }
+                    if !xhci_port_clear(hc, port) {
+                        continue;
+                    }
+
+                    let dev = usb_root_device(hc, port);
+                    if !dev.is_null() {
+                        usb_detach(dev);
+                    }
+                    if xhci_port_connected(hc, port) {
+                        // debounce
+                        sleep(100);
+                        usb_root_attach(hc, port);
+                    }
+                }
+            }
+
+            // hubs hang off the device list, which only this thread changes
+            let mut browse = USB_DEVICES;
+            while !browse.is_null() {
+                if !(*browse).hub.is_null() {
+                    usb_hub_poll((*browse).hub);
+                }
+                browse = (*browse).next;
+            }
+
+            usb_hid_tick();
+            sleep(USB_THREAD_PERIOD);
+        }
+    }
+}
+
+pub unsafe fn usb_thread_start() {
+    if USB_THREAD {
+        return;
+    }
+    USB_THREAD = true;
+
+    static NAME: &[u8] = b"usbd";
+    let task = taskCreateKernel(usb_thread as u64, 0);
+    taskNameKernel(task, NAME.as_ptr(), NAME.len());
+}
@@ -0,0 +1,977 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::cmp::min;
+use core::ptr::{copy_nonoverlapping, null_mut, write_bytes};
+
+use crate::kb::{kb_keycode, kb_leds};
+use crate::usb::*;
+use crate::xhci::*;
+
+//
+// ================= Externs =================
+//
+
+extern "C" {
+    fn debugf(fmt: *const u8, ...) -> i32;
+
+    fn malloc(size: usize) -> *mut u8;
+
+    fn VirtualAllocatePhysicallyContiguous(pages: usize) -> *mut u8;
+
+    fn devInputEventSetup(name: *const u8) -> *mut DevInputEvent;
+    fn inputGenerateEvent(dev: *mut DevInputEvent, etype: u16, code: u16, value: i32);
+
+    static timerTicks: u64;
+}
+
+//
+// ================= Constants =================
+//
+
+const HID_MAX_DEVICES: usize = 16;
+const HID_MAX_FIELDS: usize = 64;
+const HID_MAX_USAGES: usize = 16;
+// a page for the descriptor, and then for reports
+const HID_MAX_DESCRIPTOR: usize = 4096;
+
+const HID_SUBCLASS_BOOT: u8 = 1;
+const HID_PROTOCOL_KEYBOARD: u8 = 1;
+const HID_PROTOCOL_MOUSE: u8 = 2;
+
+// class requests
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0a;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0b;
+const HID_REPORT_OUTPUT: u16 = 2;
+
+// main item flags
+const HID_CONSTANT: u32 = 1 << 0;
+const HID_VARIABLE: u32 = 1 << 1;
+const HID_RELATIVE: u32 = 1 << 2;
+
+// usage pages
+const HID_PAGE_DESKTOP: u32 = 0x01;
+const HID_PAGE_KEYBOARD: u32 = 0x07;
+const HID_PAGE_LED: u32 = 0x08;
+const HID_PAGE_BUTTON: u32 = 0x09;
+const HID_PAGE_CONSUMER: u32 = 0x0c;
+
+const HID_USAGE_X: u32 = 0x30;
+const HID_USAGE_Y: u32 = 0x31;
+const HID_USAGE_Z: u32 = 0x32;
+const HID_USAGE_WHEEL: u32 = 0x38;
+const HID_USAGE_AC_PAN: u32 = 0x238;
+
+const HID_LED_NUM: u32 = 1;
+const HID_LED_CAPS: u32 = 2;
+const HID_LED_SCROLL: u32 = 3;
+
+// until a held key starts repeating, then between repeats
+const HID_REPEAT_DELAY: u64 = 250;
+const HID_REPEAT_PERIOD: u64 = 33;
+
+// evdev
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_REL: u16 = 0x02;
+const EV_ABS: u16 = 0x03;
+const EV_LED: u16 = 0x11;
+
+const SYN_REPORT: u16 = 0;
+
+const REL_X: u16 = 0x00;
+const REL_Y: u16 = 0x01;
+const REL_Z: u16 = 0x02;
+const REL_HWHEEL: u16 = 0x06;
+const REL_WHEEL: u16 = 0x08;
+
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+
+const LED_NUML: u16 = 0x00;
+const LED_CAPSL: u16 = 0x01;
+const LED_SCROLLL: u16 = 0x02;
+
+const BTN_MOUSE: u16 = 0x110;
+const KEY_CNT: usize = 0x300;
+
+// kb_leds()
+const K_SCROLLLOCK: u8 = 0x01;
+const K_NUMLOCK: u8 = 0x02;
+const K_CAPSLOCK: u8 = 0x04;
+
+// Keyboard page usages to evdev keycodes, as Linux maps them
+static HID_KEYBOARD: [u8; 0xe8] = {
+    let mut map = [0u8; 0xe8];
+    let rows: [(usize, [u8; 16]); 10] = [
+        (0x00, [0, 0, 0, 0, 30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38]),
+        (0x10, [50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44, 2, 3]),
+        (0x20, [4, 5, 6, 7, 8, 9, 10, 11, 28, 1, 14, 15, 57, 12, 13, 26]),
+        (0x30, [27, 43, 43, 39, 40, 41, 51, 52, 53, 58, 59, 60, 61, 62, 63, 64]),
+        (0x40, [65, 66, 67, 68, 87, 88, 99, 70, 119, 110, 102, 104, 111, 107, 109, 106]),
+        (0x50, [105, 108, 103, 69, 98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77, 71]),
+        (0x60, [72, 73, 82, 83, 86, 127, 116, 117, 183, 184, 185, 186, 187, 188, 189, 190]),
+        (0x70, [191, 192, 193, 194, 134, 138, 130, 132, 128, 129, 131, 137, 133, 135, 136, 113]),
+        (0x80, [115, 114, 0, 0, 0, 121, 0, 89, 93, 124, 92, 94, 95, 0, 0, 0]),
+        (0x90, [122, 123, 90, 91, 85, 0, 0, 0, 0, 0, 0, 0, 111, 0, 0, 0]),
+    ];
+    let mut row = 0;
+    while row < rows.len() {
+        let mut i = 0;
+        while i < 16 {
+            map[rows[row].0 + i] = rows[row].1[i];
+            i += 1;
+        }
+        row += 1;
+    }
+    // left ctrl, shift, alt, meta, then the right ones
+    let modifiers = [29, 42, 56, 125, 97, 54, 100, 126];
+    let mut i = 0;
+    while i < modifiers.len() {
+        map[0xe0 + i] = modifiers[i];
+        i += 1;
+    }
+    map
+};
+
+// What the boot protocol sends, for devices whose own descriptor doesn't
+// make sense (HID 1.11, appendix B)
+static HID_BOOT_KEYBOARD: [u8; 63] = [
+    0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25,
+    0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05,
+    0x75, 0x01, 0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91,
+    0x01, 0x95, 0x06, 0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65,
+    0x81, 0x00, 0xc0,
+];
+
+static HID_BOOT_MOUSE: [u8; 50] = [
+    0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29,
+    0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05,
+    0x81, 0x01, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95,
+    0x02, 0x81, 0x06, 0xc0, 0xc0,
+];
+
+//
+// ================= Kernel structs =================
+//
+
+#[repr(C)]
+pub struct InputId {
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+#[repr(C)]
+pub struct DevInputEvent {
+    pub inputid: InputId,
+    pub eventBit: extern "C" fn(*mut OpenFile, u64, *mut u8) -> usize,
+}
+
+#[repr(C)]
+pub struct OpenFile {
+    pub flags: u32,
+    pub dir: *mut DevInputEvent,
+}
+
+#[repr(C)]
+pub struct InputAbsInfo {
+    pub value: i32,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    pub resolution: i32,
+}
+
+//
+// ================= HID structs =================
+//
+
+// One input or output main item: count values of size bits each
+#[derive(Clone, Copy)]
+struct HidField {
+    reportId: u8,
+    output: bool,
+    flags: u32,
+
+    offset: u32,
+    size: u32,
+    count: u32,
+
+    logMin: i32,
+    logMax: i32,
+
+    // full usages (page << 16 | id), either listed or as a range
+    usages: [u32; HID_MAX_USAGES],
+    usageCount: usize,
+    usageMin: u32,
+    usageMax: u32,
+}
+
+#[derive(Clone, Copy)]
+struct HidGlobals {
+    usagePage: u32,
+    logMin: i32,
+    logMax: i32,
+    size: u32,
+    count: u32,
+    reportId: u8,
+}
+
+pub struct UsbHid {
+    dev: *mut UsbDevice,
+    iface: u8,
+    vendor: u16,
+    product: u16,
+    attached: bool,
+
+    event: *mut DevInputEvent,
+    keyboard: bool,
+
+    fields: [HidField; HID_MAX_FIELDS],
+    fieldCount: usize,
+    numbered: bool,
+
+    buffer: *mut u8,
+    transfer: UsbTransfer,
+    stalled: bool,
+
+    // capabilities, and the state userspace can ask for
+    keyBits: [u8; KEY_CNT / 8],
+    relBits: u16,
+    absBits: u16,
+    abs: [InputAbsInfo; 2],
+    keys: [u8; KEY_CNT / 8],
+
+    hasLeds: bool,
+    leds: u8,
+
+    repeatKey: u16,
+    repeatAt: u64,
+}
+
+static mut HID_DEVICES: [*mut UsbHid; HID_MAX_DEVICES] = [null_mut(); HID_MAX_DEVICES];
+
+//
+// ================= Helpers =================
+//
+
+fn bitmap_get(map: &[u8], index: usize) -> bool {
+    index / 8 < map.len() && map[index / 8] & (1 << (index % 8)) != 0
+}
+
+fn bitmap_set(map: &mut [u8], index: usize, value: bool) {
+    if index / 8 >= map.len() {
+        return;
+    }
+    if value {
+        map[index / 8] |= 1 << (index % 8);
+    } else {
+        map[index / 8] &= !(1 << (index % 8));
+    }
+}
+
+// Little endian bit field out of a report, whatever lies past its end is 0
+unsafe fn report_bits(data: *const u8, length: usize, offset: u32, size: u32) -> u32 {
+    let mut value = 0u32;
+    for bit in 0..size.min(32) {
+        let at = (offset + bit) as usize;
+        if at / 8 >= length {
+            break;
+        }
+        if *data.add(at / 8) & (1 << (at % 8)) != 0 {
+            value |= 1 << bit;
+        }
+    }
+    value
+}
+
+fn sign_extend(value: u32, size: u32) -> i32 {
+    if size == 0 || size >= 32 {
+        return value as i32;
+    }
+    let shift = 32 - size;
+    ((value << shift) as i32) >> shift
+}
+
+impl HidField {
+    // Usage of the index-th value of a variable field
+    fn variable_usage(&self, index: u32) -> u32 {
+        if self.usageCount > 0 {
+            return self.usages[(index as usize).min(self.usageCount - 1)];
+        }
+        (self.usageMin + index).min(self.usageMax)
+    }
+
+    // Usage an array field's value stands for, 0 for none
+    fn array_usage(&self, value: i32) -> u32 {
+        if value < self.logMin || value > self.logMax {
+            return 0;
+        }
+        let index = (value - self.logMin) as u32;
+        if self.usageCount > 0 {
+            return if (index as usize) < self.usageCount { self.usages[index as usize] } else { 0 };
+        }
+        if self.usageMin + index > self.usageMax {
+            return 0;
+        }
+        self.usageMin + index
+    }
+}
+
+// evdev key for a keyboard or button usage
+fn usage_keycode(usage: u32) -> u16 {
+    let id = usage & 0xffff;
+    match usage >> 16 {
+        HID_PAGE_KEYBOARD if (id as usize) < HID_KEYBOARD.len() => HID_KEYBOARD[id as usize] as u16,
+        // left, right, middle, then side buttons
+        HID_PAGE_BUTTON if id >= 1 && id <= 16 => BTN_MOUSE + id as u16 - 1,
+        _ => 0,
+    }
+}
+
+fn usage_relative(usage: u32) -> Option<u16> {
+    match (usage >> 16, usage & 0xffff) {
+        (HID_PAGE_DESKTOP, HID_USAGE_X) => Some(REL_X),
+        (HID_PAGE_DESKTOP, HID_USAGE_Y) => Some(REL_Y),
+        (HID_PAGE_DESKTOP, HID_USAGE_Z) => Some(REL_Z),
+        (HID_PAGE_DESKTOP, HID_USAGE_WHEEL) => Some(REL_WHEEL),
+        (HID_PAGE_CONSUMER, HID_USAGE_AC_PAN) => Some(REL_HWHEEL),
+        _ => None,
+    }
+}
+
+fn usage_absolute(usage: u32) -> Option<u16> {
+    match (usage >> 16, usage & 0xffff) {
+        (HID_PAGE_DESKTOP, HID_USAGE_X) => Some(ABS_X),
+        (HID_PAGE_DESKTOP, HID_USAGE_Y) => Some(ABS_Y),
+        _ => None,
+    }
+}
+
+// Calls out for every usage a field could report
+fn field_usages(field: &HidField, mut each: impl FnMut(u32)) {
+    if field.usageCount > 0 {
+        field.usages[..field.usageCount].iter().for_each(|&usage| each(usage));
+    } else if field.usageMax >= field.usageMin {
+        (field.usageMin..=field.usageMax.min(field.usageMin + 0xff)).for_each(each);
+    }
+}
+
+//
+// ================= Report descriptor parser =================
+//
+
+// Walks the report descriptor into fields, returning the biggest input
+// report in bytes (0 when there's nothing to use)
+unsafe fn hid_parse(hid: *mut UsbHid, desc: *const u8, length: usize) -> usize {
+    let mut globals = HidGlobals { usagePage: 0, logMin: 0, logMax: 0, size: 0, count: 0, reportId: 0 };
+    let mut stack = [globals; 4];
+    let mut depth = 0;
+
+    let mut usages = [0u32; HID_MAX_USAGES];
+    let mut usageCount = 0;
+    let mut usageMin = 0u32;
+    let mut usageMax = 0u32;
+
+    // bits so far, per report id
+    let mut inputBits = [0u32; 256];
+    let mut outputBits = [0u32; 256];
+
+    (*hid).fieldCount = 0;
+    (*hid).numbered = false;
+
+    let mut i = 0;
+    while i < length {
+        let prefix = *desc.add(i);
+        // long items: skipped whole
+        if prefix == 0xfe {
+            if i + 1 >= length {
+                break;
+            }
+            i += 3 + *desc.add(i + 1) as usize;
+            continue;
+        }
+
+        let size = match prefix & 0b11 {
+            3 => 4,
+            s => s as usize,
+        };
+        if i + 1 + size > length {
+            break;
+        }
+        let mut raw = 0u32;
+        for b in 0..size {
+            raw |= (*desc.add(i + 1 + b) as u32) << (8 * b);
+        }
+        let signed = sign_extend(raw, size as u32 * 8);
+        i += 1 + size;
+
+        let kind = (prefix >> 2) & 0b11;
+        let tag = prefix >> 4;
+        match (kind, tag) {
+            // main: input, output
+            (0, 8) | (0, 9) => {
+                let output = tag == 9;
+                let id = globals.reportId as usize;
+                let bits = if output { &mut outputBits[id] } else { &mut inputBits[id] };
+                let offset = *bits;
+                *bits += globals.size * globals.count;
+
+                if raw & HID_CONSTANT == 0 && (*hid).fieldCount < HID_MAX_FIELDS && globals.size > 0 {
+                    let field = &mut (*hid).fields[(*hid).fieldCount];
+                    field.reportId = globals.reportId;
+                    field.output = output;
+                    field.flags = raw;
+                    field.offset = offset;
+                    field.size = globals.size;
+                    field.count = globals.count;
+                    field.logMin = globals.logMin;
+                    // descriptors often mean unsigned where the encoding
+                    // reads negative
+                    field.logMax = if globals.logMax < globals.logMin {
+                        globals.logMax & ((1i64 << (globals.size.min(31))) - 1) as i32
+                    } else {
+                        globals.logMax
+                    };
+                    field.usages = usages;
+                    field.usageCount = usageCount;
+                    field.usageMin = usageMin;
+                    field.usageMax = usageMax;
+                    (*hid).fieldCount += 1;
+                }
+                usageCount = 0;
+                usageMin = 0;
+                usageMax = 0;
+            }
+            // main: feature, collection, end collection
+            (0, _) => {
+                usageCount = 0;
+                usageMin = 0;
+                usageMax = 0;
+            }
+            // global
+            (1, 0) => globals.usagePage = raw,
+            (1, 1) => globals.logMin = signed,
+            (1, 2) => globals.logMax = signed,
+            (1, 7) => globals.size = raw,
+            (1, 8) => {
+                globals.reportId = raw as u8;
+                (*hid).numbered = true;
+            }
+            (1, 9) => globals.count = raw,
+            (1, 10) if depth < stack.len() => {
+                stack[depth] = globals;
+                depth += 1;
+            }
+            (1, 11) if depth > 0 => {
+                depth -= 1;
+                globals = stack[depth];
+            }
+            // local: short usages are on the current page
+            (2, 0) | (2, 1) | (2, 2) => {
+                let usage = if size == 4 { raw } else { (globals.usagePage << 16) | raw };
+                match tag {
+                    0 if usageCount < HID_MAX_USAGES => {
+                        usages[usageCount] = usage;
+                        usageCount += 1;
+                    }
+                    1 => usageMin = usage,
+                    2 => usageMax = usage,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut biggest = 0;
+    for id in 0..256 {
+        if inputBits[id] > 0 {
+            biggest = biggest.max((inputBits[id] as usize).div_ceil(8) + (*hid).numbered as usize);
+        }
+    }
+    if (*hid).fieldCount == 0 {
+        return 0;
+    }
+    biggest
+}
+
+// Works out what the device can report, for the evdev capability bits
+unsafe fn hid_capabilities(hid: *mut UsbHid) {
+    (*hid).keyBits = [0; KEY_CNT / 8];
+    (*hid).relBits = 0;
+    (*hid).absBits = 0;
+    (*hid).keyboard = false;
+    (*hid).hasLeds = false;
+
+    for index in 0..(*hid).fieldCount {
+        let field = (*hid).fields[index];
+        if field.output {
+            field_usages(&field, |usage| {
+                if usage >> 16 == HID_PAGE_LED && (HID_LED_NUM..=HID_LED_SCROLL).contains(&(usage & 0xffff)) {
+                    (*hid).hasLeds = true;
+                }
+            });
+            continue;
+        }
+
+        field_usages(&field, |usage| {
+            let keycode = usage_keycode(usage);
+            if keycode != 0 {
+                bitmap_set(&mut (*hid).keyBits, keycode as usize, true);
+                if usage >> 16 == HID_PAGE_KEYBOARD {
+                    (*hid).keyboard = true;
+                }
+            }
+
+            if field.flags & HID_VARIABLE == 0 {
+                return;
+            }
+            if field.flags & HID_RELATIVE != 0 {
+                if let Some(code) = usage_relative(usage) {
+                    (*hid).relBits |= 1 << code;
+                }
+            } else if let Some(code) = usage_absolute(usage) {
+                (*hid).absBits |= 1 << code;
+                let info = &mut (*hid).abs[code as usize];
+                info.minimum = field.logMin;
+                info.maximum = field.logMax;
+            }
+        });
+    }
+}
+
+//
+// ================= Reports =================
+//
+
+unsafe fn hid_key(hid: *mut UsbHid, keycode: u16, down: bool) {
+    bitmap_set(&mut (*hid).keys, keycode as usize, down);
+    inputGenerateEvent((*hid).event, EV_KEY, keycode, down as i32);
+
+    // buttons are for userspace only
+    if keycode > 0xff {
+        return;
+    }
+    kb_keycode(keycode as u8, down, false);
+
+    // no typematic over USB, repeating is up to us
+    if down {
+        (*hid).repeatKey = keycode;
+        (*hid).repeatAt = timerTicks + HID_REPEAT_DELAY;
+    } else if (*hid).repeatKey == keycode {
+        (*hid).repeatKey = 0;
+    }
+}
+
+unsafe fn hid_report(hid: *mut UsbHid, mut data: *const u8, mut length: usize) {
+    let mut id = 0;
+    if (*hid).numbered {
+        if length == 0 {
+            return;
+        }
+        id = *data;
+        data = data.add(1);
+        length -= 1;
+    }
+
+    // keys this report speaks for start released, then whatever it says is
+    // held goes back in
+    let mut keys = (*hid).keys;
+    for index in 0..(*hid).fieldCount {
+        let field = &(*hid).fields[index];
+        if field.output || field.reportId != id {
+            continue;
+        }
+        field_usages(field, |usage| {
+            let keycode = usage_keycode(usage);
+            if keycode != 0 {
+                bitmap_set(&mut keys, keycode as usize, false);
+            }
+        });
+    }
+
+    for index in 0..(*hid).fieldCount {
+        let field = (*hid).fields[index];
+        if field.output || field.reportId != id {
+            continue;
+        }
+
+        for i in 0..field.count {
+            let raw = report_bits(data, length, field.offset + i * field.size, field.size);
+            let value = if field.logMin < 0 { sign_extend(raw, field.size) } else { raw as i32 };
+
+            if field.flags & HID_VARIABLE == 0 {
+                let keycode = usage_keycode(field.array_usage(value));
+                if keycode != 0 {
+                    bitmap_set(&mut keys, keycode as usize, true);
+                }
+                continue;
+            }
+
+            let usage = field.variable_usage(i);
+            let keycode = usage_keycode(usage);
+            if keycode != 0 {
+                bitmap_set(&mut keys, keycode as usize, value != 0);
+            } else if field.flags & HID_RELATIVE != 0 {
+                if let Some(code) = usage_relative(usage) {
+                    if value != 0 {
+                        inputGenerateEvent((*hid).event, EV_REL, code, value);
+                    }
+                }
+            } else if let Some(code) = usage_absolute(usage) {
+                let info = &mut (*hid).abs[code as usize];
+                if info.value != value {
+                    info.value = value;
+                    inputGenerateEvent((*hid).event, EV_ABS, code, value);
+                }
+            }
+        }
+    }
+
+    for keycode in 0..KEY_CNT {
+        let down = bitmap_get(&keys, keycode);
+        if down != bitmap_get(&(*hid).keys, keycode) {
+            hid_key(hid, keycode as u16, down);
+        }
+    }
+
+    inputGenerateEvent((*hid).event, EV_SYN, SYN_REPORT, 0);
+}
+
+// Interrupt endpoint finished, from the xHCI interrupt handler
+unsafe fn hid_callback(transfer: *mut UsbTransfer) {
+    let hid = (*transfer).context as *mut UsbHid;
+    if !(*hid).attached || (*(*hid).dev).gone {
+        return;
+    }
+
+    match (*transfer).code {
+        CC_SUCCESS => hid_report(hid, (*hid).buffer, (*transfer).length),
+        CC_SHORT_PACKET => hid_report(hid, (*hid).buffer, (*transfer).actual),
+        // clearing the halt takes a control transfer, the usb thread does it
+        _ => {
+            (*hid).stalled = true;
+            return;
+        }
+    }
+
+    xhci_queue(transfer);
+}
+
+// Sends the lock LEDs over as an output report
+unsafe fn hid_leds(hid: *mut UsbHid, leds: u8) {
+    let mut report = [0u8; 64];
+    let mut id = 0;
+    let mut bits = 0;
+
+    for index in 0..(*hid).fieldCount {
+        let field = (*hid).fields[index];
+        if !field.output || field.flags & HID_VARIABLE == 0 {
+            continue;
+        }
+        for i in 0..field.count {
+            let usage = field.variable_usage(i);
+            if usage >> 16 != HID_PAGE_LED {
+                continue;
+            }
+            let on = match usage & 0xffff {
+                HID_LED_NUM => leds & K_NUMLOCK != 0,
+                HID_LED_CAPS => leds & K_CAPSLOCK != 0,
+                HID_LED_SCROLL => leds & K_SCROLLLOCK != 0,
+                _ => false,
+            };
+            let at = (field.offset + i * field.size) as usize;
+            if at / 8 < report.len() && on {
+                report[at / 8] |= 1 << (at % 8);
+            }
+            id = field.reportId;
+            bits = bits.max(at + 1);
+        }
+    }
+
+    let mut data = report.as_mut_ptr();
+    let mut length = bits.div_ceil(8).min(report.len());
+    let mut numbered = [0u8; 65];
+    if (*hid).numbered {
+        numbered[0] = id;
+        numbered[1..].copy_from_slice(&report);
+        data = numbered.as_mut_ptr();
+        length += 1;
+    }
+
+    usb_control(
+        (*hid).dev,
+        USB_TYPE_CLASS | USB_RECIP_INTERFACE,
+        HID_REQ_SET_REPORT,
+        (HID_REPORT_OUTPUT << 8) | id as u16,
+        (*hid).iface as u16,
+        length as u16,
+        data,
+    )
+    .ok();
+
+    let changed = leds ^ (*hid).leds;
+    (*hid).leds = leds;
+    for (bit, code) in [(K_NUMLOCK, LED_NUML), (K_CAPSLOCK, LED_CAPSL), (K_SCROLLLOCK, LED_SCROLLL)] {
+        if changed & bit != 0 {
+            inputGenerateEvent((*hid).event, EV_LED, code, (leds & bit != 0) as i32);
+        }
+    }
+    inputGenerateEvent((*hid).event, EV_SYN, SYN_REPORT, 0);
+}
+
+// From the usb thread: LEDs, key repeat, and endpoints that stalled
+pub unsafe fn usb_hid_tick() {
+    let leds = kb_leds();
+
+    for &hid in HID_DEVICES.iter() {
+        if hid.is_null() || !(*hid).attached {
+            continue;
+        }
+
+        if (*hid).hasLeds && (*hid).leds != leds {
+            hid_leds(hid, leds);
+        }
+
+        if (*hid).stalled {
+            (*hid).stalled = false;
+            usb_clear_halt((*hid).dev, (*hid).transfer.endpoint);
+            xhci_queue(&mut (*hid).transfer);
+        }
+
+        let keycode = (*hid).repeatKey;
+        if keycode != 0 && timerTicks >= (*hid).repeatAt {
+            // the same key state the interrupt handler works on
+            core::arch::asm!("cli");
+            if (*hid).repeatKey == keycode {
+                (*hid).repeatAt = timerTicks + HID_REPEAT_PERIOD;
+                inputGenerateEvent((*hid).event, EV_KEY, keycode, 2);
+                inputGenerateEvent((*hid).event, EV_SYN, SYN_REPORT, 0);
+                kb_keycode(keycode as u8, true, true);
+            }
+            core::arch::asm!("sti");
+        }
+    }
+}
+
+//
+// ================= evdev ioctls =================
+//
+
+unsafe fn hid_by_event(event: *mut DevInputEvent) -> *mut UsbHid {
+    for &hid in HID_DEVICES.iter() {
+        if !hid.is_null() && (*hid).event == event {
+            return hid;
+        }
+    }
+    null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn usb_hid_event_bit(fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    unsafe {
+        let hid = hid_by_event((*fd).dir);
+        if hid.is_null() {
+            return 0;
+        }
+
+        let number = (request & 0xff) as usize;
+        let size = ((request >> 16) & 0x3fff) as usize;
+
+        let mut map = [0u8; KEY_CNT / 8];
+        match number {
+            0x20 => {
+                bitmap_set(&mut map, EV_SYN as usize, true);
+                bitmap_set(&mut map, EV_KEY as usize, (*hid).keyBits.iter().any(|&b| b != 0));
+                bitmap_set(&mut map, EV_REL as usize, (*hid).relBits != 0);
+                bitmap_set(&mut map, EV_ABS as usize, (*hid).absBits != 0);
+                bitmap_set(&mut map, EV_LED as usize, (*hid).hasLeds);
+            }
+            0x21 => map = (*hid).keyBits, // 0x20 + EV_KEY
+            0x22 => map[..2].copy_from_slice(&(*hid).relBits.to_le_bytes()), // 0x20 + EV_REL
+            0x23 => map[..2].copy_from_slice(&(*hid).absBits.to_le_bytes()), // 0x20 + EV_ABS
+            0x31 if (*hid).hasLeds => {
+                // 0x20 + EV_LED
+                for led in [LED_NUML, LED_CAPSL, LED_SCROLLL] {
+                    bitmap_set(&mut map, led as usize, true);
+                }
+            }
+            0x40 | 0x41 => {
+                // 0x40 + ABS_X / ABS_Y
+                let info = &(*hid).abs[number - 0x40];
+                copy_nonoverlapping(
+                    info as *const InputAbsInfo as *const u8,
+                    arg,
+                    min(size, core::mem::size_of::<InputAbsInfo>()),
+                );
+                return 0;
+            }
+            // EVIOCGKEY
+            0x18 => map = (*hid).keys,
+            // EVIOCGLED
+            0x19 => {
+                bitmap_set(&mut map, LED_NUML as usize, (*hid).leds & K_NUMLOCK != 0);
+                bitmap_set(&mut map, LED_CAPSL as usize, (*hid).leds & K_CAPSLOCK != 0);
+                bitmap_set(&mut map, LED_SCROLLL as usize, (*hid).leds & K_SCROLLLOCK != 0);
+            }
+            _ => return 0,
+        }
+
+        let len = min(map.len(), size);
+        copy_nonoverlapping(map.as_ptr(), arg, len);
+        len
+    }
+}
+
+//
+// ================= Attach / detach =================
+//
+
+// Event devices can't go away, an unplugged one waits for the same kind of
+// device to come back
+unsafe fn hid_slot(vendor: u16, product: u16, iface: u8) -> *mut UsbHid {
+    let mut free = None;
+    for (index, &hid) in HID_DEVICES.iter().enumerate() {
+        if hid.is_null() {
+            free = free.or(Some(index));
+            continue;
+        }
+        if !(*hid).attached && (*hid).vendor == vendor && (*hid).product == product && (*hid).iface == iface {
+            return hid;
+        }
+    }
+
+    let Some(index) = free else {
+        return null_mut();
+    };
+    let hid = malloc(core::mem::size_of::<UsbHid>()) as *mut UsbHid;
+    write_bytes(hid, 0, 1);
+    (*hid).vendor = vendor;
+    (*hid).product = product;
+    (*hid).iface = iface;
+    (*hid).buffer = VirtualAllocatePhysicallyContiguous(1);
+    HID_DEVICES[index] = hid;
+    hid
+}
+
+pub unsafe fn usb_hid_attach(dev: *mut UsbDevice, iface: *const UsbInterfaceDescriptor, end: *const u8) {
+    let endpoint = usb_find_endpoint(iface, end, USB_ENDPOINT_INTERRUPT, true);
+    if endpoint.is_null() {
+        return;
+    }
+
+    let descriptor = (*dev).descriptor;
+    let hid = hid_slot(descriptor.idVendor, descriptor.idProduct, (*iface).bInterfaceNumber);
+    if hid.is_null() {
+        debugf(b"[usb] Out of HID devices!\n\0".as_ptr());
+        return;
+    }
+    (*hid).dev = dev;
+
+    let number = (*iface).bInterfaceNumber as u16;
+    let classInterface = USB_TYPE_CLASS | USB_RECIP_INTERFACE;
+
+    // reports only when something changes
+    usb_control(dev, classInterface, HID_REQ_SET_IDLE, 0, number, 0, null_mut()).ok();
+
+    let mut reportBytes = 0;
+    let hidDescriptor = usb_find_class_descriptor(iface, end, USB_DT_HID);
+    if !hidDescriptor.is_null() && *hidDescriptor >= 9 {
+        let length = u16::from_le_bytes([*hidDescriptor.add(7), *hidDescriptor.add(8)]) as usize;
+        let length = length.min(HID_MAX_DESCRIPTOR);
+        let value = (USB_DT_REPORT as u16) << 8;
+        let requestType = USB_DIR_IN | USB_RECIP_INTERFACE;
+        if let Ok(actual) = usb_control(dev, requestType, USB_REQ_GET_DESCRIPTOR, value, number, length as u16, (*hid).buffer) {
+            reportBytes = hid_parse(hid, (*hid).buffer, actual);
+        }
+    }
+
+    // boot devices can always be talked to the simple way
+    if reportBytes == 0 && (*iface).bInterfaceSubClass == HID_SUBCLASS_BOOT {
+        let boot: &[u8] = match (*iface).bInterfaceProtocol {
+            HID_PROTOCOL_KEYBOARD => &HID_BOOT_KEYBOARD,
+            HID_PROTOCOL_MOUSE => &HID_BOOT_MOUSE,
+            _ => &[],
+        };
+        if !boot.is_empty() {
+            usb_control(dev, classInterface, HID_REQ_SET_PROTOCOL, 0, number, 0, null_mut()).ok();
+            reportBytes = hid_parse(hid, boot.as_ptr(), boot.len());
+        }
+    }
+
+    if reportBytes == 0 {
+        debugf(b"[usb] HID interface %d has nothing to report\n\0".as_ptr(), number as u32);
+        return;
+    }
+
+    hid_capabilities(hid);
+    if !xhci_open_endpoint(dev, endpoint) {
+        return;
+    }
+
+    if (*hid).event.is_null() {
+        let name: &[u8] = if (*hid).keyboard {
+            b"USB Keyboard\0"
+        } else if (*hid).absBits != 0 {
+            b"USB Tablet\0"
+        } else {
+            b"USB Mouse\0"
+        };
+        (*hid).event = devInputEventSetup(name.as_ptr());
+        (*(*hid).event).inputid = InputId {
+            bustype: 0x03, // BUS_USB
+            vendor: descriptor.idVendor,
+            product: descriptor.idProduct,
+            version: descriptor.bcdDevice,
+        };
+        (*(*hid).event).eventBit = usb_hid_event_bit;
+    }
+
+    (*hid).keys = [0; KEY_CNT / 8];
+    (*hid).leds = 0;
+    (*hid).repeatKey = 0;
+    (*hid).stalled = false;
+
+    let maxPacket = (u16::from_le_bytes((*endpoint).wMaxPacketSize) & 0x7ff) as usize;
+    (*hid).transfer = UsbTransfer::new();
+    (*hid).transfer.dev = dev;
+    (*hid).transfer.endpoint = (*endpoint).bEndpointAddress;
+    (*hid).transfer.buffer = (*hid).buffer;
+    (*hid).transfer.length = reportBytes.max(maxPacket).min(HID_MAX_DESCRIPTOR);
+    (*hid).transfer.callback = Some(hid_callback);
+    (*hid).transfer.context = hid as *mut core::ffi::c_void;
+    (*hid).attached = true;
+
+    // the keyboard starts out showing whatever the locks are, see
+    // usb_hid_tick()
+    if (*hid).hasLeds {
+        hid_leds(hid, kb_leds());
+    }
+
+    xhci_queue(&mut (*hid).transfer);
+}
+
+pub unsafe fn usb_hid_detach(dev: *mut UsbDevice) {
+    for &hid in HID_DEVICES.iter() {
+        if hid.is_null() || (*hid).dev != dev || !(*hid).attached {
+            continue;
+        }
+        (*hid).attached = false;
+
+        // nothing stays held down
+        core::arch::asm!("cli");
+        (*hid).repeatKey = 0;
+        for keycode in 0..KEY_CNT {
+            if bitmap_get(&(*hid).keys, keycode) {
+                hid_key(hid, keycode as u16, false);
+            }
+        }
+        inputGenerateEvent((*hid).event, EV_SYN, SYN_REPORT, 0);
+        core::arch::asm!("sti");
+    }
+}
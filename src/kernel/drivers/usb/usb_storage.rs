@@ -0,0 +1,384 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::ptr::{copy_nonoverlapping, null_mut, write_bytes};
+
+use crate::usb::*;
+use crate::xhci::*;
+
+//
+// ================= Externs =================
+//
+
+extern "C" {
+    fn debugf(fmt: *const u8, ...) -> i32;
+
+    fn malloc(size: usize) -> *mut u8;
+
+    fn VirtualAllocatePhysicallyContiguous(pages: usize) -> *mut u8;
+
+    fn spinlockAcquire(lock: *mut Spinlock);
+    fn spinlockRelease(lock: *mut Spinlock);
+
+    fn sleep(ms: u32);
+
+    static mut systemDiskInit: bool;
+}
+
+//
+// ================= Constants =================
+//
+
+const BLOCK_SIZE: usize = 4096;
+const SECTOR_SIZE: usize = 512;
+
+const STORAGE_MAX_DEVICES: usize = 8;
+// bounce buffer for the data stage
+const STORAGE_BUFFER_PAGES: usize = 16;
+const STORAGE_BUFFER_SIZE: usize = STORAGE_BUFFER_PAGES * BLOCK_SIZE;
+
+const STORAGE_SUBCLASS_SCSI: u8 = 0x06;
+const STORAGE_PROTOCOL_BOT: u8 = 0x50;
+
+// class requests
+const BOT_REQ_RESET: u8 = 0xff;
+
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CBW_LENGTH: usize = 31;
+const CSW_LENGTH: usize = 13;
+
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+
+// SCSI
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2a;
+
+const SCSI_READY_TRIES: usize = 10;
+
+//
+// ================= Structs =================
+//
+
+#[repr(C, packed)]
+struct Cbw {
+    signature: u32,
+    tag: u32,
+    dataLength: u32,
+    flags: u8,
+    lun: u8,
+    cbLength: u8,
+    cb: [u8; 16],
+}
+
+#[repr(C, packed)]
+struct Csw {
+    signature: u32,
+    tag: u32,
+    residue: u32,
+    status: u8,
+}
+
+pub struct UsbStorage {
+    dev: *mut UsbDevice,
+    iface: u8,
+    bulkIn: u8,
+    bulkOut: u8,
+    attached: bool,
+
+    blocks: u64,
+    blockSize: u32,
+
+    LOCK_STORAGE: Spinlock,
+    tag: u32,
+    // CBW at the start, CSW further in
+    command: *mut u8,
+    buffer: *mut u8,
+}
+
+static mut USB_STORAGE: [*mut UsbStorage; STORAGE_MAX_DEVICES] = [null_mut(); STORAGE_MAX_DEVICES];
+
+//
+// ================= Bulk-only transport =================
+//
+
+// Bulk-only reset, then both endpoints back to running
+unsafe fn bot_reset(st: *mut UsbStorage) {
+    let dev = (*st).dev;
+    usb_control(
+        dev,
+        USB_TYPE_CLASS | USB_RECIP_INTERFACE,
+        BOT_REQ_RESET,
+        0,
+        (*st).iface as u16,
+        0,
+        null_mut(),
+    )
+    .ok();
+    usb_clear_halt(dev, (*st).bulkIn);
+    usb_clear_halt(dev, (*st).bulkOut);
+}
+
+unsafe fn bot_status(st: *mut UsbStorage) -> Result<Csw, u8> {
+    let csw = (*st).command.add(64);
+    let mut ret = xhci_transfer((*st).dev, (*st).bulkIn, csw, CSW_LENGTH);
+    if ret == Err(CC_STALL) {
+        // one more go, as the spec has it
+        usb_clear_halt((*st).dev, (*st).bulkIn);
+        ret = xhci_transfer((*st).dev, (*st).bulkIn, csw, CSW_LENGTH);
+    }
+    match ret {
+        Ok(CSW_LENGTH) => Ok(core::ptr::read_unaligned(csw as *const Csw)),
+        Ok(_) => Err(0),
+        Err(code) => Err(code),
+    }
+}
+
+// One command, with the data stage going through the bounce buffer.
+// Returns the CSW status, or None when the transport fell over
+unsafe fn bot_command(st: *mut UsbStorage, cb: &[u8], length: usize, input: bool) -> Option<u8> {
+    let dev = (*st).dev;
+    if (*dev).gone {
+        return None;
+    }
+
+    (*st).tag = (*st).tag.wrapping_add(1);
+    let mut cbw = Cbw {
+        signature: CBW_SIGNATURE,
+        tag: (*st).tag,
+        dataLength: length as u32,
+        flags: if input { 0x80 } else { 0 },
+        lun: 0,
+        cbLength: cb.len() as u8,
+        cb: [0; 16],
+    };
+    cbw.cb[..cb.len()].copy_from_slice(cb);
+    core::ptr::write_unaligned((*st).command as *mut Cbw, cbw);
+
+    if xhci_transfer(dev, (*st).bulkOut, (*st).command, CBW_LENGTH).is_err() {
+        bot_reset(st);
+        return None;
+    }
+
+    if length > 0 {
+        let endpoint = if input { (*st).bulkIn } else { (*st).bulkOut };
+        match xhci_transfer(dev, endpoint, (*st).buffer, length) {
+            Ok(_) => {}
+            // the status still follows a stalled data stage
+            Err(CC_STALL) => {
+                usb_clear_halt(dev, endpoint);
+            }
+            Err(_) => {
+                bot_reset(st);
+                return None;
+            }
+        }
+    }
+
+    let Ok(csw) = bot_status(st) else {
+        bot_reset(st);
+        return None;
+    };
+    let (signature, tag, status) = (csw.signature, csw.tag, csw.status);
+    if signature != CSW_SIGNATURE || tag != (*st).tag || status > CSW_FAILED {
+        bot_reset(st);
+        return None;
+    }
+    Some(status)
+}
+
+//
+// ================= SCSI =================
+//
+
+unsafe fn scsi_sense(st: *mut UsbStorage) -> u8 {
+    let cb = [SCSI_REQUEST_SENSE, 0, 0, 0, 18, 0];
+    if bot_command(st, &cb, 18, true) != Some(CSW_PASSED) {
+        return 0;
+    }
+    // sense key
+    *(*st).buffer.add(2) & 0x0f
+}
+
+// Devices take a while to spin up, or report a medium change first
+unsafe fn scsi_ready(st: *mut UsbStorage) -> bool {
+    let cb = [SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0];
+    for _ in 0..SCSI_READY_TRIES {
+        match bot_command(st, &cb, 0, false) {
+            Some(CSW_PASSED) => return true,
+            Some(_) => {
+                scsi_sense(st);
+                sleep(100);
+            }
+            None => return false,
+        }
+    }
+    false
+}
+
+unsafe fn scsi_capacity(st: *mut UsbStorage) -> bool {
+    let cb = [SCSI_READ_CAPACITY, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    if bot_command(st, &cb, 8, true) != Some(CSW_PASSED) {
+        return false;
+    }
+
+    let data = core::slice::from_raw_parts((*st).buffer, 8);
+    let last = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    (*st).blocks = last as u64 + 1;
+    (*st).blockSize = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    true
+}
+
+unsafe fn scsi_rw(st: *mut UsbStorage, lba: u32, count: usize, write: bool) -> bool {
+    let lba = lba.to_be_bytes();
+    let blocks = (count as u16).to_be_bytes();
+    let cb = [
+        if write { SCSI_WRITE_10 } else { SCSI_READ_10 },
+        0,
+        lba[0],
+        lba[1],
+        lba[2],
+        lba[3],
+        0,
+        blocks[0],
+        blocks[1],
+        0,
+    ];
+
+    let length = count * (*st).blockSize as usize;
+    match bot_command(st, &cb, length, !write) {
+        Some(CSW_PASSED) => true,
+        Some(_) => {
+            scsi_sense(st);
+            false
+        }
+        None => false,
+    }
+}
+
+//
+// ================= Disk layer =================
+//
+
+// Sector reads and writes for the disk layer, on the first USB disk with
+// 512 byte sectors. False when there's none
+pub unsafe fn usb_storage_bytes(target: *mut u8, lba: u32, count: usize, write: bool) -> bool {
+    let st = USB_STORAGE
+        .iter()
+        .copied()
+        .find(|&st| !st.is_null() && (*st).attached && (*st).blockSize as usize == SECTOR_SIZE);
+    let Some(st) = st else {
+        return false;
+    };
+
+    spinlockAcquire(&mut (*st).LOCK_STORAGE);
+    let chunk = STORAGE_BUFFER_SIZE / SECTOR_SIZE;
+    let mut done = 0;
+    while done < count {
+        let sectors = (count - done).min(chunk);
+        let bytes = sectors * SECTOR_SIZE;
+        let at = target.add(done * SECTOR_SIZE);
+        let sector = lba + done as u32;
+
+        if write {
+            copy_nonoverlapping(at, (*st).buffer, bytes);
+        }
+        // once more after a failure, it might've been a unit attention
+        if !scsi_rw(st, sector, sectors, write) && !scsi_rw(st, sector, sectors, write) {
+            debugf(b"[usb] Storage I/O failed at sector %d\n\0".as_ptr(), sector);
+            if !write {
+                write_bytes(at, 0, bytes);
+            }
+        } else if !write {
+            copy_nonoverlapping((*st).buffer, at, bytes);
+        }
+        done += sectors;
+    }
+    spinlockRelease(&mut (*st).LOCK_STORAGE);
+    true
+}
+
+//
+// ================= Attach / detach =================
+//
+
+pub unsafe fn usb_storage_attach(dev: *mut UsbDevice, iface: *const UsbInterfaceDescriptor, end: *const u8) {
+    if (*iface).bInterfaceSubClass != STORAGE_SUBCLASS_SCSI || (*iface).bInterfaceProtocol != STORAGE_PROTOCOL_BOT {
+        debugf(
+            b"[usb] Unsupported storage interface %x/%x\n\0".as_ptr(),
+            (*iface).bInterfaceSubClass as u32,
+            (*iface).bInterfaceProtocol as u32,
+        );
+        return;
+    }
+
+    let bulkIn = usb_find_endpoint(iface, end, USB_ENDPOINT_BULK, true);
+    let bulkOut = usb_find_endpoint(iface, end, USB_ENDPOINT_BULK, false);
+    if bulkIn.is_null() || bulkOut.is_null() || !xhci_open_endpoint(dev, bulkIn) || !xhci_open_endpoint(dev, bulkOut) {
+        return;
+    }
+
+    let Some(index) = USB_STORAGE.iter().position(|&st| st.is_null() || !(*st).attached) else {
+        debugf(b"[usb] Out of storage devices!\n\0".as_ptr());
+        return;
+    };
+    let mut st = USB_STORAGE[index];
+    if st.is_null() {
+        st = malloc(core::mem::size_of::<UsbStorage>()) as *mut UsbStorage;
+        write_bytes(st, 0, 1);
+        (*st).command = VirtualAllocatePhysicallyContiguous(1);
+        (*st).buffer = VirtualAllocatePhysicallyContiguous(STORAGE_BUFFER_PAGES);
+        USB_STORAGE[index] = st;
+    }
+    (*st).dev = dev;
+    (*st).iface = (*iface).bInterfaceNumber;
+    (*st).bulkIn = (*bulkIn).bEndpointAddress;
+    (*st).bulkOut = (*bulkOut).bEndpointAddress;
+
+    let inquiry = [SCSI_INQUIRY, 0, 0, 0, 36, 0];
+    if bot_command(st, &inquiry, 36, true) != Some(CSW_PASSED) {
+        debugf(b"[usb] Storage device doesn't answer INQUIRY\n\0".as_ptr());
+        return;
+    }
+    // direct access block devices only
+    if *(*st).buffer & 0x1f != 0 {
+        return;
+    }
+
+    if !scsi_ready(st) || !scsi_capacity(st) {
+        debugf(b"[usb] Storage device has no medium\n\0".as_ptr());
+        return;
+    }
+
+    (*st).attached = true;
+    debugf(
+        b"[usb] Storage device: %ld blocks of %d bytes\n\0".as_ptr(),
+        (*st).blocks,
+        (*st).blockSize,
+    );
+
+    if (*st).blockSize as usize != SECTOR_SIZE {
+        return;
+    }
+
+    // a system disk, as far as the disk layer is concerned
+    let mut mbr = [0u8; SECTOR_SIZE];
+    if usb_storage_bytes(mbr.as_mut_ptr(), 0, 1, false) && mbr[510] == 0x55 && mbr[511] == 0xaa {
+        systemDiskInit = true;
+    }
+}
+
+pub unsafe fn usb_storage_detach(dev: *mut UsbDevice) {
+    for &st in USB_STORAGE.iter() {
+        if st.is_null() || (*st).dev != dev || !(*st).attached {
+            continue;
+        }
+        // a transfer still going fails on its own, the device being gone
+        (*st).attached = false;
+    }
+}
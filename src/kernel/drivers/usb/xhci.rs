@@ -0,0 +1,1186 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::ffi::c_void;
+use core::ptr::{null_mut, read_volatile, write_bytes, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::usb::{usb_root_attach, usb_thread_start, UsbDevice, UsbEndpointDescriptor, UsbSetup};
+
+//
+// ================= Externs =================
+//
+
+extern "C" {
+    fn debugf(fmt: *const u8, ...) -> i32;
+
+    fn malloc(size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    fn lookupPCIdevice(dev: *const PCIdevice) -> *mut PCI;
+    fn setupPCIdeviceDriver(pci: *mut PCI, driver: u32, category: u32);
+    fn GetGeneralDevice(dev: *const PCIdevice, out: *mut PCIgeneralDevice);
+    fn ConfigReadWord(bus: u8, slot: u8, func: u8, offset: u8) -> u16;
+    fn ConfigWriteDword(bus: u8, slot: u8, func: u8, offset: u8, val: u32);
+
+    fn ioApicPciRegister(dev: *const PCIdevice, info: *mut PCIgeneralDevice) -> u8;
+    fn registerIRQhandler(irq: u8, handler: extern "C" fn(*mut AsmPassedInterrupt))
+        -> *mut c_void;
+
+    fn VirtualAllocatePhysicallyContiguous(pages: usize) -> *mut u8;
+    fn VirtualToPhysical(addr: usize) -> usize;
+    fn VirtualMap(virt: usize, phys: usize, flags: u64);
+
+    fn spinlockAcquire(lock: *mut Spinlock);
+    fn spinlockRelease(lock: *mut Spinlock);
+
+    fn sleep(ms: u32);
+
+    static bootloader: BootloaderInfo;
+    static timerTicks: u64;
+}
+
+//
+// ================= Basic structs =================
+//
+
+#[repr(C)]
+pub struct BootloaderInfo {
+    pub hhdmOffset: usize,
+}
+
+#[repr(C)]
+pub struct PCIdevice {
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub command: u16,
+    pub status: u16,
+}
+
+#[repr(C)]
+pub struct PCIgeneralDevice {
+    pub bar: [u32; 6],
+    pub cardBusCISPtr: u32,
+    pub system_id: u16,
+    pub system_vendor_id: u16,
+    pub expROMaddr: u32,
+    pub capabilitiesPtr: u8,
+    pub interruptLine: u8,
+    pub interruptPIN: u8,
+    pub minGrant: u8,
+    pub maxLatency: u8,
+}
+
+#[repr(C)]
+pub struct PCI {
+    _ll: usize,
+    pub bus: u8,
+    pub slot: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub name: *mut u8,
+    pub driver: u32,
+    pub category: u32,
+    pub extra: *mut c_void,
+    pub irqHandler: *mut c_void,
+}
+
+#[repr(C)]
+pub struct AsmPassedInterrupt;
+
+#[repr(C)]
+pub struct Spinlock {
+    pub locked: u32,
+}
+
+//
+// ================= Constants =================
+//
+
+pub const PCI_DRIVER_XHCI: u32 = 5;
+pub const PCI_DRIVER_CATEGORY_USB: u32 = 3;
+
+const BLOCK_SIZE: usize = 4096;
+
+const PF_RW: u64 = 1 << 1;
+const PF_CACHE_DISABLE: u64 = 1 << 4;
+
+// capability registers
+const CAP_CAPLENGTH: usize = 0x00;
+const CAP_HCSPARAMS1: usize = 0x04;
+const CAP_HCSPARAMS2: usize = 0x08;
+const CAP_HCCPARAMS1: usize = 0x10;
+const CAP_DBOFF: usize = 0x14;
+const CAP_RTSOFF: usize = 0x18;
+
+// operational registers
+const OP_USBCMD: usize = 0x00;
+const OP_USBSTS: usize = 0x04;
+const OP_CRCR: usize = 0x18;
+const OP_DCBAAP: usize = 0x30;
+const OP_CONFIG: usize = 0x38;
+const OP_PORTSC: usize = 0x400;
+
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_HCRST: u32 = 1 << 1;
+const USBCMD_INTE: u32 = 1 << 2;
+
+const USBSTS_HCH: u32 = 1 << 0;
+const USBSTS_EINT: u32 = 1 << 3;
+const USBSTS_CNR: u32 = 1 << 11;
+
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PP: u32 = 1 << 9;
+const PORTSC_CSC: u32 = 1 << 17;
+const PORTSC_PRC: u32 = 1 << 21;
+// write 1 to clear, along with PED, which a plain write-back would turn off
+const PORTSC_CHANGES: u32 = 0x7f << 17;
+
+// interrupter 0, off the runtime registers
+const IR0_IMAN: usize = 0x20;
+const IR0_IMOD: usize = 0x24;
+const IR0_ERSTSZ: usize = 0x28;
+const IR0_ERSTBA: usize = 0x30;
+const IR0_ERDP: usize = 0x38;
+
+const IMAN_IP: u32 = 1 << 0;
+const IMAN_IE: u32 = 1 << 1;
+const ERDP_EHB: u64 = 1 << 3;
+
+// extended capabilities
+const XECP_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+
+// TRB types
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_RESET_ENDPOINT: u32 = 14;
+const TRB_SET_TR_DEQUEUE: u32 = 16;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_ISP: u32 = 1 << 2;
+const TRB_CHAIN: u32 = 1 << 4;
+const TRB_IOC: u32 = 1 << 5;
+const TRB_IDT: u32 = 1 << 6;
+const TRB_DIR_IN: u32 = 1 << 16;
+
+// completion codes
+pub const CC_SUCCESS: u8 = 1;
+pub const CC_STALL: u8 = 6;
+pub const CC_SHORT_PACKET: u8 = 13;
+// ours, for a transfer that never completed
+pub const CC_TIMEOUT: u8 = 0xff;
+
+// endpoint types (endpoint context)
+const EP_TYPE_CONTROL: u32 = 4;
+
+// port speeds, as PORTSC and the slot context have them
+pub const USB_SPEED_FULL: u8 = 1;
+pub const USB_SPEED_LOW: u8 = 2;
+pub const USB_SPEED_HIGH: u8 = 3;
+pub const USB_SPEED_SUPER: u8 = 4;
+
+const RING_TRBS: usize = BLOCK_SIZE / 16;
+// the largest piece of a buffer a TRB can point at, also never crossing
+const TRB_MAX_BYTES: usize = 0x10000;
+
+const COMMAND_TIMEOUT: u64 = 1000;
+const TRANSFER_TIMEOUT: u64 = 5000;
+
+const XHCI_MAX_CONTROLLERS: usize = 4;
+const XHCI_MAX_DCI: usize = 32;
+
+//
+// ================= Structs =================
+//
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Trb {
+    pub param: u64,
+    pub status: u32,
+    pub control: u32,
+}
+
+#[repr(C)]
+struct ErstEntry {
+    base: u64,
+    size: u32,
+    _rsv: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Ring {
+    pub trbs: *mut Trb,
+    pub phys: usize,
+    pub enqueue: usize,
+    pub cycle: u32,
+}
+
+// A transfer queued on an endpoint, finished from the event handler
+pub struct UsbTransfer {
+    pub dev: *mut UsbDevice,
+    // endpoint address, bit 7 set for IN
+    pub endpoint: u8,
+
+    // physically contiguous
+    pub buffer: *mut u8,
+    pub length: usize,
+
+    pub actual: usize,
+    pub code: u8,
+    pub done: AtomicBool,
+
+    // called from the interrupt handler once done, instead of anyone waiting
+    pub callback: Option<unsafe fn(*mut UsbTransfer)>,
+    pub context: *mut c_void,
+
+    lastTrb: usize,
+}
+
+impl UsbTransfer {
+    pub const fn new() -> Self {
+        UsbTransfer {
+            dev: null_mut(),
+            endpoint: 0,
+            buffer: null_mut(),
+            length: 0,
+            actual: 0,
+            code: 0,
+            done: AtomicBool::new(false),
+            callback: None,
+            context: null_mut(),
+            lastTrb: 0,
+        }
+    }
+}
+
+pub struct XhciSlot {
+    input: *mut u8,
+    inputPhys: usize,
+    output: *mut u8,
+    outputPhys: usize,
+    rings: [Ring; XHCI_MAX_DCI],
+    pending: [*mut UsbTransfer; XHCI_MAX_DCI],
+}
+
+pub struct Xhci {
+    pub pci: *mut PCI,
+
+    cap: usize,
+    op: usize,
+    rt: usize,
+    db: usize,
+
+    pub maxSlots: u8,
+    pub maxPorts: u8,
+    ctxSize: usize,
+
+    dcbaa: *mut u64,
+
+    commands: Ring,
+    LOCK_COMMAND: Spinlock,
+    commandTrb: usize,
+    commandDone: AtomicBool,
+    commandResult: Trb,
+
+    events: *mut Trb,
+    eventsPhys: usize,
+    eventDequeue: usize,
+    eventCycle: u32,
+    eventBusy: AtomicBool,
+
+    pub slots: [*mut XhciSlot; 256],
+
+    // root hub ports with a connect change the usb thread hasn't looked at
+    portChanges: [u32; 8],
+}
+
+static mut XHCI_CONTROLLERS: [*mut Xhci; XHCI_MAX_CONTROLLERS] = [null_mut(); XHCI_MAX_CONTROLLERS];
+static mut XHCI_COUNT: usize = 0;
+
+//
+// ================= MMIO helpers =================
+//
+
+#[inline]
+unsafe fn rd32(addr: usize) -> u32 {
+    read_volatile(addr as *const u32)
+}
+
+#[inline]
+unsafe fn wr32(addr: usize, value: u32) {
+    write_volatile(addr as *mut u32, value);
+}
+
+#[inline]
+unsafe fn wr64(addr: usize, value: u64) {
+    write_volatile(addr as *mut u32, value as u32);
+    write_volatile((addr + 4) as *mut u32, (value >> 32) as u32);
+}
+
+unsafe fn wait_bits(addr: usize, mask: u32, set: bool, ms: u64) -> bool {
+    let start = timerTicks;
+    while (rd32(addr) & mask != 0) != set {
+        if timerTicks > start + ms {
+            return false;
+        }
+        core::hint::spin_loop();
+    }
+    true
+}
+
+unsafe fn dma_page(phys: &mut usize) -> *mut u8 {
+    let virt = VirtualAllocatePhysicallyContiguous(1);
+    write_bytes(virt, 0, BLOCK_SIZE);
+    *phys = VirtualToPhysical(virt as usize);
+    virt
+}
+
+//
+// ================= Rings =================
+//
+
+unsafe fn ring_create() -> Ring {
+    let mut phys = 0;
+    let trbs = dma_page(&mut phys) as *mut Trb;
+
+    // the last one loops back to the start, flipping the cycle bit
+    let link = &mut *trbs.add(RING_TRBS - 1);
+    link.param = phys as u64;
+    link.control = (TRB_LINK << 10) | TRB_TOGGLE_CYCLE;
+
+    Ring { trbs, phys, enqueue: 0, cycle: 1 }
+}
+
+// Returns where the TRB went, which is what events point back at
+unsafe fn ring_push(ring: &mut Ring, param: u64, status: u32, control: u32) -> usize {
+    let trb = ring.trbs.add(ring.enqueue);
+    write_volatile(&mut (*trb).param, param);
+    write_volatile(&mut (*trb).status, status);
+    write_volatile(&mut (*trb).control, (control & !TRB_CYCLE) | ring.cycle);
+    let phys = ring.phys + ring.enqueue * 16;
+
+    ring.enqueue += 1;
+    if ring.enqueue == RING_TRBS - 1 {
+        let link = ring.trbs.add(RING_TRBS - 1);
+        // chained TRBs have to stay chained across the link
+        let chain = control & TRB_CHAIN;
+        let value = ((*link).control & !(TRB_CYCLE | TRB_CHAIN)) | chain | ring.cycle;
+        write_volatile(&mut (*link).control, value);
+        ring.enqueue = 0;
+        ring.cycle ^= 1;
+    }
+    phys
+}
+
+#[inline]
+unsafe fn ring_dequeue_pointer(ring: &Ring) -> u64 {
+    (ring.phys + ring.enqueue * 16) as u64 | ring.cycle as u64
+}
+
+#[inline]
+unsafe fn doorbell(hc: *mut Xhci, slot: u8, target: u32) {
+    wr32((*hc).db + slot as usize * 4, target);
+}
+
+//
+// ================= Contexts =================
+//
+
+// Input contexts: input control, slot, then endpoints by DCI
+#[inline]
+unsafe fn input_ctx(hc: *mut Xhci, slot: *mut XhciSlot, index: usize) -> *mut u32 {
+    (*slot).input.add(index * (*hc).ctxSize) as *mut u32
+}
+
+#[inline]
+unsafe fn output_ctx(hc: *mut Xhci, slot: *mut XhciSlot, index: usize) -> *mut u32 {
+    (*slot).output.add(index * (*hc).ctxSize) as *mut u32
+}
+
+// Starts a fresh input context off the current output one
+unsafe fn input_prepare(hc: *mut Xhci, slot: *mut XhciSlot, add: u32) {
+    write_bytes((*slot).input, 0, BLOCK_SIZE);
+    let control = input_ctx(hc, slot, 0);
+    *control.add(1) = add;
+    core::ptr::copy_nonoverlapping(
+        output_ctx(hc, slot, 0) as *const u8,
+        input_ctx(hc, slot, 1) as *mut u8,
+        (*hc).ctxSize * XHCI_MAX_DCI,
+    );
+}
+
+#[inline]
+pub fn endpoint_dci(endpoint: u8) -> usize {
+    let number = (endpoint & 0x0f) as usize;
+    if number == 0 {
+        return 1;
+    }
+    number * 2 + ((endpoint & 0x80 != 0) as usize)
+}
+
+//
+// ================= Events =================
+//
+
+unsafe fn event_transfer(hc: *mut Xhci, event: &Trb) {
+    let slotId = (event.control >> 24) as usize;
+    let dci = ((event.control >> 16) & 0x1f) as usize;
+    let slot = (*hc).slots[slotId];
+    if slot.is_null() {
+        return;
+    }
+
+    let transfer = (*slot).pending[dci];
+    if transfer.is_null() {
+        return;
+    }
+
+    let code = (event.status >> 24) as u8;
+    let residual = (event.status & 0xffffff) as usize;
+
+    // a short data stage reports early, the status stage still follows
+    if event.param as usize != (*transfer).lastTrb && code == CC_SHORT_PACKET {
+        (*transfer).actual = (*transfer).length.saturating_sub(residual);
+        (*transfer).code = code;
+        return;
+    }
+
+    if (*transfer).code != CC_SHORT_PACKET || code != CC_SUCCESS {
+        (*transfer).actual = (*transfer).length.saturating_sub(residual);
+        (*transfer).code = code;
+    }
+    (*slot).pending[dci] = null_mut();
+    (*transfer).done.store(true, Ordering::Release);
+
+    if let Some(callback) = (*transfer).callback {
+        callback(transfer);
+    }
+}
+
+unsafe fn event_handle(hc: *mut Xhci, event: &Trb) {
+    match (event.control >> 10) & 0x3f {
+        TRB_TRANSFER_EVENT => event_transfer(hc, event),
+        TRB_COMMAND_COMPLETION => {
+            if event.param as usize == (*hc).commandTrb {
+                (*hc).commandResult = *event;
+                (*hc).commandDone.store(true, Ordering::Release);
+            }
+        }
+        TRB_PORT_STATUS_CHANGE => {
+            let port = ((event.param >> 24) & 0xff) as usize;
+            if port > 0 && port <= (*hc).maxPorts as usize {
+                (*hc).portChanges[(port - 1) / 32] |= 1 << ((port - 1) % 32);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Drains the event ring. Whoever gets here first does it, the interrupt
+// handler or someone spinning on a transfer, the other one just moves on
+pub unsafe fn xhci_poll(hc: *mut Xhci) {
+    if (*hc).eventBusy.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    wr32((*hc).op + OP_USBSTS, USBSTS_EINT);
+    wr32((*hc).rt + IR0_IMAN, IMAN_IE | IMAN_IP);
+
+    let mut handled = false;
+    loop {
+        let trb = (*hc).events.add((*hc).eventDequeue);
+        let event = read_volatile(trb);
+        if event.control & TRB_CYCLE != (*hc).eventCycle {
+            break;
+        }
+
+        event_handle(hc, &event);
+        handled = true;
+
+        (*hc).eventDequeue += 1;
+        if (*hc).eventDequeue == RING_TRBS {
+            (*hc).eventDequeue = 0;
+            (*hc).eventCycle ^= 1;
+        }
+    }
+
+    if handled {
+        let dequeue = ((*hc).eventsPhys + (*hc).eventDequeue * 16) as u64;
+        wr64((*hc).rt + IR0_ERDP, dequeue | ERDP_EHB);
+    }
+
+    (*hc).eventBusy.store(false, Ordering::Release);
+}
+
+extern "C" fn xhci_irq(_regs: *mut AsmPassedInterrupt) {
+    unsafe {
+        for i in 0..XHCI_COUNT {
+            xhci_poll(XHCI_CONTROLLERS[i]);
+        }
+    }
+}
+
+pub unsafe fn xhci_controllers() -> &'static [*mut Xhci] {
+    &XHCI_CONTROLLERS[..XHCI_COUNT]
+}
+
+//
+// ================= Commands =================
+//
+
+unsafe fn xhci_command(hc: *mut Xhci, param: u64, status: u32, control: u32) -> Option<Trb> {
+    spinlockAcquire(&mut (*hc).LOCK_COMMAND);
+    (*hc).commandDone.store(false, Ordering::Release);
+    (*hc).commandTrb = ring_push(&mut (*hc).commands, param, status, control);
+    doorbell(hc, 0, 0);
+
+    let start = timerTicks;
+    while !(*hc).commandDone.load(Ordering::Acquire) {
+        if timerTicks > start + COMMAND_TIMEOUT {
+            spinlockRelease(&mut (*hc).LOCK_COMMAND);
+            debugf(b"[xhci] Command %d timed out!\n\0".as_ptr(), control >> 10);
+            return None;
+        }
+        xhci_poll(hc);
+    }
+
+    let result = (*hc).commandResult;
+    spinlockRelease(&mut (*hc).LOCK_COMMAND);
+
+    let code = (result.status >> 24) as u8;
+    if code != CC_SUCCESS {
+        debugf(b"[xhci] Command %d failed with %d\n\0".as_ptr(), control >> 10, code as u32);
+        return None;
+    }
+    Some(result)
+}
+
+pub unsafe fn xhci_enable_slot(hc: *mut Xhci) -> Option<u8> {
+    let result = xhci_command(hc, 0, 0, TRB_ENABLE_SLOT << 10)?;
+    let slotId = (result.control >> 24) as u8;
+
+    let slot = malloc(core::mem::size_of::<XhciSlot>()) as *mut XhciSlot;
+    write_bytes(slot, 0, 1);
+    let mut phys = 0;
+    (*slot).input = dma_page(&mut phys);
+    (*slot).inputPhys = phys;
+    (*slot).output = dma_page(&mut phys);
+    (*slot).outputPhys = phys;
+
+    *(*hc).dcbaa.add(slotId as usize) = (*slot).outputPhys as u64;
+    (*hc).slots[slotId as usize] = slot;
+    Some(slotId)
+}
+
+// The slot's pages stay around, a late event can't point into freed memory
+pub unsafe fn xhci_disable_slot(hc: *mut Xhci, slotId: u8) {
+    xhci_command(hc, 0, 0, (TRB_DISABLE_SLOT << 10) | ((slotId as u32) << 24));
+    let slot = (*hc).slots[slotId as usize];
+    (*hc).slots[slotId as usize] = null_mut();
+    *(*hc).dcbaa.add(slotId as usize) = 0;
+    if slot.is_null() {
+        return;
+    }
+    for dci in 0..XHCI_MAX_DCI {
+        let transfer = (*slot).pending[dci];
+        if !transfer.is_null() {
+            (*transfer).code = CC_TIMEOUT;
+            (*transfer).done.store(true, Ordering::Release);
+        }
+    }
+}
+
+// Default control endpoint packet size, until the device descriptor says
+pub fn default_max_packet(speed: u8) -> u16 {
+    match speed {
+        USB_SPEED_LOW | USB_SPEED_FULL => 8,
+        USB_SPEED_HIGH => 64,
+        _ => 512,
+    }
+}
+
+// Slot context bits that don't change over a device's life
+unsafe fn slot_fill(dev: *mut UsbDevice, ctx: *mut u32, entries: u32) {
+    *ctx = ((*dev).route & 0xfffff) | (((*dev).speed as u32) << 20) | (entries << 27);
+    *ctx.add(1) = ((*dev).rootPort as u32) << 16;
+    *ctx.add(2) = ((*dev).ttSlot as u32) | (((*dev).ttPort as u32) << 8);
+}
+
+pub unsafe fn xhci_address_device(dev: *mut UsbDevice) -> bool {
+    let hc = (*dev).hc;
+    let slot = (*hc).slots[(*dev).slot as usize];
+
+    write_bytes((*slot).input, 0, BLOCK_SIZE);
+    let control = input_ctx(hc, slot, 0);
+    // slot and EP0
+    *control.add(1) = 0b11;
+
+    slot_fill(dev, input_ctx(hc, slot, 1), 1);
+
+    (*slot).rings[1] = ring_create();
+    let ep0 = input_ctx(hc, slot, 2);
+    *ep0.add(1) = (3 << 1) | (EP_TYPE_CONTROL << 3) | (((*dev).maxPacket0 as u32) << 16);
+    let dequeue = ring_dequeue_pointer(&(*slot).rings[1]);
+    *ep0.add(2) = dequeue as u32;
+    *ep0.add(3) = (dequeue >> 32) as u32;
+    *ep0.add(4) = 8;
+
+    xhci_command(
+        hc,
+        (*slot).inputPhys as u64,
+        0,
+        (TRB_ADDRESS_DEVICE << 10) | (((*dev).slot as u32) << 24),
+    )
+    .is_some()
+}
+
+// EP0's max packet size, once the first 8 bytes of the descriptor are in
+pub unsafe fn xhci_update_ep0(dev: *mut UsbDevice) -> bool {
+    let hc = (*dev).hc;
+    let slot = (*hc).slots[(*dev).slot as usize];
+
+    input_prepare(hc, slot, 0b10);
+    let ep0 = input_ctx(hc, slot, 2);
+    *ep0.add(1) = (*ep0.add(1) & 0xffff) | (((*dev).maxPacket0 as u32) << 16);
+
+    xhci_command(
+        hc,
+        (*slot).inputPhys as u64,
+        0,
+        (TRB_EVALUATE_CONTEXT << 10) | (((*dev).slot as u32) << 24),
+    )
+    .is_some()
+}
+
+// Marks a slot as a hub, for the controller to route to what's behind it
+pub unsafe fn xhci_configure_hub(dev: *mut UsbDevice, ports: u8, ttThinkTime: u8, multiTT: bool) -> bool {
+    let hc = (*dev).hc;
+    let slot = (*hc).slots[(*dev).slot as usize];
+
+    input_prepare(hc, slot, 0b1);
+    let ctx = input_ctx(hc, slot, 1);
+    *ctx |= (1 << 26) | ((multiTT as u32) << 25);
+    *ctx.add(1) = (*ctx.add(1) & 0x00ff_ffff) | ((ports as u32) << 24);
+    *ctx.add(2) = (*ctx.add(2) & !(0b11 << 16)) | (((ttThinkTime & 0b11) as u32) << 16);
+    // the state field isn't an input
+    *ctx.add(3) = 0;
+
+    xhci_command(
+        hc,
+        (*slot).inputPhys as u64,
+        0,
+        (TRB_CONFIGURE_ENDPOINT << 10) | (((*dev).slot as u32) << 24),
+    )
+    .is_some()
+}
+
+// xHCI wants 125us units as a power of two, descriptors have frames (full
+// and low speed) or already an exponent (high and super speed)
+fn endpoint_interval(speed: u8, interrupt: bool, bInterval: u8) -> u32 {
+    let interval = bInterval.max(1) as u32;
+    match speed {
+        USB_SPEED_LOW | USB_SPEED_FULL if interrupt => {
+            // frames to microframes, then the exponent
+            (31 - (interval * 8).leading_zeros()).clamp(3, 10)
+        }
+        USB_SPEED_LOW | USB_SPEED_FULL => 0,
+        _ => (interval - 1).min(15),
+    }
+}
+
+pub unsafe fn xhci_open_endpoint(dev: *mut UsbDevice, desc: *const UsbEndpointDescriptor) -> bool {
+    let hc = (*dev).hc;
+    let slot = (*hc).slots[(*dev).slot as usize];
+
+    let address = (*desc).bEndpointAddress;
+    let attributes = (*desc).bmAttributes & 0b11;
+    let maxPacket = u16::from_le_bytes((*desc).wMaxPacketSize);
+    let dci = endpoint_dci(address);
+    let input = address & 0x80 != 0;
+
+    // isochronous 1/5, bulk 2/6, interrupt 3/7
+    let epType = attributes as u32 + if input { 4 } else { 0 };
+
+    input_prepare(hc, slot, 0b1 | (1 << dci));
+    let slotCtx = input_ctx(hc, slot, 1);
+    let entries = ((*slotCtx >> 27) as usize).max(dci) as u32;
+    *slotCtx = (*slotCtx & 0x07ff_ffff) | (entries << 27);
+    *slotCtx.add(3) = 0;
+
+    if (*slot).rings[dci].trbs.is_null() {
+        (*slot).rings[dci] = ring_create();
+    }
+    let ring = &(*slot).rings[dci];
+
+    let ep = input_ctx(hc, slot, dci + 1);
+    write_bytes(ep as *mut u8, 0, (*hc).ctxSize);
+    let interval = endpoint_interval((*dev).speed, attributes == 3, (*desc).bInterval);
+    let burst = ((maxPacket >> 11) & 0b11) as u32;
+    *ep = interval << 16;
+    *ep.add(1) = (3 << 1) | (epType << 3) | (burst << 8) | (((maxPacket & 0x7ff) as u32) << 16);
+    let dequeue = ring_dequeue_pointer(ring);
+    *ep.add(2) = dequeue as u32;
+    *ep.add(3) = (dequeue >> 32) as u32;
+    let payload = (maxPacket & 0x7ff) as u32 * (burst + 1);
+    *ep.add(4) = if attributes == 3 { payload | (payload << 16) } else { 3072 };
+
+    xhci_command(
+        hc,
+        (*slot).inputPhys as u64,
+        0,
+        (TRB_CONFIGURE_ENDPOINT << 10) | (((*dev).slot as u32) << 24),
+    )
+    .is_some()
+}
+
+// After a stall: the endpoint goes back to running, starting past whatever
+// was left on its ring
+pub unsafe fn xhci_reset_endpoint(dev: *mut UsbDevice, endpoint: u8) -> bool {
+    let hc = (*dev).hc;
+    let slot = (*hc).slots[(*dev).slot as usize];
+    let dci = endpoint_dci(endpoint);
+    let target = ((dci as u32) << 16) | (((*dev).slot as u32) << 24);
+
+    xhci_command(hc, 0, 0, (TRB_RESET_ENDPOINT << 10) | target);
+    xhci_command(
+        hc,
+        ring_dequeue_pointer(&(*slot).rings[dci]),
+        0,
+        (TRB_SET_TR_DEQUEUE << 10) | target,
+    )
+    .is_some()
+}
+
+//
+// ================= Transfers =================
+//
+
+// Queues the buffer as Normal (or Data) TRBs, split wherever it crosses 64K
+unsafe fn push_buffer(ring: &mut Ring, phys: usize, length: usize, first: u32, input: bool) -> usize {
+    let dir = if input { TRB_DIR_IN } else { 0 };
+    let mut kind = first;
+    let mut offset = 0;
+    let mut last;
+
+    loop {
+        let addr = phys + offset;
+        let piece = (TRB_MAX_BYTES - (addr % TRB_MAX_BYTES)).min(length - offset);
+        offset += piece;
+        let more = offset < length;
+
+        let mut control = (kind << 10) | TRB_ISP;
+        if kind == TRB_DATA {
+            control |= dir;
+        }
+        control |= if more { TRB_CHAIN } else { TRB_IOC };
+
+        // TD size: packets left after this one, capped
+        let remaining = ((length - offset) / 512).min(31) as u32;
+        last = ring_push(ring, addr as u64, piece as u32 | (remaining << 17), control);
+
+        kind = TRB_NORMAL;
+        if !more {
+            break;
+        }
+    }
+    last
+}
+
+unsafe fn transfer_wait(hc: *mut Xhci, transfer: *mut UsbTransfer) -> bool {
+    let start = timerTicks;
+    while !(*transfer).done.load(Ordering::Acquire) {
+        if timerTicks > start + TRANSFER_TIMEOUT {
+            return false;
+        }
+        xhci_poll(hc);
+    }
+    true
+}
+
+// Forgets about a transfer that never finished, stopping short of anything
+// the controller might still write to
+unsafe fn transfer_abandon(dev: *mut UsbDevice, dci: usize) {
+    let hc = (*dev).hc;
+    let slot = (*hc).slots[(*dev).slot as usize];
+    if !slot.is_null() {
+        (*slot).pending[dci] = null_mut();
+    }
+}
+
+// Control transfer on EP0, data through the device's DMA page. Returns the
+// bytes moved, or the completion code
+pub unsafe fn xhci_control(dev: *mut UsbDevice, setup: &UsbSetup, data: *mut u8) -> Result<usize, u8> {
+    let hc = (*dev).hc;
+    let slot = (*hc).slots[(*dev).slot as usize];
+    if slot.is_null() {
+        return Err(CC_TIMEOUT);
+    }
+
+    let length = (setup.wLength as usize).min(BLOCK_SIZE);
+    let input = setup.bmRequestType & 0x80 != 0;
+    if !input && length > 0 {
+        core::ptr::copy_nonoverlapping(data, (*dev).dma, length);
+    }
+
+    let mut transfer = UsbTransfer::new();
+    transfer.dev = dev;
+    transfer.length = length;
+
+    let ring = &mut (*slot).rings[1];
+    let setupRaw = core::ptr::read_unaligned(setup as *const UsbSetup as *const u64);
+    let trt = match (length, input) {
+        (0, _) => 0,
+        (_, false) => 2,
+        (_, true) => 3,
+    };
+    ring_push(ring, setupRaw, 8, (TRB_SETUP << 10) | TRB_IDT | (trt << 16));
+    if length > 0 {
+        push_buffer(ring, (*dev).dmaPhys, length, TRB_DATA, input);
+    }
+    // the status stage goes the other way
+    let statusDir = if length > 0 && input { 0 } else { TRB_DIR_IN };
+    transfer.lastTrb = ring_push(ring, 0, 0, (TRB_STATUS << 10) | TRB_IOC | statusDir);
+
+    (*slot).pending[1] = &mut transfer;
+    doorbell(hc, (*dev).slot, 1);
+
+    if !transfer_wait(hc, &mut transfer) {
+        transfer_abandon(dev, 1);
+        return Err(CC_TIMEOUT);
+    }
+
+    match transfer.code {
+        CC_SUCCESS | CC_SHORT_PACKET => {
+            let actual = if transfer.code == CC_SUCCESS { length } else { transfer.actual };
+            if input && actual > 0 {
+                core::ptr::copy_nonoverlapping((*dev).dma, data, actual);
+            }
+            Ok(actual)
+        }
+        CC_STALL => {
+            // EP0 comes back by itself on the next setup packet, the ring
+            // still has to move on
+            xhci_reset_endpoint(dev, 0);
+            Err(CC_STALL)
+        }
+        code => Err(code),
+    }
+}
+
+// Starts a bulk or interrupt transfer. The buffer has to be physically
+// contiguous, the transfer has to stay put until it's done
+pub unsafe fn xhci_queue(transfer: *mut UsbTransfer) -> bool {
+    let dev = (*transfer).dev;
+    let hc = (*dev).hc;
+    let slot = (*hc).slots[(*dev).slot as usize];
+    let dci = endpoint_dci((*transfer).endpoint);
+    if slot.is_null() || (*slot).rings[dci].trbs.is_null() || !(*slot).pending[dci].is_null() {
+        return false;
+    }
+
+    (*transfer).actual = 0;
+    (*transfer).code = 0;
+    (*transfer).done.store(false, Ordering::Release);
+
+    let phys = VirtualToPhysical((*transfer).buffer as usize);
+    let input = (*transfer).endpoint & 0x80 != 0;
+    let ring = &mut (*slot).rings[dci];
+    (*transfer).lastTrb = push_buffer(ring, phys, (*transfer).length, TRB_NORMAL, input);
+
+    (*slot).pending[dci] = transfer;
+    doorbell(hc, (*dev).slot, dci as u32);
+    true
+}
+
+// The same, waiting for it to finish
+pub unsafe fn xhci_transfer(dev: *mut UsbDevice, endpoint: u8, buffer: *mut u8, length: usize) -> Result<usize, u8> {
+    let mut transfer = UsbTransfer::new();
+    transfer.dev = dev;
+    transfer.endpoint = endpoint;
+    transfer.buffer = buffer;
+    transfer.length = length;
+
+    if !xhci_queue(&mut transfer) {
+        return Err(CC_TIMEOUT);
+    }
+    if !transfer_wait((*dev).hc, &mut transfer) {
+        transfer_abandon(dev, endpoint_dci(endpoint));
+        return Err(CC_TIMEOUT);
+    }
+
+    match transfer.code {
+        CC_SUCCESS => Ok(length),
+        CC_SHORT_PACKET => Ok(transfer.actual),
+        code => Err(code),
+    }
+}
+
+//
+// ================= Root hub ports =================
+//
+
+#[inline]
+unsafe fn portsc(hc: *mut Xhci, port: u8) -> usize {
+    (*hc).op + OP_PORTSC + (port as usize - 1) * 0x10
+}
+
+pub unsafe fn xhci_port_connected(hc: *mut Xhci, port: u8) -> bool {
+    rd32(portsc(hc, port)) & PORTSC_CCS != 0
+}
+
+// Acknowledges the port's changes, for the next one to be noticed. True if
+// something got plugged or unplugged, rather than the port just resetting
+pub unsafe fn xhci_port_clear(hc: *mut Xhci, port: u8) -> bool {
+    let reg = portsc(hc, port);
+    let value = rd32(reg);
+    wr32(reg, (value & !(PORTSC_PED | PORTSC_CHANGES)) | (value & PORTSC_CHANGES));
+    value & PORTSC_CSC != 0
+}
+
+// Resets a port with something on it, giving back its speed once enabled.
+// USB 3 ports come up enabled on their own
+pub unsafe fn xhci_port_reset(hc: *mut Xhci, port: u8) -> Option<u8> {
+    let reg = portsc(hc, port);
+    let value = rd32(reg);
+    if value & PORTSC_CCS == 0 {
+        return None;
+    }
+
+    if value & PORTSC_PED == 0 {
+        wr32(reg, (value & !(PORTSC_PED | PORTSC_CHANGES)) | PORTSC_PR);
+        if !wait_bits(reg, PORTSC_PRC, true, 500) {
+            return None;
+        }
+        xhci_port_clear(hc, port);
+        // reset recovery
+        sleep(10);
+    }
+
+    let value = rd32(reg);
+    if value & PORTSC_PED == 0 {
+        return None;
+    }
+    Some(((value >> 10) & 0xf) as u8)
+}
+
+// Ports that got plugged or unplugged since the last call
+pub unsafe fn xhci_port_changes(hc: *mut Xhci, out: &mut [u32; 8]) {
+    for i in 0..8 {
+        // the event handler only ever sets bits
+        let bits = read_volatile(&(*hc).portChanges[i]);
+        out[i] = bits;
+        (*hc).portChanges[i] &= !bits;
+    }
+}
+
+//
+// ================= Initialization =================
+//
+
+unsafe fn xhci_take_ownership(hc: *mut Xhci, hccparams1: u32) {
+    let mut offset = ((hccparams1 >> 16) as usize) << 2;
+    while offset != 0 {
+        let addr = (*hc).cap + offset;
+        let value = rd32(addr);
+        if value & 0xff == XECP_LEGACY {
+            wr32(addr, value | LEGACY_OS_OWNED);
+            if !wait_bits(addr, LEGACY_BIOS_OWNED, false, 1000) {
+                debugf(b"[xhci] BIOS didn't hand over the controller\n\0".as_ptr());
+            }
+            // no more SMIs
+            wr32(addr + 4, rd32(addr + 4) & 0x000e_1fee);
+            return;
+        }
+        let next = ((value >> 8) & 0xff) as usize;
+        if next == 0 {
+            return;
+        }
+        offset += next << 2;
+    }
+}
+
+unsafe fn xhci_map(phys: usize, length: usize) -> usize {
+    let virt = bootloader.hhdmOffset + phys;
+    let mut page = phys & !(BLOCK_SIZE - 1);
+    while page < phys + length {
+        VirtualMap(bootloader.hhdmOffset + page, page, PF_RW | PF_CACHE_DISABLE);
+        page += BLOCK_SIZE;
+    }
+    virt
+}
+
+unsafe fn xhci_reset(hc: *mut Xhci) -> bool {
+    let cmd = (*hc).op + OP_USBCMD;
+    let sts = (*hc).op + OP_USBSTS;
+
+    wr32(cmd, rd32(cmd) & !USBCMD_RUN);
+    if !wait_bits(sts, USBSTS_HCH, true, 100) {
+        return false;
+    }
+
+    wr32(cmd, rd32(cmd) | USBCMD_HCRST);
+    wait_bits(cmd, USBCMD_HCRST, false, 1000) && wait_bits(sts, USBSTS_CNR, false, 1000)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn initiateXHCI(device: *const PCIdevice) {
+    if XHCI_COUNT >= XHCI_MAX_CONTROLLERS {
+        return;
+    }
+
+    let details = malloc(core::mem::size_of::<PCIgeneralDevice>()) as *mut PCIgeneralDevice;
+    GetGeneralDevice(device, details);
+
+    let bar0 = (*details).bar[0];
+    let mut phys = (bar0 & !0xf) as usize;
+    if (bar0 >> 1) & 0b11 == 0b10 {
+        phys |= ((*details).bar[1] as usize) << 32;
+    }
+
+    // memory space and bus mastering
+    let (bus, slot, function) = ((*device).bus, (*device).slot, (*device).function);
+    let command = ConfigReadWord(bus, slot, function, 0x04) | 0b110;
+    let status = ConfigReadWord(bus, slot, function, 0x06);
+    ConfigWriteDword(bus, slot, function, 0x04, ((status as u32) << 16) | command as u32);
+
+    let hc = malloc(core::mem::size_of::<Xhci>()) as *mut Xhci;
+    write_bytes(hc, 0, 1);
+
+    (*hc).cap = xhci_map(phys, BLOCK_SIZE);
+    let capLength = read_volatile(((*hc).cap + CAP_CAPLENGTH) as *const u8) as usize;
+    let hcsparams1 = rd32((*hc).cap + CAP_HCSPARAMS1);
+    let hcsparams2 = rd32((*hc).cap + CAP_HCSPARAMS2);
+    let hccparams1 = rd32((*hc).cap + CAP_HCCPARAMS1);
+    let dboff = (rd32((*hc).cap + CAP_DBOFF) & !0b11) as usize;
+    let rtsoff = (rd32((*hc).cap + CAP_RTSOFF) & !0x1f) as usize;
+
+    (*hc).maxSlots = hcsparams1 as u8;
+    (*hc).maxPorts = (hcsparams1 >> 24) as u8;
+    (*hc).ctxSize = if hccparams1 & (1 << 2) != 0 { 64 } else { 32 };
+
+    // everything else, now that it's known how far the registers go
+    let end = (capLength + OP_PORTSC + (*hc).maxPorts as usize * 0x10)
+        .max(rtsoff + IR0_ERDP + 8)
+        .max(dboff + 256 * 4);
+    xhci_map(phys, end);
+    (*hc).op = (*hc).cap + capLength;
+    (*hc).rt = (*hc).cap + rtsoff;
+    (*hc).db = (*hc).cap + dboff;
+
+    xhci_take_ownership(hc, hccparams1);
+    if !xhci_reset(hc) {
+        debugf(b"[xhci] Controller didn't reset!\n\0".as_ptr());
+        free(details as *mut u8);
+        free(hc as *mut u8);
+        return;
+    }
+
+    wr32((*hc).op + OP_CONFIG, (*hc).maxSlots as u32);
+
+    // device context pointers, entry 0 being the scratchpad array
+    let mut dcbaaPhys = 0;
+    (*hc).dcbaa = dma_page(&mut dcbaaPhys) as *mut u64;
+    let scratchpads = (((hcsparams2 >> 21) & 0x1f) << 5 | (hcsparams2 >> 27)) as usize;
+    if scratchpads > 0 {
+        let mut arrayPhys = 0;
+        let array = dma_page(&mut arrayPhys) as *mut u64;
+        for i in 0..scratchpads.min(BLOCK_SIZE / 8) {
+            let mut pagePhys = 0;
+            dma_page(&mut pagePhys);
+            *array.add(i) = pagePhys as u64;
+        }
+        *(*hc).dcbaa = arrayPhys as u64;
+    }
+    wr64((*hc).op + OP_DCBAAP, dcbaaPhys as u64);
+
+    (*hc).commands = ring_create();
+    wr64((*hc).op + OP_CRCR, (*hc).commands.phys as u64 | 1);
+
+    // one event ring segment for interrupter 0
+    let mut eventsPhys = 0;
+    (*hc).events = dma_page(&mut eventsPhys) as *mut Trb;
+    (*hc).eventsPhys = eventsPhys;
+    (*hc).eventCycle = 1;
+    let mut erstPhys = 0;
+    let erst = dma_page(&mut erstPhys) as *mut ErstEntry;
+    (*erst).base = eventsPhys as u64;
+    (*erst).size = RING_TRBS as u32;
+    wr32((*hc).rt + IR0_ERSTSZ, 1);
+    wr64((*hc).rt + IR0_ERDP, eventsPhys as u64);
+    wr64((*hc).rt + IR0_ERSTBA, erstPhys as u64);
+    // 1ms between interrupts at most
+    wr32((*hc).rt + IR0_IMOD, 4000);
+    wr32((*hc).rt + IR0_IMAN, IMAN_IE | IMAN_IP);
+
+    let pci = lookupPCIdevice(device);
+    (*hc).pci = pci;
+    if !pci.is_null() {
+        setupPCIdeviceDriver(pci, PCI_DRIVER_XHCI, PCI_DRIVER_CATEGORY_USB);
+        (*pci).extra = hc as *mut c_void;
+    }
+
+    XHCI_CONTROLLERS[XHCI_COUNT] = hc;
+    XHCI_COUNT += 1;
+
+    let irq = ioApicPciRegister(device, details);
+    let handler = registerIRQhandler(irq, xhci_irq);
+    if !pci.is_null() {
+        (*pci).irqHandler = handler;
+    }
+    free(details as *mut u8);
+
+    wr32((*hc).op + OP_USBCMD, USBCMD_RUN | USBCMD_INTE);
+    if !wait_bits((*hc).op + OP_USBSTS, USBSTS_HCH, false, 100) {
+        debugf(b"[xhci] Controller didn't start!\n\0".as_ptr());
+        return;
+    }
+
+    debugf(
+        b"[xhci] Controller up: %d slots, %d ports\n\0".as_ptr(),
+        (*hc).maxSlots as u32,
+        (*hc).maxPorts as u32,
+    );
+
+    // power everything, then give devices some time to connect
+    for port in 1..=(*hc).maxPorts {
+        let reg = portsc(hc, port);
+        let value = rd32(reg);
+        if value & PORTSC_PP == 0 {
+            wr32(reg, (value & !(PORTSC_PED | PORTSC_CHANGES)) | PORTSC_PP);
+        }
+    }
+    sleep(100);
+
+    // what's plugged in already gets set up right away, the disk might be
+    // among it
+    for port in 1..=(*hc).maxPorts {
+        (*hc).portChanges[(port as usize - 1) / 32] &= !(1 << ((port as usize - 1) % 32));
+        xhci_port_clear(hc, port);
+        if xhci_port_connected(hc, port) {
+            usb_root_attach(hc, port);
+        }
+    }
+
+    usb_thread_start();
+}
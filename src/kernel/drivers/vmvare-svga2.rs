@@ -10,6 +10,7 @@ use crate::fb::*;
 use crate::bootloader::*;
 use crate::util::*;
 use crate::vga::*;
+use crate::drm::{drmRegisterBackend, DrmBackend};
 
 #[repr(C)]
 pub struct VMWareSvga {
@@ -116,6 +117,30 @@ pub fn vmware_svga2_update(x: u32, y: u32, width: u32, height: u32) {
     vmware_svga2_fifo_write(height);
 }
 
+// KMS goes through here: refuse instead of asserting on what userspace asked
+unsafe fn vmware_svga2_drm_set_mode(width: u32, height: u32) -> bool {
+    if width > vmware_svga2_read(SVGA_REG_MAX_WIDTH) || height > vmware_svga2_read(SVGA_REG_MAX_HEIGHT) {
+        return false;
+    }
+    vmware_svga2_set_mode(width, height, 32);
+    vmware_svga2_sync();
+    true
+}
+
+unsafe fn vmware_svga2_drm_update(x: u32, y: u32, width: u32, height: u32) {
+    vmware_svga2_update(x, y, width, height);
+}
+
+static mut VMWARE_SVGA2_DRM: DrmBackend = DrmBackend {
+    name: b"VMware SVGA II\0".as_ptr(),
+    maxWidth: 0,
+    maxHeight: 0,
+    vramSize: 0,
+    setMode: vmware_svga2_drm_set_mode,
+    pan: None,
+    update: Some(vmware_svga2_drm_update),
+};
+
 pub fn initiate_vmware_svga2(device: &PciDevice) {
     if !vmware_svga2_detect(device) { return; }
 
@@ -161,5 +186,12 @@ pub fn initiate_vmware_svga2(device: &PciDevice) {
 
     draw_rect(0, 0, fb().width, fb().height, 255, 255, 0);
     vmware_svga2_update(0, 0, fb().width, fb().height);
-    unsafe { VMWareSvga2.exists = true; }
+    unsafe {
+        VMWareSvga2.exists = true;
+
+        VMWARE_SVGA2_DRM.maxWidth = vmware_svga2_read(SVGA_REG_MAX_WIDTH);
+        VMWARE_SVGA2_DRM.maxHeight = vmware_svga2_read(SVGA_REG_MAX_HEIGHT);
+        VMWARE_SVGA2_DRM.vramSize = vmware_svga2_read(SVGA_REG_VRAM_SIZE) as usize;
+        drmRegisterBackend(&VMWARE_SVGA2_DRM);
+    }
 }
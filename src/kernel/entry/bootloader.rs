@@ -57,6 +57,14 @@ static LIMINE_RSDP_REQ: limine_rsdp_request = limine_rsdp_request {
     response: ptr::null_mut(),
 };
 
+#[used]
+#[link_section = ".limine_reqs"]
+static LIMINE_KERNEL_FILE_REQ: limine_kernel_file_request = limine_kernel_file_request {
+    id: LIMINE_KERNEL_FILE_REQUEST,
+    revision: 0,
+    response: ptr::null_mut(),
+};
+
 pub unsafe fn initialise_bootloader_parser() {
     // Paging mode
     let paging_res = LIMINE_PAGING_REQ.response.as_ref().unwrap_or_else(|| {
@@ -143,4 +151,31 @@ pub unsafe fn initialise_bootloader_parser() {
 
     bootloader.rsdp =
         (rsdp_res.address as usize).wrapping_sub(bootloader.hhdm_offset as usize);
+
+    // Kernel command line (limine.conf's cmdline:), optional
+    bootloader.cmdline = LIMINE_KERNEL_FILE_REQ
+        .response
+        .as_ref()
+        .and_then(|res| res.kernel_file.as_ref())
+        .map_or(ptr::null(), |file| file.cmdline as *const _);
+}
+
+// The value of a `key=value` option on the kernel command line, or "" for a
+// bare `key`
+pub fn boot_option(key: &str) -> Option<&'static str> {
+    let cmdline = unsafe { bootloader.cmdline };
+    if cmdline.is_null() {
+        return None;
+    }
+
+    let cmdline = unsafe { core::ffi::CStr::from_ptr(cmdline as *const _) };
+    cmdline
+        .to_str()
+        .ok()?
+        .split_ascii_whitespace()
+        .find_map(|option| match option.split_once('=') {
+            Some((name, value)) if name == key => Some(value),
+            None if option == key => Some(""),
+            _ => None,
+        })
 }
@@ -32,6 +32,8 @@ extern "C" {
     fn initiateApicTimer();
 
     fn initiateKb();
+    fn initiateConsoleTty();
+    fn initiateVirtualTerminals();
     fn initiateMouse();
 
     fn initiateTasks();
@@ -47,7 +49,8 @@ extern "C" {
         b: u64,
     );
 
-    fn psfLoadFromFile(path: *const u8);
+    fn psfLoadFromFile(path: *const u8) -> bool;
+    fn vtFontChanged();
 
     fn initiateSyscallInst();
     fn initiateSyscalls();
@@ -64,6 +67,7 @@ const CONNECTOR_DEV: u32 = 0;
 const CONNECTOR_AHCI: u32 = 1;
 const CONNECTOR_SYS: u32 = 2;
 const CONNECTOR_PROC: u32 = 3;
+const CONNECTOR_TMPFS: u32 = 4;
 
 const DEFAULT_FONT_PATH: *const u8 = b"/sys/font.psf\0";
 
@@ -96,6 +100,8 @@ pub extern "C" fn _start() -> ! {
         fsMount(b"/dev/\0".as_ptr(), CONNECTOR_DEV, 0, 0);
         initiateKb();
         initiateMouse();
+        initiateConsoleTty();
+        initiateVirtualTerminals();
 
         initiateTasks();
         initiateKernelThreads();
@@ -107,8 +113,13 @@ pub extern "C" fn _start() -> ! {
         fsMount(b"/boot/\0".as_ptr(), CONNECTOR_AHCI, 0, 0);
         fsMount(b"/sys/\0".as_ptr(), CONNECTOR_SYS, 0, 0);
         fsMount(b"/proc/\0".as_ptr(), CONNECTOR_PROC, 0, 0);
+        fsMount(b"/tmp/\0".as_ptr(), CONNECTOR_TMPFS, 0, 0);
+        fsMount(b"/dev/shm/\0".as_ptr(), CONNECTOR_TMPFS, 0, 0);
+        fsMount(b"/run/\0".as_ptr(), CONNECTOR_TMPFS, 0, 0);
 
-        psfLoadFromFile(DEFAULT_FONT_PATH);
+        if psfLoadFromFile(DEFAULT_FONT_PATH) {
+            vtFontChanged();
+        }
 
         initiateSyscallInst();
         initiateSyscalls();
@@ -1,5 +1,6 @@
 #![no_std]
 
+use core::net::{IpAddr, Ipv4Addr};
 use core::ptr;
 
 use crate::{
@@ -31,8 +32,10 @@ extern "C" {
     fn handControl();
 }
 
-/// Equivalent of `waitNicIPAssigned`
-pub fn wait_nic_ip_assigned() {
+/// Equivalent of `waitNicIPAssigned`, returns the address tests should use.
+/// Without a physical NIC there's nothing to wait for, lo always has
+/// 127.0.0.1.
+pub fn wait_nic_ip_assigned() -> Ipv4Addr {
     unsafe {
         let mut pci = dsPCI.firstObject as *mut PCI;
 
@@ -49,17 +52,81 @@ pub fn wait_nic_ip_assigned() {
             ptr::null_mut()
         };
 
-        if !nic.is_null() {
-            while (*nic).lwip.ip_addr.addr == 0 {
-                handControl();
+        if nic.is_null() {
+            return Ipv4Addr::LOCALHOST;
+        }
+
+        while (*nic).lwip.ip_addr.addr == 0 {
+            handControl();
+        }
+        // network byte order
+        Ipv4Addr::from(u32::from_be((*nic).lwip.ip_addr.addr))
+    }
+}
+
+/// Looks `host` up with the kernel resolver (/etc/hosts, then whatever
+/// /etc/resolv.conf points at, a local stub included) and logs the answer
+pub fn testing_resolve(host: &str) -> Option<IpAddr> {
+    let addrs = match crate::dns::dns_resolve(host, 0) {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            unsafe {
+                debugf(
+                    b"[testing] %.*s: resolution failed (errno %d)\n\0".as_ptr() as *const i8,
+                    host.len() as i32,
+                    host.as_ptr(),
+                    err.raw_os_error().unwrap_or(0),
+                );
+            }
+            return None;
+        }
+    };
+
+    for addr in &addrs {
+        unsafe {
+            match addr {
+                IpAddr::V4(v4) => {
+                    let [a, b, c, d] = v4.octets();
+                    debugf(
+                        b"[testing] %.*s: %d.%d.%d.%d\n\0".as_ptr() as *const i8,
+                        host.len() as i32,
+                        host.as_ptr(),
+                        a as u32,
+                        b as u32,
+                        c as u32,
+                        d as u32,
+                    );
+                }
+                IpAddr::V6(v6) => {
+                    let s = v6.segments();
+                    debugf(
+                        b"[testing] %.*s: %x:%x:%x:%x:%x:%x:%x:%x\n\0".as_ptr() as *const i8,
+                        host.len() as i32,
+                        host.as_ptr(),
+                        s[0] as u32,
+                        s[1] as u32,
+                        s[2] as u32,
+                        s[3] as u32,
+                        s[4] as u32,
+                        s[5] as u32,
+                        s[6] as u32,
+                        s[7] as u32,
+                    );
+                }
             }
         }
     }
+    addrs.first().copied()
 }
 
-/// Testing init (currently empty, same as C)
+/// Testing init. `testing.resolve[=host]` on the kernel command line looks
+/// `host` (stub.test by default) up once there's an address, for running
+/// against a local stub DNS server that /etc/resolv.conf points at.
 pub fn testing_init() {
-    // wait_nic_ip_assigned();
+    if let Some(host) = crate::bootloader::boot_option("testing.resolve") {
+        wait_nic_ip_assigned();
+        testing_resolve(if host.is_empty() { "stub.test" } else { host });
+    }
     // run(argv[0], true, argv.len(), argv);
 }
 
@@ -38,6 +38,10 @@ pub static HANDLE_RANDOM: VfsHandlers = VfsHandlers {
     ..VfsHandlers::default()
 };
 
+extern "C" {
+    fn ttyReopen(tty: *mut core::ffi::c_void, fd: &mut OpenFile) -> usize;
+}
+
 /// Open a /dev/tty for the current task
 pub fn dev_tty_open(
     _filename: &str,
@@ -47,15 +51,15 @@ pub fn dev_tty_open(
 ) -> Result<(), i32> {
     let task = current_task();
 
-    if task.ctrl_pty == -1 {
+    if task.ctrl_tty.is_null() {
         return Err(ENXIO);
     }
 
-    let mut path = [0u8; 128];
-    let _ = snprintf(&mut path, "/pts/{}", task.ctrl_pty);
-
-    fd.handlers = &HANDLE_PTS;
-    pts_open(&path, fd)?;
+    // whoever drives the terminal (pty, console) hands out the new handle
+    let ret = unsafe { ttyReopen(task.ctrl_tty, fd) } as isize;
+    if ret < 0 {
+        return Err(-ret as i32);
+    }
     Ok(())
 }
 
@@ -72,6 +76,10 @@ pub fn dev_setup() {
         fakefs_add_file(root_file, "stdout", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_STDIO);
         fakefs_add_file(root_file, "stderr", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_STDIO);
         fakefs_add_file(root_file, "tty", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_TTY);
+        // tty0 is whichever virtual terminal is on screen
+        for name in ["tty0", "tty1", "tty2", "tty3", "tty4", "tty5", "tty6"] {
+            fakefs_add_file(root_file, name, 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_VT);
+        }
         fakefs_add_file(root_file, "fb0", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_FB0);
         fakefs_add_file(root_file, "null", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_NULL);
         fakefs_add_file(root_file, "random", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_RANDOM);
@@ -83,6 +91,13 @@ pub fn dev_setup() {
         let pts = fakefs_add_file(root_file, "pts", 0, S_IFDIR | S_IRUSR | S_IWUSR, &FAKEFS_ROOT_HANDLERS);
         fakefs_add_file(&pts, "*", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_PTS);
 
+        let dri = fakefs_add_file(root_file, "dri", 0, S_IFDIR | S_IRUSR | S_IWUSR, &FAKEFS_ROOT_HANDLERS);
+        fakefs_add_file(&dri, "card0", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_DRM);
+
+        let snd = fakefs_add_file(root_file, "snd", 0, S_IFDIR | S_IRUSR | S_IWUSR, &FAKEFS_ROOT_HANDLERS);
+        fakefs_add_file(&snd, "controlC0", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_SND_CONTROL);
+        fakefs_add_file(&snd, "pcmC0D0p", 0, S_IFCHR | S_IRUSR | S_IWUSR, &HANDLE_SND_PCM);
+
         INPUT_FAKE_DIR = fakefs_add_file(root_file, "input", 0, S_IFDIR | S_IRUSR | S_IWUSR, &FAKEFS_ROOT_HANDLERS);
     }
 }
@@ -1,8 +1,9 @@
 #![no_std]
 
 use core::cmp::min;
-use core::ptr::{copy_nonoverlapping, null_mut};
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::ptr::null_mut;
+
+use super::tty::*;
 
 //
 // Constants
@@ -15,33 +16,34 @@ const O_NONBLOCK: u32 = 0x800;
 
 const EPOLLIN: i32 = 0x001;
 const EPOLLOUT: i32 = 0x004;
+const EPOLLHUP: i32 = 0x010;
 
 const EWOULDBLOCK: isize = 11;
+const EINTR: isize = 4;
+const EFAULT: isize = 14;
 const ENOENT: isize = 2;
 const EIO: isize = 5;
-const EPERM: isize = 1;
-const ENOTTY: isize = 25;
+
+const TIOCGPTN: u64 = 0x80045430;
+const TIOCSPTLCK: u64 = 0x40045431;
 
 #[inline]
 const fn err(code: isize) -> usize {
     (!code + 1) as usize
 }
 
+#[inline]
+const fn RET_IS_ERR(ret: usize) -> bool {
+    ret > (-4096isize) as usize
+}
+
 //
 // Kernel primitives
 //
 
-#[repr(C)]
-pub struct Spinlock {
-    _priv: u32,
-}
-
 extern "C" {
     fn spinlockAcquire(lock: *mut Spinlock);
     fn spinlockRelease(lock: *mut Spinlock);
-
-    fn spinlockCntReadAcquire(lock: *mut Spinlock);
-    fn spinlockCntReadRelease(lock: *mut Spinlock);
 }
 
 //
@@ -49,6 +51,8 @@ extern "C" {
 //
 
 extern "C" {
+    static mut currentTask: *mut Task;
+
     fn bitmapGenericGet(map: *mut u8, idx: usize) -> bool;
     fn bitmapGenericSet(map: *mut u8, idx: usize, val: bool);
 
@@ -60,6 +64,7 @@ extern "C" {
     fn memmove(dst: *mut u8, src: *const u8, n: usize);
 
     fn handControl();
+    fn signalsPendingQuick(task: *mut Task) -> bool;
 
     fn pollInstanceRing(key: usize, events: i32);
 
@@ -79,7 +84,7 @@ pub struct LLcontrol {
 extern "C" {
     fn LinkedListInit(ctrl: *mut LLcontrol, size: usize);
     fn LinkedListAllocate(ctrl: *mut LLcontrol, size: usize) -> *mut PtyPair;
-    fn LinkedListRemove(ctrl: *mut LLcontrol, size: usize, elem: *mut PtyPair) -> bool;
+    fn LinkedListUnregister(ctrl: *mut LLcontrol, size: usize, elem: *const PtyPair) -> bool;
     fn LinkedListSearch(
         ctrl: *mut LLcontrol,
         cb: extern "C" fn(*mut u8, *mut u8) -> bool,
@@ -87,38 +92,10 @@ extern "C" {
     ) -> *mut PtyPair;
 }
 
-//
-// Tasks
-//
-
-#[repr(C)]
-pub struct Task {
-    pub next: *mut Task,
-    pub state: i32,
-    pub ctrlPty: i32,
-    pub sid: i32,
-    pub tgid: i32,
-    pub pgid: i32,
-}
-
-extern "C" {
-    static mut firstTask: *mut Task;
-    static mut currentTask: *mut Task;
-    static mut TASK_LL_MODIFY: Spinlock;
-}
-
-const TASK_STATE_DEAD: i32 = 3;
-
 //
 // VFS
 //
 
-#[repr(C)]
-pub struct OpenFile {
-    pub flags: u32,
-    pub dir: *mut PtyPair,
-}
-
 #[repr(C)]
 pub struct VfsHandlers {
     pub open: Option<extern "C" fn(*mut u8, i32, i32, *mut OpenFile, *mut *mut u8) -> usize>,
@@ -136,46 +113,6 @@ extern "C" {
     fn fakefsFstat();
 }
 
-//
-// termios / winsize
-//
-
-#[repr(C)]
-pub struct winsize {
-    pub ws_row: u16,
-    pub ws_col: u16,
-    pub ws_xpixel: u16,
-    pub ws_ypixel: u16,
-}
-
-#[repr(C)]
-pub struct termios {
-    pub c_iflag: u32,
-    pub c_oflag: u32,
-    pub c_cflag: u32,
-    pub c_lflag: u32,
-    pub c_cc: [u8; 32],
-}
-
-//
-// Flags (subset)
-//
-
-const ICRNL: u32 = 0x100;
-const IXON: u32 = 0x400;
-const BRKINT: u32 = 0x2;
-const ISTRIP: u32 = 0x20;
-const INPCK: u32 = 0x10;
-
-const OPOST: u32 = 0x1;
-const ONLCR: u32 = 0x4;
-
-const ISIG: u32 = 0x1;
-const ICANON: u32 = 0x2;
-const ECHO: u32 = 0x8;
-const ECHOE: u32 = 0x10;
-const ECHOK: u32 = 0x20;
-
 //
 // Pty pair
 //
@@ -189,18 +126,25 @@ pub struct PtyPair {
 
     pub masterFds: usize,
     pub slaveFds: usize,
+    // slave has been opened at least once (EIO/HUP on the master after)
+    pub slaveOpened: bool,
 
+    // slave output, post-processed by the line discipline
     pub bufferMaster: *mut u8,
-    pub bufferSlave: *mut u8,
-
     pub ptrMaster: usize,
-    pub ptrSlave: usize,
 
-    pub term: termios,
-    pub win: winsize,
+    // line discipline of the slave side
+    pub tty: Tty,
+}
+
+#[inline]
+unsafe fn ptyFromTty(tty: *mut Tty) -> *mut PtyPair {
+    (*tty).ctx as *mut PtyPair
+}
 
-    pub ctrlSession: i32,
-    pub ctrlPgid: i32,
+#[inline]
+unsafe fn ptyFromFd(fd: *mut OpenFile) -> *mut PtyPair {
+    (*fd).dir as *mut PtyPair
 }
 
 //
@@ -238,6 +182,68 @@ unsafe fn ptyBitmapRemove(idx: i32) {
     spinlockRelease(&mut LOCK_PTY_GLOBAL);
 }
 
+extern "C" fn ptyIsAssigned(data: *mut u8, ctx: *mut u8) -> bool {
+    let pair = data as *mut PtyPair;
+    unsafe { (*pair).id as usize == ctx as usize }
+}
+
+extern "C" fn ptyIsPair(data: *mut u8, ctx: *mut u8) -> bool {
+    data == ctx
+}
+
+// LOCK_PTY_GLOBAL before LOCK_PTY: once unlinked under both, no ptsOpen()
+// can find the pair anymore and it's ours to free. Both ends closing at once
+// may get here twice, only the one still finding it linked goes on.
+unsafe fn ptyDestroyIfUnused(pair: *mut PtyPair) {
+    spinlockAcquire(&mut LOCK_PTY_GLOBAL);
+    if LinkedListSearch(&mut dsPtyPair, ptyIsPair, pair as *mut u8).is_null() {
+        spinlockRelease(&mut LOCK_PTY_GLOBAL);
+        return;
+    }
+    spinlockAcquire(&mut (*pair).LOCK_PTY);
+    if (*pair).masterFds != 0 || (*pair).slaveFds != 0 {
+        spinlockRelease(&mut (*pair).LOCK_PTY);
+        spinlockRelease(&mut LOCK_PTY_GLOBAL);
+        return;
+    }
+    LinkedListUnregister(&mut dsPtyPair, core::mem::size_of::<PtyPair>(), pair);
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+    spinlockRelease(&mut LOCK_PTY_GLOBAL);
+
+    free((*pair).bufferMaster);
+    ttyDestroy(&mut (*pair).tty);
+    ptyBitmapRemove((*pair).id);
+    free(pair as *mut u8);
+}
+
+//
+// Line discipline output (slave -> master)
+//
+
+unsafe extern "C" fn ptyOutput(tty: *mut Tty, buff: *const u8, len: usize) -> usize {
+    let pair = ptyFromTty(tty);
+
+    spinlockAcquire(&mut (*pair).LOCK_PTY);
+    let toCopy = min(len, PTY_BUFF_SIZE - (*pair).ptrMaster);
+    memcpy(
+        (*pair).bufferMaster.add((*pair).ptrMaster),
+        buff,
+        toCopy,
+    );
+    (*pair).ptrMaster += toCopy;
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+
+    if toCopy > 0 {
+        pollInstanceRing(pair as usize, EPOLLIN);
+    }
+    toCopy
+}
+
+unsafe extern "C" fn ptyOutputRoom(tty: *mut Tty) -> usize {
+    let pair = ptyFromTty(tty);
+    PTY_BUFF_SIZE - (*pair).ptrMaster
+}
+
 //
 // Init
 //
@@ -266,18 +272,152 @@ pub unsafe extern "C" fn ptmxOpen(
     (*pair).id = id;
     (*pair).masterFds = 1;
     (*pair).bufferMaster = malloc(PTY_BUFF_SIZE);
-    (*pair).bufferSlave = malloc(PTY_BUFF_SIZE);
-    (*pair).win.ws_row = 24;
-    (*pair).win.ws_col = 80;
-    (*fd).dir = pair;
+    ttyInit(
+        &mut (*pair).tty,
+        Some(ptyOutput),
+        Some(ptyOutputRoom),
+        pair as *mut core::ffi::c_void,
+    );
+    (*pair).tty.reopen = Some(ptsReopen);
+    (*fd).dir = pair as *mut core::ffi::c_void;
     spinlockRelease(&mut LOCK_PTY_GLOBAL);
     0
 }
 
+#[no_mangle]
+pub unsafe extern "C" fn ptmxRead(fd: *mut OpenFile, out: *mut u8, limit: usize) -> usize {
+    let pair = ptyFromFd(fd);
+    loop {
+        spinlockAcquire(&mut (*pair).LOCK_PTY);
+        if (*pair).ptrMaster > 0 {
+            break;
+        }
+        if (*pair).slaveOpened && (*pair).slaveFds == 0 {
+            spinlockRelease(&mut (*pair).LOCK_PTY);
+            return err(EIO);
+        }
+        spinlockRelease(&mut (*pair).LOCK_PTY);
+
+        if (*fd).flags & O_NONBLOCK != 0 {
+            return err(EWOULDBLOCK);
+        }
+        if signalsPendingQuick(currentTask) {
+            return err(EINTR);
+        }
+        handControl();
+    }
+
+    let toCopy = min(limit, (*pair).ptrMaster);
+    memcpy(out, (*pair).bufferMaster, toCopy);
+    memmove(
+        (*pair).bufferMaster,
+        (*pair).bufferMaster.add(toCopy),
+        PTY_BUFF_SIZE - toCopy,
+    );
+    (*pair).ptrMaster -= toCopy;
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+
+    // there's room for the slave to write again
+    pollInstanceRing((*pair).tty.pollKey, EPOLLOUT);
+    toCopy
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptmxWrite(fd: *mut OpenFile, input: *mut u8, limit: usize) -> usize {
+    let pair = ptyFromFd(fd);
+    let mut written = 0;
+
+    while written < limit {
+        let cnt = ttyReceive(&mut (*pair).tty, input.add(written), limit - written);
+        written += cnt;
+        if cnt > 0 {
+            continue;
+        }
+
+        // slave's read queue is full
+        if (*fd).flags & O_NONBLOCK != 0 {
+            return if written > 0 { written } else { err(EWOULDBLOCK) };
+        }
+        if signalsPendingQuick(currentTask) {
+            return if written > 0 { written } else { err(EINTR) };
+        }
+        handControl();
+    }
+
+    written
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptmxIoctl(fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    let pair = ptyFromFd(fd);
+    match request {
+        TIOCGPTN => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *(arg as *mut u32) = (*pair).id as u32;
+            0
+        }
+        TIOCSPTLCK => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            spinlockAcquire(&mut (*pair).LOCK_PTY);
+            (*pair).locked = *(arg as *mut i32) != 0;
+            spinlockRelease(&mut (*pair).LOCK_PTY);
+            0
+        }
+        FIONREAD => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            spinlockAcquire(&mut (*pair).LOCK_PTY);
+            *(arg as *mut i32) = (*pair).ptrMaster as i32;
+            spinlockRelease(&mut (*pair).LOCK_PTY);
+            0
+        }
+        // terminal emulators ask about the slave's foreground group without
+        // being in its session
+        TIOCGPGRP => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *(arg as *mut i32) = (*pair).tty.ctrlPgid;
+            0
+        }
+        _ => ttyIoctl(&mut (*pair).tty, request, arg),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptmxInternalPoll(fd: *mut OpenFile, events: i32) -> i32 {
+    let pair = ptyFromFd(fd);
+    let mut revents = 0;
+
+    spinlockAcquire(&mut (*pair).LOCK_PTY);
+    if events & EPOLLIN != 0 && (*pair).ptrMaster > 0 {
+        revents |= EPOLLIN;
+    }
+    if (*pair).slaveOpened && (*pair).slaveFds == 0 {
+        revents |= EPOLLHUP;
+    }
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+
+    if events & EPOLLOUT != 0 && ttyReceiveRoom(&mut (*pair).tty) > 0 {
+        revents |= EPOLLOUT;
+    }
+    revents
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptmxReportKey(fd: *mut OpenFile) -> usize {
+    (*fd).dir as usize
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn ptmxDuplicate(orig: *mut OpenFile, new: *mut OpenFile) -> bool {
     (*new).dir = (*orig).dir;
-    let pair = (*orig).dir;
+    let pair = ptyFromFd(orig);
     spinlockAcquire(&mut (*pair).LOCK_PTY);
     (*pair).masterFds += 1;
     spinlockRelease(&mut (*pair).LOCK_PTY);
@@ -286,20 +426,182 @@ pub unsafe extern "C" fn ptmxDuplicate(orig: *mut OpenFile, new: *mut OpenFile)
 
 #[no_mangle]
 pub unsafe extern "C" fn ptmxClose(fd: *mut OpenFile) -> bool {
-    let pair = (*fd).dir;
+    let pair = ptyFromFd(fd);
     spinlockAcquire(&mut (*pair).LOCK_PTY);
     (*pair).masterFds -= 1;
-    if (*pair).masterFds == 0 && (*pair).slaveFds == 0 {
-        spinlockRelease(&mut (*pair).LOCK_PTY);
-        free((*pair).bufferMaster);
-        free((*pair).bufferSlave);
-        ptyBitmapRemove((*pair).id);
-        spinlockAcquire(&mut LOCK_PTY_GLOBAL);
-        LinkedListRemove(&mut dsPtyPair, core::mem::size_of::<PtyPair>(), pair);
+    let hangup = (*pair).masterFds == 0;
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+
+    if hangup {
+        // signals the session and wakes up slave readers
+        ttyHangup(&mut (*pair).tty);
+    }
+    ptyDestroyIfUnused(pair);
+    true
+}
+
+//
+// /dev/pts/N handlers
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn ptsOpen(
+    filename: *mut u8,
+    flags: i32,
+    _mode: i32,
+    fd: *mut OpenFile,
+    _sym: *mut *mut u8,
+) -> usize {
+    // filename is in the form of /pts/N
+    let mut ptr = filename;
+    while *ptr != 0 && !(*ptr).is_ascii_digit() {
+        ptr = ptr.add(1);
+    }
+    if *ptr == 0 {
+        return err(ENOENT);
+    }
+    let mut number: usize = 0;
+    while (*ptr).is_ascii_digit() {
+        number = number * 10 + (*ptr - b'0') as usize;
+        ptr = ptr.add(1);
+    }
+
+    spinlockAcquire(&mut LOCK_PTY_GLOBAL);
+    let pair = LinkedListSearch(&mut dsPtyPair, ptyIsAssigned, number as *mut u8);
+    if pair.is_null() {
         spinlockRelease(&mut LOCK_PTY_GLOBAL);
-    } else {
+        return err(ENOENT);
+    }
+    // still under the global lock, so ptyDestroyIfUnused() can't free it
+    spinlockAcquire(&mut (*pair).LOCK_PTY);
+    spinlockRelease(&mut LOCK_PTY_GLOBAL);
+    if (*pair).locked || (*pair).masterFds == 0 {
+        spinlockRelease(&mut (*pair).LOCK_PTY);
+        return err(EIO);
+    }
+    (*pair).slaveFds += 1;
+    (*pair).slaveOpened = true;
+    (*fd).dir = pair as *mut core::ffi::c_void;
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+
+    ttyOpened(&mut (*pair).tty, flags as u32);
+    0
+}
+
+// /dev/tty, when the slave is the caller's controlling terminal
+#[no_mangle]
+pub unsafe extern "C" fn ptsReopen(tty: *mut Tty, fd: *mut OpenFile) -> usize {
+    let pair = (*tty).ctx as *mut PtyPair;
+    spinlockAcquire(&mut (*pair).LOCK_PTY);
+    if (*pair).masterFds == 0 {
         spinlockRelease(&mut (*pair).LOCK_PTY);
+        return err(EIO);
     }
+    (*pair).slaveFds += 1;
+    (*fd).dir = pair as *mut core::ffi::c_void;
+    (*fd).handlers = &handlePts as *const VfsHandlers as *const core::ffi::c_void;
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptsRead(fd: *mut OpenFile, out: *mut u8, limit: usize) -> usize {
+    let pair = ptyFromFd(fd);
+    if (*pair).masterFds == 0 && ttyReadable(&mut (*pair).tty) == 0 {
+        return 0;
+    }
+    let ret = ttyRead(&mut (*pair).tty, fd, out, limit);
+
+    // there's room for the master to write again
+    if (ret as isize) > 0 {
+        pollInstanceRing(pair as usize, EPOLLOUT);
+    }
+    ret
+}
+
+// Pushes slave output through the line discipline, without blocking
+#[no_mangle]
+pub unsafe extern "C" fn ptsWriteInner(pair: *mut PtyPair, input: *mut u8, limit: usize) -> usize {
+    ttyWrite(&mut (*pair).tty, input, limit)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptsWrite(fd: *mut OpenFile, input: *mut u8, limit: usize) -> usize {
+    let pair = ptyFromFd(fd);
+    let mut written = 0;
+
+    while written < limit {
+        if (*pair).masterFds == 0 {
+            return if written > 0 { written } else { err(EIO) };
+        }
+
+        // background writer with TOSTOP, see ttyJobCheck()
+        let cnt = ptsWriteInner(pair, input.add(written), limit - written);
+        if RET_IS_ERR(cnt) {
+            return if written > 0 { written } else { cnt };
+        }
+        written += cnt;
+        if cnt > 0 {
+            continue;
+        }
+
+        // master buffer is full
+        if (*fd).flags & O_NONBLOCK != 0 {
+            return if written > 0 { written } else { err(EWOULDBLOCK) };
+        }
+        if signalsPendingQuick(currentTask) {
+            return if written > 0 { written } else { err(EINTR) };
+        }
+        handControl();
+    }
+
+    written
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptsIoctl(fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    let pair = ptyFromFd(fd);
+    ttyIoctl(&mut (*pair).tty, request, arg)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptsInternalPoll(fd: *mut OpenFile, events: i32) -> i32 {
+    let pair = ptyFromFd(fd);
+    let mut revents = ttyPoll(&mut (*pair).tty, events);
+    if (*pair).masterFds == 0 {
+        revents |= EPOLLHUP;
+    }
+    revents
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptsReportKey(fd: *mut OpenFile) -> usize {
+    let pair = ptyFromFd(fd);
+    (*pair).tty.pollKey
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptsDuplicate(orig: *mut OpenFile, new: *mut OpenFile) -> bool {
+    (*new).dir = (*orig).dir;
+    let pair = ptyFromFd(orig);
+    spinlockAcquire(&mut (*pair).LOCK_PTY);
+    (*pair).slaveFds += 1;
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ptsClose(fd: *mut OpenFile) -> bool {
+    let pair = ptyFromFd(fd);
+    spinlockAcquire(&mut (*pair).LOCK_PTY);
+    (*pair).slaveFds -= 1;
+    let hangup = (*pair).slaveFds == 0;
+    spinlockRelease(&mut (*pair).LOCK_PTY);
+
+    if hangup {
+        pollInstanceRing(pair as usize, EPOLLIN | EPOLLHUP);
+    }
+    ptyDestroyIfUnused(pair);
     true
 }
 
@@ -312,10 +614,23 @@ pub static handlePtmx: VfsHandlers = VfsHandlers {
     open: Some(ptmxOpen),
     duplicate: Some(ptmxDuplicate),
     close: Some(ptmxClose),
-    read: None,
-    write: None,
-    internalPoll: None,
-    ioctl: None,
-    reportKey: None,
-    stat: None,
+    read: Some(ptmxRead),
+    write: Some(ptmxWrite),
+    internalPoll: Some(ptmxInternalPoll),
+    ioctl: Some(ptmxIoctl),
+    reportKey: Some(ptmxReportKey),
+    stat: Some(fakefsFstat),
+};
+
+#[no_mangle]
+pub static handlePts: VfsHandlers = VfsHandlers {
+    open: Some(ptsOpen),
+    duplicate: Some(ptsDuplicate),
+    close: Some(ptsClose),
+    read: Some(ptsRead),
+    write: Some(ptsWrite),
+    internalPoll: Some(ptsInternalPoll),
+    ioctl: Some(ptsIoctl),
+    reportKey: Some(ptsReportKey),
+    stat: Some(fakefsFstat),
 };
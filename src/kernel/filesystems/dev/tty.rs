@@ -0,0 +1,1308 @@
+#![no_std]
+
+use core::cmp::min;
+use core::ffi::c_void;
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+//
+// N_TTY line discipline, shared by PTYs and the console (/dev/tty*)
+//
+
+//
+// Constants
+//
+
+pub const TTY_BUFF_SIZE: usize = 4096;
+pub const TTY_LINE_MAX: usize = 4095;
+
+const O_NOCTTY: u32 = 0x100;
+const O_NONBLOCK: u32 = 0x800;
+
+const EPOLLIN: i32 = 0x001;
+const EPOLLOUT: i32 = 0x004;
+const EPOLLHUP: i32 = 0x010;
+
+const EWOULDBLOCK: isize = 11;
+const EINTR: isize = 4;
+const EIO: isize = 5;
+const EPERM: isize = 1;
+const EINVAL: isize = 22;
+const EFAULT: isize = 14;
+const ENOTTY: isize = 25;
+
+const SIGINT: usize = 2;
+const SIGQUIT: usize = 3;
+const SIGTSTP: usize = 20;
+const SIGTTIN: usize = 21;
+const SIGTTOU: usize = 22;
+const SIGWINCH: usize = 28;
+
+
+#[inline]
+const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+//
+// ioctl requests
+//
+
+pub const TCGETS: u64 = 0x5401;
+pub const TCSETS: u64 = 0x5402;
+pub const TCSETSW: u64 = 0x5403;
+pub const TCSETSF: u64 = 0x5404;
+pub const TCFLSH: u64 = 0x540B;
+pub const TIOCSCTTY: u64 = 0x540E;
+pub const TIOCGPGRP: u64 = 0x540F;
+pub const TIOCSPGRP: u64 = 0x5410;
+pub const TIOCGWINSZ: u64 = 0x5413;
+pub const TIOCSWINSZ: u64 = 0x5414;
+pub const FIONREAD: u64 = 0x541B;
+pub const TIOCINQ: u64 = FIONREAD;
+pub const TIOCNOTTY: u64 = 0x5422;
+pub const TIOCGSID: u64 = 0x5429;
+
+const TCIFLUSH: usize = 0;
+const TCOFLUSH: usize = 1;
+const TCIOFLUSH: usize = 2;
+
+//
+// termios flags
+//
+
+// c_iflag
+pub const IGNBRK: u32 = 0o000001;
+pub const BRKINT: u32 = 0o000002;
+pub const ISTRIP: u32 = 0o000040;
+pub const INLCR: u32 = 0o000100;
+pub const IGNCR: u32 = 0o000200;
+pub const ICRNL: u32 = 0o000400;
+pub const IUCLC: u32 = 0o001000;
+pub const IXON: u32 = 0o002000;
+pub const IMAXBEL: u32 = 0o020000;
+pub const IUTF8: u32 = 0o040000;
+
+// c_oflag
+pub const OPOST: u32 = 0o000001;
+pub const OLCUC: u32 = 0o000002;
+pub const ONLCR: u32 = 0o000004;
+pub const OCRNL: u32 = 0o000010;
+pub const ONOCR: u32 = 0o000020;
+pub const ONLRET: u32 = 0o000040;
+pub const TABDLY: u32 = 0o014000;
+pub const XTABS: u32 = 0o014000;
+
+// c_cflag
+pub const B38400: u32 = 0o000017;
+pub const CS8: u32 = 0o000060;
+pub const CREAD: u32 = 0o000200;
+pub const HUPCL: u32 = 0o002000;
+
+// c_lflag
+pub const ISIG: u32 = 0o000001;
+pub const ICANON: u32 = 0o000002;
+pub const ECHO: u32 = 0o000010;
+pub const ECHOE: u32 = 0o000020;
+pub const ECHOK: u32 = 0o000040;
+pub const ECHONL: u32 = 0o000100;
+pub const NOFLSH: u32 = 0o000200;
+pub const TOSTOP: u32 = 0o000400;
+pub const ECHOCTL: u32 = 0o001000;
+pub const ECHOPRT: u32 = 0o002000;
+pub const ECHOKE: u32 = 0o004000;
+pub const IEXTEN: u32 = 0o100000;
+
+// c_cc
+pub const VINTR: usize = 0;
+pub const VQUIT: usize = 1;
+pub const VERASE: usize = 2;
+pub const VKILL: usize = 3;
+pub const VEOF: usize = 4;
+pub const VTIME: usize = 5;
+pub const VMIN: usize = 6;
+pub const VSTART: usize = 8;
+pub const VSTOP: usize = 9;
+pub const VSUSP: usize = 10;
+pub const VEOL: usize = 11;
+pub const VREPRINT: usize = 12;
+pub const VDISCARD: usize = 13;
+pub const VWERASE: usize = 14;
+pub const VLNEXT: usize = 15;
+pub const VEOL2: usize = 16;
+
+// disabled c_cc slot
+const DISABLED_CHAR: u8 = 0;
+
+//
+// Kernel primitives
+//
+
+#[repr(C)]
+pub struct Spinlock {
+    _priv: u32,
+}
+
+#[repr(C)]
+pub struct SpinlockCnt {
+    _priv: u32,
+}
+
+extern "C" {
+    fn spinlockAcquire(lock: *mut Spinlock);
+    fn spinlockRelease(lock: *mut Spinlock);
+}
+
+//
+// External kernel APIs
+//
+
+extern "C" {
+    static mut timerTicks: u64;
+
+    fn calloc(n: usize, size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    fn atomicBitmapClear(bitmap: *mut u64, bit: usize);
+
+    fn handControl();
+    fn signalsPendingQuick(task: *mut Task) -> bool;
+
+    fn pollInstanceRing(key: usize, events: i32);
+}
+
+//
+// Tasks
+//
+
+#[repr(C)]
+pub struct Task {
+    pub id: u64,
+    pub pgid: i32,
+    pub tgid: i32,
+    pub sid: i32,
+    pub kernel_task: bool,
+    pub state: u8,
+
+    pub waitingForPid: u64,
+
+    // controlling terminal (Tty*), shared by the whole session
+    pub ctrlTty: *mut c_void,
+    // pending wait4() reports, see JOBCTL_*
+    pub jobctl: u32,
+    // what taskContinue() puts it back to, see TASK_STATE_STOPPED
+    pub stateBeforeStop: u8,
+
+    pub sigBlockList: u64,
+    pub sigPendingList: u64,
+    pub infoSignals: *mut c_void,
+
+    pub parent: *mut Task,
+    pub next: *mut Task,
+}
+
+extern "C" {
+    static mut currentTask: *mut Task;
+}
+
+// multitasking/jobctl.rs
+extern "C" {
+    fn pgrpSignal(pgid: i32, signal: usize);
+    fn pgrpExists(pgid: i32, sid: i32) -> bool;
+    fn pgrpIsOrphaned(pgid: i32) -> bool;
+    fn taskSignalIgnored(task: *mut Task, signal: usize) -> bool;
+    fn taskSignalCaught(task: *mut Task, signal: usize) -> bool;
+    fn taskStop(task: *mut Task, signal: usize);
+    fn sessionSetCtty(task: *mut Task, tty: *mut Tty, steal: bool) -> usize;
+    fn sessionDetachCtty(task: *mut Task);
+    fn sessionHangup(tty: *mut Tty);
+}
+
+//
+// VFS
+//
+
+#[repr(C)]
+pub struct OpenFile {
+    pub flags: u32,
+    pub dir: *mut c_void,
+    pub handlers: *const c_void,
+}
+
+//
+// termios / winsize
+//
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct winsize {
+    pub ws_row: u16,
+    pub ws_col: u16,
+    pub ws_xpixel: u16,
+    pub ws_ypixel: u16,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct termios {
+    pub c_iflag: u32,
+    pub c_oflag: u32,
+    pub c_cflag: u32,
+    pub c_lflag: u32,
+    pub c_line: u8,
+    pub c_cc: [u8; 32],
+}
+
+//
+// Tty instance
+//
+
+// Pushes already post-processed bytes to whatever is behind the tty (the
+// framebuffer console, a pty master buffer...). Returns how much was taken.
+pub type TtyOutput = unsafe extern "C" fn(tty: *mut Tty, buff: *const u8, len: usize) -> usize;
+// How many bytes the output side can currently take without blocking.
+pub type TtyOutputRoom = unsafe extern "C" fn(tty: *mut Tty) -> usize;
+// Opens another handle to the device behind the tty, for /dev/tty.
+pub type TtyReopen = unsafe extern "C" fn(tty: *mut Tty, fd: *mut OpenFile) -> usize;
+
+#[repr(C)]
+pub struct Tty {
+    pub LOCK_TTY: Spinlock,
+
+    pub term: termios,
+    pub win: winsize,
+
+    // cooked input ready for read(), with bitmaps marking line ends and
+    // the zero-length lines a lone VEOF leaves behind
+    pub readBuff: *mut u8,
+    pub readDelim: *mut u8,
+    pub readEof: *mut u8,
+    pub readHead: usize,
+    pub readTail: usize,
+    pub readLines: usize,
+    pub lastInput: u64,
+
+    // the line currently being edited (ICANON)
+    pub lineBuff: *mut u8,
+    pub lineLen: usize,
+    pub lnext: bool,
+
+    // output column, for tab expansion and echo erasing
+    pub column: usize,
+
+    // session this is the controlling terminal of, and its foreground group
+    pub ctrlSession: i32,
+    pub ctrlPgid: i32,
+
+    pub output: Option<TtyOutput>,
+    pub outputRoom: Option<TtyOutputRoom>,
+    pub reopen: Option<TtyReopen>,
+    pub ctx: *mut c_void,
+
+    // key used for waking up pollers of the reading side
+    pub pollKey: usize,
+}
+
+//
+// Defaults
+//
+
+const TTY_DEFAULT_CC: [u8; 17] = [
+    0o003, // VINTR    ^C
+    0o034, // VQUIT    ^\
+    0o177, // VERASE   DEL
+    0o025, // VKILL    ^U
+    0o004, // VEOF     ^D
+    0,     // VTIME
+    1,     // VMIN
+    0,     // VSWTC
+    0o021, // VSTART   ^Q
+    0o023, // VSTOP    ^S
+    0o032, // VSUSP    ^Z
+    0,     // VEOL
+    0o022, // VREPRINT ^R
+    0o017, // VDISCARD ^O
+    0o027, // VWERASE  ^W
+    0o026, // VLNEXT   ^V
+    0,     // VEOL2
+];
+
+#[no_mangle]
+pub unsafe extern "C" fn ttyTermiosDefaults(term: *mut termios) {
+    *term = core::mem::zeroed();
+    (*term).c_iflag = ICRNL | IXON;
+    (*term).c_oflag = OPOST | ONLCR;
+    (*term).c_cflag = B38400 | CS8 | CREAD | HUPCL;
+    (*term).c_lflag = ISIG | ICANON | ECHO | ECHOE | ECHOK | ECHOCTL | ECHOKE | IEXTEN;
+    (*term).c_cc[..TTY_DEFAULT_CC.len()].copy_from_slice(&TTY_DEFAULT_CC);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ttyInit(
+    tty: *mut Tty,
+    output: Option<TtyOutput>,
+    outputRoom: Option<TtyOutputRoom>,
+    ctx: *mut c_void,
+) {
+    ttyTermiosDefaults(&mut (*tty).term);
+    (*tty).win.ws_row = 24;
+    (*tty).win.ws_col = 80;
+
+    (*tty).readBuff = calloc(TTY_BUFF_SIZE, 1);
+    (*tty).readDelim = calloc(TTY_BUFF_SIZE / 8, 1);
+    (*tty).readEof = calloc(TTY_BUFF_SIZE / 8, 1);
+    (*tty).readHead = 0;
+    (*tty).readTail = 0;
+    (*tty).readLines = 0;
+
+    (*tty).lineBuff = calloc(TTY_LINE_MAX, 1);
+    (*tty).lineLen = 0;
+    (*tty).lnext = false;
+    (*tty).column = 0;
+
+    (*tty).ctrlSession = 0;
+    (*tty).ctrlPgid = 0;
+
+    (*tty).output = output;
+    (*tty).outputRoom = outputRoom;
+    (*tty).reopen = None;
+    (*tty).ctx = ctx;
+    (*tty).pollKey = tty as usize;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ttyDestroy(tty: *mut Tty) {
+    free((*tty).readBuff);
+    free((*tty).readDelim);
+    free((*tty).readEof);
+    free((*tty).lineBuff);
+    (*tty).readBuff = null_mut();
+    (*tty).readDelim = null_mut();
+    (*tty).readEof = null_mut();
+    (*tty).lineBuff = null_mut();
+}
+
+//
+// Read queue helpers (call with LOCK_TTY held)
+//
+
+#[inline]
+unsafe fn readQueued(tty: *mut Tty) -> usize {
+    ((*tty).readHead + TTY_BUFF_SIZE - (*tty).readTail) % TTY_BUFF_SIZE
+}
+
+#[inline]
+unsafe fn readRoom(tty: *mut Tty) -> usize {
+    TTY_BUFF_SIZE - 1 - readQueued(tty)
+}
+
+#[inline]
+unsafe fn bitGet(map: *mut u8, idx: usize) -> bool {
+    *map.add(idx / 8) & (1 << (idx % 8)) != 0
+}
+
+#[inline]
+unsafe fn bitSet(map: *mut u8, idx: usize, val: bool) {
+    let byte = map.add(idx / 8);
+    if val {
+        *byte |= 1 << (idx % 8);
+    } else {
+        *byte &= !(1 << (idx % 8));
+    }
+}
+
+#[inline]
+unsafe fn delimGet(tty: *mut Tty, idx: usize) -> bool {
+    bitGet((*tty).readDelim, idx)
+}
+
+#[inline]
+unsafe fn delimSet(tty: *mut Tty, idx: usize, val: bool) {
+    bitSet((*tty).readDelim, idx, val)
+}
+
+#[inline]
+unsafe fn eofGet(tty: *mut Tty, idx: usize) -> bool {
+    bitGet((*tty).readEof, idx)
+}
+
+unsafe fn readPush(tty: *mut Tty, c: u8, delim: bool) -> bool {
+    if readRoom(tty) == 0 {
+        return false;
+    }
+    let head = (*tty).readHead;
+    *(*tty).readBuff.add(head) = c;
+    delimSet(tty, head, delim);
+    bitSet((*tty).readEof, head, false);
+    if delim {
+        (*tty).readLines += 1;
+    }
+    (*tty).readHead = (head + 1) % TTY_BUFF_SIZE;
+    true
+}
+
+// A lone VEOF: an empty line so read() returns 0. The slot's byte is never
+// handed out, so it can't be confused with a NUL that ended a line.
+unsafe fn readPushEof(tty: *mut Tty) -> bool {
+    let head = (*tty).readHead;
+    if !readPush(tty, 0, true) {
+        return false;
+    }
+    bitSet((*tty).readEof, head, true);
+    true
+}
+
+unsafe fn readFlush(tty: *mut Tty) {
+    (*tty).readHead = 0;
+    (*tty).readTail = 0;
+    (*tty).readLines = 0;
+    (*tty).lineLen = 0;
+    (*tty).lnext = false;
+}
+
+// Bytes a read() would be able to return right now
+unsafe fn readAvailable(tty: *mut Tty) -> usize {
+    if (*tty).term.c_lflag & ICANON == 0 {
+        return readQueued(tty);
+    }
+    if (*tty).readLines == 0 {
+        return 0;
+    }
+
+    // only complete lines count, and EOF markers are never handed out
+    let mut cnt = 0;
+    let mut idx = (*tty).readTail;
+    let mut pending = 0;
+    while idx != (*tty).readHead {
+        let delim = delimGet(tty, idx);
+        if !eofGet(tty, idx) {
+            pending += 1;
+        }
+        if delim {
+            cnt += pending;
+            pending = 0;
+        }
+        idx = (idx + 1) % TTY_BUFF_SIZE;
+    }
+    cnt
+}
+
+//
+// Signals
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn ttySignal(tty: *mut Tty, signal: usize) {
+    pgrpSignal((*tty).ctrlPgid, signal);
+}
+
+//
+// Job control
+//
+
+unsafe fn ttyIsCtty(tty: *mut Tty) -> bool {
+    (*currentTask).ctrlTty == tty as *mut c_void
+}
+
+// Background process groups touching their controlling terminal get stopped
+// with SIGTTIN (reads) or SIGTTOU (writes with TOSTOP, settings changes). The
+// caller is stopped right here, and checked again once it's continued, so
+// the access goes through if it got moved to the foreground meanwhile.
+unsafe fn ttyJobCheck(tty: *mut Tty, signal: usize) -> usize {
+    let task = currentTask;
+    loop {
+        if !ttyIsCtty(tty) || (*tty).ctrlPgid <= 0 || (*task).pgid == (*tty).ctrlPgid {
+            return 0;
+        }
+
+        if taskSignalIgnored(task, signal) {
+            return if signal == SIGTTIN { err(EIO) } else { 0 };
+        }
+        if pgrpIsOrphaned((*task).pgid) {
+            return err(EIO);
+        }
+
+        pgrpSignal((*task).pgid, signal);
+        if taskSignalCaught(task, signal) {
+            // nothing to restart the syscall after the handler
+            return err(EINTR);
+        }
+
+        // take the stop now instead of on the way out
+        atomicBitmapClear(&mut (*task).sigPendingList, signal);
+        taskStop(task, signal);
+        handControl();
+
+        // SIGKILL, or something else arrived while we were stopped
+        if signalsPendingQuick(task) {
+            return err(EINTR);
+        }
+    }
+}
+
+// To be called by every open of the device: a session leader without a
+// controlling terminal acquires it, unless O_NOCTTY was given
+#[no_mangle]
+pub unsafe extern "C" fn ttyOpened(tty: *mut Tty, flags: u32) {
+    let task = currentTask;
+    if flags & O_NOCTTY != 0
+        || (*task).kernel_task
+        || (*task).sid != (*task).tgid
+        || !(*task).ctrlTty.is_null()
+        || (*tty).ctrlSession != 0
+    {
+        return;
+    }
+    sessionSetCtty(task, tty, false);
+}
+
+// /dev/tty
+#[no_mangle]
+pub unsafe extern "C" fn ttyReopen(tty: *mut Tty, fd: *mut OpenFile) -> usize {
+    match (*tty).reopen {
+        Some(reopen) => reopen(tty, fd),
+        None => err(EIO),
+    }
+}
+
+// The other side is gone (pty master closed)
+#[no_mangle]
+pub unsafe extern "C" fn ttyHangup(tty: *mut Tty) {
+    sessionHangup(tty);
+    pollInstanceRing((*tty).pollKey, EPOLLIN | EPOLLHUP);
+}
+
+//
+// Output processing
+//
+
+// Expands a single character as per c_oflag into out (max 8 bytes)
+unsafe fn outputExpand(tty: *mut Tty, c: u8, out: &mut [u8; 8]) -> usize {
+    let oflag = (*tty).term.c_oflag;
+    if oflag & OPOST == 0 {
+        out[0] = c;
+        return 1;
+    }
+
+    match c {
+        b'\n' => {
+            if oflag & ONLCR != 0 {
+                out[0] = b'\r';
+                out[1] = b'\n';
+                return 2;
+            }
+            out[0] = b'\n';
+            1
+        }
+        b'\r' => {
+            if oflag & ONOCR != 0 && (*tty).column == 0 {
+                return 0;
+            }
+            out[0] = if oflag & OCRNL != 0 { b'\n' } else { b'\r' };
+            1
+        }
+        b'\t' => {
+            if oflag & TABDLY == XTABS {
+                let spaces = 8 - ((*tty).column & 7);
+                for i in 0..spaces {
+                    out[i] = b' ';
+                }
+                return spaces;
+            }
+            out[0] = b'\t';
+            1
+        }
+        _ => {
+            out[0] = if oflag & OLCUC != 0 && c.is_ascii_lowercase() {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            };
+            1
+        }
+    }
+}
+
+unsafe fn outputColumn(tty: *mut Tty, out: &[u8]) {
+    let oflag = (*tty).term.c_oflag;
+    for &c in out {
+        match c {
+            b'\r' => (*tty).column = 0,
+            b'\n' => {
+                if oflag & (ONLCR | ONLRET) != 0 {
+                    (*tty).column = 0;
+                }
+            }
+            b'\t' => (*tty).column = ((*tty).column | 7) + 1,
+            0x08 => {
+                if (*tty).column > 0 {
+                    (*tty).column -= 1;
+                }
+            }
+            _ => {
+                if c >= 0x20 && c != 0x7f {
+                    (*tty).column += 1;
+                }
+            }
+        }
+    }
+}
+
+unsafe fn outputRaw(tty: *mut Tty, buff: *const u8, len: usize) -> usize {
+    match (*tty).output {
+        Some(output) => output(tty, buff, len),
+        None => len,
+    }
+}
+
+unsafe fn outputRoomGet(tty: *mut Tty) -> usize {
+    match (*tty).outputRoom {
+        Some(room) => room(tty),
+        None => usize::MAX,
+    }
+}
+
+// Post-processes and pushes one character, used by echoing
+unsafe fn outputChar(tty: *mut Tty, c: u8) {
+    let mut out = [0u8; 8];
+    let len = outputExpand(tty, c, &mut out);
+    if len == 0 {
+        return;
+    }
+    outputColumn(tty, &out[..len]);
+    outputRaw(tty, out.as_ptr(), len);
+}
+
+unsafe fn outputStr(tty: *mut Tty, s: &[u8]) {
+    for &c in s {
+        outputChar(tty, c);
+    }
+}
+
+//
+// Echoing
+//
+
+#[inline]
+fn isCtl(c: u8) -> bool {
+    (c < 0x20 || c == 0x7f) && c != b'\t' && c != b'\n'
+}
+
+unsafe fn echoChar(tty: *mut Tty, c: u8) {
+    let lflag = (*tty).term.c_lflag;
+    if lflag & ECHO == 0 {
+        return;
+    }
+
+    if lflag & ECHOCTL != 0 && isCtl(c) {
+        outputChar(tty, b'^');
+        outputChar(tty, c ^ 0x40);
+    } else {
+        outputChar(tty, c);
+    }
+}
+
+// How many columns a character took when it got echoed
+unsafe fn echoWidth(tty: *mut Tty, c: u8) -> usize {
+    // tabs are treated as a single cell, good enough for shells
+    if (*tty).term.c_lflag & ECHOCTL != 0 && isCtl(c) {
+        2
+    } else {
+        1
+    }
+}
+
+unsafe fn eraseOne(tty: *mut Tty) -> bool {
+    if (*tty).lineLen == 0 {
+        return false;
+    }
+
+    (*tty).lineLen -= 1;
+    let c = *(*tty).lineBuff.add((*tty).lineLen);
+
+    let lflag = (*tty).term.c_lflag;
+    if lflag & ECHO != 0 {
+        if lflag & ECHOE != 0 {
+            for _ in 0..echoWidth(tty, c) {
+                outputStr(tty, b"\x08 \x08");
+            }
+        } else {
+            echoChar(tty, (*tty).term.c_cc[VERASE]);
+        }
+    }
+    true
+}
+
+unsafe fn eraseWord(tty: *mut Tty) {
+    // trailing whitespace first, then the word itself
+    while (*tty).lineLen > 0 {
+        let c = *(*tty).lineBuff.add((*tty).lineLen - 1);
+        if c != b' ' && c != b'\t' {
+            break;
+        }
+        eraseOne(tty);
+    }
+    while (*tty).lineLen > 0 {
+        let c = *(*tty).lineBuff.add((*tty).lineLen - 1);
+        if c == b' ' || c == b'\t' {
+            break;
+        }
+        eraseOne(tty);
+    }
+}
+
+unsafe fn eraseLine(tty: *mut Tty) {
+    let lflag = (*tty).term.c_lflag;
+    if lflag & ECHO == 0 {
+        (*tty).lineLen = 0;
+        return;
+    }
+
+    if lflag & ECHOKE != 0 && lflag & ECHOE != 0 {
+        while eraseOne(tty) {}
+        return;
+    }
+
+    (*tty).lineLen = 0;
+    echoChar(tty, (*tty).term.c_cc[VKILL]);
+    if lflag & ECHOK != 0 {
+        outputChar(tty, b'\n');
+    }
+}
+
+unsafe fn reprintLine(tty: *mut Tty) {
+    echoChar(tty, (*tty).term.c_cc[VREPRINT]);
+    outputChar(tty, b'\n');
+    for i in 0..(*tty).lineLen {
+        echoChar(tty, *(*tty).lineBuff.add(i));
+    }
+}
+
+// Moves the line being edited into the read queue, all of it or (when the
+// queue can't take it yet) none of it
+unsafe fn lineCommit(tty: *mut Tty, terminator: Option<u8>) -> bool {
+    let len = (*tty).lineLen;
+    let slots = if terminator.is_some() || len == 0 { len + 1 } else { len };
+    if readRoom(tty) < slots {
+        return false;
+    }
+
+    for i in 0..len {
+        let last = i == len - 1 && terminator.is_none();
+        readPush(tty, *(*tty).lineBuff.add(i), last);
+    }
+
+    match terminator {
+        Some(c) => {
+            readPush(tty, c, true);
+        }
+        None => {
+            if len == 0 {
+                readPushEof(tty);
+            }
+        }
+    }
+
+    (*tty).lineLen = 0;
+    true
+}
+
+//
+// Input processing
+//
+
+#[inline]
+unsafe fn ccIs(tty: *mut Tty, idx: usize, c: u8) -> bool {
+    let cc = (*tty).term.c_cc[idx];
+    cc != DISABLED_CHAR && cc == c
+}
+
+// Returns true if the character was consumed as a signal
+unsafe fn receiveSignal(tty: *mut Tty, c: u8) -> bool {
+    let lflag = (*tty).term.c_lflag;
+    if lflag & ISIG == 0 {
+        return false;
+    }
+
+    let signal = if ccIs(tty, VINTR, c) {
+        SIGINT
+    } else if ccIs(tty, VQUIT, c) {
+        SIGQUIT
+    } else if ccIs(tty, VSUSP, c) {
+        SIGTSTP
+    } else {
+        return false;
+    };
+
+    if lflag & NOFLSH == 0 {
+        readFlush(tty);
+    }
+    echoChar(tty, c);
+    ttySignal(tty, signal);
+    true
+}
+
+// Returns false if the read queue is full and the character wasn't taken
+unsafe fn receiveChar(tty: *mut Tty, mut c: u8) -> bool {
+    let iflag = (*tty).term.c_iflag;
+    let lflag = (*tty).term.c_lflag;
+
+    if iflag & ISTRIP != 0 {
+        c &= 0x7f;
+    }
+
+    if (*tty).lnext {
+        (*tty).lnext = false;
+    } else {
+        if lflag & ICANON != 0 && lflag & IEXTEN != 0 && ccIs(tty, VLNEXT, c) {
+            (*tty).lnext = true;
+            if lflag & ECHO != 0 && lflag & ECHOCTL != 0 {
+                outputStr(tty, b"^\x08");
+            }
+            return true;
+        }
+
+        if receiveSignal(tty, c) {
+            return true;
+        }
+
+        if c == b'\r' {
+            if iflag & IGNCR != 0 {
+                return true;
+            }
+            if iflag & ICRNL != 0 {
+                c = b'\n';
+            }
+        } else if c == b'\n' && iflag & INLCR != 0 {
+            c = b'\r';
+        }
+
+        if iflag & IUCLC != 0 && lflag & IEXTEN != 0 && c.is_ascii_uppercase() {
+            c = c.to_ascii_lowercase();
+        }
+
+        if lflag & ICANON != 0 {
+            if ccIs(tty, VERASE, c) {
+                eraseOne(tty);
+                return true;
+            }
+            if ccIs(tty, VKILL, c) {
+                eraseLine(tty);
+                return true;
+            }
+            if lflag & IEXTEN != 0 && ccIs(tty, VWERASE, c) {
+                eraseWord(tty);
+                return true;
+            }
+            if lflag & IEXTEN != 0 && ccIs(tty, VREPRINT, c) {
+                reprintLine(tty);
+                return true;
+            }
+            if ccIs(tty, VEOF, c) {
+                return lineCommit(tty, None);
+            }
+            if c == b'\n' || ccIs(tty, VEOL, c) || ccIs(tty, VEOL2, c) {
+                if !lineCommit(tty, Some(c)) {
+                    return false;
+                }
+                if lflag & ECHO != 0 || (c == b'\n' && lflag & ECHONL != 0) {
+                    outputChar(tty, c);
+                }
+                return true;
+            }
+        }
+    }
+
+    if lflag & ICANON != 0 {
+        // keep a slot for the line terminator, past that the line
+        // discards like n_tty does
+        if (*tty).lineLen >= TTY_LINE_MAX - 1 {
+            if iflag & IMAXBEL != 0 {
+                outputChar(tty, 0x07);
+            }
+            return true;
+        }
+        *(*tty).lineBuff.add((*tty).lineLen) = c;
+        (*tty).lineLen += 1;
+        echoChar(tty, c);
+    } else {
+        if !readPush(tty, c, false) {
+            return false;
+        }
+        echoChar(tty, c);
+    }
+    true
+}
+
+// Feeds raw input (keyboard, pty master writes) through the discipline.
+// Returns how much was taken, it stops early once the read queue is full.
+#[no_mangle]
+pub unsafe extern "C" fn ttyReceive(tty: *mut Tty, buff: *const u8, len: usize) -> usize {
+    spinlockAcquire(&mut (*tty).LOCK_TTY);
+    let mut taken = 0;
+    while taken < len && receiveChar(tty, *buff.add(taken)) {
+        taken += 1;
+    }
+    if taken > 0 {
+        (*tty).lastInput = timerTicks;
+    }
+    let readable = readAvailable(tty) > 0;
+    spinlockRelease(&mut (*tty).LOCK_TTY);
+
+    if readable {
+        pollInstanceRing((*tty).pollKey, EPOLLIN);
+    }
+    taken
+}
+
+//
+// Reading
+//
+
+unsafe fn readCanonical(tty: *mut Tty, out: *mut u8, limit: usize) -> usize {
+    let mut copied = 0;
+    while copied < limit && (*tty).readTail != (*tty).readHead {
+        let tail = (*tty).readTail;
+        let c = *(*tty).readBuff.add(tail);
+        let delim = delimGet(tty, tail);
+
+        (*tty).readTail = (tail + 1) % TTY_BUFF_SIZE;
+        if delim {
+            delimSet(tty, tail, false);
+            (*tty).readLines -= 1;
+            if !eofGet(tty, tail) {
+                *out.add(copied) = c;
+                copied += 1;
+            }
+            break;
+        }
+
+        *out.add(copied) = c;
+        copied += 1;
+    }
+    copied
+}
+
+unsafe fn readRaw(tty: *mut Tty, out: *mut u8, limit: usize) -> usize {
+    let mut copied = 0;
+    while copied < limit && (*tty).readTail != (*tty).readHead {
+        let tail = (*tty).readTail;
+        (*tty).readTail = (tail + 1) % TTY_BUFF_SIZE;
+        if delimGet(tty, tail) {
+            // leftovers from a previous ICANON session
+            delimSet(tty, tail, false);
+            (*tty).readLines -= 1;
+            if eofGet(tty, tail) {
+                continue;
+            }
+        }
+        *out.add(copied) = *(*tty).readBuff.add(tail);
+        copied += 1;
+    }
+    copied
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ttyRead(
+    tty: *mut Tty,
+    fd: *mut OpenFile,
+    out: *mut u8,
+    limit: usize,
+) -> usize {
+    if limit == 0 {
+        return 0;
+    }
+
+    let ret = ttyJobCheck(tty, SIGTTIN);
+    if ret != 0 {
+        return ret;
+    }
+
+    let start = timerTicks;
+    let mut copied = 0;
+    loop {
+        spinlockAcquire(&mut (*tty).LOCK_TTY);
+        let canonical = (*tty).term.c_lflag & ICANON != 0;
+        let vmin = (*tty).term.c_cc[VMIN] as usize;
+        let vtime = (*tty).term.c_cc[VTIME] as u64 * 100;
+
+        if canonical {
+            if (*tty).readLines > 0 {
+                copied = readCanonical(tty, out, limit);
+                spinlockRelease(&mut (*tty).LOCK_TTY);
+                return copied;
+            }
+        } else {
+            copied += readRaw(tty, out.add(copied), limit - copied);
+            let want = min(vmin, limit);
+
+            let done = if vmin == 0 && vtime == 0 {
+                true
+            } else if vmin == 0 {
+                // pure read timeout
+                copied > 0 || timerTicks >= start + vtime
+            } else if vtime == 0 {
+                copied >= want
+            } else {
+                // inter-byte timer, only armed after the first byte
+                copied >= want || (copied > 0 && timerTicks >= (*tty).lastInput + vtime)
+            };
+
+            if done {
+                spinlockRelease(&mut (*tty).LOCK_TTY);
+                // a polling read on an empty queue is 0 even with O_NONBLOCK,
+                // n_tty checks the zero timeout before nonblocking
+                return copied;
+            }
+        }
+        spinlockRelease(&mut (*tty).LOCK_TTY);
+
+        if copied > 0 && (*fd).flags & O_NONBLOCK != 0 {
+            return copied;
+        }
+        if (*fd).flags & O_NONBLOCK != 0 {
+            return err(EWOULDBLOCK);
+        }
+        if signalsPendingQuick(currentTask) {
+            return if copied > 0 { copied } else { err(EINTR) };
+        }
+        handControl();
+    }
+}
+
+//
+// Writing
+//
+
+// Post-processes as much of the buffer as the output side can take
+#[no_mangle]
+pub unsafe extern "C" fn ttyWrite(tty: *mut Tty, buff: *const u8, len: usize) -> usize {
+    if (*tty).term.c_lflag & TOSTOP != 0 {
+        let ret = ttyJobCheck(tty, SIGTTOU);
+        if ret != 0 {
+            return ret;
+        }
+    }
+
+    let mut out = [0u8; 8];
+    let mut done = 0;
+
+    spinlockAcquire(&mut (*tty).LOCK_TTY);
+    while done < len {
+        let expanded = outputExpand(tty, *buff.add(done), &mut out);
+        if expanded > outputRoomGet(tty) {
+            break;
+        }
+        if expanded > 0 {
+            outputColumn(tty, &out[..expanded]);
+            outputRaw(tty, out.as_ptr(), expanded);
+        }
+        done += 1;
+    }
+    spinlockRelease(&mut (*tty).LOCK_TTY);
+
+    done
+}
+
+//
+// Polling
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn ttyPoll(tty: *mut Tty, events: i32) -> i32 {
+    let mut revents = 0;
+
+    spinlockAcquire(&mut (*tty).LOCK_TTY);
+    if events & EPOLLIN != 0 && readAvailable(tty) > 0 {
+        revents |= EPOLLIN;
+    }
+    if events & EPOLLOUT != 0 && outputRoomGet(tty) > 0 {
+        revents |= EPOLLOUT;
+    }
+    spinlockRelease(&mut (*tty).LOCK_TTY);
+
+    revents
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ttyReadable(tty: *mut Tty) -> usize {
+    spinlockAcquire(&mut (*tty).LOCK_TTY);
+    let ret = readAvailable(tty);
+    spinlockRelease(&mut (*tty).LOCK_TTY);
+    ret
+}
+
+// Room left in the read queue, for whoever feeds ttyReceive()
+#[no_mangle]
+pub unsafe extern "C" fn ttyReceiveRoom(tty: *mut Tty) -> usize {
+    spinlockAcquire(&mut (*tty).LOCK_TTY);
+    let ret = readRoom(tty);
+    spinlockRelease(&mut (*tty).LOCK_TTY);
+    ret
+}
+
+//
+// Settings
+//
+
+unsafe fn ttySetTermios(tty: *mut Tty, new: *const termios, flush: bool) {
+    spinlockAcquire(&mut (*tty).LOCK_TTY);
+    if flush {
+        readFlush(tty);
+    }
+
+    let wasCanonical = (*tty).term.c_lflag & ICANON != 0;
+    (*tty).term = *new;
+    let isCanonical = (*tty).term.c_lflag & ICANON != 0;
+
+    if wasCanonical && !isCanonical {
+        // whatever was being edited becomes readable as-is, as far as it
+        // fits; n_tty drops the rest too
+        for i in 0..min((*tty).lineLen, readRoom(tty)) {
+            readPush(tty, *(*tty).lineBuff.add(i), false);
+        }
+        (*tty).lineLen = 0;
+        (*tty).lnext = false;
+    } else if !wasCanonical && isCanonical && readQueued(tty) > 0 {
+        // raw leftovers are handed out as one line
+        let last = ((*tty).readHead + TTY_BUFF_SIZE - 1) % TTY_BUFF_SIZE;
+        if !delimGet(tty, last) {
+            delimSet(tty, last, true);
+            (*tty).readLines += 1;
+        }
+    }
+    let readable = readAvailable(tty) > 0;
+    spinlockRelease(&mut (*tty).LOCK_TTY);
+
+    if readable {
+        pollInstanceRing((*tty).pollKey, EPOLLIN);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ttySetWinsize(tty: *mut Tty, win: *const winsize) {
+    spinlockAcquire(&mut (*tty).LOCK_TTY);
+    let changed = (*tty).win.ws_row != (*win).ws_row
+        || (*tty).win.ws_col != (*win).ws_col
+        || (*tty).win.ws_xpixel != (*win).ws_xpixel
+        || (*tty).win.ws_ypixel != (*win).ws_ypixel;
+    (*tty).win = *win;
+    spinlockRelease(&mut (*tty).LOCK_TTY);
+
+    if changed {
+        ttySignal(tty, SIGWINCH);
+    }
+}
+
+// Generic termios ioctls; anything device-specific is handled by the caller
+#[no_mangle]
+pub unsafe extern "C" fn ttyIoctl(tty: *mut Tty, request: u64, arg: *mut u8) -> usize {
+    if matches!(request, TCSETS | TCSETSW | TCSETSF | TCFLSH | TIOCSWINSZ | TIOCSPGRP) {
+        let ret = ttyJobCheck(tty, SIGTTOU);
+        if ret != 0 {
+            return ret;
+        }
+    }
+
+    match request {
+        TCGETS => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            spinlockAcquire(&mut (*tty).LOCK_TTY);
+            copy_nonoverlapping(
+                &(*tty).term as *const termios as *const u8,
+                arg,
+                core::mem::size_of::<termios>(),
+            );
+            spinlockRelease(&mut (*tty).LOCK_TTY);
+            0
+        }
+        TCSETS | TCSETSW | TCSETSF => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            // output is pushed out synchronously, so TCSETSW has nothing to drain
+            ttySetTermios(tty, arg as *const termios, request == TCSETSF);
+            0
+        }
+        TCFLSH => match arg as usize {
+            TCIFLUSH | TCIOFLUSH => {
+                spinlockAcquire(&mut (*tty).LOCK_TTY);
+                readFlush(tty);
+                spinlockRelease(&mut (*tty).LOCK_TTY);
+                0
+            }
+            TCOFLUSH => 0,
+            _ => err(EINVAL),
+        },
+        TIOCGWINSZ => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            spinlockAcquire(&mut (*tty).LOCK_TTY);
+            *(arg as *mut winsize) = (*tty).win;
+            spinlockRelease(&mut (*tty).LOCK_TTY);
+            0
+        }
+        TIOCSWINSZ => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            ttySetWinsize(tty, arg as *const winsize);
+            0
+        }
+        FIONREAD => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *(arg as *mut i32) = ttyReadable(tty) as i32;
+            0
+        }
+        TIOCSCTTY => sessionSetCtty(currentTask, tty, arg as usize == 1),
+        TIOCNOTTY => {
+            if !ttyIsCtty(tty) {
+                return err(ENOTTY);
+            }
+            sessionDetachCtty(currentTask);
+            0
+        }
+        TIOCGPGRP => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            if !ttyIsCtty(tty) {
+                return err(ENOTTY);
+            }
+            *(arg as *mut i32) = (*tty).ctrlPgid;
+            0
+        }
+        TIOCSPGRP => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            if !ttyIsCtty(tty) || (*tty).ctrlSession != (*currentTask).sid {
+                return err(ENOTTY);
+            }
+            let pgid = *(arg as *const i32);
+            if pgid < 0 {
+                return err(EINVAL);
+            }
+            if !pgrpExists(pgid, (*tty).ctrlSession) {
+                return err(EPERM);
+            }
+            (*tty).ctrlPgid = pgid;
+            0
+        }
+        TIOCGSID => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            if !ttyIsCtty(tty) || (*tty).ctrlSession == 0 {
+                return err(ENOTTY);
+            }
+            *(arg as *mut i32) = (*tty).ctrlSession;
+            0
+        }
+        _ => err(ENOTTY),
+    }
+}
@@ -123,6 +123,9 @@ pub struct Ext2Superblock {
     pub log2block_size: u32,
     pub total_blocks: u32,
     pub total_inodes: u32,
+    pub su_blocks: u32,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
     pub blocks_per_group: u32,
     pub inodes_per_group: u32,
     pub fs_state: u16,
@@ -204,6 +207,22 @@ pub struct OpenFile {
     pub dirname: *mut u8,
 }
 
+#[repr(C)]
+pub struct Statfs {
+    pub f_type: i64,
+    pub f_bsize: i64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_fsid: [i32; 2],
+    pub f_namelen: i64,
+    pub f_frsize: i64,
+    pub f_flags: i64,
+    pub f_spare: [i64; 4],
+}
+
 #[repr(C)]
 pub struct stat {
     pub st_dev: u64,
@@ -256,6 +275,30 @@ pub unsafe fn ext2StatInternal(
     (*target).st_ctime = (*inode).ctime;
 }
 
+// The superblock's counters are kept up to date by every allocation
+#[no_mangle]
+pub unsafe extern "C" fn ext2Statfs(mnt: *mut MountPoint, target: *mut Statfs) -> usize {
+    let ext2 = (*mnt).fsInfo as *mut Ext2;
+    memset(target as *mut u8, 0, size_of::<Statfs>());
+
+    spinlockCntReadAcquire(&mut (*ext2).WLOCK_GLOBAL_NOFD);
+    (*target).f_type = EXT2_MAGIC as i64;
+    (*target).f_bsize = (*ext2).blockSize as i64;
+    (*target).f_frsize = (*ext2).blockSize as i64;
+    (*target).f_blocks = (*ext2).superblock.total_blocks as u64;
+    (*target).f_bfree = (*ext2).superblock.free_blocks as u64;
+    (*target).f_bavail = (*ext2)
+        .superblock
+        .free_blocks
+        .saturating_sub((*ext2).superblock.su_blocks) as u64;
+    (*target).f_files = (*ext2).superblock.total_inodes as u64;
+    (*target).f_ffree = (*ext2).superblock.free_inodes as u64;
+    (*target).f_namelen = 255;
+    spinlockCntReadRelease(&mut (*ext2).WLOCK_GLOBAL_NOFD);
+
+    0
+}
+
 //
 // ===================== HANDLERS TABLE =====================
 //
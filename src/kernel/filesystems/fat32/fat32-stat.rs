@@ -17,22 +17,32 @@ const S_IXUSR: u32 = 0o100;
 
 const FAT_ATTRIB_DIRECTORY: u8 = 0x10;
 
+const SECTOR_SIZE: usize = 512;
+const MSDOS_SUPER_MAGIC: i64 = 0x4d44;
+
 //
 // Structs (partial, ABI-compatible)
 //
 
 #[repr(C)]
 pub struct FAT32 {
+    pub offsetBase: u32,
+    pub offsetFats: u32,
+    pub offsetClusters: u32,
+
     pub bootsec: FAT32BootSector,
 }
 
 #[repr(C)]
 pub struct FAT32BootSector {
+    pub sectors_per_cluster: u8,
+    pub total_sectors_32: u32,
     pub extended_section: FAT32Extended,
 }
 
 #[repr(C)]
 pub struct FAT32Extended {
+    pub table_size_32: u32,
     pub root_cluster: u32,
 }
 
@@ -74,6 +84,22 @@ pub struct OpenFile {
     pub dir: *mut c_void,
 }
 
+#[repr(C)]
+pub struct Statfs {
+    pub f_type: i64,
+    pub f_bsize: i64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_fsid: [i32; 2],
+    pub f_namelen: i64,
+    pub f_frsize: i64,
+    pub f_flags: i64,
+    pub f_spare: [i64; 4],
+}
+
 #[repr(C)]
 pub struct stat {
     pub st_dev: u64,
@@ -97,6 +123,9 @@ pub struct stat {
 
 extern "C" {
     fn memcpy(dst: *mut c_void, src: *const c_void, size: usize);
+    fn memset(dst: *mut c_void, val: i32, size: usize);
+
+    fn fat32FATfetch(fat: *mut FAT32, offsetSector: u32, bytes: *mut u8);
 
     fn fat32TraversePath(
         fat: *mut FAT32,
@@ -214,3 +243,54 @@ pub unsafe extern "C" fn fat32StatFd(
     fat32StatInternal(&res, target);
     0
 }
+
+//
+// fat32Statfs (free clusters are counted off the FAT, there's no FSInfo use)
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn fat32Statfs(
+    mnt: *mut MountPoint,
+    target: *mut Statfs,
+) -> usize {
+    let fat = (*mnt).fsInfo as *mut FAT32;
+    memset(target as *mut c_void, 0, core::mem::size_of::<Statfs>());
+
+    let sectors_per_cluster = (*fat).bootsec.sectors_per_cluster as u32;
+    let data_sectors = (*fat).bootsec.total_sectors_32
+        - ((*fat).offsetClusters - (*fat).offsetBase);
+    let clusters = data_sectors / sectors_per_cluster;
+
+    // entries 0 & 1 are reserved, data clusters start at 2
+    let entries_per_sector = (SECTOR_SIZE / 4) as u32;
+    let mut bytes = [0u8; SECTOR_SIZE];
+    let mut free = 0u64;
+    let mut cached_sector = u32::MAX;
+    for cluster in 2..clusters + 2 {
+        let sector = cluster / entries_per_sector;
+        if sector != cached_sector {
+            fat32FATfetch(fat, (*fat).offsetFats + sector, bytes.as_mut_ptr());
+            cached_sector = sector;
+        }
+
+        let entry = (cluster % entries_per_sector) as usize * 4;
+        let value = u32::from_le_bytes([
+            bytes[entry],
+            bytes[entry + 1],
+            bytes[entry + 2],
+            bytes[entry + 3],
+        ]);
+        if value & 0x0FFFFFFF == 0 {
+            free += 1;
+        }
+    }
+
+    (*target).f_type = MSDOS_SUPER_MAGIC;
+    (*target).f_bsize = (sectors_per_cluster as usize * SECTOR_SIZE) as i64;
+    (*target).f_frsize = (*target).f_bsize;
+    (*target).f_blocks = clusters as u64;
+    (*target).f_bfree = free;
+    (*target).f_bavail = free;
+    (*target).f_namelen = 255;
+    0
+}
@@ -1,6 +1,7 @@
 use alloc::vec::Vec;
 use alloc::string::String;
 use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::vfs::{MountPoint, MOUNTS};
 
 struct UserspaceProc {
     pid: u64,
@@ -46,6 +47,92 @@ fn meminfo_read(fd_pointer: usize, buf: &mut [u8]) -> usize {
     len
 }
 
+extern "C" {
+    fn coredumpPatternGet(out: *mut u8, max: usize) -> usize;
+    fn coredumpPatternSet(input: *const u8, len: usize) -> usize;
+}
+
+// /proc/sys/kernel/core_pattern
+fn core_pattern_read(fd_pointer: usize, buf: &mut [u8]) -> usize {
+    let mut pattern = [0u8; 129];
+    let mut content_len = unsafe { coredumpPatternGet(pattern.as_mut_ptr(), 128) };
+    pattern[content_len] = b'\n';
+    content_len += 1;
+
+    let start = core::cmp::min(fd_pointer, content_len);
+    let len = core::cmp::min(buf.len(), content_len - start);
+    buf[..len].copy_from_slice(&pattern[start..start + len]);
+    len
+}
+
+fn core_pattern_write(buf: &[u8]) -> usize {
+    unsafe { coredumpPatternSet(buf.as_ptr(), buf.len()) };
+    buf.len()
+}
+
+// /proc/sys/net/ipv4/ip_forward
+fn ip_forward_read(fd_pointer: usize, buf: &mut [u8]) -> usize {
+    let content: &[u8] = if crate::firewall::forwarding() { b"1\n" } else { b"0\n" };
+    let start = core::cmp::min(fd_pointer, content.len());
+    let len = core::cmp::min(buf.len(), content.len() - start);
+    buf[..len].copy_from_slice(&content[start..start + len]);
+    len
+}
+
+fn ip_forward_write(buf: &[u8]) -> usize {
+    match buf.first() {
+        Some(b'0') => crate::firewall::set_forwarding(false),
+        Some(b'1') => crate::firewall::set_forwarding(true),
+        _ => return -22isize as usize,
+    }
+    buf.len()
+}
+
+// /proc/net/route
+fn net_route_read(fd_pointer: usize, buf: &mut [u8]) -> usize {
+    let content = crate::route::proc_net_route();
+    let content_bytes = content.as_bytes();
+    let start = core::cmp::min(fd_pointer, content_bytes.len());
+    let len = core::cmp::min(buf.len(), content_bytes.len() - start);
+    buf[..len].copy_from_slice(&content_bytes[start..start + len]);
+    len
+}
+
+// /proc/net/dns_cache (any write flushes it)
+fn net_dns_cache_read(fd_pointer: usize, buf: &mut [u8]) -> usize {
+    let content = crate::dns::proc_net_dns_cache();
+    let content_bytes = content.as_bytes();
+    let start = core::cmp::min(fd_pointer, content_bytes.len());
+    let len = core::cmp::min(buf.len(), content_bytes.len() - start);
+    buf[..len].copy_from_slice(&content_bytes[start..start + len]);
+    len
+}
+
+fn net_dns_cache_write(buf: &[u8]) -> usize {
+    crate::dns::dns_cache_flush();
+    buf.len()
+}
+
+// /proc/mounts
+fn mounts_read(fd_pointer: usize, buf: &mut [u8]) -> usize {
+    let content = MOUNTS.format_mounts();
+    let content_bytes = content.as_bytes();
+    let start = core::cmp::min(fd_pointer, content_bytes.len());
+    let len = core::cmp::min(buf.len(), content_bytes.len() - start);
+    buf[..len].copy_from_slice(&content_bytes[start..start + len]);
+    len
+}
+
+// /proc/[pid]/mountinfo (one mount namespace, so the same for everyone)
+fn mountinfo_read(fd_pointer: usize, buf: &mut [u8]) -> usize {
+    let content = MOUNTS.format_mountinfo();
+    let content_bytes = content.as_bytes();
+    let start = core::cmp::min(fd_pointer, content_bytes.len());
+    let len = core::cmp::min(buf.len(), content_bytes.len() - start);
+    buf[..len].copy_from_slice(&content_bytes[start..start + len]);
+    len
+}
+
 // /proc/[pid]/cmdline
 fn proc_cmdline_read(proc: &UserspaceProc, fd_pointer: usize, buf: &mut [u8]) -> usize {
     let task = task_get(proc.pid).expect("task not found"); // Kernel function
@@ -85,10 +172,11 @@ fn proc_each_close(uproc: UserspaceProc) {
 }
 
 // Mounting procfs pseudo-filesystem
-fn proc_mount(mount_point: &mut MountPoint) {
+pub fn proc_mount(mount_point: &mut MountPoint) -> bool {
     mount_point.handlers = &FAKEFS_HANDLERS;
     mount_point.fs_info = Box::new(FakefsOverlay::new());
     proc_setup();
+    true
 }
 
 fn proc_setup() {
@@ -96,6 +184,13 @@ fn proc_setup() {
     add_file("/proc/meminfo", meminfo_read);
     add_file("/proc/uptime", uptime_read);
     add_file("/proc/stat", stat_read);
+    add_file_writable("/proc/sys/kernel/core_pattern", core_pattern_read, core_pattern_write);
+    add_file_writable("/proc/sys/net/ipv4/ip_forward", ip_forward_read, ip_forward_write);
+    add_file("/proc/mounts", mounts_read);
+    add_file("/proc/net/route", net_route_read);
+    add_file_writable("/proc/net/dns_cache", net_dns_cache_read, net_dns_cache_write);
     add_dir("/proc/*", proc_root_handlers);
     add_dir("/proc/self", proc_root_handlers);
+    add_file("/proc/*/mountinfo", mountinfo_read);
+    add_file("/proc/self/mountinfo", mountinfo_read);
 }
@@ -9,6 +9,10 @@ use crate::fb::*;
 use crate::syscalls::*;
 use crate::util::*;
 use crate::pci::*;
+use crate::firewall::{conntrack_read, conntrack_write, firewall_read, firewall_write};
+use crate::vfs::{MountPoint, FAKEFS_HANDLERS, fakefs_stat, fakefs_lstat};
+
+const EINVAL: i32 = 22;
 
 /// The root of the sys filesystem
 pub struct FakeFs {
@@ -81,6 +85,45 @@ fn cavos_console_write(fd: &mut OpenFile, buff: &[u8]) -> usize {
     buff.len()
 }
 
+/// Copies a generated text file out from the file pointer onwards
+fn text_read(fd: &mut OpenFile, out: &mut [u8], text: &str) -> usize {
+    let bytes = text.as_bytes();
+    if fd.pointer >= bytes.len() { return 0; }
+    let to_copy = core::cmp::min(bytes.len() - fd.pointer, out.len());
+
+    out[..to_copy].copy_from_slice(&bytes[fd.pointer..fd.pointer + to_copy]);
+    fd.pointer += to_copy;
+    to_copy
+}
+
+/// Firewall rules read handler (iptables-save -c)
+fn firewall_rules_read(fd: &mut OpenFile, out: &mut [u8]) -> usize {
+    text_read(fd, out, &firewall_read())
+}
+
+/// Firewall rules write handler, one iptables command per line
+fn firewall_rules_write(_fd: &mut OpenFile, buff: &[u8]) -> usize {
+    let Ok(text) = core::str::from_utf8(buff) else { return -(EINVAL as isize) as usize };
+    match firewall_write(text) {
+        Ok(()) => buff.len(),
+        Err(e) => -(e.raw_os_error().unwrap_or(EINVAL) as isize) as usize,
+    }
+}
+
+/// Connection tracking table read handler
+fn firewall_conntrack_read(fd: &mut OpenFile, out: &mut [u8]) -> usize {
+    text_read(fd, out, &conntrack_read())
+}
+
+/// Connection tracking table write handler ("-F" flushes)
+fn firewall_conntrack_write(_fd: &mut OpenFile, buff: &[u8]) -> usize {
+    let Ok(text) = core::str::from_utf8(buff) else { return -(EINVAL as isize) as usize };
+    match conntrack_write(text) {
+        Ok(()) => buff.len(),
+        Err(e) => -(e.raw_os_error().unwrap_or(EINVAL) as isize) as usize,
+    }
+}
+
 /// Setup PCI devices under `/sys/bus/pci/devices`
 fn sys_setup_pci(devices_dir: &mut FakeFsFile) {
     for bus in 0..PCI_MAX_BUSES {
@@ -206,4 +249,47 @@ pub fn sys_setup(root: &mut FakeFs) {
     pci.children.borrow_mut().push(Box::new(devices));
     bus.children.borrow_mut().push(Box::new(pci));
     root.root.borrow_mut().push(Box::new(bus));
+
+    // net/firewall
+    let mut net = FakeFsFile { name: "net".into(), kind: FsKind::Dir, handlers: None, extra: None, children: RefCell::new(vec![]) };
+    let mut firewall = FakeFsFile { name: "firewall".into(), kind: FsKind::Dir, handlers: None, extra: None, children: RefCell::new(vec![]) };
+    let rules = FakeFsFile {
+        name: "rules".into(),
+        kind: FsKind::File,
+        handlers: Some(FsHandlers { read: Some(firewall_rules_read), write: Some(firewall_rules_write), stat: None, seek: None }),
+        extra: None,
+        children: RefCell::new(vec![]),
+    };
+    let conntrack = FakeFsFile {
+        name: "conntrack".into(),
+        kind: FsKind::File,
+        handlers: Some(FsHandlers { read: Some(firewall_conntrack_read), write: Some(firewall_conntrack_write), stat: None, seek: None }),
+        extra: None,
+        children: RefCell::new(vec![]),
+    };
+
+    firewall.children.borrow_mut().push(Box::new(rules));
+    firewall.children.borrow_mut().push(Box::new(conntrack));
+    net.children.borrow_mut().push(Box::new(firewall));
+    root.root.borrow_mut().push(Box::new(net));
+}
+
+static mut ROOT_SYS: FakeFs = FakeFs {
+    root: RefCell::new(Vec::new()),
+};
+
+/// Mount /sys, every mount shares the same tree
+pub fn sys_mount(mount: &mut MountPoint) -> bool {
+    mount.handlers = Some(&FAKEFS_HANDLERS);
+    mount.stat = Some(fakefs_stat);
+    mount.lstat = Some(fakefs_lstat);
+
+    unsafe {
+        if ROOT_SYS.root.borrow().is_empty() {
+            sys_setup(&mut ROOT_SYS);
+        }
+        mount.fs_info = &mut ROOT_SYS as *mut FakeFs as *mut _;
+    }
+
+    true
 }
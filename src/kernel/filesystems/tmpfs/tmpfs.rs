@@ -0,0 +1,1258 @@
+#![no_std]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::ptr::{copy_nonoverlapping, null_mut, write_bytes};
+
+//
+// tmpfs: a RAM backed filesystem, everything lives in inodes hanging off the
+// mount's Tmpfs struct. File contents are kept as an array of physical pages
+// so mmap() can hand the very same pages to userspace.
+//
+
+//
+// Constants
+//
+
+pub const PAGE_SIZE: usize = 4096;
+
+pub const TMPFS_MAGIC: i64 = 0x0102_1994;
+pub const TMPFS_NAME_MAX: usize = 255;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFLNK: u32 = 0o120000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFDIR: u32 = 0o040000;
+
+pub const CDT_DIR: u8 = 4;
+pub const CDT_REG: u8 = 8;
+pub const CDT_LNK: u8 = 10;
+
+pub const EPERM: isize = 1;
+pub const ENOENT: isize = 2;
+pub const EBADF: isize = 9;
+pub const ENOMEM: isize = 12;
+pub const EEXIST: isize = 17;
+pub const ENOTDIR: isize = 20;
+pub const EISDIR: isize = 21;
+pub const EINVAL: isize = 22;
+pub const EFBIG: isize = 27;
+pub const ENOSPC: isize = 28;
+pub const EMLINK: isize = 31;
+pub const ENAMETOOLONG: isize = 36;
+pub const ENOTEMPTY: isize = 39;
+pub const ELOOP: isize = 40;
+
+#[inline]
+pub const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+#[inline]
+pub const fn DivRoundUp(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
+//
+// External kernel APIs
+//
+
+#[repr(C)]
+pub struct Spinlock {
+    _priv: u32,
+}
+
+#[repr(C)]
+pub struct Bootloader {
+    pub mmTotal: usize,
+    pub mmEntryCnt: usize,
+    pub mmEntries: *const c_void,
+    pub hhdmOffset: usize,
+}
+
+extern "C" {
+    static bootloader: Bootloader;
+    static timerTicks: u64;
+    static timerBootUnix: u64;
+
+    pub fn calloc(n: usize, size: usize) -> *mut u8;
+    pub fn malloc(size: usize) -> *mut u8;
+    pub fn free(ptr: *mut u8);
+
+    pub fn spinlockAcquire(lock: *mut Spinlock);
+    pub fn spinlockRelease(lock: *mut Spinlock);
+
+    pub fn PhysicalAllocate(pages: usize) -> usize;
+    pub fn PhysicalFree(ptr: usize, pages: usize);
+
+    pub fn debugf(fmt: *const u8, ...);
+}
+
+#[repr(C)]
+pub struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_nlink: u64,
+    pub st_mode: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub __pad0: i32,
+    pub st_rdev: u64,
+    pub st_size: i64,
+    pub st_blksize: i64,
+    pub st_blocks: i64,
+    pub st_atime: i64,
+    pub st_atimensec: u64,
+    pub st_mtime: i64,
+    pub st_mtimensec: u64,
+    pub st_ctime: i64,
+    pub st_ctimensec: u64,
+    pub __unused: [i64; 3],
+}
+
+#[repr(C)]
+pub struct Statfs {
+    pub f_type: i64,
+    pub f_bsize: i64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_fsid: [i32; 2],
+    pub f_namelen: i64,
+    pub f_frsize: i64,
+    pub f_flags: i64,
+    pub f_spare: [i64; 4],
+}
+
+#[repr(C)]
+pub struct MountPoint {
+    pub fsInfo: *mut c_void,
+}
+
+//
+// Structures
+//
+
+#[repr(C)]
+pub struct TmpfsDirent {
+    pub next: *mut TmpfsDirent,
+
+    pub name: *mut u8,
+    pub nameLen: usize,
+
+    pub inode: *mut TmpfsInode,
+}
+
+#[repr(C)]
+pub struct TmpfsInode {
+    pub ino: usize,
+
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+
+    pub atime: u64,
+    pub mtime: u64,
+    pub ctime: u64,
+
+    pub size: usize,
+
+    // regular files, physical page per file page (0 being a hole)
+    pub pages: *mut usize,
+    pub pagesCap: usize,
+    // live MAP_SHARED mappings, pages truncated away meanwhile wait in
+    // orphans for the last one to go
+    pub mappings: usize,
+    pub orphans: *mut usize,
+    pub orphansLen: usize,
+    pub orphansCap: usize,
+    pub tmpfs: *mut Tmpfs,
+
+    // symlinks
+    pub symlink: *mut u8,
+
+    // directories
+    pub firstDirent: *mut TmpfsDirent,
+    pub parent: *mut TmpfsInode,
+
+    // kept alive after the last unlink() while still open
+    pub openFds: usize,
+}
+
+#[repr(C)]
+pub struct Tmpfs {
+    pub LOCK_TMPFS: Spinlock,
+
+    pub root: *mut TmpfsInode,
+    pub lastIno: usize,
+
+    // size= & nr_inodes=
+    pub maxPages: usize,
+    pub maxInodes: usize,
+
+    pub usedPages: usize,
+    pub usedInodes: usize,
+
+    // mappings outlive the mount, the last one frees an unmounted instance
+    pub mappings: usize,
+    pub unmounted: bool,
+}
+
+#[inline]
+pub unsafe fn TMPFS_PTR(mnt: *mut MountPoint) -> *mut Tmpfs {
+    (*mnt).fsInfo as *mut Tmpfs
+}
+
+#[inline]
+pub fn S_ISDIR(mode: u32) -> bool {
+    mode & S_IFMT == S_IFDIR
+}
+
+#[inline]
+pub fn S_ISLNK(mode: u32) -> bool {
+    mode & S_IFMT == S_IFLNK
+}
+
+#[inline]
+pub fn S_ISREG(mode: u32) -> bool {
+    mode & S_IFMT == S_IFREG
+}
+
+pub unsafe fn tmpfsNow() -> u64 {
+    timerBootUnix + timerTicks / 1000
+}
+
+pub unsafe fn strlength(s: *const u8) -> usize {
+    let mut len = 0;
+    while *s.add(len) != 0 {
+        len += 1;
+    }
+    len
+}
+
+unsafe fn strndup(s: *const u8, len: usize) -> *mut u8 {
+    let ret = malloc(len + 1);
+    copy_nonoverlapping(s, ret, len);
+    *ret.add(len) = 0;
+    ret
+}
+
+//
+// Mounting
+//
+
+// "size=64m" style numbers, with an optional k/m/g suffix or % of RAM
+unsafe fn tmpfsParseSize(value: *const u8, len: usize, percentBase: usize) -> Option<usize> {
+    let mut num: usize = 0;
+    let mut i = 0;
+    while i < len && (*value.add(i)).is_ascii_digit() {
+        num = num.checked_mul(10)?.checked_add((*value.add(i) - b'0') as usize)?;
+        i += 1;
+    }
+    if i == 0 {
+        return None;
+    }
+
+    if i == len {
+        return Some(num);
+    }
+    if i + 1 != len {
+        return None;
+    }
+    match *value.add(i) {
+        b'k' | b'K' => num.checked_mul(1024),
+        b'm' | b'M' => num.checked_mul(1024 * 1024),
+        b'g' | b'G' => num.checked_mul(1024 * 1024 * 1024),
+        b'%' => Some(percentBase / 100 * num),
+        _ => None,
+    }
+}
+
+unsafe fn tmpfsParseOptions(tmpfs: *mut Tmpfs, options: *const u8, rootMode: &mut u32) -> bool {
+    if options.is_null() {
+        return true;
+    }
+
+    let total = strlength(options);
+    let mut start = 0;
+    while start < total {
+        let mut end = start;
+        while end < total && *options.add(end) != b',' {
+            end += 1;
+        }
+
+        let opt = options.add(start);
+        let len = end - start;
+        let mut eq = 0;
+        while eq < len && *opt.add(eq) != b'=' {
+            eq += 1;
+        }
+        let key = core::slice::from_raw_parts(opt, eq);
+        let value = opt.add((eq + 1).min(len));
+        let valueLen = len.saturating_sub(eq + 1);
+
+        match key {
+            b"size" => match tmpfsParseSize(value, valueLen, bootloader.mmTotal) {
+                Some(bytes) => (*tmpfs).maxPages = DivRoundUp(bytes, PAGE_SIZE),
+                None => return false,
+            },
+            b"nr_inodes" => match tmpfsParseSize(value, valueLen, 0) {
+                Some(inodes) => (*tmpfs).maxInodes = inodes,
+                None => return false,
+            },
+            b"mode" => {
+                let mut mode = 0;
+                for i in 0..valueLen {
+                    let c = *value.add(i);
+                    if !(b'0'..=b'7').contains(&c) {
+                        return false;
+                    }
+                    mode = mode * 8 + (c - b'0') as u32;
+                }
+                *rootMode = mode & 0o7777;
+            }
+            // generic ones that mean nothing to us
+            b"" | b"rw" | b"nosuid" | b"nodev" | b"noexec" | b"relatime" => {}
+            _ => {
+                debugf(b"[tmpfs] Unknown mount option, ignoring!\n\0".as_ptr());
+            }
+        }
+
+        start = end + 1;
+    }
+
+    true
+}
+
+// Defaults like Linux: half of RAM for data, as many inodes as half of the
+// RAM's pages. A limit of 0 stands for unlimited.
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsMount(mnt: *mut MountPoint, options: *const u8) -> bool {
+    let tmpfs = calloc(1, size_of::<Tmpfs>()) as *mut Tmpfs;
+    (*tmpfs).maxPages = bootloader.mmTotal / PAGE_SIZE / 2;
+    (*tmpfs).maxInodes = bootloader.mmTotal / PAGE_SIZE / 2;
+
+    let mut rootMode = 0o1777;
+    if !tmpfsParseOptions(tmpfs, options, &mut rootMode) {
+        free(tmpfs as *mut u8);
+        return false;
+    }
+
+    let root = tmpfsInodeAllocate(tmpfs, S_IFDIR | rootMode);
+    if root.is_null() {
+        free(tmpfs as *mut u8);
+        return false;
+    }
+    (*root).nlink = 2;
+    (*root).parent = root;
+    (*tmpfs).root = root;
+
+    (*mnt).fsInfo = tmpfs as *mut c_void;
+    true
+}
+
+// Only the limits and the root's mode can change. Shrinking below what's
+// already in use is refused, as Linux does.
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsRemount(mnt: *mut MountPoint, options: *const u8) -> bool {
+    let tmpfs = TMPFS_PTR(mnt);
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+
+    let oldPages = (*tmpfs).maxPages;
+    let oldInodes = (*tmpfs).maxInodes;
+    let mut rootMode = (*(*tmpfs).root).mode & 0o7777;
+    let ok = tmpfsParseOptions(tmpfs, options, &mut rootMode)
+        && ((*tmpfs).maxPages == 0 || (*tmpfs).maxPages >= (*tmpfs).usedPages)
+        && ((*tmpfs).maxInodes == 0 || (*tmpfs).maxInodes >= (*tmpfs).usedInodes);
+    if ok {
+        (*(*tmpfs).root).mode = S_IFDIR | rootMode;
+    } else {
+        (*tmpfs).maxPages = oldPages;
+        (*tmpfs).maxInodes = oldInodes;
+    }
+
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+    ok
+}
+
+unsafe fn tmpfsFreeTree(tmpfs: *mut Tmpfs, dir: *mut TmpfsInode) {
+    while !(*dir).firstDirent.is_null() {
+        let dirent = (*dir).firstDirent;
+        let inode = (*dirent).inode;
+        if S_ISDIR((*inode).mode) {
+            tmpfsFreeTree(tmpfs, inode);
+        }
+        tmpfsDirRemove(dir, dirent);
+        (*inode).nlink = if S_ISDIR((*inode).mode) { 0 } else { (*inode).nlink - 1 };
+        tmpfsInodePut(tmpfs, inode);
+    }
+}
+
+// Called once the last mount of this instance is gone and nothing is open
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsUnmount(mnt: *mut MountPoint) {
+    let tmpfs = TMPFS_PTR(mnt);
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    tmpfsFreeTree(tmpfs, (*tmpfs).root);
+    (*(*tmpfs).root).nlink = 0;
+    tmpfsInodePut(tmpfs, (*tmpfs).root);
+    // still mapped somewhere, tmpfsMappingHold() finishes the job
+    (*tmpfs).unmounted = true;
+    let dead = (*tmpfs).mappings == 0;
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    if dead {
+        free(tmpfs as *mut u8);
+    }
+    (*mnt).fsInfo = null_mut();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsStatfs(mnt: *mut MountPoint, target: *mut Statfs) -> usize {
+    let tmpfs = TMPFS_PTR(mnt);
+    write_bytes(target as *mut u8, 0, size_of::<Statfs>());
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    (*target).f_type = TMPFS_MAGIC;
+    (*target).f_bsize = PAGE_SIZE as i64;
+    (*target).f_frsize = PAGE_SIZE as i64;
+    (*target).f_namelen = TMPFS_NAME_MAX as i64;
+    if (*tmpfs).maxPages != 0 {
+        (*target).f_blocks = (*tmpfs).maxPages as u64;
+        (*target).f_bfree = ((*tmpfs).maxPages - (*tmpfs).usedPages) as u64;
+        (*target).f_bavail = (*target).f_bfree;
+    }
+    if (*tmpfs).maxInodes != 0 {
+        (*target).f_files = (*tmpfs).maxInodes as u64;
+        (*target).f_ffree = ((*tmpfs).maxInodes - (*tmpfs).usedInodes) as u64;
+    }
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    0
+}
+
+//
+// Inodes (LOCK_TMPFS held for everything below)
+//
+
+pub unsafe fn tmpfsInodeAllocate(tmpfs: *mut Tmpfs, mode: u32) -> *mut TmpfsInode {
+    if (*tmpfs).maxInodes != 0 && (*tmpfs).usedInodes >= (*tmpfs).maxInodes {
+        return null_mut();
+    }
+
+    let inode = calloc(1, size_of::<TmpfsInode>()) as *mut TmpfsInode;
+    (*tmpfs).usedInodes += 1;
+    (*tmpfs).lastIno += 1;
+    (*inode).ino = (*tmpfs).lastIno;
+    (*inode).tmpfs = tmpfs;
+    (*inode).mode = mode;
+    (*inode).nlink = 1;
+
+    let now = tmpfsNow();
+    (*inode).atime = now;
+    (*inode).mtime = now;
+    (*inode).ctime = now;
+    inode
+}
+
+// Frees the inode once it has neither names nor open files left
+pub unsafe fn tmpfsInodePut(tmpfs: *mut Tmpfs, inode: *mut TmpfsInode) {
+    if (*inode).nlink > 0 || (*inode).openFds > 0 || (*inode).mappings > 0 {
+        return;
+    }
+
+    tmpfsPagesResize(tmpfs, inode, 0);
+    if !(*inode).pages.is_null() {
+        free((*inode).pages as *mut u8);
+    }
+    if !(*inode).symlink.is_null() {
+        free((*inode).symlink);
+    }
+
+    (*tmpfs).usedInodes -= 1;
+    free(inode as *mut u8);
+}
+
+// Grows or shrinks the page array so it can hold `pages` pages, freeing
+// whatever falls off the end. Nothing gets allocated for new pages (holes).
+pub unsafe fn tmpfsPagesResize(tmpfs: *mut Tmpfs, inode: *mut TmpfsInode, pages: usize) {
+    if pages < (*inode).pagesCap {
+        for i in pages..(*inode).pagesCap {
+            let phys = *(*inode).pages.add(i);
+            if phys == 0 {
+                continue;
+            }
+            // someone may still have it mapped
+            if (*inode).mappings > 0 {
+                tmpfsOrphanPush(inode, phys);
+            } else {
+                PhysicalFree(phys, 1);
+                (*tmpfs).usedPages -= 1;
+            }
+            *(*inode).pages.add(i) = 0;
+        }
+        return;
+    }
+
+    if pages == (*inode).pagesCap {
+        return;
+    }
+
+    let cap = pages.max((*inode).pagesCap * 2).max(4);
+    let new = calloc(cap, size_of::<usize>()) as *mut usize;
+    if !(*inode).pages.is_null() {
+        copy_nonoverlapping((*inode).pages, new, (*inode).pagesCap);
+        free((*inode).pages as *mut u8);
+    }
+    (*inode).pages = new;
+    (*inode).pagesCap = cap;
+}
+
+unsafe fn tmpfsOrphanPush(inode: *mut TmpfsInode, phys: usize) {
+    if (*inode).orphansLen == (*inode).orphansCap {
+        let cap = ((*inode).orphansCap * 2).max(4);
+        let new = calloc(cap, size_of::<usize>()) as *mut usize;
+        if !(*inode).orphans.is_null() {
+            copy_nonoverlapping((*inode).orphans, new, (*inode).orphansLen);
+            free((*inode).orphans as *mut u8);
+        }
+        (*inode).orphans = new;
+        (*inode).orphansCap = cap;
+    }
+    *(*inode).orphans.add((*inode).orphansLen) = phys;
+    (*inode).orphansLen += 1;
+}
+
+// FileMapping hold of MAP_SHARED mappings, `ctx` being the inode. The last
+// mapping to go hands back what truncate() couldn't, and the inode or the
+// whole instance if those were only waiting on it.
+pub unsafe extern "C" fn tmpfsMappingHold(ctx: *mut c_void, take: bool) {
+    let inode = ctx as *mut TmpfsInode;
+    let tmpfs = (*inode).tmpfs;
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+
+    if take {
+        (*inode).mappings += 1;
+        (*tmpfs).mappings += 1;
+        spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+        return;
+    }
+
+    (*inode).mappings -= 1;
+    (*tmpfs).mappings -= 1;
+    if (*inode).mappings == 0 {
+        for i in 0..(*inode).orphansLen {
+            PhysicalFree(*(*inode).orphans.add(i), 1);
+        }
+        (*tmpfs).usedPages -= (*inode).orphansLen;
+        if !(*inode).orphans.is_null() {
+            free((*inode).orphans as *mut u8);
+        }
+        (*inode).orphans = null_mut();
+        (*inode).orphansLen = 0;
+        (*inode).orphansCap = 0;
+        tmpfsInodePut(tmpfs, inode);
+    }
+    let dead = (*tmpfs).unmounted && (*tmpfs).mappings == 0;
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    if dead {
+        free(tmpfs as *mut u8);
+    }
+}
+
+// Physical page backing `index`, allocated (zeroed) on demand
+pub unsafe fn tmpfsPageGet(tmpfs: *mut Tmpfs, inode: *mut TmpfsInode, index: usize) -> usize {
+    if index >= (*inode).pagesCap {
+        tmpfsPagesResize(tmpfs, inode, index + 1);
+    }
+
+    let slot = (*inode).pages.add(index);
+    if *slot == 0 {
+        if (*tmpfs).maxPages != 0 && (*tmpfs).usedPages >= (*tmpfs).maxPages {
+            return 0;
+        }
+        let phys = PhysicalAllocate(1);
+        write_bytes(tmpfsPageVirt(phys), 0, PAGE_SIZE);
+        (*tmpfs).usedPages += 1;
+        *slot = phys;
+    }
+    *slot
+}
+
+#[inline]
+pub unsafe fn tmpfsPageVirt(phys: usize) -> *mut u8 {
+    (phys + bootloader.hhdmOffset) as *mut u8
+}
+
+// truncate(): drops pages past the new end and zeroes the tail of the last one
+pub unsafe fn tmpfsInodeTruncate(tmpfs: *mut Tmpfs, inode: *mut TmpfsInode, size: usize) -> usize {
+    if S_ISDIR((*inode).mode) {
+        return err(EISDIR);
+    }
+    if !S_ISREG((*inode).mode) {
+        return err(EINVAL);
+    }
+
+    if size < (*inode).size {
+        let pages = DivRoundUp(size, PAGE_SIZE);
+        tmpfsPagesResize(tmpfs, inode, pages);
+        let tail = size % PAGE_SIZE;
+        if tail != 0 && pages <= (*inode).pagesCap {
+            let phys = *(*inode).pages.add(pages - 1);
+            if phys != 0 {
+                write_bytes(tmpfsPageVirt(phys).add(tail), 0, PAGE_SIZE - tail);
+            }
+        }
+    } else if size > (*inode).size
+        && (*tmpfs).maxPages != 0
+        && DivRoundUp(size, PAGE_SIZE) > (*tmpfs).maxPages
+    {
+        return err(EFBIG);
+    }
+
+    (*inode).size = size;
+    let now = tmpfsNow();
+    (*inode).mtime = now;
+    (*inode).ctime = now;
+    0
+}
+
+pub unsafe fn tmpfsStatInternal(inode: *mut TmpfsInode, target: *mut Stat) {
+    write_bytes(target as *mut u8, 0, size_of::<Stat>());
+    (*target).st_dev = 71;
+    (*target).st_ino = (*inode).ino as u64;
+    (*target).st_mode = (*inode).mode;
+    (*target).st_nlink = (*inode).nlink as u64;
+    (*target).st_uid = (*inode).uid;
+    (*target).st_gid = (*inode).gid;
+    (*target).st_blksize = PAGE_SIZE as i64;
+    (*target).st_size = (*inode).size as i64;
+
+    let mut allocated = 0;
+    for i in 0..(*inode).pagesCap {
+        if *(*inode).pages.add(i) != 0 {
+            allocated += 1;
+        }
+    }
+    (*target).st_blocks = (allocated * PAGE_SIZE / 512) as i64;
+
+    (*target).st_atime = (*inode).atime as i64;
+    (*target).st_mtime = (*inode).mtime as i64;
+    (*target).st_ctime = (*inode).ctime as i64;
+}
+
+//
+// Directories
+//
+
+pub unsafe fn tmpfsDirFind(dir: *mut TmpfsInode, name: *const u8, nameLen: usize) -> *mut TmpfsDirent {
+    let mut browse = (*dir).firstDirent;
+    while !browse.is_null() {
+        if (*browse).nameLen == nameLen
+            && core::slice::from_raw_parts((*browse).name, nameLen)
+                == core::slice::from_raw_parts(name, nameLen)
+        {
+            return browse;
+        }
+        browse = (*browse).next;
+    }
+    null_mut()
+}
+
+// Appended so getdents64() offsets stay stable while entries get added
+pub unsafe fn tmpfsDirAdd(dir: *mut TmpfsInode, name: *const u8, nameLen: usize, inode: *mut TmpfsInode) {
+    let dirent = calloc(1, size_of::<TmpfsDirent>()) as *mut TmpfsDirent;
+    (*dirent).name = strndup(name, nameLen);
+    (*dirent).nameLen = nameLen;
+    (*dirent).inode = inode;
+
+    let mut slot = &mut (*dir).firstDirent as *mut *mut TmpfsDirent;
+    while !(*slot).is_null() {
+        slot = &mut (**slot).next;
+    }
+    *slot = dirent;
+
+    if S_ISDIR((*inode).mode) {
+        (*inode).parent = dir;
+        (*dir).nlink += 1;
+    }
+    (*dir).size += 20;
+    let now = tmpfsNow();
+    (*dir).mtime = now;
+    (*dir).ctime = now;
+}
+
+// Unlinks the entry, the inode itself is the caller's business
+pub unsafe fn tmpfsDirRemove(dir: *mut TmpfsInode, dirent: *mut TmpfsDirent) {
+    let mut slot = &mut (*dir).firstDirent as *mut *mut TmpfsDirent;
+    while *slot != dirent {
+        slot = &mut (**slot).next;
+    }
+    *slot = (*dirent).next;
+
+    if S_ISDIR((*(*dirent).inode).mode) {
+        (*dir).nlink -= 1;
+    }
+    (*dir).size -= 20;
+    let now = tmpfsNow();
+    (*dir).mtime = now;
+    (*dir).ctime = now;
+
+    free((*dirent).name);
+    free(dirent as *mut u8);
+}
+
+//
+// Path lookup
+//
+
+pub struct TmpfsWalk {
+    pub inode: *mut TmpfsInode,
+    // directory the last component lives (or would live) in
+    pub parent: *mut TmpfsInode,
+    pub name: *const u8,
+    pub nameLen: usize,
+}
+
+// Builds the path the VFS has to restart the lookup from. Absolute targets are
+// handed back as system paths (prefixed with '!'), relative ones are resolved
+// against the link's directory inside this mount, like ext2 does.
+unsafe fn tmpfsSymlinkResolve(
+    path: *const u8,
+    dirEnd: usize,
+    target: *const u8,
+    rest: *const u8,
+    symlinkResolve: *mut *mut u8,
+) {
+    let targetLen = strlength(target);
+    let restLen = strlength(rest);
+    let out = malloc(dirEnd + targetLen + restLen + 3);
+    let mut len = 0;
+
+    if *target == b'/' {
+        *out = b'!';
+        len += 1;
+    } else {
+        copy_nonoverlapping(path, out, dirEnd);
+        len += dirEnd;
+        *out.add(len) = b'/';
+        len += 1;
+    }
+    copy_nonoverlapping(target, out.add(len), targetLen);
+    len += targetLen;
+    copy_nonoverlapping(rest, out.add(len), restLen);
+    len += restLen;
+    *out.add(len) = 0;
+
+    *symlinkResolve = out;
+}
+
+// Walks `path` (relative to the mount, '/' separated). On success walk.inode
+// is the target or null if only the last component is missing (walk.parent is
+// valid then). A symlink on the way (or at the end with `follow`) stops the
+// walk with ELOOP and *symlinkResolve set.
+pub unsafe fn tmpfsWalk(
+    tmpfs: *mut Tmpfs,
+    path: *const u8,
+    follow: bool,
+    symlinkResolve: *mut *mut u8,
+    walk: &mut TmpfsWalk,
+) -> usize {
+    let len = strlength(path);
+    let mut curr = (*tmpfs).root;
+    walk.inode = curr;
+    walk.parent = curr;
+    walk.name = b".\0".as_ptr();
+    walk.nameLen = 1;
+
+    let mut i = 0;
+    while i < len {
+        while i < len && *path.add(i) == b'/' {
+            i += 1;
+        }
+        if i >= len {
+            break;
+        }
+
+        let start = i;
+        while i < len && *path.add(i) != b'/' {
+            i += 1;
+        }
+        let name = path.add(start);
+        let nameLen = i - start;
+        if nameLen > TMPFS_NAME_MAX {
+            return err(ENAMETOOLONG);
+        }
+
+        let mut last = true;
+        for j in i..len {
+            if *path.add(j) != b'/' {
+                last = false;
+                break;
+            }
+        }
+
+        if !S_ISDIR((*curr).mode) {
+            return err(ENOTDIR);
+        }
+
+        walk.parent = curr;
+        walk.name = name;
+        walk.nameLen = nameLen;
+
+        let next = if nameLen == 1 && *name == b'.' {
+            curr
+        } else if nameLen == 2 && *name == b'.' && *name.add(1) == b'.' {
+            (*curr).parent
+        } else {
+            let dirent = tmpfsDirFind(curr, name, nameLen);
+            if dirent.is_null() {
+                walk.inode = null_mut();
+                return if last { 0 } else { err(ENOENT) };
+            }
+            (*dirent).inode
+        };
+
+        if S_ISLNK((*next).mode) && (!last || follow) {
+            if symlinkResolve.is_null() {
+                return err(ELOOP);
+            }
+            tmpfsSymlinkResolve(path, start.saturating_sub(1), (*next).symlink, path.add(i), symlinkResolve);
+            return err(ELOOP);
+        }
+
+        curr = next;
+        walk.inode = curr;
+    }
+
+    0
+}
+
+// Lookup for a name that's about to be created or removed, "." & ".." as the
+// last component are refused
+pub unsafe fn tmpfsWalkParent(
+    tmpfs: *mut Tmpfs,
+    path: *const u8,
+    symlinkResolve: *mut *mut u8,
+    walk: &mut TmpfsWalk,
+) -> usize {
+    let ret = tmpfsWalk(tmpfs, path, false, symlinkResolve, walk);
+    if ret != 0 {
+        return ret;
+    }
+    if walk.inode == (*tmpfs).root
+        || (walk.nameLen == 1 && *walk.name == b'.')
+        || (walk.nameLen == 2 && *walk.name == b'.' && *walk.name.add(1) == b'.')
+    {
+        return err(EINVAL);
+    }
+    0
+}
+
+//
+// Namespace operations (MountPoint callbacks)
+//
+
+unsafe fn tmpfsLookupStat(
+    mnt: *mut MountPoint,
+    filename: *mut u8,
+    target: *mut Stat,
+    follow: bool,
+    symlinkResolve: *mut *mut u8,
+) -> bool {
+    let tmpfs = TMPFS_PTR(mnt);
+    let mut walk: TmpfsWalk = core::mem::zeroed();
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let ret = tmpfsWalk(tmpfs, filename, follow, symlinkResolve, &mut walk);
+    let found = ret == 0 && !walk.inode.is_null();
+    if found {
+        tmpfsStatInternal(walk.inode, target);
+    }
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    found
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsStat(
+    mnt: *mut MountPoint,
+    filename: *mut u8,
+    target: *mut Stat,
+    symlinkResolve: *mut *mut u8,
+) -> bool {
+    tmpfsLookupStat(mnt, filename, target, true, symlinkResolve)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsLstat(
+    mnt: *mut MountPoint,
+    filename: *mut u8,
+    target: *mut Stat,
+    symlinkResolve: *mut *mut u8,
+) -> bool {
+    tmpfsLookupStat(mnt, filename, target, false, symlinkResolve)
+}
+
+// Creates a fresh inode under the last component of `path`
+pub unsafe fn tmpfsCreate(
+    tmpfs: *mut Tmpfs,
+    path: *mut u8,
+    mode: u32,
+    symlinkResolve: *mut *mut u8,
+    out: *mut *mut TmpfsInode,
+) -> usize {
+    let mut walk: TmpfsWalk = core::mem::zeroed();
+    let ret = tmpfsWalkParent(tmpfs, path, symlinkResolve, &mut walk);
+    if ret != 0 {
+        return ret;
+    }
+    if !walk.inode.is_null() {
+        return err(EEXIST);
+    }
+
+    let inode = tmpfsInodeAllocate(tmpfs, mode);
+    if inode.is_null() {
+        return err(ENOSPC);
+    }
+    if S_ISDIR(mode) {
+        (*inode).nlink = 2;
+    }
+    tmpfsDirAdd(walk.parent, walk.name, walk.nameLen, inode);
+
+    *out = inode;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsMkdir(
+    mnt: *mut MountPoint,
+    dirname: *mut u8,
+    mode: u32,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    let tmpfs = TMPFS_PTR(mnt);
+    let mut inode = null_mut();
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let ret = tmpfsCreate(tmpfs, dirname, S_IFDIR | (mode & 0o7777), symlinkResolve, &mut inode);
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsSymlink(
+    mnt: *mut MountPoint,
+    filename: *mut u8,
+    target: *mut u8,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    let tmpfs = TMPFS_PTR(mnt);
+    let targetLen = strlength(target);
+    if targetLen == 0 {
+        return err(ENOENT);
+    }
+    if targetLen >= PAGE_SIZE {
+        return err(ENAMETOOLONG);
+    }
+
+    let mut inode = null_mut();
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let ret = tmpfsCreate(tmpfs, filename, S_IFLNK | 0o777, symlinkResolve, &mut inode);
+    if ret == 0 {
+        (*inode).symlink = strndup(target, targetLen);
+        (*inode).size = targetLen;
+    }
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    ret
+}
+
+// Hard links, both paths are inside this mount (EXDEV is the VFS' job)
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsLink(
+    mnt: *mut MountPoint,
+    filename: *mut u8,
+    target: *mut u8,
+    symlinkResolve: *mut *mut u8,
+    symlinkResolveTarget: *mut *mut u8,
+) -> usize {
+    let tmpfs = TMPFS_PTR(mnt);
+    let mut old: TmpfsWalk = core::mem::zeroed();
+    let mut new: TmpfsWalk = core::mem::zeroed();
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let mut ret = tmpfsWalk(tmpfs, filename, false, symlinkResolve, &mut old);
+    if ret == 0 && old.inode.is_null() {
+        ret = err(ENOENT);
+    }
+    if ret == 0 && S_ISDIR((*old.inode).mode) {
+        ret = err(EPERM);
+    }
+    if ret == 0 && (*old.inode).nlink == u32::MAX {
+        ret = err(EMLINK);
+    }
+    if ret == 0 {
+        ret = tmpfsWalkParent(tmpfs, target, symlinkResolveTarget, &mut new);
+    }
+    if ret == 0 && !new.inode.is_null() {
+        ret = err(EEXIST);
+    }
+    if ret == 0 {
+        (*old.inode).nlink += 1;
+        (*old.inode).ctime = tmpfsNow();
+        tmpfsDirAdd(new.parent, new.name, new.nameLen, old.inode);
+    }
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    ret
+}
+
+// unlink() & rmdir()
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsDelete(
+    mnt: *mut MountPoint,
+    filename: *mut u8,
+    directory: bool,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    let tmpfs = TMPFS_PTR(mnt);
+    let mut walk: TmpfsWalk = core::mem::zeroed();
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let mut ret = tmpfsWalkParent(tmpfs, filename, symlinkResolve, &mut walk);
+    if ret == 0 && walk.inode.is_null() {
+        ret = err(ENOENT);
+    }
+    if ret == 0 {
+        let inode = walk.inode;
+        if directory && !S_ISDIR((*inode).mode) {
+            ret = err(ENOTDIR);
+        } else if !directory && S_ISDIR((*inode).mode) {
+            ret = err(EISDIR);
+        } else if directory && !(*inode).firstDirent.is_null() {
+            ret = err(ENOTEMPTY);
+        } else {
+            let dirent = tmpfsDirFind(walk.parent, walk.name, walk.nameLen);
+            tmpfsDirRemove(walk.parent, dirent);
+            (*inode).nlink = if directory { 0 } else { (*inode).nlink - 1 };
+            (*inode).ctime = tmpfsNow();
+            tmpfsInodePut(tmpfs, inode);
+        }
+    }
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsReadlink(
+    mnt: *mut MountPoint,
+    path: *mut u8,
+    buf: *mut u8,
+    size: i32,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    let tmpfs = TMPFS_PTR(mnt);
+    let mut walk: TmpfsWalk = core::mem::zeroed();
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let mut ret = tmpfsWalk(tmpfs, path, false, symlinkResolve, &mut walk);
+    if ret == 0 && walk.inode.is_null() {
+        ret = err(ENOENT);
+    }
+    if ret == 0 {
+        if !S_ISLNK((*walk.inode).mode) {
+            ret = err(EINVAL);
+        } else {
+            ret = (*walk.inode).size.min(size.max(0) as usize);
+            copy_nonoverlapping((*walk.inode).symlink, buf, ret);
+            (*walk.inode).atime = tmpfsNow();
+        }
+    }
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    ret
+}
+
+// Is `inode` `dir` itself or somewhere below it?
+unsafe fn tmpfsIsAncestor(tmpfs: *mut Tmpfs, dir: *mut TmpfsInode, mut inode: *mut TmpfsInode) -> bool {
+    loop {
+        if inode == dir {
+            return true;
+        }
+        if inode == (*tmpfs).root {
+            return false;
+        }
+        inode = (*inode).parent;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsRename(
+    mnt: *mut MountPoint,
+    oldpath: *mut u8,
+    newpath: *mut u8,
+    symlinkResolve: *mut *mut u8,
+    symlinkResolveTarget: *mut *mut u8,
+) -> usize {
+    let tmpfs = TMPFS_PTR(mnt);
+    let mut old: TmpfsWalk = core::mem::zeroed();
+    let mut new: TmpfsWalk = core::mem::zeroed();
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let ret = 'rename: {
+        let ret = tmpfsWalkParent(tmpfs, oldpath, symlinkResolve, &mut old);
+        if ret != 0 {
+            break 'rename ret;
+        }
+        if old.inode.is_null() {
+            break 'rename err(ENOENT);
+        }
+        let ret = tmpfsWalkParent(tmpfs, newpath, symlinkResolveTarget, &mut new);
+        if ret != 0 {
+            break 'rename ret;
+        }
+
+        let inode = old.inode;
+        let isDir = S_ISDIR((*inode).mode);
+
+        // a directory can't become its own descendant
+        if isDir && tmpfsIsAncestor(tmpfs, inode, new.parent) {
+            break 'rename err(EINVAL);
+        }
+
+        if !new.inode.is_null() {
+            let victim = new.inode;
+            if victim == inode {
+                // hard links to the same file, nothing happens
+                break 'rename 0;
+            }
+            if isDir && !S_ISDIR((*victim).mode) {
+                break 'rename err(ENOTDIR);
+            }
+            if !isDir && S_ISDIR((*victim).mode) {
+                break 'rename err(EISDIR);
+            }
+            if S_ISDIR((*victim).mode) && !(*victim).firstDirent.is_null() {
+                break 'rename err(ENOTEMPTY);
+            }
+
+            let dirent = tmpfsDirFind(new.parent, new.name, new.nameLen);
+            tmpfsDirRemove(new.parent, dirent);
+            (*victim).nlink = if S_ISDIR((*victim).mode) { 0 } else { (*victim).nlink - 1 };
+            (*victim).ctime = tmpfsNow();
+            tmpfsInodePut(tmpfs, victim);
+        }
+
+        let dirent = tmpfsDirFind(old.parent, old.name, old.nameLen);
+        tmpfsDirRemove(old.parent, dirent);
+        tmpfsDirAdd(new.parent, new.name, new.nameLen, inode);
+        (*inode).ctime = tmpfsNow();
+        0
+    };
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    ret
+}
+
+// Attribute changes on a path, `follow` decides about a trailing symlink
+unsafe fn tmpfsAttr(
+    mnt: *mut MountPoint,
+    path: *mut u8,
+    follow: bool,
+    symlinkResolve: *mut *mut u8,
+    apply: &mut dyn FnMut(*mut Tmpfs, *mut TmpfsInode) -> usize,
+) -> usize {
+    let tmpfs = TMPFS_PTR(mnt);
+    let mut walk: TmpfsWalk = core::mem::zeroed();
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let mut ret = tmpfsWalk(tmpfs, path, follow, symlinkResolve, &mut walk);
+    if ret == 0 && walk.inode.is_null() {
+        ret = err(ENOENT);
+    }
+    if ret == 0 {
+        ret = apply(tmpfs, walk.inode);
+    }
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsChmod(
+    mnt: *mut MountPoint,
+    path: *mut u8,
+    mode: u32,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    tmpfsAttr(mnt, path, true, symlinkResolve, &mut |_, inode| {
+        (*inode).mode = ((*inode).mode & S_IFMT) | (mode & 0o7777);
+        (*inode).ctime = tmpfsNow();
+        0
+    })
+}
+
+// -1 (u32::MAX) leaves an id untouched
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsChown(
+    mnt: *mut MountPoint,
+    path: *mut u8,
+    uid: u32,
+    gid: u32,
+    follow: bool,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    tmpfsAttr(mnt, path, follow, symlinkResolve, &mut |_, inode| {
+        if uid != u32::MAX {
+            (*inode).uid = uid;
+        }
+        if gid != u32::MAX {
+            (*inode).gid = gid;
+        }
+        (*inode).ctime = tmpfsNow();
+        0
+    })
+}
+
+// Times in seconds, u64::MAX leaves one untouched (UTIME_OMIT)
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsUtimens(
+    mnt: *mut MountPoint,
+    path: *mut u8,
+    atime: u64,
+    mtime: u64,
+    follow: bool,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    tmpfsAttr(mnt, path, follow, symlinkResolve, &mut |_, inode| {
+        if atime != u64::MAX {
+            (*inode).atime = atime;
+        }
+        if mtime != u64::MAX {
+            (*inode).mtime = mtime;
+        }
+        (*inode).ctime = tmpfsNow();
+        0
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsTruncate(
+    mnt: *mut MountPoint,
+    path: *mut u8,
+    length: usize,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    tmpfsAttr(mnt, path, true, symlinkResolve, &mut |tmpfs, inode| {
+        tmpfsInodeTruncate(tmpfs, inode, length)
+    })
+}
@@ -0,0 +1,587 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::ptr::{copy_nonoverlapping, write_bytes};
+
+use super::tmpfs::*;
+
+//
+// Open file side of tmpfs (read/write/seek/getdents64/mmap)
+//
+
+//
+// Constants
+//
+
+const O_ACCMODE: i32 = 0o3;
+const O_RDONLY: i32 = 0o0;
+const O_CREAT: i32 = 0o100;
+const O_EXCL: i32 = 0o200;
+const O_TRUNC: i32 = 0o1000;
+const O_APPEND: i32 = 0o2000;
+const O_DIRECTORY: i32 = 0o200000;
+const O_NOFOLLOW: i32 = 0o400000;
+
+const SEEK_SET: i32 = 0;
+const SEEK_CUR: i32 = 1;
+const SEEK_END: i32 = 2;
+
+const PROT_WRITE: i32 = 0x2;
+const MAP_SHARED: i32 = 0x01;
+const MAP_FIXED: i32 = 0x10;
+
+const PF_RW: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_SHARED: u64 = 1 << 9;
+
+#[repr(C)]
+#[allow(non_camel_case_types, dead_code)]
+enum DENTS_RES {
+    DENTS_NO_SPACE = 0,
+    DENTS_SUCCESS = 1,
+    DENTS_RETURN = 2,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct linux_dirent64 {
+    _priv: u8,
+}
+
+//
+// External kernel APIs
+//
+
+extern "C" {
+    static mut currentTask: *mut Task;
+
+    fn VirtualMap(virt: usize, phys: usize, flags: u64);
+    fn VirtualToPhysical(virt: usize) -> usize;
+    fn taskInfoPdMappingAddHeld(
+        target: *mut TaskInfoPagedir,
+        start: usize,
+        end: usize,
+        offset: usize,
+        hold: unsafe extern "C" fn(ctx: *mut c_void, take: bool),
+        holdCtx: *mut c_void,
+    );
+
+    fn dentsAdd(
+        buffStart: *mut c_void,
+        dirp: *mut *mut linux_dirent64,
+        allocatedlimit: *mut usize,
+        hardlimit: u32,
+        filename: *const u8,
+        filenameLength: usize,
+        inode: usize,
+        dtype: u8,
+    ) -> DENTS_RES;
+}
+
+#[repr(C)]
+pub struct TaskInfoPagedir {
+    pub LOCK_PD: Spinlock,
+    pub utilizedBy: i32,
+
+    pub heap_start: u64,
+    pub heap_end: u64,
+
+    pub mmap_start: u64,
+    pub mmap_end: u64,
+}
+
+#[repr(C)]
+pub struct Task {
+    pub id: u64,
+    pub infoPd: *mut TaskInfoPagedir,
+}
+
+#[repr(C)]
+pub struct OpenFile {
+    pub mountPoint: *mut MountPoint,
+    pub dir: *mut c_void,
+    pub flags: i32,
+}
+
+#[repr(C)]
+pub struct TmpfsOpenFd {
+    pub inode: *mut TmpfsInode,
+    // byte offset for files, entry index for directories
+    pub ptr: usize,
+}
+
+#[inline]
+unsafe fn TMPFS_FD(fd: *mut OpenFile) -> *mut TmpfsOpenFd {
+    (*fd).dir as *mut TmpfsOpenFd
+}
+
+//
+// Open & close
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsOpen(
+    filename: *mut u8,
+    flags: i32,
+    mode: i32,
+    fd: *mut OpenFile,
+    symlinkResolve: *mut *mut u8,
+) -> usize {
+    let tmpfs = TMPFS_PTR((*fd).mountPoint);
+    let mut walk: TmpfsWalk = core::mem::zeroed();
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let ret = 'open: {
+        let follow = flags & O_NOFOLLOW == 0;
+        let ret = tmpfsWalk(tmpfs, filename, follow, symlinkResolve, &mut walk);
+        if ret != 0 {
+            break 'open ret;
+        }
+
+        let mut inode = walk.inode;
+        if inode.is_null() {
+            if flags & O_CREAT == 0 {
+                break 'open err(ENOENT);
+            }
+            let ret = tmpfsCreate(
+                tmpfs,
+                filename,
+                S_IFREG | (mode as u32 & 0o7777),
+                symlinkResolve,
+                &mut inode,
+            );
+            if ret != 0 {
+                break 'open ret;
+            }
+        } else if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+            break 'open err(EEXIST);
+        }
+
+        if S_ISLNK((*inode).mode) {
+            // only reachable with O_NOFOLLOW
+            break 'open err(ELOOP);
+        }
+        if S_ISDIR((*inode).mode) && flags & O_ACCMODE != O_RDONLY {
+            break 'open err(EISDIR);
+        }
+        if !S_ISDIR((*inode).mode) && flags & O_DIRECTORY != 0 {
+            break 'open err(ENOTDIR);
+        }
+
+        if flags & O_TRUNC != 0 && S_ISREG((*inode).mode) && flags & O_ACCMODE != O_RDONLY {
+            tmpfsInodeTruncate(tmpfs, inode, 0);
+        }
+
+        let tfd = calloc(1, size_of::<TmpfsOpenFd>()) as *mut TmpfsOpenFd;
+        (*tfd).inode = inode;
+        (*inode).openFds += 1;
+        (*fd).dir = tfd as *mut c_void;
+        0
+    };
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsClose(fd: *mut OpenFile) -> bool {
+    let tmpfs = TMPFS_PTR((*fd).mountPoint);
+    let tfd = TMPFS_FD(fd);
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    (*(*tfd).inode).openFds -= 1;
+    tmpfsInodePut(tmpfs, (*tfd).inode);
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    free(tfd as *mut u8);
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsDuplicate(original: *mut OpenFile, orphan: *mut OpenFile) -> bool {
+    let tmpfs = TMPFS_PTR((*original).mountPoint);
+    let tfd = calloc(1, size_of::<TmpfsOpenFd>()) as *mut TmpfsOpenFd;
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    copy_nonoverlapping(TMPFS_FD(original), tfd, 1);
+    (*(*tfd).inode).openFds += 1;
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    (*orphan).dir = tfd as *mut c_void;
+    true
+}
+
+//
+// Data
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsRead(fd: *mut OpenFile, buff: *mut u8, limit: usize) -> usize {
+    let tmpfs = TMPFS_PTR((*fd).mountPoint);
+    let tfd = TMPFS_FD(fd);
+    let inode = (*tfd).inode;
+
+    if S_ISDIR((*inode).mode) {
+        return err(EISDIR);
+    }
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let mut done = 0;
+    while done < limit && (*tfd).ptr < (*inode).size {
+        let index = (*tfd).ptr / PAGE_SIZE;
+        let offset = (*tfd).ptr % PAGE_SIZE;
+        let chunk = (PAGE_SIZE - offset)
+            .min(limit - done)
+            .min((*inode).size - (*tfd).ptr);
+
+        let phys = if index < (*inode).pagesCap {
+            *(*inode).pages.add(index)
+        } else {
+            0
+        };
+        if phys == 0 {
+            write_bytes(buff.add(done), 0, chunk);
+        } else {
+            copy_nonoverlapping(tmpfsPageVirt(phys).add(offset), buff.add(done), chunk);
+        }
+
+        done += chunk;
+        (*tfd).ptr += chunk;
+    }
+    (*inode).atime = tmpfsNow();
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    done
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsWrite(fd: *mut OpenFile, buff: *mut u8, limit: usize) -> usize {
+    let tmpfs = TMPFS_PTR((*fd).mountPoint);
+    let tfd = TMPFS_FD(fd);
+    let inode = (*tfd).inode;
+
+    if S_ISDIR((*inode).mode) {
+        return err(EISDIR);
+    }
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    if (*fd).flags & O_APPEND != 0 {
+        (*tfd).ptr = (*inode).size;
+    }
+
+    let mut done = 0;
+    while done < limit {
+        let index = (*tfd).ptr / PAGE_SIZE;
+        let offset = (*tfd).ptr % PAGE_SIZE;
+        let chunk = (PAGE_SIZE - offset).min(limit - done);
+
+        let phys = tmpfsPageGet(tmpfs, inode, index);
+        if phys == 0 {
+            break;
+        }
+        copy_nonoverlapping(buff.add(done), tmpfsPageVirt(phys).add(offset), chunk);
+
+        done += chunk;
+        (*tfd).ptr += chunk;
+        if (*tfd).ptr > (*inode).size {
+            (*inode).size = (*tfd).ptr;
+        }
+    }
+
+    if done > 0 {
+        let now = tmpfsNow();
+        (*inode).mtime = now;
+        (*inode).ctime = now;
+    }
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    if done == 0 && limit > 0 {
+        return err(ENOSPC);
+    }
+    done
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsSeek(fd: *mut OpenFile, target: usize, offset: i64, whence: i32) -> usize {
+    let tfd = TMPFS_FD(fd);
+    let base = match whence {
+        SEEK_SET => 0,
+        SEEK_CUR => (*tfd).ptr as i64,
+        SEEK_END => (*(*tfd).inode).size as i64,
+        _ => return err(EINVAL),
+    };
+
+    let new = if whence == SEEK_SET { target as i64 } else { base + offset };
+    if new < 0 {
+        return err(EINVAL);
+    }
+    (*tfd).ptr = new as usize;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsGetFilesize(fd: *mut OpenFile) -> usize {
+    (*(*TMPFS_FD(fd)).inode).size
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsStatFd(fd: *mut OpenFile, target: *mut Stat) -> usize {
+    let tmpfs = TMPFS_PTR((*fd).mountPoint);
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    tmpfsStatInternal((*TMPFS_FD(fd)).inode, target);
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsFtruncate(fd: *mut OpenFile, length: usize) -> usize {
+    let tmpfs = TMPFS_PTR((*fd).mountPoint);
+    if (*fd).flags & O_ACCMODE == O_RDONLY {
+        return err(EBADF);
+    }
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let ret = tmpfsInodeTruncate(tmpfs, (*TMPFS_FD(fd)).inode, length);
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+    ret
+}
+
+//
+// Directories
+//
+
+// "." and ".." come first, then the entries in creation order
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsGetdents64(
+    fd: *mut OpenFile,
+    start: *mut linux_dirent64,
+    hardlimit: u32,
+) -> usize {
+    let tmpfs = TMPFS_PTR((*fd).mountPoint);
+    let tfd = TMPFS_FD(fd);
+    let dir = (*tfd).inode;
+
+    if !S_ISDIR((*dir).mode) {
+        return err(ENOTDIR);
+    }
+
+    let mut allocated = 0usize;
+    let mut dirp = start;
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    let mut index = 0;
+    let mut browse = (*dir).firstDirent;
+    loop {
+        let (name, nameLen, ino, dtype): (*const u8, usize, usize, u8) = match index {
+            0 => (b".".as_ptr(), 1, (*dir).ino, CDT_DIR),
+            1 => (b"..".as_ptr(), 2, (*(*dir).parent).ino, CDT_DIR),
+            _ => {
+                if browse.is_null() {
+                    break;
+                }
+                let inode = (*browse).inode;
+                let dtype = if S_ISDIR((*inode).mode) {
+                    CDT_DIR
+                } else if S_ISLNK((*inode).mode) {
+                    CDT_LNK
+                } else {
+                    CDT_REG
+                };
+                let entry = ((*browse).name as *const u8, (*browse).nameLen, (*inode).ino, dtype);
+                browse = (*browse).next;
+                entry
+            }
+        };
+
+        index += 1;
+        if index <= (*tfd).ptr {
+            continue;
+        }
+
+        match dentsAdd(
+            start as *mut c_void,
+            &mut dirp,
+            &mut allocated,
+            hardlimit,
+            name,
+            nameLen,
+            ino,
+            dtype,
+        ) {
+            DENTS_RES::DENTS_NO_SPACE => {
+                allocated = err(EINVAL);
+                break;
+            }
+            DENTS_RES::DENTS_RETURN => break,
+            _ => {}
+        }
+        (*tfd).ptr = index;
+    }
+    (*dir).atime = tmpfsNow();
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    allocated
+}
+
+//
+// mmap
+//
+
+// MAP_SHARED maps the file's own pages (so writes show up everywhere),
+// MAP_PRIVATE gets a copy. Pages past the end of the file are left unmapped.
+#[no_mangle]
+pub unsafe extern "C" fn tmpfsMmap(
+    addr: usize,
+    length: usize,
+    prot: i32,
+    flags: i32,
+    fd: *mut OpenFile,
+    pgoffset: usize,
+) -> usize {
+    let tmpfs = TMPFS_PTR((*fd).mountPoint);
+    let inode = (*TMPFS_FD(fd)).inode;
+    // the offset arrives in bytes, as mmap(2) was handed it
+    if !S_ISREG((*inode).mode) || pgoffset % PAGE_SIZE != 0 {
+        return err(EINVAL);
+    }
+
+    let pages = DivRoundUp(length, PAGE_SIZE);
+    let pd = (*currentTask).infoPd;
+
+    let virt = if flags & MAP_FIXED != 0 {
+        addr
+    } else {
+        spinlockAcquire(&mut (*pd).LOCK_PD);
+        let virt = (*pd).mmap_end as usize;
+        (*pd).mmap_end += (pages * PAGE_SIZE) as u64;
+        spinlockRelease(&mut (*pd).LOCK_PD);
+        virt
+    };
+
+    let shared = flags & MAP_SHARED != 0;
+    let mut pageFlags = PF_USER;
+    if prot & PROT_WRITE != 0 || !shared {
+        pageFlags |= PF_RW;
+    }
+    if shared {
+        pageFlags |= PF_SHARED;
+    }
+
+    spinlockAcquire(&mut (*tmpfs).LOCK_TMPFS);
+    // taken before the first page goes in, so truncate() can't free it
+    // under us; the FileMapping record below owns it from then on
+    if shared {
+        (*inode).mappings += 1;
+        (*tmpfs).mappings += 1;
+    }
+    let filePages = DivRoundUp((*inode).size, PAGE_SIZE);
+    let mut mapped = 0;
+    let mut failed = false;
+    for i in 0..pages {
+        let index = pgoffset / PAGE_SIZE + i;
+        if index >= filePages {
+            break;
+        }
+
+        let phys = if shared {
+            tmpfsPageGet(tmpfs, inode, index)
+        } else {
+            let phys = PhysicalAllocate(1);
+            if phys != 0 {
+                let source = if index < (*inode).pagesCap {
+                    *(*inode).pages.add(index)
+                } else {
+                    0
+                };
+                if source == 0 {
+                    write_bytes(tmpfsPageVirt(phys), 0, PAGE_SIZE);
+                } else {
+                    copy_nonoverlapping(tmpfsPageVirt(source), tmpfsPageVirt(phys), PAGE_SIZE);
+                }
+            }
+            phys
+        };
+        if phys == 0 {
+            failed = true;
+            break;
+        }
+
+        VirtualMap(virt + i * PAGE_SIZE, phys, pageFlags);
+        mapped += 1;
+    }
+
+    if failed {
+        // back out what went in: private copies are ours to free, shared
+        // pages stay with the inode
+        for i in 0..mapped {
+            let page = virt + i * PAGE_SIZE;
+            if !shared {
+                PhysicalFree(VirtualToPhysical(page), 1);
+            }
+            VirtualMap(page, 0, PF_USER);
+        }
+        spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+        if shared {
+            tmpfsMappingHold(inode as *mut c_void, false);
+        }
+        return err(ENOMEM);
+    }
+    (*inode).atime = tmpfsNow();
+    spinlockRelease(&mut (*tmpfs).LOCK_TMPFS);
+
+    if shared {
+        if mapped == 0 {
+            // nothing of the file lies in range, so there's nothing to hold
+            tmpfsMappingHold(inode as *mut c_void, false);
+        } else {
+            spinlockAcquire(&mut (*pd).LOCK_PD);
+            taskInfoPdMappingAddHeld(
+                pd,
+                virt,
+                virt + mapped * PAGE_SIZE,
+                pgoffset,
+                tmpfsMappingHold,
+                inode as *mut c_void,
+            );
+            spinlockRelease(&mut (*pd).LOCK_PD);
+        }
+    }
+
+    virt
+}
+
+//
+// Registration
+//
+
+#[repr(C)]
+pub struct VfsHandlers {
+    pub open: Option<unsafe extern "C" fn(*mut u8, i32, i32, *mut OpenFile, *mut *mut u8) -> usize>,
+    pub close: Option<unsafe extern "C" fn(*mut OpenFile) -> bool>,
+    pub duplicate: Option<unsafe extern "C" fn(*mut OpenFile, *mut OpenFile) -> bool>,
+    pub read: Option<unsafe extern "C" fn(*mut OpenFile, *mut u8, usize) -> usize>,
+    pub write: Option<unsafe extern "C" fn(*mut OpenFile, *mut u8, usize) -> usize>,
+    pub seek: Option<unsafe extern "C" fn(*mut OpenFile, usize, i64, i32) -> usize>,
+    pub stat: Option<unsafe extern "C" fn(*mut OpenFile, *mut Stat) -> usize>,
+    pub getdents64: Option<unsafe extern "C" fn(*mut OpenFile, *mut linux_dirent64, u32) -> usize>,
+    pub getFilesize: Option<unsafe extern "C" fn(*mut OpenFile) -> usize>,
+    pub mmap: Option<unsafe extern "C" fn(usize, usize, i32, i32, *mut OpenFile, usize) -> usize>,
+    pub truncate: Option<unsafe extern "C" fn(*mut OpenFile, usize) -> usize>,
+}
+
+#[no_mangle]
+pub static tmpfsHandlers: VfsHandlers = VfsHandlers {
+    open: Some(tmpfsOpen),
+    close: Some(tmpfsClose),
+    duplicate: Some(tmpfsDuplicate),
+    read: Some(tmpfsRead),
+    write: Some(tmpfsWrite),
+    seek: Some(tmpfsSeek),
+    stat: Some(tmpfsStatFd),
+    getdents64: Some(tmpfsGetdents64),
+    getFilesize: Some(tmpfsGetFilesize),
+    mmap: Some(tmpfsMmap),
+    truncate: Some(tmpfsFtruncate),
+};
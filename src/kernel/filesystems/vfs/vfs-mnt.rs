@@ -1,120 +1,710 @@
-use alloc::{boxed::Box, string::String, vec::Vec};
-use core::cell::RefCell;
-use spin::Mutex;
-use hashbrown::HashMap;
+use alloc::{boxed::Box, ffi::CString, format, string::{String, ToString}, sync::Arc, vec::Vec};
+use core::ffi::c_void;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
 
-/// --- Filesystem Types ---
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum FileSystem {
-    Fat32,
-    Ext2,
-    Dev,
-    Sys,
-    Proc,
+use crate::dev::dev_mount;
+use crate::linux::*;
+use crate::proc::proc_mount;
+use crate::sys::sys_mount;
+use crate::vfs::Statfs;
+
+extern "C" {
+    fn getDiskBytes(buf: *mut u8, lba: u32, sectors: u32);
+
+    fn ext2Mount(mount: *mut MountPoint) -> bool;
+    fn ext2Statfs(mount: *const MountPoint, target: *mut Statfs) -> usize;
+    fn fat32Mount(mount: *mut MountPoint) -> bool;
+    fn fat32Statfs(mount: *const MountPoint, target: *mut Statfs) -> usize;
+    fn tmpfsMount(mount: *mut MountPoint, options: *const u8) -> bool;
+    fn tmpfsRemount(mount: *mut MountPoint, options: *const u8) -> bool;
+    fn tmpfsUnmount(mount: *mut MountPoint);
+    fn tmpfsStatfs(mount: *const MountPoint, target: *mut Statfs) -> usize;
+
+    fn taskInfoFsCwdWithin(path: *const u8, len: usize) -> bool;
 }
 
-/// --- Connector Types ---
+/// --- mount(2) flags ---
+pub const MS_RDONLY: u64 = 1;
+pub const MS_NOSUID: u64 = 2;
+pub const MS_NODEV: u64 = 4;
+pub const MS_NOEXEC: u64 = 8;
+pub const MS_REMOUNT: u64 = 32;
+pub const MS_BIND: u64 = 4096;
+pub const MS_REC: u64 = 16384;
+
+/// Flags that stick to a mountpoint, the rest of them are actions
+pub const MS_PER_MOUNT: u64 = MS_RDONLY | MS_NOSUID | MS_NODEV | MS_NOEXEC;
+
+/// --- umount2(2) flags ---
+pub const MNT_FORCE: u32 = 1;
+pub const MNT_DETACH: u32 = 2;
+pub const MNT_EXPIRE: u32 = 4;
+pub const UMOUNT_NOFOLLOW: u32 = 8;
+
+/// statfs()'s f_flags, the low bits line up with MS_*
+const ST_VALID: i64 = 0x20;
+
+const PROC_SUPER_MAGIC: i64 = 0x9fa0;
+const SYSFS_MAGIC: i64 = 0x6265_6572;
+const TMPFS_MAGIC: i64 = 0x0102_1994;
+const EXT2_MAGIC: u16 = 0xEF53;
+
+/// --- Connector Types (boot time mounts) ---
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Connector {
     Ahci,
     Dev,
     Sys,
     Proc,
+    Tmpfs,
 }
 
 /// --- Master Boot Record Partition ---
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 pub struct MbrPartition {
     pub lba_first_sector: u32,
     pub partition_type: u8,
 }
 
+/// --- Filesystem types ---
+pub struct FilesystemType {
+    pub name: &'static str,
+    /// Block device backed: checks the superblock, used when no type is given
+    pub probe: Option<fn(&MbrPartition) -> bool>,
+    pub mount: fn(&mut MountPoint, Option<&str>) -> bool,
+    pub remount: Option<fn(&MountPoint, Option<&str>) -> bool>,
+    /// Tears the instance down once its last mount is gone
+    pub kill: Option<fn(&MountPoint)>,
+    pub statfs: fn(&MountPoint, &mut Statfs) -> usize,
+}
+
+/// Hands mount options over to the C side as a NUL terminated string
+fn with_c_options<T>(data: Option<&str>, f: impl FnOnce(*const u8) -> T) -> T {
+    let options = data.and_then(|d| CString::new(d).ok());
+    f(options.as_ref().map_or(core::ptr::null(), |o| o.as_ptr() as *const u8))
+}
+
+fn probe_ext2(mbr: &MbrPartition) -> bool {
+    // superblock starts 1024 bytes in, the magic is 56 bytes into it
+    let mut sector = [0u8; 512];
+    unsafe { getDiskBytes(sector.as_mut_ptr(), mbr.lba_first_sector + 2, 1) };
+    u16::from_le_bytes([sector[56], sector[57]]) == EXT2_MAGIC
+}
+
+fn probe_vfat(mbr: &MbrPartition) -> bool {
+    let mut sector = [0u8; 512];
+    unsafe { getDiskBytes(sector.as_mut_ptr(), mbr.lba_first_sector, 1) };
+    sector[510] == 0x55 && sector[511] == 0xAA && &sector[82..90] == b"FAT32   "
+}
+
+fn pseudo_statfs(target: &mut Statfs, magic: i64) -> usize {
+    target.f_type = magic;
+    target.f_bsize = 4096;
+    target.f_frsize = 4096;
+    target.f_namelen = 255;
+    0
+}
+
+static BUILTIN_FILESYSTEMS: [FilesystemType; 6] = [
+    FilesystemType {
+        name: "ext2",
+        probe: Some(probe_ext2),
+        mount: |mnt, _| unsafe { ext2Mount(mnt) },
+        remount: None,
+        kill: None,
+        statfs: |mnt, target| unsafe { ext2Statfs(mnt, target) },
+    },
+    FilesystemType {
+        name: "vfat",
+        probe: Some(probe_vfat),
+        mount: |mnt, _| unsafe { fat32Mount(mnt) },
+        remount: None,
+        kill: None,
+        statfs: |mnt, target| unsafe { fat32Statfs(mnt, target) },
+    },
+    FilesystemType {
+        name: "tmpfs",
+        probe: None,
+        mount: |mnt, data| with_c_options(data, |opts| unsafe { tmpfsMount(mnt, opts) }),
+        // the C side only goes through fs_info, the mountpoint itself is shared
+        remount: Some(|mnt, data| {
+            with_c_options(data, |opts| unsafe { tmpfsRemount(mnt as *const _ as *mut _, opts) })
+        }),
+        kill: Some(|mnt| unsafe { tmpfsUnmount(mnt as *const _ as *mut _) }),
+        statfs: |mnt, target| unsafe { tmpfsStatfs(mnt, target) },
+    },
+    FilesystemType {
+        name: "proc",
+        probe: None,
+        mount: |mnt, _| proc_mount(mnt),
+        remount: None,
+        kill: None,
+        statfs: |_, target| pseudo_statfs(target, PROC_SUPER_MAGIC),
+    },
+    FilesystemType {
+        name: "sysfs",
+        probe: None,
+        mount: |mnt, _| sys_mount(mnt),
+        remount: None,
+        kill: None,
+        statfs: |_, target| pseudo_statfs(target, SYSFS_MAGIC),
+    },
+    FilesystemType {
+        name: "devtmpfs",
+        probe: None,
+        mount: |mnt, _| dev_mount(mnt),
+        remount: None,
+        kill: None,
+        statfs: |_, target| pseudo_statfs(target, TMPFS_MAGIC),
+    },
+];
+
+/// --- Filesystem type registry ---
+static FILESYSTEMS: Mutex<Vec<&'static FilesystemType>> = Mutex::new(Vec::new());
+
+fn filesystems() -> MutexGuard<'static, Vec<&'static FilesystemType>> {
+    let mut filesystems = FILESYSTEMS.lock();
+    if filesystems.is_empty() {
+        filesystems.extend(BUILTIN_FILESYSTEMS.iter());
+    }
+    filesystems
+}
+
+/// Make a filesystem type available to mount(2), names are unique
+pub fn register_filesystem(fs: &'static FilesystemType) -> bool {
+    let mut filesystems = filesystems();
+    if filesystems.iter().any(|f| f.name == fs.name) {
+        return false;
+    }
+    filesystems.push(fs);
+    true
+}
+
+pub fn find_filesystem(name: &str) -> Option<&'static FilesystemType> {
+    filesystems().iter().find(|f| f.name == name).copied()
+}
+
+/// First block device backed filesystem whose superblock matches
+fn probe_filesystem(mbr: &MbrPartition) -> Option<&'static FilesystemType> {
+    filesystems()
+        .iter()
+        .find(|f| f.probe.map_or(false, |probe| probe(mbr)))
+        .copied()
+}
+
+/// --- Block devices ---
+
+/// Entry `index` (0-3) of the system disk's partition table
+fn mbr_partition(index: u8) -> Option<MbrPartition> {
+    if index > 3 {
+        return None;
+    }
+
+    let mut sector = [0u8; 512];
+    unsafe { getDiskBytes(sector.as_mut_ptr(), 0, 1) };
+    if sector[510] != 0x55 || sector[511] != 0xAA {
+        return None;
+    }
+
+    let entry = &sector[446 + index as usize * 16..][..16];
+    if entry[4] == 0 {
+        return None;
+    }
+    Some(MbrPartition {
+        lba_first_sector: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+        partition_type: entry[4],
+    })
+}
+
+/// "/dev/sda" is the whole (and only) disk, "/dev/sdaN" its Nth partition
+fn parse_block_source(source: &str) -> Result<(u32, Option<u8>), usize> {
+    let name = source.strip_prefix("/dev/sd").ok_or(ENOTBLK)?;
+    let disk = match name.bytes().next() {
+        Some(c @ b'a'..=b'z') => (c - b'a') as u32,
+        _ => return Err(ENOTBLK),
+    };
+    if disk != 0 {
+        return Err(ENXIO);
+    }
+
+    match &name[1..] {
+        "" => Ok((disk, None)),
+        number => match number.parse::<u8>() {
+            Ok(n) if n > 0 => Ok((disk, Some(n - 1))),
+            _ => Err(ENXIO),
+        },
+    }
+}
+
+/// "rw,nosuid,..." followed by whatever the filesystem was mounted with
+pub fn mount_options_string(flags: u64, options: &str) -> String {
+    let mut out = String::from(if flags & MS_RDONLY != 0 { "ro" } else { "rw" });
+    if flags & MS_NOSUID != 0 {
+        out.push_str(",nosuid");
+    }
+    if flags & MS_NODEV != 0 {
+        out.push_str(",nodev");
+    }
+    if flags & MS_NOEXEC != 0 {
+        out.push_str(",noexec");
+    }
+    if !options.is_empty() {
+        out.push(',');
+        out.push_str(options);
+    }
+    out
+}
+
+/// --- Superblock, shared between a filesystem and its bind mounts ---
+pub struct Superblock {
+    pub dev: u32,
+    pub fs_type: &'static FilesystemType,
+    pub source: String,
+    pub options: Mutex<String>,
+}
+
 /// --- MountPoint Structure ---
 pub struct MountPoint {
     pub prefix: String,          // Must end with '/'
     pub disk: Option<u32>,
     pub partition: Option<u8>,
-    pub connector: Connector,
-    pub filesystem: FileSystem,
     pub mbr: Option<MbrPartition>,
+    pub fs_info: *mut c_void,
+
+    pub id: u32,
+    pub parent_id: u32,
+    pub flags: AtomicU64,        // MS_PER_MOUNT bits only
+    pub root: String,            // Subtree shown here, empty unless bind mounted
+    pub sb: Arc<Superblock>,
+
+    pub open_files: AtomicUsize,
+    pub detached: AtomicBool,
+}
+
+// fs_info belongs to the filesystem, which does its own locking
+unsafe impl Send for MountPoint {}
+unsafe impl Sync for MountPoint {}
+
+impl MountPoint {
+    pub fn flags(&self) -> u64 {
+        self.flags.load(Ordering::SeqCst)
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.flags() & MS_RDONLY != 0
+    }
+
+    /// EROFS for anything that would modify the filesystem
+    pub fn check_writable(&self) -> Result<(), usize> {
+        if self.read_only() { Err(EROFS) } else { Ok(()) }
+    }
+}
+
+/// --- An open file's hold on its mount ---
+/// Dropped along with the file however that goes away (close(), exit,
+/// close-on-exec...), copies made by dup() & fork() pin it again
+pub struct MountPin(Arc<MountPoint>);
+
+impl Clone for MountPin {
+    fn clone(&self) -> Self {
+        MOUNTS.file_opened(&self.0);
+        MountPin(self.0.clone())
+    }
+}
+
+impl Drop for MountPin {
+    fn drop(&mut self) {
+        MOUNTS.file_closed(&self.0);
+    }
+}
+
+impl core::ops::Deref for MountPin {
+    type Target = MountPoint;
+
+    fn deref(&self) -> &MountPoint {
+        &self.0
+    }
 }
 
 /// --- Global mount points list (thread-safe) ---
+/// Lookups hand out Arc clones, so a mountpoint outlives its umount for as
+/// long as someone is still looking at it
 pub struct MountManager {
-    mounts: Mutex<Vec<Box<MountPoint>>>,
+    mounts: Mutex<Vec<Arc<MountPoint>>>,
+    /// Lazily unmounted ones still kept alive by open files
+    detached: Mutex<Vec<Arc<MountPoint>>>,
+    next_id: AtomicU32,
+    next_dev: AtomicU32,
 }
 
+pub static MOUNTS: MountManager = MountManager::new();
+
 impl MountManager {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         MountManager {
             mounts: Mutex::new(Vec::new()),
+            detached: Mutex::new(Vec::new()),
+            next_id: AtomicU32::new(1),
+            next_dev: AtomicU32::new(1),
         }
     }
 
-    /// Mount a new filesystem
+    /// Boot time mounts, AHCI partitions get their filesystem probed
     pub fn mount(
         &self,
         prefix: &str,
         connector: Connector,
         disk: Option<u32>,
         partition: Option<u8>,
-    ) -> Option<&Box<MountPoint>> {
-        let mut mounts = self.mounts.lock();
+    ) -> bool {
+        let (source, fs_type) = match connector {
+            Connector::Ahci => {
+                if disk.is_none() {
+                    return false;
+                }
+                (format!("/dev/sda{}", partition.map_or(0, |p| p + 1)), None)
+            }
+            Connector::Dev => ("devtmpfs".to_string(), Some("devtmpfs")),
+            Connector::Sys => ("sysfs".to_string(), Some("sysfs")),
+            Connector::Proc => ("proc".to_string(), Some("proc")),
+            Connector::Tmpfs => ("tmpfs".to_string(), Some("tmpfs")),
+        };
+
+        self.mount_new(&source, prefix, fs_type, 0, None).is_ok()
+    }
+
+    /// mount(2), `target` is an already sanitized absolute path
+    pub fn mount_user(
+        &self,
+        source: Option<&str>,
+        target: &str,
+        fs_type: Option<&str>,
+        flags: u64,
+        data: Option<&str>,
+    ) -> Result<(), usize> {
+        if flags & MS_REMOUNT != 0 {
+            return self.remount(target, flags, data);
+        }
+        if flags & MS_BIND != 0 {
+            return self.bind(source.ok_or(EFAULT)?, target, flags);
+        }
+
+        let source = source.ok_or(EFAULT)?;
+        self.mount_new(source, target, Some(fs_type.ok_or(EINVAL)?), flags, data)
+    }
 
-        let mut mount = Box::new(MountPoint {
-            prefix: prefix.to_string(),
+    fn prefix_of(target: &str) -> String {
+        if target.ends_with('/') {
+            target.to_string()
+        } else {
+            format!("{}/", target)
+        }
+    }
+
+    fn parent_id(mounts: &[Arc<MountPoint>], prefix: &str) -> Option<u32> {
+        mounts
+            .iter()
+            .filter(|m| prefix.starts_with(&m.prefix) && m.prefix != prefix)
+            .max_by_key(|m| m.prefix.len())
+            .map(|m| m.id)
+    }
+
+    fn mount_new(
+        &self,
+        source: &str,
+        target: &str,
+        fs_type: Option<&str>,
+        flags: u64,
+        data: Option<&str>,
+    ) -> Result<(), usize> {
+        let prefix = Self::prefix_of(target);
+        if self.mounts.lock().iter().any(|m| m.prefix == prefix) {
+            return Err(EBUSY);
+        }
+
+        let fs_type = match fs_type {
+            Some(name) => Some(find_filesystem(name).ok_or(ENODEV)?),
+            None => None,
+        };
+
+        // block devices need their partition & superblock looked at first
+        let mut disk = None;
+        let mut partition = None;
+        let mut mbr = None;
+        if fs_type.map_or(true, |f| f.probe.is_some()) {
+            let (d, p) = parse_block_source(source)?;
+            let entry = match p {
+                Some(index) => mbr_partition(index).ok_or(ENXIO)?,
+                None => MbrPartition { lba_first_sector: 0, partition_type: 0 },
+            };
+            disk = Some(d);
+            partition = p;
+            mbr = Some(entry);
+        }
+        let fs_type = match fs_type {
+            Some(f) if f.probe.map_or(true, |probe| probe(mbr.as_ref().unwrap())) => f,
+            // wrong fs type or bad superblock
+            Some(_) => return Err(EINVAL),
+            None => probe_filesystem(mbr.as_ref().unwrap()).ok_or(EINVAL)?,
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut mount = MountPoint {
+            prefix: prefix.clone(),
             disk,
             partition,
-            connector,
-            filesystem: match connector {
-                Connector::Ahci => FileSystem::Fat32, // placeholder
-                Connector::Dev => FileSystem::Dev,
-                Connector::Sys => FileSystem::Sys,
-                Connector::Proc => FileSystem::Proc,
-            },
-            mbr: None,
-        });
+            mbr,
+            fs_info: core::ptr::null_mut(),
+            id,
+            parent_id: id,
+            flags: AtomicU64::new(flags & MS_PER_MOUNT),
+            root: String::new(),
+            sb: Arc::new(Superblock {
+                dev: self.next_dev.fetch_add(1, Ordering::SeqCst),
+                fs_type,
+                source: source.to_string(),
+                options: Mutex::new(data.unwrap_or("").to_string()),
+            }),
+            open_files: AtomicUsize::new(0),
+            detached: AtomicBool::new(false),
+        };
+
+        if !(fs_type.mount)(&mut mount, data) {
+            return Err(EINVAL);
+        }
+
+        let mut mounts = self.mounts.lock();
+        mount.parent_id = Self::parent_id(&mounts, &prefix).unwrap_or(id);
+        mounts.push(Arc::new(mount));
+        Ok(())
+    }
+
+    /// MS_REMOUNT, with MS_BIND only the per-mount flags change
+    fn remount(&self, target: &str, flags: u64, data: Option<&str>) -> Result<(), usize> {
+        let prefix = Self::prefix_of(target);
+        let mounts = self.mounts.lock();
+        let mount = mounts.iter().find(|m| m.prefix == prefix).ok_or(EINVAL)?;
 
-        // Here we would check the disk type and call mount functions
-        // For example:
-        if connector == Connector::Ahci {
-            if let Some(d) = disk {
-                // Simulate reading MBR
-                let mbr = MbrPartition {
-                    lba_first_sector: 2048,
-                    partition_type: 0x83,
-                };
-                mount.mbr = Some(mbr);
-                // Detect FS type
-                if mbr.partition_type == 0x83 {
-                    mount.filesystem = FileSystem::Ext2;
-                } else {
-                    mount.filesystem = FileSystem::Fat32;
+        if flags & MS_BIND == 0 {
+            if let Some(data) = data {
+                if let Some(remount) = mount.sb.fs_type.remount {
+                    if !remount(mount, Some(data)) {
+                        return Err(EINVAL);
+                    }
                 }
-            } else {
-                return None;
+                *mount.sb.options.lock() = data.to_string();
             }
         }
 
+        mount.flags.store(flags & MS_PER_MOUNT, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// MS_BIND, `source` is an already sanitized absolute path
+    fn bind(&self, source: &str, target: &str, flags: u64) -> Result<(), usize> {
+        let prefix = Self::prefix_of(target);
+        let mut mounts = self.mounts.lock();
+        if mounts.iter().any(|m| m.prefix == prefix) {
+            return Err(EBUSY);
+        }
+
+        let from = mounts
+            .iter()
+            .filter(|m| Self::prefix_of(source).starts_with(&m.prefix))
+            .max_by_key(|m| m.prefix.len())
+            .ok_or(ENOENT)?;
+        let subpath = &source[(from.prefix.len() - 1).min(source.len())..];
+        let root = format!("{}{}", from.root, subpath.trim_end_matches('/'));
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mount = Arc::new(MountPoint {
+            prefix: prefix.clone(),
+            disk: from.disk,
+            partition: from.partition,
+            mbr: from.mbr,
+            fs_info: from.fs_info,
+            id,
+            parent_id: Self::parent_id(&mounts, &prefix).unwrap_or(id),
+            flags: AtomicU64::new(flags & MS_PER_MOUNT),
+            root,
+            sb: from.sb.clone(),
+            open_files: AtomicUsize::new(0),
+            detached: AtomicBool::new(false),
+        });
+
         mounts.push(mount);
-        mounts.last()
+        Ok(())
     }
 
-    /// Unmount a filesystem
-    pub fn unmount(&self, mount: &MountPoint) -> bool {
+    /// umount2(2), MNT_DETACH takes the whole subtree out of the namespace
+    /// right away and lets each mount go once its last open file closes
+    pub fn unmount(&self, target: &str, flags: u32) -> Result<(), usize> {
+        if flags & !(MNT_FORCE | MNT_DETACH | MNT_EXPIRE | UMOUNT_NOFOLLOW) != 0
+            || (flags & MNT_EXPIRE != 0 && flags & (MNT_FORCE | MNT_DETACH) != 0)
+        {
+            return Err(EINVAL);
+        }
+
+        let prefix = Self::prefix_of(target);
+        if prefix == "/" {
+            return Err(EBUSY);
+        }
+
         let mut mounts = self.mounts.lock();
-        if let Some(pos) = mounts.iter().position(|m| m.prefix == mount.prefix) {
-            mounts.remove(pos);
-            true
+        if !mounts.iter().any(|m| m.prefix == prefix) {
+            return Err(EINVAL);
+        }
+
+        if flags & MNT_DETACH == 0 {
+            let pos = mounts.iter().position(|m| m.prefix == prefix).unwrap();
+            let nested = mounts
+                .iter()
+                .any(|m| m.prefix != prefix && m.prefix.starts_with(&prefix));
+            // no chroot() here, every task's root is / which never unmounts
+            let cwd = &prefix[..prefix.len() - 1];
+            if nested
+                || mounts[pos].open_files.load(Ordering::SeqCst) > 0
+                || unsafe { taskInfoFsCwdWithin(cwd.as_ptr(), cwd.len()) }
+            {
+                return Err(EBUSY);
+            }
+
+            let mount = mounts.remove(pos);
+            drop(mounts);
+            self.release(mount);
+            return Ok(());
+        }
+
+        let (subtree, rest): (Vec<_>, Vec<_>) =
+            mounts.drain(..).partition(|m| m.prefix.starts_with(&prefix));
+        *mounts = rest;
+        drop(mounts);
+
+        for mount in subtree {
+            mount.detached.store(true, Ordering::SeqCst);
+            // file_closed() looks for it on the list under the same lock
+            let mut detached = self.detached.lock();
+            if mount.open_files.load(Ordering::SeqCst) == 0 {
+                drop(detached);
+                self.release(mount);
+            } else {
+                detached.push(mount);
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&self, mount: Arc<MountPoint>) {
+        if Arc::strong_count(&mount.sb) == 1 {
+            if let Some(kill) = mount.sb.fs_type.kill {
+                kill(&mount);
+            }
+        }
+    }
+
+    /// Pins the mount an open file lives on, None if it's already gone
+    pub fn pin(&self, mount: &MountPoint) -> Option<MountPin> {
+        let mounts = self.mounts.lock();
+        let mount = mounts.iter().find(|m| m.id == mount.id)?.clone();
+        mount.open_files.fetch_add(1, Ordering::SeqCst);
+        Some(MountPin(mount))
+    }
+
+    /// Open files pin their mount, see unmount(). Taken under the list lock
+    /// so an umount either sees the pin or has already taken the mount away.
+    fn file_opened(&self, mount: &MountPoint) {
+        let _mounts = self.mounts.lock();
+        mount.open_files.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn file_closed(&self, mount: &MountPoint) {
+        let mut detached = self.detached.lock();
+        if mount.open_files.fetch_sub(1, Ordering::SeqCst) != 1
+            || !mount.detached.load(Ordering::SeqCst)
+        {
+            return;
+        }
+
+        if let Some(pos) = detached.iter().position(|m| m.id == mount.id) {
+            let mount = detached.remove(pos);
+            drop(detached);
+            self.release(mount);
+        }
+    }
+
+    /// statfs(2) & fstatfs(2)
+    pub fn statfs(&self, mount: &MountPoint, target: &mut Statfs) -> Result<(), usize> {
+        *target = Statfs::default();
+        let ret = (mount.sb.fs_type.statfs)(mount, target);
+        if ret != 0 {
+            return Err(ret);
+        }
+
+        target.f_fsid = [mount.sb.dev as i32, 0];
+        target.f_flags = ST_VALID | (mount.flags() & MS_PER_MOUNT) as i64;
+        Ok(())
+    }
+
+    /// /proc/mounts
+    pub fn format_mounts(&self) -> String {
+        let mounts = self.mounts.lock();
+        let mut out = String::new();
+        for m in mounts.iter() {
+            out.push_str(&format!(
+                "{} {} {} {} 0 0\n",
+                m.sb.source,
+                Self::target_of(m),
+                m.sb.fs_type.name,
+                mount_options_string(m.flags(), &m.sb.options.lock())
+            ));
+        }
+        out
+    }
+
+    /// /proc/[pid]/mountinfo, there's a single mount namespace
+    pub fn format_mountinfo(&self) -> String {
+        let mounts = self.mounts.lock();
+        let mut out = String::new();
+        for m in mounts.iter() {
+            let root = if m.root.is_empty() { "/" } else { m.root.as_str() };
+            let options = m.sb.options.lock();
+            let super_options = if options.is_empty() {
+                String::from(if m.read_only() { "ro" } else { "rw" })
+            } else {
+                format!("{},{}", if m.read_only() { "ro" } else { "rw" }, options)
+            };
+            out.push_str(&format!(
+                "{} {} 0:{} {} {} {} - {} {} {}\n",
+                m.id,
+                m.parent_id,
+                m.sb.dev,
+                root,
+                Self::target_of(m),
+                mount_options_string(m.flags(), ""),
+                m.sb.fs_type.name,
+                m.sb.source,
+                super_options
+            ));
+        }
+        out
+    }
+
+    fn target_of(mount: &MountPoint) -> &str {
+        if mount.prefix.len() > 1 {
+            &mount.prefix[..mount.prefix.len() - 1]
         } else {
-            false
+            "/"
         }
     }
 
     /// Determine the mount point that best matches a filename
-    pub fn determine_mount_point(&self, filename: &str) -> Option<&MountPoint> {
-        let mounts = self.mounts.lock();
-        let mut best: Option<&MountPoint> = None;
+    pub fn determine_mount_point(&self, filename: &str) -> Option<Arc<MountPoint>> {
+        Self::best_match(&self.mounts.lock(), filename).cloned()
+    }
+
+    fn best_match<'a>(mounts: &'a [Arc<MountPoint>], filename: &str) -> Option<&'a Arc<MountPoint>> {
+        let mut best = None;
         let mut largest_len = 0;
 
         for mount in mounts.iter() {
@@ -137,7 +727,12 @@ impl MountManager {
         let prefix_len = mount.prefix.len() - 1; // remove trailing '/'
 
         if symlink.starts_with('/') {
-            Some(format!("{}{}", &mount.prefix[..prefix_len], symlink))
+            // bind mounts only show a subtree, anything outside it stays hidden
+            let inside = symlink.strip_prefix(mount.root.as_str())?;
+            if !mount.root.is_empty() && !inside.is_empty() && !inside.starts_with('/') {
+                return None;
+            }
+            Some(format!("{}{}", &mount.prefix[..prefix_len], inside))
         } else if symlink.starts_with('!') {
             Some(symlink[1..].to_string())
         } else {
@@ -145,3 +740,4 @@ impl MountManager {
         }
     }
 }
+
@@ -1,17 +1,28 @@
 /// Generic file path/name sanitization
 /// (C) 2025  kevin danthew
 
+use alloc::borrow::Cow;
+
 /// The root path fallback
 pub const ROOT: &str = "/";
 
 /// Strip the mountpoint prefix from a filename.
-/// Returns a slice of the original string.
-pub fn fs_strip_mountpoint<'a>(filename: &'a str, mnt: &MountPoint) -> &'a str {
+/// Borrows from the original string unless the mount is a bind mount, whose
+/// root subtree has to be put in front.
+pub fn fs_strip_mountpoint<'a>(filename: &'a str, mnt: &MountPoint) -> Cow<'a, str> {
     let prefix_len = mnt.prefix.len() - 1; // remove trailing slash
-    if filename.len() > prefix_len {
+    let stripped = if filename.len() > prefix_len {
         &filename[prefix_len..]
     } else {
         ROOT
+    };
+
+    if mnt.root.is_empty() {
+        Cow::Borrowed(stripped)
+    } else if stripped == ROOT {
+        Cow::Owned(mnt.root.clone())
+    } else {
+        Cow::Owned(format!("{}{}", mnt.root, stripped))
     }
 }
 
@@ -91,6 +102,7 @@ pub fn fs_sanitize(prefix: &str, filename: &str) -> String {
 /// MountPoint stub for demonstration
 pub struct MountPoint {
     pub prefix: String,
+    pub root: String,
 }
 
 #[cfg(test)]
@@ -106,4 +118,14 @@ mod tests {
         assert_eq!(fs_sanitize(cwd, "a//b///c/"), "/home/user/a/b/c");
         assert_eq!(fs_sanitize(cwd, "a/../b"), "/home/user/b");
     }
+
+    #[test]
+    fn test_strip_mountpoint() {
+        let plain = MountPoint { prefix: "/mnt/".into(), root: String::new() };
+        let bind = MountPoint { prefix: "/mnt/".into(), root: "/srv/www".into() };
+        assert_eq!(fs_strip_mountpoint("/mnt/a/b", &plain), "/a/b");
+        assert_eq!(fs_strip_mountpoint("/mnt", &plain), "/");
+        assert_eq!(fs_strip_mountpoint("/mnt/a/b", &bind), "/srv/www/a/b");
+        assert_eq!(fs_strip_mountpoint("/mnt", &bind), "/srv/www");
+    }
 }
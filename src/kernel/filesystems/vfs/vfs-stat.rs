@@ -10,6 +10,24 @@ pub struct Stat {
     pub is_dir: bool,
 }
 
+/// statfs() result, filled in by the filesystem (laid out like struct statfs)
+#[repr(C)]
+#[derive(Default)]
+pub struct Statfs {
+    pub f_type: i64,
+    pub f_bsize: i64,
+    pub f_blocks: u64,
+    pub f_bfree: u64,
+    pub f_bavail: u64,
+    pub f_files: u64,
+    pub f_ffree: u64,
+    pub f_fsid: [i32; 2],
+    pub f_namelen: i64,
+    pub f_frsize: i64,
+    pub f_flags: i64,
+    pub f_spare: [i64; 4],
+}
+
 /// Generic fsStat for an already-open file
 pub fn fs_stat(fd: &OpenFile, target: &mut Stat) -> bool {
     if let Some(stat_handler) = fd.handlers.stat {
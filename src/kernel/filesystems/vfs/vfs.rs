@@ -3,6 +3,7 @@ use core::cell::RefCell;
 use spin::Mutex;
 use alloc::rc::Rc;
 use hashbrown::HashMap;
+use crate::vfs::MountPin;
 
 /// --- VFS layer types ---
 pub type FileId = usize;
@@ -17,6 +18,10 @@ pub struct OpenFile {
     pub close_flags: VfsCloseFlag,
     pub handlers: Option<Rc<VfsHandlers>>,
     pub mount_point: Option<Rc<MountPoint>>,
+    /// Keeps umount() away until the last copy of the file is gone
+    pub mount_pin: Option<MountPin>,
+    /// Absolute path it was opened by, names its mappings in core dumps
+    pub path: Option<String>,
     pub lock_operations: Mutex<()>,
     pub tmp1: u32,
 }
@@ -32,6 +37,8 @@ impl OpenFile {
             close_flags: VfsCloseFlag::empty(),
             handlers: None,
             mount_point: None,
+            mount_pin: None,
+            path: None,
             lock_operations: Mutex::new(()),
             tmp1: 0,
         }
@@ -45,6 +52,8 @@ impl OpenFile {
         self.close_flags = other.close_flags;
         self.handlers = other.handlers.clone();
         self.mount_point = other.mount_point.clone();
+        self.mount_pin = other.mount_pin.clone();
+        self.path = other.path.clone();
         self.tmp1 = other.tmp1;
     }
 }
@@ -98,6 +107,13 @@ pub struct MountPoint {
     pub mkdir: Option<fn(&MountPoint, &str, u32) -> usize>,
     pub delete: Option<fn(&MountPoint, &str, bool) -> usize>,
     pub link: Option<fn(&MountPoint, &str, &str) -> usize>,
+    pub symlink: Option<fn(&MountPoint, &str, &str) -> usize>,
+    pub rename: Option<fn(&MountPoint, &str, &str) -> usize>,
+    pub chmod: Option<fn(&MountPoint, &str, u32) -> usize>,
+    pub chown: Option<fn(&MountPoint, &str, u32, u32, bool) -> usize>,
+    pub utimens: Option<fn(&MountPoint, &str, u64, u64, bool) -> usize>,
+    pub truncate: Option<fn(&MountPoint, &str, usize) -> usize>,
+    pub statfs: Option<fn(&MountPoint, &mut Statfs) -> usize>,
 }
 
 /// --- Simple FakeFS layer ---
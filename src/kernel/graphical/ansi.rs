@@ -14,41 +14,55 @@ use core::ffi::c_int;
 extern "C" {
     fn eraseBull();
     fn updateBull();
-    fn changeBg(r: u8, g: u8, b: u8);
-    fn changeTextColor(r: u8, g: u8, b: u8);
-    fn drawRect(x: i64, y: i64, w: i64, h: i64, r: u8, g: u8, b: u8);
+    fn changeBg(r: c_int, g: c_int, b: c_int);
+    fn changeTextColor(r: c_int, g: c_int, b: c_int);
+    fn changeAttr(attr: u8);
     fn clearScreen();
+
+    fn consoleCols() -> u32;
+    fn consoleRows() -> u32;
+    fn consoleCursorCol() -> u32;
+    fn consoleCursorRow() -> u32;
+    fn consoleCursorSet(col: u32, row: u32);
+    fn consoleEraseCells(row: u32, from: u32, to: u32);
+    fn consoleIndex();
+    fn consoleReverseIndex();
+    fn consoleScrollUp(count: u32);
+    fn consoleScrollDown(count: u32);
+    fn consoleSetRegion(top: u32, bottom: u32);
+    fn consoleInsertLines(count: u32);
+    fn consoleDeleteLines(count: u32);
+    fn consoleInsertChars(count: u32);
+    fn consoleDeleteChars(count: u32);
+    fn consoleAltScreen(enable: bool);
+    fn consoleScrollbackClear();
+
+    // answers (DSR, DA) go back through the terminal's input queue
+    fn vtReply(buff: *const u8, len: usize);
 }
 
 // framebuffer / console globals
 extern "C" {
     static mut cursorHidden: bool;
 
-    static mut width: i64;
-    static mut height: i64;
-
-    static fb: FrameBuffer;
+    static mut width: u32;
 
-    static bg_color: [u8; 3];
-
-    static TTY_CHARACTER_WIDTH: i64;
-    static TTY_CHARACTER_HEIGHT: i64;
-}
+    static mut textcolor: [c_int; 3];
+    static mut bg_color: [c_int; 3];
+    static mut textattr: u8;
 
-#[repr(C)]
-pub struct FrameBuffer {
-    pub width: i64,
-    pub height: i64,
+    static mut consoleAutowrap: bool;
+    static mut consoleCursorKeys: bool;
+    static mut scrollTop: u32;
+    static mut scrollBottom: u32;
 }
 
-// -----------------------------
-// Helpers
-// -----------------------------
+const ATTR_BOLD: u8 = 1 << 0;
+const ATTR_UNDERLINE: u8 = 1 << 1;
+const ATTR_REVERSE: u8 = 1 << 2;
 
-#[inline]
-fn is_valid_number(c: i32) -> bool {
-    c >= b'0' as i32 && c <= b'9' as i32
-}
+const DEFAULT_FG: [c_int; 3] = [255, 255, 255];
+const DEFAULT_BG: [c_int; 3] = [0, 0, 0];
 
 // -----------------------------
 // ANSI color table
@@ -59,7 +73,8 @@ struct ANSIColor {
     rgb: [u8; 3],
 }
 
-static ANSI_COLORS: [ANSIColor; 18] = [
+// 0-7 normal, 8-15 bright (bold)
+static ANSI_COLORS: [ANSIColor; 16] = [
     ANSIColor { rgb: [0, 0, 0] },
     ANSIColor { rgb: [170, 0, 0] },
     ANSIColor { rgb: [0, 170, 0] },
@@ -68,8 +83,6 @@ static ANSI_COLORS: [ANSIColor; 18] = [
     ANSIColor { rgb: [170, 0, 170] },
     ANSIColor { rgb: [0, 170, 170] },
     ANSIColor { rgb: [170, 170, 170] },
-    ANSIColor { rgb: [69, 69, 69] },
-    ANSIColor { rgb: [69, 69, 69] },
     ANSIColor { rgb: [85, 85, 85] },
     ANSIColor { rgb: [255, 85, 85] },
     ANSIColor { rgb: [85, 255, 85] },
@@ -80,198 +93,539 @@ static ANSI_COLORS: [ANSIColor; 18] = [
     ANSIColor { rgb: [255, 255, 255] },
 ];
 
+// xterm 256 colors: the 16 above, a 6x6x6 cube and a grayscale ramp
+fn paletteColor(index: u32) -> [c_int; 3] {
+    match index {
+        0..=15 => {
+            let rgb = ANSI_COLORS[index as usize].rgb;
+            [rgb[0] as c_int, rgb[1] as c_int, rgb[2] as c_int]
+        }
+        16..=231 => {
+            const LEVELS: [c_int; 6] = [0, 95, 135, 175, 215, 255];
+            let cube = index - 16;
+            [
+                LEVELS[(cube / 36) as usize],
+                LEVELS[(cube / 6 % 6) as usize],
+                LEVELS[(cube % 6) as usize],
+            ]
+        }
+        _ => {
+            let level = (8 + (index.min(255) - 232) * 10) as c_int;
+            [level, level, level]
+        }
+    }
+}
+
 // -----------------------------
 // ANSI parser state
 // -----------------------------
 
-static mut asciiQuestionmark: bool = true;
-static mut asciiEscaping: bool = false;
-static mut asciiInside: bool = false;
+const ANSI_PARAMS_MAX: usize = 16;
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq)]
+pub enum AnsiParse {
+    Ground,
+    Escape,
+    // ESC ( B and friends, the next byte is swallowed
+    EscapeIntermediate,
+    Csi,
+    // OSC (window titles...), ignored up to BEL or ST
+    Osc,
+    OscEscape,
+}
+
+// DECSC / DECRC
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct AnsiCursor {
+    pub col: u32,
+    pub row: u32,
+    pub fg: [c_int; 3],
+    pub bg: [c_int; 3],
+    pub attr: u8,
+    pub fgPalette: i32,
+}
+
+// Parser state, per terminal (see vtBind())
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct AnsiState {
+    pub parse: AnsiParse,
+    // '?', '>' or '=' right after the CSI
+    pub private: u8,
+    pub intermediate: u8,
+    pub params: [u32; ANSI_PARAMS_MAX],
+    pub paramCount: usize,
+
+    // palette index of the foreground, so bold can brighten it (-1 for
+    // default and direct colors)
+    pub fgPalette: i32,
+
+    pub saved: AnsiCursor,
+}
+
+const ANSI_STATE_DEFAULT: AnsiState = AnsiState {
+    parse: AnsiParse::Ground,
+    private: 0,
+    intermediate: 0,
+    params: [0; ANSI_PARAMS_MAX],
+    paramCount: 0,
+    fgPalette: -1,
+    saved: AnsiCursor {
+        col: 0,
+        row: 0,
+        fg: DEFAULT_FG,
+        bg: DEFAULT_BG,
+        attr: 0,
+        fgPalette: -1,
+    },
+};
+
+static mut ANSI: AnsiState = ANSI_STATE_DEFAULT;
+
+#[no_mangle]
+pub unsafe extern "C" fn ansiStateInit(state: *mut AnsiState) {
+    *state = ANSI_STATE_DEFAULT;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ansiSave(state: *mut AnsiState) {
+    *state = ANSI;
+}
 
-static mut asciiChar1: i64 = 0;
-static mut asciiFirstDone: bool = false;
-static mut asciiChar2: i64 = 0;
+#[no_mangle]
+pub unsafe extern "C" fn ansiRestore(state: *const AnsiState) {
+    ANSI = *state;
+}
 
 // -----------------------------
-// Core logic
+// Replies
 // -----------------------------
 
-/// returns true = done with escape sequence
-unsafe fn asciiProcess(charnum: i32) -> bool {
-    if !asciiFirstDone && charnum == b'?' as i32 {
-        asciiQuestionmark = true;
-        return false;
+// Decimal, for reports
+fn formatNumber(out: &mut [u8], at: &mut usize, mut value: u32) {
+    let mut digits = [0u8; 10];
+    let mut count = 0;
+    loop {
+        digits[count] = b'0' + (value % 10) as u8;
+        count += 1;
+        value /= 10;
+        if value == 0 {
+            break;
+        }
     }
-
-    if is_valid_number(charnum) {
-        let target = (charnum - b'0' as i32) as i64;
-        if !asciiFirstDone {
-            asciiChar1 = asciiChar1 * 10 + target;
-        } else {
-            asciiChar2 = asciiChar2 * 10 + target;
+    for i in (0..count).rev() {
+        if *at < out.len() {
+            out[*at] = digits[i];
+            *at += 1;
         }
-        return false;
     }
+}
 
-    if charnum == b';' as i32 {
-        if asciiFirstDone {
-            return true;
-        }
-        asciiFirstDone = true;
-        return false;
+unsafe fn reply(buff: &[u8]) {
+    vtReply(buff.as_ptr(), buff.len());
+}
+
+// CPR, ESC [ row ; col R
+unsafe fn replyCursorPosition(private: bool) {
+    let mut out = [0u8; 32];
+    let mut at = 0;
+    out[at] = 0x1B;
+    out[at + 1] = b'[';
+    at += 2;
+    if private {
+        out[at] = b'?';
+        at += 1;
     }
+    formatNumber(&mut out, &mut at, consoleCursorRow() + 1);
+    out[at] = b';';
+    at += 1;
+    formatNumber(&mut out, &mut at, consoleCursorCol() + 1);
+    out[at] = b'R';
+    at += 1;
+    reply(&out[..at]);
+}
 
-    eraseBull();
+// -----------------------------
+// Core logic
+// -----------------------------
 
-    match charnum as u8 as char {
-        'l' => {
-            if asciiChar1 == 25 && asciiQuestionmark {
-                cursorHidden = true;
+#[inline]
+unsafe fn param(index: usize, default: u32) -> u32 {
+    if index < ANSI.paramCount && ANSI.params[index] != 0 {
+        ANSI.params[index]
+    } else {
+        default
+    }
+}
+
+unsafe fn saveCursor() {
+    ANSI.saved = AnsiCursor {
+        col: consoleCursorCol(),
+        row: consoleCursorRow(),
+        fg: textcolor,
+        bg: bg_color,
+        attr: textattr,
+        fgPalette: ANSI.fgPalette,
+    };
+}
+
+unsafe fn restoreCursor() {
+    let saved = ANSI.saved;
+    consoleCursorSet(saved.col, saved.row);
+    changeTextColor(saved.fg[0], saved.fg[1], saved.fg[2]);
+    changeBg(saved.bg[0], saved.bg[1], saved.bg[2]);
+    changeAttr(saved.attr);
+    ANSI.fgPalette = saved.fgPalette;
+}
+
+// RIS
+unsafe fn resetTerminal() {
+    changeTextColor(DEFAULT_FG[0], DEFAULT_FG[1], DEFAULT_FG[2]);
+    changeBg(DEFAULT_BG[0], DEFAULT_BG[1], DEFAULT_BG[2]);
+    changeAttr(0);
+    consoleAltScreen(false);
+    consoleAutowrap = true;
+    consoleCursorKeys = false;
+    scrollTop = 0;
+    scrollBottom = u32::MAX;
+    cursorHidden = false;
+
+    let saved = ANSI.saved;
+    ANSI = ANSI_STATE_DEFAULT;
+    ANSI.saved = saved;
+    clearScreen();
+}
+
+// Bold shows palette colors 0-7 as their bright variant
+unsafe fn applyForeground() {
+    if ANSI.fgPalette < 0 {
+        return;
+    }
+    let mut index = ANSI.fgPalette as u32;
+    if index < 8 && textattr & ATTR_BOLD != 0 {
+        index += 8;
+    }
+    let rgb = paletteColor(index);
+    changeTextColor(rgb[0], rgb[1], rgb[2]);
+}
+
+// 38;5;n and 38;2;r;g;b, returns how many extra parameters were used
+unsafe fn extendedColor(at: usize, background: bool) -> usize {
+    let (rgb, used, palette) = match param(at + 1, 0) {
+        5 => (paletteColor(param(at + 2, 0).min(255)), 2, param(at + 2, 0).min(255) as i32),
+        2 => (
+            [
+                param(at + 2, 0).min(255) as c_int,
+                param(at + 3, 0).min(255) as c_int,
+                param(at + 4, 0).min(255) as c_int,
+            ],
+            4,
+            -1,
+        ),
+        _ => return 0,
+    };
+
+    if background {
+        changeBg(rgb[0], rgb[1], rgb[2]);
+    } else {
+        ANSI.fgPalette = palette;
+        changeTextColor(rgb[0], rgb[1], rgb[2]);
+        applyForeground();
+    }
+    used
+}
+
+// SGR
+unsafe fn selectGraphicRendition() {
+    let count = ANSI.paramCount.max(1);
+    let mut i = 0;
+    while i < count {
+        let p = param(i, 0);
+        match p {
+            0 => {
+                changeTextColor(DEFAULT_FG[0], DEFAULT_FG[1], DEFAULT_FG[2]);
+                changeBg(DEFAULT_BG[0], DEFAULT_BG[1], DEFAULT_BG[2]);
+                changeAttr(0);
+                ANSI.fgPalette = -1;
             }
-        }
-        'h' => {
-            if asciiChar1 == 25 && asciiQuestionmark {
-                cursorHidden = false;
-                updateBull();
+            1 => {
+                changeAttr(textattr | ATTR_BOLD);
+                applyForeground();
             }
-        }
-        'm' => {
-            if asciiChar1 == 0 && asciiChar2 == 0 {
-                changeBg(0, 0, 0);
-                changeTextColor(255, 255, 255);
-            } else {
-                if asciiChar1 >= 100 {
-                    asciiChar2 = asciiChar1 - (100 - 40);
-                    asciiChar1 = 1;
-                }
-
-                let extra = asciiChar1 == 1;
-                let do2 = asciiChar2 >= 30;
-                let controlling = if do2 { asciiChar2 } else { asciiChar1 };
-
-                if controlling >= 30 {
-                    let bg = controlling >= 40;
-                    let index =
-                        (controlling - 30 - if bg { 10 } else { 0 } + if extra { 10 } else { 0 })
-                            as usize;
-
-                    let color = ANSI_COLORS[index];
-                    if bg {
-                        changeBg(color.rgb[0], color.rgb[1], color.rgb[2]);
-                    } else {
-                        changeTextColor(color.rgb[0], color.rgb[1], color.rgb[2]);
-                    }
-                }
+            4 | 21 => changeAttr(textattr | ATTR_UNDERLINE),
+            7 => changeAttr(textattr | ATTR_REVERSE),
+            22 => {
+                changeAttr(textattr & !ATTR_BOLD);
+                applyForeground();
             }
-        }
-        'H' => {
-            if asciiChar1 == 0 {
-                asciiChar1 = 1;
+            24 => changeAttr(textattr & !ATTR_UNDERLINE),
+            27 => changeAttr(textattr & !ATTR_REVERSE),
+            30..=37 => {
+                ANSI.fgPalette = (p - 30) as i32;
+                applyForeground();
+            }
+            38 => i += extendedColor(i, false),
+            39 => {
+                ANSI.fgPalette = -1;
+                changeTextColor(DEFAULT_FG[0], DEFAULT_FG[1], DEFAULT_FG[2]);
             }
-            if asciiChar2 == 0 {
-                asciiChar2 = 1;
+            40..=47 => {
+                let rgb = paletteColor(p - 40);
+                changeBg(rgb[0], rgb[1], rgb[2]);
             }
-            height = (asciiChar1 - 1) * TTY_CHARACTER_HEIGHT;
-            width = (asciiChar2 - 1) * TTY_CHARACTER_WIDTH;
+            48 => i += extendedColor(i, true),
+            49 => changeBg(DEFAULT_BG[0], DEFAULT_BG[1], DEFAULT_BG[2]),
+            90..=97 => {
+                ANSI.fgPalette = (p - 90 + 8) as i32;
+                applyForeground();
+            }
+            100..=107 => {
+                let rgb = paletteColor(p - 100 + 8);
+                changeBg(rgb[0], rgb[1], rgb[2]);
+            }
+            // dim, italic, blink, conceal, strikethrough and their resets
+            _ => {}
         }
-        'C' => {
-            if asciiChar1 == 0 {
-                asciiChar1 = 1;
+        i += 1;
+    }
+}
+
+// DEC private modes (CSI ? n h / CSI ? n l)
+unsafe fn setPrivateMode(mode: u32, enable: bool) {
+    match mode {
+        1 => consoleCursorKeys = enable,
+        7 => consoleAutowrap = enable,
+        25 => cursorHidden = !enable,
+        47 | 1047 => consoleAltScreen(enable),
+        1048 => {
+            if enable {
+                saveCursor();
+            } else {
+                restoreCursor();
             }
-            width += (asciiChar1 - 1) * TTY_CHARACTER_WIDTH;
         }
-        'd' => {
-            if asciiChar1 == 0 {
-                asciiChar1 = 1;
+        1049 => {
+            if enable {
+                saveCursor();
+                consoleAltScreen(true);
+            } else {
+                consoleAltScreen(false);
+                restoreCursor();
             }
-            height = (asciiChar1 - 1) * TTY_CHARACTER_HEIGHT;
         }
-        'G' => {
-            if asciiChar1 == 0 {
-                asciiChar1 = 1;
+        // origin mode, cursor blinking, mouse reporting, bracketed paste...
+        _ => {}
+    }
+}
+
+unsafe fn eraseDisplay(mode: u32) {
+    let (col, row) = (consoleCursorCol(), consoleCursorRow());
+    match mode {
+        0 => {
+            consoleEraseCells(row, col, consoleCols());
+            for r in row + 1..consoleRows() {
+                consoleEraseCells(r, 0, consoleCols());
             }
-            width = (asciiChar1 - 1) * TTY_CHARACTER_WIDTH;
         }
-        'A' => {
-            if asciiChar1 == 0 {
-                asciiChar1 = 1;
+        1 => {
+            for r in 0..row {
+                consoleEraseCells(r, 0, consoleCols());
             }
-            height -= (asciiChar1 - 2) * TTY_CHARACTER_HEIGHT;
-            if height > fb.height {
-                height = 0;
+            consoleEraseCells(row, 0, col + 1);
+        }
+        2 => {
+            for r in 0..consoleRows() {
+                consoleEraseCells(r, 0, consoleCols());
             }
         }
-        'J' => {
-            match asciiChar1 {
-                0 => {
-                    let restWidth = fb.width - width;
-                    if restWidth > 0 {
-                        drawRect(
-                            width,
-                            height,
-                            restWidth,
-                            TTY_CHARACTER_HEIGHT,
-                            bg_color[0],
-                            bg_color[1],
-                            bg_color[2],
-                        );
-                    }
-
-                    let restHeight = fb.height - (height + TTY_CHARACTER_HEIGHT);
-                    if restHeight > 0 {
-                        drawRect(
-                            0,
-                            height + TTY_CHARACTER_HEIGHT,
-                            fb.width,
-                            fb.height,
-                            bg_color[0],
-                            bg_color[1],
-                            bg_color[2],
-                        );
-                    }
-                    updateBull();
-                }
-                2 | 3 => {
-                    clearScreen();
-                }
-                _ => {}
+        // the scrollback, the screen stays
+        3 => consoleScrollbackClear(),
+        _ => {}
+    }
+}
+
+unsafe fn eraseLine(mode: u32) {
+    let (col, row) = (consoleCursorCol(), consoleCursorRow());
+    match mode {
+        0 => consoleEraseCells(row, col, consoleCols()),
+        1 => consoleEraseCells(row, 0, col + 1),
+        2 => consoleEraseCells(row, 0, consoleCols()),
+        _ => {}
+    }
+}
+
+unsafe fn csiDispatch(finalByte: u8) {
+    let private = ANSI.private;
+    let (col, row) = (consoleCursorCol(), consoleCursorRow());
+
+    eraseBull();
+
+    match (private, finalByte) {
+        // cursor movement
+        (0, b'A') => consoleCursorSet(col, row.saturating_sub(param(0, 1))),
+        (0, b'B') | (0, b'e') => consoleCursorSet(col, row.saturating_add(param(0, 1))),
+        (0, b'C') | (0, b'a') => consoleCursorSet(col.saturating_add(param(0, 1)), row),
+        (0, b'D') => consoleCursorSet(col.saturating_sub(param(0, 1)), row),
+        (0, b'E') => consoleCursorSet(0, row.saturating_add(param(0, 1))),
+        (0, b'F') => consoleCursorSet(0, row.saturating_sub(param(0, 1))),
+        (0, b'G') | (0, b'`') => consoleCursorSet(param(0, 1) - 1, row),
+        (0, b'd') => consoleCursorSet(col, param(0, 1) - 1),
+        (0, b'H') | (0, b'f') => consoleCursorSet(param(1, 1) - 1, param(0, 1) - 1),
+
+        // erasing
+        (0, b'J') | (b'?', b'J') => eraseDisplay(param(0, 0)),
+        (0, b'K') | (b'?', b'K') => eraseLine(param(0, 0)),
+        (0, b'X') => consoleEraseCells(row, col, col.saturating_add(param(0, 1))),
+
+        // insert / delete
+        (0, b'@') => consoleInsertChars(param(0, 1)),
+        (0, b'P') => consoleDeleteChars(param(0, 1)),
+        (0, b'L') => consoleInsertLines(param(0, 1)),
+        (0, b'M') => consoleDeleteLines(param(0, 1)),
+
+        // scrolling
+        (0, b'S') => consoleScrollUp(param(0, 1)),
+        (0, b'T') if ANSI.paramCount <= 1 => consoleScrollDown(param(0, 1)),
+        (0, b'r') => consoleSetRegion(param(0, 1) - 1, param(1, consoleRows()) - 1),
+
+        // cursor save / restore (SCO)
+        (0, b's') => saveCursor(),
+        (0, b'u') => restoreCursor(),
+
+        (0, b'm') => selectGraphicRendition(),
+
+        (b'?', b'h') | (b'?', b'l') => {
+            for i in 0..ANSI.paramCount.max(1) {
+                setPrivateMode(param(i, 0), finalByte == b'h');
             }
         }
+
+        // device status
+        (0, b'n') | (b'?', b'n') => match param(0, 0) {
+            5 => reply(b"\x1b[0n"),
+            6 => replyCursorPosition(private == b'?'),
+            _ => {}
+        },
+
+        // device attributes: a VT102, like the Linux console
+        (0, b'c') if param(0, 0) == 0 => reply(b"\x1b[?6c"),
+        (b'>', b'c') if param(0, 0) == 0 => reply(b"\x1b[>0;0;0c"),
+
         _ => {}
     }
 
     updateBull();
-    true
 }
 
-unsafe fn ansiReset() {
-    asciiEscaping = false;
-    asciiInside = false;
-    asciiQuestionmark = false;
+unsafe fn escDispatch(c: u8) {
+    ANSI.parse = AnsiParse::Ground;
 
-    asciiFirstDone = false;
-    asciiChar1 = 0;
-    asciiChar2 = 0;
+    eraseBull();
+    match c {
+        b'[' => {
+            ANSI.parse = AnsiParse::Csi;
+            ANSI.private = 0;
+            ANSI.intermediate = 0;
+            ANSI.params = [0; ANSI_PARAMS_MAX];
+            ANSI.paramCount = 0;
+        }
+        b']' => ANSI.parse = AnsiParse::Osc,
+        b'(' | b')' | b'*' | b'+' | b'#' | b'%' => ANSI.parse = AnsiParse::EscapeIntermediate,
+        b'7' => saveCursor(),
+        b'8' => restoreCursor(),
+        b'D' => consoleIndex(),
+        b'E' => {
+            width = 0;
+            consoleIndex();
+        }
+        b'M' => consoleReverseIndex(),
+        b'c' => resetTerminal(),
+        // keypad modes, charsets...
+        _ => {}
+    }
+    updateBull();
+}
+
+unsafe fn csiCollect(c: u8) {
+    match c {
+        b'0'..=b'9' => {
+            if ANSI.paramCount == 0 {
+                ANSI.paramCount = 1;
+            }
+            let p = &mut ANSI.params[ANSI.paramCount - 1];
+            *p = p.saturating_mul(10).saturating_add((c - b'0') as u32);
+        }
+        b';' | b':' => {
+            if ANSI.paramCount == 0 {
+                ANSI.paramCount = 1;
+            }
+            if ANSI.paramCount < ANSI_PARAMS_MAX {
+                ANSI.paramCount += 1;
+            }
+        }
+        b'?' | b'>' | b'=' | b'<' => ANSI.private = c,
+        0x20..=0x2F => ANSI.intermediate = c,
+        0x40..=0x7E => {
+            ANSI.parse = AnsiParse::Ground;
+            // nothing with intermediates (DECSCUSR, DECSTR...) is supported
+            if ANSI.intermediate == 0 {
+                csiDispatch(c);
+            }
+        }
+        _ => {}
+    }
 }
 
 /// returns true = don't echo character
 #[no_mangle]
 pub unsafe extern "C" fn ansiHandle(charnum: c_int) -> bool {
+    // an escape always starts over, CAN and SUB abort
     if charnum == 0x1B {
-        ansiReset();
-        asciiEscaping = true;
-        true
-    } else if asciiEscaping && charnum == b'[' as i32 {
-        ansiReset();
-        asciiEscaping = true;
-        asciiInside = true;
-        true
-    } else if asciiEscaping && asciiInside {
-        if asciiProcess(charnum) {
-            ansiReset();
+        ANSI.parse = if ANSI.parse == AnsiParse::Osc {
+            AnsiParse::OscEscape
+        } else {
+            AnsiParse::Escape
+        };
+        return true;
+    }
+    if (charnum == 0x18 || charnum == 0x1A) && ANSI.parse != AnsiParse::Ground {
+        ANSI.parse = AnsiParse::Ground;
+        return true;
+    }
+
+    match ANSI.parse {
+        AnsiParse::Ground => false,
+        AnsiParse::Escape => {
+            escDispatch(charnum as u8);
+            true
+        }
+        AnsiParse::EscapeIntermediate => {
+            ANSI.parse = AnsiParse::Ground;
+            true
+        }
+        AnsiParse::Csi => {
+            // control characters in the middle of a sequence still do their thing
+            if charnum < 0x20 && charnum >= 0 {
+                return false;
+            }
+            csiCollect(charnum as u8);
+            true
+        }
+        AnsiParse::Osc => {
+            if charnum == 0x07 {
+                ANSI.parse = AnsiParse::Ground;
+            }
+            true
+        }
+        AnsiParse::OscEscape => {
+            // ESC \ (ST) ends it, anything else is a new escape sequence
+            ANSI.parse = AnsiParse::Ground;
+            if charnum != b'\\' as c_int {
+                escDispatch(charnum as u8);
+            }
+            true
         }
-        true
-    } else {
-        false
     }
 }
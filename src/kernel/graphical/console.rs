@@ -9,6 +9,8 @@
 use core::ffi::c_int;
 use core::sync::atomic::{AtomicBool, Ordering};
 
+use crate::bootloader::boot_option;
+
 // --------------------------------
 // External types & globals
 // --------------------------------
@@ -21,11 +23,6 @@ pub struct FrameBuffer {
     pub pitch: usize,
 }
 
-#[repr(C)]
-pub struct PSF {
-    pub height: u32,
-}
-
 // spinlock
 #[repr(C)]
 pub struct Spinlock {
@@ -37,10 +34,10 @@ extern "C" {
     static fb: FrameBuffer;
 
     // psf font
-    static psf: *const PSF;
-
-    fn psfLoadDefaults();
-    fn psfPutC(ch: c_int, x: u32, y: u32, r: c_int, g: c_int, b: c_int);
+    fn psfLoadDefaults() -> bool;
+    fn psfGlyphWidth() -> u32;
+    fn psfGlyphHeight() -> u32;
+    fn psfPutC(codepoint: u32, x: u32, y: u32, fg: u32, bg: u32);
 
     // ansi
     fn ansiHandle(ch: c_int) -> bool;
@@ -62,6 +59,13 @@ extern "C" {
 
     // memcpy
     fn memcpy(dest: *mut u8, src: *const u8, n: usize);
+    fn memmove(dest: *mut u8, src: *const u8, n: usize);
+
+    fn calloc(n: usize, size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    // virtual terminals
+    fn vtBindForeground();
 }
 
 // --------------------------------
@@ -79,6 +83,10 @@ pub static mut bg_color: [c_int; 3] = [0, 0, 0];
 #[no_mangle]
 pub static mut textcolor: [c_int; 3] = [255, 255, 255];
 
+// ATTR_* of what gets written next
+#[no_mangle]
+pub static mut textattr: u8 = 0;
+
 #[no_mangle]
 pub static mut width: u32 = 0;
 
@@ -91,16 +99,179 @@ pub static consoleDisabled: AtomicBool = AtomicBool::new(false);
 #[no_mangle]
 pub static mut cursorHidden: bool = false;
 
+// DECAWM
+#[no_mangle]
+pub static mut consoleAutowrap: bool = true;
+
+// DECCKM, the keyboard sends ESC O x instead of ESC [ x for cursor keys
+#[no_mangle]
+pub static mut consoleCursorKeys: bool = false;
+
+// DECSTBM, in rows (inclusive). u32::MAX follows the bottom of the screen
+#[no_mangle]
+pub static mut scrollTop: u32 = 0;
+
+#[no_mangle]
+pub static mut scrollBottom: u32 = u32::MAX;
+
+// Geometry of the grid being written to, in cells and pixels per cell. It
+// follows the font, see consoleGeometryCheck()
+#[no_mangle]
+pub static mut consoleGridCols: u32 = 0;
+
+#[no_mangle]
+pub static mut consoleGridRows: u32 = 0;
+
+#[no_mangle]
+pub static mut consoleCellWidth: u32 = 8;
+
+#[no_mangle]
+pub static mut consoleCellHeight: u32 = 16;
+
+// Scrollback: rows that went off the top of the primary screen, as a ring
+// of consoleHistoryRows rows (console.scrollback= on the kernel command
+// line). Allocated on first use, thrown away when the geometry changes.
+#[no_mangle]
+pub static mut consoleHistoryRows: u32 = CONSOLE_HISTORY_DEFAULT;
+
+#[no_mangle]
+pub static mut consoleHistory: *mut ConsoleCell = core::ptr::null_mut();
+
+#[no_mangle]
+pub static mut consoleHistoryLen: u32 = 0;
+
+#[no_mangle]
+pub static mut consoleHistoryNext: u32 = 0;
+
+// Rows scrolled back (Shift+PageUp), 0 while showing the live screen
+#[no_mangle]
+pub static mut consoleHistoryView: u32 = 0;
+
+const CONSOLE_HISTORY_DEFAULT: u32 = 512;
+const CONSOLE_HISTORY_MAX: u32 = 65536;
+
+// UTF-8 sequence being decoded, see drawCharacter()
+#[no_mangle]
+pub static mut utf8Codepoint: u32 = 0;
+
+#[no_mangle]
+pub static mut utf8Remaining: u8 = 0;
+
+#[no_mangle]
+pub static mut utf8Length: u8 = 0;
+
+pub const ATTR_BOLD: u8 = 1 << 0;
+pub const ATTR_UNDERLINE: u8 = 1 << 1;
+pub const ATTR_REVERSE: u8 = 1 << 2;
+
+// --------------------------------
+// Character grid
+// --------------------------------
+
+// Every virtual terminal keeps what's on its screen as cells, so it can be
+// redrawn when switched to. The globals above (and the grid below) always
+// describe the terminal currently being written to, see vtBind().
+// Right half of a double-width character, the glyph lives in the left one
+pub const CELL_WIDE: c_int = -2;
+
+const REPLACEMENT_CHARACTER: u32 = 0xFFFD;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ConsoleCell {
+    // code point, 0 when blank or CELL_WIDE
+    pub ch: c_int,
+    pub fg: u32,
+    pub bg: u32,
+    pub attr: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ConsoleState {
+    pub x: u32,
+    pub y: u32,
+    pub bg: [c_int; 3],
+    pub fg: [c_int; 3],
+    pub attr: u8,
+    pub cursorHidden: bool,
+    pub autowrap: bool,
+    pub cursorKeys: bool,
+    pub scrollTop: u32,
+    pub scrollBottom: u32,
+
+    pub cols: u32,
+    pub rows: u32,
+    pub cellWidth: u32,
+    pub cellHeight: u32,
+    pub cells: *mut ConsoleCell,
+    pub otherCells: *mut ConsoleCell,
+    pub onAlt: bool,
+
+    pub history: *mut ConsoleCell,
+    pub historyLen: u32,
+    pub historyNext: u32,
+    pub historyView: u32,
+
+    pub utf8Codepoint: u32,
+    pub utf8Remaining: u8,
+    pub utf8Length: u8,
+}
+
+#[no_mangle]
+pub static mut consoleCells: *mut ConsoleCell = core::ptr::null_mut();
+
+// The screen that isn't shown: the normal one while on the alternate screen
+// (?1049h) and the other way around. Allocated on first use.
+#[no_mangle]
+pub static mut consoleOtherCells: *mut ConsoleCell = core::ptr::null_mut();
+
+#[no_mangle]
+pub static mut consoleOnAlt: bool = false;
+
+// Set while writing to a terminal that isn't on screen (or is in KD_GRAPHICS)
+#[no_mangle]
+pub static mut consoleOffscreen: bool = false;
+
 // --------------------------------
 // Macros (translated)
 // --------------------------------
 
 #[inline(always)]
 unsafe fn CHAR_HEIGHT() -> u32 {
-    (*psf).height
+    consoleCellHeight
+}
+
+#[inline(always)]
+unsafe fn CHAR_WIDTH() -> u32 {
+    consoleCellWidth
+}
+
+#[inline(always)]
+unsafe fn gridCols() -> u32 {
+    consoleGridCols
+}
+
+#[inline(always)]
+unsafe fn gridRows() -> u32 {
+    consoleGridRows
 }
 
-const CHAR_WIDTH: u32 = 8;
+// The cursor column can be one past the end (pending autowrap)
+#[inline(always)]
+unsafe fn cursorCol() -> u32 {
+    (width / CHAR_WIDTH()).min(gridCols() - 1)
+}
+
+#[inline(always)]
+unsafe fn cursorRow() -> u32 {
+    (height / CHAR_HEIGHT()).min(gridRows() - 1)
+}
+
+#[inline(always)]
+unsafe fn regionBottom() -> u32 {
+    scrollBottom.min(gridRows() - 1)
+}
 
 // --------------------------------
 // Color helpers
@@ -119,45 +290,710 @@ pub extern "C" fn rgbaToHex(r: c_int, g: c_int, b: c_int, a: c_int) -> u32 {
         | (a as u32 & 0xff)
 }
 
+fn hexToRgb(hex: u32) -> [c_int; 3] {
+    [
+        (hex >> 16 & 0xff) as c_int,
+        (hex >> 8 & 0xff) as c_int,
+        (hex & 0xff) as c_int,
+    ]
+}
+
 // --------------------------------
-// Console core
+// Character widths
 // --------------------------------
 
+// East Asian wide and fullwidth characters, and emoji, take two cells
+const WIDE_RANGES: [(u32, u32); 15] = [
+    (0x1100, 0x115F),
+    (0x2E80, 0x303E),
+    (0x3041, 0x33FF),
+    (0x3400, 0x4DBF),
+    (0x4E00, 0x9FFF),
+    (0xA000, 0xA4CF),
+    (0xAC00, 0xD7A3),
+    (0xF900, 0xFAFF),
+    (0xFE30, 0xFE4F),
+    (0xFF00, 0xFF60),
+    (0xFFE0, 0xFFE6),
+    (0x1F300, 0x1F64F),
+    (0x1F900, 0x1F9FF),
+    (0x20000, 0x2FFFD),
+    (0x30000, 0x3FFFD),
+];
+
+// Combining marks and zero-width spaces/joiners, which get dropped as there's
+// no way to overlay them
+const ZERO_WIDTH_RANGES: [(u32, u32); 3] = [(0x0300, 0x036F), (0x200B, 0x200F), (0xFE00, 0xFE0F)];
+
+fn inRanges(codepoint: u32, ranges: &[(u32, u32)]) -> bool {
+    ranges
+        .iter()
+        .any(|&(first, last)| codepoint >= first && codepoint <= last)
+}
+
+fn charWidth(codepoint: u32) -> u32 {
+    if codepoint < 0x300 {
+        1
+    } else if inRanges(codepoint, &ZERO_WIDTH_RANGES) {
+        0
+    } else if inRanges(codepoint, &WIDE_RANGES) {
+        2
+    } else {
+        1
+    }
+}
+
+// --------------------------------
+// Grid helpers
+// --------------------------------
+
+// A cell holding just the background, as left behind by erasing
+unsafe fn blankCell() -> ConsoleCell {
+    ConsoleCell {
+        ch: 0,
+        fg: rgbToHex(textcolor[0], textcolor[1], textcolor[2]),
+        bg: rgbToHex(bg_color[0], bg_color[1], bg_color[2]),
+        attr: 0,
+    }
+}
+
+#[inline(always)]
+unsafe fn cellAt(col: u32, row: u32) -> *mut ConsoleCell {
+    consoleCells.add((row * gridCols() + col) as usize)
+}
+
+// Blanks halves of double-width characters that lost their other half, after
+// part of a row was overwritten or shifted
+unsafe fn mendRow(row: u32) {
+    let blank = blankCell();
+    for col in 0..gridCols() {
+        let cell = cellAt(col, row);
+        let orphan = if (*cell).ch == CELL_WIDE {
+            col == 0 || charWidth((*cellAt(col - 1, row)).ch as u32) != 2
+        } else {
+            (*cell).ch > 0
+                && charWidth((*cell).ch as u32) == 2
+                && (col + 1 >= gridCols() || (*cellAt(col + 1, row)).ch != CELL_WIDE)
+        };
+        if orphan {
+            *cell = ConsoleCell { bg: (*cell).bg, ..blank };
+            paintCell(col, row, false);
+        }
+    }
+}
+
+// Draws a cell of a row (of the grid or the scrollback) at col, row on
+// screen. The cursor is shown as reverse video.
+unsafe fn paintLineCell(line: *const ConsoleCell, col: u32, row: u32, cursor: bool) {
+    let cell = *line.add(col as usize);
+    if cell.ch == CELL_WIDE && col > 0 {
+        // drawn along with its left half
+        paintLineCell(line, col - 1, row, cursor);
+        return;
+    }
+    let wide = col + 1 < gridCols() && (*line.add(col as usize + 1)).ch == CELL_WIDE;
+
+    let (mut fg, mut bg) = (cell.fg, cell.bg);
+    if cell.attr & ATTR_REVERSE != 0 {
+        core::mem::swap(&mut fg, &mut bg);
+    }
+    if cursor {
+        core::mem::swap(&mut fg, &mut bg);
+    }
+
+    let x = col * CHAR_WIDTH();
+    let y = row * CHAR_HEIGHT();
+    let span = if wide { 2 * CHAR_WIDTH() } else { CHAR_WIDTH() };
+    let bgRgb = hexToRgb(bg);
+    if cell.ch <= 0 || cell.ch == b' ' as c_int {
+        drawRect(x, y, span, CHAR_HEIGHT(), bgRgb[0], bgRgb[1], bgRgb[2]);
+    } else {
+        psfPutC(cell.ch as u32, x, y, fg, bg);
+        // console fonts only have single-width glyphs
+        if wide {
+            drawRect(x + CHAR_WIDTH(), y, CHAR_WIDTH(), CHAR_HEIGHT(), bgRgb[0], bgRgb[1], bgRgb[2]);
+        }
+    }
+
+    if cell.attr & ATTR_UNDERLINE != 0 {
+        let fgRgb = hexToRgb(fg);
+        drawRect(x, y + CHAR_HEIGHT() - 1, span, 1, fgRgb[0], fgRgb[1], fgRgb[2]);
+    }
+}
+
+// Draws a cell from the grid, unless the scrollback is being looked at
+unsafe fn paintCell(col: u32, row: u32, cursor: bool) {
+    if consoleOffscreen || consoleHistoryView > 0 || consoleCells.is_null() {
+        return;
+    }
+    if col >= gridCols() || row >= gridRows() {
+        return;
+    }
+    paintLineCell(cellAt(0, row), col, row, cursor);
+}
+
+unsafe fn paintRow(row: u32, from: u32) {
+    for col in from..gridCols() {
+        paintCell(col, row, false);
+    }
+}
+
+// Moves whole rows of the framebuffer, for scrolling without repainting
+unsafe fn screenMoveRows(dst: u32, src: u32, count: u32) {
+    if consoleOffscreen || count == 0 {
+        return;
+    }
+
+    let charH = CHAR_HEIGHT() as usize;
+    let lines = count as usize * charH;
+    let dst = dst as usize * charH;
+    let src = src as usize * charH;
+    let lineBytes = fb.width as usize * 4;
+
+    // copy in the direction that doesn't overwrite what's still to be moved
+    if dst < src {
+        for line in 0..lines {
+            memcpy(
+                fb.virt.add((dst + line) * fb.pitch),
+                fb.virt.add((src + line) * fb.pitch),
+                lineBytes,
+            );
+        }
+    } else {
+        for line in (0..lines).rev() {
+            memcpy(
+                fb.virt.add((dst + line) * fb.pitch),
+                fb.virt.add((src + line) * fb.pitch),
+                lineBytes,
+            );
+        }
+    }
+}
+
+unsafe fn eraseRows(from: u32, to: u32) {
+    if consoleCells.is_null() {
+        // no grid yet (early boot), just the pixels
+        if !consoleOffscreen && from < to {
+            let charH = CHAR_HEIGHT();
+            drawRect(
+                0,
+                from * charH,
+                fb.width,
+                (to - from) * charH,
+                bg_color[0],
+                bg_color[1],
+                bg_color[2],
+            );
+        }
+        return;
+    }
+    for row in from..to {
+        consoleEraseCells(row, 0, gridCols());
+    }
+}
+
+// Scrolls rows top..=bottom by count, up (content moves towards the top) or
+// down, blanking what comes in
+unsafe fn scrollRows(top: u32, bottom: u32, count: u32, up: bool) {
+    if top > bottom {
+        return;
+    }
+    let span = bottom - top + 1;
+    let count = count.min(span);
+    let bytes = ((span - count) * gridCols()) as usize * core::mem::size_of::<ConsoleCell>();
+
+    if up {
+        // what leaves the whole (primary) screen is kept for Shift+PageUp
+        if top == 0 && bottom == gridRows() - 1 && !consoleOnAlt && !consoleCells.is_null() {
+            for row in 0..count {
+                historyPush(cellAt(0, row));
+            }
+        }
+        if !consoleCells.is_null() {
+            memmove(cellAt(0, top) as *mut u8, cellAt(0, top + count) as *const u8, bytes);
+        }
+        screenMoveRows(top, top + count, span - count);
+        eraseRows(bottom + 1 - count, bottom + 1);
+    } else {
+        if !consoleCells.is_null() {
+            memmove(cellAt(0, top + count) as *mut u8, cellAt(0, top) as *const u8, bytes);
+        }
+        screenMoveRows(top + count, top, span - count);
+        eraseRows(top, top + count);
+    }
+}
+
+// --------------------------------
+// Scrollback
+// --------------------------------
+
+unsafe fn historyPush(line: *const ConsoleCell) {
+    if consoleHistoryRows == 0 {
+        return;
+    }
+    if consoleHistory.is_null() {
+        consoleHistory = gridAllocate(gridCols(), consoleHistoryRows);
+        if consoleHistory.is_null() {
+            return;
+        }
+    }
+
+    memcpy(
+        consoleHistory.add((consoleHistoryNext * gridCols()) as usize) as *mut u8,
+        line as *const u8,
+        gridCols() as usize * core::mem::size_of::<ConsoleCell>(),
+    );
+    consoleHistoryNext = (consoleHistoryNext + 1) % consoleHistoryRows;
+    consoleHistoryLen = (consoleHistoryLen + 1).min(consoleHistoryRows);
+}
+
+unsafe fn historyClear() {
+    if !consoleHistory.is_null() {
+        free(consoleHistory as *mut u8);
+    }
+    consoleHistory = core::ptr::null_mut();
+    consoleHistoryLen = 0;
+    consoleHistoryNext = 0;
+    consoleHistoryView = 0;
+}
+
+// What's on screen row, given how far back the view is
+unsafe fn viewLine(row: u32) -> *const ConsoleCell {
+    let line = consoleHistoryLen - consoleHistoryView + row;
+    if line >= consoleHistoryLen {
+        return cellAt(0, line - consoleHistoryLen);
+    }
+
+    let oldest = (consoleHistoryNext + consoleHistoryRows - consoleHistoryLen) % consoleHistoryRows;
+    let slot = (oldest + line) % consoleHistoryRows;
+    consoleHistory.add((slot * gridCols()) as usize)
+}
+
+// Moves the view by rows (positive goes back), clamped to what's there
 #[no_mangle]
-pub unsafe extern "C" fn initiateConsole() {
+pub unsafe extern "C" fn consoleScrollbackView(rows: i32) {
+    if consoleCells.is_null() {
+        return;
+    }
+    let target = (consoleHistoryView as i64 + rows as i64).clamp(0, consoleHistoryLen as i64) as u32;
+    if target != consoleHistoryView {
+        consoleHistoryView = target;
+        consoleRedraw();
+    }
+}
+
+// Back to the live screen, on output or a keypress
+#[no_mangle]
+pub unsafe extern "C" fn consoleScrollbackReset() {
+    if consoleHistoryView > 0 {
+        consoleHistoryView = 0;
+        consoleRedraw();
+    }
+}
+
+// ED 3
+#[no_mangle]
+pub unsafe extern "C" fn consoleScrollbackClear() {
+    let viewing = consoleHistoryView > 0;
+    historyClear();
+    if viewing {
+        consoleRedraw();
+    }
+}
+
+unsafe fn gridAllocate(cols: u32, rows: u32) -> *mut ConsoleCell {
+    calloc((cols * rows) as usize, core::mem::size_of::<ConsoleCell>()) as *mut ConsoleCell
+}
+
+// A grid the size of the one being written to
+#[no_mangle]
+pub unsafe extern "C" fn consoleCellsAllocate() -> *mut ConsoleCell {
+    gridAllocate(gridCols(), gridRows())
+}
+
+// Defaults for a terminal that hasn't been written to yet
+#[no_mangle]
+pub unsafe extern "C" fn consoleStateInit(state: *mut ConsoleState) {
+    let cellWidth = psfGlyphWidth();
+    let cellHeight = psfGlyphHeight();
+    let cols = fb.width / cellWidth;
+    let rows = fb.height / cellHeight;
+    *state = ConsoleState {
+        x: 0,
+        y: 0,
+        bg: [0, 0, 0],
+        fg: [255, 255, 255],
+        attr: 0,
+        cursorHidden: false,
+        autowrap: true,
+        cursorKeys: false,
+        scrollTop: 0,
+        scrollBottom: u32::MAX,
+        cols,
+        rows,
+        cellWidth,
+        cellHeight,
+        cells: gridAllocate(cols, rows),
+        otherCells: core::ptr::null_mut(),
+        onAlt: false,
+        history: core::ptr::null_mut(),
+        historyLen: 0,
+        historyNext: 0,
+        historyView: 0,
+        utf8Codepoint: 0,
+        utf8Remaining: 0,
+        utf8Length: 0,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleSave(state: *mut ConsoleState) {
+    *state = ConsoleState {
+        x: width,
+        y: height,
+        bg: bg_color,
+        fg: textcolor,
+        attr: textattr,
+        cursorHidden,
+        autowrap: consoleAutowrap,
+        cursorKeys: consoleCursorKeys,
+        scrollTop,
+        scrollBottom,
+        cols: consoleGridCols,
+        rows: consoleGridRows,
+        cellWidth: consoleCellWidth,
+        cellHeight: consoleCellHeight,
+        cells: consoleCells,
+        otherCells: consoleOtherCells,
+        onAlt: consoleOnAlt,
+        history: consoleHistory,
+        historyLen: consoleHistoryLen,
+        historyNext: consoleHistoryNext,
+        historyView: consoleHistoryView,
+        utf8Codepoint,
+        utf8Remaining,
+        utf8Length,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleRestore(state: *const ConsoleState) {
+    width = (*state).x;
+    height = (*state).y;
+    bg_color = (*state).bg;
+    textcolor = (*state).fg;
+    textattr = (*state).attr;
+    cursorHidden = (*state).cursorHidden;
+    consoleAutowrap = (*state).autowrap;
+    consoleCursorKeys = (*state).cursorKeys;
+    scrollTop = (*state).scrollTop;
+    scrollBottom = (*state).scrollBottom;
+    consoleGridCols = (*state).cols;
+    consoleGridRows = (*state).rows;
+    consoleCellWidth = (*state).cellWidth;
+    consoleCellHeight = (*state).cellHeight;
+    consoleCells = (*state).cells;
+    consoleOtherCells = (*state).otherCells;
+    consoleOnAlt = (*state).onAlt;
+    consoleHistory = (*state).history;
+    consoleHistoryLen = (*state).historyLen;
+    consoleHistoryNext = (*state).historyNext;
+    consoleHistoryView = (*state).historyView;
+    utf8Codepoint = (*state).utf8Codepoint;
+    utf8Remaining = (*state).utf8Remaining;
+    utf8Length = (*state).utf8Length;
+}
+
+// Swaps a grid for one of another size with as much of it as fits. When
+// there are fewer rows, the bottom ones (up to the cursor) are kept.
+unsafe fn gridResize(old: *mut ConsoleCell, cols: u32, rows: u32, skip: u32) -> *mut ConsoleCell {
+    if old.is_null() {
+        return old;
+    }
+
+    let new = gridAllocate(cols, rows);
+    if !new.is_null() {
+        let blank = blankCell();
+        for row in 0..rows {
+            for col in 0..cols {
+                let from = row + skip;
+                *new.add((row * cols + col) as usize) = if from < gridRows() && col < gridCols() {
+                    *old.add((from * gridCols() + col) as usize)
+                } else {
+                    blank
+                };
+            }
+        }
+    }
+    free(old as *mut u8);
+    new
+}
+
+// Brings the grid being written to in line with the font, after it got
+// changed (setfont, the font file at boot). Returns whether anything changed.
+#[no_mangle]
+pub unsafe extern "C" fn consoleGeometryCheck() -> bool {
+    let cellWidth = psfGlyphWidth();
+    let cellHeight = psfGlyphHeight();
+    let cols = fb.width / cellWidth;
+    let rows = fb.height / cellHeight;
+    if cellWidth == consoleCellWidth
+        && cellHeight == consoleCellHeight
+        && cols == consoleGridCols
+        && rows == consoleGridRows
+    {
+        return false;
+    }
+
+    // the cursor, in cells of the old size
+    let col = width / consoleCellWidth;
+    let row = (height / consoleCellHeight).min(gridRows().saturating_sub(1));
+    let skip = (row + 1).saturating_sub(rows);
+
+    // the scrollback has the old width, and isn't worth converting
+    historyClear();
+
+    // without memory for it, the console carries on without a grid
+    consoleCells = gridResize(consoleCells, cols, rows, skip);
+    consoleOtherCells = gridResize(consoleOtherCells, cols, rows, skip);
+
+    consoleGridCols = cols;
+    consoleGridRows = rows;
+    consoleCellWidth = cellWidth;
+    consoleCellHeight = cellHeight;
+    width = col.min(cols) * cellWidth;
+    height = (row - skip).min(rows - 1) * cellHeight;
+    scrollTop = 0;
+    scrollBottom = u32::MAX;
+
+    if !consoleCells.is_null() {
+        for row in 0..rows {
+            mendRow(row);
+        }
+    }
+    true
+}
+
+// Repaints the whole framebuffer from the grid, after a terminal switch
+#[no_mangle]
+pub unsafe extern "C" fn consoleRedraw() {
+    if consoleCells.is_null() || consoleOffscreen {
+        return;
+    }
+
+    for row in 0..gridRows() {
+        let line = viewLine(row);
+        for col in 0..gridCols() {
+            paintLineCell(line, col, row, false);
+        }
+    }
+
+    // leftovers past the last full row/column
+    let right = gridCols() * CHAR_WIDTH();
+    if right < fb.width {
+        drawRect(right, 0, fb.width - right, fb.height, bg_color[0], bg_color[1], bg_color[2]);
+    }
+    let bottom = gridRows() * CHAR_HEIGHT();
+    if bottom < fb.height {
+        drawRect(0, bottom, fb.width, fb.height - bottom, bg_color[0], bg_color[1], bg_color[2]);
+    }
+
+    // no cursor while looking at the scrollback
+    if consoleHistoryView == 0 {
+        updateBull();
+    }
+}
+
+// --------------------------------
+// Terminal operations (cells)
+// --------------------------------
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleCols() -> u32 {
+    gridCols()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleRows() -> u32 {
+    gridRows()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleCursorCol() -> u32 {
+    cursorCol()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleCursorRow() -> u32 {
+    cursorRow()
+}
+
+// Clamped to the screen
+#[no_mangle]
+pub unsafe extern "C" fn consoleCursorSet(col: u32, row: u32) {
+    width = col.min(gridCols() - 1) * CHAR_WIDTH();
+    height = row.min(gridRows() - 1) * CHAR_HEIGHT();
+}
+
+// Cells from..to (exclusive) of a row, to the current background
+#[no_mangle]
+pub unsafe extern "C" fn consoleEraseCells(row: u32, from: u32, to: u32) {
+    if consoleCells.is_null() || row >= gridRows() {
+        return;
+    }
+    let to = to.min(gridCols());
+    let blank = blankCell();
+    for col in from..to {
+        *cellAt(col, row) = blank;
+        paintCell(col, row, false);
+    }
+    if from > 0 || to < gridCols() {
+        mendRow(row);
+    }
+}
+
+// Line feed: down a row, scrolling the region when at its bottom
+#[no_mangle]
+pub unsafe extern "C" fn consoleIndex() {
+    let row = cursorRow();
+    if row == regionBottom() {
+        scrollRows(scrollTop, regionBottom(), 1, true);
+    } else if row + 1 < gridRows() {
+        height = (row + 1) * CHAR_HEIGHT();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleReverseIndex() {
+    let row = cursorRow();
+    if row == scrollTop {
+        scrollRows(scrollTop, regionBottom(), 1, false);
+    } else if row > 0 {
+        height = (row - 1) * CHAR_HEIGHT();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleScrollUp(count: u32) {
+    scrollRows(scrollTop, regionBottom(), count, true);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn consoleScrollDown(count: u32) {
+    scrollRows(scrollTop, regionBottom(), count, false);
+}
+
+// DECSTBM, also homes the cursor
+#[no_mangle]
+pub unsafe extern "C" fn consoleSetRegion(top: u32, bottom: u32) {
+    let bottom = bottom.min(gridRows() - 1);
+    if top >= bottom {
+        return;
+    }
+    scrollTop = top;
+    scrollBottom = if bottom == gridRows() - 1 { u32::MAX } else { bottom };
     width = 0;
     height = 0;
-    psfLoadDefaults();
 }
 
+// IL/DL only act within the scrolling region
 #[no_mangle]
-pub unsafe extern "C" fn scrollConsole(check: bool) -> bool {
-    if check && !(height >= fb.height - CHAR_HEIGHT()) {
-        return false;
+pub unsafe extern "C" fn consoleInsertLines(count: u32) {
+    let row = cursorRow();
+    if row < scrollTop || row > regionBottom() {
+        return;
     }
+    scrollRows(row, regionBottom(), count, false);
+    width = 0;
+}
 
-    let char_h = CHAR_HEIGHT() as usize;
+#[no_mangle]
+pub unsafe extern "C" fn consoleDeleteLines(count: u32) {
+    let row = cursorRow();
+    if row < scrollTop || row > regionBottom() {
+        return;
+    }
+    scrollRows(row, regionBottom(), count, true);
+    width = 0;
+}
 
-    let mut y = char_h;
-    while y < fb.height as usize {
-        let dest = fb.virt.add((y - char_h) * fb.pitch);
-        let src = fb.virt.add(y * fb.pitch);
-        memcpy(dest, src, fb.width as usize * 4);
-        y += 1;
+#[no_mangle]
+pub unsafe extern "C" fn consoleInsertChars(count: u32) {
+    if consoleCells.is_null() {
+        return;
     }
+    let (col, row) = (cursorCol(), cursorRow());
+    let count = count.min(gridCols() - col);
+    let keep = gridCols() - col - count;
+    memmove(
+        cellAt(col + count, row) as *mut u8,
+        cellAt(col, row) as *const u8,
+        keep as usize * core::mem::size_of::<ConsoleCell>(),
+    );
+    consoleEraseCells(row, col, col + count);
+    paintRow(row, col + count);
+    mendRow(row);
+}
 
-    drawRect(
-        0,
-        fb.height - CHAR_HEIGHT(),
-        fb.width,
-        CHAR_HEIGHT(),
-        bg_color[0],
-        bg_color[1],
-        bg_color[2],
+#[no_mangle]
+pub unsafe extern "C" fn consoleDeleteChars(count: u32) {
+    if consoleCells.is_null() {
+        return;
+    }
+    let (col, row) = (cursorCol(), cursorRow());
+    let count = count.min(gridCols() - col);
+    let keep = gridCols() - col - count;
+    memmove(
+        cellAt(col, row) as *mut u8,
+        cellAt(col + count, row) as *const u8,
+        keep as usize * core::mem::size_of::<ConsoleCell>(),
     );
+    consoleEraseCells(row, col + keep, gridCols());
+    paintRow(row, col);
+    mendRow(row);
+}
 
-    height -= CHAR_HEIGHT();
-    true
+// Switches between the normal and the alternate screen, the latter always
+// starts out blank
+#[no_mangle]
+pub unsafe extern "C" fn consoleAltScreen(enable: bool) {
+    if enable == consoleOnAlt {
+        return;
+    }
+    if consoleOtherCells.is_null() {
+        consoleOtherCells = consoleCellsAllocate();
+        if consoleOtherCells.is_null() {
+            return;
+        }
+    }
+
+    core::mem::swap(&mut consoleCells, &mut consoleOtherCells);
+    consoleOnAlt = enable;
+    if enable {
+        eraseRows(0, gridRows());
+    }
+    consoleRedraw();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn changeAttr(attr: u8) {
+    textattr = attr;
+}
+
+// --------------------------------
+// Console core
+// --------------------------------
+
+#[no_mangle]
+pub unsafe extern "C" fn initiateConsole() {
+    width = 0;
+    height = 0;
+    psfLoadDefaults();
+    consoleGeometryCheck();
+
+    if let Some(rows) = boot_option("console.scrollback").and_then(|rows| rows.parse::<u32>().ok()) {
+        consoleHistoryRows = rows.min(CONSOLE_HISTORY_MAX);
+    }
 }
 
 #[no_mangle]
@@ -165,16 +1001,7 @@ pub unsafe extern "C" fn eraseBull() {
     if cursorHidden {
         return;
     }
-
-    drawRect(
-        width,
-        height,
-        CHAR_WIDTH,
-        CHAR_HEIGHT(),
-        bg_color[0],
-        bg_color[1],
-        bg_color[2],
-    );
+    paintCell(cursorCol(), cursorRow(), false);
 }
 
 #[no_mangle]
@@ -183,38 +1010,35 @@ pub unsafe extern "C" fn updateBull() {
         return;
     }
 
-    if width >= fb.width {
-        let needed = scrollConsole(true);
-        if !needed {
-            height += CHAR_HEIGHT();
+    if consoleCells.is_null() {
+        // no grid yet (early boot), a plain block will do
+        if !consoleOffscreen {
+            drawRect(
+                cursorCol() * CHAR_WIDTH(),
+                cursorRow() * CHAR_HEIGHT(),
+                CHAR_WIDTH(),
+                CHAR_HEIGHT(),
+                textcolor[0],
+                textcolor[1],
+                textcolor[2],
+            );
         }
-        width = 0;
+        return;
     }
-
-    drawRect(
-        width,
-        height,
-        CHAR_WIDTH,
-        CHAR_HEIGHT(),
-        textcolor[0],
-        textcolor[1],
-        textcolor[2],
-    );
+    paintCell(cursorCol(), cursorRow(), true);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn clearScreen() {
     width = 0;
     height = 0;
-    drawRect(
-        0,
-        0,
-        fb.width,
-        fb.height,
-        bg_color[0],
-        bg_color[1],
-        bg_color[2],
-    );
+    if consoleCells.is_null() {
+        if !consoleOffscreen {
+            drawRect(0, 0, fb.width, fb.height, bg_color[0], bg_color[1], bg_color[2]);
+        }
+    } else {
+        eraseRows(0, gridRows());
+    }
     updateBull();
 }
 
@@ -285,89 +1109,170 @@ pub extern "C" fn setConsoleY(y: u32) {
 // Character output
 // --------------------------------
 
-#[no_mangle]
-pub unsafe extern "C" fn drawCharacter(charnum: c_int) {
-    if charnum == 0 || consoleDisabled.load(Ordering::Relaxed) {
-        return;
+// Writes a character taking cells (1 or 2) columns at the cursor
+unsafe fn putGlyph(codepoint: u32, cells: u32) {
+    // the previous character filled the last column, or this one won't fit
+    if width / CHAR_WIDTH() + cells > gridCols() {
+        if consoleAutowrap {
+            width = 0;
+            consoleIndex();
+        } else {
+            width = gridCols().saturating_sub(cells) * CHAR_WIDTH();
+        }
     }
 
-    if ansiHandle(charnum) {
-        return;
+    let (col, row) = (cursorCol(), cursorRow());
+    let cells = cells.min(gridCols() - col);
+    let fg = rgbToHex(textcolor[0], textcolor[1], textcolor[2]);
+    let bg = rgbToHex(bg_color[0], bg_color[1], bg_color[2]);
+    if consoleCells.is_null() {
+        if !consoleOffscreen {
+            psfPutC(codepoint, width, height, fg, bg);
+        }
+    } else {
+        *cellAt(col, row) = ConsoleCell {
+            ch: codepoint as c_int,
+            fg,
+            bg,
+            attr: textattr,
+        };
+        if cells == 2 {
+            *cellAt(col + 1, row) = ConsoleCell {
+                ch: CELL_WIDE,
+                fg,
+                bg,
+                attr: textattr,
+            };
+        }
+        // whatever was half overwritten goes
+        mendRow(row);
+        paintCell(col, row, false);
     }
+    width = (col + cells) * CHAR_WIDTH();
+}
+
+// Feeds a byte to the UTF-8 decoder, returning the code point once one is
+// complete. Anything malformed comes out as U+FFFD.
+unsafe fn utf8Start(byte: u8) -> Option<u32> {
+    let (length, bits) = match byte {
+        0x00..=0x7f => return Some(byte as u32),
+        0xc2..=0xdf => (2, byte & 0x1f),
+        0xe0..=0xef => (3, byte & 0x0f),
+        0xf0..=0xf4 => (4, byte & 0x07),
+        // stray continuation bytes, overlong leads, past U+10FFFF
+        _ => return Some(REPLACEMENT_CHARACTER),
+    };
+    utf8Codepoint = bits as u32;
+    utf8Length = length;
+    utf8Remaining = length - 1;
+    None
+}
 
-    if width > fb.width - CHAR_WIDTH {
-        width = 0;
-        height += CHAR_HEIGHT();
+unsafe fn utf8Finish() -> u32 {
+    let minimum = match utf8Length {
+        2 => 0x80,
+        3 => 0x800,
+        _ => 0x10000,
+    };
+    let codepoint = utf8Codepoint;
+    if codepoint < minimum || (0xD800..=0xDFFF).contains(&codepoint) || codepoint > 0x10FFFF {
+        REPLACEMENT_CHARACTER
+    } else {
+        codepoint
     }
+}
 
-    scrollConsole(true);
+unsafe fn drawCodepoint(codepoint: u32) {
+    // escape sequences are plain ASCII, anything else in one just needs to
+    // not pass for a final byte
+    if ansiHandle(codepoint.min(0xff) as c_int) {
+        return;
+    }
 
-    match charnum {
-        -1 => {
-            drawRect(
-                width,
-                height,
-                CHAR_WIDTH,
-                CHAR_HEIGHT(),
-                bg_color[0],
-                bg_color[1],
-                bg_color[2],
-            );
-            width += CHAR_WIDTH;
-        }
-        b'\n' as c_int => {
-            eraseBull();
-            width = 0;
-            height += CHAR_HEIGHT();
-        }
-        0x0d => {
-            eraseBull();
-            width = 0;
-        }
-        0x0f => {}
-        b'\x08' as c_int => {
-            eraseBull();
-            width -= CHAR_WIDTH;
-            drawRect(
-                width,
-                height,
-                CHAR_WIDTH,
-                CHAR_HEIGHT(),
-                bg_color[0],
-                bg_color[1],
-                bg_color[2],
-            );
-        }
-        b'\t' as c_int => {
-            for _ in 0..4 {
-                drawCharacter(b' ' as c_int);
+    eraseBull();
+
+    match codepoint {
+        // LF, VT, FF (no carriage return, ONLCR takes care of that)
+        0x0a | 0x0b | 0x0c => consoleIndex(),
+        0x0d => width = 0,
+        0x08 => {
+            let col = cursorCol();
+            if col > 0 {
+                width = (col - 1) * CHAR_WIDTH();
             }
         }
+        0x09 => {
+            // tab stops every 8 columns
+            let col = ((cursorCol() / 8 + 1) * 8).min(gridCols() - 1);
+            width = col * CHAR_WIDTH();
+        }
+        // BEL, SO, SI, DEL, C1 controls
+        0x07 | 0x0e | 0x0f | 0x7f..=0x9f => {}
         _ => {
-            eraseBull();
-            psfPutC(
-                charnum,
-                width,
-                height,
-                textcolor[0],
-                textcolor[1],
-                textcolor[2],
-            );
-            width += CHAR_WIDTH;
+            let cells = charWidth(codepoint);
+            if cells > 0 {
+                putGlyph(codepoint, cells);
+            }
         }
     }
 
     updateBull();
 }
 
+// Takes the output byte by byte, UTF-8 encoded
+#[no_mangle]
+pub unsafe extern "C" fn drawCharacter(charnum: c_int) {
+    if charnum == 0 || consoleDisabled.load(Ordering::Relaxed) {
+        return;
+    }
+
+    // new output brings the live screen back
+    if consoleHistoryView > 0 {
+        consoleScrollbackReset();
+    }
+
+    // blank cell
+    if charnum == -1 {
+        eraseBull();
+        putGlyph(0, 1);
+        updateBull();
+        return;
+    }
+
+    // printf() hands over (signed) chars
+    let byte = charnum as u8;
+    if utf8Remaining > 0 {
+        if byte & 0xc0 == 0x80 {
+            utf8Codepoint = utf8Codepoint << 6 | (byte & 0x3f) as u32;
+            utf8Remaining -= 1;
+            if utf8Remaining == 0 {
+                drawCodepoint(utf8Finish());
+            }
+            return;
+        }
+        // cut short, the byte starts something new
+        utf8Remaining = 0;
+        drawCodepoint(REPLACEMENT_CHARACTER);
+    }
+
+    if let Some(codepoint) = utf8Start(byte) {
+        drawCodepoint(codepoint);
+    }
+}
+
 // --------------------------------
 // printf glue
 // --------------------------------
 
+// Kernel messages go to whichever terminal is in the foreground
 #[no_mangle]
 pub extern "C" fn printfch(character: c_int) {
     unsafe {
         spinlockAcquire(&mut LOCK_CONSOLE);
+        vtBindForeground();
+        if character == b'\n' as c_int {
+            drawCharacter(b'\r' as c_int);
+        }
         drawCharacter(character);
         spinlockRelease(&mut LOCK_CONSOLE);
     }
@@ -0,0 +1,1978 @@
+// Minimal DRM/KMS device (/dev/dri/card0)
+// Copyright (C) 2025 kevin dan mathew
+
+#![no_std]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+#![allow(dead_code)]
+
+use core::cmp::min;
+use core::ffi::c_void;
+use core::mem::size_of;
+use core::ptr::{copy_nonoverlapping, null, null_mut, write_bytes};
+
+// One CRTC driving one virtual connector through one encoder, with a single
+// primary plane. Dumb buffers live in ordinary memory and get copied into
+// VRAM ("shadow" buffers, see DRM_CAP_DUMB_PREFER_SHADOW) on SETCRTC,
+// PAGE_FLIP and DIRTYFB. None of the devices have a vblank interrupt, so a
+// kernel thread ticks at 60Hz, latches flips and sends out the events.
+
+// --------------------------------
+// Constants
+// --------------------------------
+
+const DRM_IOCTL_BASE: u64 = 0x64; // 'd'
+
+const DRM_IOCTL_VERSION: usize = 0x00;
+const DRM_IOCTL_GET_UNIQUE: usize = 0x01;
+const DRM_IOCTL_GET_MAGIC: usize = 0x02;
+const DRM_IOCTL_GEM_CLOSE: usize = 0x09;
+const DRM_IOCTL_GET_CAP: usize = 0x0c;
+const DRM_IOCTL_SET_CLIENT_CAP: usize = 0x0d;
+const DRM_IOCTL_AUTH_MAGIC: usize = 0x11;
+const DRM_IOCTL_SET_MASTER: usize = 0x1e;
+const DRM_IOCTL_DROP_MASTER: usize = 0x1f;
+const DRM_IOCTL_WAIT_VBLANK: usize = 0x3a;
+
+const DRM_IOCTL_MODE_GETRESOURCES: usize = 0xa0;
+const DRM_IOCTL_MODE_GETCRTC: usize = 0xa1;
+const DRM_IOCTL_MODE_SETCRTC: usize = 0xa2;
+const DRM_IOCTL_MODE_CURSOR: usize = 0xa3;
+const DRM_IOCTL_MODE_GETGAMMA: usize = 0xa4;
+const DRM_IOCTL_MODE_SETGAMMA: usize = 0xa5;
+const DRM_IOCTL_MODE_GETENCODER: usize = 0xa6;
+const DRM_IOCTL_MODE_GETCONNECTOR: usize = 0xa7;
+const DRM_IOCTL_MODE_GETPROPERTY: usize = 0xaa;
+const DRM_IOCTL_MODE_SETPROPERTY: usize = 0xab;
+const DRM_IOCTL_MODE_GETPROPBLOB: usize = 0xac;
+const DRM_IOCTL_MODE_GETFB: usize = 0xad;
+const DRM_IOCTL_MODE_ADDFB: usize = 0xae;
+const DRM_IOCTL_MODE_RMFB: usize = 0xaf;
+const DRM_IOCTL_MODE_PAGE_FLIP: usize = 0xb0;
+const DRM_IOCTL_MODE_DIRTYFB: usize = 0xb1;
+const DRM_IOCTL_MODE_CREATE_DUMB: usize = 0xb2;
+const DRM_IOCTL_MODE_MAP_DUMB: usize = 0xb3;
+const DRM_IOCTL_MODE_DESTROY_DUMB: usize = 0xb4;
+const DRM_IOCTL_MODE_GETPLANERESOURCES: usize = 0xb5;
+const DRM_IOCTL_MODE_GETPLANE: usize = 0xb6;
+const DRM_IOCTL_MODE_SETPLANE: usize = 0xb7;
+const DRM_IOCTL_MODE_ADDFB2: usize = 0xb8;
+const DRM_IOCTL_MODE_OBJ_GETPROPERTIES: usize = 0xb9;
+const DRM_IOCTL_MODE_OBJ_SETPROPERTY: usize = 0xba;
+const DRM_IOCTL_MODE_CURSOR2: usize = 0xbb;
+const DRM_IOCTL_MODE_ATOMIC: usize = 0xbc;
+
+const DRM_CAP_DUMB_BUFFER: u64 = 0x1;
+const DRM_CAP_VBLANK_HIGH_CRTC: u64 = 0x2;
+const DRM_CAP_DUMB_PREFERRED_DEPTH: u64 = 0x3;
+const DRM_CAP_DUMB_PREFER_SHADOW: u64 = 0x4;
+const DRM_CAP_PRIME: u64 = 0x5;
+const DRM_CAP_TIMESTAMP_MONOTONIC: u64 = 0x6;
+const DRM_CAP_ASYNC_PAGE_FLIP: u64 = 0x7;
+const DRM_CAP_CURSOR_WIDTH: u64 = 0x8;
+const DRM_CAP_CURSOR_HEIGHT: u64 = 0x9;
+const DRM_CAP_ADDFB2_MODIFIERS: u64 = 0x10;
+const DRM_CAP_PAGE_FLIP_TARGET: u64 = 0x11;
+const DRM_CAP_CRTC_IN_VBLANK_EVENT: u64 = 0x12;
+const DRM_CAP_SYNCOBJ: u64 = 0x13;
+
+const DRM_CLIENT_CAP_STEREO_3D: u64 = 1;
+const DRM_CLIENT_CAP_UNIVERSAL_PLANES: u64 = 2;
+const DRM_CLIENT_CAP_ATOMIC: u64 = 3;
+
+const DRM_EVENT_VBLANK: u32 = 0x01;
+const DRM_EVENT_FLIP_COMPLETE: u32 = 0x02;
+
+const DRM_VBLANK_ABSOLUTE: u32 = 0x0;
+const DRM_VBLANK_RELATIVE: u32 = 0x1;
+const DRM_VBLANK_TYPES_MASK: u32 = DRM_VBLANK_ABSOLUTE | DRM_VBLANK_RELATIVE;
+const DRM_VBLANK_EVENT: u32 = 0x0400_0000;
+const DRM_VBLANK_NEXTONMISS: u32 = 0x1000_0000;
+const DRM_VBLANK_HIGH_CRTC_MASK: u32 = 0x0000_003e;
+
+const DRM_MODE_PAGE_FLIP_EVENT: u32 = 0x01;
+const DRM_MODE_PAGE_FLIP_ASYNC: u32 = 0x02;
+
+const DRM_MODE_TYPE_PREFERRED: u32 = 1 << 3;
+const DRM_MODE_TYPE_DRIVER: u32 = 1 << 6;
+
+const DRM_MODE_FLAG_NHSYNC: u32 = 1 << 1;
+const DRM_MODE_FLAG_PVSYNC: u32 = 1 << 2;
+
+const DRM_MODE_ENCODER_VIRTUAL: u32 = 5;
+const DRM_MODE_CONNECTOR_VIRTUAL: u32 = 15;
+const DRM_MODE_CONNECTED: u32 = 1;
+const DRM_MODE_SUBPIXEL_UNKNOWN: u32 = 1;
+
+const DRM_MODE_PROP_IMMUTABLE: u32 = 1 << 2;
+const DRM_MODE_PROP_ENUM: u32 = 1 << 3;
+
+const DRM_PLANE_TYPE_OVERLAY: u64 = 0;
+const DRM_PLANE_TYPE_PRIMARY: u64 = 1;
+const DRM_PLANE_TYPE_CURSOR: u64 = 2;
+
+// fourcc('X', 'R', '2', '4') / fourcc('A', 'R', '2', '4')
+const DRM_FORMAT_XRGB8888: u32 = 0x3432_5258;
+const DRM_FORMAT_ARGB8888: u32 = 0x3432_5241;
+
+// mode object ids, fbs are numbered from DRM_FB_ID_BASE
+const DRM_PROP_TYPE_ID: u32 = 0x10;
+const DRM_CRTC_ID: u32 = 0x20;
+const DRM_ENCODER_ID: u32 = 0x21;
+const DRM_CONNECTOR_ID: u32 = 0x22;
+const DRM_PLANE_ID: u32 = 0x23;
+const DRM_FB_ID_BASE: u32 = 0x100;
+
+const DRM_MAX_BUFFERS: usize = 64;
+const DRM_MAX_FBS: usize = 64;
+const DRM_MAX_MODES: usize = 24;
+const DRM_MAX_EVENTS: usize = 16;
+const DRM_MAX_WAITERS: usize = 16;
+const DRM_MAX_CLIPS: u32 = 256;
+
+const DRM_GAMMA_SIZE: u32 = 256;
+const DRM_MAX_DUMB_SIZE: u32 = 8192;
+
+// MAP_DUMB hands out the handle shifted by this as the mmap offset
+const DRM_MAP_SHIFT: usize = 32;
+
+// 60Hz, in timer ticks (ms)
+const DRM_VBLANK_MS: u64 = 16;
+
+const PAGE_SIZE: usize = 4096;
+
+const PF_RW: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_SHARED: u64 = 1 << 9;
+
+const MAP_FIXED: i32 = 0x10;
+
+const O_NONBLOCK: u32 = 0x800;
+const EPOLLIN: i32 = 0x001;
+
+const ENOENT: isize = 2;
+const EINTR: isize = 4;
+const ENXIO: isize = 6;
+const EAGAIN: isize = 11;
+const ENOMEM: isize = 12;
+const EBUSY: isize = 16;
+const ENODEV: isize = 19;
+const EINVAL: isize = 22;
+const ENOSPC: isize = 28;
+const ENOTTY: isize = 25;
+const EOPNOTSUPP: isize = 95;
+
+#[inline]
+const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+// --------------------------------
+// Userspace structures (Linux ABI)
+// --------------------------------
+
+#[repr(C)]
+pub struct drm_version {
+    pub version_major: i32,
+    pub version_minor: i32,
+    pub version_patchlevel: i32,
+    pub name_len: usize,
+    pub name: *mut u8,
+    pub date_len: usize,
+    pub date: *mut u8,
+    pub desc_len: usize,
+    pub desc: *mut u8,
+}
+
+#[repr(C)]
+pub struct drm_unique {
+    pub unique_len: usize,
+    pub unique: *mut u8,
+}
+
+#[repr(C)]
+pub struct drm_auth {
+    pub magic: u32,
+}
+
+#[repr(C)]
+pub struct drm_gem_close {
+    pub handle: u32,
+    pub pad: u32,
+}
+
+#[repr(C)]
+pub struct drm_get_cap {
+    pub capability: u64,
+    pub value: u64,
+}
+
+#[repr(C)]
+pub struct drm_set_client_cap {
+    pub capability: u64,
+    pub value: u64,
+}
+
+#[repr(C)]
+pub struct drm_wait_vblank {
+    pub type_: u32,
+    pub sequence: u32,
+    // request: signal (user data for the event), reply: tval_sec
+    pub signal: u64,
+    // reply: tval_usec
+    pub tval_usec: i64,
+}
+
+#[repr(C)]
+pub struct drm_event_vblank {
+    pub type_: u32,
+    pub length: u32,
+    pub user_data: u64,
+    pub tv_sec: u32,
+    pub tv_usec: u32,
+    pub sequence: u32,
+    pub crtc_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct drm_mode_modeinfo {
+    pub clock: u32,
+    pub hdisplay: u16,
+    pub hsync_start: u16,
+    pub hsync_end: u16,
+    pub htotal: u16,
+    pub hskew: u16,
+    pub vdisplay: u16,
+    pub vsync_start: u16,
+    pub vsync_end: u16,
+    pub vtotal: u16,
+    pub vscan: u16,
+    pub vrefresh: u32,
+    pub flags: u32,
+    pub type_: u32,
+    pub name: [u8; 32],
+}
+
+#[repr(C)]
+pub struct drm_mode_card_res {
+    pub fb_id_ptr: u64,
+    pub crtc_id_ptr: u64,
+    pub connector_id_ptr: u64,
+    pub encoder_id_ptr: u64,
+    pub count_fbs: u32,
+    pub count_crtcs: u32,
+    pub count_connectors: u32,
+    pub count_encoders: u32,
+    pub min_width: u32,
+    pub max_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+}
+
+#[repr(C)]
+pub struct drm_mode_crtc {
+    pub set_connectors_ptr: u64,
+    pub count_connectors: u32,
+    pub crtc_id: u32,
+    pub fb_id: u32,
+    pub x: u32,
+    pub y: u32,
+    pub gamma_size: u32,
+    pub mode_valid: u32,
+    pub mode: drm_mode_modeinfo,
+}
+
+#[repr(C)]
+pub struct drm_mode_crtc_lut {
+    pub crtc_id: u32,
+    pub gamma_size: u32,
+    pub red: u64,
+    pub green: u64,
+    pub blue: u64,
+}
+
+#[repr(C)]
+pub struct drm_mode_get_encoder {
+    pub encoder_id: u32,
+    pub encoder_type: u32,
+    pub crtc_id: u32,
+    pub possible_crtcs: u32,
+    pub possible_clones: u32,
+}
+
+#[repr(C)]
+pub struct drm_mode_get_connector {
+    pub encoders_ptr: u64,
+    pub modes_ptr: u64,
+    pub props_ptr: u64,
+    pub prop_values_ptr: u64,
+    pub count_modes: u32,
+    pub count_props: u32,
+    pub count_encoders: u32,
+    pub encoder_id: u32,
+    pub connector_id: u32,
+    pub connector_type: u32,
+    pub connector_type_id: u32,
+    pub connection: u32,
+    pub mm_width: u32,
+    pub mm_height: u32,
+    pub subpixel: u32,
+    pub pad: u32,
+}
+
+#[repr(C)]
+pub struct drm_mode_property_enum {
+    pub value: u64,
+    pub name: [u8; 32],
+}
+
+#[repr(C)]
+pub struct drm_mode_get_property {
+    pub values_ptr: u64,
+    pub enum_blob_ptr: u64,
+    pub prop_id: u32,
+    pub flags: u32,
+    pub name: [u8; 32],
+    pub count_values: u32,
+    pub count_enum_blobs: u32,
+}
+
+#[repr(C)]
+pub struct drm_mode_obj_get_properties {
+    pub props_ptr: u64,
+    pub prop_values_ptr: u64,
+    pub count_props: u32,
+    pub obj_id: u32,
+    pub obj_type: u32,
+}
+
+#[repr(C)]
+pub struct drm_mode_fb_cmd {
+    pub fb_id: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+    pub bpp: u32,
+    pub depth: u32,
+    pub handle: u32,
+}
+
+#[repr(C)]
+pub struct drm_mode_fb_cmd2 {
+    pub fb_id: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pixel_format: u32,
+    pub flags: u32,
+    pub handles: [u32; 4],
+    pub pitches: [u32; 4],
+    pub offsets: [u32; 4],
+    pub modifier: [u64; 4],
+}
+
+#[repr(C)]
+pub struct drm_mode_crtc_page_flip {
+    pub crtc_id: u32,
+    pub fb_id: u32,
+    pub flags: u32,
+    pub reserved: u32,
+    pub user_data: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct drm_clip_rect {
+    pub x1: u16,
+    pub y1: u16,
+    pub x2: u16,
+    pub y2: u16,
+}
+
+#[repr(C)]
+pub struct drm_mode_fb_dirty_cmd {
+    pub fb_id: u32,
+    pub flags: u32,
+    pub color: u32,
+    pub num_clips: u32,
+    pub clips_ptr: u64,
+}
+
+#[repr(C)]
+pub struct drm_mode_create_dumb {
+    pub height: u32,
+    pub width: u32,
+    pub bpp: u32,
+    pub flags: u32,
+    pub handle: u32,
+    pub pitch: u32,
+    pub size: u64,
+}
+
+#[repr(C)]
+pub struct drm_mode_map_dumb {
+    pub handle: u32,
+    pub pad: u32,
+    pub offset: u64,
+}
+
+#[repr(C)]
+pub struct drm_mode_destroy_dumb {
+    pub handle: u32,
+}
+
+#[repr(C)]
+pub struct drm_mode_get_plane_res {
+    pub plane_id_ptr: u64,
+    pub count_planes: u32,
+}
+
+#[repr(C)]
+pub struct drm_mode_get_plane {
+    pub plane_id: u32,
+    pub crtc_id: u32,
+    pub fb_id: u32,
+    pub possible_crtcs: u32,
+    pub gamma_size: u32,
+    pub count_format_types: u32,
+    pub format_type_ptr: u64,
+}
+
+#[repr(C)]
+pub struct drm_mode_set_plane {
+    pub plane_id: u32,
+    pub crtc_id: u32,
+    pub fb_id: u32,
+    pub flags: u32,
+    pub crtc_x: i32,
+    pub crtc_y: i32,
+    pub crtc_w: u32,
+    pub crtc_h: u32,
+    // 16.16 fixed point
+    pub src_x: u32,
+    pub src_y: u32,
+    pub src_h: u32,
+    pub src_w: u32,
+}
+
+// --------------------------------
+// Kernel structures
+// --------------------------------
+
+#[repr(C)]
+pub struct Framebuffer {
+    pub virt: *mut u8,
+    pub phys: usize,
+    pub width: usize,
+    pub height: usize,
+    pub pitch: usize,
+
+    pub red_shift: u32,
+    pub red_size: u32,
+    pub green_shift: u32,
+    pub green_size: u32,
+    pub blue_shift: u32,
+    pub blue_size: u32,
+
+    pub bpp: u32,
+}
+
+#[repr(C)]
+pub struct Spinlock {
+    _priv: u32,
+}
+
+#[repr(C)]
+pub struct TaskInfoPagedir {
+    pub LOCK_PD: Spinlock,
+    pub utilizedBy: i32,
+
+    pub heap_start: u64,
+    pub heap_end: u64,
+
+    pub mmap_start: u64,
+    pub mmap_end: u64,
+}
+
+#[repr(C)]
+pub struct Task {
+    pub id: u64,
+    pub infoPd: *mut TaskInfoPagedir,
+}
+
+#[repr(C)]
+pub struct OpenFile {
+    pub flags: u32,
+    pub dir: *mut c_void,
+}
+
+#[repr(C)]
+pub struct VfsHandlers {
+    pub open: Option<unsafe extern "C" fn(*mut u8, i32, i32, *mut OpenFile, *mut *mut u8) -> usize>,
+    pub duplicate: Option<unsafe extern "C" fn(*mut OpenFile, *mut OpenFile) -> bool>,
+    pub close: Option<unsafe extern "C" fn(*mut OpenFile) -> bool>,
+    pub read: Option<unsafe extern "C" fn(*mut OpenFile, *mut u8, usize) -> usize>,
+    pub internalPoll: Option<unsafe extern "C" fn(*mut OpenFile, i32) -> i32>,
+    pub ioctl: Option<unsafe extern "C" fn(*mut OpenFile, u64, *mut u8) -> usize>,
+    pub mmap: Option<unsafe extern "C" fn(usize, usize, i32, i32, *mut OpenFile, usize) -> usize>,
+    pub reportKey: Option<unsafe extern "C" fn(*mut OpenFile) -> usize>,
+    pub stat: Option<unsafe extern "C" fn()>,
+}
+
+// --------------------------------
+// External kernel APIs
+// --------------------------------
+
+extern "C" {
+    static mut fb: Framebuffer;
+    static mut currentTask: *mut Task;
+    static timerTicks: u64;
+
+    fn spinlockAcquire(lock: *mut Spinlock);
+    fn spinlockRelease(lock: *mut Spinlock);
+
+    fn calloc(n: usize, size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    fn VirtualAllocate(pages: usize) -> *mut u8;
+    fn VirtualFree(ptr: *mut u8, pages: usize);
+    fn VirtualToPhysical(addr: usize) -> usize;
+    fn VirtualMap(virt: usize, phys: usize, flags: u64);
+    fn taskInfoPdMappingAddHeld(
+        target: *mut TaskInfoPagedir,
+        start: usize,
+        end: usize,
+        offset: usize,
+        hold: unsafe extern "C" fn(ctx: *mut c_void, take: bool),
+        holdCtx: *mut c_void,
+    );
+
+    fn taskCreateKernel(entry: u64, arg: u64) -> *mut Task;
+    fn taskNameKernel(task: *mut Task, name: *const u8, len: usize);
+    fn sleep(ms: u32);
+    fn handControl();
+    fn signalsPendingQuick(task: *mut Task) -> bool;
+    fn pollInstanceRing(key: usize, events: i32);
+
+    fn vtModeChanged();
+
+    fn fakefsFstat();
+    fn debugf(fmt: *const u8, ...);
+}
+
+// --------------------------------
+// Display backends
+// --------------------------------
+
+// What a display driver hands to the DRM device. Modes are always 32bpp
+// XRGB8888; after setMode() `fb` describes the first page of VRAM.
+#[repr(C)]
+pub struct DrmBackend {
+    // NUL-terminated, reported as the DRM driver description
+    pub name: *const u8,
+
+    pub maxWidth: u32,
+    pub maxHeight: u32,
+    pub vramSize: usize,
+
+    pub setMode: unsafe fn(width: u32, height: u32) -> bool,
+    // shows VRAM from the given line on (a second page for flips)
+    pub pan: Option<unsafe fn(line: u32) -> bool>,
+    // tells the device a part of the screen changed
+    pub update: Option<unsafe fn(x: u32, y: u32, width: u32, height: u32)>,
+}
+
+// --------------------------------
+// DRM state
+// --------------------------------
+
+#[repr(C)]
+pub struct DrmFile {
+    pub events: [drm_event_vblank; DRM_MAX_EVENTS],
+    pub eventFirst: usize,
+    pub eventCount: usize,
+
+    pub universalPlanes: bool,
+    pub opens: usize,
+}
+
+// a dumb buffer (GEM object), its handle is the index + 1. The handle,
+// every framebuffer made from it and every userspace mapping of it hold a
+// reference; the pages (and the slot) go once the last one is dropped.
+#[repr(C)]
+pub struct DrmBuffer {
+    // null once the handle is closed
+    pub owner: *mut DrmFile,
+    pub virt: *mut u8,
+    pub pages: usize,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+    pub size: usize,
+    pub refs: usize,
+}
+
+// a framebuffer object, its id is DRM_FB_ID_BASE + index
+#[repr(C)]
+pub struct DrmFb {
+    pub owner: *mut DrmFile,
+    pub buffer: u32,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+    pub offset: u32,
+    pub format: u32,
+}
+
+// DRM_VBLANK_EVENT requests still waiting for their sequence
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct DrmWaiter {
+    pub file: *mut DrmFile,
+    pub sequence: u32,
+    pub userData: u64,
+}
+
+// a PAGE_FLIP that gets latched on the next vblank
+#[repr(C)]
+pub struct DrmFlip {
+    pub pending: bool,
+    pub file: *mut DrmFile,
+    pub event: bool,
+    pub userData: u64,
+    pub page: u32,
+}
+
+#[repr(C)]
+pub struct DrmCrtc {
+    pub enabled: bool,
+    pub fb: u32,
+    pub x: u32,
+    pub y: u32,
+    pub mode: drm_mode_modeinfo,
+    // the VRAM page on screen (0 or 1)
+    pub page: u32,
+}
+
+static mut LOCK_DRM: Spinlock = Spinlock { _priv: 0 };
+
+static mut drmBackend: *const DrmBackend = null();
+static mut drmBootBackend: DrmBackend = DrmBackend {
+    name: b"Boot framebuffer\0".as_ptr(),
+    maxWidth: 0,
+    maxHeight: 0,
+    vramSize: 0,
+    setMode: drmBootSetMode,
+    pan: None,
+    update: None,
+};
+
+// the mode the console was using before anyone touched the CRTC
+static mut drmConsoleWidth: u32 = 0;
+static mut drmConsoleHeight: u32 = 0;
+
+static mut drmModes: [drm_mode_modeinfo; DRM_MAX_MODES] = unsafe { core::mem::zeroed() };
+static mut drmModeCount: usize = 0;
+
+static mut drmBuffers: [DrmBuffer; DRM_MAX_BUFFERS] = unsafe { core::mem::zeroed() };
+static mut drmFbs: [DrmFb; DRM_MAX_FBS] = unsafe { core::mem::zeroed() };
+static mut drmWaiters: [DrmWaiter; DRM_MAX_WAITERS] = unsafe { core::mem::zeroed() };
+static mut drmFlip: DrmFlip = unsafe { core::mem::zeroed() };
+static mut drmCrtc: DrmCrtc = unsafe { core::mem::zeroed() };
+
+static mut drmOpens: usize = 0;
+// somebody put their own framebuffer on screen
+static mut drmTakenOver: bool = false;
+
+static mut drmSequence: u32 = 0;
+static mut drmLastVblank: u64 = 0;
+static mut drmThread: bool = false;
+
+const DRM_NAME: &[u8] = b"cavos_kms";
+const DRM_DATE: &[u8] = b"20250101";
+
+// the usual VESA/CVT sizes, cut down to what the backend can show
+const DRM_STANDARD_MODES: [(u32, u32); 14] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 800),
+    (1280, 1024),
+    (1366, 768),
+    (1440, 900),
+    (1600, 900),
+    (1600, 1200),
+    (1680, 1050),
+    (1920, 1080),
+    (1920, 1200),
+    (2560, 1440),
+];
+
+// --------------------------------
+// Helpers
+// --------------------------------
+
+#[inline]
+unsafe fn drmFromFd(fd: *mut OpenFile) -> *mut DrmFile {
+    (*fd).dir as *mut DrmFile
+}
+
+#[inline]
+unsafe fn drmCopyOut<T>(dst: u64, src: *const T, count: usize) {
+    copy_nonoverlapping(src, dst as *mut T, count);
+}
+
+// copies a string out the DRM_IOCTL_VERSION way, returning the full length
+unsafe fn drmCopyString(dst: *mut u8, len: usize, src: &[u8]) -> usize {
+    if !dst.is_null() {
+        copy_nonoverlapping(src.as_ptr(), dst, min(len, src.len()));
+    }
+    src.len()
+}
+
+// a buffer by its (still open) handle
+unsafe fn drmBuffer(handle: u32) -> *mut DrmBuffer {
+    if handle == 0 || handle as usize > DRM_MAX_BUFFERS {
+        return null_mut();
+    }
+    let buffer = &mut drmBuffers[handle as usize - 1];
+    if buffer.owner.is_null() {
+        return null_mut();
+    }
+    buffer
+}
+
+// Drops a reference, freeing the buffer with the last one (LOCK_DRM held)
+unsafe fn drmBufferPut(buffer: *mut DrmBuffer) {
+    (*buffer).refs -= 1;
+    if (*buffer).refs == 0 {
+        VirtualFree((*buffer).virt, (*buffer).pages);
+        write_bytes(buffer, 0, 1);
+    }
+}
+
+// FileMapping hold of dumb buffer mappings, `ctx` being the buffer
+unsafe extern "C" fn drmBufferHold(ctx: *mut c_void, take: bool) {
+    let buffer = ctx as *mut DrmBuffer;
+    spinlockAcquire(&mut LOCK_DRM);
+    if take {
+        (*buffer).refs += 1;
+    } else {
+        drmBufferPut(buffer);
+    }
+    spinlockRelease(&mut LOCK_DRM);
+}
+
+unsafe fn drmFb(id: u32) -> *mut DrmFb {
+    if id < DRM_FB_ID_BASE || (id - DRM_FB_ID_BASE) as usize >= DRM_MAX_FBS {
+        return null_mut();
+    }
+    let fbo = &mut drmFbs[(id - DRM_FB_ID_BASE) as usize];
+    if fbo.owner.is_null() {
+        return null_mut();
+    }
+    fbo
+}
+
+#[inline]
+unsafe fn drmVramPages() -> u32 {
+    let page = fb.pitch * fb.height;
+    if (*drmBackend).pan.is_some() && page * 2 <= (*drmBackend).vramSize {
+        2
+    } else {
+        1
+    }
+}
+
+// Generates a mode with reduced-blanking style timings, 60Hz
+fn drmModeMake(width: u32, height: u32, preferred: bool) -> drm_mode_modeinfo {
+    let mut mode: drm_mode_modeinfo = unsafe { core::mem::zeroed() };
+    mode.hdisplay = width as u16;
+    mode.hsync_start = (width + 48) as u16;
+    mode.hsync_end = (width + 80) as u16;
+    mode.htotal = (width + 160) as u16;
+    mode.vdisplay = height as u16;
+    mode.vsync_start = (height + 3) as u16;
+    mode.vsync_end = (height + 9) as u16;
+    mode.vtotal = (height + 32) as u16;
+    mode.vrefresh = 60;
+    mode.clock = mode.htotal as u32 * mode.vtotal as u32 * 60 / 1000;
+    mode.flags = DRM_MODE_FLAG_NHSYNC | DRM_MODE_FLAG_PVSYNC;
+    mode.type_ = DRM_MODE_TYPE_DRIVER;
+    if preferred {
+        mode.type_ |= DRM_MODE_TYPE_PREFERRED;
+    }
+
+    // "WIDTHxHEIGHT"
+    let mut digits = [0u8; 10];
+    let mut pos = 0;
+    for (i, value) in [width, height].iter().enumerate() {
+        if i == 1 {
+            mode.name[pos] = b'x';
+            pos += 1;
+        }
+        let mut value = *value;
+        let mut count = 0;
+        loop {
+            digits[count] = b'0' + (value % 10) as u8;
+            count += 1;
+            value /= 10;
+            if value == 0 {
+                break;
+            }
+        }
+        while count > 0 {
+            count -= 1;
+            mode.name[pos] = digits[count];
+            pos += 1;
+        }
+    }
+    mode
+}
+
+unsafe fn drmModesBuild() {
+    let backend = &*drmBackend;
+    drmModeCount = 0;
+
+    drmModes[0] = drmModeMake(drmConsoleWidth, drmConsoleHeight, true);
+    drmModeCount = 1;
+
+    for &(width, height) in DRM_STANDARD_MODES.iter() {
+        if drmModeCount >= DRM_MAX_MODES {
+            break;
+        }
+        if width > backend.maxWidth
+            || height > backend.maxHeight
+            || (width * height * 4) as usize > backend.vramSize
+            || (width == drmConsoleWidth && height == drmConsoleHeight)
+        {
+            continue;
+        }
+        drmModes[drmModeCount] = drmModeMake(width, height, false);
+        drmModeCount += 1;
+    }
+}
+
+unsafe fn drmModeFind(mode: &drm_mode_modeinfo) -> bool {
+    drmModes[..drmModeCount]
+        .iter()
+        .any(|m| m.hdisplay == mode.hdisplay && m.vdisplay == mode.vdisplay)
+}
+
+// Only the mode Limine set up, when there's no driver that can change it
+unsafe fn drmBootSetMode(width: u32, height: u32) -> bool {
+    width as usize == fb.width && height as usize == fb.height
+}
+
+// Picks the backend on first use, whatever registered by then wins over the
+// boot framebuffer
+unsafe fn drmSetup() -> bool {
+    if !drmBackend.is_null() {
+        return true;
+    }
+    if fb.virt.is_null() || fb.bpp != 32 {
+        return false;
+    }
+
+    drmBootBackend.maxWidth = fb.width as u32;
+    drmBootBackend.maxHeight = fb.height as u32;
+    drmBootBackend.vramSize = fb.pitch * fb.height;
+    drmConsoleWidth = fb.width as u32;
+    drmConsoleHeight = fb.height as u32;
+    drmBackend = core::ptr::addr_of!(drmBootBackend);
+    drmModesBuild();
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drmRegisterBackend(backend: *const DrmBackend) {
+    spinlockAcquire(&mut LOCK_DRM);
+    if drmOpens > 0 {
+        // too late to swap the device underneath its users
+        spinlockRelease(&mut LOCK_DRM);
+        return;
+    }
+    drmBackend = backend;
+    drmConsoleWidth = fb.width as u32;
+    drmConsoleHeight = fb.height as u32;
+    drmModesBuild();
+    spinlockRelease(&mut LOCK_DRM);
+
+    debugf(b"[drm] Using %s for /dev/dri/card0\n\0".as_ptr(), (*backend).name);
+}
+
+// For fbdev, which mustn't change modes underneath a KMS client
+#[no_mangle]
+pub unsafe extern "C" fn drmInUse() -> bool {
+    drmOpens > 0
+}
+
+// Switches the hardware mode, the console follows along
+unsafe fn drmModeSet(width: u32, height: u32) -> bool {
+    if width as usize == fb.width && height as usize == fb.height {
+        return true;
+    }
+    if !((*drmBackend).setMode)(width, height) {
+        return false;
+    }
+    drmCrtc.page = 0;
+    vtModeChanged();
+    true
+}
+
+// Copies a rectangle of a framebuffer object into a VRAM page
+unsafe fn drmBlit(fbo: *const DrmFb, page: u32, x: u32, y: u32, width: u32, height: u32) {
+    // the framebuffer keeps its buffer alive, closed handle or not
+    let buffer = &drmBuffers[(*fbo).buffer as usize - 1];
+
+    let right = min(x + width, min((*fbo).width - drmCrtc.x, fb.width as u32));
+    let bottom = min(y + height, min((*fbo).height - drmCrtc.y, fb.height as u32));
+    if right <= x || bottom <= y {
+        return;
+    }
+
+    let vram = fb.virt.add(page as usize * fb.pitch * fb.height);
+    let src = buffer.virt.add((*fbo).offset as usize);
+    for row in y..bottom {
+        copy_nonoverlapping(
+            src.add((drmCrtc.y + row) as usize * (*fbo).pitch as usize + (drmCrtc.x + x) as usize * 4),
+            vram.add(row as usize * fb.pitch + x as usize * 4),
+            (right - x) as usize * 4,
+        );
+    }
+
+    if let Some(update) = (*drmBackend).update {
+        update(x, y, right - x, bottom - y);
+    }
+}
+
+// Queues an event on a file (LOCK_DRM held)
+unsafe fn drmEventQueue(file: *mut DrmFile, type_: u32, userData: u64, sequence: u32) {
+    if file.is_null() || (*file).eventCount >= DRM_MAX_EVENTS {
+        return;
+    }
+
+    let index = ((*file).eventFirst + (*file).eventCount) % DRM_MAX_EVENTS;
+    let event = &mut (*file).events[index];
+    event.type_ = type_;
+    event.length = size_of::<drm_event_vblank>() as u32;
+    event.user_data = userData;
+    event.tv_sec = (drmLastVblank / 1000) as u32;
+    event.tv_usec = ((drmLastVblank % 1000) * 1000) as u32;
+    event.sequence = sequence;
+    event.crtc_id = DRM_CRTC_ID;
+    (*file).eventCount += 1;
+
+    pollInstanceRing(file as usize, EPOLLIN);
+}
+
+// --------------------------------
+// Vblank
+// --------------------------------
+
+unsafe fn drmVblank() {
+    spinlockAcquire(&mut LOCK_DRM);
+    drmSequence = drmSequence.wrapping_add(1);
+    drmLastVblank = timerTicks;
+
+    if drmFlip.pending {
+        if let Some(pan) = (*drmBackend).pan {
+            if drmVramPages() > 1 {
+                pan(drmFlip.page * fb.height as u32);
+                drmCrtc.page = drmFlip.page;
+            }
+        }
+        if drmFlip.event {
+            drmEventQueue(drmFlip.file, DRM_EVENT_FLIP_COMPLETE, drmFlip.userData, drmSequence);
+        }
+        drmFlip.pending = false;
+    }
+
+    for waiter in drmWaiters.iter_mut() {
+        if waiter.file.is_null() || (drmSequence.wrapping_sub(waiter.sequence) as i32) < 0 {
+            continue;
+        }
+        drmEventQueue(waiter.file, DRM_EVENT_VBLANK, waiter.userData, drmSequence);
+        waiter.file = null_mut();
+    }
+    spinlockRelease(&mut LOCK_DRM);
+}
+
+extern "C" fn drmVblankThread() {
+    unsafe {
+        loop {
+            sleep(DRM_VBLANK_MS as u32);
+            drmVblank();
+        }
+    }
+}
+
+unsafe fn drmThreadStart() {
+    if drmThread {
+        return;
+    }
+    drmThread = true;
+
+    static NAME: &[u8] = b"drmvblank";
+    let task = taskCreateKernel(drmVblankThread as u64, 0);
+    taskNameKernel(task, NAME.as_ptr(), NAME.len());
+}
+
+// Waits for a vblank sequence, for DRM_IOCTL_WAIT_VBLANK without an event
+unsafe fn drmVblankWait(sequence: u32) -> usize {
+    while (drmSequence.wrapping_sub(sequence) as i32) < 0 {
+        if signalsPendingQuick(currentTask) {
+            return err(EINTR);
+        }
+        handControl();
+    }
+    0
+}
+
+// --------------------------------
+// Ioctls
+// --------------------------------
+
+unsafe fn drmGetCap(cap: *mut drm_get_cap) -> usize {
+    (*cap).value = match (*cap).capability {
+        DRM_CAP_DUMB_BUFFER => 1,
+        DRM_CAP_VBLANK_HIGH_CRTC => 1,
+        DRM_CAP_DUMB_PREFERRED_DEPTH => 24,
+        DRM_CAP_DUMB_PREFER_SHADOW => 1,
+        DRM_CAP_TIMESTAMP_MONOTONIC => 1,
+        DRM_CAP_CRTC_IN_VBLANK_EVENT => 1,
+        DRM_CAP_CURSOR_WIDTH | DRM_CAP_CURSOR_HEIGHT => 64,
+        DRM_CAP_PRIME
+        | DRM_CAP_ASYNC_PAGE_FLIP
+        | DRM_CAP_ADDFB2_MODIFIERS
+        | DRM_CAP_PAGE_FLIP_TARGET
+        | DRM_CAP_SYNCOBJ => 0,
+        _ => return err(EINVAL),
+    };
+    0
+}
+
+unsafe fn drmSetClientCap(file: *mut DrmFile, cap: *const drm_set_client_cap) -> usize {
+    match (*cap).capability {
+        DRM_CLIENT_CAP_UNIVERSAL_PLANES => {
+            (*file).universalPlanes = (*cap).value != 0;
+            0
+        }
+        // no atomic modesetting, clients fall back to the legacy ioctls
+        DRM_CLIENT_CAP_STEREO_3D | DRM_CLIENT_CAP_ATOMIC => err(EOPNOTSUPP),
+        _ => err(EINVAL),
+    }
+}
+
+unsafe fn drmGetResources(file: *mut DrmFile, res: *mut drm_mode_card_res) -> usize {
+    let mut fbs = 0u32;
+    for (i, fbo) in drmFbs.iter().enumerate() {
+        if fbo.owner != file {
+            continue;
+        }
+        if fbs < (*res).count_fbs {
+            let id = DRM_FB_ID_BASE + i as u32;
+            drmCopyOut((*res).fb_id_ptr + fbs as u64 * 4, &id, 1);
+        }
+        fbs += 1;
+    }
+
+    if (*res).count_crtcs >= 1 {
+        drmCopyOut((*res).crtc_id_ptr, &DRM_CRTC_ID, 1);
+    }
+    if (*res).count_connectors >= 1 {
+        drmCopyOut((*res).connector_id_ptr, &DRM_CONNECTOR_ID, 1);
+    }
+    if (*res).count_encoders >= 1 {
+        drmCopyOut((*res).encoder_id_ptr, &DRM_ENCODER_ID, 1);
+    }
+
+    (*res).count_fbs = fbs;
+    (*res).count_crtcs = 1;
+    (*res).count_connectors = 1;
+    (*res).count_encoders = 1;
+    (*res).min_width = 1;
+    (*res).min_height = 1;
+    (*res).max_width = (*drmBackend).maxWidth;
+    (*res).max_height = (*drmBackend).maxHeight;
+    0
+}
+
+unsafe fn drmGetConnector(conn: *mut drm_mode_get_connector) -> usize {
+    if (*conn).connector_id != DRM_CONNECTOR_ID {
+        return err(ENOENT);
+    }
+
+    if (*conn).count_modes as usize >= drmModeCount {
+        drmCopyOut((*conn).modes_ptr, drmModes.as_ptr(), drmModeCount);
+    }
+    if (*conn).count_encoders >= 1 {
+        drmCopyOut((*conn).encoders_ptr, &DRM_ENCODER_ID, 1);
+    }
+
+    (*conn).count_modes = drmModeCount as u32;
+    (*conn).count_encoders = 1;
+    (*conn).count_props = 0;
+    (*conn).encoder_id = DRM_ENCODER_ID;
+    (*conn).connector_type = DRM_MODE_CONNECTOR_VIRTUAL;
+    (*conn).connector_type_id = 1;
+    (*conn).connection = DRM_MODE_CONNECTED;
+    // as if it was 96 DPI
+    (*conn).mm_width = fb.width as u32 * 254 / 960;
+    (*conn).mm_height = fb.height as u32 * 254 / 960;
+    (*conn).subpixel = DRM_MODE_SUBPIXEL_UNKNOWN;
+    0
+}
+
+unsafe fn drmGetEncoder(enc: *mut drm_mode_get_encoder) -> usize {
+    if (*enc).encoder_id != DRM_ENCODER_ID {
+        return err(ENOENT);
+    }
+    (*enc).encoder_type = DRM_MODE_ENCODER_VIRTUAL;
+    (*enc).crtc_id = DRM_CRTC_ID;
+    (*enc).possible_crtcs = 1;
+    (*enc).possible_clones = 0;
+    0
+}
+
+unsafe fn drmGetCrtc(crtc: *mut drm_mode_crtc) -> usize {
+    if (*crtc).crtc_id != DRM_CRTC_ID {
+        return err(ENOENT);
+    }
+    (*crtc).fb_id = if drmCrtc.enabled { drmCrtc.fb } else { 0 };
+    (*crtc).x = drmCrtc.x;
+    (*crtc).y = drmCrtc.y;
+    (*crtc).gamma_size = DRM_GAMMA_SIZE;
+    (*crtc).mode_valid = drmCrtc.enabled as u32;
+    (*crtc).mode = if drmCrtc.enabled {
+        drmCrtc.mode
+    } else {
+        core::mem::zeroed()
+    };
+    0
+}
+
+unsafe fn drmSetCrtc(crtc: *mut drm_mode_crtc) -> usize {
+    if (*crtc).crtc_id != DRM_CRTC_ID {
+        return err(ENOENT);
+    }
+
+    if (*crtc).fb_id == 0 || (*crtc).mode_valid == 0 {
+        drmCrtc.enabled = false;
+        drmCrtc.fb = 0;
+        return 0;
+    }
+
+    if (*crtc).count_connectors > 1 {
+        return err(EINVAL);
+    }
+    if (*crtc).count_connectors == 1 {
+        let mut connector = 0u32;
+        copy_nonoverlapping((*crtc).set_connectors_ptr as *const u32, &mut connector, 1);
+        if connector != DRM_CONNECTOR_ID {
+            return err(ENOENT);
+        }
+    }
+
+    let fbo = drmFb((*crtc).fb_id);
+    if fbo.is_null() {
+        return err(ENOENT);
+    }
+    let mode = (*crtc).mode;
+    if !drmModeFind(&mode) {
+        return err(EINVAL);
+    }
+    if (*crtc).x + mode.hdisplay as u32 > (*fbo).width
+        || (*crtc).y + mode.vdisplay as u32 > (*fbo).height
+    {
+        return err(ENOSPC);
+    }
+
+    if !drmModeSet(mode.hdisplay as u32, mode.vdisplay as u32) {
+        return err(EINVAL);
+    }
+
+    drmTakenOver = true;
+    drmCrtc.enabled = true;
+    drmCrtc.fb = (*crtc).fb_id;
+    drmCrtc.x = (*crtc).x;
+    drmCrtc.y = (*crtc).y;
+    drmCrtc.mode = mode;
+
+    drmBlit(fbo, drmCrtc.page, 0, 0, mode.hdisplay as u32, mode.vdisplay as u32);
+    0
+}
+
+unsafe fn drmGetGamma(lut: *mut drm_mode_crtc_lut) -> usize {
+    if (*lut).crtc_id != DRM_CRTC_ID {
+        return err(ENOENT);
+    }
+    if (*lut).gamma_size != DRM_GAMMA_SIZE {
+        return err(EINVAL);
+    }
+
+    // no hardware LUT, it's always linear
+    for i in 0..DRM_GAMMA_SIZE as u64 {
+        let value = (i * 0x101) as u16;
+        drmCopyOut((*lut).red + i * 2, &value, 1);
+        drmCopyOut((*lut).green + i * 2, &value, 1);
+        drmCopyOut((*lut).blue + i * 2, &value, 1);
+    }
+    0
+}
+
+unsafe fn drmCreateDumb(file: *mut DrmFile, args: *mut drm_mode_create_dumb) -> usize {
+    if (*args).bpp != 32
+        || (*args).width == 0
+        || (*args).height == 0
+        || (*args).width > DRM_MAX_DUMB_SIZE
+        || (*args).height > DRM_MAX_DUMB_SIZE
+    {
+        return err(EINVAL);
+    }
+
+    let Some(index) = drmBuffers.iter().position(|b| b.virt.is_null()) else {
+        return err(ENOSPC);
+    };
+
+    let pitch = ((*args).width * 4 + 63) & !63;
+    let size = pitch as usize * (*args).height as usize;
+    let pages = size.div_ceil(PAGE_SIZE);
+    let virt = VirtualAllocate(pages);
+    if virt.is_null() {
+        return err(ENOMEM);
+    }
+    write_bytes(virt, 0, pages * PAGE_SIZE);
+
+    let buffer = &mut drmBuffers[index];
+    buffer.owner = file;
+    buffer.virt = virt;
+    buffer.pages = pages;
+    buffer.width = (*args).width;
+    buffer.height = (*args).height;
+    buffer.pitch = pitch;
+    buffer.size = pages * PAGE_SIZE;
+    buffer.refs = 1;
+
+    (*args).handle = index as u32 + 1;
+    (*args).pitch = pitch;
+    (*args).size = buffer.size as u64;
+    0
+}
+
+unsafe fn drmDestroyDumb(file: *mut DrmFile, handle: u32) -> usize {
+    let buffer = drmBuffer(handle);
+    if buffer.is_null() || (*buffer).owner != file {
+        return err(ENOENT);
+    }
+
+    // framebuffers and mappings made from it keep the pages
+    (*buffer).owner = null_mut();
+    drmBufferPut(buffer);
+    0
+}
+
+unsafe fn drmAddFb(
+    file: *mut DrmFile,
+    width: u32,
+    height: u32,
+    pitch: u32,
+    offset: u32,
+    handle: u32,
+    format: u32,
+) -> Result<u32, usize> {
+    let buffer = drmBuffer(handle);
+    if buffer.is_null() || (*buffer).owner != file {
+        return Err(err(ENOENT));
+    }
+    if width == 0
+        || height == 0
+        || pitch < width * 4
+        || offset as usize + pitch as usize * (height as usize - 1) + width as usize * 4
+            > (*buffer).size
+    {
+        return Err(err(EINVAL));
+    }
+
+    let Some(index) = drmFbs.iter().position(|f| f.owner.is_null()) else {
+        return Err(err(ENOSPC));
+    };
+    drmFbs[index] = DrmFb {
+        owner: file,
+        buffer: handle,
+        width,
+        height,
+        pitch,
+        offset,
+        format,
+    };
+    (*buffer).refs += 1;
+    Ok(DRM_FB_ID_BASE + index as u32)
+}
+
+unsafe fn drmRmFb(file: *mut DrmFile, id: u32) -> usize {
+    let fbo = drmFb(id);
+    if fbo.is_null() || (*fbo).owner != file {
+        return err(ENOENT);
+    }
+    if drmCrtc.fb == id {
+        // whatever it showed stays in VRAM
+        drmCrtc.enabled = false;
+        drmCrtc.fb = 0;
+    }
+    drmBufferPut(&mut drmBuffers[(*fbo).buffer as usize - 1]);
+    write_bytes(fbo, 0, 1);
+    0
+}
+
+unsafe fn drmPageFlip(file: *mut DrmFile, flip: *const drm_mode_crtc_page_flip) -> usize {
+    if (*flip).crtc_id != DRM_CRTC_ID {
+        return err(ENOENT);
+    }
+    if (*flip).flags & !(DRM_MODE_PAGE_FLIP_EVENT | DRM_MODE_PAGE_FLIP_ASYNC) != 0
+        || (*flip).flags & DRM_MODE_PAGE_FLIP_ASYNC != 0
+    {
+        return err(EINVAL);
+    }
+    if !drmCrtc.enabled {
+        return err(EINVAL);
+    }
+    if drmFlip.pending {
+        return err(EBUSY);
+    }
+
+    let fbo = drmFb((*flip).fb_id);
+    if fbo.is_null() {
+        return err(ENOENT);
+    }
+    let mode = &drmCrtc.mode;
+    if drmCrtc.x + mode.hdisplay as u32 > (*fbo).width
+        || drmCrtc.y + mode.vdisplay as u32 > (*fbo).height
+    {
+        return err(ENOSPC);
+    }
+
+    // draw into the page that isn't on screen, the vblank shows it
+    let page = if drmVramPages() > 1 { 1 - drmCrtc.page } else { 0 };
+    drmBlit(fbo, page, 0, 0, mode.hdisplay as u32, mode.vdisplay as u32);
+
+    drmCrtc.fb = (*flip).fb_id;
+    drmFlip = DrmFlip {
+        pending: true,
+        file,
+        event: (*flip).flags & DRM_MODE_PAGE_FLIP_EVENT != 0,
+        userData: (*flip).user_data,
+        page,
+    };
+    0
+}
+
+unsafe fn drmDirtyFb(cmd: *const drm_mode_fb_dirty_cmd) -> usize {
+    let fbo = drmFb((*cmd).fb_id);
+    if fbo.is_null() {
+        return err(ENOENT);
+    }
+    if !drmCrtc.enabled || drmCrtc.fb != (*cmd).fb_id {
+        return 0;
+    }
+
+    let mode = &drmCrtc.mode;
+    if (*cmd).num_clips == 0 || (*cmd).num_clips > DRM_MAX_CLIPS {
+        drmBlit(fbo, drmCrtc.page, 0, 0, mode.hdisplay as u32, mode.vdisplay as u32);
+        return 0;
+    }
+
+    let clips = (*cmd).clips_ptr as *const drm_clip_rect;
+    for i in 0..(*cmd).num_clips as usize {
+        let clip = *clips.add(i);
+        if clip.x2 <= clip.x1 || clip.y2 <= clip.y1 {
+            continue;
+        }
+        // clips are in framebuffer coordinates
+        let x1 = (clip.x1 as u32).saturating_sub(drmCrtc.x);
+        let y1 = (clip.y1 as u32).saturating_sub(drmCrtc.y);
+        let x2 = (clip.x2 as u32).saturating_sub(drmCrtc.x);
+        let y2 = (clip.y2 as u32).saturating_sub(drmCrtc.y);
+        if x2 > x1 && y2 > y1 {
+            drmBlit(fbo, drmCrtc.page, x1, y1, x2 - x1, y2 - y1);
+        }
+    }
+    0
+}
+
+unsafe fn drmGetFb(file: *mut DrmFile, cmd: *mut drm_mode_fb_cmd) -> usize {
+    let fbo = drmFb((*cmd).fb_id);
+    if fbo.is_null() {
+        return err(ENOENT);
+    }
+    (*cmd).width = (*fbo).width;
+    (*cmd).height = (*fbo).height;
+    (*cmd).pitch = (*fbo).pitch;
+    (*cmd).bpp = 32;
+    (*cmd).depth = if (*fbo).format == DRM_FORMAT_ARGB8888 { 32 } else { 24 };
+    // handles aren't shared across files, nor handed out again once closed
+    (*cmd).handle = if (*fbo).owner == file && !drmBuffer((*fbo).buffer).is_null() {
+        (*fbo).buffer
+    } else {
+        0
+    };
+    0
+}
+
+unsafe fn drmGetPlaneResources(file: *mut DrmFile, res: *mut drm_mode_get_plane_res) -> usize {
+    // the primary plane is only visible to clients that asked for it
+    let count = (*file).universalPlanes as u32;
+    if count > 0 && (*res).count_planes >= count {
+        drmCopyOut((*res).plane_id_ptr, &DRM_PLANE_ID, 1);
+    }
+    (*res).count_planes = count;
+    0
+}
+
+unsafe fn drmGetPlane(plane: *mut drm_mode_get_plane) -> usize {
+    if (*plane).plane_id != DRM_PLANE_ID {
+        return err(ENOENT);
+    }
+
+    const FORMATS: [u32; 2] = [DRM_FORMAT_XRGB8888, DRM_FORMAT_ARGB8888];
+    if (*plane).count_format_types as usize >= FORMATS.len() {
+        drmCopyOut((*plane).format_type_ptr, FORMATS.as_ptr(), FORMATS.len());
+    }
+
+    (*plane).crtc_id = if drmCrtc.enabled { DRM_CRTC_ID } else { 0 };
+    (*plane).fb_id = if drmCrtc.enabled { drmCrtc.fb } else { 0 };
+    (*plane).possible_crtcs = 1;
+    (*plane).gamma_size = 0;
+    (*plane).count_format_types = FORMATS.len() as u32;
+    0
+}
+
+// The primary plane can only cover the whole CRTC, so this is a flip
+// without an event
+unsafe fn drmSetPlane(plane: *const drm_mode_set_plane) -> usize {
+    if (*plane).plane_id != DRM_PLANE_ID {
+        return err(ENOENT);
+    }
+    if (*plane).fb_id == 0 {
+        drmCrtc.enabled = false;
+        drmCrtc.fb = 0;
+        return 0;
+    }
+    if (*plane).crtc_id != DRM_CRTC_ID || !drmCrtc.enabled {
+        return err(EINVAL);
+    }
+
+    let mode = &drmCrtc.mode;
+    if (*plane).crtc_x != 0
+        || (*plane).crtc_y != 0
+        || (*plane).crtc_w != mode.hdisplay as u32
+        || (*plane).crtc_h != mode.vdisplay as u32
+        || (*plane).src_w >> 16 != mode.hdisplay as u32
+        || (*plane).src_h >> 16 != mode.vdisplay as u32
+    {
+        return err(EINVAL);
+    }
+
+    let fbo = drmFb((*plane).fb_id);
+    if fbo.is_null() {
+        return err(ENOENT);
+    }
+    let x = (*plane).src_x >> 16;
+    let y = (*plane).src_y >> 16;
+    if x + mode.hdisplay as u32 > (*fbo).width || y + mode.vdisplay as u32 > (*fbo).height {
+        return err(ENOSPC);
+    }
+
+    drmCrtc.fb = (*plane).fb_id;
+    drmCrtc.x = x;
+    drmCrtc.y = y;
+    drmBlit(fbo, drmCrtc.page, 0, 0, mode.hdisplay as u32, mode.vdisplay as u32);
+    0
+}
+
+unsafe fn drmObjGetProperties(props: *mut drm_mode_obj_get_properties) -> usize {
+    match (*props).obj_id {
+        DRM_PLANE_ID => {
+            // "type", so universal plane clients can tell it's the primary one
+            if (*props).count_props >= 1 {
+                drmCopyOut((*props).props_ptr, &DRM_PROP_TYPE_ID, 1);
+                drmCopyOut((*props).prop_values_ptr, &DRM_PLANE_TYPE_PRIMARY, 1);
+            }
+            (*props).count_props = 1;
+            0
+        }
+        DRM_CRTC_ID | DRM_CONNECTOR_ID | DRM_ENCODER_ID => {
+            (*props).count_props = 0;
+            0
+        }
+        id if !drmFb(id).is_null() => {
+            (*props).count_props = 0;
+            0
+        }
+        _ => err(ENOENT),
+    }
+}
+
+unsafe fn drmGetProperty(prop: *mut drm_mode_get_property) -> usize {
+    if (*prop).prop_id != DRM_PROP_TYPE_ID {
+        return err(ENOENT);
+    }
+
+    const ENUMS: [(u64, &[u8]); 3] = [
+        (DRM_PLANE_TYPE_OVERLAY, b"Overlay"),
+        (DRM_PLANE_TYPE_PRIMARY, b"Primary"),
+        (DRM_PLANE_TYPE_CURSOR, b"Cursor"),
+    ];
+
+    write_bytes((*prop).name.as_mut_ptr(), 0, 32);
+    copy_nonoverlapping(b"type".as_ptr(), (*prop).name.as_mut_ptr(), 4);
+    (*prop).flags = DRM_MODE_PROP_ENUM | DRM_MODE_PROP_IMMUTABLE;
+
+    if (*prop).count_values as usize >= ENUMS.len() {
+        for (i, (value, _)) in ENUMS.iter().enumerate() {
+            drmCopyOut((*prop).values_ptr + i as u64 * 8, value, 1);
+        }
+    }
+    if (*prop).count_enum_blobs as usize >= ENUMS.len() {
+        for (i, (value, name)) in ENUMS.iter().enumerate() {
+            let mut entry = drm_mode_property_enum {
+                value: *value,
+                name: [0; 32],
+            };
+            entry.name[..name.len()].copy_from_slice(name);
+            drmCopyOut(
+                (*prop).enum_blob_ptr + (i * size_of::<drm_mode_property_enum>()) as u64,
+                &entry,
+                1,
+            );
+        }
+    }
+    (*prop).count_values = ENUMS.len() as u32;
+    (*prop).count_enum_blobs = ENUMS.len() as u32;
+    0
+}
+
+unsafe fn drmWaitVblank(file: *mut DrmFile, wait: *mut drm_wait_vblank) -> usize {
+    let type_ = (*wait).type_;
+    if type_ & DRM_VBLANK_HIGH_CRTC_MASK != 0 {
+        // only crtc 0
+        return err(EINVAL);
+    }
+
+    spinlockAcquire(&mut LOCK_DRM);
+    let mut sequence = (*wait).sequence;
+    if type_ & DRM_VBLANK_TYPES_MASK == DRM_VBLANK_RELATIVE {
+        sequence = sequence.wrapping_add(drmSequence);
+    }
+    if type_ & DRM_VBLANK_NEXTONMISS != 0 && (drmSequence.wrapping_sub(sequence) as i32) >= 0 {
+        sequence = drmSequence.wrapping_add(1);
+    }
+
+    if type_ & DRM_VBLANK_EVENT != 0 {
+        if (drmSequence.wrapping_sub(sequence) as i32) >= 0 {
+            drmEventQueue(file, DRM_EVENT_VBLANK, (*wait).signal, drmSequence);
+        } else {
+            let Some(slot) = drmWaiters.iter_mut().find(|w| w.file.is_null()) else {
+                spinlockRelease(&mut LOCK_DRM);
+                return err(EBUSY);
+            };
+            *slot = DrmWaiter {
+                file,
+                sequence,
+                userData: (*wait).signal,
+            };
+        }
+        spinlockRelease(&mut LOCK_DRM);
+        (*wait).sequence = sequence;
+        return 0;
+    }
+    spinlockRelease(&mut LOCK_DRM);
+
+    let ret = drmVblankWait(sequence);
+    if ret != 0 {
+        return ret;
+    }
+    (*wait).sequence = drmSequence;
+    (*wait).signal = drmLastVblank / 1000;
+    (*wait).tval_usec = ((drmLastVblank % 1000) * 1000) as i64;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drmIoctl(fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    if (request >> 8) & 0xff != DRM_IOCTL_BASE {
+        return err(ENOTTY);
+    }
+    let file = drmFromFd(fd);
+    let number = (request & 0xff) as usize;
+
+    // vblank waits block, so they take the lock themselves
+    if number == DRM_IOCTL_WAIT_VBLANK {
+        return drmWaitVblank(file, arg as *mut drm_wait_vblank);
+    }
+
+    spinlockAcquire(&mut LOCK_DRM);
+    let ret = match number {
+        DRM_IOCTL_VERSION => {
+            let version = arg as *mut drm_version;
+            (*version).version_major = 1;
+            (*version).version_minor = 0;
+            (*version).version_patchlevel = 0;
+            let desc = (*drmBackend).name;
+            let mut descLen = 0;
+            while *desc.add(descLen) != 0 {
+                descLen += 1;
+            }
+            (*version).name_len = drmCopyString((*version).name, (*version).name_len, DRM_NAME);
+            (*version).date_len = drmCopyString((*version).date, (*version).date_len, DRM_DATE);
+            (*version).desc_len = drmCopyString(
+                (*version).desc,
+                (*version).desc_len,
+                core::slice::from_raw_parts(desc, descLen),
+            );
+            0
+        }
+        DRM_IOCTL_GET_UNIQUE => {
+            let unique = arg as *mut drm_unique;
+            (*unique).unique_len = drmCopyString((*unique).unique, (*unique).unique_len, DRM_NAME);
+            0
+        }
+        DRM_IOCTL_GET_MAGIC => {
+            (*(arg as *mut drm_auth)).magic = 1;
+            0
+        }
+        // everyone is master, there is nothing to authenticate against
+        DRM_IOCTL_AUTH_MAGIC | DRM_IOCTL_SET_MASTER | DRM_IOCTL_DROP_MASTER => 0,
+        DRM_IOCTL_GET_CAP => drmGetCap(arg as *mut drm_get_cap),
+        DRM_IOCTL_SET_CLIENT_CAP => drmSetClientCap(file, arg as *const drm_set_client_cap),
+        DRM_IOCTL_GEM_CLOSE => drmDestroyDumb(file, (*(arg as *const drm_gem_close)).handle),
+
+        DRM_IOCTL_MODE_GETRESOURCES => drmGetResources(file, arg as *mut drm_mode_card_res),
+        DRM_IOCTL_MODE_GETCONNECTOR => drmGetConnector(arg as *mut drm_mode_get_connector),
+        DRM_IOCTL_MODE_GETENCODER => drmGetEncoder(arg as *mut drm_mode_get_encoder),
+        DRM_IOCTL_MODE_GETCRTC => drmGetCrtc(arg as *mut drm_mode_crtc),
+        DRM_IOCTL_MODE_SETCRTC => drmSetCrtc(arg as *mut drm_mode_crtc),
+        DRM_IOCTL_MODE_GETGAMMA => drmGetGamma(arg as *mut drm_mode_crtc_lut),
+        DRM_IOCTL_MODE_SETGAMMA => 0,
+        // no hardware cursor, clients draw their own
+        DRM_IOCTL_MODE_CURSOR | DRM_IOCTL_MODE_CURSOR2 => err(ENXIO),
+
+        DRM_IOCTL_MODE_CREATE_DUMB => drmCreateDumb(file, arg as *mut drm_mode_create_dumb),
+        DRM_IOCTL_MODE_MAP_DUMB => {
+            let map = arg as *mut drm_mode_map_dumb;
+            let buffer = drmBuffer((*map).handle);
+            if buffer.is_null() || (*buffer).owner != file {
+                err(ENOENT)
+            } else {
+                (*map).offset = ((*map).handle as u64) << DRM_MAP_SHIFT;
+                0
+            }
+        }
+        DRM_IOCTL_MODE_DESTROY_DUMB => {
+            drmDestroyDumb(file, (*(arg as *const drm_mode_destroy_dumb)).handle)
+        }
+
+        DRM_IOCTL_MODE_ADDFB => {
+            let cmd = arg as *mut drm_mode_fb_cmd;
+            if (*cmd).bpp != 32 || ((*cmd).depth != 24 && (*cmd).depth != 32) {
+                err(EINVAL)
+            } else {
+                let format = if (*cmd).depth == 32 {
+                    DRM_FORMAT_ARGB8888
+                } else {
+                    DRM_FORMAT_XRGB8888
+                };
+                match drmAddFb(file, (*cmd).width, (*cmd).height, (*cmd).pitch, 0, (*cmd).handle, format)
+                {
+                    Ok(id) => {
+                        (*cmd).fb_id = id;
+                        0
+                    }
+                    Err(e) => e,
+                }
+            }
+        }
+        DRM_IOCTL_MODE_ADDFB2 => {
+            let cmd = arg as *mut drm_mode_fb_cmd2;
+            if (*cmd).pixel_format != DRM_FORMAT_XRGB8888
+                && (*cmd).pixel_format != DRM_FORMAT_ARGB8888
+            {
+                err(EINVAL)
+            } else if (*cmd).flags != 0 {
+                // no modifiers or interlaced buffers
+                err(EINVAL)
+            } else {
+                match drmAddFb(
+                    file,
+                    (*cmd).width,
+                    (*cmd).height,
+                    (*cmd).pitches[0],
+                    (*cmd).offsets[0],
+                    (*cmd).handles[0],
+                    (*cmd).pixel_format,
+                ) {
+                    Ok(id) => {
+                        (*cmd).fb_id = id;
+                        0
+                    }
+                    Err(e) => e,
+                }
+            }
+        }
+        DRM_IOCTL_MODE_RMFB => drmRmFb(file, *(arg as *const u32)),
+        DRM_IOCTL_MODE_GETFB => drmGetFb(file, arg as *mut drm_mode_fb_cmd),
+        DRM_IOCTL_MODE_PAGE_FLIP => drmPageFlip(file, arg as *const drm_mode_crtc_page_flip),
+        DRM_IOCTL_MODE_DIRTYFB => drmDirtyFb(arg as *const drm_mode_fb_dirty_cmd),
+
+        DRM_IOCTL_MODE_GETPLANERESOURCES => {
+            drmGetPlaneResources(file, arg as *mut drm_mode_get_plane_res)
+        }
+        DRM_IOCTL_MODE_GETPLANE => drmGetPlane(arg as *mut drm_mode_get_plane),
+        DRM_IOCTL_MODE_SETPLANE => drmSetPlane(arg as *const drm_mode_set_plane),
+        DRM_IOCTL_MODE_OBJ_GETPROPERTIES => {
+            drmObjGetProperties(arg as *mut drm_mode_obj_get_properties)
+        }
+        DRM_IOCTL_MODE_GETPROPERTY => drmGetProperty(arg as *mut drm_mode_get_property),
+        DRM_IOCTL_MODE_SETPROPERTY
+        | DRM_IOCTL_MODE_OBJ_SETPROPERTY
+        | DRM_IOCTL_MODE_GETPROPBLOB => err(EINVAL),
+        DRM_IOCTL_MODE_ATOMIC => err(EOPNOTSUPP),
+
+        _ => err(EINVAL),
+    };
+    spinlockRelease(&mut LOCK_DRM);
+    ret
+}
+
+// --------------------------------
+// /dev/dri/card0 handlers
+// --------------------------------
+
+#[no_mangle]
+pub unsafe extern "C" fn drmOpen(
+    _filename: *mut u8,
+    _flags: i32,
+    _mode: i32,
+    fd: *mut OpenFile,
+    _sym: *mut *mut u8,
+) -> usize {
+    spinlockAcquire(&mut LOCK_DRM);
+    if !drmSetup() {
+        spinlockRelease(&mut LOCK_DRM);
+        return err(ENODEV);
+    }
+
+    let file = calloc(1, size_of::<DrmFile>()) as *mut DrmFile;
+    if file.is_null() {
+        spinlockRelease(&mut LOCK_DRM);
+        return err(ENOMEM);
+    }
+    (*file).opens = 1;
+    (*fd).dir = file as *mut c_void;
+    if drmOpens == 0
+        && (fb.width as u32 != drmConsoleWidth || fb.height as u32 != drmConsoleHeight)
+    {
+        // fbdev switched modes meanwhile, that's what the console uses now
+        drmConsoleWidth = fb.width as u32;
+        drmConsoleHeight = fb.height as u32;
+        drmModesBuild();
+    }
+    drmOpens += 1;
+    spinlockRelease(&mut LOCK_DRM);
+
+    drmThreadStart();
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drmRead(fd: *mut OpenFile, out: *mut u8, limit: usize) -> usize {
+    let file = drmFromFd(fd);
+    let event = size_of::<drm_event_vblank>();
+    if limit < event {
+        return err(EINVAL);
+    }
+
+    loop {
+        spinlockAcquire(&mut LOCK_DRM);
+        let mut done = 0;
+        while (*file).eventCount > 0 && done + event <= limit {
+            copy_nonoverlapping(
+                &(*file).events[(*file).eventFirst] as *const drm_event_vblank as *const u8,
+                out.add(done),
+                event,
+            );
+            (*file).eventFirst = ((*file).eventFirst + 1) % DRM_MAX_EVENTS;
+            (*file).eventCount -= 1;
+            done += event;
+        }
+        spinlockRelease(&mut LOCK_DRM);
+
+        if done > 0 {
+            return done;
+        }
+        if (*fd).flags & O_NONBLOCK != 0 {
+            return err(EAGAIN);
+        }
+        if signalsPendingQuick(currentTask) {
+            return err(EINTR);
+        }
+        handControl();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drmInternalPoll(fd: *mut OpenFile, events: i32) -> i32 {
+    if (*drmFromFd(fd)).eventCount > 0 {
+        events & EPOLLIN
+    } else {
+        0
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drmReportKey(fd: *mut OpenFile) -> usize {
+    drmFromFd(fd) as usize
+}
+
+// Dumb buffers, at the offset MAP_DUMB gave out. Always shared: the whole
+// point is that we see what userspace draws.
+#[no_mangle]
+pub unsafe extern "C" fn drmMmap(
+    addr: usize,
+    length: usize,
+    _prot: i32,
+    flags: i32,
+    fd: *mut OpenFile,
+    pgoffset: usize,
+) -> usize {
+    let file = drmFromFd(fd);
+    // mmap(2)'s offset comes through in bytes, as MAP_DUMB handed it out
+    let handle = (pgoffset >> DRM_MAP_SHIFT) as u32;
+
+    spinlockAcquire(&mut LOCK_DRM);
+    let buffer = drmBuffer(handle);
+    if buffer.is_null()
+        || (*buffer).owner != file
+        || pgoffset & ((1 << DRM_MAP_SHIFT) - 1) != 0
+        || length > (*buffer).size
+    {
+        spinlockRelease(&mut LOCK_DRM);
+        return err(EINVAL);
+    }
+
+    // the mapping's reference, taken before LOCK_DRM goes: LOCK_PD nests
+    // outside it once munmap() hands the reference back
+    (*buffer).refs += 1;
+    spinlockRelease(&mut LOCK_DRM);
+
+    let pages = length.div_ceil(PAGE_SIZE);
+    let pd = (*currentTask).infoPd;
+    let virt = if flags & MAP_FIXED != 0 {
+        addr
+    } else {
+        spinlockAcquire(&mut (*pd).LOCK_PD);
+        let virt = (*pd).mmap_end as usize;
+        (*pd).mmap_end += (pages * PAGE_SIZE) as u64;
+        spinlockRelease(&mut (*pd).LOCK_PD);
+        virt
+    };
+
+    for i in 0..pages {
+        let phys = VirtualToPhysical((*buffer).virt as usize + i * PAGE_SIZE);
+        VirtualMap(virt + i * PAGE_SIZE, phys, PF_RW | PF_USER | PF_SHARED);
+    }
+
+    spinlockAcquire(&mut (*pd).LOCK_PD);
+    taskInfoPdMappingAddHeld(
+        pd,
+        virt,
+        virt + pages * PAGE_SIZE,
+        pgoffset,
+        drmBufferHold,
+        buffer as *mut c_void,
+    );
+    spinlockRelease(&mut (*pd).LOCK_PD);
+    virt
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drmDuplicate(orig: *mut OpenFile, new: *mut OpenFile) -> bool {
+    let file = drmFromFd(orig);
+    spinlockAcquire(&mut LOCK_DRM);
+    (*file).opens += 1;
+    spinlockRelease(&mut LOCK_DRM);
+    (*new).dir = file as *mut c_void;
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn drmClose(fd: *mut OpenFile) -> bool {
+    let file = drmFromFd(fd);
+
+    spinlockAcquire(&mut LOCK_DRM);
+    (*file).opens -= 1;
+    if (*file).opens > 0 {
+        spinlockRelease(&mut LOCK_DRM);
+        return true;
+    }
+
+    for i in 0..DRM_MAX_FBS {
+        if drmFbs[i].owner == file {
+            drmRmFb(file, DRM_FB_ID_BASE + i as u32);
+        }
+    }
+    for i in 0..DRM_MAX_BUFFERS {
+        if drmBuffers[i].owner == file {
+            drmDestroyDumb(file, i as u32 + 1);
+        }
+    }
+    for waiter in drmWaiters.iter_mut() {
+        if waiter.file == file {
+            waiter.file = null_mut();
+        }
+    }
+    if drmFlip.file == file {
+        drmFlip.file = null_mut();
+        drmFlip.event = false;
+    }
+
+    drmOpens -= 1;
+    let restore = drmOpens == 0 && drmTakenOver;
+    if restore {
+        // last one out gives the console its mode and first page back
+        let backend = &*drmBackend;
+        if fb.width != drmConsoleWidth as usize || fb.height != drmConsoleHeight as usize {
+            (backend.setMode)(drmConsoleWidth, drmConsoleHeight);
+        } else if let Some(pan) = backend.pan {
+            pan(0);
+        }
+        drmCrtc = core::mem::zeroed();
+        drmFlip.pending = false;
+        drmTakenOver = false;
+    }
+    spinlockRelease(&mut LOCK_DRM);
+
+    if restore {
+        vtModeChanged();
+    }
+    free(file as *mut u8);
+    true
+}
+
+// --------------------------------
+// Registration
+// --------------------------------
+
+#[no_mangle]
+pub static handleDrm: VfsHandlers = VfsHandlers {
+    open: Some(drmOpen),
+    duplicate: Some(drmDuplicate),
+    close: Some(drmClose),
+    read: Some(drmRead),
+    internalPoll: Some(drmInternalPoll),
+    ioctl: Some(drmIoctl),
+    mmap: Some(drmMmap),
+    reportKey: Some(drmReportKey),
+    stat: Some(fakefsFstat),
+};
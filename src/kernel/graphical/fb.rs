@@ -64,7 +64,7 @@ pub struct fb_bitfield {
 pub struct fb_fix_screeninfo {
     pub id: [u8; 16],
     pub smem_start: usize,
-    pub smem_len: usize,
+    pub smem_len: u32,
     pub type_: u32,
     pub type_aux: u32,
     pub visual: u32,
@@ -73,8 +73,10 @@ pub struct fb_fix_screeninfo {
     pub ywrapstep: u16,
     pub line_length: u32,
     pub mmio_start: usize,
-    pub mmio_len: usize,
-    pub capabilities: u32,
+    pub mmio_len: u32,
+    pub accel: u32,
+    pub capabilities: u16,
+    pub reserved: [u16; 2],
 }
 
 #[repr(C)]
@@ -83,18 +85,49 @@ pub struct fb_var_screeninfo {
     pub yres: u32,
     pub xres_virtual: u32,
     pub yres_virtual: u32,
+    pub xoffset: u32,
+    pub yoffset: u32,
+
+    pub bits_per_pixel: u32,
+    pub grayscale: u32,
 
     pub red: fb_bitfield,
     pub green: fb_bitfield,
     pub blue: fb_bitfield,
     pub transp: fb_bitfield,
 
-    pub bits_per_pixel: u32,
-    pub grayscale: u32,
     pub nonstd: u32,
     pub activate: u32,
     pub height: u32,
     pub width: u32,
+    pub accel_flags: u32,
+
+    pub pixclock: u32,
+    pub left_margin: u32,
+    pub right_margin: u32,
+    pub upper_margin: u32,
+    pub lower_margin: u32,
+    pub hsync_len: u32,
+    pub vsync_len: u32,
+    pub sync: u32,
+    pub vmode: u32,
+    pub rotate: u32,
+    pub colorspace: u32,
+    pub reserved: [u32; 4],
+}
+
+// The head of drivers/bga.rs's state, what fbdev needs to know
+#[repr(C)]
+pub struct Bga {
+    pub exists: bool,
+    pub vramPhys: usize,
+    pub vram: *mut u8,
+    pub vramSize: usize,
+    pub maxWidth: u32,
+    pub maxHeight: u32,
+
+    pub virtHeight: u32,
+    pub yOffset: u32,
 }
 
 #[repr(C)]
@@ -129,6 +162,14 @@ extern "C" {
     fn VirtualMap(virt: usize, phys: usize, flags: usize);
 
     fn DivRoundUp(a: usize, b: usize) -> usize;
+
+    static BGA: Bga;
+    fn bgaModeSupported(width: u32, height: u32) -> bool;
+    fn bgaSetMode(width: u32, height: u32) -> bool;
+    fn bgaPan(line: u32) -> bool;
+
+    fn drmInUse() -> bool;
+    fn vtModeChanged();
 }
 
 // --------------------------------
@@ -141,13 +182,19 @@ const FB_VISUAL_TRUECOLOR: u32 = 2;
 const FBIOGET_FSCREENINFO: u64 = 0x4602;
 const FBIOGET_VSCREENINFO: u64 = 0x4600;
 const FBIOPUT_VSCREENINFO: u64 = 0x4601;
+const FBIOPAN_DISPLAY: u64 = 0x4606;
+
+const FB_ACTIVATE_MASK: u32 = 15;
+const FB_ACTIVATE_TEST: u32 = 2;
 
+const EBUSY: usize = 16;
+const EINVAL: usize = 22;
 const ENOTTY: usize = 25;
 const PAGE_SIZE: usize = 4096;
 
 const PF_RW: usize = 1 << 1;
 const PF_USER: usize = 1 << 2;
-const PF_CACHE_WC: usize = 1 << 7;
+const PF_CACHE_WC: usize = (1 << 7) | (1 << 3);
 
 const S_IFCHR: u32 = 0o020000;
 const S_IRUSR: u32 = 0o400;
@@ -193,6 +240,122 @@ pub unsafe extern "C" fn drawRect(
     }
 }
 
+// --------------------------------
+// Mode setting
+// --------------------------------
+
+// Lines userspace may draw into and pan over, the mode itself without BGA
+unsafe fn fbVirtualHeight() -> u32 {
+    if BGA.exists {
+        BGA.virtHeight
+    } else {
+        fb.height as u32
+    }
+}
+
+unsafe fn fbYOffset() -> u32 {
+    if BGA.exists {
+        BGA.yOffset
+    } else {
+        0
+    }
+}
+
+unsafe fn fbPan(var: *const fb_var_screeninfo) -> usize {
+    if (*var).xoffset != 0 || (*var).yoffset + fb.height as u32 > fbVirtualHeight() {
+        return ERR(EINVAL);
+    }
+    if (*var).yoffset == fbYOffset() {
+        return 0;
+    }
+    if !bgaPan((*var).yoffset) {
+        return ERR(EINVAL);
+    }
+    0
+}
+
+// Checks a requested mode, then switches to it unless it's only a test
+unsafe fn fbSetVar(var: *const fb_var_screeninfo) -> usize {
+    let width = (*var).xres;
+    let height = (*var).yres;
+    let same = width as usize == fb.width && height as usize == fb.height;
+
+    if (*var).bits_per_pixel != 0 && (*var).bits_per_pixel != 32 {
+        return ERR(EINVAL);
+    }
+    if (*var).xres_virtual > width {
+        return ERR(EINVAL);
+    }
+    if !same && !(BGA.exists && bgaModeSupported(width, height)) {
+        return ERR(EINVAL);
+    }
+
+    // the virtual height follows the width, ask the hardware for a new mode
+    // just to find out is overkill
+    let virtHeight = if BGA.exists {
+        core::cmp::min(BGA.vramSize / (width as usize * 4), 0xFFFF) as u32
+    } else {
+        fb.height as u32
+    };
+    if (*var).yres_virtual > virtHeight || (*var).yoffset + height > virtHeight {
+        return ERR(EINVAL);
+    }
+
+    if (*var).activate & FB_ACTIVATE_MASK == FB_ACTIVATE_TEST {
+        return 0;
+    }
+
+    if !same {
+        if drmInUse() {
+            return ERR(EBUSY);
+        }
+        if !bgaSetMode(width, height) {
+            return ERR(EINVAL);
+        }
+        vtModeChanged();
+    }
+    fbPan(var)
+}
+
+unsafe fn fbGetVar(fbtarg: *mut fb_var_screeninfo) {
+    ptr::write_bytes(fbtarg, 0, 1);
+
+    (*fbtarg).xres = fb.width as u32;
+    (*fbtarg).yres = fb.height as u32;
+    (*fbtarg).xres_virtual = fb.width as u32;
+    (*fbtarg).yres_virtual = fbVirtualHeight();
+    (*fbtarg).xoffset = 0;
+    (*fbtarg).yoffset = fbYOffset();
+
+    (*fbtarg).red = fb_bitfield {
+        offset: fb.red_shift,
+        length: fb.red_size,
+        msb_right: 1,
+    };
+    (*fbtarg).green = fb_bitfield {
+        offset: fb.green_shift,
+        length: fb.green_size,
+        msb_right: 1,
+    };
+    (*fbtarg).blue = fb_bitfield {
+        offset: fb.blue_shift,
+        length: fb.blue_size,
+        msb_right: 1,
+    };
+    (*fbtarg).transp = fb_bitfield {
+        offset: 24,
+        length: 8,
+        msb_right: 1,
+    };
+
+    (*fbtarg).bits_per_pixel = fb.bpp;
+    (*fbtarg).grayscale = 0;
+    (*fbtarg).nonstd = 0;
+    (*fbtarg).activate = 0;
+    (*fbtarg).height = (fb.height / 4) as u32;
+    (*fbtarg).width = (fb.width / 4) as u32;
+}
+
 // --------------------------------
 // Userspace handlers
 // --------------------------------
@@ -219,66 +382,44 @@ pub unsafe extern "C" fn fbUserIoctl(
         FBIOGET_FSCREENINFO => {
             let fbtarg = arg as *mut fb_fix_screeninfo;
 
-            memcpy(
-                (*fbtarg).id.as_mut_ptr(),
-                b"BIOS\0".as_ptr(),
-                5,
-            );
+            ptr::write_bytes(fbtarg, 0, 1);
+            if BGA.exists {
+                memcpy((*fbtarg).id.as_mut_ptr(), b"bochs-vbe\0".as_ptr(), 10);
+            } else {
+                memcpy((*fbtarg).id.as_mut_ptr(), b"BIOS\0".as_ptr(), 5);
+            }
 
+            let len = (fb.pitch * fbVirtualHeight() as usize) as u32;
             (*fbtarg).smem_start = fb.phys;
-            (*fbtarg).smem_len = fb.width * fb.height * 4;
+            (*fbtarg).smem_len = len;
             (*fbtarg).type_ = FB_TYPE_PACKED_PIXELS;
             (*fbtarg).type_aux = 0;
             (*fbtarg).visual = FB_VISUAL_TRUECOLOR;
             (*fbtarg).xpanstep = 0;
-            (*fbtarg).ypanstep = 0;
+            (*fbtarg).ypanstep = if BGA.exists { 1 } else { 0 };
             (*fbtarg).ywrapstep = 0;
-            (*fbtarg).line_length = (fb.width * 4) as u32;
+            (*fbtarg).line_length = fb.pitch as u32;
             (*fbtarg).mmio_start = fb.phys;
-            (*fbtarg).mmio_len = fb.width * fb.height * 4;
+            (*fbtarg).mmio_len = len;
             (*fbtarg).capabilities = 0;
             0
         }
 
-        FBIOPUT_VSCREENINFO => 0,
+        FBIOPUT_VSCREENINFO => {
+            let fbtarg = arg as *mut fb_var_screeninfo;
+            let ret = fbSetVar(fbtarg);
+            if ret == 0 {
+                fbGetVar(fbtarg);
+            }
+            ret
+        }
+
+        FBIOPAN_DISPLAY => fbPan(arg as *const fb_var_screeninfo),
 
         0x4605 => 0, // FBIOPUTCMAP (ignored)
 
         FBIOGET_VSCREENINFO => {
-            let fbtarg = arg as *mut fb_var_screeninfo;
-
-            (*fbtarg).xres = fb.width as u32;
-            (*fbtarg).yres = fb.height as u32;
-            (*fbtarg).xres_virtual = fb.width as u32;
-            (*fbtarg).yres_virtual = fb.height as u32;
-
-            (*fbtarg).red = fb_bitfield {
-                offset: fb.red_shift,
-                length: fb.red_size,
-                msb_right: 1,
-            };
-            (*fbtarg).green = fb_bitfield {
-                offset: fb.green_shift,
-                length: fb.green_size,
-                msb_right: 1,
-            };
-            (*fbtarg).blue = fb_bitfield {
-                offset: fb.blue_shift,
-                length: fb.blue_size,
-                msb_right: 1,
-            };
-            (*fbtarg).transp = fb_bitfield {
-                offset: 24,
-                length: 8,
-                msb_right: 1,
-            };
-
-            (*fbtarg).bits_per_pixel = fb.bpp;
-            (*fbtarg).grayscale = 0;
-            (*fbtarg).nonstd = 0;
-            (*fbtarg).activate = 0;
-            (*fbtarg).height = (fb.height / 4) as u32;
-            (*fbtarg).width = (fb.width / 4) as u32;
+            fbGetVar(arg as *mut fb_var_screeninfo);
             0
         }
 
@@ -295,8 +436,10 @@ pub unsafe extern "C" fn fbUserMmap(
     _fd: *mut OpenFile,
     _pgoffset: usize,
 ) -> usize {
-    if length == 0 {
-        length = fb.width * fb.height * 4;
+    // the whole virtual height, so double buffering needs a single mapping
+    let available = fb.pitch * fbVirtualHeight() as usize;
+    if length == 0 || length > available {
+        length = available;
     }
 
     let pages = DivRoundUp(length, PAGE_SIZE);
@@ -0,0 +1,1108 @@
+// Virtual terminals on top of the framebuffer console
+// Copyright (C) 2025 kevin dan mathew
+
+#![no_std]
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use core::ffi::c_void;
+
+use crate::ansi::AnsiState;
+use crate::console::ConsoleState;
+use crate::kb::{
+    kb_ioctl, KDGETLED, KDGKBLED, KDGKBTYPE, KDSETLED, KDSKBLED, K_ESCPREFIX, K_METABIT, K_OFF, K_RAW,
+    K_UNICODE,
+};
+use crate::keymap::{
+    keymap_ioctl, KDGKBDIACR, KDGKBDIACRUC, KDGKBENT, KDGKBSENT, KDSKBDIACR, KDSKBDIACRUC, KDSKBENT,
+    KDSKBSENT,
+};
+use crate::psf::UnicodePair;
+use crate::tty::*;
+
+// --------------------------------
+// Constants
+// --------------------------------
+
+// tty1..tty6, tty1 being the console tty (consoleTty)
+pub const VT_COUNT: usize = 6;
+const VT_NONE: usize = usize::MAX;
+
+const VT_OPENQRY: u64 = 0x5600;
+const VT_GETMODE: u64 = 0x5601;
+const VT_SETMODE: u64 = 0x5602;
+const VT_GETSTATE: u64 = 0x5603;
+const VT_RELDISP: u64 = 0x5605;
+const VT_ACTIVATE: u64 = 0x5606;
+const VT_WAITACTIVE: u64 = 0x5607;
+
+const KDSETMODE: u64 = 0x4B3A;
+const KDGETMODE: u64 = 0x4B3B;
+const KDGKBMODE: u64 = 0x4B44;
+const KDSKBMODE: u64 = 0x4B45;
+const KDGKBMETA: u64 = 0x4B62;
+const KDSKBMETA: u64 = 0x4B63;
+
+// fonts, as used by setfont
+const GIO_FONT: u64 = 0x4B60;
+const PIO_FONT: u64 = 0x4B61;
+const GIO_UNIMAP: u64 = 0x4B66;
+const PIO_UNIMAP: u64 = 0x4B67;
+const PIO_UNIMAPCLR: u64 = 0x4B68;
+const GIO_FONTX: u64 = 0x4B6B;
+const PIO_FONTX: u64 = 0x4B6C;
+const KDFONTOP: u64 = 0x4B72;
+
+const KD_FONT_OP_SET: u32 = 0;
+const KD_FONT_OP_GET: u32 = 1;
+const KD_FONT_OP_SET_DEFAULT: u32 = 2;
+
+// glyphs are exchanged 32 rows apart, whatever their height
+const FONT_VPITCH: u32 = 32;
+const FONT_MAX_WIDTH: u32 = 32;
+const FONT_MAX_GLYPHS: u32 = 512;
+
+pub const KD_TEXT: i32 = 0;
+pub const KD_GRAPHICS: i32 = 1;
+
+const VT_AUTO: i8 = 0;
+const VT_PROCESS: i8 = 1;
+const VT_ACKACQ: usize = 2;
+
+const EINTR: isize = 4;
+const EFAULT: isize = 14;
+const ENXIO: isize = 6;
+const ENOMEM: isize = 12;
+const EINVAL: isize = 22;
+const ENOSPC: isize = 28;
+const ENOSYS: isize = 38;
+
+const SIGNAL_MAX: i16 = 64;
+
+// pending answers to terminal queries (DSR, DA)
+const VT_REPLY_MAX: usize = 64;
+
+#[inline]
+const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+// --------------------------------
+// Userspace structures
+// --------------------------------
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct vt_mode {
+    pub mode: i8,
+    pub waitv: i8,
+    pub relsig: i16,
+    pub acqsig: i16,
+    pub frsig: i16,
+}
+
+#[repr(C)]
+pub struct vt_stat {
+    pub v_active: u16,
+    pub v_signal: u16,
+    pub v_state: u16,
+}
+
+#[repr(C)]
+pub struct console_font_op {
+    pub op: u32,
+    pub flags: u32,
+    pub width: u32,
+    pub height: u32,
+    pub charcount: u32,
+    pub data: *mut u8,
+}
+
+#[repr(C)]
+pub struct consolefontdesc {
+    pub charcount: u16,
+    pub charheight: u16,
+    pub chardata: *mut u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct unipair {
+    pub unicode: u16,
+    pub fontpos: u16,
+}
+
+#[repr(C)]
+pub struct unimapdesc {
+    pub entry_ct: u16,
+    pub entries: *mut unipair,
+}
+
+// --------------------------------
+// External kernel APIs
+// --------------------------------
+
+extern "C" {
+    static mut LOCK_CONSOLE: Spinlock;
+    static mut consoleOffscreen: bool;
+    static mut consoleCursorKeys: bool;
+
+    static mut consoleTty: Tty;
+    static mut currentTask: *mut Task;
+
+    static mut width: u32;
+    static mut height: u32;
+
+    fn consoleStateInit(state: *mut ConsoleState);
+    fn consoleSave(state: *mut ConsoleState);
+    fn consoleRestore(state: *const ConsoleState);
+    fn consoleRedraw();
+    fn consoleGeometryCheck() -> bool;
+    fn consoleScrollbackView(rows: i32);
+    fn consoleScrollbackReset();
+    fn consoleCols() -> u32;
+    fn consoleRows() -> u32;
+    fn drawCharacter(charnum: i32);
+
+    fn ansiStateInit(state: *mut AnsiState);
+    fn ansiSave(state: *mut AnsiState);
+    fn ansiRestore(state: *const AnsiState);
+
+    fn psfLoadDefaults() -> bool;
+    fn psfGlyphWidth() -> u32;
+    fn psfGlyphHeight() -> u32;
+    fn psfGlyphCount() -> u32;
+    fn psfSetFont(glyphs: *const u8, count: u32, width: u32, height: u32, charsize: u32) -> bool;
+    fn psfGetFont(out: *mut u8, max: u32, charsize: u32) -> u32;
+    fn psfUnicodeClear();
+    fn psfUnicodeAdd(pairs: *const UnicodePair, len: usize) -> bool;
+    fn psfUnicodeGet(out: *mut UnicodePair, from: usize, max: usize) -> usize;
+
+    fn processSignal(pid: i32, signal: usize) -> bool;
+
+    fn spinlockAcquire(lock: *mut Spinlock);
+    fn spinlockRelease(lock: *mut Spinlock);
+
+    fn handControl();
+    fn signalsPendingQuick(task: *mut Task) -> bool;
+
+    fn fakefsFstat();
+    fn debugf(fmt: *const u8, ...);
+}
+
+#[repr(C)]
+pub struct VfsHandlers {
+    pub open: Option<unsafe extern "C" fn(*mut u8, i32, i32, *mut OpenFile, *mut *mut u8) -> usize>,
+    pub duplicate: Option<unsafe extern "C" fn(*mut OpenFile, *mut OpenFile) -> bool>,
+    pub close: Option<unsafe extern "C" fn(*mut OpenFile) -> bool>,
+    pub read: Option<unsafe extern "C" fn(*mut OpenFile, *mut u8, usize) -> usize>,
+    pub write: Option<unsafe extern "C" fn(*mut OpenFile, *mut u8, usize) -> usize>,
+    pub internalPoll: Option<unsafe extern "C" fn(*mut OpenFile, i32) -> i32>,
+    pub ioctl: Option<unsafe extern "C" fn(*mut OpenFile, u64, *mut u8) -> usize>,
+    pub reportKey: Option<unsafe extern "C" fn(*mut OpenFile) -> usize>,
+    pub stat: Option<unsafe extern "C" fn()>,
+}
+
+// --------------------------------
+// Virtual terminals
+// --------------------------------
+
+#[repr(C)]
+pub struct VirtualTerminal {
+    pub index: usize,
+    pub tty: *mut Tty,
+
+    // screen contents and the console/parser globals, while not bound
+    pub console: ConsoleState,
+    pub ansi: AnsiState,
+
+    // fed to the input queue outside of the output path (LOCK_TTY is held
+    // while writing), see vtFlushReplies()
+    pub reply: [u8; VT_REPLY_MAX],
+    pub replyLen: usize,
+
+    // KD_TEXT or KD_GRAPHICS (the owner draws on the framebuffer itself)
+    pub kdMode: i32,
+
+    // what keys turn into while on screen (KDSKBMODE) and how Alt shows up
+    // in characters (KDSKBMETA)
+    pub kbMode: i32,
+    pub kbMeta: i32,
+
+    // VT_SETMODE, with the process that asked for VT_PROCESS
+    pub mode: vt_mode,
+    pub modePid: i32,
+
+    pub opens: usize,
+}
+
+static mut VTS: [VirtualTerminal; VT_COUNT] = unsafe { core::mem::zeroed() };
+
+// line disciplines of tty2..tty6, tty1 uses consoleTty
+static mut vtTtys: [Tty; VT_COUNT - 1] = unsafe { core::mem::zeroed() };
+
+static mut vtReady: bool = false;
+
+// the one on screen
+#[no_mangle]
+pub static mut vtActive: usize = 0;
+
+// the one on screen before it, for the Last_Console key
+#[no_mangle]
+pub static mut vtLast: usize = 0;
+
+// the one whose state is loaded in the console globals
+static mut vtBound: usize = 0;
+
+// VT_PROCESS switch, waiting on the owner's VT_RELDISP
+static mut vtPending: usize = VT_NONE;
+
+#[inline]
+unsafe fn vtFromFd(fd: *mut OpenFile) -> *mut VirtualTerminal {
+    (*fd).dir as *mut VirtualTerminal
+}
+
+// Loads a terminal's state into the console globals (LOCK_CONSOLE held)
+unsafe fn vtBind(index: usize) {
+    if !vtReady {
+        return;
+    }
+
+    if vtBound != index {
+        let old = &mut VTS[vtBound];
+        consoleSave(&mut old.console);
+        ansiSave(&mut old.ansi);
+
+        let new = &mut VTS[index];
+        consoleRestore(&new.console);
+        ansiRestore(&new.ansi);
+        vtBound = index;
+    }
+
+    consoleOffscreen = index != vtActive || VTS[index].kdMode == KD_GRAPHICS;
+
+    // the font might have changed since it was last written to
+    consoleGeometryCheck();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtBindForeground() {
+    vtBind(vtActive);
+}
+
+// Output of a terminal's line discipline
+#[no_mangle]
+pub unsafe extern "C" fn vtConsoleWrite(index: usize, buff: *const u8, len: usize) -> usize {
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    vtBind(index);
+    for i in 0..len {
+        drawCharacter(*buff.add(i) as i32);
+    }
+    spinlockRelease(&mut LOCK_CONSOLE);
+    len
+}
+
+unsafe extern "C" fn vtTtyOutput(tty: *mut Tty, buff: *const u8, len: usize) -> usize {
+    let vt = (*tty).ctx as *mut VirtualTerminal;
+    vtConsoleWrite((*vt).index, buff, len)
+}
+
+unsafe extern "C" fn vtTtyReopen(tty: *mut Tty, fd: *mut OpenFile) -> usize {
+    let vt = (*tty).ctx as *mut VirtualTerminal;
+    (*vt).opens += 1;
+    (*fd).dir = vt as *mut c_void;
+    (*fd).handlers = &handleVt as *const VfsHandlers as *const c_void;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn initiateVirtualTerminals() {
+    spinlockAcquire(&mut LOCK_CONSOLE);
+
+    for i in 0..VT_COUNT {
+        let vt = &mut VTS[i];
+        vt.index = i;
+        consoleStateInit(&mut vt.console);
+        ansiStateInit(&mut vt.ansi);
+        vt.replyLen = 0;
+        vt.kdMode = KD_TEXT;
+        vt.kbMode = K_UNICODE;
+        vt.kbMeta = K_ESCPREFIX;
+        vt.mode = vt_mode {
+            mode: VT_AUTO,
+            waitv: 0,
+            relsig: 0,
+            acqsig: 0,
+            frsig: 0,
+        };
+        vt.modePid = 0;
+        vt.opens = 0;
+
+        if i == 0 {
+            // what's already on screen is tty1's
+            vt.tty = &mut consoleTty;
+            consoleTty.ctx = vt as *mut VirtualTerminal as *mut c_void;
+            continue;
+        }
+
+        vt.tty = &mut vtTtys[i - 1];
+        ttyInit(vt.tty, Some(vtTtyOutput), None, vt as *mut VirtualTerminal as *mut c_void);
+        (*vt.tty).reopen = Some(vtTtyReopen);
+        (*vt.tty).win = consoleTty.win;
+    }
+
+    // tty1 carries on from where the boot console is
+    VTS[0].console.x = width;
+    VTS[0].console.y = height;
+    consoleRestore(&VTS[0].console);
+    ansiRestore(&VTS[0].ansi);
+
+    vtActive = 0;
+    vtBound = 0;
+    vtReady = true;
+
+    // boot messages from before this point aren't in tty1's grid, so they
+    // won't survive switching away
+    vtBind(0);
+
+    spinlockRelease(&mut LOCK_CONSOLE);
+}
+
+// --------------------------------
+// Switching
+// --------------------------------
+
+unsafe fn vtSwitchUnsafe(target: usize) {
+    let old = vtActive;
+    vtActive = target;
+    vtPending = VT_NONE;
+    if old != target {
+        vtLast = old;
+    }
+
+    vtBind(target);
+    if VTS[target].kdMode == KD_TEXT {
+        consoleRedraw();
+    }
+
+    if old != target && VTS[target].mode.mode == VT_PROCESS {
+        processSignal(VTS[target].modePid, VTS[target].mode.acqsig as usize);
+    }
+}
+
+// A VT_PROCESS owner that died can't hold on to the display anymore
+unsafe fn vtResetMode(vt: *mut VirtualTerminal) {
+    (*vt).mode.mode = VT_AUTO;
+    (*vt).modePid = 0;
+    (*vt).kdMode = KD_TEXT;
+    // an X server leaves it in K_OFF or K_MEDIUMRAW
+    (*vt).kbMode = K_UNICODE;
+}
+
+// Alt+Fn, VT_ACTIVATE
+#[no_mangle]
+pub unsafe extern "C" fn vtActivate(target: usize) -> usize {
+    if !vtReady || target >= VT_COUNT {
+        return err(ENXIO);
+    }
+
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    if target == vtActive {
+        spinlockRelease(&mut LOCK_CONSOLE);
+        return 0;
+    }
+
+    let current = &mut VTS[vtActive] as *mut VirtualTerminal;
+    if (*current).mode.mode == VT_PROCESS {
+        // the owner gets to release the display with VT_RELDISP
+        if processSignal((*current).modePid, (*current).mode.relsig as usize) {
+            vtPending = target;
+            spinlockRelease(&mut LOCK_CONSOLE);
+            return 0;
+        }
+        vtResetMode(current);
+    }
+
+    vtSwitchUnsafe(target);
+    spinlockRelease(&mut LOCK_CONSOLE);
+    0
+}
+
+// Keyboard input, for the terminal on screen
+#[no_mangle]
+pub unsafe extern "C" fn vtInput(c: u8) {
+    if !vtReady {
+        return;
+    }
+    vtScrollbackSnap();
+    let tty = VTS[vtActive].tty;
+    if (*tty).readBuff.is_null() {
+        return;
+    }
+    ttyReceive(tty, &c, 1);
+}
+
+// Keyboard mode of the terminal on screen, for the keyboard driver
+#[no_mangle]
+pub unsafe extern "C" fn vtKeyboardMode() -> i32 {
+    if !vtReady {
+        return K_UNICODE;
+    }
+    VTS[vtActive].kbMode
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtKeyboardMeta() -> i32 {
+    if !vtReady {
+        return K_ESCPREFIX;
+    }
+    VTS[vtActive].kbMeta
+}
+
+// --------------------------------
+// Scrollback
+// --------------------------------
+
+// Half a screen per page, i32::MAX / i32::MIN for either end
+unsafe fn vtScrollbackRows(pages: i32) -> i32 {
+    match pages {
+        i32::MAX | i32::MIN => pages,
+        _ => pages.saturating_mul((consoleRows() / 2).max(1) as i32),
+    }
+}
+
+// Shift+PageUp/PageDown/Home/End, on the terminal on screen
+#[no_mangle]
+pub unsafe extern "C" fn vtScrollback(pages: i32) {
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    vtBind(vtActive);
+    if VTS[vtActive].kdMode == KD_TEXT {
+        consoleScrollbackView(vtScrollbackRows(pages));
+    }
+    spinlockRelease(&mut LOCK_CONSOLE);
+}
+
+// The same after a panic: nothing else runs anymore, and whoever held the
+// console lock never lets go of it
+#[no_mangle]
+pub unsafe extern "C" fn vtScrollbackPanic(pages: i32) {
+    vtBind(vtActive);
+    consoleOffscreen = false;
+    consoleScrollbackView(vtScrollbackRows(pages));
+}
+
+// Any key brings the live screen back
+unsafe fn vtScrollbackSnap() {
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    vtBind(vtActive);
+    consoleScrollbackReset();
+    spinlockRelease(&mut LOCK_CONSOLE);
+}
+
+// Cursor keys, as ESC [ x or ESC O x depending on DECCKM
+#[no_mangle]
+pub unsafe extern "C" fn vtCursorKey(key: u8) {
+    if !vtReady {
+        return;
+    }
+
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    vtBind(vtActive);
+    consoleScrollbackReset();
+    let application = consoleCursorKeys;
+    let tty = VTS[vtActive].tty;
+    spinlockRelease(&mut LOCK_CONSOLE);
+
+    let seq = [0x1B, if application { b'O' } else { b'[' }, key];
+    if (*tty).readBuff.is_null() {
+        return;
+    }
+    ttyReceive(tty, seq.as_ptr(), seq.len());
+}
+
+// Queued by the ANSI parser for the terminal being written to (LOCK_CONSOLE
+// held)
+#[no_mangle]
+pub unsafe extern "C" fn vtReply(buff: *const u8, len: usize) {
+    if !vtReady {
+        return;
+    }
+    let vt = &mut VTS[vtBound];
+    if vt.replyLen + len > VT_REPLY_MAX {
+        return;
+    }
+    core::ptr::copy_nonoverlapping(buff, vt.reply.as_mut_ptr().add(vt.replyLen), len);
+    vt.replyLen += len;
+}
+
+// Whoever asked reads (or polls) the terminal next, that's when the answer
+// is handed over
+#[no_mangle]
+pub unsafe extern "C" fn vtFlushReplies(index: usize) {
+    if !vtReady {
+        return;
+    }
+
+    let mut reply = [0u8; VT_REPLY_MAX];
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    let vt = &mut VTS[index];
+    let len = vt.replyLen;
+    reply[..len].copy_from_slice(&vt.reply[..len]);
+    vt.replyLen = 0;
+    spinlockRelease(&mut LOCK_CONSOLE);
+
+    if len > 0 {
+        ttyReceive(vt.tty, reply.as_ptr(), len);
+    }
+}
+
+// --------------------------------
+// Fonts
+// --------------------------------
+
+// The font is shared by all terminals, each one gets resized to it the next
+// time it's bound (LOCK_CONSOLE held). Returns the new size in cells.
+unsafe fn vtFontResizeUnsafe() -> (u32, u32) {
+    for i in 0..VT_COUNT {
+        vtBind(i);
+    }
+    vtBind(vtActive);
+    if VTS[vtActive].kdMode == KD_TEXT {
+        consoleRedraw();
+    }
+    (consoleCols(), consoleRows())
+}
+
+// Outside of the console lock, SIGWINCH goes out from here
+unsafe fn vtWinsizeUpdate(cols: u32, rows: u32) {
+    for i in 0..VT_COUNT {
+        let mut win = (*VTS[i].tty).win;
+        win.ws_col = cols as u16;
+        win.ws_row = rows as u16;
+        ttySetWinsize(VTS[i].tty, &win);
+    }
+}
+
+// After the font got replaced (font file at boot, setfont)
+#[no_mangle]
+pub unsafe extern "C" fn vtFontChanged() {
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    if !vtReady {
+        consoleGeometryCheck();
+        consoleRedraw();
+        spinlockRelease(&mut LOCK_CONSOLE);
+        return;
+    }
+    let (cols, rows) = vtFontResizeUnsafe();
+    spinlockRelease(&mut LOCK_CONSOLE);
+
+    vtWinsizeUpdate(cols, rows);
+}
+
+// After the framebuffer changed size or got drawn over by someone else (KMS,
+// fbdev mode switches); as far as the grids go that's a font change
+#[no_mangle]
+pub unsafe extern "C" fn vtModeChanged() {
+    vtFontChanged();
+}
+
+// PIO_FONT doesn't say how tall the glyphs are, so go by the lowest row any
+// of them uses
+unsafe fn vtFontGuessHeight(data: *const u8, count: u32, charsize: u32, bytesPerRow: u32) -> u32 {
+    let mut height = 1;
+    for glyph in 0..count {
+        for row in height..FONT_VPITCH {
+            let line = data.add((glyph * charsize + row * bytesPerRow) as usize);
+            if (0..bytesPerRow as usize).any(|byte| *line.add(byte) != 0) {
+                height = row + 1;
+            }
+        }
+    }
+    height
+}
+
+// Glyphs FONT_VPITCH rows apart; a height of 0 means it has to be guessed
+unsafe fn vtFontSet(data: *const u8, count: u32, width: u32, height: u32) -> usize {
+    if data.is_null() {
+        return err(EFAULT);
+    }
+    if width == 0 || width > FONT_MAX_WIDTH || height > FONT_VPITCH {
+        return err(EINVAL);
+    }
+    if count == 0 || count > FONT_MAX_GLYPHS {
+        return err(EINVAL);
+    }
+
+    let bytesPerRow = (width + 7) / 8;
+    let charsize = bytesPerRow * FONT_VPITCH;
+    let height = if height == 0 {
+        vtFontGuessHeight(data, count, charsize, bytesPerRow)
+    } else {
+        height
+    };
+
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    if !psfSetFont(data, count, width, height, charsize) {
+        spinlockRelease(&mut LOCK_CONSOLE);
+        return err(ENOMEM);
+    }
+    let (cols, rows) = vtFontResizeUnsafe();
+    spinlockRelease(&mut LOCK_CONSOLE);
+
+    vtWinsizeUpdate(cols, rows);
+    0
+}
+
+// Copies the glyphs out FONT_VPITCH rows apart, max being how many fit
+unsafe fn vtFontGet(data: *mut u8, max: u32) -> usize {
+    if psfGlyphHeight() > FONT_VPITCH || psfGlyphWidth() > FONT_MAX_WIDTH {
+        return err(ENOSYS);
+    }
+    if data.is_null() {
+        return 0;
+    }
+    if max < psfGlyphCount() {
+        return err(ENOSPC);
+    }
+
+    let charsize = (psfGlyphWidth() + 7) / 8 * FONT_VPITCH;
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    psfGetFont(data, max, charsize);
+    spinlockRelease(&mut LOCK_CONSOLE);
+    0
+}
+
+unsafe fn vtFontOp(op: *mut console_font_op) -> usize {
+    match (*op).op {
+        KD_FONT_OP_SET => vtFontSet((*op).data, (*op).charcount, (*op).width, (*op).height),
+        KD_FONT_OP_GET => {
+            let ret = vtFontGet((*op).data, (*op).charcount);
+            if ret == 0 {
+                (*op).width = psfGlyphWidth();
+                (*op).height = psfGlyphHeight();
+                (*op).charcount = psfGlyphCount();
+            }
+            ret
+        }
+        KD_FONT_OP_SET_DEFAULT => {
+            spinlockAcquire(&mut LOCK_CONSOLE);
+            psfLoadDefaults();
+            let (cols, rows) = vtFontResizeUnsafe();
+            spinlockRelease(&mut LOCK_CONSOLE);
+            vtWinsizeUpdate(cols, rows);
+
+            (*op).width = psfGlyphWidth();
+            (*op).height = psfGlyphHeight();
+            0
+        }
+        _ => err(ENOSYS),
+    }
+}
+
+// PIO_UNIMAP, added to what's there (PIO_UNIMAPCLR starts over)
+unsafe fn vtUnimapSet(desc: *const unimapdesc) -> usize {
+    let count = (*desc).entry_ct as usize;
+    if count > 0 && (*desc).entries.is_null() {
+        return err(EFAULT);
+    }
+
+    const CHUNK: usize = 64;
+    let mut pairs = [UnicodePair { codepoint: 0, glyph: 0 }; CHUNK];
+    let mut done = 0;
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    while done < count {
+        let len = (count - done).min(CHUNK);
+        for i in 0..len {
+            let entry = *(*desc).entries.add(done + i);
+            pairs[i] = UnicodePair {
+                codepoint: entry.unicode as u32,
+                glyph: entry.fontpos as u32,
+            };
+        }
+        if !psfUnicodeAdd(pairs.as_ptr(), len) {
+            spinlockRelease(&mut LOCK_CONSOLE);
+            return err(ENOMEM);
+        }
+        done += len;
+    }
+    if VTS[vtActive].kdMode == KD_TEXT {
+        vtBind(vtActive);
+        consoleRedraw();
+    }
+    spinlockRelease(&mut LOCK_CONSOLE);
+    0
+}
+
+// GIO_UNIMAP, entry_ct comes back as the full size even when it didn't fit.
+// Code points past the BMP can't be expressed and are left out.
+unsafe fn vtUnimapGet(desc: *mut unimapdesc) -> usize {
+    let max = (*desc).entry_ct as usize;
+    if max > 0 && (*desc).entries.is_null() {
+        return err(EFAULT);
+    }
+
+    const CHUNK: usize = 64;
+    let mut pairs = [UnicodePair { codepoint: 0, glyph: 0 }; CHUNK];
+    let mut from = 0;
+    let mut filled = 0;
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    loop {
+        let total = psfUnicodeGet(pairs.as_mut_ptr(), from, CHUNK);
+        let len = total.saturating_sub(from).min(CHUNK);
+        for pair in &pairs[..len] {
+            if pair.codepoint > 0xFFFF {
+                continue;
+            }
+            if filled < max {
+                *(*desc).entries.add(filled) = unipair {
+                    unicode: pair.codepoint as u16,
+                    fontpos: pair.glyph as u16,
+                };
+            }
+            filled += 1;
+        }
+        from += len;
+        if from >= total {
+            break;
+        }
+    }
+    spinlockRelease(&mut LOCK_CONSOLE);
+
+    (*desc).entry_ct = filled.min(u16::MAX as usize) as u16;
+    if filled > max {
+        return err(ENOMEM);
+    }
+    0
+}
+
+// --------------------------------
+// ioctl()
+// --------------------------------
+
+#[no_mangle]
+pub unsafe extern "C" fn vtIoctl(index: usize, request: u64, arg: *mut u8) -> usize {
+    if !vtReady {
+        return ttyIoctl(&mut consoleTty, request, arg);
+    }
+
+    let vt = &mut VTS[index] as *mut VirtualTerminal;
+    match request {
+        VT_OPENQRY => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            let free = (0..VT_COUNT).find(|&i| VTS[i].opens == 0 && (*VTS[i].tty).ctrlSession == 0);
+            *(arg as *mut i32) = match free {
+                Some(i) => i as i32 + 1,
+                None => -1,
+            };
+            0
+        }
+        VT_GETSTATE => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            // bit N is ttyN
+            let mut state: u16 = 1;
+            for i in 0..VT_COUNT {
+                if VTS[i].opens != 0 || (*VTS[i].tty).ctrlSession != 0 {
+                    state |= 1 << (i + 1);
+                }
+            }
+            *(arg as *mut vt_stat) = vt_stat {
+                v_active: vtActive as u16 + 1,
+                v_signal: 0,
+                v_state: state,
+            };
+            0
+        }
+        VT_ACTIVATE => {
+            let target = arg as usize;
+            if target == 0 || target > VT_COUNT {
+                return err(ENXIO);
+            }
+            vtActivate(target - 1)
+        }
+        VT_WAITACTIVE => {
+            let target = arg as usize;
+            if target == 0 || target > VT_COUNT {
+                return err(ENXIO);
+            }
+            while vtActive != target - 1 {
+                if signalsPendingQuick(currentTask) {
+                    return err(EINTR);
+                }
+                handControl();
+            }
+            0
+        }
+        VT_GETMODE => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *(arg as *mut vt_mode) = (*vt).mode;
+            0
+        }
+        VT_SETMODE => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            let mode = *(arg as *const vt_mode);
+            if mode.mode != VT_AUTO && mode.mode != VT_PROCESS {
+                return err(EINVAL);
+            }
+            if !(0..=SIGNAL_MAX).contains(&mode.relsig) || !(0..=SIGNAL_MAX).contains(&mode.acqsig) {
+                return err(EINVAL);
+            }
+            spinlockAcquire(&mut LOCK_CONSOLE);
+            (*vt).mode = mode;
+            (*vt).modePid = if mode.mode == VT_PROCESS { (*currentTask).tgid } else { 0 };
+            spinlockRelease(&mut LOCK_CONSOLE);
+            0
+        }
+        VT_RELDISP => {
+            spinlockAcquire(&mut LOCK_CONSOLE);
+            if (*vt).mode.mode != VT_PROCESS {
+                spinlockRelease(&mut LOCK_CONSOLE);
+                return err(EINVAL);
+            }
+            match arg as usize {
+                // acknowledging an acquire, nothing to do
+                VT_ACKACQ => {}
+                // refused, stay where we are
+                0 => vtPending = VT_NONE,
+                _ => {
+                    if index == vtActive && vtPending != VT_NONE {
+                        vtSwitchUnsafe(vtPending);
+                    }
+                }
+            }
+            spinlockRelease(&mut LOCK_CONSOLE);
+            0
+        }
+        KDGETMODE => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *(arg as *mut i32) = (*vt).kdMode;
+            0
+        }
+        KDSETMODE => {
+            let mode = arg as usize as i32;
+            if mode != KD_TEXT && mode != KD_GRAPHICS {
+                return err(EINVAL);
+            }
+            spinlockAcquire(&mut LOCK_CONSOLE);
+            let changed = (*vt).kdMode != mode;
+            (*vt).kdMode = mode;
+            // handing the framebuffer back, put the text back up
+            if changed && mode == KD_TEXT && index == vtActive {
+                vtBind(index);
+                consoleRedraw();
+            }
+            spinlockRelease(&mut LOCK_CONSOLE);
+            0
+        }
+        KDFONTOP => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            vtFontOp(arg as *mut console_font_op)
+        }
+        // 256 glyphs, 8 pixels wide
+        PIO_FONT => vtFontSet(arg, 256, 8, 0),
+        GIO_FONT => {
+            if psfGlyphWidth() != 8 {
+                return err(EINVAL);
+            }
+            vtFontGet(arg, 256)
+        }
+        PIO_FONTX => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            let desc = arg as *const consolefontdesc;
+            if (*desc).charcount != 256 && (*desc).charcount != 512 {
+                return err(EINVAL);
+            }
+            vtFontSet((*desc).chardata, (*desc).charcount as u32, 8, (*desc).charheight as u32)
+        }
+        GIO_FONTX => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            if psfGlyphWidth() != 8 {
+                return err(EINVAL);
+            }
+            let desc = arg as *mut consolefontdesc;
+            let ret = vtFontGet((*desc).chardata, (*desc).charcount as u32);
+            (*desc).charcount = psfGlyphCount() as u16;
+            (*desc).charheight = psfGlyphHeight() as u16;
+            ret
+        }
+        PIO_UNIMAPCLR => {
+            spinlockAcquire(&mut LOCK_CONSOLE);
+            psfUnicodeClear();
+            spinlockRelease(&mut LOCK_CONSOLE);
+            0
+        }
+        PIO_UNIMAP => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            vtUnimapSet(arg as *const unimapdesc)
+        }
+        GIO_UNIMAP => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            vtUnimapGet(arg as *mut unimapdesc)
+        }
+        KDGKBMODE => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *(arg as *mut i32) = (*vt).kbMode;
+            0
+        }
+        KDSKBMODE => {
+            let mode = arg as usize as i32;
+            if !(K_RAW..=K_OFF).contains(&mode) {
+                return err(EINVAL);
+            }
+            (*vt).kbMode = mode;
+            0
+        }
+        KDGKBMETA => {
+            if arg.is_null() {
+                return err(EFAULT);
+            }
+            *(arg as *mut i32) = (*vt).kbMeta;
+            0
+        }
+        KDSKBMETA => {
+            let meta = arg as usize as i32;
+            if meta != K_METABIT && meta != K_ESCPREFIX {
+                return err(EINVAL);
+            }
+            (*vt).kbMeta = meta;
+            0
+        }
+        KDGKBENT | KDSKBENT | KDGKBSENT | KDSKBSENT | KDGKBDIACR | KDSKBDIACR | KDGKBDIACRUC
+        | KDSKBDIACRUC => keymap_ioctl(request, arg, (*vt).kbMode == K_UNICODE),
+        // one keyboard for all of them
+        KDGKBTYPE | KDGETLED | KDSETLED | KDGKBLED | KDSKBLED => kb_ioctl(request, arg),
+        _ => ttyIoctl((*vt).tty, request, arg),
+    }
+}
+
+// --------------------------------
+// /dev/tty0 .. /dev/tty6 handlers
+// --------------------------------
+
+#[no_mangle]
+pub unsafe extern "C" fn vtOpen(
+    filename: *mut u8,
+    flags: i32,
+    _mode: i32,
+    fd: *mut OpenFile,
+    _sym: *mut *mut u8,
+) -> usize {
+    if !vtReady {
+        return err(ENXIO);
+    }
+
+    // filename is in the form of /ttyN, tty0 being the one on screen
+    let mut ptr = filename;
+    while *ptr != 0 && !(*ptr).is_ascii_digit() {
+        ptr = ptr.add(1);
+    }
+    let mut number: usize = 0;
+    while (*ptr).is_ascii_digit() {
+        number = number * 10 + (*ptr - b'0') as usize;
+        ptr = ptr.add(1);
+    }
+    if number > VT_COUNT {
+        return err(ENXIO);
+    }
+
+    let index = if number == 0 { vtActive } else { number - 1 };
+    let vt = &mut VTS[index];
+    vt.opens += 1;
+    (*fd).dir = vt as *mut VirtualTerminal as *mut c_void;
+
+    ttyOpened(vt.tty, flags as u32);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtRead(fd: *mut OpenFile, out: *mut u8, limit: usize) -> usize {
+    vtFlushReplies((*vtFromFd(fd)).index);
+    ttyRead((*vtFromFd(fd)).tty, fd, out, limit)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtWrite(fd: *mut OpenFile, input: *mut u8, limit: usize) -> usize {
+    ttyWrite((*vtFromFd(fd)).tty, input, limit)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtFdIoctl(fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    vtIoctl((*vtFromFd(fd)).index, request, arg)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtInternalPoll(fd: *mut OpenFile, events: i32) -> i32 {
+    vtFlushReplies((*vtFromFd(fd)).index);
+    ttyPoll((*vtFromFd(fd)).tty, events)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtReportKey(fd: *mut OpenFile) -> usize {
+    (*(*vtFromFd(fd)).tty).pollKey
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtDuplicate(orig: *mut OpenFile, new: *mut OpenFile) -> bool {
+    let vt = vtFromFd(orig);
+    (*vt).opens += 1;
+    (*new).dir = vt as *mut c_void;
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn vtClose(fd: *mut OpenFile) -> bool {
+    let vt = vtFromFd(fd);
+    spinlockAcquire(&mut LOCK_CONSOLE);
+    (*vt).opens -= 1;
+    if (*vt).opens == 0
+        && (*vt).mode.mode == VT_PROCESS
+        && ((*currentTask).tgid == (*vt).modePid || !processSignal((*vt).modePid, 0))
+    {
+        // the display server is gone (or crashed), give the screen back
+        vtResetMode(vt);
+        if (*vt).index == vtActive {
+            vtBind((*vt).index);
+            consoleRedraw();
+        }
+    }
+    spinlockRelease(&mut LOCK_CONSOLE);
+    true
+}
+
+// --------------------------------
+// Registration
+// --------------------------------
+
+#[no_mangle]
+pub static handleVt: VfsHandlers = VfsHandlers {
+    open: Some(vtOpen),
+    duplicate: Some(vtDuplicate),
+    close: Some(vtClose),
+    read: Some(vtRead),
+    write: Some(vtWrite),
+    internalPoll: Some(vtInternalPoll),
+    ioctl: Some(vtFdIoctl),
+    reportKey: Some(vtReportKey),
+    stat: Some(fakefsFstat),
+};
@@ -0,0 +1,58 @@
+#include "pci.h"
+#include "types.h"
+
+#ifndef BGA_H
+#define BGA_H
+
+// Bochs VBE "dispi" interface (QEMU -vga std, Bochs, VirtualBox)
+#define VBE_DISPI_IOPORT_INDEX 0x01CE
+#define VBE_DISPI_IOPORT_DATA 0x01CF
+
+#define VBE_DISPI_INDEX_ID 0x0
+#define VBE_DISPI_INDEX_XRES 0x1
+#define VBE_DISPI_INDEX_YRES 0x2
+#define VBE_DISPI_INDEX_BPP 0x3
+#define VBE_DISPI_INDEX_ENABLE 0x4
+#define VBE_DISPI_INDEX_BANK 0x5
+#define VBE_DISPI_INDEX_VIRT_WIDTH 0x6
+#define VBE_DISPI_INDEX_VIRT_HEIGHT 0x7
+#define VBE_DISPI_INDEX_X_OFFSET 0x8
+#define VBE_DISPI_INDEX_Y_OFFSET 0x9
+#define VBE_DISPI_INDEX_VIDEO_MEMORY_64K 0xA
+
+#define VBE_DISPI_DISABLED 0x00
+#define VBE_DISPI_ENABLED 0x01
+#define VBE_DISPI_GETCAPS 0x02
+#define VBE_DISPI_LFB_ENABLED 0x40
+#define VBE_DISPI_NOCLEARMEM 0x80
+
+#define BGA_MMIO_DISPI 0x500
+#define BGA_MAX_MODES 24
+
+typedef struct BgaMode {
+  uint32_t width, height;
+} BgaMode;
+
+typedef struct Bga {
+  bool     exists;
+  size_t   vramPhys;
+  uint8_t *vram;
+  size_t   vramSize;
+  uint32_t maxWidth, maxHeight;
+
+  uint32_t virtHeight, yOffset;
+
+  uint16_t *mmio; // NULL: port I/O
+
+  BgaMode modes[BGA_MAX_MODES];
+  size_t  modeCount;
+} Bga;
+
+extern Bga BGA;
+
+void initiateBGA(PCIdevice *device);
+bool bgaModeSupported(uint32_t width, uint32_t height);
+bool bgaSetMode(uint32_t width, uint32_t height);
+bool bgaPan(uint32_t line);
+
+#endif
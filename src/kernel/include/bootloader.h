@@ -22,6 +22,8 @@ typedef struct Bootloader {
   LIMINE_PTR(struct limine_memmap_entry **) mmEntries;
   LIMINE_PTR(struct limine_smp_response *) smp;
   uint64_t smpBspIndex;
+
+  LIMINE_PTR(const char *) cmdline;
 } Bootloader;
 
 Bootloader bootloader;
@@ -11,13 +11,10 @@ extern int textcolor[];
 
 atomic_bool consoleDisabled;
 
-#define TTY_CHARACTER_WIDTH 8
-#define TTY_CHARACTER_HEIGHT 16
-
 #define DEFAULT_FONT_PATH "/fonts/u_custom.psf"
 
 void initiateConsole();
-void drawCharacter(int charnum);
+void drawCharacter(int charnum); // UTF-8, a byte at a time
 
 bool cursorHidden;
 
@@ -35,6 +32,98 @@ void updateBull();
 
 void clearScreen();
 
+// Character grid of the terminal being written to (see vt.h)
+#define ATTR_BOLD (1 << 0)
+#define ATTR_UNDERLINE (1 << 1)
+#define ATTR_REVERSE (1 << 2)
+
+// Right half of a double-width character
+#define CELL_WIDE (-2)
+
+typedef struct ConsoleCell {
+  int      ch; // code point
+  uint32_t fg;
+  uint32_t bg;
+  uint8_t  attr;
+} ConsoleCell;
+
+typedef struct ConsoleState {
+  uint32_t     x;
+  uint32_t     y;
+  int          bg[3];
+  int          fg[3];
+  uint8_t      attr;
+  bool         cursorHidden;
+  bool         autowrap;
+  bool         cursorKeys;
+  uint32_t     scrollTop;
+  uint32_t     scrollBottom;
+  uint32_t     cols;
+  uint32_t     rows;
+  uint32_t     cellWidth;
+  uint32_t     cellHeight;
+  ConsoleCell *cells;
+  ConsoleCell *otherCells;
+  bool         onAlt;
+  ConsoleCell *history;
+  uint32_t     historyLen;
+  uint32_t     historyNext;
+  uint32_t     historyView;
+  uint32_t     utf8Codepoint;
+  uint8_t      utf8Remaining;
+  uint8_t      utf8Length;
+} ConsoleState;
+
+extern ConsoleCell *consoleCells;
+extern ConsoleCell *consoleOtherCells;
+extern bool         consoleOnAlt;
+extern bool         consoleOffscreen;
+extern uint8_t      textattr;
+extern bool         consoleAutowrap;
+extern bool         consoleCursorKeys;
+extern uint32_t     scrollTop;
+extern uint32_t     scrollBottom;
+extern uint32_t     consoleGridCols;
+extern uint32_t     consoleGridRows;
+extern uint32_t     consoleCellWidth;
+extern uint32_t     consoleCellHeight;
+
+// Scrollback (console.scrollback=<rows> on the kernel command line)
+extern uint32_t     consoleHistoryRows;
+extern ConsoleCell *consoleHistory;
+extern uint32_t     consoleHistoryLen;
+extern uint32_t     consoleHistoryNext;
+extern uint32_t     consoleHistoryView;
+
+ConsoleCell *consoleCellsAllocate();
+void         consoleStateInit(ConsoleState *state);
+void         consoleSave(ConsoleState *state);
+void         consoleRestore(const ConsoleState *state);
+void         consoleRedraw();
+bool         consoleGeometryCheck();
+
+// Terminal operations, in cells (used by ansi.c)
+uint32_t consoleCols();
+uint32_t consoleRows();
+uint32_t consoleCursorCol();
+uint32_t consoleCursorRow();
+void     consoleCursorSet(uint32_t col, uint32_t row);
+void     consoleEraseCells(uint32_t row, uint32_t from, uint32_t to);
+void     consoleIndex();
+void     consoleReverseIndex();
+void     consoleScrollUp(uint32_t count);
+void     consoleScrollDown(uint32_t count);
+void     consoleSetRegion(uint32_t top, uint32_t bottom);
+void     consoleInsertLines(uint32_t count);
+void     consoleDeleteLines(uint32_t count);
+void     consoleInsertChars(uint32_t count);
+void     consoleDeleteChars(uint32_t count);
+void     consoleAltScreen(bool enable);
+void     consoleScrollbackView(int32_t rows);
+void     consoleScrollbackReset();
+void     consoleScrollbackClear();
+void     changeAttr(uint8_t attr);
+
 void printfch(char character);
 void putchar_(char c);
 
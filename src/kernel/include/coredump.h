@@ -0,0 +1,18 @@
+#include "isr.h"
+#include "task.h"
+#include "types.h"
+
+#ifndef COREDUMP_H
+#define COREDUMP_H
+
+// ELF core dumps for signals whose default action is Core (coredump.c)
+
+#define CORE_PATTERN_MAX 128
+
+bool coredumpWrite(Task *task, AsmPassedInterrupt *regs, size_t signal);
+
+// /proc/sys/kernel/core_pattern
+size_t coredumpPatternGet(char *out, size_t max);
+size_t coredumpPatternSet(const char *input, size_t len);
+
+#endif
@@ -2,6 +2,7 @@
 #include "fakefs.h"
 #include "linked_list.h"
 #include "linux.h"
+#include "tty.h"
 #include "types.h"
 #include "vfs.h"
 
@@ -50,24 +51,19 @@ typedef struct PtyPair {
 
   Spinlock LOCK_PTY;
 
-  int masterFds;
-  int slaveFds;
-
-  termios  term;
-  winsize  win;
-  uint8_t *bufferMaster;
-  uint8_t *bufferSlave;
+  int  id;
+  bool locked; // by default unlocked (hence 0)
 
-  int ptrMaster;
-  int ptrSlave;
+  int  masterFds;
+  int  slaveFds;
+  bool slaveOpened; // for EIO/POLLHUP on the master after the slave is gone
 
-  // controlling stuff
-  int ctrlSession;
-  int ctrlPgid;
-  // above not used by ptyIsAssigned() since they aren't cleared
+  // slave output, already post-processed by the line discipline
+  uint8_t *bufferMaster;
+  int      ptrMaster;
 
-  int  id;
-  bool locked; // by default unlocked (hence 0)
+  // slave side line discipline (termios, winsize, controlling stuff)
+  Tty tty;
 } PtyPair;
 
 VfsHandlers handlePtmx;
@@ -0,0 +1,16 @@
+#include "types.h"
+
+#ifndef DNS_H
+#define DNS_H
+
+// kernel stub resolver: /etc/hosts, /etc/resolv.conf & a TTL cache
+// (networking/dns.rs)
+
+struct ip_addr;
+
+// LWIP_HOOK_NETCONN_EXTERNAL_RESOLVE: always handles the lookup, *err says
+// how it went
+int dnsExternalResolve(const char *name, struct ip_addr *addr,
+                       uint8_t addrtype, int8_t *err);
+
+#endif
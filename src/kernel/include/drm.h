@@ -0,0 +1,67 @@
+#include "types.h"
+#include "vfs.h"
+
+#ifndef DRM_H
+#define DRM_H
+
+// A display that can be mode-set. Modes are always 32bpp XRGB; after setMode()
+// succeeds `fb` describes page 0 of the new mode's VRAM and panning is reset
+typedef struct DrmBackend {
+  const char *name;
+  uint32_t    maxWidth, maxHeight;
+  size_t      vramSize;
+
+  bool (*setMode)(uint32_t width, uint32_t height);
+  // optional: scan out from `line` on (double buffering inside VRAM)
+  bool (*pan)(uint32_t line);
+  // optional: for devices that only redraw when told to
+  void (*update)(uint32_t x, uint32_t y, uint32_t width, uint32_t height);
+} DrmBackend;
+
+void drmRegisterBackend(const DrmBackend *backend);
+bool drmInUse();
+
+extern VfsHandlers handleDrm;
+
+/* the subset of the Linux DRM uapi we answer ('d' ioctls, nr below) */
+#define DRM_IOCTL_BASE 'd'
+
+#define DRM_IOCTL_VERSION 0x00
+#define DRM_IOCTL_GET_UNIQUE 0x01
+#define DRM_IOCTL_GET_MAGIC 0x02
+#define DRM_IOCTL_GEM_CLOSE 0x09
+#define DRM_IOCTL_GET_CAP 0x0c
+#define DRM_IOCTL_SET_CLIENT_CAP 0x0d
+#define DRM_IOCTL_AUTH_MAGIC 0x11
+#define DRM_IOCTL_SET_MASTER 0x1e
+#define DRM_IOCTL_DROP_MASTER 0x1f
+#define DRM_IOCTL_WAIT_VBLANK 0x3a
+
+#define DRM_IOCTL_MODE_GETRESOURCES 0xa0
+#define DRM_IOCTL_MODE_GETCRTC 0xa1
+#define DRM_IOCTL_MODE_SETCRTC 0xa2
+#define DRM_IOCTL_MODE_CURSOR 0xa3
+#define DRM_IOCTL_MODE_GETGAMMA 0xa4
+#define DRM_IOCTL_MODE_SETGAMMA 0xa5
+#define DRM_IOCTL_MODE_GETENCODER 0xa6
+#define DRM_IOCTL_MODE_GETCONNECTOR 0xa7
+#define DRM_IOCTL_MODE_GETPROPERTY 0xaa
+#define DRM_IOCTL_MODE_GETFB 0xad
+#define DRM_IOCTL_MODE_ADDFB 0xae
+#define DRM_IOCTL_MODE_RMFB 0xaf
+#define DRM_IOCTL_MODE_PAGE_FLIP 0xb0
+#define DRM_IOCTL_MODE_DIRTYFB 0xb1
+#define DRM_IOCTL_MODE_CREATE_DUMB 0xb2
+#define DRM_IOCTL_MODE_MAP_DUMB 0xb3
+#define DRM_IOCTL_MODE_DESTROY_DUMB 0xb4
+#define DRM_IOCTL_MODE_GETPLANERESOURCES 0xb5
+#define DRM_IOCTL_MODE_GETPLANE 0xb6
+#define DRM_IOCTL_MODE_SETPLANE 0xb7
+#define DRM_IOCTL_MODE_ADDFB2 0xb8
+#define DRM_IOCTL_MODE_OBJ_GETPROPERTIES 0xb9
+#define DRM_IOCTL_MODE_ATOMIC 0xbc
+
+#define DRM_EVENT_VBLANK 0x01
+#define DRM_EVENT_FLIP_COMPLETE 0x02
+
+#endif
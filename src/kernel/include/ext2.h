@@ -263,6 +263,7 @@ typedef struct Ext2OpenFd {
 
 // ext2_controller.c
 bool   ext2Mount(MountPoint *mount);
+size_t ext2Statfs(MountPoint *mnt, struct statfs *target);
 size_t ext2Open(char *filename, int flags, int mode, OpenFile *fd,
                 char **symlinkResolve);
 bool   ext2Close(OpenFile *fd);
@@ -157,6 +157,7 @@ FAT32TraverseResult fat32TraversePath(FAT32 *fat, char *path,
 bool   fat32Stat(MountPoint *mnt, char *filename, struct stat *target,
                  char **symlinkResolve);
 size_t fat32StatFd(OpenFile *fd, struct stat *target);
+size_t fat32Statfs(MountPoint *mnt, struct statfs *target);
 
 // fat32_dirs.c
 size_t fat32Getdents64(OpenFile *file, struct linux_dirent64 *start,
@@ -0,0 +1,18 @@
+#include "types.h"
+
+#ifndef FIREWALL_H
+#define FIREWALL_H
+
+// stateful IPv4 filter, connection tracking & NAT (networking/firewall.rs)
+
+struct pbuf;
+struct netif;
+
+// LWIP_HOOK_IP4_INPUT: non-zero when the packet was dropped (and freed)
+int firewallIp4Input(struct pbuf *p, struct netif *inp);
+// LWIP_HOOK_IP4_CANFORWARD: 0 while /proc/sys/net/ipv4/ip_forward is off
+int firewallIp4CanForward(struct pbuf *p, uint32_t dest);
+// OUTPUT & POSTROUTING over a finished ethernet frame, false drops it
+bool firewallOutput(void *netif, uint8_t *frame, uint32_t len);
+
+#endif
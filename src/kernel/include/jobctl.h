@@ -0,0 +1,35 @@
+#include "task.h"
+#include "tty.h"
+#include "types.h"
+
+#ifndef JOBCTL_H
+#define JOBCTL_H
+
+// Sessions, process groups, controlling terminals & stopped tasks (jobctl.c)
+
+// Task->jobctl, pending reports for wait4(WUNTRACED / WCONTINUED)
+#define JOBCTL_SIGNAL_MASK 0xff
+#define JOBCTL_STOPPED (1 << 8)
+#define JOBCTL_CONTINUED (1 << 9)
+
+bool taskSignalIgnored(Task *task, size_t signal);
+bool taskSignalCaught(Task *task, size_t signal);
+void taskSignalSend(Task *task, size_t signal);
+void taskStop(Task *task, size_t signal);
+void taskContinue(Task *task);
+bool processSignal(int pid, size_t signal);
+
+void   pgrpSignal(int pgid, size_t signal);
+bool   pgrpExists(int pgid, int sid);
+bool   pgrpIsOrphaned(int pgid);
+size_t pgrpSet(Task *caller, int pid, int pgid);
+
+size_t sessionCreate(Task *task);
+size_t sessionSetCtty(Task *task, Tty *tty, bool steal);
+void   sessionDetachCtty(Task *task);
+void   sessionHangup(Tty *tty);
+
+void jobctlTaskExit(Task *task);
+void jobctlWakeWaiter(Task *parent, uint64_t pid);
+
+#endif
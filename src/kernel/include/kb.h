@@ -4,21 +4,95 @@
 #ifndef KB_H
 #define KB_H
 
-#define SCANCODE_ENTER 28
-#define SCANCODE_BACK 14
-#define SCANCODE_SHIFT 42
-#define SCANCODE_CAPS 58
-#define SCANCODE_UP 0x48
+#define SCANCODE_SHIFT 0x2A
+#define SCANCODE_RSHIFT 0x36
+#define SCANCODE_E0 0xE0
+#define SCANCODE_E1 0xE1
 
 #define CHARACTER_ENTER '\n'
 #define CHARACTER_BACK '\b'
 
+// Keyboard modes (KDSKBMODE), per virtual terminal
+#define K_RAW 0
+#define K_XLATE 1
+#define K_MEDIUMRAW 2
+#define K_UNICODE 3
+#define K_OFF 4
+
+// KDSKBMETA
+#define K_METABIT 3
+#define K_ESCPREFIX 4
+
+// Lock flags (KDGKBLED) and LEDs (KDGETLED)
+#define K_SCROLLLOCK 0x01
+#define K_NUMLOCK 0x02
+#define K_CAPSLOCK 0x04
+
+#define KDGETLED 0x4B31
+#define KDSETLED 0x4B32
+#define KDGKBTYPE 0x4B33
+#define KDGKBLED 0x4B64
+#define KDSKBLED 0x4B65
+
+#define KB_101 0x02
+
+// Keymaps (keymap.c), the tables loadkeys fills
+#define NR_KEYS 256
+#define MAX_NR_KEYMAPS 256
+#define MAX_NR_FUNC 256
+#define MAX_DIACR 256
+
+#define KDGKBENT 0x4B46
+#define KDSKBENT 0x4B47
+#define KDGKBSENT 0x4B48
+#define KDSKBSENT 0x4B49
+#define KDGKBDIACR 0x4B4A
+#define KDSKBDIACR 0x4B4B
+#define KDGKBDIACRUC 0x4BFA
+#define KDSKBDIACRUC 0x4BFB
+
+typedef struct kbentry {
+  uint8_t  kb_table;
+  uint8_t  kb_index;
+  uint16_t kb_value;
+} kbentry;
+
+typedef struct kbsentry {
+  uint8_t kb_func;
+  uint8_t kb_string[512];
+} kbsentry;
+
+typedef struct kbdiacr {
+  uint8_t diacr, base, result;
+} kbdiacr;
+
+typedef struct kbdiacrs {
+  uint32_t kb_cnt;
+  kbdiacr  kbdiacr[MAX_DIACR];
+} kbdiacrs;
+
+typedef struct kbdiacruc {
+  uint32_t diacr, base, result;
+} kbdiacruc;
+
+typedef struct kbdiacrsuc {
+  uint32_t  kb_cnt;
+  kbdiacruc kbdiacruc[MAX_DIACR];
+} kbdiacrsuc;
+
 uint32_t readStr(char *buffstr);
 void     initiateKb();
 void     kbIrq();
 bool     kbTaskRead(uint32_t taskId, char *buff, uint32_t limit,
                     bool changeTaskState);
 bool     kbIsOccupied();
+size_t   kbIoctl(uint64_t request, void *arg);
+void     kbPanicPoll();
+void     kbKeycode(uint8_t keycode, bool down, bool repeat);
+uint8_t  kbLeds();
+
+void   initiateKeymap();
+size_t keymapIoctl(uint64_t request, void *arg, bool unicode);
 
 DevInputEvent *kbEvent;
 
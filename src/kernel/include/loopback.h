@@ -0,0 +1,19 @@
+#include "types.h"
+
+#include <lwip/netif.h>
+
+#ifndef LOOPBACK_H
+#define LOOPBACK_H
+
+// Loopback interface (lo), always present: 127.0.0.0/8 and ::1
+
+// lwIP keeps the MTU as a u16, so this is both enforced and reported
+#define LOOPBACK_MTU 65535
+
+struct netif loopbackNetif;
+
+void  loopbackInitInThread(void *arg);
+err_t loopbackNetifInit(struct netif *netif);
+err_t loopbackTransmit(struct netif *netif, struct pbuf *p);
+
+#endif
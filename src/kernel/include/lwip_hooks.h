@@ -0,0 +1,10 @@
+#ifndef LWIP_HOOKS_H
+#define LWIP_HOOKS_H
+
+// LWIP_HOOK_FILENAME: everything lwIP calls back into
+
+#include "dns.h"
+#include "firewall.h"
+#include "route.h"
+
+#endif
@@ -14,9 +14,30 @@
 
 #define MOUSE_TIMEOUT 100000
 
+#define MOUSE_CMD_SET_SAMPLE_RATE 0xF3
+#define MOUSE_CMD_GET_ID 0xF2
+
+#define MOUSE_ID_STANDARD 0x00
+#define MOUSE_ID_INTELLIMOUSE 0x03
+#define MOUSE_ID_EXPLORER 0x04
+
+#define VMWARE_MAGIC 0x564D5868
+#define VMWARE_PORT 0x5658
+
+#define VMWARE_CMD_GETVERSION 10
+#define VMWARE_CMD_ABSPOINTER_DATA 39
+#define VMWARE_CMD_ABSPOINTER_STATUS 40
+#define VMWARE_CMD_ABSPOINTER_COMMAND 41
+
+#define VMMOUSE_CMD_ENABLE 0x45414552
+#define VMMOUSE_CMD_DISABLE 0x000000F5
+#define VMMOUSE_CMD_REQUEST_ABSOLUTE 0x53424152
+#define VMMOUSE_VERSION_ID 0x3442554A
+
 void initiateMouse();
 void mouseIrq();
 
 DevInputEvent *mouseEvent;
+DevInputEvent *vmmouseEvent;
 
 #endif
@@ -0,0 +1,14 @@
+#include "types.h"
+
+#ifndef NETLINK_H
+#define NETLINK_H
+
+// NETLINK_ROUTE sockets & the SIOCGIF*/SIOCSIF* ioctls over lwIP's netifs
+
+#define AF_NETLINK 16
+#define NETLINK_ROUTE 0
+
+// hooks into lwIP's netif status callbacks for multicast notifications
+void netlinkInit();
+
+#endif
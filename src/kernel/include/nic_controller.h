@@ -86,5 +86,6 @@ atomic_int  netQueueRead;
 atomic_int  netQueueWrite;
 
 void netQueueAdd(NIC *nic, uint8_t *packet, uint16_t packetLength);
+uint16_t nativeQueuePop(NIC **nic, uint8_t *buff);
 
 #endif
@@ -0,0 +1,13 @@
+#include "types.h"
+
+#ifndef PACKET_H
+#define PACKET_H
+
+// AF_PACKET sockets, classic BPF filters & TPACKET_V1/V2 capture rings
+
+#define AF_PACKET 17
+
+// offers a frame the NIC received (or is about to send) to packet sockets
+void packetTap(void *netif, uint8_t *frame, uint16_t len, bool outgoing);
+
+#endif
@@ -16,6 +16,7 @@
 #define PF_GLOBAL (1 << 8)  // Indicates the page is globally cached
 #define PF_SHARED (1 << 9)  // Userland page is shared
 // #define PF_SYSTEM (1 << 9)  // Page used by the kernel
+#define PF_NX (1ULL << 63)  // No execute (needs EFER.NXE)
 
 // Region caching (following the Limine protocol)
 #define PF_CACHE_WC (PF_PAT | PF_PWT)
@@ -87,6 +88,13 @@ void      PageDirectoryFree(uint64_t *page_dir);
 
 void PageDirectoryUserDuplicate(uint64_t *source, uint64_t *target);
 
+// Calls back for every run of present user pages with the same PF_RW & PF_NX
+// bits
+typedef void (*PagingRegionCallback)(size_t start, size_t end, uint64_t flags,
+                                     void *ctx);
+void PagingUserRegions(uint64_t *pagedir, PagingRegionCallback callback,
+                       void *ctx);
+
 void invalidate(uint64_t vaddr);
 
 #endif
\ No newline at end of file
@@ -123,12 +123,17 @@ typedef enum PCI_DRIVER {
   PCI_DRIVER_RTL8139,
   PCI_DRIVER_RTL8169,
   PCI_DRIVER_E1000,
+  PCI_DRIVER_XHCI,
+  PCI_DRIVER_HDA,
+  PCI_DRIVER_AC97,
 } PCI_DRIVER;
 
 typedef enum PCI_DRIVER_CATEGORY {
   PCI_DRIVER_CATEGORY_NULL = 0,
   PCI_DRIVER_CATEGORY_STORAGE,
   PCI_DRIVER_CATEGORY_NIC,
+  PCI_DRIVER_CATEGORY_USB,
+  PCI_DRIVER_CATEGORY_SOUND,
 } PCI_DRIVER_CATEGORY;
 
 typedef struct PCI PCI;
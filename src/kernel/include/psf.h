@@ -17,13 +17,48 @@ typedef enum PSF1_MODES {
   PSF1_MODESEQ = 0x04,
 } PSF1_MODES;
 
-PSF1Header *psf;
+#define PSF1_SEPARATOR 0xFFFF
+#define PSF1_STARTSEQ 0xFFFE
+
+#define PSF2_MAGIC 0x864ab572
+#define PSF2_HAS_UNICODE_TABLE 0x01
+#define PSF2_SEPARATOR 0xFF
+#define PSF2_STARTSEQ 0xFE
+
+typedef struct PSF2Header {
+  uint32_t magic;
+  uint32_t version;
+  uint32_t headersize;
+  uint32_t flags;
+  uint32_t length; // glyphs
+  uint32_t charsize;
+  uint32_t height;
+  uint32_t width;
+} PSF2Header;
+
+// Code point -> glyph, from the font's unicode table or PIO_UNIMAP
+typedef struct UnicodePair {
+  uint32_t codepoint;
+  uint32_t glyph;
+} UnicodePair;
 
 bool psfLoadDefaults();
 bool psfLoadFromFile(char *path);
-bool psfLoad(void *buffer);
 
-void psfPutC(char c, uint32_t x, uint32_t y, uint32_t r, uint32_t g,
-             uint32_t b);
+uint32_t psfGlyphWidth();
+uint32_t psfGlyphHeight();
+uint32_t psfGlyphCount();
+
+// Colors as 0xRRGGBB, unknown code points get the replacement glyph
+void psfPutC(uint32_t codepoint, uint32_t x, uint32_t y, uint32_t fg,
+             uint32_t bg);
+
+// Console font ioctls (see vt.c)
+bool     psfSetFont(const uint8_t *glyphs, uint32_t count, uint32_t width,
+                    uint32_t height, uint32_t charsize);
+uint32_t psfGetFont(uint8_t *out, uint32_t max, uint32_t charsize);
+void     psfUnicodeClear();
+bool     psfUnicodeAdd(const UnicodePair *pairs, size_t len);
+size_t   psfUnicodeGet(UnicodePair *out, size_t from, size_t max);
 
 #endif
@@ -0,0 +1,19 @@
+#include "types.h"
+
+#ifndef ROUTE_H
+#define ROUTE_H
+
+// IPv4 routing table: longest prefix match across every netif
+// (networking/route.rs)
+
+struct netif;
+struct ip4_addr;
+
+// LWIP_HOOK_IP4_ROUTE_SRC: the outgoing netif, NULL for lwIP's default
+struct netif *routeIp4Select(const struct ip4_addr *src,
+                             const struct ip4_addr *dest);
+// LWIP_HOOK_ETHARP_GET_GW: the next hop, NULL for the netif's gateway
+const struct ip4_addr *routeIp4Gateway(struct netif *netif,
+                                       const struct ip4_addr *dest);
+
+#endif
@@ -18,7 +18,4 @@ VfsHandlers socketHandlers;
 uint16_t sockaddrLinuxToLwip(void *dest_addr, uint32_t addrlen);
 void     sockaddrLwipToLinux(void *dest_addr, uint16_t initialFamily);
 
-// socket_v6.c
-VfsHandlers socketv6Handlers;
-
 #endif
\ No newline at end of file
@@ -0,0 +1,80 @@
+#include "pci.h"
+#include "types.h"
+#include "vfs.h"
+
+#ifndef SOUND_H
+#define SOUND_H
+
+// Where the hardware plays from: the ALSA buffer, cut into periods
+typedef struct SoundStream {
+  uint8_t *ring;
+  size_t   ringPhys;
+  size_t   bytes;
+  size_t   periodBytes;
+  uint32_t periods;
+  uint32_t rate;
+  uint32_t channels;
+} SoundStream;
+
+// An audio driver, as the sound core sees it. Samples are always S16_LE,
+// interleaved; the first driver to register becomes card 0
+typedef struct SoundBackend {
+  const char *id;
+  const char *driver;
+  const char *name;
+
+  // SNDRV_PCM_RATE_* bits
+  uint32_t rates;
+  uint32_t channelsMin, channelsMax;
+
+  // buffer descriptor list limits
+  uint32_t periodBytesAlign, periodBytesMax, periodsMax;
+  bool     periodsPow2;
+
+  bool (*prepare)(const SoundStream *stream);
+  void (*start)();
+  // may be called from the interrupt handler
+  void (*stop)();
+  // bytes into the ring the hardware has reached
+  size_t (*position)();
+} SoundBackend;
+
+void soundRegisterBackend(const SoundBackend *backend);
+// from the driver's interrupt handler, once per period played
+void soundPeriodElapsed();
+
+void initiateHDA(PCIdevice *device);
+void initiateAC97(PCIdevice *device);
+
+extern VfsHandlers handleSndPcm;
+extern VfsHandlers handleSndControl;
+
+/* the subset of the Linux ALSA uapi we answer ('A' pcm, 'U' control) */
+#define SNDRV_PCM_IOCTL_BASE 'A'
+#define SNDRV_CTL_IOCTL_BASE 'U'
+
+#define SNDRV_PCM_IOCTL_PVERSION 0x00
+#define SNDRV_PCM_IOCTL_INFO 0x01
+#define SNDRV_PCM_IOCTL_HW_REFINE 0x10
+#define SNDRV_PCM_IOCTL_HW_PARAMS 0x11
+#define SNDRV_PCM_IOCTL_HW_FREE 0x12
+#define SNDRV_PCM_IOCTL_SW_PARAMS 0x13
+#define SNDRV_PCM_IOCTL_STATUS 0x20
+#define SNDRV_PCM_IOCTL_DELAY 0x21
+#define SNDRV_PCM_IOCTL_HWSYNC 0x22
+#define SNDRV_PCM_IOCTL_SYNC_PTR 0x23
+#define SNDRV_PCM_IOCTL_PREPARE 0x40
+#define SNDRV_PCM_IOCTL_RESET 0x41
+#define SNDRV_PCM_IOCTL_START 0x42
+#define SNDRV_PCM_IOCTL_DROP 0x43
+#define SNDRV_PCM_IOCTL_DRAIN 0x44
+#define SNDRV_PCM_IOCTL_PAUSE 0x45
+#define SNDRV_PCM_IOCTL_WRITEI_FRAMES 0x50
+
+#define SNDRV_CTL_IOCTL_PVERSION 0x00
+#define SNDRV_CTL_IOCTL_CARD_INFO 0x01
+#define SNDRV_CTL_IOCTL_ELEM_LIST 0x10
+#define SNDRV_CTL_IOCTL_PCM_NEXT_DEVICE 0x30
+#define SNDRV_CTL_IOCTL_PCM_INFO 0x31
+
+#endif
@@ -114,10 +114,12 @@ typedef enum SignalInternal {
 void   initiateSignalDefs();
 void   signalsPendingHandleSys(void *taskPtr, uint64_t *rsp,
                                AsmPassedInterrupt *registers);
-void   signalsPendingHandleSched(void *taskPtr);
+bool   signalsPendingHandleSched(void *taskPtr);
+void   signalsPendingHandleIrq(AsmPassedInterrupt *registers);
 size_t signalsSigreturnSyscall(void *taskPtr);
 bool   signalsPendingQuick(void *taskPtr);
 bool   signalsRevivableState(int state);
+void   signalsFault(AsmPassedInterrupt *registers, size_t signal);
 
 #if DEBUG_SIGNALS_HITS
 #define dbgSigHitf debugf
@@ -38,6 +38,7 @@ typedef enum TASK_STATE {
   TASK_STATE_BLOCKED = 8,
   TASK_STATE_SIGKILLED = 9,
   TASK_STATE_FUTEX = 10,
+  TASK_STATE_STOPPED = 11, // job control, see jobctl.h
   TASK_STATE_DUMMY = 69,
 } TASK_STATE;
 
@@ -45,7 +46,8 @@ typedef struct KilledInfo {
   LLheader _ll;
 
   uint64_t pid;
-  uint16_t ret;
+  int      pgid;
+  uint16_t ret; // wait4() status
 } KilledInfo;
 
 typedef struct Task Task;
@@ -62,6 +64,7 @@ typedef struct TaskInfoFs {
 TaskInfoFs *taskInfoFsAllocate();
 TaskInfoFs *taskInfoFsClone(TaskInfoFs *old);
 void        taskInfoFsDiscard(TaskInfoFs *target);
+bool        taskInfoFsCwdWithin(const char *path, size_t len);
 
 typedef struct UserspaceMapping {
   void  *virt; // it is the key aswell
@@ -69,6 +72,22 @@ typedef struct UserspaceMapping {
   bool   onDemand;
 } UserspaceMapping;
 
+#define AUXV_SAVED_MAX 48
+
+typedef struct FileMapping {
+  LLheader _ll;
+
+  size_t start;
+  size_t end;
+  size_t offset;
+  char  *path; // null until syscall_mmap() names it
+
+  // whatever the mapping keeps alive (tmpfs pages), told whenever a record of
+  // it comes (take) or goes
+  void (*hold)(void *ctx, bool take);
+  void *holdCtx;
+} FileMapping;
+
 typedef struct TaskInfoPagedir {
   Spinlock LOCK_PD;
   int      utilizedBy;
@@ -82,10 +101,21 @@ typedef struct TaskInfoPagedir {
   // todo: maybe make it a linked list, might be more efficient
   AVLheader *mappings; // UserspaceMapping*
 
+  // copy of the auxiliary vector handed to the executable (core dumps)
+  uint64_t  savedAuxv[AUXV_SAVED_MAX];
+  LLcontrol dsFileMapping; // struct FileMapping
+
   uint64_t *pagedir;
 } TaskInfoPagedir;
 
 TaskInfoPagedir *taskInfoPdAllocate(bool pagedir);
+void taskInfoPdMappingAdd(TaskInfoPagedir *target, size_t start, size_t end,
+                          size_t offset, const char *path);
+void taskInfoPdMappingAddHeld(TaskInfoPagedir *target, size_t start,
+                              size_t end, size_t offset,
+                              void (*hold)(void *ctx, bool take),
+                              void *holdCtx);
+void taskInfoPdMappingRemove(TaskInfoPagedir *target, size_t start, size_t end);
 TaskInfoPagedir *taskInfoPdClone(TaskInfoPagedir *old);
 void             taskInfoPdDiscard(TaskInfoPagedir *target);
 
@@ -100,6 +130,10 @@ typedef struct TaskInfoSignal {
 
   IntTimerInternal itimerReal; // ITIMER_REAL
   struct sigaction signals[_NSIG + 1];
+
+  // RLIMIT_CORE
+  size_t rlimitCoreSoft;
+  size_t rlimitCoreHard;
 } TaskInfoSignal;
 
 TaskInfoSignal *taskInfoSignalAllocate();
@@ -170,7 +204,9 @@ struct Task {
   termios  term;
   uint32_t tmpRecV;
   int      kernelErrno;
-  int      ctrlPty;
+  void    *ctrlTty; // Tty*, same for the whole session
+  uint32_t jobctl;  // JOBCTL_* reports pending for wait4()
+  uint8_t  stateBeforeStop; // restored by taskContinue()
   void    *spinlockQueueEntry; // check on kill!
 
   Semaphore lwipSem;
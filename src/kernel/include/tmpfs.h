@@ -0,0 +1,132 @@
+#include "linux.h"
+#include "types.h"
+#include "vfs.h"
+
+#ifndef TMPFS_H
+#define TMPFS_H
+
+// RAM backed filesystem (/tmp, /dev/shm, /run)
+
+#define TMPFS_MAGIC 0x01021994
+#define TMPFS_NAME_MAX 255
+
+typedef struct TmpfsInode TmpfsInode;
+
+typedef struct TmpfsDirent {
+  struct TmpfsDirent *next;
+
+  char  *name;
+  size_t nameLen;
+
+  TmpfsInode *inode;
+} TmpfsDirent;
+
+struct TmpfsInode {
+  size_t ino;
+
+  uint32_t mode;
+  uint32_t uid;
+  uint32_t gid;
+  uint32_t nlink;
+
+  uint64_t atime;
+  uint64_t mtime;
+  uint64_t ctime;
+
+  size_t size;
+
+  // regular files, physical page per file page (0 being a hole)
+  size_t *pages;
+  size_t  pagesCap;
+  // live MAP_SHARED mappings, pages truncated away meanwhile wait in orphans
+  // for the last one to go
+  size_t  mappings;
+  size_t *orphans;
+  size_t  orphansLen;
+  size_t  orphansCap;
+  struct Tmpfs *tmpfs;
+
+  // symlinks
+  char *symlink;
+
+  // directories
+  TmpfsDirent       *firstDirent;
+  struct TmpfsInode *parent;
+
+  size_t openFds;
+};
+
+typedef struct Tmpfs {
+  Spinlock LOCK_TMPFS;
+
+  TmpfsInode *root;
+  size_t      lastIno;
+
+  // size= & nr_inodes= (0 for unlimited)
+  size_t maxPages;
+  size_t maxInodes;
+
+  size_t usedPages;
+  size_t usedInodes;
+
+  // mappings outlive the mount, the last one frees an unmounted instance
+  size_t mappings;
+  bool   unmounted;
+} Tmpfs;
+
+typedef struct TmpfsOpenFd {
+  TmpfsInode *inode;
+  size_t      ptr;
+} TmpfsOpenFd;
+
+#define TMPFS_PTR(mnt) ((Tmpfs *)((mnt)->fsInfo))
+
+// tmpfs.c
+bool   tmpfsMount(MountPoint *mount, char *options);
+bool   tmpfsRemount(MountPoint *mount, char *options);
+void   tmpfsUnmount(MountPoint *mount);
+size_t tmpfsStatfs(MountPoint *mnt, struct statfs *target);
+bool   tmpfsStat(MountPoint *mnt, char *filename, struct stat *target,
+                 char **symlinkResolve);
+bool   tmpfsLstat(MountPoint *mnt, char *filename, struct stat *target,
+                  char **symlinkResolve);
+size_t tmpfsMkdir(MountPoint *mnt, char *dirname, uint32_t mode,
+                  char **symlinkResolve);
+size_t tmpfsSymlink(MountPoint *mnt, char *filename, char *target,
+                    char **symlinkResolve);
+size_t tmpfsLink(MountPoint *mnt, char *filename, char *target,
+                 char **symlinkResolve, char **symlinkResolveTarget);
+size_t tmpfsDelete(MountPoint *mnt, char *filename, bool directory,
+                   char **symlinkResolve);
+size_t tmpfsReadlink(MountPoint *mnt, char *path, char *buf, int size,
+                     char **symlinkResolve);
+size_t tmpfsRename(MountPoint *mnt, char *oldpath, char *newpath,
+                   char **symlinkResolve, char **symlinkResolveTarget);
+size_t tmpfsChmod(MountPoint *mnt, char *path, uint32_t mode,
+                  char **symlinkResolve);
+size_t tmpfsChown(MountPoint *mnt, char *path, uint32_t uid, uint32_t gid,
+                  bool follow, char **symlinkResolve);
+size_t tmpfsUtimens(MountPoint *mnt, char *path, uint64_t atime,
+                    uint64_t mtime, bool follow, char **symlinkResolve);
+size_t tmpfsTruncate(MountPoint *mnt, char *path, size_t length,
+                     char **symlinkResolve);
+
+// tmpfs_fd.c
+size_t tmpfsOpen(char *filename, int flags, int mode, OpenFile *fd,
+                 char **symlinkResolve);
+bool   tmpfsClose(OpenFile *fd);
+bool   tmpfsDuplicate(OpenFile *original, OpenFile *orphan);
+size_t tmpfsRead(OpenFile *fd, uint8_t *buff, size_t limit);
+size_t tmpfsWrite(OpenFile *fd, uint8_t *buff, size_t limit);
+size_t tmpfsSeek(OpenFile *fd, size_t target, long int offset, int whence);
+size_t tmpfsGetFilesize(OpenFile *fd);
+size_t tmpfsStatFd(OpenFile *fd, struct stat *target);
+size_t tmpfsFtruncate(OpenFile *fd, size_t length);
+size_t tmpfsGetdents64(OpenFile *fd, struct linux_dirent64 *start,
+                       unsigned int hardlimit);
+size_t tmpfsMmap(size_t addr, size_t length, int prot, int flags, OpenFile *fd,
+                 size_t pgoffset);
+
+VfsHandlers tmpfsHandlers;
+
+#endif
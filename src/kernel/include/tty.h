@@ -0,0 +1,79 @@
+#include "linux.h"
+#include "task.h"
+#include "types.h"
+#include "util.h"
+#include "vfs.h"
+
+#ifndef TTY_H
+#define TTY_H
+
+// N_TTY line discipline, shared by ptys and the console (tty.c)
+#define TTY_BUFF_SIZE 4096
+#define TTY_LINE_MAX 4095
+
+typedef struct Tty Tty;
+
+// pushes post-processed output to whatever is behind the tty
+typedef size_t (*TtyOutput)(Tty *tty, const uint8_t *buff, size_t len);
+// how much the output side can currently take without blocking
+typedef size_t (*TtyOutputRoom)(Tty *tty);
+// opens another handle to the device behind the tty (/dev/tty)
+typedef size_t (*TtyReopen)(Tty *tty, OpenFile *fd);
+
+struct Tty {
+  Spinlock LOCK_TTY;
+
+  termios term;
+  winsize win;
+
+  // cooked input ready for read(), readDelim marks line ends and readEof
+  // the empty lines left by a lone VEOF
+  uint8_t *readBuff;
+  uint8_t *readDelim;
+  uint8_t *readEof;
+  size_t   readHead;
+  size_t   readTail;
+  size_t   readLines;
+  uint64_t lastInput;
+
+  // line currently being edited (ICANON)
+  uint8_t *lineBuff;
+  size_t   lineLen;
+  bool     lnext;
+
+  size_t column;
+
+  // session this is the controlling terminal of, and its foreground group
+  int ctrlSession;
+  int ctrlPgid;
+
+  TtyOutput     output;
+  TtyOutputRoom outputRoom;
+  TtyReopen     reopen;
+  void         *ctx;
+
+  size_t pollKey;
+};
+
+void   ttyTermiosDefaults(termios *term);
+void   ttyInit(Tty *tty, TtyOutput output, TtyOutputRoom outputRoom, void *ctx);
+void   ttyDestroy(Tty *tty);
+size_t ttyReceive(Tty *tty, const uint8_t *buff, size_t len);
+size_t ttyRead(Tty *tty, OpenFile *fd, uint8_t *out, size_t limit);
+size_t ttyWrite(Tty *tty, const uint8_t *buff, size_t len);
+int    ttyPoll(Tty *tty, int events);
+size_t ttyReadable(Tty *tty);
+size_t ttyReceiveRoom(Tty *tty);
+void   ttySignal(Tty *tty, size_t signal);
+void   ttySetWinsize(Tty *tty, const winsize *win);
+size_t ttyIoctl(Tty *tty, uint64_t request, void *arg);
+void   ttyOpened(Tty *tty, uint32_t flags);
+size_t ttyReopen(Tty *tty, OpenFile *fd);
+void   ttyHangup(Tty *tty);
+
+// io.c
+Tty  consoleTty;
+void initiateConsoleTty();
+void consoleTtyAttach(Task *task);
+
+#endif
@@ -0,0 +1,108 @@
+#include "console.h"
+#include "tty.h"
+#include "types.h"
+#include "vfs.h"
+
+#ifndef VT_H
+#define VT_H
+
+// Virtual terminals tty1..tty6 on the framebuffer console (vt.c)
+#define VT_COUNT 6
+
+#define VT_OPENQRY 0x5600
+#define VT_GETMODE 0x5601
+#define VT_SETMODE 0x5602
+#define VT_GETSTATE 0x5603
+#define VT_RELDISP 0x5605
+#define VT_ACTIVATE 0x5606
+#define VT_WAITACTIVE 0x5607
+
+#define KDSETMODE 0x4B3A
+#define KDGETMODE 0x4B3B
+#define KDGKBMODE 0x4B44
+#define KDSKBMODE 0x4B45
+#define KDGKBMETA 0x4B62
+#define KDSKBMETA 0x4B63
+
+#define GIO_FONT 0x4B60
+#define PIO_FONT 0x4B61
+#define GIO_UNIMAP 0x4B66
+#define PIO_UNIMAP 0x4B67
+#define PIO_UNIMAPCLR 0x4B68
+#define GIO_FONTX 0x4B6B
+#define PIO_FONTX 0x4B6C
+#define KDFONTOP 0x4B72
+
+#define KD_FONT_OP_SET 0
+#define KD_FONT_OP_GET 1
+#define KD_FONT_OP_SET_DEFAULT 2
+
+#define KD_TEXT 0
+#define KD_GRAPHICS 1
+
+#define VT_AUTO 0
+#define VT_PROCESS 1
+#define VT_ACKACQ 2
+
+typedef struct vt_mode {
+  int8_t  mode;
+  int8_t  waitv;
+  int16_t relsig;
+  int16_t acqsig;
+  int16_t frsig;
+} vt_mode;
+
+typedef struct vt_stat {
+  uint16_t v_active;
+  uint16_t v_signal;
+  uint16_t v_state;
+} vt_stat;
+
+// Glyphs in font ioctls are 32 rows apart
+typedef struct console_font_op {
+  uint32_t op;
+  uint32_t flags;
+  uint32_t width;
+  uint32_t height;
+  uint32_t charcount;
+  uint8_t *data;
+} console_font_op;
+
+typedef struct consolefontdesc {
+  uint16_t charcount;
+  uint16_t charheight;
+  uint8_t *chardata;
+} consolefontdesc;
+
+typedef struct unipair {
+  uint16_t unicode;
+  uint16_t fontpos;
+} unipair;
+
+typedef struct unimapdesc {
+  uint16_t entry_ct;
+  unipair *entries;
+} unimapdesc;
+
+extern size_t vtActive;
+extern size_t vtLast;
+
+VfsHandlers handleVt;
+
+void   initiateVirtualTerminals();
+void   vtBindForeground();
+void   vtModeChanged();
+size_t vtConsoleWrite(size_t index, const uint8_t *buff, size_t len);
+size_t vtActivate(size_t target);
+void   vtInput(uint8_t c);
+void   vtCursorKey(uint8_t key);
+void   vtReply(const uint8_t *buff, size_t len);
+void   vtFlushReplies(size_t index);
+size_t vtIoctl(size_t index, uint64_t request, void *arg);
+void   vtFontChanged();
+void   vtScrollback(int32_t pages);
+void   vtScrollbackPanic(int32_t pages);
+int    vtKeyboardMode();
+int    vtKeyboardMeta();
+
+#endif
@@ -3,6 +3,7 @@
 #![allow(non_snake_case)]
 #![allow(unused_variables)]
 
+use core::ffi::c_void;
 use core::ptr::{null_mut};
 use core::sync::atomic::{AtomicUsize, Ordering};
 
@@ -20,6 +21,7 @@ const PF_RW: u64      = 1 << 1;
 const PF_USER: u64    = 1 << 2;
 const PF_PS: u64      = 1 << 7;
 const PF_SHARED: u64  = 1 << 9;
+const PF_NX: u64      = 1 << 63;
 
 #[inline(always)]
 const fn PML4E(v: u64) -> usize { ((v >> 39) & 0x1FF) as usize }
@@ -253,3 +255,68 @@ pub unsafe fn VirtualToPhysicalL(pagedir: *mut u64, virt: usize) -> usize {
 pub unsafe fn VirtualToPhysical(virt: usize) -> usize {
     VirtualToPhysicalL(globalPagedir, virt)
 }
+
+//
+// ======================
+// Walking
+// ======================
+//
+
+pub type PagingRegionCallback =
+    unsafe extern "C" fn(start: usize, end: usize, flags: u64, ctx: *mut c_void);
+
+// Calls `callback` for every run of contiguous present user pages in the lower
+// half of `pagedir`. Pages of one run share the same PF_RW bit and the same
+// PF_NX bit, which counts when set at any level of the walk.
+#[no_mangle]
+pub unsafe extern "C" fn PagingUserRegions(
+    pagedir: *mut u64,
+    callback: PagingRegionCallback,
+    ctx: *mut c_void,
+) {
+    let mut runStart: u64 = 0;
+    let mut runEnd: u64 = 0;
+    let mut runFlags: u64 = 0;
+
+    for pml4i in 0..256 {
+        let pml4 = *pagedir.add(pml4i);
+        if pml4 & PF_PRESENT == 0 { continue; }
+        let pdp = (PTE_GET_ADDR(pml4) + bootloader.hhdmOffset) as *const u64;
+
+        for pdpi in 0..512 {
+            let pdpe = *pdp.add(pdpi);
+            if pdpe & PF_PRESENT == 0 { continue; }
+            let pd = (PTE_GET_ADDR(pdpe) + bootloader.hhdmOffset) as *const u64;
+
+            for pdi in 0..512 {
+                let pde = *pd.add(pdi);
+                // userspace never gets huge pages
+                if pde & PF_PRESENT == 0 || pde & PF_PS != 0 { continue; }
+                let pt = (PTE_GET_ADDR(pde) + bootloader.hhdmOffset) as *const u64;
+
+                for pti in 0..512 {
+                    let pte = *pt.add(pti);
+                    if pte & PF_PRESENT == 0 || pte & PF_USER == 0 { continue; }
+
+                    let virt = BITS_TO_VIRT_ADDR(pml4i, pdpi, pdi, pti);
+                    let flags = (pte & PF_RW) | ((pml4 | pdpe | pde | pte) & PF_NX);
+                    if runEnd == virt && runFlags == flags && runEnd != runStart {
+                        runEnd += PAGE_SIZE as u64;
+                        continue;
+                    }
+
+                    if runEnd != runStart {
+                        callback(runStart as usize, runEnd as usize, runFlags, ctx);
+                    }
+                    runStart = virt;
+                    runEnd = virt + PAGE_SIZE as u64;
+                    runFlags = flags;
+                }
+            }
+        }
+    }
+
+    if runEnd != runStart {
+        callback(runStart as usize, runEnd as usize, runFlags, ctx);
+    }
+}
@@ -0,0 +1,581 @@
+#![no_std]
+#![allow(non_snake_case)]
+
+use core::ffi::c_void;
+use core::ptr::null_mut;
+
+use crate::tty::*;
+
+//
+// Job control: sessions, process groups, controlling terminals and stopped
+// tasks. Everything here walks the global task list, so the "Unsafe" variants
+// expect TASK_LL_MODIFY to already be held by the caller: write-held for
+// anything changing another task's state, pgid, sid or terminal, read-held
+// for pure lookups.
+//
+
+//
+// Constants
+//
+
+const SIGHUP: usize = 1;
+const SIGKILL: usize = 9;
+const SIGCHLD: usize = 17;
+const SIGCONT: usize = 18;
+const SIGSTOP: usize = 19;
+const SIGTSTP: usize = 20;
+const SIGTTIN: usize = 21;
+const SIGTTOU: usize = 22;
+
+const TASK_STATE_DEAD: u8 = 0;
+const TASK_STATE_READY: u8 = 1;
+const TASK_STATE_WAITING_CHILD: u8 = 5;
+const TASK_STATE_WAITING_CHILD_SPECIFIC: u8 = 6;
+pub const TASK_STATE_STOPPED: u8 = 11;
+
+// Task::jobctl, pending reports for wait4(WUNTRACED / WCONTINUED)
+pub const JOBCTL_SIGNAL_MASK: u32 = 0xff;
+pub const JOBCTL_STOPPED: u32 = 1 << 8;
+pub const JOBCTL_CONTINUED: u32 = 1 << 9;
+
+const SIG_DFL: usize = 0;
+const SIG_IGN: usize = 1;
+
+const EPERM: isize = 1;
+const ESRCH: isize = 3;
+const EINVAL: isize = 22;
+
+#[inline]
+const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+//
+// External kernel APIs
+//
+
+extern "C" {
+    fn spinlockCntReadAcquire(lock: *mut SpinlockCnt);
+    fn spinlockCntReadRelease(lock: *mut SpinlockCnt);
+    fn spinlockCntWriteAcquire(lock: *mut SpinlockCnt);
+    fn spinlockCntWriteRelease(lock: *mut SpinlockCnt);
+
+    fn atomicBitmapSet(bitmap: *mut u64, bit: usize);
+    fn atomicBitmapClear(bitmap: *mut u64, bit: usize);
+
+    static mut firstTask: *mut Task;
+    static mut TASK_LL_MODIFY: SpinlockCnt;
+}
+
+//
+// Signal dispositions (partial TaskInfoSignal)
+//
+
+#[repr(C)]
+pub struct sigaction {
+    pub sa_handler: usize,
+    pub sa_flags: u64,
+    pub sa_restorer: usize,
+    pub sa_mask: u64,
+}
+
+#[repr(C)]
+pub struct TaskInfoSignal {
+    pub LOCK_SIGNAL: Spinlock,
+    pub utilizedBy: i32,
+    pub itimerReal: [u64; 2],
+    pub signals: [sigaction; 65],
+}
+
+//
+// Helpers
+//
+
+#[inline]
+unsafe fn taskAlive(task: *mut Task) -> bool {
+    !(*task).kernel_task && (*task).state != TASK_STATE_DEAD
+}
+
+fn isStopSignal(signal: usize) -> bool {
+    matches!(signal, SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU)
+}
+
+unsafe fn taskGetUnsafe(id: u64) -> *mut Task {
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if (*browse).id == id && (*browse).state != TASK_STATE_DEAD {
+            return browse;
+        }
+        browse = (*browse).next;
+    }
+    null_mut()
+}
+
+// wait4() reports belong to the process, so they live on the group leader
+unsafe fn taskGroupLeaderUnsafe(task: *mut Task) -> *mut Task {
+    let leader = taskGetUnsafe((*task).tgid as u64);
+    if leader.is_null() {
+        task
+    } else {
+        leader
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn taskSignalIgnored(task: *mut Task, signal: usize) -> bool {
+    if (*task).sigBlockList & (1 << signal) != 0 {
+        return true;
+    }
+    let info = (*task).infoSignals as *mut TaskInfoSignal;
+    !info.is_null() && (*info).signals[signal].sa_handler == SIG_IGN
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn taskSignalCaught(task: *mut Task, signal: usize) -> bool {
+    let info = (*task).infoSignals as *mut TaskInfoSignal;
+    !info.is_null() && !matches!((*info).signals[signal].sa_handler, SIG_DFL | SIG_IGN)
+}
+
+//
+// Stopping & continuing
+//
+
+unsafe fn jobctlNotifyParentUnsafe(leader: *mut Task) {
+    let parent = (*leader).parent;
+    if parent.is_null() || (*parent).kernel_task || (*parent).state == TASK_STATE_DEAD {
+        return;
+    }
+
+    atomicBitmapSet(&mut (*parent).sigPendingList, SIGCHLD);
+    jobctlWakeWaiter(parent, (*leader).id);
+}
+
+// Wakes a parent sitting in wait4() for `pid`. One that's stopped mid-wait
+// gets it recorded for when it's continued instead.
+#[no_mangle]
+pub unsafe extern "C" fn jobctlWakeWaiter(parent: *mut Task, pid: u64) {
+    let state = if (*parent).state == TASK_STATE_STOPPED {
+        &mut (*parent).stateBeforeStop
+    } else {
+        &mut (*parent).state
+    };
+    if *state == TASK_STATE_WAITING_CHILD
+        || (*state == TASK_STATE_WAITING_CHILD_SPECIFIC && (*parent).waitingForPid == pid)
+    {
+        *state = TASK_STATE_READY;
+    }
+}
+
+unsafe fn taskStopUnsafe(task: *mut Task, signal: usize) {
+    let tgid = (*task).tgid;
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && (*browse).tgid == tgid && (*browse).state != TASK_STATE_STOPPED {
+            // whatever it was blocked in gets picked up again on SIGCONT
+            (*browse).stateBeforeStop = (*browse).state;
+            (*browse).state = TASK_STATE_STOPPED;
+        }
+        browse = (*browse).next;
+    }
+
+    let leader = taskGroupLeaderUnsafe(task);
+    (*leader).jobctl = JOBCTL_STOPPED | (signal as u32 & JOBCTL_SIGNAL_MASK);
+    jobctlNotifyParentUnsafe(leader);
+}
+
+unsafe fn taskContinueUnsafe(task: *mut Task) {
+    let tgid = (*task).tgid;
+    let mut stopped = false;
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && (*browse).tgid == tgid && (*browse).state == TASK_STATE_STOPPED {
+            (*browse).state = (*browse).stateBeforeStop;
+            stopped = true;
+        }
+        browse = (*browse).next;
+    }
+
+    if !stopped {
+        return;
+    }
+    let leader = taskGroupLeaderUnsafe(task);
+    (*leader).jobctl = JOBCTL_CONTINUED;
+    jobctlNotifyParentUnsafe(leader);
+}
+
+// Stops the whole thread group; the caller is expected to handControl() if
+// it stopped itself. Only SIGCONT or SIGKILL bring it back.
+#[no_mangle]
+pub unsafe extern "C" fn taskStop(task: *mut Task, signal: usize) {
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    taskStopUnsafe(task, signal);
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn taskContinue(task: *mut Task) {
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    taskContinueUnsafe(task);
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+}
+
+unsafe fn taskSignalSendUnsafe(task: *mut Task, signal: usize) {
+    if signal == SIGCONT {
+        // continuing happens at send time, even if SIGCONT is blocked
+        atomicBitmapClear(&mut (*task).sigPendingList, SIGSTOP);
+        atomicBitmapClear(&mut (*task).sigPendingList, SIGTSTP);
+        atomicBitmapClear(&mut (*task).sigPendingList, SIGTTIN);
+        atomicBitmapClear(&mut (*task).sigPendingList, SIGTTOU);
+        taskContinueUnsafe(task);
+    } else if isStopSignal(signal) {
+        atomicBitmapClear(&mut (*task).sigPendingList, SIGCONT);
+    }
+
+    atomicBitmapSet(&mut (*task).sigPendingList, signal);
+
+    // a stopped task still has to be able to die
+    if signal == SIGKILL && (*task).state == TASK_STATE_STOPPED {
+        (*task).state = TASK_STATE_READY;
+    }
+}
+
+// Should be used instead of poking sigPendingList directly whenever the
+// signal could be SIGCONT, SIGKILL or a stop signal
+#[no_mangle]
+pub unsafe extern "C" fn taskSignalSend(task: *mut Task, signal: usize) {
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    taskSignalSendUnsafe(task, signal);
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+}
+
+// For callers that only kept a pid around, false if the process is gone
+#[no_mangle]
+pub unsafe extern "C" fn processSignal(pid: i32, signal: usize) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    let task = taskGetUnsafe(pid as u64);
+    let alive = !task.is_null() && taskAlive(task);
+    if alive && signal != 0 {
+        taskSignalSendUnsafe(task, signal);
+    }
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+    alive
+}
+
+//
+// Process groups
+//
+
+unsafe fn pgrpSignalUnsafe(pgid: i32, signal: usize) {
+    if pgid <= 0 {
+        return;
+    }
+
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && (*browse).pgid == pgid {
+            taskSignalSendUnsafe(browse, signal);
+        }
+        browse = (*browse).next;
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pgrpSignal(pgid: i32, signal: usize) {
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    pgrpSignalUnsafe(pgid, signal);
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+}
+
+unsafe fn pgrpExistsUnsafe(pgid: i32, sid: i32) -> bool {
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && (*browse).pgid == pgid && (sid == 0 || (*browse).sid == sid) {
+            return true;
+        }
+        browse = (*browse).next;
+    }
+    false
+}
+
+// sid == 0 matches a process group in any session
+#[no_mangle]
+pub unsafe extern "C" fn pgrpExists(pgid: i32, sid: i32) -> bool {
+    spinlockCntReadAcquire(&mut TASK_LL_MODIFY);
+    let ret = pgrpExistsUnsafe(pgid, sid);
+    spinlockCntReadRelease(&mut TASK_LL_MODIFY);
+    ret
+}
+
+// A process group is orphaned when no member has a parent in a different
+// process group of the same session. `ignore` is treated as already gone.
+unsafe fn pgrpIsOrphanedUnsafe(pgid: i32, ignore: *mut Task) -> bool {
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && browse != ignore && (*browse).pgid == pgid {
+            let parent = (*browse).parent;
+            if !parent.is_null()
+                && parent != ignore
+                && taskAlive(parent)
+                && (*parent).pgid != pgid
+                && (*parent).sid == (*browse).sid
+            {
+                return false;
+            }
+        }
+        browse = (*browse).next;
+    }
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pgrpIsOrphaned(pgid: i32) -> bool {
+    spinlockCntReadAcquire(&mut TASK_LL_MODIFY);
+    let ret = pgrpIsOrphanedUnsafe(pgid, null_mut());
+    spinlockCntReadRelease(&mut TASK_LL_MODIFY);
+    ret
+}
+
+unsafe fn pgrpHasStoppedUnsafe(pgid: i32, ignore: *mut Task) -> bool {
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse)
+            && browse != ignore
+            && (*browse).pgid == pgid
+            && (*browse).state == TASK_STATE_STOPPED
+        {
+            return true;
+        }
+        browse = (*browse).next;
+    }
+    false
+}
+
+// POSIX: a process group that just got orphaned while having stopped members
+// gets SIGHUP followed by SIGCONT, otherwise nobody would ever resume it
+unsafe fn pgrpOrphanCheckUnsafe(pgid: i32, exiting: *mut Task) {
+    if pgid > 0 && pgrpIsOrphanedUnsafe(pgid, exiting) && pgrpHasStoppedUnsafe(pgid, exiting) {
+        pgrpSignalUnsafe(pgid, SIGHUP);
+        pgrpSignalUnsafe(pgid, SIGCONT);
+    }
+}
+
+// setpgid(2) on behalf of `caller`
+#[no_mangle]
+pub unsafe extern "C" fn pgrpSet(caller: *mut Task, pid: i32, pgid: i32) -> usize {
+    if pgid < 0 {
+        return err(EINVAL);
+    }
+
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    let target = if pid == 0 {
+        taskGroupLeaderUnsafe(caller)
+    } else {
+        taskGetUnsafe(pid as u64)
+    };
+
+    // only ourselves or our own children
+    if target.is_null()
+        || (target != taskGroupLeaderUnsafe(caller)
+            && (*target).parent != taskGroupLeaderUnsafe(caller)
+            && (*target).parent != caller)
+    {
+        spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+        return err(ESRCH);
+    }
+
+    let pgid = if pgid == 0 { (*target).tgid } else { pgid };
+    if (*target).sid == (*target).tgid
+        || (*target).sid != (*caller).sid
+        || (pgid != (*target).tgid && !pgrpExistsUnsafe(pgid, (*caller).sid))
+    {
+        spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+        return err(EPERM);
+    }
+
+    let tgid = (*target).tgid;
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && (*browse).tgid == tgid {
+            (*browse).pgid = pgid;
+        }
+        browse = (*browse).next;
+    }
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+
+    0
+}
+
+//
+// Sessions & controlling terminals
+//
+
+unsafe fn sessionCttyUnsafe(sid: i32) -> *mut Tty {
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && (*browse).sid == sid && !(*browse).ctrlTty.is_null() {
+            return (*browse).ctrlTty as *mut Tty;
+        }
+        browse = (*browse).next;
+    }
+    null_mut()
+}
+
+unsafe fn sessionAssignUnsafe(sid: i32, tty: *mut Tty) {
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && (*browse).sid == sid {
+            (*browse).ctrlTty = tty as *mut c_void;
+        }
+        browse = (*browse).next;
+    }
+}
+
+unsafe fn sessionDropCttyUnsafe(sid: i32, hangup: bool) {
+    let tty = sessionCttyUnsafe(sid);
+    if tty.is_null() {
+        return;
+    }
+
+    if hangup {
+        pgrpSignalUnsafe((*tty).ctrlPgid, SIGHUP);
+        pgrpSignalUnsafe((*tty).ctrlPgid, SIGCONT);
+    }
+    sessionAssignUnsafe(sid, null_mut());
+    (*tty).ctrlSession = 0;
+    (*tty).ctrlPgid = 0;
+}
+
+// setsid(2): the process becomes the leader of a new session and process
+// group, without a controlling terminal
+#[no_mangle]
+pub unsafe extern "C" fn sessionCreate(task: *mut Task) -> usize {
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    let tgid = (*task).tgid;
+    if pgrpExistsUnsafe(tgid, 0) {
+        // already a process group leader (or our id is taken as a pgid)
+        spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+        return err(EPERM);
+    }
+
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse) && (*browse).tgid == tgid {
+            (*browse).sid = tgid;
+            (*browse).pgid = tgid;
+            (*browse).ctrlTty = null_mut();
+        }
+        browse = (*browse).next;
+    }
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+
+    tgid as usize
+}
+
+// Makes `tty` the controlling terminal of the caller's session. The caller
+// must be the session leader; `steal` takes it away from another session.
+#[no_mangle]
+pub unsafe extern "C" fn sessionSetCtty(task: *mut Task, tty: *mut Tty, steal: bool) -> usize {
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    let sid = (*task).sid;
+    if sid != (*task).tgid || !(*task).ctrlTty.is_null() {
+        let same = (*task).ctrlTty == tty as *mut c_void;
+        spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+        return if same { 0 } else { err(EPERM) };
+    }
+
+    if (*tty).ctrlSession != 0 && (*tty).ctrlSession != sid {
+        if !steal {
+            spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+            return err(EPERM);
+        }
+        sessionDropCttyUnsafe((*tty).ctrlSession, false);
+    }
+
+    sessionAssignUnsafe(sid, tty);
+    (*tty).ctrlSession = sid;
+    (*tty).ctrlPgid = (*task).pgid;
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+
+    0
+}
+
+// TIOCNOTTY: a session leader takes the whole session with it, anyone else
+// only detaches its own process
+#[no_mangle]
+pub unsafe extern "C" fn sessionDetachCtty(task: *mut Task) {
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    if (*task).sid == (*task).tgid {
+        sessionDropCttyUnsafe((*task).sid, true);
+    } else {
+        let tgid = (*task).tgid;
+        let mut browse = firstTask;
+        while !browse.is_null() {
+            if taskAlive(browse) && (*browse).tgid == tgid {
+                (*browse).ctrlTty = null_mut();
+            }
+            browse = (*browse).next;
+        }
+    }
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+}
+
+// The terminal went away (pty master closed): everyone in the session loses
+// it, the session leader and the foreground group are told about it
+#[no_mangle]
+pub unsafe extern "C" fn sessionHangup(tty: *mut Tty) {
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+    let sid = (*tty).ctrlSession;
+    if sid > 0 {
+        let leader = taskGetUnsafe(sid as u64);
+        if !leader.is_null() && taskAlive(leader) {
+            taskSignalSendUnsafe(leader, SIGHUP);
+            taskSignalSendUnsafe(leader, SIGCONT);
+        }
+        sessionDropCttyUnsafe(sid, true);
+    }
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+}
+
+//
+// Exit hook
+//
+
+// Called by taskKill() for a thread group leader before it's marked dead
+#[no_mangle]
+pub unsafe extern "C" fn jobctlTaskExit(task: *mut Task) {
+    if (*task).kernel_task || (*task).id != (*task).tgid as u64 {
+        return;
+    }
+
+    spinlockCntWriteAcquire(&mut TASK_LL_MODIFY);
+
+    // a dying session leader hangs up its terminal
+    if (*task).sid == (*task).tgid {
+        sessionDropCttyUnsafe((*task).sid, true);
+    }
+
+    // our own group may lose its link to the rest of the session...
+    let parent = (*task).parent;
+    if !parent.is_null() && (*parent).pgid != (*task).pgid && (*parent).sid == (*task).sid {
+        pgrpOrphanCheckUnsafe((*task).pgid, task);
+    }
+
+    // ...and so may our children's groups, since we were their link
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if taskAlive(browse)
+            && (*browse).parent == task
+            && (*browse).pgid != (*task).pgid
+            && (*browse).sid == (*task).sid
+        {
+            pgrpOrphanCheckUnsafe((*browse).pgid, task);
+        }
+        browse = (*browse).next;
+    }
+
+    spinlockCntWriteRelease(&mut TASK_LL_MODIFY);
+}
@@ -12,6 +12,7 @@ const GDT_KERNEL_CODE: u64 = 0x08;
 
 const TASK_STATE_READY: i32 = 0;
 const TASK_STATE_SIGKILLED: i32 = 5;
+const TASK_STATE_STOPPED: i32 = 11;
 
 const EXTRAS_INVOLUTARY_WAKEUP: u32 = 1 << 3;
 
@@ -44,7 +45,7 @@ extern "C" {
 
     fn signalsRevivableState(state: i32) -> bool;
     fn signalsPendingQuick(task: *mut Task) -> bool;
-    fn signalsPendingHandleSched(task: *mut Task);
+    fn signalsPendingHandleSched(task: *mut Task) -> bool;
 
     fn atomicRead64(ptr: *const u64) -> u64;
     fn atomicWrite64(ptr: *mut u64, val: u64);
@@ -177,14 +178,18 @@ pub unsafe extern "C" fn schedule(rsp: u64) {
     let mut full_run = 0;
 
     while (*next).state != TASK_STATE_READY {
-        if signalsRevivableState((*next).state) && signalsPendingQuick(next) {
+        // stopped tasks only come back through taskContinue() / SIGKILL
+        let stopped = (*next).state == TASK_STATE_STOPPED;
+
+        if !stopped && signalsRevivableState((*next).state) && signalsPendingQuick(next) {
             (*next).extras |= EXTRAS_INVOLUTARY_WAKEUP;
             (*next).forcefulWakeupTimeUnsafe = 0;
             (*next).state = TASK_STATE_READY;
             break;
         }
 
-        if (*next).forcefulWakeupTimeUnsafe != 0
+        if !stopped
+            && (*next).forcefulWakeupTimeUnsafe != 0
             && (*next).forcefulWakeupTimeUnsafe <= timerTicks
         {
             (*next).state = TASK_STATE_READY;
@@ -234,8 +239,8 @@ pub unsafe extern "C" fn schedule(rsp: u64) {
     }
 
     if !(*next).kernel_task && ((*next).registers.cs & GDT_KERNEL_CODE) == 0 {
-        signalsPendingHandleSched(next);
-        if (*next).state == TASK_STATE_SIGKILLED {
+        // killed or stopped by a default action on the way in
+        if !signalsPendingHandleSched(next) || (*next).state == TASK_STATE_SIGKILLED {
             currentTask = old;
             schedule(rsp);
             return;
@@ -11,6 +11,8 @@ const PAGE_SIZE: usize = 4096;
 const USER_STACK_PAGES: usize = 8;
 const USER_STACK_BOTTOM: usize = 0x0000_7fff_ffff_f000;
 
+const AUXV_SAVED_MAX: usize = 48;
+
 const PF_USER: u64 = 1 << 2;
 const PF_RW: u64 = 1 << 1;
 
@@ -94,6 +96,7 @@ pub struct PageInfo {
     pub pagedir: *mut c_void,
     pub heap_start: usize,
     pub heap_end: usize,
+    pub savedAuxv: [u64; AUXV_SAVED_MAX],
     pub LOCK_PD: *mut c_void,
 }
 
@@ -267,6 +270,7 @@ pub unsafe fn stack_generate_user(
     // AUXV (reverse order)
     push_to_stack(rsp, 0usize);
     push_to_stack(rsp, 0usize);
+    let auxv_end = *rsp + 2 * core::mem::size_of::<u64>();
 
     push_to_stack(rsp, random_start as usize);
     push_to_stack(rsp, 25u64);
@@ -301,6 +305,14 @@ pub unsafe fn stack_generate_user(
     );
     push_to_stack(rsp, 3u64);
 
+    // kept around for NT_AUXV in core dumps
+    let auxv_len = core::cmp::min(auxv_end - *rsp, AUXV_SAVED_MAX * 8);
+    memcpy(
+        (*pd).savedAuxv.as_mut_ptr() as *mut c_void,
+        *rsp as *const c_void,
+        auxv_len,
+    );
+
     let args = stack_store_ptr_style(target, argc, argv);
     let envs = if envc > 0 {
         stack_store_ptr_style(target, envc, envv)
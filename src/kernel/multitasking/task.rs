@@ -16,8 +16,6 @@ const TASK_STATE_CREATED: i32 = 1;
 const TASK_STATE_READY: i32 = 2;
 const TASK_STATE_BLOCKED: i32 = 3;
 const TASK_STATE_DUMMY: i32 = 4;
-const TASK_STATE_WAITING_CHILD: i32 = 5;
-const TASK_STATE_WAITING_CHILD_SPECIFIC: i32 = 6;
 const TASK_STATE_WAITING_VFORK: i32 = 7;
 
 const GDT_KERNEL_CODE: u64 = 0x08;
@@ -28,6 +26,8 @@ const DPL_USER: u64 = 3;
 
 const KERNEL_TASK_ID: u64 = 0;
 
+const SIGCHLD: usize = 17;
+
 //
 // Externals
 //
@@ -89,6 +89,9 @@ extern "C" {
 
     fn stackGenerateKernel(task: *mut Task, param: u64);
 
+    fn jobctlTaskExit(task: *mut Task);
+    fn jobctlWakeWaiter(parent: *mut Task, pid: u64);
+
     fn LinkedListInit(list: *mut LinkedList, elem_size: usize);
     fn LinkedListAllocate(list: *mut LinkedList, elem_size: usize) -> *mut c_void;
     fn LinkedListRemove(list: *mut LinkedList, elem_size: usize, obj: *mut c_void);
@@ -160,10 +163,22 @@ pub struct Task {
 
     pub noInformParent: bool,
 
+    pub LOCK_CHILD_TERM: *mut c_void,
     pub dsChildTerminated: LinkedList,
+    pub childrenTerminatedAmnt: i32,
+    pub waitingForPid: u64,
     pub dsSysIntr: LinkedList,
 }
 
+#[repr(C)]
+pub struct KilledInfo {
+    pub _ll: *mut c_void,
+
+    pub pid: u64,
+    pub pgid: i32,
+    pub ret: u16, // wait4() status
+}
+
 #[repr(C)]
 pub struct TaskInfoPagedir {
     pub pagedir: *mut c_void,
@@ -309,7 +324,7 @@ pub unsafe fn task_create(
 // Task kill
 //
 
-pub unsafe fn task_kill(id: u32, _ret: u16) {
+pub unsafe fn task_kill(id: u32, ret: u16) {
     let mut browse = firstTask;
     while !browse.is_null() {
         if (*browse).id == id as u64 {
@@ -322,6 +337,29 @@ pub unsafe fn task_kill(id: u32, _ret: u16) {
         return;
     }
 
+    let leader = (*browse).id == (*browse).tgid;
+    if leader {
+        // hang up the terminal if we lead a session, SIGHUP orphaned groups
+        jobctlTaskExit(browse);
+    }
+
+    let parent = (*browse).parent;
+    if leader && !(*browse).noInformParent && !parent.is_null() {
+        spinlockAcquire((*parent).LOCK_CHILD_TERM);
+        let info = LinkedListAllocate(
+            &mut (*parent).dsChildTerminated,
+            core::mem::size_of::<KilledInfo>(),
+        ) as *mut KilledInfo;
+        (*info).pid = (*browse).id;
+        (*info).pgid = (*browse).pgid as i32;
+        (*info).ret = ret;
+        (*parent).childrenTerminatedAmnt += 1;
+        spinlockRelease((*parent).LOCK_CHILD_TERM);
+
+        atomicBitmapSet(&mut (*parent).sigPendingList, SIGCHLD);
+        jobctlWakeWaiter(parent, (*browse).id);
+    }
+
     taskInfoFilesDiscard((*browse).infoFiles, browse);
     taskInfoPdDiscard((*browse).infoPd);
 
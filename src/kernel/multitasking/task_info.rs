@@ -10,9 +10,15 @@ use core::ptr;
 const USER_HEAP_START: usize = 0x0000_4000_0000;
 const USER_MMAP_START: usize = 0x0000_6000_0000;
 
+pub const AUXV_SAVED_MAX: usize = 48;
+
+const RLIM_INFINITY: usize = !0;
+
 const S_IWGRP: u32 = 0o020;
 const S_IWOTH: u32 = 0o002;
 
+const TASK_STATE_DEAD: u8 = 0;
+
 //
 // Externals
 //
@@ -24,12 +30,23 @@ extern "C" {
 
     fn memcpy(dst: *mut c_void, src: *const c_void, size: usize);
     fn strlength(s: *const u8) -> usize;
+    fn strdup(s: *const u8) -> *mut u8;
+
+    fn LinkedListInit(ll: *mut LLcontrol, structSize: u32);
+    fn LinkedListAllocate(ll: *mut LLcontrol, structSize: u32) -> *mut c_void;
+    fn LinkedListRemove(ll: *mut LLcontrol, structSize: u32, LLtarget: *mut c_void) -> bool;
+    fn LinkedListDestroy(ll: *mut LLcontrol, structSize: u32);
 
     fn spinlockAcquire(lock: *mut c_void);
     fn spinlockRelease(lock: *mut c_void);
 
     fn spinlockCntWriteAcquire(lock: *mut SpinlockCnt);
     fn spinlockCntWriteRelease(lock: *mut SpinlockCnt);
+    fn spinlockCntReadAcquire(lock: *mut SpinlockCnt);
+    fn spinlockCntReadRelease(lock: *mut SpinlockCnt);
+
+    static mut firstTask: *mut Task;
+    static mut TASK_LL_MODIFY: SpinlockCnt;
 
     fn PageDirectoryAllocate() -> *mut c_void;
     fn PageDirectoryUserDuplicate(src: *mut c_void, dst: *mut c_void);
@@ -70,9 +87,43 @@ pub struct TaskInfoPagedir {
     pub mmap_start: usize,
     pub mmap_end: usize,
 
+    // copy of the auxiliary vector handed to the executable (for core dumps)
+    pub savedAuxv: [u64; AUXV_SAVED_MAX],
+    // file backed regions, struct FileMapping
+    pub dsFileMapping: LLcontrol,
+
     pub LOCK_PD: *mut c_void,
 }
 
+#[repr(C)]
+pub struct LLheader {
+    pub next: *mut LLheader,
+}
+
+#[repr(C)]
+pub struct LLcontrol {
+    pub signature1: u64,
+    pub signature2: u64,
+    pub structSize: u32,
+    pub LOCK_LL: u32,
+    pub firstObject: *mut LLheader,
+}
+
+#[repr(C)]
+pub struct FileMapping {
+    pub _ll: LLheader,
+
+    pub start: usize,
+    pub end: usize,
+    pub offset: usize,
+    pub path: *mut u8, // null until syscall_mmap() names it
+
+    // whatever the mapping keeps alive (tmpfs pages), told whenever a
+    // record of it comes (take) or goes
+    pub hold: Option<unsafe extern "C" fn(ctx: *mut c_void, take: bool)>,
+    pub holdCtx: *mut c_void,
+}
+
 #[repr(C)]
 pub struct TaskInfoFiles {
     pub utilizedBy: u32,
@@ -88,6 +139,8 @@ pub struct TaskInfoFiles {
 pub struct TaskInfoSignal {
     pub utilizedBy: u32,
     pub signals: [u64; 64],
+    pub rlimitCoreSoft: usize,
+    pub rlimitCoreHard: usize,
     pub LOCK_SIGNAL: *mut c_void,
 }
 
@@ -97,6 +150,15 @@ pub struct FileNode {
     pub next: *mut FileNode,
 }
 
+// Tasks (partial)
+#[repr(C)]
+pub struct Task {
+    pub id: u64,
+    pub state: u8,
+    pub infoFs: *mut TaskInfoFs,
+    pub next: *mut Task,
+}
+
 //
 // CLONE_FS
 //
@@ -126,6 +188,33 @@ pub unsafe extern "C" fn taskInfoFsDiscard(target: *mut TaskInfoFs) {
     }
 }
 
+// Whether some task's working directory is `path` (no trailing slash) or
+// below it, umount() refuses to pull a mount from under those
+#[no_mangle]
+pub unsafe extern "C" fn taskInfoFsCwdWithin(path: *const u8, len: usize) -> bool {
+    let mut found = false;
+
+    spinlockCntReadAcquire(&mut TASK_LL_MODIFY);
+    let mut browse = firstTask;
+    while !browse.is_null() && !found {
+        let fs = (*browse).infoFs;
+        if (*browse).state != TASK_STATE_DEAD && !fs.is_null() {
+            spinlockAcquire((*fs).LOCK_FS);
+            let cwd = (*fs).cwd;
+            let mut i = 0;
+            while i < len && *cwd.add(i) == *path.add(i) {
+                i += 1;
+            }
+            found = i == len && (*cwd.add(len) == 0 || *cwd.add(len) == b'/');
+            spinlockRelease((*fs).LOCK_FS);
+        }
+        browse = (*browse).next;
+    }
+    spinlockCntReadRelease(&mut TASK_LL_MODIFY);
+
+    found
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn taskInfoFsClone(old: *mut TaskInfoFs) -> *mut TaskInfoFs {
     let new = taskInfoFsAllocate();
@@ -165,9 +254,136 @@ pub unsafe extern "C" fn taskInfoPdAllocate(pagedir: bool) -> *mut TaskInfoPaged
     (*target).mmap_start = USER_MMAP_START;
     (*target).mmap_end = USER_MMAP_START;
 
+    LinkedListInit(
+        &mut (*target).dsFileMapping,
+        core::mem::size_of::<FileMapping>() as u32,
+    );
+
     target
 }
 
+unsafe fn taskInfoPdMappingInsert(
+    target: *mut TaskInfoPagedir,
+    start: usize,
+    end: usize,
+    offset: usize,
+    path: *const u8,
+    hold: Option<unsafe extern "C" fn(ctx: *mut c_void, take: bool)>,
+    holdCtx: *mut c_void,
+) {
+    let mapping = LinkedListAllocate(
+        &mut (*target).dsFileMapping,
+        core::mem::size_of::<FileMapping>() as u32,
+    ) as *mut FileMapping;
+    (*mapping).start = start;
+    (*mapping).end = end;
+    (*mapping).offset = offset;
+    (*mapping).path = if path.is_null() { ptr::null_mut() } else { strdup(path) };
+    (*mapping).hold = hold;
+    (*mapping).holdCtx = holdCtx;
+}
+
+// Drops the record along with whatever it was holding on to
+unsafe fn taskInfoPdMappingDrop(target: *mut TaskInfoPagedir, mapping: *mut FileMapping) {
+    if let Some(hold) = (*mapping).hold {
+        hold((*mapping).holdCtx, false);
+    }
+    free((*mapping).path as *mut c_void);
+    LinkedListRemove(
+        &mut (*target).dsFileMapping,
+        core::mem::size_of::<FileMapping>() as u32,
+        mapping as *mut c_void,
+    );
+}
+
+// Remembers where a file got mapped, for the NT_FILE note of core dumps. A
+// filesystem that already registered the exact range just gets it named.
+#[no_mangle]
+pub unsafe extern "C" fn taskInfoPdMappingAdd(
+    target: *mut TaskInfoPagedir,
+    start: usize,
+    end: usize,
+    offset: usize,
+    path: *const u8,
+) {
+    let mut browse = (*target).dsFileMapping.firstObject as *mut FileMapping;
+    while !browse.is_null() {
+        if (*browse).start == start && (*browse).end == end && (*browse).path.is_null() {
+            (*browse).path = strdup(path);
+            return;
+        }
+        browse = (*browse)._ll.next as *mut FileMapping;
+    }
+
+    taskInfoPdMappingInsert(target, start, end, offset, path, None, ptr::null_mut());
+}
+
+// For filesystems that need to know when the pages they handed out stop being
+// mapped. Takes over one reference the caller already got through `hold`.
+#[no_mangle]
+pub unsafe extern "C" fn taskInfoPdMappingAddHeld(
+    target: *mut TaskInfoPagedir,
+    start: usize,
+    end: usize,
+    offset: usize,
+    hold: unsafe extern "C" fn(ctx: *mut c_void, take: bool),
+    holdCtx: *mut c_void,
+) {
+    taskInfoPdMappingInsert(target, start, end, offset, ptr::null(), Some(hold), holdCtx);
+}
+
+// munmap(): forgets [start, end) of whatever file mappings it overlaps,
+// splitting the ones it punches a hole into
+#[no_mangle]
+pub unsafe extern "C" fn taskInfoPdMappingRemove(target: *mut TaskInfoPagedir, start: usize, end: usize) {
+    let mut browse = (*target).dsFileMapping.firstObject as *mut FileMapping;
+    while !browse.is_null() {
+        let mapping = browse;
+        browse = (*browse)._ll.next as *mut FileMapping;
+        if (*mapping).end <= start || (*mapping).start >= end {
+            continue;
+        }
+
+        if (*mapping).start < start && (*mapping).end > end {
+            if let Some(hold) = (*mapping).hold {
+                hold((*mapping).holdCtx, true);
+            }
+            taskInfoPdMappingInsert(
+                target,
+                end,
+                (*mapping).end,
+                (*mapping).offset + (end - (*mapping).start),
+                (*mapping).path,
+                (*mapping).hold,
+                (*mapping).holdCtx,
+            );
+            (*mapping).end = start;
+        } else if (*mapping).start < start {
+            (*mapping).end = start;
+        } else if (*mapping).end > end {
+            (*mapping).offset += end - (*mapping).start;
+            (*mapping).start = end;
+        } else {
+            taskInfoPdMappingDrop(target, mapping);
+        }
+    }
+}
+
+unsafe fn taskInfoPdMappingsFree(target: *mut TaskInfoPagedir) {
+    let mut browse = (*target).dsFileMapping.firstObject as *mut FileMapping;
+    while !browse.is_null() {
+        if let Some(hold) = (*browse).hold {
+            hold((*browse).holdCtx, false);
+        }
+        free((*browse).path as *mut c_void);
+        browse = (*browse)._ll.next as *mut FileMapping;
+    }
+    LinkedListDestroy(
+        &mut (*target).dsFileMapping,
+        core::mem::size_of::<FileMapping>() as u32,
+    );
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn taskInfoPdClone(old: *mut TaskInfoPagedir) -> *mut TaskInfoPagedir {
     let new = taskInfoPdAllocate(true);
@@ -182,6 +398,24 @@ pub unsafe extern "C" fn taskInfoPdClone(old: *mut TaskInfoPagedir) -> *mut Task
     (*new).mmap_start = (*old).mmap_start;
     (*new).mmap_end = (*old).mmap_end;
 
+    (*new).savedAuxv = (*old).savedAuxv;
+    let mut browse = (*old).dsFileMapping.firstObject as *mut FileMapping;
+    while !browse.is_null() {
+        if let Some(hold) = (*browse).hold {
+            hold((*browse).holdCtx, true);
+        }
+        taskInfoPdMappingInsert(
+            new,
+            (*browse).start,
+            (*browse).end,
+            (*browse).offset,
+            (*browse).path,
+            (*browse).hold,
+            (*browse).holdCtx,
+        );
+        browse = (*browse)._ll.next as *mut FileMapping;
+    }
+
     spinlockRelease((*old).LOCK_PD);
     new
 }
@@ -193,6 +427,7 @@ pub unsafe extern "C" fn taskInfoPdDiscard(target: *mut TaskInfoPagedir) {
 
     if (*target).utilizedBy == 0 {
         PageDirectoryFree((*target).pagedir);
+        taskInfoPdMappingsFree(target);
         // intentionally leaked (scheduler safety)
     } else {
         spinlockRelease((*target).LOCK_PD);
@@ -247,6 +482,10 @@ pub unsafe extern "C" fn taskInfoSignalAllocate() -> *mut TaskInfoSignal {
     let target =
         calloc(1, core::mem::size_of::<TaskInfoSignal>()) as *mut TaskInfoSignal;
     (*target).utilizedBy = 1;
+
+    // nothing sets up limits before the first process, so dumps are on
+    (*target).rlimitCoreSoft = RLIM_INFINITY;
+    (*target).rlimitCoreHard = RLIM_INFINITY;
     target
 }
 
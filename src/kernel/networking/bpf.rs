@@ -0,0 +1,292 @@
+use std::io::{Error, Result};
+use std::ptr;
+
+//
+// Classic BPF (SO_ATTACH_FILTER), checked once at attach time and then
+// interpreted per packet. Linux semantics: out-of-bounds loads drop the
+// packet, the return value is how many bytes of it to keep.
+//
+
+const BPF_MAXINSNS: usize = 4096;
+const BPF_MEMWORDS: usize = 16;
+
+// classes
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+
+// ld/ldx sizes & modes
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+
+// alu/jmp operations & sources
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_NEG: u16 = 0x80;
+const BPF_MOD: u16 = 0x90;
+const BPF_XOR: u16 = 0xa0;
+
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+// ancillary data, loaded through negative absolute offsets
+const SKF_AD_OFF: i32 = -0x1000;
+const SKF_AD_PROTOCOL: i32 = 0;
+const SKF_AD_PKTTYPE: i32 = 4;
+const SKF_AD_IFINDEX: i32 = 8;
+const SKF_AD_MAX: i32 = 64;
+const SKF_NET_OFF: i32 = -0x100000;
+const SKF_LL_OFF: i32 = -0x200000;
+
+/// struct sock_filter
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct SockFilter {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+/// struct sock_fprog
+#[repr(C)]
+pub struct SockFprog {
+    pub len: u16,
+    pub filter: *const SockFilter,
+}
+
+/// What the filter gets to see besides the bytes themselves
+pub struct BpfPacket<'a> {
+    pub data: &'a [u8],
+    // where the link and network headers start within `data`, if they're
+    // in there at all (SOCK_DGRAM doesn't see the link header)
+    pub link_offset: Option<usize>,
+    pub net_offset: usize,
+    pub protocol: u16,
+    pub pkttype: u8,
+    pub ifindex: u32,
+}
+
+fn class(code: u16) -> u16 {
+    code & 0x07
+}
+
+/// Copies a program in from userspace and checks it the way sk_chk_filter()
+/// does: known opcodes only, jumps forward & in range, no constant division
+/// by zero, scratch memory in bounds and a RET at the end
+pub fn bpf_load(fprog: *const SockFprog) -> Result<Vec<SockFilter>> {
+    if fprog.is_null() {
+        return Err(Error::from_raw_os_error(libc::EFAULT));
+    }
+    let fprog = unsafe { ptr::read_unaligned(fprog) };
+    let len = fprog.len as usize;
+    if len == 0 || len > BPF_MAXINSNS || fprog.filter.is_null() {
+        return Err(Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    let program: Vec<SockFilter> = (0..len)
+        .map(|i| unsafe { ptr::read_unaligned(fprog.filter.add(i)) })
+        .collect();
+
+    for (pc, insn) in program.iter().enumerate() {
+        let valid = match class(insn.code) {
+            BPF_LD | BPF_LDX => {
+                let size = insn.code & 0x18;
+                let mode = insn.code & 0xe0;
+                match mode {
+                    BPF_IMM | BPF_LEN => size == BPF_W,
+                    BPF_MEM => size == BPF_W && (insn.k as usize) < BPF_MEMWORDS,
+                    BPF_ABS | BPF_IND => class(insn.code) == BPF_LD && size != 0x18,
+                    BPF_MSH => class(insn.code) == BPF_LDX && size == BPF_B,
+                    _ => false,
+                }
+            }
+            BPF_ST | BPF_STX => insn.code & !0x07 == 0 && (insn.k as usize) < BPF_MEMWORDS,
+            BPF_ALU => {
+                let op = insn.code & 0xf0;
+                let constant_zero = insn.code & BPF_X == 0 && insn.k == 0;
+                insn.code & 0x07 == BPF_ALU
+                    && op <= BPF_XOR
+                    && !((op == BPF_DIV || op == BPF_MOD) && constant_zero)
+            }
+            BPF_JMP => {
+                let op = insn.code & 0xf0;
+                let left = len - pc - 1;
+                if op == BPF_JA {
+                    (insn.k as usize) < left
+                } else {
+                    op <= BPF_JSET && (insn.jt as usize) < left && (insn.jf as usize) < left
+                }
+            }
+            BPF_RET => matches!(insn.code & 0x18, BPF_K | BPF_X | BPF_A),
+            BPF_MISC => matches!(insn.code & 0xf8, BPF_TAX | BPF_TXA),
+            _ => false,
+        };
+        if !valid {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+    }
+
+    if class(program[len - 1].code) != BPF_RET {
+        return Err(Error::from_raw_os_error(libc::EINVAL));
+    }
+    Ok(program)
+}
+
+fn load(packet: &BpfPacket, offset: i64, size: usize) -> Option<u32> {
+    let offset = if offset >= 0 {
+        offset as usize
+    } else if offset >= SKF_NET_OFF as i64 && offset < SKF_AD_OFF as i64 {
+        packet.net_offset + (offset - SKF_NET_OFF as i64) as usize
+    } else if offset >= SKF_LL_OFF as i64 && offset < SKF_NET_OFF as i64 {
+        packet.link_offset? + (offset - SKF_LL_OFF as i64) as usize
+    } else {
+        return None;
+    };
+
+    let bytes = packet.data.get(offset..offset.checked_add(size)?)?;
+    Some(bytes.iter().fold(0u32, |value, byte| (value << 8) | *byte as u32))
+}
+
+fn ancillary(packet: &BpfPacket, k: i32) -> Option<u32> {
+    match k - SKF_AD_OFF {
+        SKF_AD_PROTOCOL => Some(packet.protocol as u32),
+        SKF_AD_PKTTYPE => Some(packet.pkttype as u32),
+        SKF_AD_IFINDEX => Some(packet.ifindex),
+        _ => None,
+    }
+}
+
+/// Runs a program checked by bpf_load(), 0 meaning drop
+pub fn bpf_run(program: &[SockFilter], packet: &BpfPacket) -> u32 {
+    let mut a: u32 = 0;
+    let mut x: u32 = 0;
+    let mut mem = [0u32; BPF_MEMWORDS];
+    let mut pc = 0;
+
+    while pc < program.len() {
+        let insn = program[pc];
+        pc += 1;
+
+        let size = match insn.code & 0x18 {
+            BPF_H => 2,
+            BPF_B => 1,
+            _ => 4,
+        };
+
+        match class(insn.code) {
+            BPF_LD => {
+                a = match insn.code & 0xe0 {
+                    BPF_IMM => insn.k,
+                    BPF_LEN => packet.data.len() as u32,
+                    BPF_MEM => mem[insn.k as usize],
+                    BPF_ABS => {
+                        let k = insn.k as i32;
+                        let value = if (SKF_AD_OFF..SKF_AD_OFF + SKF_AD_MAX).contains(&k) {
+                            ancillary(packet, k)
+                        } else {
+                            load(packet, k as i64, size)
+                        };
+                        match value {
+                            Some(value) => value,
+                            None => return 0,
+                        }
+                    }
+                    _ => match load(packet, x as i64 + insn.k as i32 as i64, size) {
+                        Some(value) => value,
+                        None => return 0,
+                    },
+                };
+            }
+            BPF_LDX => {
+                x = match insn.code & 0xe0 {
+                    BPF_IMM => insn.k,
+                    BPF_LEN => packet.data.len() as u32,
+                    BPF_MEM => mem[insn.k as usize],
+                    // 4 * (P[k] & 0xf), the IPv4 header length
+                    _ => match load(packet, insn.k as i64, 1) {
+                        Some(value) => (value & 0xf) << 2,
+                        None => return 0,
+                    },
+                };
+            }
+            BPF_ST => mem[insn.k as usize] = a,
+            BPF_STX => mem[insn.k as usize] = x,
+            BPF_ALU => {
+                let operand = if insn.code & BPF_X != 0 { x } else { insn.k };
+                a = match insn.code & 0xf0 {
+                    BPF_ADD => a.wrapping_add(operand),
+                    BPF_SUB => a.wrapping_sub(operand),
+                    BPF_MUL => a.wrapping_mul(operand),
+                    BPF_DIV | BPF_MOD if operand == 0 => return 0,
+                    BPF_DIV => a / operand,
+                    BPF_MOD => a % operand,
+                    BPF_OR => a | operand,
+                    BPF_AND => a & operand,
+                    BPF_LSH => a.checked_shl(operand).unwrap_or(0),
+                    BPF_RSH => a.checked_shr(operand).unwrap_or(0),
+                    BPF_NEG => a.wrapping_neg(),
+                    _ => a ^ operand,
+                };
+            }
+            BPF_JMP => {
+                let operand = if insn.code & BPF_X != 0 { x } else { insn.k };
+                let taken = match insn.code & 0xf0 {
+                    BPF_JA => {
+                        pc += insn.k as usize;
+                        continue;
+                    }
+                    BPF_JEQ => a == operand,
+                    BPF_JGT => a > operand,
+                    BPF_JGE => a >= operand,
+                    _ => a & operand != 0,
+                };
+                pc += if taken { insn.jt as usize } else { insn.jf as usize };
+            }
+            BPF_RET => {
+                return match insn.code & 0x18 {
+                    BPF_X => x,
+                    BPF_A => a,
+                    _ => insn.k,
+                };
+            }
+            _ => {
+                if insn.code & 0xf8 == BPF_TXA {
+                    a = x;
+                } else {
+                    x = a;
+                }
+            }
+        }
+    }
+
+    0
+}
@@ -0,0 +1,708 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{Error, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ffi::CStr;
+use std::ptr;
+use std::sync::Mutex;
+
+use crate::timer::timer_ticks;
+
+//
+// The kernel's stub resolver. Userspace (musl) talks DNS on its own, this is
+// for whatever inside the kernel needs a name turned into an address: mounts,
+// the testing harness and lwIP's netconn_gethostbyname()/lwip_getaddrinfo(),
+// which get here through LWIP_HOOK_NETCONN_EXTERNAL_RESOLVE instead of dns.c.
+//
+// /etc/hosts and /etc/resolv.conf are read on every lookup so editing them
+// takes effect right away. Answers (and NXDOMAIN/NODATA, for as long as the
+// zone's SOA allows) are cached per (name, type) until their TTL runs out.
+//
+
+const AF_UNSPEC: c_int = 0;
+const AF_INET: c_int = 2;
+const AF_INET6: c_int = 10;
+const SOCK_DGRAM: c_int = 2;
+
+// lwIP's own fcntl values, not Linux'
+const F_SETFL: c_int = 4;
+const O_NONBLOCK: c_int = 1;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_NXDOMAIN: u16 = 3;
+
+const DNS_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+const MAX_MESSAGE: usize = 512;
+const MAX_NAME: usize = 253;
+const MAX_CNAME_HOPS: usize = 8;
+
+const MAXNS: usize = 3;
+const MAX_SEARCH: usize = 6;
+const MAX_CACHE: usize = 256;
+// no SOA to go by
+const NEGATIVE_TTL: u32 = 60;
+const MAX_TTL: u32 = 86400;
+
+extern "C" {
+    fn fsKernelOpen(path: *const u8, flags: u32, mode: u32) -> *mut c_void;
+    fn fsGetFilesize(file: *mut c_void) -> u32;
+    fn fsRead(file: *mut c_void, buf: *mut u8, len: u32);
+    fn fsKernelClose(file: *mut c_void);
+
+    fn lwip_socket(domain: c_int, ty: c_int, protocol: c_int) -> c_int;
+    fn lwip_fcntl(fd: c_int, cmd: c_int, val: c_int) -> c_int;
+    fn lwip_close(fd: c_int) -> c_int;
+    fn lwip_sendto(fd: c_int, buf: *const u8, len: usize, flags: c_int, addr: *const u8, addrlen: c_uint) -> c_int;
+    fn lwip_recvfrom(fd: c_int, buf: *mut u8, len: usize, flags: c_int, addr: *mut u8, addrlen: *mut c_uint) -> c_int;
+
+    fn rand() -> u64;
+    fn handControl();
+}
+
+fn errno(code: c_int) -> Error {
+    Error::from_raw_os_error(code)
+}
+
+// ==========================
+// Configuration
+// ==========================
+
+fn read_file(path: &str) -> Option<String> {
+    let path = format!("{}\0", path);
+    unsafe {
+        let file = fsKernelOpen(path.as_ptr(), 0, 0);
+        if file.is_null() {
+            return None;
+        }
+        let mut content = vec![0u8; fsGetFilesize(file) as usize];
+        fsRead(file, content.as_mut_ptr(), content.len() as u32);
+        fsKernelClose(file);
+        Some(String::from_utf8_lossy(&content).into_owned())
+    }
+}
+
+/// Drops a zone id ("fe80::1%eth0"), there's nothing to bind it to
+fn parse_addr(text: &str) -> Option<IpAddr> {
+    text.split('%').next()?.parse().ok()
+}
+
+struct ResolvConf {
+    nameservers: Vec<IpAddr>,
+    search: Vec<String>,
+    // per query & nameserver, in ticks
+    timeout: u64,
+    attempts: u32,
+    ndots: usize,
+}
+
+impl ResolvConf {
+    /// resolv.conf(5): nameserver, search/domain (the last one wins) and
+    /// options timeout:/attempts:/ndots:, defaults as in glibc & musl
+    fn load() -> ResolvConf {
+        let mut conf = ResolvConf {
+            nameservers: Vec::new(),
+            search: Vec::new(),
+            timeout: 5000,
+            attempts: 2,
+            ndots: 1,
+        };
+
+        for line in read_file("/etc/resolv.conf").unwrap_or_default().lines() {
+            let line = line.split(|c| c == '#' || c == ';').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => {
+                    if let Some(addr) = words.next().and_then(parse_addr) {
+                        if conf.nameservers.len() < MAXNS {
+                            conf.nameservers.push(addr);
+                        }
+                    }
+                }
+                Some("search") | Some("domain") => {
+                    conf.search = words
+                        .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+                        .filter(|domain| !domain.is_empty())
+                        .take(MAX_SEARCH)
+                        .collect();
+                }
+                Some("options") => {
+                    for option in words {
+                        let Some((key, value)) = option.split_once(':') else {
+                            continue;
+                        };
+                        let Ok(value) = value.parse::<u32>() else {
+                            continue;
+                        };
+                        match key {
+                            "timeout" => conf.timeout = value.clamp(1, 30) as u64 * 1000,
+                            "attempts" => conf.attempts = value.clamp(1, 5),
+                            "ndots" => conf.ndots = value.min(15) as usize,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if conf.nameservers.is_empty() {
+            conf.nameservers.push(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        conf
+    }
+
+    /// The names to try in order: as given first when it has at least
+    /// `ndots` dots, through the search list otherwise. A trailing dot
+    /// means fully qualified.
+    fn candidates(&self, name: &str) -> Vec<String> {
+        if let Some(absolute) = name.strip_suffix('.') {
+            return vec![absolute.to_string()];
+        }
+
+        let dots = name.matches('.').count();
+        let mut candidates = Vec::new();
+        if dots >= self.ndots {
+            candidates.push(name.to_string());
+        }
+        for domain in &self.search {
+            candidates.push(format!("{}.{}", name, domain));
+        }
+        if dots < self.ndots {
+            candidates.push(name.to_string());
+        }
+        candidates
+    }
+}
+
+/// hosts(5): an address followed by its canonical name and any aliases
+fn hosts_lookup(name: &str, family: c_int) -> Vec<IpAddr> {
+    let name = name.trim_end_matches('.');
+    let mut addrs = Vec::new();
+    for line in read_file("/etc/hosts").unwrap_or_default().lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        let Some(addr) = words.next().and_then(parse_addr) else {
+            continue;
+        };
+        if !family_matches(&addr, family) || addrs.contains(&addr) {
+            continue;
+        }
+        if words.any(|host| host.trim_end_matches('.').eq_ignore_ascii_case(name)) {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
+
+fn family_matches(addr: &IpAddr, family: c_int) -> bool {
+    match family {
+        AF_INET => addr.is_ipv4(),
+        AF_INET6 => addr.is_ipv6(),
+        _ => true,
+    }
+}
+
+// ==========================
+// Cache
+// ==========================
+
+#[derive(Clone)]
+enum Answer {
+    Addrs(Vec<IpAddr>),
+    // the name exists, just not with this type
+    NoData,
+    NxDomain,
+}
+
+struct CacheEntry {
+    answer: Answer,
+    expires: u64,
+}
+
+static CACHE: Mutex<BTreeMap<(String, u16), CacheEntry>> = Mutex::new(BTreeMap::new());
+
+fn cache_get(name: &str, qtype: u16) -> Option<Answer> {
+    let mut cache = CACHE.lock().unwrap();
+    let key = (name.to_string(), qtype);
+    let entry = cache.get(&key)?;
+    if entry.expires <= timer_ticks() {
+        cache.remove(&key);
+        return None;
+    }
+    Some(entry.answer.clone())
+}
+
+fn cache_put(name: &str, qtype: u16, answer: &Answer, ttl: u32) {
+    if ttl == 0 {
+        return;
+    }
+
+    let now = timer_ticks();
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHE {
+        cache.retain(|_, entry| entry.expires > now);
+    }
+    if cache.len() >= MAX_CACHE {
+        let oldest = cache.iter().min_by_key(|(_, entry)| entry.expires).map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            cache.remove(&oldest);
+        }
+    }
+    cache.insert(
+        (name.to_string(), qtype),
+        CacheEntry { answer: answer.clone(), expires: now + ttl.min(MAX_TTL) as u64 * 1000 },
+    );
+}
+
+pub fn dns_cache_flush() {
+    CACHE.lock().unwrap().clear();
+}
+
+fn type_name(qtype: u16) -> &'static str {
+    match qtype {
+        TYPE_A => "A",
+        TYPE_AAAA => "AAAA",
+        _ => "?",
+    }
+}
+
+/// /proc/net/dns_cache: one line per cached name & type with the seconds
+/// it has left (writing to it calls dns_cache_flush())
+pub fn proc_net_dns_cache() -> String {
+    let now = timer_ticks();
+    let mut out = String::from("Name\tType\tTTL\tData\n");
+    for ((name, qtype), entry) in CACHE.lock().unwrap().iter() {
+        if entry.expires <= now {
+            continue;
+        }
+        let data = match &entry.answer {
+            Answer::Addrs(addrs) => addrs.iter().map(|addr| addr.to_string()).collect::<Vec<_>>().join(" "),
+            Answer::NoData => "NODATA".to_string(),
+            Answer::NxDomain => "NXDOMAIN".to_string(),
+        };
+        let _ = writeln!(out, "{}\t{}\t{}\t{}", name, type_name(*qtype), (entry.expires - now).div_ceil(1000), data);
+    }
+    out
+}
+
+// ==========================
+// Wire format
+// ==========================
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    if name.is_empty() || name.len() > MAX_NAME {
+        return Err(errno(libc::EINVAL));
+    }
+
+    let mut query = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&0x0100u16.to_be_bytes()); // RD
+    query.extend_from_slice(&1u16.to_be_bytes());
+    query.extend_from_slice(&[0; 6]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(errno(libc::EINVAL));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn be16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn be32(msg: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(msg.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+/// A possibly compressed name at `pos`, lowercased, and where the record
+/// goes on after it
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // every pointer has to go backwards, that bounds the loop
+    let mut limit = pos;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => break,
+            0x00 => {
+                let label = msg.get(pos + 1..pos + 1 + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+                if name.len() > MAX_NAME {
+                    return None;
+                }
+                pos += 1 + len;
+            }
+            0xc0 => {
+                let target = (be16(msg, pos)? & 0x3fff) as usize;
+                if target >= limit {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                limit = target;
+                pos = target;
+            }
+            _ => return None,
+        }
+    }
+    Some((name, end.unwrap_or(pos + 1)))
+}
+
+struct Record<'a> {
+    name: String,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    // where rdata starts within the message, for names pointing back
+    rdata_pos: usize,
+    rdata: &'a [u8],
+}
+
+fn read_record(msg: &[u8], pos: usize) -> Option<(Record<'_>, usize)> {
+    let (name, pos) = read_name(msg, pos)?;
+    let rtype = be16(msg, pos)?;
+    let class = be16(msg, pos + 2)?;
+    // a TTL with the top bit set counts as 0 (RFC 2181)
+    let ttl = be32(msg, pos + 4)?;
+    let ttl = if ttl & 0x8000_0000 != 0 { 0 } else { ttl };
+    let len = be16(msg, pos + 8)? as usize;
+    let rdata = msg.get(pos + 10..pos + 10 + len)?;
+    Some((Record { name, rtype, class, ttl, rdata_pos: pos + 10, rdata }, pos + 10 + len))
+}
+
+/// The answer to `name`/`qtype` and how long it may be cached, Err(EAGAIN)
+/// when the server failed us (SERVFAIL, REFUSED & co) and None when the
+/// message doesn't fit the question. CNAME chains are followed as far as
+/// the answer section goes; a truncated reply is used for whatever made it
+/// in.
+fn parse_response(msg: &[u8], id: u16, name: &str, qtype: u16) -> Option<Result<(Answer, u32)>> {
+    let flags = be16(msg, 2)?;
+    if be16(msg, 0)? != id || flags & 0x8000 == 0 || be16(msg, 4)? != 1 {
+        return None;
+    }
+    let (qname, pos) = read_name(msg, HEADER_LEN)?;
+    if qname != name || be16(msg, pos)? != qtype || be16(msg, pos + 2)? != CLASS_IN {
+        return None;
+    }
+    let mut pos = pos + 4;
+
+    let rcode = flags & 0x000f;
+    if rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN {
+        return Some(Err(errno(libc::EAGAIN)));
+    }
+
+    let mut answers = Vec::new();
+    for _ in 0..be16(msg, 6)? {
+        let Some((record, next)) = read_record(msg, pos) else {
+            break;
+        };
+        answers.push(record);
+        pos = next;
+    }
+    let mut authority = Vec::new();
+    for _ in 0..be16(msg, 8)? {
+        let Some((record, next)) = read_record(msg, pos) else {
+            break;
+        };
+        authority.push(record);
+        pos = next;
+    }
+
+    let mut target = name.to_string();
+    let mut ttl = u32::MAX;
+    for _ in 0..MAX_CNAME_HOPS {
+        let cname = answers
+            .iter()
+            .find(|r| r.class == CLASS_IN && r.rtype == TYPE_CNAME && r.name == target);
+        let Some(cname) = cname else {
+            break;
+        };
+        target = read_name(msg, cname.rdata_pos)?.0;
+        ttl = ttl.min(cname.ttl);
+    }
+
+    let mut addrs = Vec::new();
+    for record in answers.iter().filter(|r| r.class == CLASS_IN && r.rtype == qtype && r.name == target) {
+        let addr = match (qtype, record.rdata.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(record.rdata).ok()?)),
+            (TYPE_AAAA, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(record.rdata).ok()?)),
+            _ => continue,
+        };
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+        ttl = ttl.min(record.ttl);
+    }
+    if !addrs.is_empty() {
+        return Some(Ok((Answer::Addrs(addrs), ttl)));
+    }
+
+    // negative answers live as long as the SOA's TTL and MINIMUM allow
+    // (RFC 2308)
+    let negative_ttl = authority
+        .iter()
+        .find(|r| r.rtype == TYPE_SOA)
+        .and_then(|soa| {
+            let (_, pos) = read_name(msg, soa.rdata_pos)?;
+            let (_, pos) = read_name(msg, pos)?;
+            Some(soa.ttl.min(be32(msg, pos + 16)?))
+        })
+        .unwrap_or(NEGATIVE_TTL);
+    if rcode == RCODE_NXDOMAIN {
+        Some(Ok((Answer::NxDomain, negative_ttl)))
+    } else {
+        Some(Ok((Answer::NoData, negative_ttl.min(ttl))))
+    }
+}
+
+// ==========================
+// Queries
+// ==========================
+
+/// sockaddr_in/sockaddr_in6 the way lwIP lays them out (a length byte
+/// before the family)
+fn lwip_sockaddr(addr: &IpAddr, port: u16) -> ([u8; 28], c_uint) {
+    let mut raw = [0u8; 28];
+    raw[2..4].copy_from_slice(&port.to_be_bytes());
+    match addr {
+        IpAddr::V4(v4) => {
+            raw[0] = 16;
+            raw[1] = AF_INET as u8;
+            raw[4..8].copy_from_slice(&v4.octets());
+            (raw, 16)
+        }
+        IpAddr::V6(v6) => {
+            raw[0] = 28;
+            raw[1] = AF_INET6 as u8;
+            raw[8..24].copy_from_slice(&v6.octets());
+            (raw, 28)
+        }
+    }
+}
+
+/// Family, port and address; flow info & scope id don't matter
+fn same_peer(a: &[u8; 28], b: &[u8; 28]) -> bool {
+    let addr = if b[1] == AF_INET as u8 { 4..8 } else { 8..24 };
+    a[1..4] == b[1..4] && a[addr.clone()] == b[addr]
+}
+
+struct UdpSocket(c_int);
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        unsafe { lwip_close(self.0) };
+    }
+}
+
+/// One query to one nameserver. Err(ETIMEDOUT) when nothing usable came
+/// back in time, Err(EAGAIN) on SERVFAIL, REFUSED & co.
+fn exchange(nameserver: &IpAddr, name: &str, qtype: u16, timeout: u64) -> Result<(Answer, u32)> {
+    let id = unsafe { rand() } as u16;
+    let query = build_query(id, name, qtype)?;
+    let family = if nameserver.is_ipv4() { AF_INET } else { AF_INET6 };
+
+    let fd = unsafe { lwip_socket(family, SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    let socket = UdpSocket(fd);
+    unsafe { lwip_fcntl(socket.0, F_SETFL, O_NONBLOCK) };
+
+    let (to, to_len) = lwip_sockaddr(nameserver, DNS_PORT);
+    if unsafe { lwip_sendto(socket.0, query.as_ptr(), query.len(), 0, to.as_ptr(), to_len) } < 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let deadline = timer_ticks() + timeout;
+    let mut msg = [0u8; MAX_MESSAGE];
+    while timer_ticks() < deadline {
+        let mut from = [0u8; 28];
+        let mut from_len = from.len() as c_uint;
+        let len = unsafe { lwip_recvfrom(socket.0, msg.as_mut_ptr(), msg.len(), 0, from.as_mut_ptr(), &mut from_len) };
+        if len < 0 {
+            unsafe { handControl() };
+            continue;
+        }
+        // only the server we asked, mirroring the question we sent
+        if from_len < to_len || !same_peer(&from, &to) {
+            continue;
+        }
+        let msg = &msg[..len as usize];
+        // a spoofed or mangled reply doesn't end the wait, a server
+        // failure does so query() moves on to the next one
+        if let Some(reply) = parse_response(msg, id, name, qtype) {
+            return reply;
+        }
+    }
+    Err(errno(libc::ETIMEDOUT))
+}
+
+/// `name` (fully qualified, no trailing dot) for one type, from the cache
+/// or going through every nameserver `attempts` times
+fn query(conf: &ResolvConf, name: &str, qtype: u16) -> Result<Answer> {
+    let name = name.to_ascii_lowercase();
+    if let Some(answer) = cache_get(&name, qtype) {
+        return Ok(answer);
+    }
+
+    let mut error = errno(libc::ETIMEDOUT);
+    for _ in 0..conf.attempts {
+        for nameserver in &conf.nameservers {
+            match exchange(nameserver, &name, qtype, conf.timeout) {
+                Ok((answer, ttl)) => {
+                    cache_put(&name, qtype, &answer, ttl);
+                    return Ok(answer);
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Err(e),
+                Err(e) => error = e,
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Every address `name` has for `family` (AF_INET, AF_INET6 or AF_UNSPEC
+/// for both, IPv4 first). Numeric addresses come straight back, then
+/// /etc/hosts is checked, then DNS through the search list.
+///
+/// ENOENT for names that don't exist, ENODATA for names without an
+/// address of that family, ETIMEDOUT/EAGAIN when the nameservers wouldn't
+/// say.
+pub fn dns_resolve(name: &str, family: c_int) -> Result<Vec<IpAddr>> {
+    let qtypes: &[u16] = match family {
+        AF_INET => &[TYPE_A],
+        AF_INET6 => &[TYPE_AAAA],
+        AF_UNSPEC => &[TYPE_A, TYPE_AAAA],
+        _ => return Err(errno(libc::EAFNOSUPPORT)),
+    };
+
+    let name = name.trim();
+    if let Some(addr) = parse_addr(name) {
+        return if family_matches(&addr, family) { Ok(vec![addr]) } else { Err(errno(libc::ENODATA)) };
+    }
+    if name.is_empty() || name.starts_with('.') || name.contains("..") {
+        return Err(errno(libc::EINVAL));
+    }
+
+    let hosts = hosts_lookup(name, family);
+    if !hosts.is_empty() {
+        return Ok(hosts);
+    }
+
+    let conf = ResolvConf::load();
+    let mut error = errno(libc::ENOENT);
+    for candidate in conf.candidates(name) {
+        let mut addrs = Vec::new();
+        let mut exists = false;
+        for &qtype in qtypes {
+            match query(&conf, &candidate, qtype) {
+                Ok(Answer::Addrs(mut found)) => {
+                    exists = true;
+                    addrs.append(&mut found);
+                }
+                Ok(Answer::NoData) => exists = true,
+                Ok(Answer::NxDomain) => {}
+                // a server that's down doesn't make the name not exist
+                Err(e) => {
+                    if error.raw_os_error() == Some(libc::ENOENT) {
+                        error = e;
+                    }
+                }
+            }
+        }
+        if !addrs.is_empty() {
+            return Ok(addrs);
+        }
+        if exists && error.raw_os_error() == Some(libc::ENOENT) {
+            error = errno(libc::ENODATA);
+        }
+    }
+    Err(error)
+}
+
+// ==========================
+// lwIP
+// ==========================
+
+const NETCONN_DNS_IPV4: u8 = 0;
+const NETCONN_DNS_IPV6: u8 = 1;
+const NETCONN_DNS_IPV6_IPV4: u8 = 3;
+
+const IPADDR_TYPE_V4: u8 = 0;
+const IPADDR_TYPE_V6: u8 = 6;
+
+type err_t = i8;
+const ERR_OK: err_t = 0;
+const ERR_VAL: err_t = -6;
+const ERR_ARG: err_t = -16;
+
+/// ip_addr_t: a dual-stack union (ip6 is the bigger member) & its type
+#[repr(C)]
+struct ip_addr_t {
+    addr: [u32; 4],
+    zone: u8,
+    _pad: [u8; 3],
+    ty: u8,
+}
+
+/// LWIP_HOOK_NETCONN_EXTERNAL_RESOLVE: netconn_gethostbyname() (so
+/// lwip_gethostbyname() & lwip_getaddrinfo()) resolves through here and
+/// never reaches dns.c. Called from the caller's thread, outside the core
+/// lock, which is what lets it block.
+#[no_mangle]
+pub extern "C" fn dnsExternalResolve(name: *const c_char, addr: *mut ip_addr_t, addrtype: u8, err: *mut err_t) -> c_int {
+    let name = if name.is_null() { None } else { unsafe { CStr::from_ptr(name) }.to_str().ok() };
+    let Some(name) = name.filter(|_| !addr.is_null()) else {
+        unsafe { *err = ERR_ARG };
+        return 1;
+    };
+
+    let family = match addrtype {
+        NETCONN_DNS_IPV4 => AF_INET,
+        NETCONN_DNS_IPV6 => AF_INET6,
+        _ => AF_UNSPEC,
+    };
+    let addrs = dns_resolve(name, family).unwrap_or_default();
+    let chosen = if addrtype == NETCONN_DNS_IPV6_IPV4 {
+        addrs.iter().find(|a| a.is_ipv6()).or(addrs.first())
+    } else {
+        addrs.first()
+    };
+
+    let Some(chosen) = chosen else {
+        unsafe { *err = ERR_VAL };
+        return 1;
+    };
+    let mut resolved = ip_addr_t { addr: [0; 4], zone: 0, _pad: [0; 3], ty: IPADDR_TYPE_V4 };
+    match chosen {
+        IpAddr::V4(v4) => resolved.addr[0] = u32::from_ne_bytes(v4.octets()),
+        IpAddr::V6(v6) => {
+            for (i, word) in v6.octets().chunks_exact(4).enumerate() {
+                resolved.addr[i] = u32::from_ne_bytes(word.try_into().unwrap());
+            }
+            resolved.ty = IPADDR_TYPE_V6;
+        }
+    }
+    unsafe {
+        ptr::write(addr, resolved);
+        *err = ERR_OK;
+    }
+    1
+}
@@ -0,0 +1,1471 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{Error, Result};
+use std::net::Ipv4Addr;
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::netlink::{interface_ip4_of, interface_name_of, is_local_ip4};
+use crate::packet::packet_transmitting;
+use crate::route::{route_lookup, route_redirect};
+
+//
+// IPv4 packet filter with connection tracking & NAT, laid out like
+// netfilter: PREROUTING (DNAT) and then INPUT or FORWARD on ingress through
+// lwIP's IP4 input hook, OUTPUT and POSTROUTING (SNAT/MASQUERADE) on egress
+// in lwipOutput. Rules come in iptables-save syntax through
+// /sys/net/firewall/rules, which also reads back with per-rule counters.
+//
+// Everything runs on the tcpip thread (or with the core locked), so the
+// netifs can be looked at freely. IPv6 isn't filtered.
+//
+
+// ==========================
+// lwIP (partial)
+// ==========================
+
+#[repr(C)]
+pub struct pbuf {
+    next: *mut pbuf,
+    payload: *mut c_void,
+    tot_len: u16,
+    len: u16,
+    type_internal: u8,
+    flags: u8,
+    ref_: u8,
+    if_idx: u8,
+}
+
+#[repr(C)]
+struct ip_addr_t {
+    addr: [u32; 4],
+    zone: u8,
+    _pad: [u8; 3],
+    ty: u8,
+}
+
+extern "C" {
+    fn sys_now() -> u32;
+    fn pbuf_free(p: *mut pbuf) -> u8;
+    fn icmp_dest_unreach(p: *mut pbuf, t: c_int);
+    fn tcp_rst_netif(
+        netif: *mut c_void,
+        seqno: u32,
+        ackno: u32,
+        local_ip: *const ip_addr_t,
+        remote_ip: *const ip_addr_t,
+        local_port: u16,
+        remote_port: u16,
+    );
+}
+
+fn ip_addr4(addr: u32) -> ip_addr_t {
+    ip_addr_t { addr: [addr.to_be(), 0, 0, 0], zone: 0, _pad: [0; 3], ty: 0 }
+}
+
+fn errno(code: c_int) -> Error {
+    Error::from_raw_os_error(code)
+}
+
+// ==========================
+// Packets
+// ==========================
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+const ETH_HLEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+fn be16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn csum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += be16(word) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    sum
+}
+
+fn csum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// RFC 1624: the checksum once the 16-bit words in `old` turned into `new`
+fn csum_replace(check: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = !check as u32;
+    for word in old.chunks_exact(2) {
+        sum += !be16(word) as u32 & 0xffff;
+    }
+    csum_fold(csum_add(sum, new))
+}
+
+/// The transport header bytes the tracker needs in the first pbuf
+fn transport_min(proto: u8) -> usize {
+    match proto {
+        IPPROTO_TCP => 20,
+        IPPROTO_UDP | IPPROTO_ICMP => 8,
+        _ => 0,
+    }
+}
+
+/// An IPv4 packet, `buf` being however much of it is contiguous. Addresses
+/// and ports in host byte order.
+struct Ip4<'a> {
+    buf: &'a mut [u8],
+    ihl: usize,
+}
+
+impl<'a> Ip4<'a> {
+    fn parse(buf: &'a mut [u8]) -> Option<Ip4<'a>> {
+        if buf.len() < 20 || buf[0] >> 4 != 4 {
+            return None;
+        }
+        let ihl = (buf[0] & 0xf) as usize * 4;
+        if ihl < 20 || buf.len() < ihl {
+            return None;
+        }
+        Some(Ip4 { buf, ihl })
+    }
+
+    fn proto(&self) -> u8 {
+        self.buf[9]
+    }
+
+    fn total_len(&self) -> usize {
+        be16(&self.buf[2..4]) as usize
+    }
+
+    fn src(&self) -> u32 {
+        be32(&self.buf[12..16])
+    }
+
+    fn dst(&self) -> u32 {
+        be32(&self.buf[16..20])
+    }
+
+    /// More fragments or a fragment offset: only the first one has ports
+    /// and there's no reassembly in front of us, so they go untracked
+    fn fragment(&self) -> bool {
+        be16(&self.buf[6..8]) & 0x3fff != 0
+    }
+
+    fn l4(&self) -> &[u8] {
+        &self.buf[self.ihl..]
+    }
+
+    fn has_transport(&self) -> bool {
+        !self.fragment() && self.l4().len() >= transport_min(self.proto())
+    }
+
+    fn ports(&self) -> Option<(u16, u16)> {
+        match self.proto() {
+            IPPROTO_TCP | IPPROTO_UDP if self.has_transport() => Some((be16(&self.l4()[0..2]), be16(&self.l4()[2..4]))),
+            _ => None,
+        }
+    }
+
+    fn tcp_flags(&self) -> u8 {
+        if self.proto() == IPPROTO_TCP && self.has_transport() { self.l4()[13] } else { 0 }
+    }
+
+    /// TCP/UDP checksums cover the addresses through the pseudo header,
+    /// ICMP's only the message itself
+    fn l4_checksum_replace(&mut self, old: &[u8], new: &[u8], pseudo: bool) {
+        if !self.has_transport() {
+            return;
+        }
+        let offset = match self.proto() {
+            IPPROTO_TCP => 16,
+            IPPROTO_UDP => 6,
+            IPPROTO_ICMP if !pseudo => 2,
+            _ => return,
+        };
+        let at = self.ihl + offset;
+        let check = be16(&self.buf[at..at + 2]);
+        if self.proto() == IPPROTO_UDP && check == 0 {
+            return;
+        }
+        let mut check = csum_replace(check, old, new);
+        if self.proto() == IPPROTO_UDP && check == 0 {
+            check = 0xffff;
+        }
+        self.buf[at..at + 2].copy_from_slice(&check.to_be_bytes());
+    }
+
+    fn set_addr(&mut self, dst: bool, addr: u32) {
+        let at = if dst { 16 } else { 12 };
+        let old: [u8; 4] = self.buf[at..at + 4].try_into().unwrap();
+        let new = addr.to_be_bytes();
+        if old != new {
+            self.buf[at..at + 4].copy_from_slice(&new);
+            self.l4_checksum_replace(&old, &new, true);
+        }
+    }
+
+    /// Ports for TCP/UDP, the query identifier for ICMP (both "ports")
+    fn set_port(&mut self, dst: bool, port: u16) {
+        if !self.has_transport() {
+            return;
+        }
+        let offset = match self.proto() {
+            IPPROTO_TCP | IPPROTO_UDP => if dst { 2 } else { 0 },
+            IPPROTO_ICMP => 4,
+            _ => return,
+        };
+        let at = self.ihl + offset;
+        let old: [u8; 2] = self.buf[at..at + 2].try_into().unwrap();
+        let new = port.to_be_bytes();
+        if old != new {
+            self.buf[at..at + 2].copy_from_slice(&new);
+            self.l4_checksum_replace(&old, &new, false);
+        }
+    }
+
+    fn update_checksum(&mut self) {
+        self.buf[10..12].copy_from_slice(&[0, 0]);
+        let check = csum_fold(csum_add(0, &self.buf[..self.ihl]));
+        self.buf[10..12].copy_from_slice(&check.to_be_bytes());
+    }
+}
+
+// ==========================
+// Connection tracking
+// ==========================
+
+const CT_NEW: u8 = 0x01;
+const CT_ESTABLISHED: u8 = 0x02;
+const CT_RELATED: u8 = 0x04;
+const CT_INVALID: u8 = 0x08;
+const CT_UNTRACKED: u8 = 0x10;
+
+const CT_STATES: [(u8, &str); 5] = [
+    (CT_NEW, "NEW"),
+    (CT_ESTABLISHED, "ESTABLISHED"),
+    (CT_RELATED, "RELATED"),
+    (CT_INVALID, "INVALID"),
+    (CT_UNTRACKED, "UNTRACKED"),
+];
+
+const CONNTRACK_MAX: usize = 4096;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Tuple {
+    proto: u8,
+    src: u32,
+    dst: u32,
+    sport: u16,
+    dport: u16,
+}
+
+impl Tuple {
+    fn invert(&self) -> Tuple {
+        Tuple { proto: self.proto, src: self.dst, dst: self.src, sport: self.dport, dport: self.sport }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Dir {
+    Orig,
+    Reply,
+}
+
+impl Dir {
+    fn opposite(self) -> Dir {
+        if self == Dir::Orig { Dir::Reply } else { Dir::Orig }
+    }
+}
+
+/// `orig` is the first packet as it arrived, `reply` what answers to it
+/// look like when they arrive, NAT is whatever makes them differ
+struct Conn {
+    orig: Tuple,
+    reply: Tuple,
+    replied: bool,
+    snat_done: bool,
+    fin: [bool; 2],
+    rst: bool,
+    expires: u32,
+}
+
+impl Conn {
+    fn new(tuple: Tuple, now: u32) -> Conn {
+        let mut conn = Conn {
+            orig: tuple,
+            reply: tuple.invert(),
+            replied: false,
+            snat_done: false,
+            fin: [false; 2],
+            rst: false,
+            expires: 0,
+        };
+        conn.expires = now.wrapping_add(conn.timeout());
+        conn
+    }
+
+    /// nf_conntrack's defaults, in ms
+    fn timeout(&self) -> u32 {
+        match self.orig.proto {
+            IPPROTO_TCP if self.rst => 10_000,
+            IPPROTO_TCP if self.fin[0] && self.fin[1] => 120_000,
+            IPPROTO_TCP if self.fin[0] || self.fin[1] => 60_000,
+            IPPROTO_TCP if self.replied => 432_000_000,
+            IPPROTO_TCP => 120_000,
+            IPPROTO_UDP if self.replied => 120_000,
+            IPPROTO_UDP | IPPROTO_ICMP => 30_000,
+            _ => 600_000,
+        }
+    }
+
+    fn expired(&self, now: u32) -> bool {
+        now.wrapping_sub(self.expires) as i32 >= 0
+    }
+
+    fn natted(&self) -> bool {
+        self.orig != self.reply.invert()
+    }
+
+    fn tcp_state(&self) -> &'static str {
+        if self.rst {
+            "CLOSE"
+        } else if self.fin[0] && self.fin[1] {
+            "TIME_WAIT"
+        } else if self.fin[0] || self.fin[1] {
+            "FIN_WAIT"
+        } else if self.replied {
+            "ESTABLISHED"
+        } else {
+            "SYN_SENT"
+        }
+    }
+}
+
+enum Track {
+    Conn(u64, Dir),
+    New(Tuple),
+    // an ICMP error about a packet that went in Dir
+    Related(u64, Dir),
+    Invalid,
+    Untracked,
+}
+
+enum Key {
+    Tuple(Tuple),
+    IcmpError(Tuple),
+    Invalid,
+    Untracked,
+}
+
+/// The tuple of the packet quoted by an ICMP error
+fn inner_tuple(inner: &[u8]) -> Option<Tuple> {
+    if inner.len() < 20 || inner[0] >> 4 != 4 {
+        return None;
+    }
+    let ihl = (inner[0] & 0xf) as usize * 4;
+    let l4 = inner.get(ihl..ihl + 8)?;
+    let proto = inner[9];
+    let (sport, dport) = match proto {
+        IPPROTO_TCP | IPPROTO_UDP => (be16(&l4[0..2]), be16(&l4[2..4])),
+        IPPROTO_ICMP => (be16(&l4[4..6]), be16(&l4[4..6])),
+        _ => (0, 0),
+    };
+    Some(Tuple { proto, src: be32(&inner[12..16]), dst: be32(&inner[16..20]), sport, dport })
+}
+
+fn key_of(ip: &Ip4) -> Key {
+    if ip.fragment() {
+        return Key::Untracked;
+    }
+    let proto = ip.proto();
+    if !ip.has_transport() {
+        return Key::Invalid;
+    }
+
+    let l4 = ip.l4();
+    let (sport, dport) = match proto {
+        IPPROTO_TCP | IPPROTO_UDP => (be16(&l4[0..2]), be16(&l4[2..4])),
+        IPPROTO_ICMP => match l4[0] {
+            // echo, timestamp, information & address mask queries
+            0 | 8 | 13 | 14 | 15 | 16 | 17 | 18 => (be16(&l4[4..6]), be16(&l4[4..6])),
+            // unreachable, source quench, redirect, time exceeded, parameter problem
+            3 | 4 | 5 | 11 | 12 => {
+                return match inner_tuple(&l4[8..]) {
+                    Some(inner) => Key::IcmpError(inner),
+                    None => Key::Invalid,
+                };
+            }
+            _ => return Key::Untracked,
+        },
+        _ => (0, 0),
+    };
+    Key::Tuple(Tuple { proto, src: ip.src(), dst: ip.dst(), sport, dport })
+}
+
+struct Conntrack {
+    conns: BTreeMap<u64, Conn>,
+    index: BTreeMap<Tuple, (u64, Dir)>,
+    next_id: u64,
+    last_gc: u32,
+}
+
+static CONNTRACK: Mutex<Conntrack> = Mutex::new(Conntrack {
+    conns: BTreeMap::new(),
+    index: BTreeMap::new(),
+    next_id: 1,
+    last_gc: 0,
+});
+
+impl Conntrack {
+    fn remove(&mut self, id: u64) {
+        if let Some(conn) = self.conns.remove(&id) {
+            self.index.remove(&conn.orig);
+            self.index.remove(&conn.reply);
+        }
+    }
+
+    fn gc(&mut self, now: u32, force: bool) {
+        if !force && now.wrapping_sub(self.last_gc) < 1000 {
+            return;
+        }
+        self.last_gc = now;
+        let expired: Vec<u64> = self.conns.iter().filter(|(_, c)| c.expired(now)).map(|(&id, _)| id).collect();
+        for id in expired {
+            self.remove(id);
+        }
+    }
+
+    fn lookup(&mut self, tuple: &Tuple, now: u32) -> Option<(u64, Dir)> {
+        let (id, dir) = *self.index.get(tuple)?;
+        if self.conns[&id].expired(now) {
+            self.remove(id);
+            return None;
+        }
+        Some((id, dir))
+    }
+
+    fn insert(&mut self, conn: Conn, now: u32) -> Option<u64> {
+        if self.conns.len() >= CONNTRACK_MAX {
+            self.gc(now, true);
+            if self.conns.len() >= CONNTRACK_MAX {
+                return None;
+            }
+        }
+        if self.index.contains_key(&conn.orig) || self.index.contains_key(&conn.reply) {
+            return None;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.index.insert(conn.orig, (id, Dir::Orig));
+        self.index.insert(conn.reply, (id, Dir::Reply));
+        self.conns.insert(id, conn);
+        Some(id)
+    }
+
+    fn classify(&mut self, ip: &Ip4, now: u32) -> Track {
+        match key_of(ip) {
+            Key::Tuple(tuple) => {
+                if let Some((id, dir)) = self.lookup(&tuple, now) {
+                    return Track::Conn(id, dir);
+                }
+                // only a SYN or an ICMP query starts something new
+                let starts = match tuple.proto {
+                    IPPROTO_TCP => ip.tcp_flags() & (TCP_SYN | TCP_ACK | TCP_RST | TCP_FIN) == TCP_SYN,
+                    IPPROTO_ICMP => matches!(ip.l4()[0], 8 | 13 | 15 | 17),
+                    _ => true,
+                };
+                if starts { Track::New(tuple) } else { Track::Invalid }
+            }
+            // the quoted packet is either the post-NAT form of one direction
+            // or (when we sent the error ourselves) the pre-NAT one
+            Key::IcmpError(inner) => {
+                if let Some((id, dir)) = self.lookup(&inner.invert(), now) {
+                    Track::Related(id, dir.opposite())
+                } else if let Some((id, dir)) = self.lookup(&inner, now) {
+                    Track::Related(id, dir)
+                } else {
+                    Track::Invalid
+                }
+            }
+            Key::Invalid => Track::Invalid,
+            Key::Untracked => Track::Untracked,
+        }
+    }
+
+    fn state(&self, track: &Track) -> u8 {
+        match track {
+            Track::Conn(id, dir) if *dir == Dir::Reply || self.conns[id].replied => CT_ESTABLISHED,
+            Track::Conn(..) | Track::New(_) => CT_NEW,
+            Track::Related(..) => CT_RELATED,
+            Track::Invalid => CT_INVALID,
+            Track::Untracked => CT_UNTRACKED,
+        }
+    }
+
+    fn refresh(&mut self, id: u64, dir: Dir, tcp_flags: u8, now: u32) {
+        let conn = self.conns.get_mut(&id).unwrap();
+        if dir == Dir::Reply {
+            conn.replied = true;
+        }
+        if tcp_flags & TCP_RST != 0 {
+            conn.rst = true;
+        }
+        if tcp_flags & TCP_FIN != 0 {
+            conn.fin[dir as usize] = true;
+        }
+        conn.expires = now.wrapping_add(conn.timeout());
+    }
+
+    /// Rewrites the destination (ingress) or source (egress) of a packet so
+    /// it matches what the other side of the connection expects
+    fn manip(&self, ip: &mut Ip4, id: u64, dir: Dir, dst: bool) {
+        let conn = &self.conns[&id];
+        let target = if dir == Dir::Orig { conn.reply.invert() } else { conn.orig.invert() };
+        if dst {
+            ip.set_addr(true, target.dst);
+            ip.set_port(true, target.dport);
+        } else {
+            ip.set_addr(false, target.src);
+            ip.set_port(false, target.sport);
+        }
+    }
+
+    /// ICMP errors about NATed connections go back to the host that sent
+    /// the quoted packet, with the quote showing what that host sent
+    fn manip_related(&self, ip: &mut Ip4, id: u64, traveled: Dir) {
+        let conn = &self.conns[&id];
+        let total = ip.total_len();
+        if !conn.natted() || ip.buf.len() < total || total < ip.ihl + 8 + 20 {
+            return;
+        }
+        let want = if traveled == Dir::Orig { conn.orig } else { conn.reply };
+
+        let inner = ip.ihl + 8;
+        let inner_ihl = (ip.buf[inner] & 0xf) as usize * 4;
+        let inner_l4 = inner + inner_ihl;
+        if inner_ihl < 20 || total < inner_l4 + 8 {
+            return;
+        }
+
+        ip.buf[inner + 12..inner + 16].copy_from_slice(&want.src.to_be_bytes());
+        ip.buf[inner + 16..inner + 20].copy_from_slice(&want.dst.to_be_bytes());
+        ip.buf[inner + 10..inner + 12].copy_from_slice(&[0, 0]);
+        let check = csum_fold(csum_add(0, &ip.buf[inner..inner_l4]));
+        ip.buf[inner + 10..inner + 12].copy_from_slice(&check.to_be_bytes());
+
+        match want.proto {
+            IPPROTO_TCP | IPPROTO_UDP => {
+                ip.buf[inner_l4..inner_l4 + 2].copy_from_slice(&want.sport.to_be_bytes());
+                ip.buf[inner_l4 + 2..inner_l4 + 4].copy_from_slice(&want.dport.to_be_bytes());
+            }
+            IPPROTO_ICMP => ip.buf[inner_l4 + 4..inner_l4 + 6].copy_from_slice(&want.sport.to_be_bytes()),
+            _ => {}
+        }
+
+        ip.buf[16..20].copy_from_slice(&want.src.to_be_bytes());
+
+        let icmp = ip.ihl;
+        ip.buf[icmp + 2..icmp + 4].copy_from_slice(&[0, 0]);
+        let check = csum_fold(csum_add(0, &ip.buf[icmp..total]));
+        ip.buf[icmp + 2..icmp + 4].copy_from_slice(&check.to_be_bytes());
+    }
+
+    /// Source NAT for a new connection: the original source port if it's
+    /// free, the first free one in `ports` otherwise
+    fn snat(&mut self, id: u64, addr: u32, ports: Option<(u16, u16)>) {
+        let conn = &self.conns[&id];
+        let mut reply = conn.reply;
+        reply.dst = addr;
+
+        let has_ports = matches!(reply.proto, IPPROTO_TCP | IPPROTO_UDP | IPPROTO_ICMP);
+        let set_port = |tuple: &mut Tuple, port: u16| {
+            tuple.dport = port;
+            if tuple.proto == IPPROTO_ICMP {
+                tuple.sport = port;
+            }
+        };
+
+        let taken = |tuple: &Tuple| self.index.get(tuple).map_or(false, |&(other, _)| other != id);
+        let chosen = if !has_ports {
+            if taken(&reply) { None } else { Some(reply) }
+        } else {
+            let (low, high) = ports.unwrap_or((1024, 65535));
+            let original = reply.dport;
+            let keep = ports.map_or(true, |(low, high)| (low..=high).contains(&original));
+            let candidates = keep.then_some(original).into_iter().chain(low..=high);
+            candidates
+                .map(|port| {
+                    let mut tuple = reply;
+                    set_port(&mut tuple, port);
+                    tuple
+                })
+                .find(|tuple| !taken(tuple))
+        };
+
+        // out of ports: the connection goes out untranslated
+        if let Some(reply) = chosen {
+            let old = self.conns[&id].reply;
+            self.index.remove(&old);
+            self.index.insert(reply, (id, Dir::Reply));
+            self.conns.get_mut(&id).unwrap().reply = reply;
+        }
+    }
+}
+
+// ==========================
+// Rules
+// ==========================
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Hook {
+    Prerouting,
+    Input,
+    Forward,
+    Output,
+    Postrouting,
+}
+
+const HOOKS: [Hook; 5] = [Hook::Prerouting, Hook::Input, Hook::Forward, Hook::Output, Hook::Postrouting];
+
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Hook::Prerouting => "PREROUTING",
+            Hook::Input => "INPUT",
+            Hook::Forward => "FORWARD",
+            Hook::Output => "OUTPUT",
+            Hook::Postrouting => "POSTROUTING",
+        }
+    }
+
+    fn parse(name: &str) -> Result<Hook> {
+        HOOKS.iter().copied().find(|hook| hook.name() == name).ok_or_else(|| errno(libc::ENOENT))
+    }
+
+    fn nat(self) -> bool {
+        matches!(self, Hook::Prerouting | Hook::Postrouting)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum RejectWith {
+    Icmp(u8, &'static str),
+    TcpReset,
+}
+
+const REJECT_ICMP: [(u8, &str); 5] = [
+    (0, "icmp-net-unreachable"),
+    (1, "icmp-host-unreachable"),
+    (2, "icmp-proto-unreachable"),
+    (3, "icmp-port-unreachable"),
+    (13, "icmp-admin-prohibited"),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Target {
+    Accept,
+    Drop,
+    Reject(RejectWith),
+    Snat(u32, Option<(u16, u16)>),
+    Masquerade(Option<(u16, u16)>),
+    Dnat(u32, Option<u16>),
+}
+
+#[derive(Clone, PartialEq)]
+struct Rule {
+    in_iface: Option<String>,
+    out_iface: Option<String>,
+    proto: Option<u8>,
+    src: Option<(u32, u8)>,
+    dst: Option<(u32, u8)>,
+    sport: Option<(u16, u16)>,
+    dport: Option<(u16, u16)>,
+    states: u8,
+    target: Target,
+    packets: u64,
+    bytes: u64,
+}
+
+/// What rules get to match on
+struct Meta<'a> {
+    in_iface: Option<&'a str>,
+    out_iface: Option<&'a str>,
+    proto: u8,
+    src: u32,
+    dst: u32,
+    ports: Option<(u16, u16)>,
+    state: u8,
+    len: usize,
+}
+
+fn prefix_mask(prefix: u8) -> u32 {
+    if prefix == 0 { 0 } else { u32::MAX << (32 - prefix as u32) }
+}
+
+/// iptables' "eth+" matches every name starting with "eth"
+fn iface_matches(pattern: &Option<String>, name: Option<&str>) -> bool {
+    match (pattern, name) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(pattern), Some(name)) => match pattern.strip_suffix('+') {
+            Some(prefix) => name.starts_with(prefix),
+            None => pattern == name,
+        },
+    }
+}
+
+impl Rule {
+    fn matches(&self, meta: &Meta) -> bool {
+        let addr = |net: &Option<(u32, u8)>, addr: u32| {
+            net.map_or(true, |(net, prefix)| (addr ^ net) & prefix_mask(prefix) == 0)
+        };
+        let port = |range: &Option<(u16, u16)>, port: Option<u16>| match (range, port) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some((low, high)), Some(port)) => (*low..=*high).contains(&port),
+        };
+
+        iface_matches(&self.in_iface, meta.in_iface)
+            && iface_matches(&self.out_iface, meta.out_iface)
+            && self.proto.map_or(true, |proto| proto == meta.proto)
+            && addr(&self.src, meta.src)
+            && addr(&self.dst, meta.dst)
+            && port(&self.sport, meta.ports.map(|p| p.0))
+            && port(&self.dport, meta.ports.map(|p| p.1))
+            && (self.states == 0 || self.states & meta.state != 0)
+    }
+}
+
+struct Chain {
+    accept: bool,
+    rules: Vec<Rule>,
+    packets: u64,
+    bytes: u64,
+}
+
+const EMPTY_CHAIN: Chain = Chain { accept: true, rules: Vec::new(), packets: 0, bytes: 0 };
+
+static CHAINS: Mutex<[Chain; 5]> = Mutex::new([EMPTY_CHAIN; 5]);
+
+/// First matching rule wins, the policy applies when none does
+fn evaluate(hook: Hook, meta: &Meta) -> Target {
+    let mut chains = CHAINS.lock().unwrap();
+    let chain = &mut chains[hook as usize];
+    for rule in chain.rules.iter_mut() {
+        if rule.matches(meta) {
+            rule.packets += 1;
+            rule.bytes += meta.len as u64;
+            return rule.target;
+        }
+    }
+    chain.packets += 1;
+    chain.bytes += meta.len as u64;
+    if chain.accept { Target::Accept } else { Target::Drop }
+}
+
+// ==========================
+// Hooks
+// ==========================
+
+static FORWARDING: AtomicBool = AtomicBool::new(false);
+
+/// /proc/sys/net/ipv4/ip_forward
+pub fn forwarding() -> bool {
+    FORWARDING.load(Ordering::Relaxed)
+}
+
+pub fn set_forwarding(enabled: bool) {
+    FORWARDING.store(enabled, Ordering::Relaxed);
+}
+
+/// What a REJECT needs once the packet itself is handed back to lwIP
+struct RejectInfo {
+    proto: u8,
+    src: u32,
+    dst: u32,
+    ports: Option<(u16, u16)>,
+    tcp_flags: u8,
+    seq: u32,
+    ack: u32,
+    segment_len: u32,
+    icmp_error: bool,
+}
+
+impl RejectInfo {
+    fn of(ip: &Ip4) -> RejectInfo {
+        let l4 = ip.l4();
+        let tcp = ip.proto() == IPPROTO_TCP && ip.has_transport();
+        let tcp_hlen = if tcp { (l4[12] >> 4) as usize * 4 } else { 0 };
+        RejectInfo {
+            proto: ip.proto(),
+            src: ip.src(),
+            dst: ip.dst(),
+            ports: ip.ports(),
+            tcp_flags: ip.tcp_flags(),
+            seq: if tcp { be32(&l4[4..8]) } else { 0 },
+            ack: if tcp { be32(&l4[8..12]) } else { 0 },
+            segment_len: ip.total_len().saturating_sub(ip.ihl + tcp_hlen) as u32,
+            icmp_error: ip.proto() == IPPROTO_ICMP
+                && ip.has_transport()
+                && matches!(l4[0], 3 | 4 | 5 | 11 | 12),
+        }
+    }
+}
+
+/// Like ipt_REJECT: never answers RSTs, ICMP errors or non-unicast traffic
+unsafe fn reject(p: *mut pbuf, inp: *mut c_void, info: &RejectInfo, with: RejectWith) {
+    let unicast = info.dst != u32::MAX && info.dst >> 28 != 0xe;
+    match with {
+        RejectWith::TcpReset => {
+            let Some((sport, dport)) = info.ports else { return };
+            if info.proto != IPPROTO_TCP || info.tcp_flags & TCP_RST != 0 || !unicast {
+                return;
+            }
+            let seqno = if info.tcp_flags & TCP_ACK != 0 { info.ack } else { 0 };
+            let consumed = (info.tcp_flags & TCP_SYN != 0) as u32 + (info.tcp_flags & TCP_FIN != 0) as u32;
+            let ackno = info.seq.wrapping_add(info.segment_len).wrapping_add(consumed);
+            tcp_rst_netif(inp, seqno, ackno, &ip_addr4(info.dst), &ip_addr4(info.src), dport, sport);
+        }
+        RejectWith::Icmp(code, _) => {
+            if !info.icmp_error && unicast {
+                icmp_dest_unreach(p, code as c_int);
+            }
+        }
+    }
+}
+
+/// LWIP_HOOK_IP4_INPUT, before lwIP checks the header itself: non-zero
+/// means the packet was eaten (and freed) here
+#[no_mangle]
+pub extern "C" fn firewallIp4Input(p: *mut pbuf, inp: *mut c_void) -> c_int {
+    let buf = unsafe { std::slice::from_raw_parts_mut((*p).payload as *mut u8, (*p).len as usize) };
+    let Some(mut ip) = Ip4::parse(buf) else {
+        return 0;
+    };
+    if ip.total_len() < ip.ihl {
+        return 0;
+    }
+
+    let now = unsafe { sys_now() };
+    let in_iface = interface_name_of(inp);
+    let mut ct = CONNTRACK.lock().unwrap();
+    ct.gc(now, false);
+
+    let track = ct.classify(&ip, now);
+    let mut pending = None;
+    match track {
+        Track::Conn(id, dir) => ct.manip(&mut ip, id, dir, true),
+        Track::Related(id, dir) => ct.manip_related(&mut ip, id, dir),
+        Track::New(tuple) => {
+            let mut conn = Conn::new(tuple, now);
+            let meta = Meta {
+                in_iface: Some(&in_iface),
+                out_iface: None,
+                proto: ip.proto(),
+                src: ip.src(),
+                dst: ip.dst(),
+                ports: ip.ports(),
+                state: CT_NEW,
+                len: ip.total_len(),
+            };
+            if let Target::Dnat(addr, port) = evaluate(Hook::Prerouting, &meta) {
+                conn.reply.src = addr;
+                ip.set_addr(true, addr);
+                if let Some(port) = port {
+                    conn.reply.sport = port;
+                    ip.set_port(true, port);
+                }
+            }
+            pending = Some(conn);
+        }
+        Track::Invalid | Track::Untracked => {}
+    }
+    ip.update_checksum();
+
+    let local = is_local_ip4(ip.dst());
+    // without forwarding lwIP drops it by itself
+    if !local && !forwarding() {
+        return 0;
+    }
+
+    // nowhere to send it: net unreachable, like a failed ip_route_input()
+    let route = if local { None } else { route_lookup(ip.dst()) };
+    if !local && route.is_none() {
+        let info = RejectInfo::of(&ip);
+        drop(ct);
+        unsafe {
+            reject(p, inp, &info, RejectWith::Icmp(0, "icmp-net-unreachable"));
+            pbuf_free(p);
+        }
+        return 1;
+    }
+
+    let out_iface = route.map(|(_, n)| interface_name_of(n));
+    let meta = Meta {
+        in_iface: Some(&in_iface),
+        out_iface: out_iface.as_deref(),
+        proto: ip.proto(),
+        src: ip.src(),
+        dst: ip.dst(),
+        ports: ip.ports(),
+        state: ct.state(&track),
+        len: ip.total_len(),
+    };
+    let verdict = evaluate(if local { Hook::Input } else { Hook::Forward }, &meta);
+
+    if verdict == Target::Accept {
+        let tracked = match (pending, &track) {
+            (Some(conn), _) => ct.insert(conn, now).is_some(),
+            (None, Track::Conn(id, dir)) => {
+                ct.refresh(*id, *dir, ip.tcp_flags(), now);
+                true
+            }
+            _ => true,
+        };
+        if tracked {
+            if let Some((route, out)) = route.filter(|&(_, out)| out == inp) {
+                route_redirect(out, &*ip.buf, &route);
+            }
+            return 0;
+        }
+    }
+
+    let info = RejectInfo::of(&ip);
+    drop(ct);
+    unsafe {
+        if let Target::Reject(with) = verdict {
+            reject(p, inp, &info, with);
+        }
+        pbuf_free(p);
+    }
+    1
+}
+
+/// LWIP_HOOK_IP4_CANFORWARD: -1 leaves it to lwIP's own checks
+#[no_mangle]
+pub extern "C" fn firewallIp4CanForward(_p: *mut pbuf, _dest: u32) -> c_int {
+    if forwarding() { -1 } else { 0 }
+}
+
+/// Called from lwipOutput with the finished ethernet frame, which may get
+/// its source rewritten. false drops it. A REJECT in OUTPUT has nobody to
+/// tell about it here, so it drops as well. AF_PACKET writes bypass all of
+/// this, like on Linux.
+#[no_mangle]
+pub extern "C" fn firewallOutput(netif: *mut c_void, frame: *mut u8, len: u32) -> bool {
+    if packet_transmitting() || (len as usize) < ETH_HLEN + 20 {
+        return true;
+    }
+    let frame = unsafe { std::slice::from_raw_parts_mut(frame, len as usize) };
+    if be16(&frame[12..14]) != ETHERTYPE_IPV4 {
+        return true;
+    }
+    let Some(mut ip) = Ip4::parse(&mut frame[ETH_HLEN..]) else {
+        return true;
+    };
+
+    let now = unsafe { sys_now() };
+    let out_iface = interface_name_of(netif);
+    let mut ct = CONNTRACK.lock().unwrap();
+    let track = ct.classify(&ip, now);
+
+    let mut meta = Meta {
+        in_iface: None,
+        out_iface: Some(&out_iface),
+        proto: ip.proto(),
+        src: ip.src(),
+        dst: ip.dst(),
+        ports: ip.ports(),
+        state: ct.state(&track),
+        len: ip.total_len(),
+    };
+
+    // ours rather than forwarded
+    if ip.src() == 0 || is_local_ip4(ip.src()) {
+        if evaluate(Hook::Output, &meta) != Target::Accept {
+            return false;
+        }
+    }
+
+    let conn = match track {
+        Track::Conn(id, dir) => Some((id, dir)),
+        Track::New(tuple) => match ct.insert(Conn::new(tuple, now), now) {
+            Some(id) => Some((id, Dir::Orig)),
+            None => return false,
+        },
+        _ => None,
+    };
+
+    if let Some((id, dir)) = conn {
+        if dir == Dir::Orig && !ct.conns[&id].snat_done {
+            ct.conns.get_mut(&id).unwrap().snat_done = true;
+            meta.state = CT_NEW;
+            match evaluate(Hook::Postrouting, &meta) {
+                Target::Snat(addr, ports) => ct.snat(id, addr, ports),
+                Target::Masquerade(ports) => {
+                    let addr = interface_ip4_of(netif);
+                    if addr != 0 {
+                        ct.snat(id, addr, ports);
+                    }
+                }
+                _ => {}
+            }
+        }
+        ct.manip(&mut ip, id, dir, false);
+        ct.refresh(id, dir, ip.tcp_flags(), now);
+        ip.update_checksum();
+    }
+    true
+}
+
+// ==========================
+// Configuration (iptables-save syntax)
+// ==========================
+
+fn parse_addr(text: &str) -> Result<(u32, u8)> {
+    let (addr, prefix) = match text.split_once('/') {
+        Some((addr, prefix)) => (addr, prefix.parse::<u8>().map_err(|_| errno(libc::EINVAL))?),
+        None => (text, 32),
+    };
+    let addr: Ipv4Addr = addr.parse().map_err(|_| errno(libc::EINVAL))?;
+    if prefix > 32 {
+        return Err(errno(libc::EINVAL));
+    }
+    Ok((u32::from(addr) & prefix_mask(prefix), prefix))
+}
+
+fn parse_port(text: &str) -> Result<u16> {
+    text.parse::<u16>().map_err(|_| errno(libc::EINVAL))
+}
+
+fn parse_range(text: &str, separator: char) -> Result<(u16, u16)> {
+    let (low, high) = match text.split_once(separator) {
+        Some((low, high)) => (parse_port(low)?, parse_port(high)?),
+        None => (parse_port(text)?, parse_port(text)?),
+    };
+    if low > high {
+        return Err(errno(libc::EINVAL));
+    }
+    Ok((low, high))
+}
+
+fn parse_proto(text: &str) -> Result<Option<u8>> {
+    Ok(match text {
+        "all" | "0" => None,
+        "icmp" => Some(IPPROTO_ICMP),
+        "tcp" => Some(IPPROTO_TCP),
+        "udp" => Some(IPPROTO_UDP),
+        _ => Some(text.parse::<u8>().map_err(|_| errno(libc::EINVAL))?),
+    })
+}
+
+fn parse_states(text: &str) -> Result<u8> {
+    text.split(',').try_fold(0, |states, name| {
+        CT_STATES
+            .iter()
+            .find(|(_, state)| *state == name)
+            .map(|(bit, _)| states | bit)
+            .ok_or_else(|| errno(libc::EINVAL))
+    })
+}
+
+/// "--to-source 1.2.3.4[:port[-port]]" & "--to-destination 1.2.3.4[:port]"
+fn parse_nat_addr(text: &str) -> Result<(u32, Option<(u16, u16)>)> {
+    let (addr, ports) = match text.split_once(':') {
+        Some((addr, ports)) => (addr, Some(parse_range(ports, '-')?)),
+        None => (text, None),
+    };
+    let addr: Ipv4Addr = addr.parse().map_err(|_| errno(libc::EINVAL))?;
+    Ok((u32::from(addr), ports))
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a str>) -> Result<String> {
+    args.next().map(str::to_owned).ok_or_else(|| errno(libc::EINVAL))
+}
+
+fn parse_rule(hook: Hook, args: &[&str]) -> Result<Rule> {
+    let mut rule = Rule {
+        in_iface: None,
+        out_iface: None,
+        proto: None,
+        src: None,
+        dst: None,
+        sport: None,
+        dport: None,
+        states: 0,
+        target: Target::Accept,
+        packets: 0,
+        bytes: 0,
+    };
+    let mut target = None;
+    let mut reject_with = None;
+    let mut to_source = None;
+    let mut to_destination = None;
+    let mut to_ports = None;
+
+    let mut args = args.iter().copied();
+    while let Some(arg) = args.next() {
+        match arg {
+            "-i" | "--in-interface" => rule.in_iface = Some(value(&mut args)?),
+            "-o" | "--out-interface" => rule.out_iface = Some(value(&mut args)?),
+            "-p" | "--protocol" => rule.proto = parse_proto(&value(&mut args)?)?,
+            "-s" | "--source" => rule.src = Some(parse_addr(&value(&mut args)?)?),
+            "-d" | "--destination" => rule.dst = Some(parse_addr(&value(&mut args)?)?),
+            "--sport" | "--source-port" => rule.sport = Some(parse_range(&value(&mut args)?, ':')?),
+            "--dport" | "--destination-port" => rule.dport = Some(parse_range(&value(&mut args)?, ':')?),
+            "--state" | "--ctstate" => rule.states = parse_states(&value(&mut args)?)?,
+            // match modules are implied by their options
+            "-m" | "--match" => {
+                value(&mut args)?;
+            }
+            "-j" | "--jump" => target = Some(value(&mut args)?),
+            "--reject-with" => reject_with = Some(value(&mut args)?),
+            "--to-source" => to_source = Some(parse_nat_addr(&value(&mut args)?)?),
+            "--to-destination" => to_destination = Some(parse_nat_addr(&value(&mut args)?)?),
+            "--to-ports" => to_ports = Some(parse_range(&value(&mut args)?, '-')?),
+            _ => return Err(errno(libc::EINVAL)),
+        }
+    }
+
+    let has_ports = matches!(rule.proto, Some(IPPROTO_TCP | IPPROTO_UDP));
+    if (rule.sport.is_some() || rule.dport.is_some()) && !has_ports {
+        return Err(errno(libc::EINVAL));
+    }
+    if rule.in_iface.is_some() && matches!(hook, Hook::Output | Hook::Postrouting) {
+        return Err(errno(libc::EINVAL));
+    }
+    if rule.out_iface.is_some() && matches!(hook, Hook::Prerouting | Hook::Input) {
+        return Err(errno(libc::EINVAL));
+    }
+
+    rule.target = match (target.as_deref().ok_or_else(|| errno(libc::EINVAL))?, hook) {
+        ("ACCEPT", _) => Target::Accept,
+        ("DROP", hook) if !hook.nat() => Target::Drop,
+        ("REJECT", hook) if !hook.nat() => Target::Reject(match reject_with.as_deref() {
+            None => RejectWith::Icmp(3, "icmp-port-unreachable"),
+            Some("tcp-reset") if rule.proto == Some(IPPROTO_TCP) => RejectWith::TcpReset,
+            Some(name) => REJECT_ICMP
+                .iter()
+                .find(|(_, known)| *known == name)
+                .map(|&(code, name)| RejectWith::Icmp(code, name))
+                .ok_or_else(|| errno(libc::EINVAL))?,
+        }),
+        ("SNAT", Hook::Postrouting) => {
+            let (addr, ports) = to_source.ok_or_else(|| errno(libc::EINVAL))?;
+            if ports.is_some() && !has_ports {
+                return Err(errno(libc::EINVAL));
+            }
+            Target::Snat(addr, ports)
+        }
+        ("MASQUERADE", Hook::Postrouting) => {
+            if to_ports.is_some() && !has_ports {
+                return Err(errno(libc::EINVAL));
+            }
+            Target::Masquerade(to_ports)
+        }
+        ("DNAT", Hook::Prerouting) => {
+            let (addr, ports) = to_destination.ok_or_else(|| errno(libc::EINVAL))?;
+            if ports.is_some() && (!has_ports || ports.unwrap().0 != ports.unwrap().1) {
+                return Err(errno(libc::EINVAL));
+            }
+            Target::Dnat(addr, ports.map(|(port, _)| port))
+        }
+        _ => return Err(errno(libc::EINVAL)),
+    };
+    Ok(rule)
+}
+
+fn format_range(range: (u16, u16), separator: char) -> String {
+    if range.0 == range.1 { range.0.to_string() } else { format!("{}{}{}", range.0, separator, range.1) }
+}
+
+fn format_rule(hook: Hook, rule: &Rule) -> String {
+    let mut out = format!("-A {}", hook.name());
+    if let Some(iface) = &rule.in_iface {
+        let _ = write!(out, " -i {}", iface);
+    }
+    if let Some(iface) = &rule.out_iface {
+        let _ = write!(out, " -o {}", iface);
+    }
+    match rule.proto {
+        Some(IPPROTO_ICMP) => out.push_str(" -p icmp"),
+        Some(IPPROTO_TCP) => out.push_str(" -p tcp"),
+        Some(IPPROTO_UDP) => out.push_str(" -p udp"),
+        Some(proto) => {
+            let _ = write!(out, " -p {}", proto);
+        }
+        None => {}
+    }
+    if let Some((addr, prefix)) = rule.src {
+        let _ = write!(out, " -s {}/{}", Ipv4Addr::from(addr), prefix);
+    }
+    if let Some((addr, prefix)) = rule.dst {
+        let _ = write!(out, " -d {}/{}", Ipv4Addr::from(addr), prefix);
+    }
+    if let Some(range) = rule.sport {
+        let _ = write!(out, " --sport {}", format_range(range, ':'));
+    }
+    if let Some(range) = rule.dport {
+        let _ = write!(out, " --dport {}", format_range(range, ':'));
+    }
+    if rule.states != 0 {
+        let names: Vec<&str> = CT_STATES.iter().filter(|(bit, _)| rule.states & bit != 0).map(|(_, name)| *name).collect();
+        let _ = write!(out, " -m conntrack --ctstate {}", names.join(","));
+    }
+
+    match rule.target {
+        Target::Accept => out.push_str(" -j ACCEPT"),
+        Target::Drop => out.push_str(" -j DROP"),
+        Target::Reject(RejectWith::TcpReset) => out.push_str(" -j REJECT --reject-with tcp-reset"),
+        Target::Reject(RejectWith::Icmp(_, name)) => {
+            let _ = write!(out, " -j REJECT --reject-with {}", name);
+        }
+        Target::Snat(addr, ports) => {
+            let _ = write!(out, " -j SNAT --to-source {}", Ipv4Addr::from(addr));
+            if let Some(range) = ports {
+                let _ = write!(out, ":{}", format_range(range, '-'));
+            }
+        }
+        Target::Masquerade(ports) => {
+            out.push_str(" -j MASQUERADE");
+            if let Some(range) = ports {
+                let _ = write!(out, " --to-ports {}", format_range(range, '-'));
+            }
+        }
+        Target::Dnat(addr, port) => {
+            let _ = write!(out, " -j DNAT --to-destination {}", Ipv4Addr::from(addr));
+            if let Some(port) = port {
+                let _ = write!(out, ":{}", port);
+            }
+        }
+    }
+    out
+}
+
+/// "[packets:bytes]" as iptables-save -c writes them
+fn parse_counters(text: &str) -> Option<(u64, u64)> {
+    let (packets, bytes) = text.strip_prefix('[')?.strip_suffix(']')?.split_once(':')?;
+    Some((packets.parse().ok()?, bytes.parse().ok()?))
+}
+
+fn rule_index(chain: &Chain, text: &str, insert: bool) -> Result<usize> {
+    let number = text.parse::<usize>().map_err(|_| errno(libc::EINVAL))?;
+    let limit = if insert { chain.rules.len() + 1 } else { chain.rules.len() };
+    if number == 0 || number > limit {
+        return Err(errno(libc::ENOENT));
+    }
+    Ok(number - 1)
+}
+
+/// One line of iptables(-save) syntax: -A/-I/-R/-D/-F/-Z/-P, ":CHAIN POLICY
+/// [counters]", "[counters] -A ..." and *table headers (which flush that
+/// table, like iptables-restore does)
+fn command(line: &str) -> Result<()> {
+    let mut args: Vec<&str> = line.split_whitespace().collect();
+    let mut counters = None;
+    if let Some(first) = args.first() {
+        if first.starts_with('[') {
+            counters = Some(parse_counters(first).ok_or_else(|| errno(libc::EINVAL))?);
+            args.remove(0);
+        }
+    }
+    let Some(&op) = args.first() else {
+        return Ok(());
+    };
+    let mut chains = CHAINS.lock().unwrap();
+
+    match op {
+        "COMMIT" => Ok(()),
+        "*filter" | "*nat" => {
+            for hook in HOOKS.iter().filter(|hook| hook.nat() == (op == "*nat")) {
+                chains[*hook as usize] = EMPTY_CHAIN;
+            }
+            Ok(())
+        }
+        _ if op.starts_with(':') => {
+            let hook = Hook::parse(&op[1..])?;
+            let chain = &mut chains[hook as usize];
+            match args.get(1).copied() {
+                Some("ACCEPT") | Some("-") => chain.accept = true,
+                Some("DROP") if !hook.nat() => chain.accept = false,
+                _ => return Err(errno(libc::EINVAL)),
+            }
+            if let Some((packets, bytes)) = args.get(2).and_then(|text| parse_counters(text)) {
+                chain.packets = packets;
+                chain.bytes = bytes;
+            }
+            Ok(())
+        }
+        "-A" | "-I" | "-R" | "-D" => {
+            let hook = Hook::parse(args.get(1).ok_or_else(|| errno(libc::EINVAL))?)?;
+            let chain = &mut chains[hook as usize];
+            let numbered = args.get(2).map_or(false, |arg| arg.parse::<usize>().is_ok());
+            let rest = &args[(2 + numbered as usize).min(args.len())..];
+
+            match op {
+                "-D" if numbered && rest.is_empty() => {
+                    let index = rule_index(chain, args[2], false)?;
+                    chain.rules.remove(index);
+                }
+                "-D" => {
+                    let rule = parse_rule(hook, rest)?;
+                    let wanted = format_rule(hook, &rule);
+                    let index = chain
+                        .rules
+                        .iter()
+                        .position(|existing| format_rule(hook, existing) == wanted)
+                        .ok_or_else(|| errno(libc::ENOENT))?;
+                    chain.rules.remove(index);
+                }
+                _ => {
+                    let mut rule = parse_rule(hook, rest)?;
+                    if let Some((packets, bytes)) = counters {
+                        rule.packets = packets;
+                        rule.bytes = bytes;
+                    }
+                    match op {
+                        "-A" if !numbered => chain.rules.push(rule),
+                        "-I" => {
+                            let index = if numbered { rule_index(chain, args[2], true)? } else { 0 };
+                            chain.rules.insert(index, rule);
+                        }
+                        "-R" if numbered => {
+                            let index = rule_index(chain, args[2], false)?;
+                            chain.rules[index] = rule;
+                        }
+                        _ => return Err(errno(libc::EINVAL)),
+                    }
+                }
+            }
+            Ok(())
+        }
+        "-F" | "-Z" | "-P" => {
+            let hooks: Vec<Hook> = match args.get(1) {
+                Some(name) => vec![Hook::parse(name)?],
+                None if op != "-P" => HOOKS.to_vec(),
+                None => return Err(errno(libc::EINVAL)),
+            };
+            for hook in hooks {
+                let chain = &mut chains[hook as usize];
+                match op {
+                    "-F" => chain.rules.clear(),
+                    "-Z" => {
+                        chain.packets = 0;
+                        chain.bytes = 0;
+                        for rule in chain.rules.iter_mut() {
+                            rule.packets = 0;
+                            rule.bytes = 0;
+                        }
+                    }
+                    _ => match args.get(2).copied() {
+                        Some("ACCEPT") => chain.accept = true,
+                        Some("DROP") if !hook.nat() => chain.accept = false,
+                        _ => return Err(errno(libc::EINVAL)),
+                    },
+                }
+            }
+            Ok(())
+        }
+        _ if op.starts_with('#') => Ok(()),
+        _ => Err(errno(libc::EINVAL)),
+    }
+}
+
+/// Writes to /sys/net/firewall/rules: one command per line, stopping at
+/// the first bad one
+pub fn firewall_write(text: &str) -> Result<()> {
+    text.lines().try_for_each(command)
+}
+
+/// Reads of /sys/net/firewall/rules: iptables-save -c, so it can be
+/// written straight back
+pub fn firewall_read() -> String {
+    let chains = CHAINS.lock().unwrap();
+    let mut out = String::new();
+    for (table, nat) in [("nat", true), ("filter", false)] {
+        let _ = writeln!(out, "*{}", table);
+        for hook in HOOKS.iter().filter(|hook| hook.nat() == nat) {
+            let chain = &chains[*hook as usize];
+            let policy = if chain.accept { "ACCEPT" } else { "DROP" };
+            let _ = writeln!(out, ":{} {} [{}:{}]", hook.name(), policy, chain.packets, chain.bytes);
+        }
+        for hook in HOOKS.iter().filter(|hook| hook.nat() == nat) {
+            for rule in &chains[*hook as usize].rules {
+                let _ = writeln!(out, "[{}:{}] {}", rule.packets, rule.bytes, format_rule(*hook, rule));
+            }
+        }
+        out.push_str("COMMIT\n");
+    }
+    out
+}
+
+fn format_tuple(out: &mut String, tuple: &Tuple) {
+    let _ = write!(out, " src={} dst={}", Ipv4Addr::from(tuple.src), Ipv4Addr::from(tuple.dst));
+    match tuple.proto {
+        IPPROTO_TCP | IPPROTO_UDP => {
+            let _ = write!(out, " sport={} dport={}", tuple.sport, tuple.dport);
+        }
+        IPPROTO_ICMP => {
+            let _ = write!(out, " id={}", tuple.sport);
+        }
+        _ => {}
+    }
+}
+
+/// Reads of /sys/net/firewall/conntrack, laid out like /proc/net/nf_conntrack
+pub fn conntrack_read() -> String {
+    let now = unsafe { sys_now() };
+    let ct = CONNTRACK.lock().unwrap();
+    let mut out = String::new();
+    for conn in ct.conns.values().filter(|conn| !conn.expired(now)) {
+        let name = match conn.orig.proto {
+            IPPROTO_TCP => "tcp",
+            IPPROTO_UDP => "udp",
+            IPPROTO_ICMP => "icmp",
+            _ => "unknown",
+        };
+        let _ = write!(out, "ipv4     2 {:<8} {} {}", name, conn.orig.proto, conn.expires.wrapping_sub(now) / 1000);
+        if conn.orig.proto == IPPROTO_TCP {
+            let _ = write!(out, " {}", conn.tcp_state());
+        }
+        format_tuple(&mut out, &conn.orig);
+        if !conn.replied {
+            out.push_str(" [UNREPLIED]");
+        }
+        format_tuple(&mut out, &conn.reply);
+        out.push('\n');
+    }
+    out
+}
+
+/// Writes of /sys/net/firewall/conntrack: "-F" empties the table
+pub fn conntrack_write(text: &str) -> Result<()> {
+    match text.trim() {
+        "-F" => {
+            let mut ct = CONNTRACK.lock().unwrap();
+            ct.conns.clear();
+            ct.index.clear();
+            Ok(())
+        }
+        _ => Err(errno(libc::EINVAL)),
+    }
+}
@@ -0,0 +1,272 @@
+#![no_std]
+#![allow(non_camel_case_types)]
+#![allow(non_upper_case_globals)]
+
+use core::ffi::c_void;
+use core::ptr::null_mut;
+
+//
+// Loopback interface ("lo"). Always present, independent of any NIC, so
+// 127.0.0.0/8 and ::1 work even when booted with -nic none.
+//
+
+//
+// Externs
+//
+
+extern "C" {
+    fn debugf(fmt: *const u8, ...) -> i32;
+    fn malloc(size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+    fn memcpy(dst: *mut u8, src: *const u8, len: usize);
+
+    fn tcpip_input(p: *mut pbuf, inp: *mut netif) -> err_t;
+    fn netif_add(
+        netif: *mut netif,
+        ip: *const ip4_addr,
+        mask: *const ip4_addr,
+        gw: *const ip4_addr,
+        state: *mut c_void,
+        init: extern "C" fn(*mut netif) -> err_t,
+        input: unsafe extern "C" fn(*mut pbuf, *mut netif) -> err_t,
+    ) -> *mut netif;
+    fn netif_set_up(netif: *mut netif);
+    fn netif_set_link_up(netif: *mut netif);
+    fn netif_add_ip6_address(netif: *mut netif, addr: *const ip6_addr, idx: *mut i8) -> err_t;
+    fn netif_ip6_addr_set_state(netif: *mut netif, idx: i8, state: u8);
+
+    fn pbuf_alloc(layer: i32, len: u16, r#type: i32) -> *mut pbuf;
+    fn pbuf_alloced_custom(
+        layer: i32,
+        len: u16,
+        r#type: i32,
+        p: *mut pbuf_custom,
+        payload_mem: *mut c_void,
+        payload_mem_len: u16,
+    ) -> *mut pbuf;
+    fn pbuf_copy_partial(p: *const pbuf, dataptr: *mut c_void, len: u16, offset: u16) -> u16;
+    fn pbuf_cat(head: *mut pbuf, tail: *mut pbuf);
+    fn pbuf_ref(p: *mut pbuf);
+    fn pbuf_free(p: *mut pbuf) -> u8;
+}
+
+//
+// lwIP types (partial, ABI-compatible)
+//
+
+pub type err_t = i32;
+
+pub const ERR_OK: err_t = 0;
+pub const ERR_MEM: err_t = -1;
+
+pub const PBUF_RAW: i32 = 0;
+pub const PBUF_RAM: i32 = 0x0280;
+pub const PBUF_ROM: i32 = 0x01;
+pub const PBUF_TYPE_FLAG_DATA_VOLATILE: u8 = 0x40;
+
+pub const IP6_ADDR_PREFERRED: u8 = 0x30;
+
+#[repr(C)]
+pub struct ip4_addr {
+    pub addr: u32,
+}
+
+#[repr(C)]
+pub struct ip6_addr {
+    pub addr: [u32; 4],
+    pub zone: u8,
+}
+
+#[repr(C)]
+pub struct netif {
+    pub next: *mut netif,
+    pub input: Option<unsafe extern "C" fn(*mut pbuf, *mut netif) -> err_t>,
+    pub output: Option<extern "C" fn(*mut netif, *mut pbuf, *const ip4_addr) -> err_t>,
+    pub output_ip6: Option<extern "C" fn(*mut netif, *mut pbuf, *const ip6_addr) -> err_t>,
+    pub mtu: u16,
+    pub flags: u8,
+    pub name: [u8; 2],
+}
+
+#[repr(C)]
+pub struct pbuf {
+    pub next: *mut pbuf,
+    pub payload: *mut u8,
+    pub tot_len: u16,
+    pub len: u16,
+    pub type_internal: u8,
+    pub flags: u8,
+    pub r#ref: u8,
+    pub if_idx: u8,
+}
+
+#[repr(C)]
+pub struct pbuf_custom {
+    pub pbuf: pbuf,
+    pub custom_free_function: extern "C" fn(*mut pbuf),
+}
+
+//
+// State
+//
+
+// lwIP keeps the MTU in a u16, so lo gets 65535 rather than Linux's 65536;
+// that's what it enforces, so that's what SIOCGIFMTU and netlink report
+pub const LOOPBACK_MTU: u16 = u16::MAX;
+
+// Everything up to here is copied on loopback: tcp_input() byte-swaps its
+// header in place and the sender may still retransmit the very same pbuf.
+// Covers an IPv4 header with options (60) or IPv6 (40) plus a full TCP
+// header with options (60).
+const LOOPBACK_HEADER_COPY: u16 = 128;
+
+#[no_mangle]
+pub static mut loopbackNetif: netif = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
+
+// A by-reference slice of a sent pbuf chain. Holds a reference on the chain
+// head, which keeps the whole chain alive until the receiver is done.
+#[repr(C)]
+struct LoopbackRef {
+    custom: pbuf_custom,
+    original: *mut pbuf,
+}
+
+extern "C" fn loopbackRefFree(p: *mut pbuf) {
+    unsafe {
+        let slice = p as *mut LoopbackRef;
+        pbuf_free((*slice).original);
+        free(slice as *mut u8);
+    }
+}
+
+unsafe fn loopbackSlice(original: *mut pbuf, seg: *mut pbuf, skip: u16) -> *mut pbuf {
+    let len = (*seg).len - skip;
+    let data = (*seg).payload.add(skip as usize);
+
+    // PBUF_REF data (e.g. a netbuf_ref()'d user buffer) is only valid for
+    // the duration of the output call, so that has to be copied
+    if (*seg).type_internal & PBUF_TYPE_FLAG_DATA_VOLATILE != 0 {
+        let copy = pbuf_alloc(PBUF_RAW, len, PBUF_RAM);
+        if !copy.is_null() {
+            memcpy((*copy).payload, data, len as usize);
+        }
+        return copy;
+    }
+
+    let slice = malloc(core::mem::size_of::<LoopbackRef>()) as *mut LoopbackRef;
+    if slice.is_null() {
+        return null_mut();
+    }
+    (*slice).custom.custom_free_function = loopbackRefFree;
+    (*slice).original = original;
+    pbuf_ref(original);
+
+    pbuf_alloced_custom(
+        PBUF_RAW,
+        len,
+        PBUF_ROM,
+        &mut (*slice).custom,
+        data as *mut c_void,
+        len,
+    )
+}
+
+//
+// Transmit: headers are copied, payload is handed over by reference
+//
+
+#[no_mangle]
+pub extern "C" fn loopbackTransmit(netif: *mut netif, p: *mut pbuf) -> err_t {
+    unsafe {
+        let head_len = core::cmp::min((*p).tot_len, LOOPBACK_HEADER_COPY);
+        let q = pbuf_alloc(PBUF_RAW, head_len, PBUF_RAM);
+        if q.is_null() {
+            return ERR_MEM;
+        }
+        pbuf_copy_partial(p, (*q).payload as *mut c_void, head_len, 0);
+
+        let mut offset: u16 = 0;
+        let mut seg = p;
+        while !seg.is_null() {
+            let end = offset + (*seg).len;
+            if end > head_len {
+                let skip = head_len.saturating_sub(offset);
+                let slice = loopbackSlice(p, seg, skip);
+                if slice.is_null() {
+                    pbuf_free(q);
+                    return ERR_MEM;
+                }
+                pbuf_cat(q, slice);
+            }
+            offset = end;
+            seg = (*seg).next;
+        }
+
+        // tcpip_input() only queues, so this never recurses into the stack
+        // while the sender still holds its locks
+        if tcpip_input(q, netif) != ERR_OK {
+            pbuf_free(q);
+            return ERR_MEM;
+        }
+
+        ERR_OK
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn loopbackOutput(netif: *mut netif, p: *mut pbuf, _addr: *const ip4_addr) -> err_t {
+    loopbackTransmit(netif, p)
+}
+
+#[no_mangle]
+pub extern "C" fn loopbackOutputIp6(netif: *mut netif, p: *mut pbuf, _addr: *const ip6_addr) -> err_t {
+    loopbackTransmit(netif, p)
+}
+
+//
+// Initialization (runs on the tcpip thread)
+//
+
+#[no_mangle]
+pub extern "C" fn loopbackNetifInit(netif: *mut netif) -> err_t {
+    unsafe {
+        (*netif).name = [b'l', b'o'];
+        (*netif).output = Some(loopbackOutput);
+        (*netif).output_ip6 = Some(loopbackOutputIp6);
+        (*netif).mtu = LOOPBACK_MTU;
+        // no ETHARP/BROADCAST/MLD6: raw IP in, raw IP out
+        (*netif).flags = 0;
+    }
+    ERR_OK
+}
+
+#[no_mangle]
+pub extern "C" fn loopbackInitInThread(_arg: *mut c_void) {
+    unsafe {
+        let lo = core::ptr::addr_of_mut!(loopbackNetif);
+
+        // network byte order
+        let ip = ip4_addr { addr: 0x0100007F }; // 127.0.0.1
+        let mask = ip4_addr { addr: 0x000000FF }; // 255.0.0.0
+        let gw = ip4_addr { addr: 0x0100007F };
+
+        if netif_add(lo, &ip, &mask, &gw, null_mut(), loopbackNetifInit, tcpip_input).is_null() {
+            debugf(b"[networking::lo] Couldn't add the loopback interface!\n\0".as_ptr());
+            return;
+        }
+
+        // ::1 goes straight to preferred, duplicate address detection on a
+        // loopback would only ever see its own probes
+        let ip6 = ip6_addr {
+            addr: [0, 0, 0, 0x01000000],
+            zone: 0,
+        };
+        let mut idx: i8 = -1;
+        if netif_add_ip6_address(lo, &ip6, &mut idx) == ERR_OK {
+            netif_ip6_addr_set_state(lo, idx, IP6_ADDR_PREFERRED);
+        }
+
+        netif_set_up(lo);
+        netif_set_link_up(lo);
+    }
+}
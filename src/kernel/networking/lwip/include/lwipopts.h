@@ -24,6 +24,44 @@
 #define LWIP_DNS 1
 #define LWIP_DEBUG 1
 
+// dual-stack: SLAAC, neighbour discovery & MLD come along with LWIP_IPV6.
+// No DHCPv6/RDNSS, both would overwrite the DNS servers DHCP gave us.
+#define LWIP_IPV4 1
+#define LWIP_IPV6 1
+#define LWIP_IPV6_NUM_ADDRESSES 4
+
+// socket options: SO_REUSEADDR, SO_LINGER, TCP_KEEP*, IP_ADD_MEMBERSHIP and a
+// FIONREAD that reports the next datagram's size like Linux does
+#define SO_REUSE 1
+#define LWIP_SO_LINGER 1
+#define LWIP_TCP_KEEPALIVE 1
+#define LWIP_IGMP 1
+#define LWIP_FIONREAD_LINUXMODE 1
+
+// netlink's RTM_NEWLINK/NEWADDR/NEWROUTE notifications
+#define LWIP_NETIF_EXT_STATUS_CALLBACK 1
+
+#define LWIP_HOOK_FILENAME "lwip_hooks.h"
+
+// firewall.rs: filtering, conntrack & NAT on ingress, forwarding between
+// interfaces once /proc/sys/net/ipv4/ip_forward says so (back out of the
+// same one too, with an ICMP redirect)
+#define IP_FORWARD 1
+#define IP_FORWARD_ALLOW_TX_ON_RX_NETIF 1
+#define LWIP_HOOK_IP4_INPUT(pbuf, input_netif)                                 \
+  firewallIp4Input((pbuf), (input_netif))
+#define LWIP_HOOK_IP4_CANFORWARD(pbuf, dest)                                   \
+  firewallIp4CanForward((pbuf), (dest))
+
+// route.rs: the routing table picks the netif and the next hop
+#define LWIP_HOOK_IP4_ROUTE_SRC(src, dest) routeIp4Select((src), (dest))
+#define LWIP_HOOK_ETHARP_GET_GW(netif, dest) routeIp4Gateway((netif), (dest))
+
+// dns.rs: netconn_gethostbyname() & getaddrinfo() go through the kernel
+// resolver (hosts, resolv.conf, cache) rather than dns.c
+#define LWIP_HOOK_NETCONN_EXTERNAL_RESOLVE(name, addr, addrtype, err)          \
+  dnsExternalResolve((name), (addr), (addrtype), (err))
+
 // raise connection limits
 #define MEMP_NUM_NETCONN 100
 #define MEMP_NUM_TCP_PCB 100
@@ -0,0 +1,1784 @@
+use std::collections::VecDeque;
+use std::io::{Error, Result};
+use std::os::raw::{c_int, c_uint, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+
+use crate::route::{
+    gateway_route, route_add, route_delete, route_ioctl, route_lookup, routes, Route, RTPROT_BOOT, RTPROT_KERNEL, SIOCADDRT,
+    SIOCDELRT,
+};
+
+//
+// NETLINK_ROUTE over lwIP's netif list: interfaces, their addresses and the
+// IPv4 routing table in route.rs. Also the SIOCGIF*/SIOCSIF* ioctls (and
+// SIOCADDRT/SIOCDELRT), which work on the very same data.
+//
+
+// ==========================
+// lwIP (partial, ABI-compatible with our lwipopts.h)
+// ==========================
+
+type err_t = i32;
+const ERR_OK: err_t = 0;
+
+const NETIF_FLAG_UP: u8 = 0x01;
+const NETIF_FLAG_BROADCAST: u8 = 0x02;
+const NETIF_FLAG_LINK_UP: u8 = 0x04;
+const NETIF_FLAG_ETHARP: u8 = 0x08;
+
+const IPADDR_TYPE_V4: u8 = 0;
+const IPADDR_TYPE_V6: u8 = 6;
+
+const IP6_ADDR_INVALID: u8 = 0x00;
+const IP6_ADDR_TENTATIVE: u8 = 0x08;
+const IP6_ADDR_VALID: u8 = 0x10;
+const IP6_ADDR_PREFERRED: u8 = 0x30;
+
+const LWIP_IPV6_NUM_ADDRESSES: usize = 4;
+
+const LWIP_NSC_NETIF_ADDED: u16 = 0x0001;
+const LWIP_NSC_NETIF_REMOVED: u16 = 0x0002;
+const LWIP_NSC_LINK_CHANGED: u16 = 0x0004;
+const LWIP_NSC_STATUS_CHANGED: u16 = 0x0008;
+const LWIP_NSC_IPV4_ADDRESS_CHANGED: u16 = 0x0010;
+const LWIP_NSC_IPV4_GATEWAY_CHANGED: u16 = 0x0020;
+const LWIP_NSC_IPV4_NETMASK_CHANGED: u16 = 0x0040;
+const LWIP_NSC_IPV6_SET: u16 = 0x0100;
+const LWIP_NSC_IPV6_ADDR_STATE_CHANGED: u16 = 0x0200;
+
+/// ip_addr_t: a dual-stack union (ip6 is the bigger member) & its type
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ip_addr_t {
+    addr: [u32; 4],
+    zone: u8,
+    _pad: [u8; 3],
+    ty: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ip4_addr_t {
+    addr: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ip6_addr_t {
+    addr: [u32; 4],
+    zone: u8,
+}
+
+/// struct netif up to rs_count. IPv6 address lifetimes come along with
+/// LWIP_IPV6_AUTOCONFIG, client_data has slots for DHCP, ACD, IGMP & MLD6.
+#[repr(C)]
+struct netif {
+    next: *mut netif,
+
+    ip_addr: ip_addr_t,
+    netmask: ip_addr_t,
+    gw: ip_addr_t,
+
+    ip6_addr: [ip_addr_t; LWIP_IPV6_NUM_ADDRESSES],
+    ip6_addr_state: [u8; LWIP_IPV6_NUM_ADDRESSES],
+    ip6_addr_valid_life: [u32; LWIP_IPV6_NUM_ADDRESSES],
+    ip6_addr_pref_life: [u32; LWIP_IPV6_NUM_ADDRESSES],
+
+    input: *const c_void,
+    output: *const c_void,
+    linkoutput: *const c_void,
+    output_ip6: *const c_void,
+
+    state: *mut c_void,
+    client_data: [*mut c_void; 4],
+
+    mtu: u16,
+    mtu6: u16,
+    hwaddr: [u8; 6],
+    hwaddr_len: u8,
+    flags: u8,
+    name: [u8; 2],
+    num: u8,
+    ip6_autoconfig_enabled: u8,
+    rs_count: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ipv4_changed_s {
+    old_address: *const ip_addr_t,
+    old_netmask: *const ip_addr_t,
+    old_gw: *const ip_addr_t,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ipv6_set_s {
+    addr_index: i8,
+    old_address: *const ip_addr_t,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ipv6_addr_state_changed_s {
+    addr_index: i8,
+    old_state: u8,
+    address: *const ip_addr_t,
+}
+
+#[repr(C)]
+union netif_ext_callback_args_t {
+    ipv4_changed: ipv4_changed_s,
+    ipv6_set: ipv6_set_s,
+    ipv6_addr_state_changed: ipv6_addr_state_changed_s,
+}
+
+type netif_ext_callback_fn = extern "C" fn(*mut netif, u16, *const netif_ext_callback_args_t);
+
+#[repr(C)]
+struct netif_ext_callback_t {
+    callback_fn: Option<netif_ext_callback_fn>,
+    next: *mut netif_ext_callback_t,
+}
+
+extern "C" {
+    static mut netif_list: *mut netif;
+    static mut lock_tcpip_core: u64;
+
+    fn sys_mutex_lock(lock: *mut u64);
+    fn sys_mutex_unlock(lock: *mut u64);
+
+    fn netif_set_addr(netif: *mut netif, ip: *const ip4_addr_t, mask: *const ip4_addr_t, gw: *const ip4_addr_t);
+    fn netif_set_gw(netif: *mut netif, gw: *const ip4_addr_t);
+    fn netif_set_up(netif: *mut netif);
+    fn netif_set_down(netif: *mut netif);
+    fn netif_add_ip6_address(netif: *mut netif, addr: *const ip6_addr_t, idx: *mut i8) -> err_t;
+    fn netif_ip6_addr_set_state(netif: *mut netif, idx: i8, state: u8);
+    fn netif_get_ip6_addr_match(netif: *mut netif, addr: *const ip6_addr_t) -> i8;
+    fn netif_add_ext_callback(callback: *mut netif_ext_callback_t, func: netif_ext_callback_fn);
+    fn dhcp_release_and_stop(netif: *mut netif);
+
+    // ipv4.rs keeps its own copy for the in-tree stack
+    fn netIpv4Configure(mac: *const u8, ip: u32, mask: u32, gateway: u32);
+
+    static currentTask: *mut c_void;
+    fn signalsPendingQuick(task: *mut c_void) -> bool;
+}
+
+/// LOCK_TCPIP_CORE(): netifs may only be touched with the core locked, the
+/// tcpip thread itself already holds it while running callbacks
+pub(crate) struct CoreLock;
+
+impl CoreLock {
+    pub(crate) fn new() -> CoreLock {
+        unsafe { sys_mutex_lock(ptr::addr_of_mut!(lock_tcpip_core)) };
+        CoreLock
+    }
+}
+
+impl Drop for CoreLock {
+    fn drop(&mut self) {
+        unsafe { sys_mutex_unlock(ptr::addr_of_mut!(lock_tcpip_core)) };
+    }
+}
+
+// ==========================
+// Linux ABI
+// ==========================
+
+pub const AF_NETLINK: c_int = 16;
+pub const NETLINK_ROUTE: c_int = 0;
+const AF_UNSPEC: u8 = 0;
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+
+const SOL_SOCKET: c_int = 1;
+const SOL_NETLINK: c_int = 270;
+const SO_TYPE: c_int = 3;
+const SO_SNDBUF: c_int = 7;
+const SO_RCVBUF: c_int = 8;
+const SO_PROTOCOL: c_int = 38;
+const SO_DOMAIN: c_int = 39;
+const NETLINK_ADD_MEMBERSHIP: c_int = 1;
+const NETLINK_DROP_MEMBERSHIP: c_int = 2;
+const NETLINK_PKTINFO: c_int = 3;
+const NETLINK_EXT_ACK: c_int = 11;
+const NETLINK_GET_STRICT_CHK: c_int = 12;
+
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_MIN_TYPE: u16 = 16;
+
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_MULTI: u16 = 0x02;
+const NLM_F_ACK: u16 = 0x04;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+const RTM_SETLINK: u16 = 19;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_GETADDR: u16 = 22;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+
+const RTMGRP_LINK: u32 = 0x1;
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+
+const IFLA_ADDRESS: u16 = 1;
+const IFLA_BROADCAST: u16 = 2;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_QDISC: u16 = 6;
+const IFLA_TXQLEN: u16 = 13;
+const IFLA_OPERSTATE: u16 = 16;
+const IFLA_LINKMODE: u16 = 17;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_LABEL: u16 = 3;
+const IFA_BROADCAST: u16 = 4;
+const IFA_FLAGS: u16 = 8;
+
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_PREFSRC: u16 = 7;
+const RTA_TABLE: u16 = 15;
+
+const IFF_UP: u32 = 0x1;
+const IFF_BROADCAST: u32 = 0x2;
+const IFF_LOOPBACK: u32 = 0x8;
+const IFF_RUNNING: u32 = 0x40;
+const IFF_PROMISC: u32 = 0x100;
+const IFF_MULTICAST: u32 = 0x1000;
+const IFF_LOWER_UP: u32 = 0x10000;
+
+const IFA_F_NODAD: u32 = 0x02;
+const IFA_F_DEPRECATED: u32 = 0x20;
+const IFA_F_TENTATIVE: u32 = 0x40;
+const IFA_F_PERMANENT: u32 = 0x80;
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_LOOPBACK: u16 = 772;
+
+const IF_OPER_DOWN: u8 = 2;
+const IF_OPER_UP: u8 = 6;
+
+const RT_TABLE_MAIN: u8 = 254;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RT_SCOPE_HOST: u8 = 254;
+const RTN_UNICAST: u8 = 1;
+
+const IFNAMSIZ: usize = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct nlmsghdr {
+    nlmsg_len: u32,
+    nlmsg_type: u16,
+    nlmsg_flags: u16,
+    nlmsg_seq: u32,
+    nlmsg_pid: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct sockaddr_nl {
+    pub nl_family: u16,
+    pub nl_pad: u16,
+    pub nl_pid: u32,
+    pub nl_groups: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ifinfomsg {
+    ifi_family: u8,
+    ifi_pad: u8,
+    ifi_type: u16,
+    ifi_index: i32,
+    ifi_flags: u32,
+    ifi_change: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct ifaddrmsg {
+    ifa_family: u8,
+    ifa_prefixlen: u8,
+    ifa_flags: u8,
+    ifa_scope: u8,
+    ifa_index: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct rtmsg {
+    rtm_family: u8,
+    rtm_dst_len: u8,
+    rtm_src_len: u8,
+    rtm_tos: u8,
+    rtm_table: u8,
+    rtm_protocol: u8,
+    rtm_scope: u8,
+    rtm_type: u8,
+    rtm_flags: u32,
+}
+
+fn errno(code: c_int) -> Error {
+    Error::from_raw_os_error(code)
+}
+
+fn nl_align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_struct<T: Copy + Default>(data: &[u8]) -> T {
+    let mut value = T::default();
+    let len = data.len().min(std::mem::size_of::<T>());
+    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), &mut value as *mut T as *mut u8, len) };
+    value
+}
+
+fn struct_bytes<T>(value: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(value as *const T as *const u8, std::mem::size_of::<T>()) }
+}
+
+// ==========================
+// Message building & parsing
+// ==========================
+
+/// One outgoing netlink message, header length fixed up by finish()
+struct NlMessage {
+    buf: Vec<u8>,
+}
+
+impl NlMessage {
+    fn new(ty: u16, flags: u16, seq: u32, pid: u32) -> NlMessage {
+        let header = nlmsghdr {
+            nlmsg_len: 0,
+            nlmsg_type: ty,
+            nlmsg_flags: flags,
+            nlmsg_seq: seq,
+            nlmsg_pid: pid,
+        };
+        let mut msg = NlMessage { buf: Vec::with_capacity(256) };
+        msg.put(&header);
+        msg
+    }
+
+    fn put<T>(&mut self, value: &T) {
+        self.buf.extend_from_slice(struct_bytes(value));
+        self.buf.resize(nl_align(self.buf.len()), 0);
+    }
+
+    fn attr(&mut self, ty: u16, data: &[u8]) {
+        let len = (4 + data.len()) as u16;
+        self.buf.extend_from_slice(&len.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(nl_align(self.buf.len()), 0);
+    }
+
+    fn attr_u32(&mut self, ty: u16, value: u32) {
+        self.attr(ty, &value.to_ne_bytes());
+    }
+
+    fn attr_str(&mut self, ty: u16, value: &str) {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(ty, &data);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// (type, payload) for every attribute in `data`
+fn parse_attrs(data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    let mut offset = 0;
+    while offset + 4 <= data.len() {
+        let len = u16::from_ne_bytes([data[offset], data[offset + 1]]) as usize;
+        let ty = u16::from_ne_bytes([data[offset + 2], data[offset + 3]]) & 0x3fff;
+        if len < 4 || offset + len > data.len() {
+            break;
+        }
+        attrs.push((ty, &data[offset + 4..offset + len]));
+        offset += nl_align(len);
+    }
+    attrs
+}
+
+fn find_attr<'a>(attrs: &[(u16, &'a [u8])], ty: u16) -> Option<&'a [u8]> {
+    attrs.iter().find(|(t, _)| *t == ty).map(|(_, data)| *data)
+}
+
+fn attr_ip4(data: &[u8]) -> Result<u32> {
+    data.get(..4)
+        .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| errno(libc::EINVAL))
+}
+
+fn attr_ip6(data: &[u8]) -> Result<[u32; 4]> {
+    if data.len() < 16 {
+        return Err(errno(libc::EINVAL));
+    }
+    let mut addr = [0u32; 4];
+    for (i, word) in addr.iter_mut().enumerate() {
+        *word = u32::from_ne_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]]);
+    }
+    Ok(addr)
+}
+
+fn attr_str(data: &[u8]) -> &[u8] {
+    data.split(|b| *b == 0).next().unwrap_or(&[])
+}
+
+// ==========================
+// Interfaces
+// ==========================
+
+// network byte order throughout, like lwIP keeps them
+fn prefix_to_mask(prefix: u8) -> u32 {
+    if prefix == 0 { 0 } else { (u32::MAX << (32 - prefix.min(32) as u32)).to_be() }
+}
+
+fn mask_to_prefix(mask: u32) -> u8 {
+    u32::from_be(mask).leading_ones() as u8
+}
+
+// what SIOCSIFADDR falls back to before a netmask is set
+fn classful_mask(addr: u32) -> u32 {
+    match u32::from_be(addr) >> 24 {
+        0..=127 => prefix_to_mask(8),
+        128..=191 => prefix_to_mask(16),
+        _ => prefix_to_mask(24),
+    }
+}
+
+unsafe fn netif_is_loopback(n: *const netif) -> bool {
+    (*n).name == *b"lo"
+}
+
+unsafe fn netif_iter() -> impl Iterator<Item = *mut netif> {
+    let mut next = netif_list;
+    std::iter::from_fn(move || {
+        let current = next;
+        if !current.is_null() {
+            next = (*current).next;
+            Some(current)
+        } else {
+            None
+        }
+    })
+}
+
+// netif_get_index()
+unsafe fn netif_index(n: *const netif) -> u32 {
+    (*n).num as u32 + 1
+}
+
+/// lo stays lo, NICs become eth0, eth1.. in the order they came up
+unsafe fn netif_linux_name(n: *const netif) -> String {
+    if netif_is_loopback(n) {
+        return String::from("lo");
+    }
+    let ordinal = netif_iter().filter(|&o| !netif_is_loopback(o) && (*o).num < (*n).num).count();
+    format!("eth{}", ordinal)
+}
+
+unsafe fn netif_by_index(index: u32) -> Option<*mut netif> {
+    netif_iter().find(|&n| netif_index(n) == index)
+}
+
+unsafe fn netif_by_name(name: &[u8]) -> Option<*mut netif> {
+    netif_iter().find(|&n| netif_linux_name(n).as_bytes() == name)
+}
+
+unsafe fn netif_linux_flags(n: *const netif) -> u32 {
+    let mut flags = 0;
+    if (*n).flags & NETIF_FLAG_UP != 0 {
+        flags |= IFF_UP;
+        if (*n).flags & NETIF_FLAG_LINK_UP != 0 {
+            flags |= IFF_RUNNING | IFF_LOWER_UP;
+        }
+    }
+    if netif_is_loopback(n) {
+        flags |= IFF_LOOPBACK;
+    } else {
+        if (*n).flags & NETIF_FLAG_BROADCAST != 0 {
+            flags |= IFF_BROADCAST;
+        }
+        flags |= IFF_MULTICAST;
+    }
+    if crate::packet::interface_promiscuous(netif_index(n)) {
+        flags |= IFF_PROMISC;
+    }
+    flags
+}
+
+unsafe fn netif_linux_mtu(n: *const netif) -> u32 {
+    (*n).mtu as u32
+}
+
+unsafe fn netif_hw_type(n: *const netif) -> u16 {
+    if (*n).flags & NETIF_FLAG_ETHARP != 0 { ARPHRD_ETHER } else { ARPHRD_LOOPBACK }
+}
+
+unsafe fn netif_ip4(n: *const netif) -> (u32, u32, u32) {
+    ((*n).ip_addr.addr[0], (*n).netmask.addr[0], (*n).gw.addr[0])
+}
+
+unsafe fn netif_apply_flags(n: *mut netif, flags: u32) {
+    let up = (*n).flags & NETIF_FLAG_UP != 0;
+    if flags & IFF_UP != 0 && !up {
+        netif_set_up(n);
+    } else if flags & IFF_UP == 0 && up {
+        netif_set_down(n);
+    }
+}
+
+/// Userspace is configuring IPv4 itself (udhcpc, ip, ifconfig), so the
+/// in-kernel DHCP client started by lwipInitInThread() gets out of the way
+unsafe fn netif_take_over_ip4(n: *mut netif) {
+    if !netif_is_loopback(n) {
+        dhcp_release_and_stop(n);
+    }
+}
+
+unsafe fn netif_set_ip4(n: *mut netif, addr: u32, mask: u32) {
+    let (_, _, gw) = netif_ip4(n);
+    // a gateway outside the new subnet is no longer reachable
+    let gw = if addr != 0 && gw & mask == addr & mask { gw } else { 0 };
+    netif_set_addr(n, &ip4_addr_t { addr }, &ip4_addr_t { addr: mask }, &ip4_addr_t { addr: gw });
+}
+
+// ==========================
+// Interface access (AF_PACKET, firewall)
+// ==========================
+
+extern "C" {
+    fn pbuf_alloc(layer: c_int, length: u16, ty: c_int) -> *mut c_void;
+    fn pbuf_take(buf: *mut c_void, dataptr: *const c_void, len: u16) -> err_t;
+    fn pbuf_free(p: *mut c_void) -> u8;
+}
+
+const PBUF_RAW: c_int = 0;
+const PBUF_RAM: c_int = 0x280;
+
+type netif_linkoutput_fn = unsafe extern "C" fn(*mut netif, *mut c_void) -> err_t;
+
+#[derive(Clone, Copy)]
+pub(crate) struct InterfaceInfo {
+    pub index: u32,
+    pub hatype: u16,
+    pub hwaddr: [u8; 6],
+    pub mtu: u32,
+    pub up: bool,
+}
+
+unsafe fn interface_info_of(n: *const netif) -> InterfaceInfo {
+    let mut hwaddr = [0u8; 6];
+    if !netif_is_loopback(n) {
+        hwaddr = (*n).hwaddr;
+    }
+    InterfaceInfo {
+        index: netif_index(n),
+        hatype: netif_hw_type(n),
+        hwaddr,
+        mtu: netif_linux_mtu(n),
+        up: (*n).flags & NETIF_FLAG_UP != 0,
+    }
+}
+
+/// For the NIC drivers' frame taps, which hand over their embedded netif.
+/// Only reads the netif itself, so it's fine without the core lock (and
+/// from interrupt context).
+pub(crate) fn interface_of(n: *mut c_void) -> Option<InterfaceInfo> {
+    if n.is_null() {
+        return None;
+    }
+    Some(unsafe { interface_info_of(n as *const netif) })
+}
+
+/// Caller holds the CoreLock
+pub(crate) fn interface_info(index: u32) -> Option<InterfaceInfo> {
+    unsafe { netif_by_index(index).map(|n| interface_info_of(n)) }
+}
+
+/// Linux name of a netif handed over by lwIP. Caller holds the CoreLock.
+pub(crate) fn interface_name_of(n: *mut c_void) -> String {
+    unsafe { netif_linux_name(n as *const netif) }
+}
+
+/// The netif's IPv4 address in host byte order, 0 if it has none
+pub(crate) fn interface_ip4_of(n: *mut c_void) -> u32 {
+    unsafe { u32::from_be(netif_ip4(n as *const netif).0) }
+}
+
+/// Whether an IPv4 destination (host byte order) gets delivered here rather
+/// than forwarded: one of our addresses, a broadcast, multicast or anything
+/// in 127/8. Caller holds the CoreLock.
+pub(crate) fn is_local_ip4(addr: u32) -> bool {
+    if addr == u32::MAX || addr >> 28 == 0xe || addr >> 24 == 127 {
+        return true;
+    }
+    unsafe {
+        netif_iter().any(|n| {
+            let (ip, mask, _) = netif_ip4(n);
+            let (ip, mask) = (u32::from_be(ip), u32::from_be(mask));
+            ip != 0 && (addr == ip || (mask != u32::MAX && addr == ip | !mask))
+        })
+    }
+}
+
+/// A netif's IPv4 side in host byte order, for the routing table
+#[derive(Clone)]
+pub(crate) struct InterfaceIp4 {
+    pub netif: *mut c_void,
+    pub index: u32,
+    pub name: String,
+    pub addr: u32,
+    pub mask: u32,
+    pub gateway: u32,
+    // administratively up with a link
+    pub up: bool,
+    pub loopback: bool,
+}
+
+/// Every netif. Caller holds the CoreLock.
+pub(crate) fn interfaces_ip4() -> Vec<InterfaceIp4> {
+    unsafe {
+        netif_iter()
+            .map(|n| {
+                let (addr, mask, gw) = netif_ip4(n);
+                let up = NETIF_FLAG_UP | NETIF_FLAG_LINK_UP;
+                InterfaceIp4 {
+                    netif: n as *mut c_void,
+                    index: netif_index(n),
+                    name: netif_linux_name(n),
+                    addr: u32::from_be(addr),
+                    mask: u32::from_be(mask),
+                    gateway: u32::from_be(gw),
+                    up: (*n).flags & up == up,
+                    loopback: netif_is_loopback(n),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Puts a complete ethernet frame on the wire through the netif's
+/// linkoutput, bypassing lwIP's own layers. Caller holds the CoreLock.
+pub(crate) fn interface_transmit(index: u32, frame: &[u8]) -> Result<()> {
+    unsafe {
+        let n = netif_by_index(index).ok_or_else(|| errno(libc::ENXIO))?;
+        if (*n).flags & NETIF_FLAG_UP == 0 {
+            return Err(errno(libc::ENETDOWN));
+        }
+        // lo loops pbufs straight back into lwIP, there's no link layer
+        if netif_is_loopback(n) || (*n).linkoutput.is_null() {
+            return Err(errno(libc::EOPNOTSUPP));
+        }
+        if frame.len() > u16::MAX as usize {
+            return Err(errno(libc::EMSGSIZE));
+        }
+
+        let p = pbuf_alloc(PBUF_RAW, frame.len() as u16, PBUF_RAM);
+        if p.is_null() {
+            return Err(errno(libc::ENOBUFS));
+        }
+        pbuf_take(p, frame.as_ptr() as *const c_void, frame.len() as u16);
+
+        let linkoutput: netif_linkoutput_fn = std::mem::transmute((*n).linkoutput);
+        let err = linkoutput(n, p);
+        pbuf_free(p);
+
+        if err != ERR_OK {
+            return Err(errno(libc::EIO));
+        }
+        Ok(())
+    }
+}
+
+// ==========================
+// RTM_*LINK
+// ==========================
+
+unsafe fn link_message(n: *const netif, ty: u16, flags: u16, seq: u32, pid: u32) -> Vec<u8> {
+    let mut msg = NlMessage::new(ty, flags, seq, pid);
+    msg.put(&ifinfomsg {
+        ifi_family: AF_UNSPEC,
+        ifi_pad: 0,
+        ifi_type: netif_hw_type(n),
+        ifi_index: netif_index(n) as i32,
+        ifi_flags: netif_linux_flags(n),
+        ifi_change: 0,
+    });
+
+    msg.attr_str(IFLA_IFNAME, &netif_linux_name(n));
+    msg.attr_u32(IFLA_MTU, netif_linux_mtu(n));
+    msg.attr_u32(IFLA_TXQLEN, if netif_is_loopback(n) { 1000 } else { 0 });
+    msg.attr_str(IFLA_QDISC, "noqueue");
+
+    let running = netif_linux_flags(n) & IFF_RUNNING != 0;
+    msg.attr(IFLA_OPERSTATE, &[if running { IF_OPER_UP } else { IF_OPER_DOWN }]);
+    msg.attr(IFLA_LINKMODE, &[0]);
+
+    if netif_is_loopback(n) {
+        msg.attr(IFLA_ADDRESS, &[0; 6]);
+        msg.attr(IFLA_BROADCAST, &[0; 6]);
+    } else {
+        msg.attr(IFLA_ADDRESS, &(*n).hwaddr[..(*n).hwaddr_len as usize]);
+        msg.attr(IFLA_BROADCAST, &[0xff; 6]);
+    }
+
+    msg.finish()
+}
+
+unsafe fn link_lookup(info: &ifinfomsg, attrs: &[(u16, &[u8])]) -> Result<*mut netif> {
+    let found = if info.ifi_index > 0 {
+        netif_by_index(info.ifi_index as u32)
+    } else if let Some(name) = find_attr(attrs, IFLA_IFNAME) {
+        netif_by_name(attr_str(name))
+    } else {
+        return Err(errno(libc::EINVAL));
+    };
+    found.ok_or_else(|| errno(libc::ENODEV))
+}
+
+unsafe fn rtm_getlink(hdr: &nlmsghdr, payload: &[u8], pid: u32, out: &mut Vec<Vec<u8>>) -> Result<()> {
+    if hdr.nlmsg_flags & NLM_F_DUMP == NLM_F_DUMP {
+        for n in netif_iter() {
+            out.push(link_message(n, RTM_NEWLINK, NLM_F_MULTI, hdr.nlmsg_seq, pid));
+        }
+        out.push(done_message(hdr, pid));
+        return Ok(());
+    }
+
+    let info: ifinfomsg = read_struct(payload);
+    let attrs = parse_attrs(payload.get(std::mem::size_of::<ifinfomsg>()..).unwrap_or(&[]));
+    let n = link_lookup(&info, &attrs)?;
+    out.push(link_message(n, RTM_NEWLINK, 0, hdr.nlmsg_seq, pid));
+    Ok(())
+}
+
+unsafe fn rtm_setlink(hdr: &nlmsghdr, payload: &[u8]) -> Result<()> {
+    let info: ifinfomsg = read_struct(payload);
+    let attrs = parse_attrs(payload.get(std::mem::size_of::<ifinfomsg>()..).unwrap_or(&[]));
+
+    let n = match link_lookup(&info, &attrs) {
+        // there are no virtual links to create
+        Err(e) if hdr.nlmsg_type == RTM_NEWLINK && hdr.nlmsg_flags & NLM_F_CREATE != 0 => {
+            return Err(if e.raw_os_error() == Some(libc::ENODEV) { errno(libc::EOPNOTSUPP) } else { e });
+        }
+        other => other?,
+    };
+    if hdr.nlmsg_type == RTM_NEWLINK && hdr.nlmsg_flags & (NLM_F_CREATE | NLM_F_EXCL) == NLM_F_CREATE | NLM_F_EXCL {
+        return Err(errno(libc::EEXIST));
+    }
+
+    if let Some(name) = find_attr(&attrs, IFLA_IFNAME) {
+        if attr_str(name) != netif_linux_name(n).as_bytes() {
+            return Err(errno(libc::EOPNOTSUPP));
+        }
+    }
+    if let Some(addr) = find_attr(&attrs, IFLA_ADDRESS) {
+        // the NIC drivers program their MAC once at probe time
+        if netif_is_loopback(n) || addr != &(*n).hwaddr[..(*n).hwaddr_len as usize] {
+            return Err(errno(libc::EOPNOTSUPP));
+        }
+    }
+    if let Some(mtu) = find_attr(&attrs, IFLA_MTU) {
+        let mtu = attr_ip4(mtu)?;
+        if mtu < 68 {
+            return Err(errno(libc::EINVAL));
+        }
+        if !netif_is_loopback(n) {
+            (*n).mtu = mtu.min(u16::MAX as u32) as u16;
+        }
+    }
+
+    // ifi_change picks which of ifi_flags count, none at all means every one
+    if info.ifi_flags != 0 || info.ifi_change != 0 {
+        let flags = if info.ifi_change != 0 {
+            (info.ifi_flags & info.ifi_change) | (netif_linux_flags(n) & !info.ifi_change)
+        } else {
+            info.ifi_flags
+        };
+        netif_apply_flags(n, flags);
+    }
+
+    Ok(())
+}
+
+// ==========================
+// RTM_*ADDR
+// ==========================
+
+unsafe fn addr4_message(n: *const netif, addr: u32, mask: u32, ty: u16, flags: u16, seq: u32, pid: u32) -> Vec<u8> {
+    let mut msg = NlMessage::new(ty, flags, seq, pid);
+    msg.put(&ifaddrmsg {
+        ifa_family: AF_INET,
+        ifa_prefixlen: mask_to_prefix(mask),
+        ifa_flags: IFA_F_PERMANENT as u8,
+        ifa_scope: if netif_is_loopback(n) { RT_SCOPE_HOST } else { RT_SCOPE_UNIVERSE },
+        ifa_index: netif_index(n),
+    });
+    msg.attr(IFA_ADDRESS, &addr.to_ne_bytes());
+    msg.attr(IFA_LOCAL, &addr.to_ne_bytes());
+    if !netif_is_loopback(n) {
+        msg.attr(IFA_BROADCAST, &(addr | !mask).to_ne_bytes());
+    }
+    msg.attr_str(IFA_LABEL, &netif_linux_name(n));
+    msg.attr_u32(IFA_FLAGS, IFA_F_PERMANENT);
+    msg.finish()
+}
+
+fn ip6_is_linklocal(addr: &[u32; 4]) -> bool {
+    u32::from_be(addr[0]) & 0xffc0_0000 == 0xfe80_0000
+}
+
+// lwIP doesn't keep prefix lengths, SLAAC & link-local ones are all /64
+unsafe fn ip6_prefix(n: *const netif) -> u8 {
+    if netif_is_loopback(n) { 128 } else { 64 }
+}
+
+unsafe fn addr6_message(n: *const netif, addr: &ip_addr_t, state: u8, ty: u16, flags: u16, seq: u32, pid: u32) -> Vec<u8> {
+    let mut ifa_flags = 0;
+    if state & IP6_ADDR_TENTATIVE != 0 {
+        ifa_flags |= IFA_F_TENTATIVE;
+    } else if state & IP6_ADDR_VALID != 0 && state != IP6_ADDR_PREFERRED {
+        ifa_flags |= IFA_F_DEPRECATED;
+    }
+    if netif_is_loopback(n) || ip6_is_linklocal(&addr.addr) {
+        ifa_flags |= IFA_F_PERMANENT;
+    }
+
+    let scope = if netif_is_loopback(n) {
+        RT_SCOPE_HOST
+    } else if ip6_is_linklocal(&addr.addr) {
+        RT_SCOPE_LINK
+    } else {
+        RT_SCOPE_UNIVERSE
+    };
+
+    let mut msg = NlMessage::new(ty, flags, seq, pid);
+    msg.put(&ifaddrmsg {
+        ifa_family: AF_INET6,
+        ifa_prefixlen: ip6_prefix(n),
+        ifa_flags: ifa_flags as u8,
+        ifa_scope: scope,
+        ifa_index: netif_index(n),
+    });
+    msg.attr(IFA_ADDRESS, struct_bytes(&addr.addr));
+    msg.attr_u32(IFA_FLAGS, ifa_flags);
+    msg.finish()
+}
+
+unsafe fn rtm_getaddr(hdr: &nlmsghdr, payload: &[u8], pid: u32, out: &mut Vec<Vec<u8>>) -> Result<()> {
+    let filter: ifaddrmsg = read_struct(payload);
+
+    for n in netif_iter() {
+        if filter.ifa_index != 0 && filter.ifa_index != netif_index(n) {
+            continue;
+        }
+
+        let (addr, mask, _) = netif_ip4(n);
+        if filter.ifa_family != AF_INET6 && addr != 0 {
+            out.push(addr4_message(n, addr, mask, RTM_NEWADDR, NLM_F_MULTI, hdr.nlmsg_seq, pid));
+        }
+
+        if filter.ifa_family != AF_INET {
+            for i in 0..LWIP_IPV6_NUM_ADDRESSES {
+                let state = (*n).ip6_addr_state[i];
+                if state & (IP6_ADDR_VALID | IP6_ADDR_TENTATIVE) != 0 {
+                    out.push(addr6_message(n, &(*n).ip6_addr[i], state, RTM_NEWADDR, NLM_F_MULTI, hdr.nlmsg_seq, pid));
+                }
+            }
+        }
+    }
+
+    out.push(done_message(hdr, pid));
+    Ok(())
+}
+
+unsafe fn rtm_newaddr(hdr: &nlmsghdr, payload: &[u8]) -> Result<()> {
+    let ifa: ifaddrmsg = read_struct(payload);
+    let attrs = parse_attrs(payload.get(std::mem::size_of::<ifaddrmsg>()..).unwrap_or(&[]));
+    let n = netif_by_index(ifa.ifa_index).ok_or_else(|| errno(libc::ENODEV))?;
+    let local = find_attr(&attrs, IFA_LOCAL)
+        .or_else(|| find_attr(&attrs, IFA_ADDRESS))
+        .ok_or_else(|| errno(libc::EINVAL))?;
+
+    match ifa.ifa_family {
+        AF_INET => {
+            if ifa.ifa_prefixlen > 32 {
+                return Err(errno(libc::EINVAL));
+            }
+            let addr = attr_ip4(local)?;
+            let mask = prefix_to_mask(ifa.ifa_prefixlen);
+            let (current, current_mask, _) = netif_ip4(n);
+            if current == addr && current_mask == mask && hdr.nlmsg_flags & NLM_F_EXCL != 0 {
+                return Err(errno(libc::EEXIST));
+            }
+
+            // lwIP has a single IPv4 address per netif, a new one replaces it
+            netif_take_over_ip4(n);
+            netif_set_ip4(n, addr, mask);
+            Ok(())
+        }
+        AF_INET6 => {
+            let addr = ip6_addr_t { addr: attr_ip6(local)?, zone: 0 };
+            if netif_get_ip6_addr_match(n, &addr) >= 0 {
+                return if hdr.nlmsg_flags & NLM_F_EXCL != 0 { Err(errno(libc::EEXIST)) } else { Ok(()) };
+            }
+
+            let mut idx: i8 = -1;
+            if netif_add_ip6_address(n, &addr, &mut idx) != ERR_OK {
+                return Err(errno(libc::ENOSPC));
+            }
+
+            let mut flags = ifa.ifa_flags as u32;
+            if let Some(extended) = find_attr(&attrs, IFA_FLAGS) {
+                flags = attr_ip4(extended)?;
+            }
+            // otherwise lwIP runs duplicate address detection on it
+            if flags & IFA_F_NODAD != 0 || netif_is_loopback(n) {
+                netif_ip6_addr_set_state(n, idx, IP6_ADDR_PREFERRED);
+            }
+            Ok(())
+        }
+        _ => Err(errno(libc::EAFNOSUPPORT)),
+    }
+}
+
+unsafe fn rtm_deladdr(payload: &[u8]) -> Result<()> {
+    let ifa: ifaddrmsg = read_struct(payload);
+    let attrs = parse_attrs(payload.get(std::mem::size_of::<ifaddrmsg>()..).unwrap_or(&[]));
+    let n = netif_by_index(ifa.ifa_index).ok_or_else(|| errno(libc::ENODEV))?;
+    let local = find_attr(&attrs, IFA_LOCAL).or_else(|| find_attr(&attrs, IFA_ADDRESS));
+
+    match ifa.ifa_family {
+        AF_INET => {
+            let (current, _, _) = netif_ip4(n);
+            let wanted = match local {
+                Some(local) => attr_ip4(local)?,
+                None => current,
+            };
+            if current == 0 || wanted != current {
+                return Err(errno(libc::EADDRNOTAVAIL));
+            }
+            netif_take_over_ip4(n);
+            netif_set_ip4(n, 0, 0);
+            Ok(())
+        }
+        AF_INET6 => {
+            let addr = ip6_addr_t { addr: attr_ip6(local.ok_or_else(|| errno(libc::EINVAL))?)?, zone: 0 };
+            let idx = netif_get_ip6_addr_match(n, &addr);
+            if idx < 0 {
+                return Err(errno(libc::EADDRNOTAVAIL));
+            }
+            netif_ip6_addr_set_state(n, idx, IP6_ADDR_INVALID);
+            Ok(())
+        }
+        _ => Err(errno(libc::EAFNOSUPPORT)),
+    }
+}
+
+// ==========================
+// RTM_*ROUTE
+// ==========================
+
+unsafe fn route4_message(route: &Route, ty: u16, flags: u16, seq: u32, pid: u32) -> Vec<u8> {
+    let connected = route.gateway == 0;
+    let mut msg = NlMessage::new(ty, flags, seq, pid);
+    msg.put(&rtmsg {
+        rtm_family: AF_INET,
+        rtm_dst_len: route.dst_len,
+        rtm_table: RT_TABLE_MAIN,
+        rtm_protocol: route.protocol,
+        rtm_scope: if connected { RT_SCOPE_LINK } else { RT_SCOPE_UNIVERSE },
+        rtm_type: RTN_UNICAST,
+        ..Default::default()
+    });
+    msg.attr_u32(RTA_TABLE, RT_TABLE_MAIN as u32);
+    if route.dst_len > 0 {
+        msg.attr(RTA_DST, &route.dst.to_be_bytes());
+    }
+    if route.metric != 0 {
+        msg.attr_u32(RTA_PRIORITY, route.metric);
+    }
+    if !connected {
+        msg.attr(RTA_GATEWAY, &route.gateway.to_be_bytes());
+    } else if let Some(n) = netif_by_index(route.ifindex) {
+        msg.attr(RTA_PREFSRC, &(*n).ip_addr.addr[0].to_ne_bytes());
+    }
+    msg.attr_u32(RTA_OIF, route.ifindex);
+    msg.finish()
+}
+
+unsafe fn route6_message(n: *const netif, addr: &ip_addr_t, seq: u32, pid: u32) -> Vec<u8> {
+    let prefix = ip6_prefix(n);
+    let mut dst = addr.addr;
+    if prefix < 128 {
+        dst[2] = 0;
+        dst[3] = 0;
+    }
+
+    let mut msg = NlMessage::new(RTM_NEWROUTE, NLM_F_MULTI, seq, pid);
+    msg.put(&rtmsg {
+        rtm_family: AF_INET6,
+        rtm_dst_len: prefix,
+        rtm_table: RT_TABLE_MAIN,
+        rtm_protocol: RTPROT_KERNEL,
+        rtm_scope: RT_SCOPE_UNIVERSE,
+        rtm_type: RTN_UNICAST,
+        ..Default::default()
+    });
+    msg.attr_u32(RTA_TABLE, RT_TABLE_MAIN as u32);
+    msg.attr(RTA_DST, struct_bytes(&dst));
+    msg.attr_u32(RTA_OIF, netif_index(n));
+    msg.finish()
+}
+
+unsafe fn rtm_getroute(hdr: &nlmsghdr, payload: &[u8], pid: u32, out: &mut Vec<Vec<u8>>) -> Result<()> {
+    let rtm: rtmsg = read_struct(payload);
+
+    if hdr.nlmsg_flags & NLM_F_DUMP != NLM_F_DUMP {
+        // `ip route get`
+        if rtm.rtm_family != AF_INET {
+            return Err(errno(libc::EOPNOTSUPP));
+        }
+        let attrs = parse_attrs(payload.get(std::mem::size_of::<rtmsg>()..).unwrap_or(&[]));
+        let dst = attr_ip4(find_attr(&attrs, RTA_DST).ok_or_else(|| errno(libc::EINVAL))?)?;
+
+        let (route, _) = route_lookup(u32::from_be(dst)).ok_or_else(|| errno(libc::ENETUNREACH))?;
+        let host = Route { dst: u32::from_be(dst), dst_len: 32, ..route };
+        out.push(route4_message(&host, RTM_NEWROUTE, 0, hdr.nlmsg_seq, pid));
+        return Ok(());
+    }
+
+    if rtm.rtm_family != AF_INET6 {
+        for route in routes() {
+            out.push(route4_message(&route, RTM_NEWROUTE, NLM_F_MULTI, hdr.nlmsg_seq, pid));
+        }
+    }
+    if rtm.rtm_family != AF_INET {
+        // on-link prefixes only, lwIP's nd6 router list isn't reachable here
+        for n in netif_iter() {
+            for i in 0..LWIP_IPV6_NUM_ADDRESSES {
+                if (*n).ip6_addr_state[i] & IP6_ADDR_VALID != 0 && (*n).flags & NETIF_FLAG_UP != 0 {
+                    out.push(route6_message(n, &(*n).ip6_addr[i], hdr.nlmsg_seq, pid));
+                }
+            }
+        }
+    }
+
+    out.push(done_message(hdr, pid));
+    Ok(())
+}
+
+struct RouteTarget {
+    rtm: rtmsg,
+    dst: u32,
+    gateway: Option<u32>,
+    oif: Option<u32>,
+    metric: Option<u32>,
+}
+
+unsafe fn rtm_route_target(payload: &[u8]) -> Result<RouteTarget> {
+    let rtm: rtmsg = read_struct(payload);
+    if rtm.rtm_family != AF_INET {
+        return Err(errno(libc::EOPNOTSUPP));
+    }
+    if rtm.rtm_dst_len > 32 || (rtm.rtm_table != RT_TABLE_MAIN && rtm.rtm_table != 0) {
+        return Err(errno(libc::EINVAL));
+    }
+    let attrs = parse_attrs(payload.get(std::mem::size_of::<rtmsg>()..).unwrap_or(&[]));
+
+    let dst = find_attr(&attrs, RTA_DST).map(attr_ip4).transpose()?.unwrap_or(0);
+    let gateway = find_attr(&attrs, RTA_GATEWAY).map(attr_ip4).transpose()?;
+    let oif = match find_attr(&attrs, RTA_OIF) {
+        Some(oif) => Some(netif_index(netif_by_index(attr_ip4(oif)?).ok_or_else(|| errno(libc::ENODEV))?)),
+        None => None,
+    };
+    let metric = find_attr(&attrs, RTA_PRIORITY).map(attr_ip4).transpose()?;
+    Ok(RouteTarget {
+        rtm,
+        dst: u32::from_be(dst),
+        gateway: gateway.map(u32::from_be).filter(|&gw| gw != 0),
+        oif,
+        metric,
+    })
+}
+
+unsafe fn rtm_newroute(hdr: &nlmsghdr, payload: &[u8]) -> Result<()> {
+    let target = rtm_route_target(payload)?;
+    let route = Route {
+        dst: target.dst,
+        dst_len: target.rtm.rtm_dst_len,
+        gateway: target.gateway.unwrap_or(0),
+        ifindex: target.oif.unwrap_or(0),
+        metric: target.metric.unwrap_or(0),
+        protocol: if target.rtm.rtm_protocol != 0 { target.rtm.rtm_protocol } else { RTPROT_BOOT },
+    };
+
+    let route = route_add(route, hdr.nlmsg_flags & NLM_F_REPLACE != 0)?;
+    broadcast(RTMGRP_IPV4_ROUTE, route4_message(&route, RTM_NEWROUTE, 0, 0, 0));
+    Ok(())
+}
+
+unsafe fn rtm_delroute(payload: &[u8]) -> Result<()> {
+    let target = rtm_route_target(payload)?;
+    let dst_len = target.rtm.rtm_dst_len;
+
+    match route_delete(target.dst, dst_len, target.gateway, target.oif, target.metric) {
+        Ok(route) => {
+            broadcast(RTMGRP_IPV4_ROUTE, route4_message(&route, RTM_DELROUTE, 0, 0, 0));
+            Ok(())
+        }
+        // a DHCP default route goes with its netif's gateway, the gateway
+        // change notification tells listeners
+        Err(_) if dst_len == 0 => {
+            let n = netif_iter()
+                .find(|&n| {
+                    let gw = u32::from_be(netif_ip4(n).2);
+                    gw != 0
+                        && target.gateway.map_or(true, |g| g == gw)
+                        && target.oif.map_or(true, |o| o == netif_index(n))
+                })
+                .ok_or_else(|| errno(libc::ESRCH))?;
+            netif_take_over_ip4(n);
+            netif_set_gw(n, &ip4_addr_t { addr: 0 });
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// ==========================
+// Request dispatch
+// ==========================
+
+fn done_message(hdr: &nlmsghdr, pid: u32) -> Vec<u8> {
+    let mut msg = NlMessage::new(NLMSG_DONE, NLM_F_MULTI, hdr.nlmsg_seq, pid);
+    msg.put(&0i32);
+    msg.finish()
+}
+
+/// NLMSG_ERROR, `error` 0 being an ACK. Carries the request's header back.
+fn error_message(hdr: &nlmsghdr, pid: u32, error: c_int) -> Vec<u8> {
+    let mut msg = NlMessage::new(NLMSG_ERROR, 0, hdr.nlmsg_seq, pid);
+    msg.put(&-error);
+    msg.put(hdr);
+    msg.finish()
+}
+
+/// Everything one sendmsg() asked for, replies in order
+fn process_requests(data: &[u8], pid: u32) -> Vec<Vec<u8>> {
+    let mut replies = Vec::new();
+    let mut offset = 0;
+
+    while offset + std::mem::size_of::<nlmsghdr>() <= data.len() {
+        let hdr: nlmsghdr = read_struct(&data[offset..]);
+        let len = hdr.nlmsg_len as usize;
+        if len < std::mem::size_of::<nlmsghdr>() || offset + len > data.len() {
+            break;
+        }
+        let payload = &data[offset + std::mem::size_of::<nlmsghdr>()..offset + len];
+        offset += nl_align(len);
+
+        // control messages & replies from userspace are dropped, like Linux
+        if hdr.nlmsg_flags & NLM_F_REQUEST == 0 || hdr.nlmsg_type < NLMSG_MIN_TYPE {
+            continue;
+        }
+
+        let result = unsafe {
+            let _core = CoreLock::new();
+            match hdr.nlmsg_type {
+                RTM_GETLINK => rtm_getlink(&hdr, payload, pid, &mut replies),
+                RTM_NEWLINK | RTM_SETLINK => rtm_setlink(&hdr, payload),
+                RTM_DELLINK => Err(errno(libc::EOPNOTSUPP)),
+                RTM_GETADDR => rtm_getaddr(&hdr, payload, pid, &mut replies),
+                RTM_NEWADDR => rtm_newaddr(&hdr, payload),
+                RTM_DELADDR => rtm_deladdr(payload),
+                RTM_GETROUTE => rtm_getroute(&hdr, payload, pid, &mut replies),
+                RTM_NEWROUTE => rtm_newroute(&hdr, payload),
+                RTM_DELROUTE => rtm_delroute(payload),
+                _ => Err(errno(libc::EOPNOTSUPP)),
+            }
+        };
+
+        match result {
+            Err(e) => replies.push(error_message(&hdr, pid, e.raw_os_error().unwrap_or(libc::EINVAL))),
+            Ok(()) if hdr.nlmsg_flags & NLM_F_ACK != 0 => replies.push(error_message(&hdr, pid, 0)),
+            Ok(()) => {}
+        }
+    }
+
+    replies
+}
+
+// ==========================
+// Sockets
+// ==========================
+
+// SO_RCVBUF default; a full queue drops notifications and flags ENOBUFS
+const NETLINK_RCVBUF: usize = 212992;
+
+struct NetlinkState {
+    port: u32,
+    groups: u32,
+    rcvbuf: usize,
+    queue: VecDeque<Vec<u8>>,
+    queued: usize,
+    overrun: bool,
+}
+
+impl NetlinkState {
+    fn push(&mut self, msg: Vec<u8>, force: bool) -> bool {
+        if !force && self.queued + msg.len() > self.rcvbuf {
+            self.overrun = true;
+            return false;
+        }
+        self.queued += msg.len();
+        self.queue.push_back(msg);
+        true
+    }
+}
+
+static NETLINK_SOCKETS: Mutex<Vec<Weak<Mutex<NetlinkState>>>> = Mutex::new(Vec::new());
+static NETLINK_NEXT_PORT: Mutex<u32> = Mutex::new(-4096i32 as u32);
+
+fn port_in_use(port: u32) -> bool {
+    NETLINK_SOCKETS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .any(|s| s.lock().unwrap().port == port)
+}
+
+/// Notifications go to every socket in one of `groups`
+fn broadcast(groups: u32, msg: Vec<u8>) {
+    let mut sockets = NETLINK_SOCKETS.lock().unwrap();
+    sockets.retain(|s| s.strong_count() > 0);
+    for socket in sockets.iter().filter_map(Weak::upgrade) {
+        let mut state = socket.lock().unwrap();
+        if state.groups & groups != 0 {
+            state.push(msg.clone(), false);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NetlinkSocket {
+    state: Arc<Mutex<NetlinkState>>,
+    owner: u32,
+}
+
+impl NetlinkSocket {
+    pub fn new(protocol: c_int, owner: u32) -> Result<NetlinkSocket> {
+        if protocol != NETLINK_ROUTE {
+            return Err(errno(libc::EPROTONOSUPPORT));
+        }
+
+        let state = Arc::new(Mutex::new(NetlinkState {
+            port: 0,
+            groups: 0,
+            rcvbuf: NETLINK_RCVBUF,
+            queue: VecDeque::new(),
+            queued: 0,
+            overrun: false,
+        }));
+        NETLINK_SOCKETS.lock().unwrap().push(Arc::downgrade(&state));
+        Ok(NetlinkSocket { state, owner })
+    }
+
+    /// First free of the owner's pid and then counting down from -4096,
+    /// which is what Linux hands out as well
+    fn autobind(&self) -> u32 {
+        let port = self.state.lock().unwrap().port;
+        if port != 0 {
+            return port;
+        }
+
+        let mut candidate = self.owner;
+        while candidate == 0 || port_in_use(candidate) {
+            let mut next = NETLINK_NEXT_PORT.lock().unwrap();
+            candidate = *next;
+            *next = next.wrapping_sub(1);
+        }
+        self.state.lock().unwrap().port = candidate;
+        candidate
+    }
+
+    pub fn bind(&self, addr: *const sockaddr_nl, len: c_uint) -> Result<()> {
+        if addr.is_null() || (len as usize) < std::mem::size_of::<sockaddr_nl>() {
+            return Err(errno(libc::EINVAL));
+        }
+        let addr = unsafe { ptr::read_unaligned(addr) };
+        if addr.nl_family as c_int != AF_NETLINK {
+            return Err(errno(libc::EINVAL));
+        }
+
+        let bound = self.state.lock().unwrap().port;
+        if addr.nl_pid != 0 {
+            if bound != 0 && bound != addr.nl_pid {
+                return Err(errno(libc::EINVAL));
+            }
+            if bound == 0 {
+                if port_in_use(addr.nl_pid) {
+                    return Err(errno(libc::EADDRINUSE));
+                }
+                self.state.lock().unwrap().port = addr.nl_pid;
+            }
+        } else {
+            self.autobind();
+        }
+
+        self.state.lock().unwrap().groups = addr.nl_groups;
+        Ok(())
+    }
+
+    pub fn getsockname(&self, addr: *mut sockaddr_nl, len: &mut c_uint) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        let local = sockaddr_nl {
+            nl_family: AF_NETLINK as u16,
+            nl_pad: 0,
+            nl_pid: state.port,
+            nl_groups: state.groups,
+        };
+        let copy = (*len as usize).min(std::mem::size_of::<sockaddr_nl>());
+        unsafe { ptr::copy_nonoverlapping(&local as *const sockaddr_nl as *const u8, addr as *mut u8, copy) };
+        *len = std::mem::size_of::<sockaddr_nl>() as c_uint;
+        Ok(())
+    }
+
+    /// Requests always go to the kernel (port 0), multicasting between
+    /// userspace sockets isn't supported
+    pub fn send(&self, buf: &[u8], dest: Option<&sockaddr_nl>) -> Result<usize> {
+        if let Some(dest) = dest {
+            if dest.nl_family as c_int != AF_NETLINK {
+                return Err(errno(libc::EINVAL));
+            }
+            if dest.nl_pid != 0 || dest.nl_groups != 0 {
+                return Err(errno(libc::EPERM));
+            }
+        }
+
+        let port = self.autobind();
+        let replies = process_requests(buf, port);
+
+        // replies are never dropped, a dump is only ever asked for once
+        let mut state = self.state.lock().unwrap();
+        for reply in replies {
+            state.push(reply, true);
+        }
+        Ok(buf.len())
+    }
+
+    /// One message per call, like every netlink family on Linux. Returns
+    /// (bytes copied, full message length) for MSG_TRUNC.
+    pub fn recv(&self, buf: &mut [u8], peek: bool, nonblock: bool) -> Result<(usize, usize)> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.overrun {
+                    state.overrun = false;
+                    return Err(errno(libc::ENOBUFS));
+                }
+                if let Some(msg) = state.queue.front() {
+                    let len = msg.len();
+                    let copy = len.min(buf.len());
+                    buf[..copy].copy_from_slice(&msg[..copy]);
+                    if !peek {
+                        state.queued -= len;
+                        state.queue.pop_front();
+                    }
+                    return Ok((copy, len));
+                }
+            }
+
+            if nonblock {
+                return Err(errno(libc::EAGAIN));
+            }
+            if unsafe { signalsPendingQuick(currentTask) } {
+                return Err(errno(libc::EINTR));
+            }
+            thread::yield_now();
+        }
+    }
+
+    pub fn readable(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.queue.is_empty() || state.overrun
+    }
+
+    /// Peer of every message: the kernel
+    pub fn source() -> sockaddr_nl {
+        sockaddr_nl { nl_family: AF_NETLINK as u16, ..Default::default() }
+    }
+
+    pub fn setsockopt(&self, level: c_int, optname: c_int, optval: *const c_void, optlen: c_uint) -> Result<()> {
+        if optval.is_null() {
+            return Err(errno(libc::EFAULT));
+        }
+        if (optlen as usize) < std::mem::size_of::<c_int>() {
+            return Err(errno(libc::EINVAL));
+        }
+        let value = unsafe { ptr::read_unaligned(optval as *const c_int) };
+
+        match (level, optname) {
+            (SOL_NETLINK, NETLINK_ADD_MEMBERSHIP | NETLINK_DROP_MEMBERSHIP) => {
+                // groups count from 1, past 32 there's nothing in NETLINK_ROUTE we send
+                if value <= 0 || value > 32 {
+                    return Err(errno(libc::EINVAL));
+                }
+                let mut state = self.state.lock().unwrap();
+                let bit = 1u32 << (value - 1);
+                if optname == NETLINK_ADD_MEMBERSHIP { state.groups |= bit } else { state.groups &= !bit }
+                Ok(())
+            }
+            (SOL_NETLINK, NETLINK_PKTINFO | NETLINK_EXT_ACK | NETLINK_GET_STRICT_CHK) => Ok(()),
+            (SOL_SOCKET, SO_RCVBUF) => {
+                self.state.lock().unwrap().rcvbuf = (value.max(0) as usize).saturating_mul(2).max(NETLINK_RCVBUF);
+                Ok(())
+            }
+            (SOL_SOCKET, SO_SNDBUF) => Ok(()),
+            _ => Err(errno(libc::ENOPROTOOPT)),
+        }
+    }
+
+    pub fn getsockopt(&self, level: c_int, optname: c_int, optval: *mut c_void, optlen: &mut c_uint) -> Result<()> {
+        if optval.is_null() {
+            return Err(errno(libc::EFAULT));
+        }
+        let value: c_int = match (level, optname) {
+            (SOL_SOCKET, SO_TYPE) => libc::SOCK_RAW,
+            (SOL_SOCKET, SO_PROTOCOL) => NETLINK_ROUTE,
+            (SOL_SOCKET, SO_DOMAIN) => AF_NETLINK,
+            (SOL_SOCKET, SO_RCVBUF) => self.state.lock().unwrap().rcvbuf as c_int,
+            (SOL_SOCKET, SO_SNDBUF) => NETLINK_RCVBUF as c_int,
+            _ => return Err(errno(libc::ENOPROTOOPT)),
+        };
+        let len = (*optlen as usize).min(std::mem::size_of::<c_int>());
+        unsafe { ptr::copy_nonoverlapping(&value as *const c_int as *const u8, optval as *mut u8, len) };
+        *optlen = len as c_uint;
+        Ok(())
+    }
+
+    pub fn duplicate(&self) -> NetlinkSocket {
+        self.clone()
+    }
+}
+
+// ==========================
+// Notifications (lwIP ext status callback, tcpip thread)
+// ==========================
+
+static mut NETLINK_EXT_CALLBACK: netif_ext_callback_t = netif_ext_callback_t {
+    callback_fn: None,
+    next: ptr::null_mut(),
+};
+
+extern "C" fn netlink_netif_changed(n: *mut netif, reason: u16, args: *const netif_ext_callback_args_t) {
+    unsafe {
+        if reason & LWIP_NSC_NETIF_ADDED != 0 {
+            broadcast(RTMGRP_LINK, link_message(n, RTM_NEWLINK, 0, 0, 0));
+        }
+        if reason & LWIP_NSC_NETIF_REMOVED != 0 {
+            broadcast(RTMGRP_LINK, link_message(n, RTM_DELLINK, 0, 0, 0));
+            return;
+        }
+        if reason & (LWIP_NSC_LINK_CHANGED | LWIP_NSC_STATUS_CHANGED) != 0 {
+            broadcast(RTMGRP_LINK, link_message(n, RTM_NEWLINK, 0, 0, 0));
+        }
+
+        if args.is_null() {
+            return;
+        }
+        let (addr, mask, gw) = netif_ip4(n);
+
+        let ipv4 = LWIP_NSC_IPV4_ADDRESS_CHANGED | LWIP_NSC_IPV4_NETMASK_CHANGED | LWIP_NSC_IPV4_GATEWAY_CHANGED;
+        if reason & ipv4 != 0 && !netif_is_loopback(n) {
+            netIpv4Configure((*n).hwaddr.as_ptr(), u32::from_be(addr), u32::from_be(mask), u32::from_be(gw));
+        }
+
+        if reason & (LWIP_NSC_IPV4_ADDRESS_CHANGED | LWIP_NSC_IPV4_NETMASK_CHANGED) != 0 {
+            let changed = (*args).ipv4_changed;
+            let old_addr = if reason & LWIP_NSC_IPV4_ADDRESS_CHANGED != 0 && !changed.old_address.is_null() {
+                (*changed.old_address).addr[0]
+            } else {
+                addr
+            };
+            let old_mask = if reason & LWIP_NSC_IPV4_NETMASK_CHANGED != 0 && !changed.old_netmask.is_null() {
+                (*changed.old_netmask).addr[0]
+            } else {
+                mask
+            };
+            if old_addr != 0 {
+                broadcast(RTMGRP_IPV4_IFADDR, addr4_message(n, old_addr, old_mask, RTM_DELADDR, 0, 0, 0));
+            }
+            if addr != 0 {
+                broadcast(RTMGRP_IPV4_IFADDR, addr4_message(n, addr, mask, RTM_NEWADDR, 0, 0, 0));
+            }
+        }
+
+        if reason & LWIP_NSC_IPV4_GATEWAY_CHANGED != 0 {
+            let old_gw = (*args).ipv4_changed.old_gw;
+            if !old_gw.is_null() && (*old_gw).addr[0] != 0 {
+                let route = gateway_route(netif_index(n), u32::from_be((*old_gw).addr[0]));
+                broadcast(RTMGRP_IPV4_ROUTE, route4_message(&route, RTM_DELROUTE, 0, 0, 0));
+            }
+            if gw != 0 {
+                let route = gateway_route(netif_index(n), u32::from_be(gw));
+                broadcast(RTMGRP_IPV4_ROUTE, route4_message(&route, RTM_NEWROUTE, 0, 0, 0));
+            }
+        }
+
+        if reason & LWIP_NSC_IPV6_SET != 0 {
+            let set = (*args).ipv6_set;
+            if !set.old_address.is_null() && (*set.old_address).ty == IPADDR_TYPE_V6 && (*set.old_address).addr != [0; 4] {
+                broadcast(RTMGRP_IPV6_IFADDR, addr6_message(n, &*set.old_address, IP6_ADDR_PREFERRED, RTM_DELADDR, 0, 0, 0));
+            }
+        }
+
+        if reason & LWIP_NSC_IPV6_ADDR_STATE_CHANGED != 0 {
+            let changed = (*args).ipv6_addr_state_changed;
+            let idx = changed.addr_index as usize;
+            if idx < LWIP_IPV6_NUM_ADDRESSES && !changed.address.is_null() {
+                let state = (*n).ip6_addr_state[idx];
+                if state & (IP6_ADDR_VALID | IP6_ADDR_TENTATIVE) != 0 {
+                    broadcast(RTMGRP_IPV6_IFADDR, addr6_message(n, &*changed.address, state, RTM_NEWADDR, 0, 0, 0));
+                } else if changed.old_state & (IP6_ADDR_VALID | IP6_ADDR_TENTATIVE) != 0 {
+                    broadcast(RTMGRP_IPV6_IFADDR, addr6_message(n, &*changed.address, changed.old_state, RTM_DELADDR, 0, 0, 0));
+                }
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn netlinkInit() {
+    let _core = CoreLock::new();
+    unsafe { netif_add_ext_callback(ptr::addr_of_mut!(NETLINK_EXT_CALLBACK), netlink_netif_changed) };
+}
+
+// ==========================
+// SIOCGIF* / SIOCSIF*
+// ==========================
+
+pub const SIOCGIFNAME: u64 = 0x8910;
+pub const SIOCGIFCONF: u64 = 0x8912;
+pub const SIOCGIFFLAGS: u64 = 0x8913;
+pub const SIOCSIFFLAGS: u64 = 0x8914;
+pub const SIOCGIFADDR: u64 = 0x8915;
+pub const SIOCSIFADDR: u64 = 0x8916;
+pub const SIOCGIFBRDADDR: u64 = 0x8919;
+pub const SIOCGIFNETMASK: u64 = 0x891b;
+pub const SIOCSIFNETMASK: u64 = 0x891c;
+pub const SIOCGIFMTU: u64 = 0x8921;
+pub const SIOCSIFMTU: u64 = 0x8922;
+pub const SIOCGIFHWADDR: u64 = 0x8927;
+pub const SIOCGIFINDEX: u64 = 0x8933;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct sockaddr_in_linux {
+    sin_family: u16,
+    sin_port: u16,
+    sin_addr: u32,
+    sin_zero: [u8; 8],
+}
+
+/// struct ifreq: the name and a 24 byte union
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ifreq_linux {
+    ifr_name: [u8; IFNAMSIZ],
+    ifr_data: [u8; 24],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ifconf_linux {
+    ifc_len: c_int,
+    ifc_buf: *mut ifreq_linux,
+}
+
+pub fn is_interface_ioctl(request: u64) -> bool {
+    matches!(
+        request,
+        SIOCGIFNAME
+            | SIOCGIFCONF
+            | SIOCGIFFLAGS
+            | SIOCSIFFLAGS
+            | SIOCGIFADDR
+            | SIOCSIFADDR
+            | SIOCGIFBRDADDR
+            | SIOCGIFNETMASK
+            | SIOCSIFNETMASK
+            | SIOCGIFMTU
+            | SIOCSIFMTU
+            | SIOCGIFHWADDR
+            | SIOCGIFINDEX
+            | SIOCADDRT
+            | SIOCDELRT
+    )
+}
+
+fn ifreq_set<T>(req: &mut ifreq_linux, value: &T) {
+    req.ifr_data = [0; 24];
+    let len = std::mem::size_of::<T>().min(24);
+    unsafe { ptr::copy_nonoverlapping(value as *const T as *const u8, req.ifr_data.as_mut_ptr(), len) };
+}
+
+fn ifreq_get<T: Copy + Default>(req: &ifreq_linux) -> T {
+    read_struct(&req.ifr_data)
+}
+
+fn ifreq_sockaddr_in(addr: u32) -> sockaddr_in_linux {
+    sockaddr_in_linux { sin_family: AF_INET as u16, sin_port: 0, sin_addr: addr, sin_zero: [0; 8] }
+}
+
+fn ifreq_read_in(req: &ifreq_linux) -> Result<u32> {
+    let family = u16::from_ne_bytes([req.ifr_data[0], req.ifr_data[1]]);
+    if family != AF_INET as u16 {
+        return Err(errno(libc::EINVAL));
+    }
+    Ok(u32::from_ne_bytes([req.ifr_data[4], req.ifr_data[5], req.ifr_data[6], req.ifr_data[7]]))
+}
+
+unsafe fn ifreq_netif(req: &ifreq_linux) -> Result<*mut netif> {
+    netif_by_name(attr_str(&req.ifr_name)).ok_or_else(|| errno(libc::ENODEV))
+}
+
+unsafe fn ifconf(conf: *mut ifconf_linux) -> Result<usize> {
+    let size = std::mem::size_of::<ifreq_linux>();
+    let configured: Vec<*mut netif> = netif_iter().filter(|&n| netif_ip4(n).0 != 0).collect();
+
+    // a NULL buffer asks how much room it takes
+    if (*conf).ifc_buf.is_null() {
+        (*conf).ifc_len = (configured.len() * size) as c_int;
+        return Ok(0);
+    }
+
+    let room = (*conf).ifc_len.max(0) as usize / size;
+    let mut written = 0;
+    for &n in configured.iter().take(room) {
+        let mut req = ifreq_linux { ifr_name: [0; IFNAMSIZ], ifr_data: [0; 24] };
+        let name = netif_linux_name(n);
+        req.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+        ifreq_set(&mut req, &ifreq_sockaddr_in(netif_ip4(n).0));
+        ptr::write_unaligned((*conf).ifc_buf.add(written), req);
+        written += 1;
+    }
+    (*conf).ifc_len = (written * size) as c_int;
+    Ok(0)
+}
+
+/// Interface ioctls, valid on any socket like on Linux
+pub fn interface_ioctl(request: u64, arg: *mut c_void) -> Result<usize> {
+    if arg.is_null() {
+        return Err(errno(libc::EFAULT));
+    }
+
+    unsafe {
+        let _core = CoreLock::new();
+
+        if request == SIOCGIFCONF {
+            return ifconf(arg as *mut ifconf_linux);
+        }
+        if request == SIOCADDRT || request == SIOCDELRT {
+            let route = route_ioctl(request, arg)?;
+            let ty = if request == SIOCADDRT { RTM_NEWROUTE } else { RTM_DELROUTE };
+            broadcast(RTMGRP_IPV4_ROUTE, route4_message(&route, ty, 0, 0, 0));
+            return Ok(0);
+        }
+
+        let mut req: ifreq_linux = ptr::read_unaligned(arg as *const ifreq_linux);
+
+        // the only one looking up by index rather than by name
+        if request == SIOCGIFNAME {
+            let index = ifreq_get::<c_int>(&req);
+            let n = netif_by_index(index as u32).ok_or_else(|| errno(libc::ENODEV))?;
+            let name = netif_linux_name(n);
+            req.ifr_name = [0; IFNAMSIZ];
+            req.ifr_name[..name.len()].copy_from_slice(name.as_bytes());
+            ptr::write_unaligned(arg as *mut ifreq_linux, req);
+            return Ok(0);
+        }
+
+        let n = ifreq_netif(&req)?;
+        let (addr, mask, _) = netif_ip4(n);
+
+        match request {
+            SIOCGIFFLAGS => ifreq_set(&mut req, &(netif_linux_flags(n) as i16)),
+            SIOCSIFFLAGS => netif_apply_flags(n, ifreq_get::<i16>(&req) as u16 as u32),
+            SIOCGIFINDEX => ifreq_set(&mut req, &(netif_index(n) as c_int)),
+            SIOCGIFMTU => ifreq_set(&mut req, &(netif_linux_mtu(n) as c_int)),
+            SIOCSIFMTU => {
+                let mtu = ifreq_get::<c_int>(&req);
+                if mtu < 68 {
+                    return Err(errno(libc::EINVAL));
+                }
+                if !netif_is_loopback(n) {
+                    (*n).mtu = (mtu as u32).min(u16::MAX as u32) as u16;
+                }
+            }
+            SIOCGIFHWADDR => {
+                let mut hw = [0u8; 16];
+                hw[..2].copy_from_slice(&netif_hw_type(n).to_ne_bytes());
+                if !netif_is_loopback(n) {
+                    hw[2..8].copy_from_slice(&(*n).hwaddr);
+                }
+                ifreq_set(&mut req, &hw);
+            }
+            SIOCGIFADDR | SIOCGIFNETMASK | SIOCGIFBRDADDR => {
+                if addr == 0 {
+                    return Err(errno(libc::EADDRNOTAVAIL));
+                }
+                let value = match request {
+                    SIOCGIFADDR => addr,
+                    SIOCGIFNETMASK => mask,
+                    _ => addr | !mask,
+                };
+                ifreq_set(&mut req, &ifreq_sockaddr_in(value));
+            }
+            SIOCSIFADDR => {
+                let new = ifreq_read_in(&req)?;
+                let mask = if mask != 0 { mask } else { classful_mask(new) };
+                netif_take_over_ip4(n);
+                netif_set_ip4(n, new, if new != 0 { mask } else { 0 });
+            }
+            SIOCSIFNETMASK => {
+                let new = ifreq_read_in(&req)?;
+                netif_take_over_ip4(n);
+                netif_set_ip4(n, addr, new);
+            }
+            _ => return Err(errno(libc::ENOTTY)),
+        }
+
+        ptr::write_unaligned(arg as *mut ifreq_linux, req);
+        Ok(0)
+    }
+}
@@ -0,0 +1,931 @@
+use std::collections::VecDeque;
+use std::io::{Error, Result};
+use std::os::raw::{c_int, c_uint, c_void};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bpf::*;
+use crate::netlink::{interface_info, interface_of, interface_transmit, CoreLock};
+use crate::vmm::{virtual_allocate_physically_contiguous, virtual_free};
+
+//
+// AF_PACKET: every ethernet frame the NIC drivers receive (netQueueAdd) or
+// put on the wire (sendPacketRaw) gets offered to packet sockets, after
+// their classic BPF filter if one's attached. Frames end up either in the
+// socket's queue for recvmsg() or in a TPACKET_V1/V2 ring shared with
+// userspace, which is what libpcap insists on.
+//
+
+// ==========================
+// Linux ABI
+// ==========================
+
+pub const AF_PACKET: c_int = 17;
+
+const ETH_P_ALL: u16 = 0x0003;
+const ETH_HLEN: usize = 14;
+const ETH_ALEN: usize = 6;
+
+const PACKET_HOST: u8 = 0;
+const PACKET_BROADCAST: u8 = 1;
+const PACKET_MULTICAST: u8 = 2;
+const PACKET_OTHERHOST: u8 = 3;
+const PACKET_OUTGOING: u8 = 4;
+
+const SOL_SOCKET: c_int = 1;
+const SOL_PACKET: c_int = 263;
+
+const SO_TYPE: c_int = 3;
+const SO_ERROR: c_int = 4;
+const SO_SNDBUF: c_int = 7;
+const SO_RCVBUF: c_int = 8;
+const SO_ATTACH_FILTER: c_int = 26;
+const SO_DETACH_FILTER: c_int = 27;
+const SO_PROTOCOL: c_int = 38;
+const SO_DOMAIN: c_int = 39;
+const SO_LOCK_FILTER: c_int = 44;
+
+const PACKET_ADD_MEMBERSHIP: c_int = 1;
+const PACKET_DROP_MEMBERSHIP: c_int = 2;
+const PACKET_RX_RING: c_int = 5;
+const PACKET_STATISTICS: c_int = 6;
+const PACKET_AUXDATA: c_int = 8;
+const PACKET_VERSION: c_int = 10;
+const PACKET_HDRLEN: c_int = 11;
+const PACKET_RESERVE: c_int = 12;
+const PACKET_IGNORE_OUTGOING: c_int = 23;
+
+const PACKET_MR_MULTICAST: u16 = 0;
+const PACKET_MR_PROMISC: u16 = 1;
+const PACKET_MR_ALLMULTI: u16 = 2;
+
+const TPACKET_V1: c_int = 0;
+const TPACKET_V2: c_int = 1;
+
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TP_STATUS_LOSING: u32 = 4;
+
+const TPACKET_ALIGNMENT: usize = 16;
+
+/// struct sockaddr_ll
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct sockaddr_ll {
+    pub sll_family: u16,
+    pub sll_protocol: u16,
+    pub sll_ifindex: i32,
+    pub sll_hatype: u16,
+    pub sll_pkttype: u8,
+    pub sll_halen: u8,
+    pub sll_addr: [u8; 8],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct packet_mreq {
+    mr_ifindex: c_int,
+    mr_type: u16,
+    mr_alen: u16,
+    mr_address: [u8; 8],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct tpacket_req {
+    tp_block_size: c_uint,
+    tp_block_nr: c_uint,
+    tp_frame_size: c_uint,
+    tp_frame_nr: c_uint,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct tpacket_stats {
+    tp_packets: c_uint,
+    tp_drops: c_uint,
+}
+
+/// TPACKET_V1 frame header
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct tpacket_hdr {
+    tp_status: u64,
+    tp_len: u32,
+    tp_snaplen: u32,
+    tp_mac: u16,
+    tp_net: u16,
+    tp_sec: u32,
+    tp_usec: u32,
+}
+
+/// TPACKET_V2 frame header
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct tpacket2_hdr {
+    tp_status: u32,
+    tp_len: u32,
+    tp_snaplen: u32,
+    tp_mac: u16,
+    tp_net: u16,
+    tp_sec: u32,
+    tp_nsec: u32,
+    tp_vlan_tci: u16,
+    tp_vlan_tpid: u16,
+    tp_padding: [u8; 4],
+}
+
+fn errno(code: c_int) -> Error {
+    Error::from_raw_os_error(code)
+}
+
+extern "C" {
+    static currentTask: *mut c_void;
+    fn signalsPendingQuick(task: *mut c_void) -> bool;
+}
+
+fn tpacket_align(len: usize) -> usize {
+    (len + TPACKET_ALIGNMENT - 1) & !(TPACKET_ALIGNMENT - 1)
+}
+
+fn read_opt<T: Copy + Default>(optval: *const c_void, optlen: c_uint) -> Result<T> {
+    if optval.is_null() {
+        return Err(errno(libc::EFAULT));
+    }
+    if (optlen as usize) < std::mem::size_of::<T>() {
+        return Err(errno(libc::EINVAL));
+    }
+    Ok(unsafe { ptr::read_unaligned(optval as *const T) })
+}
+
+fn write_opt<T>(value: &T, optval: *mut c_void, optlen: &mut c_uint) {
+    let len = (*optlen as usize).min(std::mem::size_of::<T>());
+    unsafe { ptr::copy_nonoverlapping(value as *const T as *const u8, optval as *mut u8, len) };
+    *optlen = len as c_uint;
+}
+
+// ==========================
+// Promiscuous mode
+// ==========================
+
+// interface index is netif num + 1, so 256 at most. The drivers already
+// accept every unicast frame (RTL8139: RCR_AAP), so promiscuous mode only
+// decides whether frames for other hosts go past the tap.
+static PROMISC: [AtomicU32; 257] = [const { AtomicU32::new(0) }; 257];
+
+pub fn interface_promiscuous(index: u32) -> bool {
+    PROMISC
+        .get(index as usize)
+        .map_or(false, |count| count.load(Ordering::Relaxed) > 0)
+}
+
+// ==========================
+// Ring (PACKET_RX_RING)
+// ==========================
+
+// libpcap's default is 2MiB, anything close to this is a typo
+const RING_MAX: usize = 64 * 1024 * 1024;
+const PAGE_SIZE: usize = 0x1000;
+
+struct Ring {
+    base: *mut u8,
+    size: usize,
+    block_size: usize,
+    frame_size: usize,
+    frames_per_block: usize,
+    frame_nr: usize,
+    head: usize,
+    mapped: bool,
+}
+
+// the buffer belongs to the ring, access is serialised by the socket's lock
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(req: &tpacket_req, hdrlen: usize) -> Result<Ring> {
+        let block_size = req.tp_block_size as usize;
+        let frame_size = req.tp_frame_size as usize;
+        if block_size == 0 || block_size % PAGE_SIZE != 0 {
+            return Err(errno(libc::EINVAL));
+        }
+        if frame_size < hdrlen || frame_size % TPACKET_ALIGNMENT != 0 {
+            return Err(errno(libc::EINVAL));
+        }
+        let frames_per_block = block_size / frame_size;
+        if frames_per_block == 0 || frames_per_block * req.tp_block_nr as usize != req.tp_frame_nr as usize {
+            return Err(errno(libc::EINVAL));
+        }
+
+        let size = block_size
+            .checked_mul(req.tp_block_nr as usize)
+            .filter(|&size| size <= RING_MAX)
+            .ok_or_else(|| errno(libc::ENOMEM))?;
+        let base = unsafe { virtual_allocate_physically_contiguous((size / PAGE_SIZE) as i32) } as *mut u8;
+        if base.is_null() {
+            return Err(errno(libc::ENOMEM));
+        }
+        unsafe { ptr::write_bytes(base, 0, size) };
+
+        Ok(Ring {
+            base,
+            size,
+            block_size,
+            frame_size,
+            frames_per_block,
+            frame_nr: req.tp_frame_nr as usize,
+            head: 0,
+            mapped: false,
+        })
+    }
+
+    fn frame(&self, index: usize) -> *mut u8 {
+        let block = index / self.frames_per_block;
+        let slot = index % self.frames_per_block;
+        unsafe { self.base.add(block * self.block_size + slot * self.frame_size) }
+    }
+
+    /// tp_status sits at the start of both header versions, V1 has it as a
+    /// long but only the low bits are ever used
+    fn status(&self, index: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.frame(index) as *const u32) }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { virtual_free(self.base as *mut c_void, (self.size / PAGE_SIZE) as i32) };
+    }
+}
+
+// ==========================
+// Sockets
+// ==========================
+
+const PACKET_RCVBUF: usize = 212992;
+
+struct QueuedFrame {
+    data: Vec<u8>,
+    len: usize,
+    addr: sockaddr_ll,
+}
+
+struct PacketState {
+    ty: c_int,
+    protocol: u16,
+    ifindex: u32,
+    filter: Option<Vec<SockFilter>>,
+    filter_locked: bool,
+    memberships: Vec<u32>,
+    ignore_outgoing: bool,
+
+    rcvbuf: usize,
+    queue: VecDeque<QueuedFrame>,
+    queued: usize,
+
+    version: c_int,
+    reserve: usize,
+    ring: Option<Ring>,
+    losing: bool,
+
+    packets: u32,
+    drops: u32,
+}
+
+struct PacketInner {
+    state: Mutex<PacketState>,
+    // frames lost to lock contention in the interrupt path
+    contended: AtomicU32,
+}
+
+impl Drop for PacketInner {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        for &index in &state.memberships {
+            PROMISC[index as usize].fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+static PACKET_SOCKETS: Mutex<Vec<Weak<PacketInner>>> = Mutex::new(Vec::new());
+
+// the socket whose frame is being transmitted right now, which doesn't get
+// it back as PACKET_OUTGOING. Only touched under the CoreLock.
+static TX_ORIGIN: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the frame going out right now was written to a packet socket,
+/// which the firewall leaves alone
+pub(crate) fn packet_transmitting() -> bool {
+    TX_ORIGIN.load(Ordering::Relaxed) != 0
+}
+
+/// What every socket gets told about a frame
+struct FrameInfo {
+    ifindex: u32,
+    hatype: u16,
+    pkttype: u8,
+    protocol: u16,
+}
+
+fn frame_timestamp() -> (u32, u32) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs() as u32, now.subsec_nanos())
+}
+
+impl PacketState {
+    fn wants(&self, info: &FrameInfo) -> bool {
+        if self.ifindex != 0 && self.ifindex != info.ifindex {
+            return false;
+        }
+        // transmitted frames only go to ETH_P_ALL taps (dev_queue_xmit_nit)
+        if info.pkttype == PACKET_OUTGOING {
+            return self.protocol == ETH_P_ALL && !self.ignore_outgoing;
+        }
+        self.protocol == ETH_P_ALL || (self.protocol != 0 && self.protocol == info.protocol)
+    }
+
+    fn source(&self, frame: &[u8], info: &FrameInfo) -> sockaddr_ll {
+        let mut addr = sockaddr_ll {
+            sll_family: AF_PACKET as u16,
+            sll_protocol: info.protocol.to_be(),
+            sll_ifindex: info.ifindex as i32,
+            sll_hatype: info.hatype,
+            sll_pkttype: info.pkttype,
+            sll_halen: ETH_ALEN as u8,
+            sll_addr: [0; 8],
+        };
+        addr.sll_addr[..ETH_ALEN].copy_from_slice(&frame[ETH_ALEN..2 * ETH_ALEN]);
+        addr
+    }
+
+    fn deliver(&mut self, frame: &[u8], info: &FrameInfo) {
+        // SOCK_DGRAM never sees the link layer header
+        let (data, link_offset, net_offset) = if self.ty == libc::SOCK_DGRAM {
+            (&frame[ETH_HLEN..], None, 0)
+        } else {
+            (frame, Some(0), ETH_HLEN)
+        };
+
+        let mut snaplen = data.len();
+        if let Some(filter) = &self.filter {
+            let packet = BpfPacket {
+                data,
+                link_offset,
+                net_offset,
+                protocol: info.protocol,
+                pkttype: info.pkttype,
+                ifindex: info.ifindex,
+            };
+            snaplen = snaplen.min(bpf_run(filter, &packet) as usize);
+            if snaplen == 0 {
+                return;
+            }
+        }
+
+        let addr = self.source(frame, info);
+        let delivered = if self.ring.is_some() {
+            self.ring_push(data, snaplen, &addr)
+        } else {
+            self.queue_push(data, snaplen, addr)
+        };
+
+        if delivered {
+            self.packets = self.packets.wrapping_add(1);
+        } else {
+            self.drops = self.drops.wrapping_add(1);
+        }
+    }
+
+    fn queue_push(&mut self, data: &[u8], snaplen: usize, addr: sockaddr_ll) -> bool {
+        if self.queued + snaplen > self.rcvbuf {
+            return false;
+        }
+        self.queued += snaplen;
+        self.queue.push_back(QueuedFrame { data: data[..snaplen].to_vec(), len: data.len(), addr });
+        true
+    }
+
+    /// tpacket_rcv(): one frame per packet, handed over by flipping
+    /// tp_status to TP_STATUS_USER once everything else is in place
+    fn ring_push(&mut self, data: &[u8], snaplen: usize, addr: &sockaddr_ll) -> bool {
+        let hdrlen = self.header_len();
+        let dgram = self.ty == libc::SOCK_DGRAM;
+        let reserve = self.reserve;
+        let version = self.version;
+        let losing = self.losing;
+
+        let ring = self.ring.as_mut().unwrap();
+        if ring.status(ring.head) != TP_STATUS_KERNEL {
+            self.losing = true;
+            return false;
+        }
+
+        let (macoff, netoff) = if dgram {
+            let netoff = tpacket_align(hdrlen) + 16 + reserve;
+            (netoff, netoff)
+        } else {
+            let netoff = tpacket_align(hdrlen + 16) + reserve;
+            (netoff - ETH_HLEN, netoff)
+        };
+        if macoff >= ring.frame_size {
+            self.losing = true;
+            return false;
+        }
+        let snaplen = snaplen.min(ring.frame_size - macoff);
+
+        let frame = ring.frame(ring.head);
+        let (sec, nsec) = frame_timestamp();
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), frame.add(macoff), snaplen);
+
+            let header_size = if version == TPACKET_V1 {
+                std::mem::size_of::<tpacket_hdr>()
+            } else {
+                std::mem::size_of::<tpacket2_hdr>()
+            };
+            ptr::write_unaligned(frame.add(tpacket_align(header_size)) as *mut sockaddr_ll, *addr);
+
+            if version == TPACKET_V1 {
+                ptr::write_unaligned(
+                    frame as *mut tpacket_hdr,
+                    tpacket_hdr {
+                        tp_status: TP_STATUS_KERNEL as u64,
+                        tp_len: data.len() as u32,
+                        tp_snaplen: snaplen as u32,
+                        tp_mac: macoff as u16,
+                        tp_net: netoff as u16,
+                        tp_sec: sec,
+                        tp_usec: nsec / 1000,
+                    },
+                );
+            } else {
+                ptr::write_unaligned(
+                    frame as *mut tpacket2_hdr,
+                    tpacket2_hdr {
+                        tp_status: TP_STATUS_KERNEL,
+                        tp_len: data.len() as u32,
+                        tp_snaplen: snaplen as u32,
+                        tp_mac: macoff as u16,
+                        tp_net: netoff as u16,
+                        tp_sec: sec,
+                        tp_nsec: nsec,
+                        ..Default::default()
+                    },
+                );
+            }
+
+            fence(Ordering::Release);
+            let status = TP_STATUS_USER | if losing { TP_STATUS_LOSING } else { 0 };
+            ptr::write_volatile(frame as *mut u32, status);
+        }
+
+        ring.head = (ring.head + 1) % ring.frame_nr;
+        self.losing = false;
+        true
+    }
+
+    /// TPACKET_HDRLEN / TPACKET2_HDRLEN: aligned header plus sockaddr_ll
+    fn header_len(&self) -> usize {
+        let header = if self.version == TPACKET_V1 {
+            std::mem::size_of::<tpacket_hdr>()
+        } else {
+            std::mem::size_of::<tpacket2_hdr>()
+        };
+        tpacket_align(header) + std::mem::size_of::<sockaddr_ll>()
+    }
+
+    fn readable(&self) -> bool {
+        match &self.ring {
+            // like Linux, look at the frame we filled last
+            Some(ring) => ring.status((ring.head + ring.frame_nr - 1) % ring.frame_nr) != TP_STATUS_KERNEL,
+            None => !self.queue.is_empty(),
+        }
+    }
+}
+
+fn classify(frame: &[u8], hwaddr: &[u8; 6], outgoing: bool) -> u8 {
+    let dest = &frame[..ETH_ALEN];
+    if outgoing {
+        PACKET_OUTGOING
+    } else if dest.iter().all(|&b| b == 0xff) {
+        PACKET_BROADCAST
+    } else if dest[0] & 1 != 0 {
+        PACKET_MULTICAST
+    } else if dest == hwaddr {
+        PACKET_HOST
+    } else {
+        PACKET_OTHERHOST
+    }
+}
+
+/// The NIC layer's tap, for frames going either way on `netif`. Received
+/// frames come straight from interrupt handlers, so nothing here may wait
+/// on a lock: a busy socket loses the frame and gets it counted as a drop.
+#[no_mangle]
+pub extern "C" fn packetTap(netif: *mut c_void, frame: *const u8, len: u16, outgoing: bool) {
+    let len = len as usize;
+    if frame.is_null() || len < ETH_HLEN {
+        return;
+    }
+    let Some(interface) = interface_of(netif) else {
+        return;
+    };
+    let frame = unsafe { std::slice::from_raw_parts(frame, len) };
+
+    let pkttype = classify(frame, &interface.hwaddr, outgoing);
+    if pkttype == PACKET_OTHERHOST && !interface_promiscuous(interface.index) {
+        return;
+    }
+    let info = FrameInfo {
+        ifindex: interface.index,
+        hatype: interface.hatype,
+        pkttype,
+        protocol: u16::from_be_bytes([frame[12], frame[13]]),
+    };
+
+    let Ok(sockets) = PACKET_SOCKETS.try_lock() else {
+        return;
+    };
+    let origin = if outgoing { TX_ORIGIN.load(Ordering::Relaxed) } else { 0 };
+    for socket in sockets.iter().filter_map(Weak::upgrade) {
+        if Arc::as_ptr(&socket) as usize == origin {
+            continue;
+        }
+        match socket.state.try_lock() {
+            Ok(mut state) => {
+                if state.wants(&info) {
+                    state.deliver(frame, &info);
+                }
+            }
+            Err(_) => {
+                socket.contended.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PacketSocket {
+    inner: Arc<PacketInner>,
+}
+
+impl PacketSocket {
+    /// `protocol` comes in network byte order, 0 receives nothing until a
+    /// bind() names one
+    pub fn new(ty: c_int, protocol: c_int) -> Result<PacketSocket> {
+        if ty != libc::SOCK_RAW && ty != libc::SOCK_DGRAM {
+            return Err(errno(libc::ESOCKTNOSUPPORT));
+        }
+
+        let inner = Arc::new(PacketInner {
+            state: Mutex::new(PacketState {
+                ty,
+                protocol: u16::from_be(protocol as u16),
+                ifindex: 0,
+                filter: None,
+                filter_locked: false,
+                memberships: Vec::new(),
+                ignore_outgoing: false,
+                rcvbuf: PACKET_RCVBUF,
+                queue: VecDeque::new(),
+                queued: 0,
+                version: TPACKET_V1,
+                reserve: 0,
+                ring: None,
+                losing: false,
+                packets: 0,
+                drops: 0,
+            }),
+            contended: AtomicU32::new(0),
+        });
+
+        let mut sockets = PACKET_SOCKETS.lock().unwrap();
+        sockets.retain(|s| s.strong_count() > 0);
+        sockets.push(Arc::downgrade(&inner));
+        Ok(PacketSocket { inner })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, PacketState> {
+        self.inner.state.lock().unwrap()
+    }
+
+    pub fn bind(&self, addr: *const sockaddr_ll, len: c_uint) -> Result<()> {
+        if addr.is_null() || (len as usize) < std::mem::size_of::<sockaddr_ll>() {
+            return Err(errno(libc::EINVAL));
+        }
+        let addr = unsafe { ptr::read_unaligned(addr) };
+        if addr.sll_family as c_int != AF_PACKET {
+            return Err(errno(libc::EINVAL));
+        }
+
+        let ifindex = addr.sll_ifindex.max(0) as u32;
+        if ifindex != 0 {
+            let _core = CoreLock::new();
+            interface_info(ifindex).ok_or_else(|| errno(libc::ENODEV))?;
+        }
+
+        let mut state = self.state();
+        state.ifindex = ifindex;
+        if addr.sll_protocol != 0 {
+            state.protocol = u16::from_be(addr.sll_protocol);
+        }
+        Ok(())
+    }
+
+    pub fn getsockname(&self, addr: *mut sockaddr_ll, len: &mut c_uint) -> Result<()> {
+        let (ifindex, protocol) = {
+            let state = self.state();
+            (state.ifindex, state.protocol)
+        };
+        let mut local = sockaddr_ll {
+            sll_family: AF_PACKET as u16,
+            sll_protocol: protocol.to_be(),
+            sll_ifindex: ifindex as i32,
+            ..Default::default()
+        };
+        if ifindex != 0 {
+            let _core = CoreLock::new();
+            if let Some(interface) = interface_info(ifindex) {
+                local.sll_hatype = interface.hatype;
+                local.sll_halen = ETH_ALEN as u8;
+                local.sll_addr[..ETH_ALEN].copy_from_slice(&interface.hwaddr);
+            }
+        }
+
+        let copy = (*len as usize).min(std::mem::size_of::<sockaddr_ll>());
+        unsafe { ptr::copy_nonoverlapping(&local as *const sockaddr_ll as *const u8, addr as *mut u8, copy) };
+        *len = std::mem::size_of::<sockaddr_ll>() as c_uint;
+        Ok(())
+    }
+
+    /// SOCK_RAW sends whole frames, SOCK_DGRAM gets the ethernet header
+    /// built from sll_addr & the protocol. Without a destination the bound
+    /// interface is used.
+    pub fn send(&self, buf: &[u8], dest: Option<&sockaddr_ll>) -> Result<usize> {
+        let (ty, bound_ifindex, bound_protocol) = {
+            let state = self.state();
+            (state.ty, state.ifindex, state.protocol)
+        };
+
+        if let Some(dest) = dest {
+            if dest.sll_family as c_int != AF_PACKET {
+                return Err(errno(libc::EINVAL));
+            }
+        }
+        let ifindex = match dest {
+            Some(dest) if dest.sll_ifindex > 0 => dest.sll_ifindex as u32,
+            _ => bound_ifindex,
+        };
+        if ifindex == 0 {
+            return Err(errno(libc::ENXIO));
+        }
+
+        let _core = CoreLock::new();
+        let interface = interface_info(ifindex).ok_or_else(|| errno(libc::ENXIO))?;
+
+        let frame = if ty == libc::SOCK_DGRAM {
+            let dest = dest.ok_or_else(|| errno(libc::EDESTADDRREQ))?;
+            if (dest.sll_halen as usize) < ETH_ALEN {
+                return Err(errno(libc::EINVAL));
+            }
+            let protocol = if dest.sll_protocol != 0 { u16::from_be(dest.sll_protocol) } else { bound_protocol };
+
+            let mut frame = Vec::with_capacity(ETH_HLEN + buf.len());
+            frame.extend_from_slice(&dest.sll_addr[..ETH_ALEN]);
+            frame.extend_from_slice(&interface.hwaddr);
+            frame.extend_from_slice(&protocol.to_be_bytes());
+            frame.extend_from_slice(buf);
+            frame
+        } else {
+            if buf.len() < ETH_HLEN {
+                return Err(errno(libc::EINVAL));
+            }
+            buf.to_vec()
+        };
+
+        if frame.len() > interface.mtu as usize + ETH_HLEN {
+            return Err(errno(libc::EMSGSIZE));
+        }
+
+        TX_ORIGIN.store(Arc::as_ptr(&self.inner) as usize, Ordering::Relaxed);
+        let result = interface_transmit(ifindex, &frame);
+        TX_ORIGIN.store(0, Ordering::Relaxed);
+        result?;
+
+        Ok(buf.len())
+    }
+
+    /// One frame per call. Returns (bytes copied, captured length, source)
+    /// where the captured length is what MSG_TRUNC reports.
+    pub fn recv(&self, buf: &mut [u8], peek: bool, nonblock: bool) -> Result<(usize, usize, sockaddr_ll)> {
+        loop {
+            {
+                let mut state = self.state();
+                if let Some(frame) = state.queue.front() {
+                    let copy = frame.data.len().min(buf.len());
+                    buf[..copy].copy_from_slice(&frame.data[..copy]);
+                    let result = (copy, frame.len, frame.addr);
+                    if !peek {
+                        state.queued -= frame.data.len();
+                        state.queue.pop_front();
+                    }
+                    return Ok(result);
+                }
+            }
+
+            if nonblock {
+                return Err(errno(libc::EAGAIN));
+            }
+            if unsafe { signalsPendingQuick(currentTask) } {
+                return Err(errno(libc::EINTR));
+            }
+            thread::yield_now();
+        }
+    }
+
+    pub fn readable(&self) -> bool {
+        self.state().readable()
+    }
+
+    /// The PACKET_RX_RING buffer for mmap(): kernel address & size. Once
+    /// handed out it stays until the socket goes away.
+    pub fn ring_region(&self) -> Result<(*mut u8, usize)> {
+        let mut state = self.state();
+        let ring = state.ring.as_mut().ok_or_else(|| errno(libc::EINVAL))?;
+        ring.mapped = true;
+        Ok((ring.base, ring.size))
+    }
+
+    fn set_membership(&self, mreq: &packet_mreq, add: bool) -> Result<()> {
+        let index = mreq.mr_ifindex.max(0) as u32;
+        {
+            let _core = CoreLock::new();
+            interface_info(index).ok_or_else(|| errno(libc::ENODEV))?;
+        }
+
+        match mreq.mr_type {
+            PACKET_MR_PROMISC => {
+                let mut state = self.state();
+                if add {
+                    state.memberships.push(index);
+                    PROMISC[index as usize].fetch_add(1, Ordering::Relaxed);
+                } else {
+                    let position = state
+                        .memberships
+                        .iter()
+                        .position(|&i| i == index)
+                        .ok_or_else(|| errno(libc::EADDRNOTAVAIL))?;
+                    state.memberships.remove(position);
+                    PROMISC[index as usize].fetch_sub(1, Ordering::Relaxed);
+                }
+                Ok(())
+            }
+            // lwIP has every multicast frame pass already
+            PACKET_MR_MULTICAST | PACKET_MR_ALLMULTI => Ok(()),
+            _ => Err(errno(libc::EINVAL)),
+        }
+    }
+
+    pub fn setsockopt(&self, level: c_int, optname: c_int, optval: *const c_void, optlen: c_uint) -> Result<()> {
+        match (level, optname) {
+            (SOL_SOCKET, SO_ATTACH_FILTER) => {
+                if (optlen as usize) < std::mem::size_of::<SockFprog>() {
+                    return Err(errno(libc::EINVAL));
+                }
+                let program = bpf_load(optval as *const SockFprog)?;
+                let mut state = self.state();
+                if state.filter_locked {
+                    return Err(errno(libc::EPERM));
+                }
+                state.filter = Some(program);
+                Ok(())
+            }
+            (SOL_SOCKET, SO_DETACH_FILTER) => {
+                let mut state = self.state();
+                if state.filter_locked {
+                    return Err(errno(libc::EPERM));
+                }
+                state.filter.take().map(|_| ()).ok_or_else(|| errno(libc::ENOENT))
+            }
+            (SOL_SOCKET, SO_LOCK_FILTER) => {
+                let value: c_int = read_opt(optval, optlen)?;
+                let mut state = self.state();
+                if state.filter_locked && value == 0 {
+                    return Err(errno(libc::EPERM));
+                }
+                state.filter_locked = value != 0;
+                Ok(())
+            }
+            (SOL_SOCKET, SO_RCVBUF) => {
+                let value: c_int = read_opt(optval, optlen)?;
+                self.state().rcvbuf = (value.max(0) as usize).saturating_mul(2).max(PACKET_RCVBUF);
+                Ok(())
+            }
+            (SOL_SOCKET, SO_SNDBUF) => Ok(()),
+
+            (SOL_PACKET, PACKET_ADD_MEMBERSHIP | PACKET_DROP_MEMBERSHIP) => {
+                let mreq: packet_mreq = read_opt(optval, optlen)?;
+                self.set_membership(&mreq, optname == PACKET_ADD_MEMBERSHIP)
+            }
+            (SOL_PACKET, PACKET_RX_RING) => {
+                let req: tpacket_req = read_opt(optval, optlen)?;
+                let mut state = self.state();
+                if state.ring.as_ref().map_or(false, |ring| ring.mapped) {
+                    return Err(errno(libc::EBUSY));
+                }
+                if req.tp_block_nr == 0 {
+                    state.ring = None;
+                    return Ok(());
+                }
+                let hdrlen = state.header_len() + state.reserve;
+                state.ring = Some(Ring::new(&req, hdrlen)?);
+                state.losing = false;
+                Ok(())
+            }
+            (SOL_PACKET, PACKET_VERSION) => {
+                let value: c_int = read_opt(optval, optlen)?;
+                let mut state = self.state();
+                if state.ring.is_some() {
+                    return Err(errno(libc::EBUSY));
+                }
+                // no TPACKET_V3 block rings, libpcap falls back to V2
+                match value {
+                    TPACKET_V1 | TPACKET_V2 => {
+                        state.version = value;
+                        Ok(())
+                    }
+                    _ => Err(errno(libc::EINVAL)),
+                }
+            }
+            (SOL_PACKET, PACKET_RESERVE) => {
+                let value: c_uint = read_opt(optval, optlen)?;
+                let mut state = self.state();
+                if state.ring.is_some() {
+                    return Err(errno(libc::EBUSY));
+                }
+                state.reserve = value as usize;
+                Ok(())
+            }
+            (SOL_PACKET, PACKET_IGNORE_OUTGOING) => {
+                let value: c_int = read_opt(optval, optlen)?;
+                self.state().ignore_outgoing = value != 0;
+                Ok(())
+            }
+            // accepted, but recvmsg() doesn't hand out any control messages
+            (SOL_PACKET, PACKET_AUXDATA) => Ok(()),
+            _ => Err(errno(libc::ENOPROTOOPT)),
+        }
+    }
+
+    pub fn getsockopt(&self, level: c_int, optname: c_int, optval: *mut c_void, optlen: &mut c_uint) -> Result<()> {
+        if optval.is_null() {
+            return Err(errno(libc::EFAULT));
+        }
+        let mut state = self.state();
+
+        let value: c_int = match (level, optname) {
+            (SOL_SOCKET, SO_TYPE) => state.ty,
+            (SOL_SOCKET, SO_PROTOCOL) => state.protocol.to_be() as c_int,
+            (SOL_SOCKET, SO_DOMAIN) => AF_PACKET,
+            (SOL_SOCKET, SO_ERROR) => 0,
+            (SOL_SOCKET, SO_RCVBUF) => state.rcvbuf as c_int,
+            (SOL_SOCKET, SO_SNDBUF) => PACKET_RCVBUF as c_int,
+            (SOL_SOCKET, SO_LOCK_FILTER) => state.filter_locked as c_int,
+
+            (SOL_PACKET, PACKET_STATISTICS) => {
+                // reading resets them, tp_packets includes the drops
+                let drops = state
+                    .drops
+                    .wrapping_add(self.inner.contended.swap(0, Ordering::Relaxed));
+                let stats = tpacket_stats { tp_packets: state.packets.wrapping_add(drops), tp_drops: drops };
+                state.packets = 0;
+                state.drops = 0;
+                write_opt(&stats, optval, optlen);
+                return Ok(());
+            }
+            (SOL_PACKET, PACKET_VERSION) => state.version,
+            (SOL_PACKET, PACKET_RESERVE) => state.reserve as c_int,
+            (SOL_PACKET, PACKET_IGNORE_OUTGOING) => state.ignore_outgoing as c_int,
+            (SOL_PACKET, PACKET_HDRLEN) => {
+                // in: the version asked about, out: its header size
+                if (*optlen as usize) < std::mem::size_of::<c_int>() {
+                    return Err(errno(libc::EINVAL));
+                }
+                match unsafe { ptr::read_unaligned(optval as *const c_int) } {
+                    TPACKET_V1 => std::mem::size_of::<tpacket_hdr>() as c_int,
+                    TPACKET_V2 => std::mem::size_of::<tpacket2_hdr>() as c_int,
+                    _ => return Err(errno(libc::EINVAL)),
+                }
+            }
+            _ => return Err(errno(libc::ENOPROTOOPT)),
+        };
+        write_opt(&value, optval, optlen);
+        Ok(())
+    }
+
+    pub fn duplicate(&self) -> PacketSocket {
+        self.clone()
+    }
+}
@@ -0,0 +1,427 @@
+use std::fmt::Write;
+use std::io::{Error, Result};
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::netlink::{interfaces_ip4, CoreLock, InterfaceIp4};
+
+//
+// The IPv4 routing table. lwIP on its own only knows "some netif's subnet,
+// else the default netif's gateway"; this sits in front of that through
+// LWIP_HOOK_IP4_ROUTE_SRC (which interface) and LWIP_HOOK_ETHARP_GET_GW
+// (which next hop), longest prefix and then lowest metric winning.
+//
+// Connected routes and the gateway DHCP puts on each netif come from the
+// netifs themselves, static routes (netlink, SIOCADDRT) live in ROUTES.
+// lwIP's plain ip4_route() still settles destinations within a netif's
+// subnet before asking, which only matters for more specific routes
+// pointing elsewhere. Addresses are in host byte order throughout.
+//
+
+pub(crate) const RTPROT_KERNEL: u8 = 2;
+pub(crate) const RTPROT_BOOT: u8 = 3;
+pub(crate) const RTPROT_DHCP: u8 = 16;
+
+// a NIC's DHCP default route gets 100 + ifindex: a default added by hand
+// (metric 0 unless told otherwise) wins, then the first NIC's
+const DHCP_METRIC: u32 = 100;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Route {
+    pub dst: u32,
+    pub dst_len: u8,
+    // 0 when the destination is on-link
+    pub gateway: u32,
+    pub ifindex: u32,
+    pub metric: u32,
+    pub protocol: u8,
+}
+
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+fn errno(code: c_int) -> Error {
+    Error::from_raw_os_error(code)
+}
+
+fn prefix_mask(len: u8) -> u32 {
+    if len == 0 { 0 } else { u32::MAX << (32 - len as u32) }
+}
+
+impl Route {
+    fn covers(&self, addr: u32) -> bool {
+        (addr ^ self.dst) & prefix_mask(self.dst_len) == 0
+    }
+}
+
+/// The default route DHCP (or an older `route add default`) left on a netif
+pub(crate) fn gateway_route(ifindex: u32, gateway: u32) -> Route {
+    Route { dst: 0, dst_len: 0, gateway, ifindex, metric: DHCP_METRIC + ifindex, protocol: RTPROT_DHCP }
+}
+
+fn routes_of(interfaces: &[InterfaceIp4]) -> Vec<Route> {
+    let mut routes = Vec::new();
+    for i in interfaces.iter().filter(|i| i.up && !i.loopback && i.addr != 0) {
+        routes.push(Route {
+            dst: i.addr & i.mask,
+            dst_len: i.mask.leading_ones() as u8,
+            gateway: 0,
+            ifindex: i.index,
+            metric: 0,
+            protocol: RTPROT_KERNEL,
+        });
+    }
+    for i in interfaces.iter().filter(|i| i.gateway != 0) {
+        routes.push(gateway_route(i.index, i.gateway));
+    }
+    routes.extend(ROUTES.lock().unwrap().iter().copied());
+    routes
+}
+
+/// The whole main table. Caller holds the CoreLock.
+pub(crate) fn routes() -> Vec<Route> {
+    routes_of(&interfaces_ip4())
+}
+
+/// The route to `dst` and the netif it goes out of, skipping routes over
+/// interfaces that are down. Caller holds the CoreLock.
+pub(crate) fn route_lookup(dst: u32) -> Option<(Route, *mut c_void)> {
+    let interfaces = interfaces_ip4();
+    routes_of(&interfaces)
+        .into_iter()
+        .filter(|r| r.covers(dst))
+        .filter_map(|r| interfaces.iter().find(|i| i.index == r.ifindex && i.up).map(|i| (r, i.netif)))
+        .max_by_key(|(r, _)| (r.dst_len, std::cmp::Reverse(r.metric)))
+}
+
+/// Adds a static route (or replaces the one with the same prefix & metric).
+/// A gateway has to be on-link through one of the connected routes, which
+/// also picks the interface if none was given. Caller holds the CoreLock.
+pub(crate) fn route_add(mut route: Route, replace: bool) -> Result<Route> {
+    if route.dst_len > 32 {
+        return Err(errno(libc::EINVAL));
+    }
+    route.dst &= prefix_mask(route.dst_len);
+
+    let interfaces = interfaces_ip4();
+    if route.gateway != 0 {
+        let via = interfaces
+            .iter()
+            .filter(|i| route.ifindex == 0 || i.index == route.ifindex)
+            .find(|i| i.addr != 0 && !i.loopback && (route.gateway ^ i.addr) & i.mask == 0)
+            .ok_or_else(|| errno(libc::ENETUNREACH))?;
+        route.ifindex = via.index;
+    } else if route.ifindex == 0 {
+        return Err(errno(libc::EINVAL));
+    } else if !interfaces.iter().any(|i| i.index == route.ifindex) {
+        return Err(errno(libc::ENODEV));
+    }
+
+    let mut table = ROUTES.lock().unwrap();
+    let same = |r: &&mut Route| r.dst == route.dst && r.dst_len == route.dst_len && r.metric == route.metric;
+    match table.iter_mut().find(same) {
+        Some(_) if !replace => return Err(errno(libc::EEXIST)),
+        Some(existing) => *existing = route,
+        None => table.push(route),
+    }
+    Ok(route)
+}
+
+/// Removes the first static route for the prefix matching whatever else
+/// was specified. Caller holds the CoreLock.
+pub(crate) fn route_delete(
+    dst: u32,
+    dst_len: u8,
+    gateway: Option<u32>,
+    ifindex: Option<u32>,
+    metric: Option<u32>,
+) -> Result<Route> {
+    let dst = dst & prefix_mask(dst_len.min(32));
+    let mut table = ROUTES.lock().unwrap();
+    let index = table
+        .iter()
+        .position(|r| {
+            r.dst == dst
+                && r.dst_len == dst_len
+                && gateway.map_or(true, |gw| gw == r.gateway)
+                && ifindex.map_or(true, |index| index == r.ifindex)
+                && metric.map_or(true, |metric| metric == r.metric)
+        })
+        .ok_or_else(|| errno(libc::ESRCH))?;
+    Ok(table.remove(index))
+}
+
+// ==========================
+// lwIP hooks
+// ==========================
+
+#[repr(C)]
+pub struct ip4_addr_t {
+    addr: u32,
+}
+
+/// LWIP_HOOK_IP4_ROUTE_SRC: NULL leaves it to lwIP's default netif. The
+/// source doesn't matter, there's no policy routing.
+#[no_mangle]
+pub extern "C" fn routeIp4Select(_src: *const ip4_addr_t, dest: *const ip4_addr_t) -> *mut c_void {
+    if dest.is_null() {
+        return ptr::null_mut();
+    }
+    let dst = u32::from_be(unsafe { (*dest).addr });
+    route_lookup(dst).map_or(ptr::null_mut(), |(_, n)| n)
+}
+
+// lwIP wants a pointer it reads right away, and only the tcpip thread asks
+static NEXT_HOP: AtomicU32 = AtomicU32::new(0);
+
+/// LWIP_HOOK_ETHARP_GET_GW, for destinations outside the netif's subnet:
+/// the route's gateway, the destination itself for on-link routes, NULL
+/// for lwIP to fall back to the netif's own gateway
+#[no_mangle]
+pub extern "C" fn routeIp4Gateway(netif: *mut c_void, dest: *const ip4_addr_t) -> *const ip4_addr_t {
+    if dest.is_null() {
+        return ptr::null();
+    }
+    let dst = u32::from_be(unsafe { (*dest).addr });
+    match route_lookup(dst) {
+        Some((route, n)) if n == netif => {
+            if route.gateway == 0 {
+                return dest;
+            }
+            NEXT_HOP.store(route.gateway.to_be(), Ordering::Relaxed);
+            NEXT_HOP.as_ptr() as *const ip4_addr_t
+        }
+        _ => ptr::null(),
+    }
+}
+
+// ==========================
+// ICMP redirects
+// ==========================
+
+extern "C" {
+    fn sys_now() -> u32;
+    fn pbuf_alloc(layer: c_int, length: u16, ty: c_int) -> *mut PbufHead;
+    fn pbuf_free(p: *mut PbufHead) -> u8;
+    fn ip4_output_if(
+        p: *mut PbufHead,
+        src: *const ip4_addr_t,
+        dest: *const ip4_addr_t,
+        ttl: u8,
+        tos: u8,
+        proto: u8,
+        netif: *mut c_void,
+    ) -> i8;
+}
+
+/// The start of struct pbuf, enough to fill one in
+#[repr(C)]
+pub struct PbufHead {
+    next: *mut PbufHead,
+    payload: *mut u8,
+}
+
+// room for the link & IP headers in front (an IPv6 sized one, since
+// LWIP_IPV6 is on)
+const PBUF_IP: c_int = 14 + 40;
+const PBUF_RAM: c_int = 0x280;
+const IP_PROTO_ICMP: u8 = 1;
+const ICMP_TTL: u8 = 255;
+
+const ICMP_REDIRECT: u8 = 5;
+const ICMP_REDIRECT_HOST: u8 = 1;
+
+// (source, destination, when) of the last few redirects, one per second
+// each is plenty
+static REDIRECTS_SENT: Mutex<Vec<(u32, u32, u32)>> = Mutex::new(Vec::new());
+const REDIRECTS_REMEMBERED: usize = 16;
+
+/// A packet about to be forwarded out of the interface it came in on: if
+/// its sender sits on that link it could just as well talk to the next hop
+/// directly, so tell it (RFC 1812 5.2.7.2). Caller holds the CoreLock.
+pub(crate) fn route_redirect(inp: *mut c_void, packet: &[u8], route: &Route) {
+    if packet.len() < 20 {
+        return;
+    }
+    let ihl = (packet[0] & 0xf) as usize * 4;
+    let src = u32::from_be_bytes([packet[12], packet[13], packet[14], packet[15]]);
+    let dst = u32::from_be_bytes([packet[16], packet[17], packet[18], packet[19]]);
+    let next_hop = if route.gateway != 0 { route.gateway } else { dst };
+
+    let interfaces = interfaces_ip4();
+    let Some(link) = interfaces.iter().find(|i| i.netif == inp) else {
+        return;
+    };
+    if link.addr == 0 || (src ^ link.addr) & link.mask != 0 || next_hop == src {
+        return;
+    }
+
+    let now = unsafe { sys_now() };
+    {
+        let mut sent = REDIRECTS_SENT.lock().unwrap();
+        if sent.iter().any(|&(s, d, when)| s == src && d == dst && now.wrapping_sub(when) < 1000) {
+            return;
+        }
+        sent.retain(|&(s, d, _)| s != src || d != dst);
+        if sent.len() >= REDIRECTS_REMEMBERED {
+            sent.remove(0);
+        }
+        sent.push((src, dst, now));
+    }
+
+    // the offending header plus 64 bits of its payload
+    let quoted = &packet[..(ihl + 8).min(packet.len())];
+    let mut icmp = Vec::with_capacity(8 + quoted.len());
+    icmp.extend_from_slice(&[ICMP_REDIRECT, ICMP_REDIRECT_HOST, 0, 0]);
+    icmp.extend_from_slice(&next_hop.to_be_bytes());
+    icmp.extend_from_slice(quoted);
+
+    let mut sum: u32 = 0;
+    for word in icmp.chunks(2) {
+        sum += (word[0] as u32) << 8 | word.get(1).copied().unwrap_or(0) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    icmp[2..4].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+
+    unsafe {
+        let p = pbuf_alloc(PBUF_IP, icmp.len() as u16, PBUF_RAM);
+        if p.is_null() {
+            return;
+        }
+        ptr::copy_nonoverlapping(icmp.as_ptr(), (*p).payload, icmp.len());
+        let dest = ip4_addr_t { addr: src.to_be() };
+        // a NULL source is the netif's own address
+        ip4_output_if(p, ptr::null(), &dest, ICMP_TTL, 0, IP_PROTO_ICMP, inp);
+        pbuf_free(p);
+    }
+}
+
+// ==========================
+// /proc/net/route & SIOCADDRT/SIOCDELRT
+// ==========================
+
+const RTF_UP: u16 = 0x0001;
+const RTF_GATEWAY: u16 = 0x0002;
+const RTF_HOST: u16 = 0x0004;
+
+/// /proc/net/route: addresses as the raw network-order words, every line
+/// padded to 127 characters like Linux does
+pub fn proc_net_route() -> String {
+    let _core = CoreLock::new();
+    let interfaces = interfaces_ip4();
+
+    let mut out = format!(
+        "{:<127}\n",
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT"
+    );
+    for route in routes_of(&interfaces) {
+        let Some(interface) = interfaces.iter().find(|i| i.index == route.ifindex) else {
+            continue;
+        };
+        let mut flags = 0;
+        if interface.up {
+            flags |= RTF_UP;
+        }
+        if route.gateway != 0 {
+            flags |= RTF_GATEWAY;
+        }
+        if route.dst_len == 32 {
+            flags |= RTF_HOST;
+        }
+
+        let mut line = String::new();
+        let _ = write!(
+            line,
+            "{}\t{:08X}\t{:08X}\t{:04X}\t0\t0\t{}\t{:08X}\t0\t0\t0",
+            interface.name,
+            route.dst.to_be(),
+            route.gateway.to_be(),
+            flags,
+            route.metric,
+            prefix_mask(route.dst_len).to_be()
+        );
+        let _ = writeln!(out, "{:<127}", line);
+    }
+    out
+}
+
+pub const SIOCADDRT: u64 = 0x890b;
+pub const SIOCDELRT: u64 = 0x890c;
+
+/// struct rtentry as route(8) fills it in
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct rtentry {
+    rt_pad1: u64,
+    rt_dst: [u8; 16],
+    rt_gateway: [u8; 16],
+    rt_genmask: [u8; 16],
+    rt_flags: u16,
+    rt_pad2: i16,
+    rt_pad3: u64,
+    rt_pad4: *mut c_void,
+    rt_metric: i16,
+    rt_dev: *const u8,
+    rt_mtu: u64,
+    rt_window: u64,
+    rt_irtt: u16,
+}
+
+fn rtentry_addr(sockaddr: &[u8; 16]) -> Result<u32> {
+    let family = u16::from_ne_bytes([sockaddr[0], sockaddr[1]]);
+    if family != libc::AF_INET as u16 {
+        return Err(errno(libc::EAFNOSUPPORT));
+    }
+    Ok(u32::from_be_bytes([sockaddr[4], sockaddr[5], sockaddr[6], sockaddr[7]]))
+}
+
+/// SIOCADDRT/SIOCDELRT. rt_metric is one more than the actual metric, the
+/// way route(8) passes it. Returns the route added or removed. Caller
+/// holds the CoreLock.
+pub(crate) fn route_ioctl(request: u64, arg: *mut c_void) -> Result<Route> {
+    let entry: rtentry = unsafe { ptr::read_unaligned(arg as *const rtentry) };
+
+    let dst = rtentry_addr(&entry.rt_dst)?;
+    let dst_len = if entry.rt_flags & RTF_HOST != 0 {
+        32
+    } else {
+        let mask = rtentry_addr(&entry.rt_genmask).unwrap_or(0);
+        if mask.leading_ones() + mask.trailing_zeros() != 32 && mask != 0 {
+            return Err(errno(libc::EINVAL));
+        }
+        mask.leading_ones() as u8
+    };
+    let gateway = if entry.rt_flags & RTF_GATEWAY != 0 { rtentry_addr(&entry.rt_gateway)? } else { 0 };
+
+    let ifindex = if entry.rt_dev.is_null() {
+        None
+    } else {
+        let name = unsafe { std::ffi::CStr::from_ptr(entry.rt_dev as *const libc::c_char) };
+        let name = name.to_str().map_err(|_| errno(libc::ENODEV))?;
+        let interface = interfaces_ip4().into_iter().find(|i| i.name == name).ok_or_else(|| errno(libc::ENODEV))?;
+        Some(interface.index)
+    };
+    let metric = (entry.rt_metric.max(1) - 1) as u32;
+
+    match request {
+        SIOCADDRT => {
+            let route = Route {
+                dst,
+                dst_len,
+                gateway,
+                ifindex: ifindex.unwrap_or(0),
+                metric,
+                protocol: RTPROT_BOOT,
+            };
+            route_add(route, false)
+        }
+        _ => {
+            let gateway = if gateway != 0 { Some(gateway) } else { None };
+            let metric = if entry.rt_metric != 0 { Some(metric) } else { None };
+            route_delete(dst, dst_len, gateway, ifindex, metric)
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use std::ptr;
 use std::sync::{Arc, Mutex};
-use std::os::raw::{c_int, c_void};
+use std::os::raw::{c_int, c_long, c_void};
 use std::io::{Error, Result};
 use libc::{c_uint, size_t, sockaddr};
 
+use crate::timer::timer_ticks;
+
 extern "C" {
     fn lwip_send(fd: c_int, buf: *const u8, len: size_t, flags: c_int) -> c_int;
     fn lwip_recv(fd: c_int, buf: *mut u8, len: size_t, flags: c_int) -> c_int;
@@ -13,16 +15,210 @@ extern "C" {
     fn lwip_listen(fd: c_int, backlog: c_int) -> c_int;
     fn lwip_recvfrom(fd: c_int, buf: *mut u8, len: size_t, flags: c_int, addr: *mut sockaddr, addrlen: *mut c_uint) -> c_int;
     fn lwip_sendto(fd: c_int, buf: *const u8, len: size_t, flags: c_int, addr: *const sockaddr, addrlen: c_uint) -> c_int;
+    fn lwip_setsockopt(fd: c_int, level: c_int, optname: c_int, optval: *const c_void, optlen: c_uint) -> c_int;
+    fn lwip_getsockopt(fd: c_int, level: c_int, optname: c_int, optval: *mut c_void, optlen: *mut c_uint) -> c_int;
+    fn lwip_getpeername(fd: c_int, addr: *mut sockaddr, len: *mut c_uint) -> c_int;
+    fn lwip_ioctl(fd: c_int, cmd: c_long, argp: *mut c_void) -> c_int;
+}
+
+// Linux option levels & names on the left, lwIP's on the right where they
+// differ. Anything lwIP doesn't know is emulated in UserSocket itself.
+const SOL_SOCKET: (c_int, c_int) = (1, 0xfff);
+const IPPROTO_IP: c_int = 0;
+const IPPROTO_TCP: c_int = 6;
+const IPPROTO_IPV6: c_int = 41;
+
+const SO_REUSEADDR: (c_int, c_int) = (2, 0x0004);
+const SO_TYPE: (c_int, c_int) = (3, 0x1008);
+const SO_ERROR: (c_int, c_int) = (4, 0x1007);
+const SO_BROADCAST: (c_int, c_int) = (6, 0x0020);
+const SO_SNDBUF: c_int = 7;
+const SO_RCVBUF: (c_int, c_int) = (8, 0x1002);
+const SO_KEEPALIVE: (c_int, c_int) = (9, 0x0008);
+const SO_LINGER: (c_int, c_int) = (13, 0x0080);
+const SO_REUSEPORT: c_int = 15;
+const SO_RCVTIMEO: c_int = 20;
+const SO_SNDTIMEO: c_int = 21;
+const SO_ACCEPTCONN: (c_int, c_int) = (30, 0x0002);
+const SO_DOMAIN: c_int = 39;
+
+const TCP_NODELAY: (c_int, c_int) = (1, 0x01);
+const TCP_KEEPIDLE: (c_int, c_int) = (4, 0x03);
+const TCP_KEEPINTVL: (c_int, c_int) = (5, 0x04);
+const TCP_KEEPCNT: (c_int, c_int) = (6, 0x05);
+const TCP_INFO: c_int = 11;
+
+const IP_TOS: c_int = 1;
+const IP_TTL: c_int = 2;
+const IP_MULTICAST_IF: (c_int, c_int) = (32, 6);
+const IP_MULTICAST_TTL: (c_int, c_int) = (33, 5);
+const IP_MULTICAST_LOOP: (c_int, c_int) = (34, 7);
+const IP_ADD_MEMBERSHIP: (c_int, c_int) = (35, 3);
+const IP_DROP_MEMBERSHIP: (c_int, c_int) = (36, 4);
+
+const IPV6_V6ONLY: (c_int, c_int) = (26, 27);
+
+// ioctls, Linux numbering (lwIP's FIONREAD is the BSD _IOR('f', 127, long))
+pub const FIONREAD: u64 = 0x541B;
+pub const FIONBIO: u64 = 0x5421;
+pub const SIOCATMARK: u64 = 0x8905;
+const LWIP_FIONREAD: c_long = 0x4008667F;
+
+const TCP_ESTABLISHED: u8 = 1;
+const TCP_CLOSE: u8 = 7;
+const TCP_LISTEN: u8 = 10;
+
+// lwIP's TCP_MSS & TCP_SND_BUF (lwipopts.h)
+const LWIP_TCP_MSS: u32 = 536;
+const LWIP_TCP_SND_BUF: c_int = 8192;
+
+/// (level, optname) as lwIP knows them
+fn sockopt_to_lwip(level: c_int, optname: c_int) -> Result<(c_int, c_int)> {
+    let pairs: &[(c_int, c_int)] = match level {
+        l if l == SOL_SOCKET.0 => &[
+            SO_REUSEADDR,
+            // lwIP has no SO_REUSEPORT; with SO_REUSE both let a second
+            // socket bind the same port, which is what servers want it for
+            (SO_REUSEPORT, SO_REUSEADDR.1),
+            SO_TYPE,
+            SO_ERROR,
+            SO_BROADCAST,
+            SO_RCVBUF,
+            SO_KEEPALIVE,
+            SO_LINGER,
+            SO_ACCEPTCONN,
+        ],
+        IPPROTO_TCP => &[TCP_NODELAY, TCP_KEEPIDLE, TCP_KEEPINTVL, TCP_KEEPCNT],
+        IPPROTO_IP => &[
+            (IP_TOS, IP_TOS),
+            (IP_TTL, IP_TTL),
+            IP_MULTICAST_IF,
+            IP_MULTICAST_TTL,
+            IP_MULTICAST_LOOP,
+            IP_ADD_MEMBERSHIP,
+            IP_DROP_MEMBERSHIP,
+        ],
+        IPPROTO_IPV6 => &[IPV6_V6ONLY],
+        _ => &[],
+    };
+
+    let lwip_level = if level == SOL_SOCKET.0 { SOL_SOCKET.1 } else { level };
+    pairs
+        .iter()
+        .find(|(linux, _)| *linux == optname)
+        .map(|(_, lwip)| (lwip_level, *lwip))
+        .ok_or_else(|| Error::from_raw_os_error(libc::ENOPROTOOPT))
+}
+
+#[repr(C)]
+struct timeval_linux {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+#[repr(C)]
+struct ip_mreqn_linux {
+    imr_multiaddr: u32,
+    imr_address: u32,
+    imr_ifindex: c_int,
+}
+
+/// struct tcp_info up to tcpi_total_retrans, the part older glibc knows
+#[repr(C)]
+#[derive(Default)]
+struct tcp_info_linux {
+    tcpi_state: u8,
+    tcpi_ca_state: u8,
+    tcpi_retransmits: u8,
+    tcpi_probes: u8,
+    tcpi_backoff: u8,
+    tcpi_options: u8,
+    tcpi_wscale: u8,
+    tcpi_flags: u8,
+
+    tcpi_rto: u32,
+    tcpi_ato: u32,
+    tcpi_snd_mss: u32,
+    tcpi_rcv_mss: u32,
+
+    tcpi_unacked: u32,
+    tcpi_sacked: u32,
+    tcpi_lost: u32,
+    tcpi_retrans: u32,
+    tcpi_fackets: u32,
+
+    tcpi_last_data_sent: u32,
+    tcpi_last_ack_sent: u32,
+    tcpi_last_data_recv: u32,
+    tcpi_last_ack_recv: u32,
+
+    tcpi_pmtu: u32,
+    tcpi_rcv_ssthresh: u32,
+    tcpi_rtt: u32,
+    tcpi_rttvar: u32,
+    tcpi_snd_ssthresh: u32,
+    tcpi_snd_cwnd: u32,
+    tcpi_advmss: u32,
+    tcpi_reordering: u32,
+
+    tcpi_rcv_rtt: u32,
+    tcpi_rcv_space: u32,
+
+    tcpi_total_retrans: u32,
+}
+
+/// What lwIP can't keep track of for us, shared between duplicates
+#[derive(Default)]
+struct SocketOptions {
+    sndbuf: Option<c_int>,
+    // milliseconds, 0 blocks forever
+    rcvtimeo: u64,
+    sndtimeo: u64,
+}
+
+unsafe fn sockopt_read_int(optval: *const c_void, optlen: c_uint) -> Result<c_int> {
+    match optlen {
+        0 => Err(Error::from_raw_os_error(libc::EINVAL)),
+        1..=3 => Ok(*(optval as *const u8) as c_int),
+        _ => Ok(ptr::read_unaligned(optval as *const c_int)),
+    }
+}
+
+unsafe fn sockopt_write<T>(optval: *mut c_void, optlen: &mut c_uint, value: &T) {
+    let len = (*optlen as usize).min(std::mem::size_of::<T>());
+    ptr::copy_nonoverlapping(value as *const T as *const u8, optval as *mut u8, len);
+    *optlen = len as c_uint;
 }
 
 #[derive(Clone)]
 pub struct UserSocket {
     lwip_fd: c_int,
+    family: c_int,
     socket_instances: Arc<Mutex<usize>>,
+    options: Arc<Mutex<SocketOptions>>,
 }
 
 impl UserSocket {
+    pub fn new(lwip_fd: c_int, family: c_int) -> UserSocket {
+        UserSocket {
+            lwip_fd,
+            family,
+            socket_instances: Arc::new(Mutex::new(1)),
+            options: Arc::new(Mutex::new(SocketOptions::default())),
+        }
+    }
+
+    /// Tick at which a blocking call gives up, SO_RCVTIMEO/SO_SNDTIMEO
+    fn deadline(timeout: u64) -> Option<u64> {
+        if timeout == 0 { None } else { Some(timer_ticks() + timeout) }
+    }
+
+    fn timed_out(deadline: Option<u64>) -> bool {
+        deadline.map_or(false, |deadline| timer_ticks() >= deadline)
+    }
+
     pub fn send(&self, buf: &[u8], flags: c_int, nonblock: bool) -> Result<usize> {
+        let deadline = Self::deadline(self.options.lock().unwrap().sndtimeo);
         loop {
             // TODO: replace this with proper epoll / poll integration
             let res = unsafe { lwip_send(self.lwip_fd, buf.as_ptr(), buf.len(), flags) };
@@ -30,7 +226,7 @@ impl UserSocket {
                 return Ok(res as usize);
             } else {
                 let err = Error::last_os_error();
-                if err.kind() == std::io::ErrorKind::WouldBlock && nonblock {
+                if err.kind() == std::io::ErrorKind::WouldBlock && (nonblock || Self::timed_out(deadline)) {
                     return Err(Error::from_raw_os_error(libc::EAGAIN));
                 } else if err.kind() == std::io::ErrorKind::Interrupted {
                     return Err(Error::from_raw_os_error(libc::EINTR));
@@ -40,13 +236,14 @@ impl UserSocket {
     }
 
     pub fn recv(&self, buf: &mut [u8], flags: c_int, nonblock: bool) -> Result<usize> {
+        let deadline = Self::deadline(self.options.lock().unwrap().rcvtimeo);
         loop {
             let res = unsafe { lwip_recv(self.lwip_fd, buf.as_mut_ptr(), buf.len(), flags) };
             if res >= 0 {
                 return Ok(res as usize);
             } else {
                 let err = Error::last_os_error();
-                if err.kind() == std::io::ErrorKind::WouldBlock && nonblock {
+                if err.kind() == std::io::ErrorKind::WouldBlock && (nonblock || Self::timed_out(deadline)) {
                     return Err(Error::from_raw_os_error(libc::EAGAIN));
                 } else if err.kind() == std::io::ErrorKind::Interrupted {
                     return Err(Error::from_raw_os_error(libc::EINTR));
@@ -113,4 +310,175 @@ impl UserSocket {
         }
         Ok(res as usize)
     }
+
+    pub fn setsockopt(&self, level: c_int, optname: c_int, optval: *const c_void, optlen: c_uint) -> Result<()> {
+        if optval.is_null() {
+            return Err(Error::from_raw_os_error(libc::EFAULT));
+        }
+
+        match (level, optname) {
+            (l, SO_SNDBUF) if l == SOL_SOCKET.0 => {
+                // lwIP sizes the send buffer at compile time, only remember
+                // what was asked for (doubled, like Linux reports it)
+                let value = unsafe { sockopt_read_int(optval, optlen)? };
+                self.options.lock().unwrap().sndbuf = Some(value.max(0).saturating_mul(2));
+                return Ok(());
+            }
+            (l, SO_RCVTIMEO | SO_SNDTIMEO) if l == SOL_SOCKET.0 => {
+                if (optlen as usize) < std::mem::size_of::<timeval_linux>() {
+                    return Err(Error::from_raw_os_error(libc::EINVAL));
+                }
+                let tv = unsafe { ptr::read_unaligned(optval as *const timeval_linux) };
+                if tv.tv_sec < 0 || !(0..1_000_000).contains(&tv.tv_usec) {
+                    return Err(Error::from_raw_os_error(libc::EDOM));
+                }
+
+                let ms = tv.tv_sec as u64 * 1000 + (tv.tv_usec as u64).div_ceil(1000);
+                let mut options = self.options.lock().unwrap();
+                if optname == SO_RCVTIMEO { options.rcvtimeo = ms } else { options.sndtimeo = ms }
+                return Ok(());
+            }
+            (IPPROTO_IP, o) if o == IP_MULTICAST_TTL.0 || o == IP_MULTICAST_LOOP.0 => {
+                // Linux takes an int or a byte, lwIP only a byte
+                let value = unsafe { sockopt_read_int(optval, optlen)? };
+                let value = u8::try_from(value).map_err(|_| Error::from_raw_os_error(libc::EINVAL))?;
+                let (level, optname) = sockopt_to_lwip(level, optname)?;
+                return self.lwip_setsockopt(level, optname, &value as *const u8 as *const c_void, 1);
+            }
+            (IPPROTO_IP, o) if o == IP_MULTICAST_IF.0 => {
+                // struct ip_mreqn / ip_mreq carry the interface address second
+                let addr = if (optlen as usize) >= 8 {
+                    unsafe { ptr::read_unaligned(optval as *const ip_mreqn_linux) }.imr_address
+                } else if optlen == 4 {
+                    unsafe { ptr::read_unaligned(optval as *const u32) }
+                } else {
+                    return Err(Error::from_raw_os_error(libc::EINVAL));
+                };
+                let (level, optname) = sockopt_to_lwip(level, optname)?;
+                return self.lwip_setsockopt(level, optname, &addr as *const u32 as *const c_void, 4);
+            }
+            _ => {}
+        }
+
+        let (level, optname) = sockopt_to_lwip(level, optname)?;
+        self.lwip_setsockopt(level, optname, optval, optlen)
+    }
+
+    fn lwip_setsockopt(&self, level: c_int, optname: c_int, optval: *const c_void, optlen: c_uint) -> Result<()> {
+        let res = unsafe { lwip_setsockopt(self.lwip_fd, level, optname, optval, optlen) };
+        if res < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn getsockopt(&self, level: c_int, optname: c_int, optval: *mut c_void, optlen: &mut c_uint) -> Result<()> {
+        if optval.is_null() {
+            return Err(Error::from_raw_os_error(libc::EFAULT));
+        }
+
+        match (level, optname) {
+            (l, SO_DOMAIN) if l == SOL_SOCKET.0 => {
+                unsafe { sockopt_write(optval, optlen, &self.family) };
+                return Ok(());
+            }
+            (l, SO_SNDBUF) if l == SOL_SOCKET.0 => {
+                let value = self.options.lock().unwrap().sndbuf.unwrap_or(LWIP_TCP_SND_BUF);
+                unsafe { sockopt_write(optval, optlen, &value) };
+                return Ok(());
+            }
+            (l, SO_RCVTIMEO | SO_SNDTIMEO) if l == SOL_SOCKET.0 => {
+                let options = self.options.lock().unwrap();
+                let ms = if optname == SO_RCVTIMEO { options.rcvtimeo } else { options.sndtimeo };
+                let tv = timeval_linux {
+                    tv_sec: (ms / 1000) as i64,
+                    tv_usec: ((ms % 1000) * 1000) as i64,
+                };
+                unsafe { sockopt_write(optval, optlen, &tv) };
+                return Ok(());
+            }
+            (IPPROTO_TCP, TCP_INFO) => {
+                let info = self.tcp_info()?;
+                unsafe { sockopt_write(optval, optlen, &info) };
+                return Ok(());
+            }
+            (IPPROTO_IP, o) if o == IP_MULTICAST_TTL.0 || o == IP_MULTICAST_LOOP.0 => {
+                let (level, optname) = sockopt_to_lwip(level, optname)?;
+                let mut value: u8 = 0;
+                let mut len: c_uint = 1;
+                self.lwip_getsockopt(level, optname, &mut value as *mut u8 as *mut c_void, &mut len)?;
+                unsafe { sockopt_write(optval, optlen, &(value as c_int)) };
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let (level, optname) = sockopt_to_lwip(level, optname)?;
+        self.lwip_getsockopt(level, optname, optval, optlen)
+    }
+
+    fn lwip_getsockopt(&self, level: c_int, optname: c_int, optval: *mut c_void, optlen: &mut c_uint) -> Result<()> {
+        let res = unsafe { lwip_getsockopt(self.lwip_fd, level, optname, optval, optlen) };
+        if res < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// lwIP's socket API doesn't expose the pcb, so this only covers what
+    /// can be asked through it: the state and lwIP's fixed MSS
+    fn tcp_info(&self) -> Result<tcp_info_linux> {
+        let mut ty: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as c_uint;
+        self.lwip_getsockopt(SOL_SOCKET.1, SO_TYPE.1, &mut ty as *mut c_int as *mut c_void, &mut len)?;
+        if ty != libc::SOCK_STREAM {
+            return Err(Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+
+        let mut listening: c_int = 0;
+        let mut len = std::mem::size_of::<c_int>() as c_uint;
+        self.lwip_getsockopt(SOL_SOCKET.1, SO_ACCEPTCONN.1, &mut listening as *mut c_int as *mut c_void, &mut len)?;
+
+        let mut peer: [u8; 28] = [0; 28];
+        let mut peer_len = peer.len() as c_uint;
+        let connected = unsafe { lwip_getpeername(self.lwip_fd, peer.as_mut_ptr() as *mut sockaddr, &mut peer_len) } == 0;
+
+        let mut info = tcp_info_linux::default();
+        info.tcpi_state = if listening != 0 {
+            TCP_LISTEN
+        } else if connected {
+            TCP_ESTABLISHED
+        } else {
+            TCP_CLOSE
+        };
+        info.tcpi_snd_mss = LWIP_TCP_MSS;
+        info.tcpi_rcv_mss = LWIP_TCP_MSS;
+        info.tcpi_advmss = LWIP_TCP_MSS;
+        Ok(info)
+    }
+
+    /// Socket ioctls; FIONBIO lives on the open file, so the caller handles it
+    pub fn ioctl(&self, request: u64, arg: *mut c_void) -> Result<usize> {
+        if arg.is_null() {
+            return Err(Error::from_raw_os_error(libc::EFAULT));
+        }
+
+        match request {
+            FIONREAD => {
+                let mut available: c_int = 0;
+                let res = unsafe { lwip_ioctl(self.lwip_fd, LWIP_FIONREAD, &mut available as *mut c_int as *mut c_void) };
+                if res < 0 {
+                    return Err(Error::last_os_error());
+                }
+                unsafe { ptr::write_unaligned(arg as *mut c_int, available) };
+                Ok(0)
+            }
+            // lwIP delivers urgent data inline and never marks it
+            SIOCATMARK => {
+                unsafe { ptr::write_unaligned(arg as *mut c_int, 0) };
+                Ok(0)
+            }
+            _ => Err(Error::from_raw_os_error(libc::ENOTTY)),
+        }
+    }
 }
@@ -1,26 +1,45 @@
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
+use crate::eth::net_eth_send;
+use crate::ipv4::NIC;
+
+// ARP for the in-tree stack (net.tcp=native). lwIP keeps its own cache and
+// keeps answering requests for the NIC's address (same MAC, same IP), what
+// lands here are copies of the ARP frames the NIC receives (netEthSnoops()).
+
 const IPV4_BYTE_SIZE: usize = 4;
 const MAC_BYTE_SIZE: usize = 6;
+const NET_ETHERTYPE_ARP: u16 = 0x0806;
+const NET_ETH_HEADER: usize = 14;
 const ARP_HARDWARE_TYPE: u16 = 1;
 const ARP_PROTOCOL_TYPE: u16 = 0x0800;
 const ARP_OP_REQUEST: u16 = 1;
-const ARP_OP_REPLY: u16 = 2;
+
+// how long an answer is trusted (lwIP's ARP_MAXAGE), and how long until an
+// unanswered request goes out again
+const ARP_ENTRY_MAX_AGE: Duration = Duration::from_secs(300);
+const ARP_REQUEST_RETRY: Duration = Duration::from_millis(500);
 
 // Broadcast MAC address
 const MAC_BROADCAST: [u8; MAC_BYTE_SIZE] = [0xff; MAC_BYTE_SIZE];
 const ADDRESS_NULL: [u8; IPV4_BYTE_SIZE] = [0; IPV4_BYTE_SIZE];
+const ADDRESS_BROADCAST: [u8; IPV4_BYTE_SIZE] = [0xff; IPV4_BYTE_SIZE];
 
-#[repr(C)]
+// An address we've heard from, or asked about (`mac` None) on the NIC
+// owning `nic_mac`
 #[derive(Clone)]
 struct ArpEntry {
-    mac: [u8; MAC_BYTE_SIZE],
+    nic_mac: [u8; MAC_BYTE_SIZE],
     ip: [u8; IPV4_BYTE_SIZE],
+    mac: Option<[u8; MAC_BYTE_SIZE]>,
+    // when it was learnt, or when the last request went out
+    stamp: Instant,
 }
 
 #[repr(C)]
+#[derive(Clone, Copy)]
 struct ArpPacket {
     hardware_type: u16,
     protocol_type: u16,
@@ -38,142 +57,135 @@ struct ArpTable {
 }
 
 impl ArpTable {
-    fn new() -> Self {
+    const fn new() -> Self {
         Self { entries: Vec::new() }
     }
 
-    fn lookup(&self, ip: &[u8; IPV4_BYTE_SIZE]) -> Option<[u8; MAC_BYTE_SIZE]> {
-        self.entries.iter()
-            .find(|entry| &entry.ip == ip)
-            .map(|entry| entry.mac)
+    fn find(&mut self, nic: &NIC, ip: &[u8; IPV4_BYTE_SIZE]) -> Option<&mut ArpEntry> {
+        self.entries.iter_mut().find(|entry| entry.nic_mac == nic.mac && &entry.ip == ip)
     }
 
-    fn add(&mut self, mac: [u8; MAC_BYTE_SIZE], ip: [u8; IPV4_BYTE_SIZE]) {
-        // Remove any conflicting entry
-        if let Some(pos) = self.entries.iter().position(|e| &e.ip == &ip || &e.mac == &mac) {
-            self.entries.remove(pos);
+    fn lookup(&mut self, nic: &NIC, ip: &[u8; IPV4_BYTE_SIZE]) -> Option<[u8; MAC_BYTE_SIZE]> {
+        let entry = self.find(nic, ip)?;
+        match entry.mac {
+            Some(_) if entry.stamp.elapsed() > ARP_ENTRY_MAX_AGE => {
+                // stale, and long enough ago that a request is due
+                entry.mac = None;
+                None
+            }
+            mac => mac,
         }
-        self.entries.push(ArpEntry { mac, ip });
     }
-}
 
-struct ArpRequest {
-    ip: [u8; IPV4_BYTE_SIZE],
-    timestamp: Instant,
-}
+    fn add(&mut self, nic: &NIC, mac: [u8; MAC_BYTE_SIZE], ip: [u8; IPV4_BYTE_SIZE]) {
+        match self.find(nic, &ip) {
+            Some(entry) => {
+                entry.mac = Some(mac);
+                entry.stamp = Instant::now();
+            }
+            None => self.entries.push(ArpEntry {
+                nic_mac: nic.mac,
+                ip,
+                mac: Some(mac),
+                stamp: Instant::now(),
+            }),
+        }
+    }
 
-struct NetQueueItem {
-    component: u32,
-    callback: Option<fn()>,
-    dir: Box<dyn std::any::Any + Send>, // stores ARP request
-    unix_time: Instant,
+    // Whether a request for `ip` is due, noting it as sent if so
+    fn request_due(&mut self, nic: &NIC, ip: [u8; IPV4_BYTE_SIZE]) -> bool {
+        match self.find(nic, &ip) {
+            Some(entry) if entry.stamp.elapsed() < ARP_REQUEST_RETRY => false,
+            Some(entry) => {
+                entry.stamp = Instant::now();
+                true
+            }
+            None => {
+                self.entries.push(ArpEntry {
+                    nic_mac: nic.mac,
+                    ip,
+                    mac: None,
+                    stamp: Instant::now(),
+                });
+                true
+            }
+        }
+    }
 }
 
-struct NetQueue {
-    queue: VecDeque<NetQueueItem>,
+static ARP: Mutex<ArpTable> = Mutex::new(ArpTable::new());
+
+// Broadcasts who-has `ip`
+fn arp_request(nic: &NIC, ip: &[u8; IPV4_BYTE_SIZE]) {
+    let arp = ArpPacket {
+        hardware_type: ARP_HARDWARE_TYPE.to_be(),
+        protocol_type: ARP_PROTOCOL_TYPE.to_be(),
+        hardware_size: MAC_BYTE_SIZE as u8,
+        protocol_size: IPV4_BYTE_SIZE as u8,
+        opcode: ARP_OP_REQUEST.to_be(),
+        sender_mac: nic.mac,
+        sender_ip: nic.ip,
+        target_mac: [0; MAC_BYTE_SIZE],
+        target_ip: *ip,
+    };
+
+    let mut frame = vec![0u8; NET_ETH_HEADER + mem::size_of::<ArpPacket>()];
+    unsafe {
+        (frame[NET_ETH_HEADER..].as_mut_ptr() as *mut ArpPacket).write_unaligned(arp);
+    }
+    net_eth_send(nic, &mut frame, &MAC_BROADCAST, NET_ETHERTYPE_ARP);
 }
 
-impl NetQueue {
-    fn new() -> Self {
-        Self { queue: VecDeque::new() }
+// The MAC to reach `ip` (on-link) through, false while it isn't known yet.
+// Asks around then, at most once per ARP_REQUEST_RETRY.
+pub(crate) fn net_arp_translate(nic: &NIC, ip: &[u8; IPV4_BYTE_SIZE], mac: &mut [u8; MAC_BYTE_SIZE]) -> bool {
+    let subnet_broadcast: [u8; IPV4_BYTE_SIZE] =
+        std::array::from_fn(|i| nic.ip[i] | !nic.subnet_mask[i]);
+    if *ip == ADDRESS_BROADCAST || (nic.subnet_mask != ADDRESS_NULL && *ip == subnet_broadcast) {
+        *mac = MAC_BROADCAST;
+        return true;
     }
 
-    fn search<F>(&self, cmp: F) -> Option<&NetQueueItem>
-    where F: Fn(&NetQueueItem) -> bool {
-        self.queue.iter().find(|item| cmp(item))
+    let mut table = ARP.lock().unwrap();
+    if let Some(known) = table.lookup(nic, ip) {
+        *mac = known;
+        return true;
     }
+    let due = table.request_due(nic, *ip);
+    drop(table);
 
-    fn remove<F>(&mut self, cmp: F) -> bool
-    where F: Fn(&NetQueueItem) -> bool {
-        if let Some(pos) = self.queue.iter().position(|item| cmp(item)) {
-            self.queue.remove(pos);
-            true
-        } else { false }
+    if due {
+        arp_request(nic, ip);
     }
+    false
 }
 
-struct NIC {
-    mac: [u8; MAC_BYTE_SIZE],
-    ip: [u8; IPV4_BYTE_SIZE],
-    arp_table: Mutex<ArpTable>,
-}
-
-impl NIC {
-    fn arp_table_lookup(&self, ip: &[u8; IPV4_BYTE_SIZE]) -> Option<[u8; MAC_BYTE_SIZE]> {
-        self.arp_table.lock().unwrap().lookup(ip)
+// Learns from an ARP frame (ethernet header included), request or reply:
+// the sender if it's talking to us or already in the table, as RFC 826 has
+// it. Requests for our address are lwIP's to answer.
+pub(crate) fn net_arp_handle(nic: &NIC, packet: &[u8]) {
+    if packet.len() < NET_ETH_HEADER + mem::size_of::<ArpPacket>() {
+        println!("[net::arp] Drop: Too small");
+        return;
     }
 
-    fn arp_table_add(&self, mac: [u8; MAC_BYTE_SIZE], ip: [u8; IPV4_BYTE_SIZE]) {
-        self.arp_table.lock().unwrap().add(mac, ip);
+    let arp = unsafe { (packet[NET_ETH_HEADER..].as_ptr() as *const ArpPacket).read_unaligned() };
+    if u16::from_be(arp.hardware_type) != ARP_HARDWARE_TYPE
+        || u16::from_be(arp.protocol_type) != ARP_PROTOCOL_TYPE
+        || arp.hardware_size as usize != MAC_BYTE_SIZE
+        || arp.protocol_size as usize != IPV4_BYTE_SIZE
+    {
+        println!("[net::arp] Drop: Not Ethernet/IPv4");
+        return;
     }
-
-    fn arp_reply(&self, req_mac: [u8; MAC_BYTE_SIZE], req_ip: [u8; IPV4_BYTE_SIZE]) {
-        let arp = ArpPacket {
-            hardware_type: ARP_HARDWARE_TYPE.to_be(),
-            protocol_type: ARP_PROTOCOL_TYPE.to_be(),
-            hardware_size: MAC_BYTE_SIZE as u8,
-            protocol_size: IPV4_BYTE_SIZE as u8,
-            opcode: ARP_OP_REPLY.to_be(),
-            target_mac: req_mac,
-            target_ip: req_ip,
-            sender_mac: self.mac,
-            sender_ip: self.ip,
-        };
-
-        // send the packet (placeholder)
-        net_eth_send(&arp, &req_mac);
+    if arp.sender_ip == ADDRESS_NULL || arp.sender_mac == MAC_BROADCAST {
+        // probes and nonsense
+        return;
     }
 
-    fn arp_translate(&self, ip: [u8; IPV4_BYTE_SIZE], queue: &Mutex<NetQueue>) -> Option<[u8; MAC_BYTE_SIZE]> {
-        // Lookup in ARP table
-        if let Some(mac) = self.arp_table_lookup(&ip) {
-            return Some(mac);
-        }
-
-        // Check queue
-        let mut net_queue = queue.lock().unwrap();
-        let existing = net_queue.search(|item| {
-            item.component == 1 && item.dir.downcast_ref::<ArpRequest>().map_or(false, |r| r.ip == ip)
-        });
-
-        let send_request = if let Some(item) = existing {
-            item.unix_time.elapsed() > Duration::from_millis(500)
-        } else {
-            true
-        };
-
-        if send_request {
-            let request = ArpRequest {
-                ip,
-                timestamp: Instant::now(),
-            };
-            net_queue.queue.push_back(NetQueueItem {
-                component: 1,
-                callback: None,
-                dir: Box::new(request),
-                unix_time: Instant::now(),
-            });
-
-            // Build and send ARP request
-            let arp = ArpPacket {
-                hardware_type: ARP_HARDWARE_TYPE.to_be(),
-                protocol_type: ARP_PROTOCOL_TYPE.to_be(),
-                hardware_size: MAC_BYTE_SIZE as u8,
-                protocol_size: IPV4_BYTE_SIZE as u8,
-                opcode: ARP_OP_REQUEST.to_be(),
-                target_mac: [0; MAC_BYTE_SIZE],
-                target_ip: ip,
-                sender_mac: self.mac,
-                sender_ip: self.ip,
-            };
-            net_eth_send(&arp, &MAC_BROADCAST);
-        }
-
-        None
+    let for_us = nic.ip != ADDRESS_NULL && arp.target_ip == nic.ip;
+    let mut table = ARP.lock().unwrap();
+    if for_us || table.find(nic, &arp.sender_ip).is_some() {
+        table.add(nic, arp.sender_mac, arp.sender_ip);
     }
 }
-
-// placeholder for sending Ethernet frame
-fn net_eth_send(packet: &ArpPacket, dest_mac: &[u8; MAC_BYTE_SIZE]) {
-    println!("Sending ARP packet to {:02x?}", dest_mac);
-}
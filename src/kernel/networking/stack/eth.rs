@@ -1,4 +1,9 @@
-use std::sync::Arc;
+use std::os::raw::c_void;
+use std::ptr;
+
+use crate::arp::net_arp_handle;
+use crate::ipv4::{net_ipv4_handle, net_ipv4_nic_of, NET_HELPER_TASK, NIC};
+use crate::tcp_socket::tcp_native;
 
 // Constants
 const MAC_BYTE_SIZE: usize = 6;
@@ -6,6 +11,11 @@ const IPV4_BYTE_SIZE: usize = 4;
 const NET_ETHERTYPE_ARP: u16 = 0x0806;
 const NET_ETHERTYPE_IPV4: u16 = 0x0800;
 const NET_ETHERTYPE_IPV6: u16 = 0x86DD;
+const IPV4_PROTOCOL_TCP: u8 = 6;
+
+// what a QueuePacket holds, and how long the helper naps when there's none
+const NET_FRAME_MAX: usize = 2048;
+const NET_HELPER_IDLE_MS: u32 = 1;
 
 // Broadcast / zero addresses
 const MAC_BROADCAST: [u8; MAC_BYTE_SIZE] = [0xff; MAC_BYTE_SIZE];
@@ -32,66 +42,103 @@ fn dbg_ip(ip: &[u8; IPV4_BYTE_SIZE]) {
     println!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
 }
 
-// NIC struct
-struct NIC {
-    mac: [u8; MAC_BYTE_SIZE],
-    mtu: usize,
-    // ARP, IPv4, etc.
+extern "C" {
+    fn sendPacketRaw(nic: *mut c_void, data: *const u8, size: u32);
+    fn nativeQueuePop(nic: *mut *mut c_void, buff: *mut u8) -> u16;
+    fn taskCreateKernel(entry: u64, arg: u64) -> *const c_void;
+    fn taskNameKernel(task: *const c_void, name: *const u8, len: usize);
+    fn sleep(ms: u32);
 }
 
-impl NIC {
-    fn net_eth_handle(&self, packet: &[u8]) {
-        if packet.len() < std::mem::size_of::<EthHeader>() {
-            println!("[net::eth] Drop: Too small");
-            return;
-        }
+pub(crate) fn net_eth_handle(nic: &NIC, packet: &[u8]) {
+    if packet.len() < std::mem::size_of::<EthHeader>() {
+        println!("[net::eth] Drop: Too small");
+        return;
+    }
 
-        let eth = unsafe { &*(packet.as_ptr() as *const EthHeader) };
+    let eth = unsafe { &*(packet.as_ptr() as *const EthHeader) };
 
-        if eth.dest != self.mac && eth.dest != MAC_BROADCAST && eth.dest != MAC_ZERO {
-            println!("[net::eth] Drop: Neither ours nor broadcast");
-            return;
-        }
+    if eth.dest != nic.mac && eth.dest != MAC_BROADCAST && eth.dest != MAC_ZERO {
+        println!("[net::eth] Drop: Neither ours nor broadcast");
+        return;
+    }
 
-        match u16::from_be(eth.ethertype) {
-            NET_ETHERTYPE_ARP => {
-                net_arp_handle(self, packet);
-            }
-            NET_ETHERTYPE_IPV4 => {
-                net_ipv4_handle(self, packet);
-            }
-            NET_ETHERTYPE_IPV6 => {
-                // ignored
-            }
-            _ => {
-                println!("[net::eth] Drop: Unhandled ethertype");
-            }
+    match u16::from_be(eth.ethertype) {
+        NET_ETHERTYPE_ARP => {
+            net_arp_handle(nic, packet);
+        }
+        NET_ETHERTYPE_IPV4 => {
+            net_ipv4_handle(nic, packet);
+        }
+        NET_ETHERTYPE_IPV6 => {
+            // ignored
+        }
+        _ => {
+            println!("[net::eth] Drop: Unhandled ethertype");
         }
     }
+}
+
+pub(crate) fn net_eth_send(nic: &NIC, packet: &mut [u8], target_mac: &[u8; MAC_BYTE_SIZE], ethertype: u16) {
+    assert!(packet.len() >= std::mem::size_of::<EthHeader>());
+    assert!(packet.len() <= std::mem::size_of::<EthHeader>() + nic.mtu);
 
-    fn net_eth_send(&self, packet: &mut [u8], target_mac: &[u8; MAC_BYTE_SIZE], ethertype: u16) {
-        assert!(packet.len() >= std::mem::size_of::<EthHeader>());
-        assert!(packet.len() <= self.mtu);
+    let eth = unsafe { &mut *(packet.as_mut_ptr() as *mut EthHeader) };
+    eth.src.copy_from_slice(&nic.mac);
+    eth.dest.copy_from_slice(target_mac);
+    eth.ethertype = ethertype.to_be();
 
-        let eth = unsafe { &mut *(packet.as_mut_ptr() as *mut EthHeader) };
-        eth.src.copy_from_slice(&self.mac);
-        eth.dest.copy_from_slice(target_mac);
-        eth.ethertype = ethertype.to_be();
+    unsafe { sendPacketRaw(nic.driver, packet.as_ptr(), packet.len() as u32) };
+}
 
-        send_packet_raw(self, packet);
+// Whether a received frame is the in-tree stack's rather than lwIP's: IPv4
+// TCP while net.tcp=native. Peeks at the headers only, called from the
+// drivers' interrupt handlers through netQueueAdd().
+#[no_mangle]
+pub extern "C" fn netEthWants(packet: *const u8, len: u16) -> bool {
+    let header = std::mem::size_of::<EthHeader>();
+    if (len as usize) < header + 20 || !tcp_native() {
+        return false;
     }
+    let packet = unsafe { std::slice::from_raw_parts(packet, len as usize) };
+    u16::from_be_bytes([packet[12], packet[13]]) == NET_ETHERTYPE_IPV4
+        && packet[header + 9] == IPV4_PROTOCOL_TCP
 }
 
-// Placeholder functions for ARP and IPv4 handlers
-fn net_arp_handle(_nic: &NIC, _packet: &[u8]) {
-    println!("[net::eth] ARP packet handled");
+// Whether the in-tree stack wants a copy of a frame lwIP gets too: ARP, to
+// learn the MACs it sends to while net.tcp=native
+#[no_mangle]
+pub extern "C" fn netEthSnoops(packet: *const u8, len: u16) -> bool {
+    if (len as usize) < std::mem::size_of::<EthHeader>() || !tcp_native() {
+        return false;
+    }
+    let packet = unsafe { std::slice::from_raw_parts(packet, len as usize) };
+    u16::from_be_bytes([packet[12], packet[13]]) == NET_ETHERTYPE_ARP
 }
 
-fn net_ipv4_handle(_nic: &NIC, _packet: &[u8]) {
-    println!("[net::eth] IPv4 packet handled");
+// Drains what netQueueAdd() set aside for us
+extern "C" fn netHelperThread() {
+    let mut frame = vec![0u8; NET_FRAME_MAX];
+    loop {
+        let mut driver: *mut c_void = ptr::null_mut();
+        let len = unsafe { nativeQueuePop(&mut driver, frame.as_mut_ptr()) } as usize;
+        if len == 0 {
+            unsafe { sleep(NET_HELPER_IDLE_MS) };
+            continue;
+        }
+        match net_ipv4_nic_of(driver) {
+            Some(nic) => net_eth_handle(&nic, &frame[..len]),
+            None => println!("[net::eth] Drop: Unknown NIC"),
+        }
+    }
 }
 
-// Placeholder raw send function
-fn send_packet_raw(_nic: &NIC, _packet: &[u8]) {
-    println!("[net::eth] Raw packet sent");
+#[no_mangle]
+pub extern "C" fn netHelperStart() {
+    static NAME: &[u8] = b"nethelper";
+    unsafe {
+        let task = taskCreateKernel(netHelperThread as u64, 0);
+        taskNameKernel(task, NAME.as_ptr(), NAME.len());
+        NET_HELPER_TASK = task;
+    }
 }
@@ -1,19 +1,30 @@
 use std::convert::TryInto;
 use std::mem;
+use std::os::raw::c_void;
 use std::ptr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::arp::net_arp_translate;
+use crate::eth::net_eth_send;
 
 // Constants
 const IPV4_BYTE_SIZE: usize = 4;
 const MAC_BYTE_SIZE: usize = 6;
 const NET_IPv4_CARRY: usize = 14; // Ethernet header size
+// where the payload starts in a buffer from ipv4_alloc_buffer()
+pub(crate) const NET_IPv4_PAYLOAD: usize = NET_IPv4_CARRY + mem::size_of::<IPv4Header>();
 const IPV4_FLAGS_MORE_FRAGMENTS: u16 = 0x2000;
 
 const IPV4_PROTOCOL_TCP: u8 = 6;
 const IPV4_PROTOCOL_UDP: u8 = 17;
 const IPV4_PROTOCOL_ICMP: u8 = 1;
 
-// Placeholder external task
-static mut NET_HELPER_TASK: *const Task = ptr::null();
+// how long a sender waits on ARP before the packet is dropped (TCP resends)
+const IPV4_ARP_WAIT: Duration = Duration::from_secs(3);
+
+// The task draining the in-tree stack's RX queue (eth.rs)
+pub(crate) static mut NET_HELPER_TASK: *const c_void = ptr::null();
 
 // IPv4 header structure
 #[repr(C)]
@@ -43,20 +54,77 @@ impl IPv4Header {
     }
 }
 
-// NIC struct
-struct NIC {
-    ip: [u8; IPV4_BYTE_SIZE],
-    subnet_mask: [u8; IPV4_BYTE_SIZE],
-    server_ip: [u8; IPV4_BYTE_SIZE],
-    mac: [u8; MAC_BYTE_SIZE],
-    mtu: usize,
+// NIC struct, `driver` is what sendPacketRaw() takes
+#[derive(Clone, Copy)]
+pub(crate) struct NIC {
+    pub driver: *mut c_void,
+    pub ip: [u8; IPV4_BYTE_SIZE],
+    pub subnet_mask: [u8; IPV4_BYTE_SIZE],
+    pub server_ip: [u8; IPV4_BYTE_SIZE],
+    pub mac: [u8; MAC_BYTE_SIZE],
+    pub mtu: usize,
+}
+
+unsafe impl Send for NIC {}
+
+// Every NIC the drivers brought up, addresses as configured
+static NICS: Mutex<Vec<NIC>> = Mutex::new(Vec::new());
+
+extern "C" {
+    static currentTask: *const c_void;
+    fn handControl();
+}
+
+// A NIC the driver just initialized, no addresses yet
+#[no_mangle]
+pub extern "C" fn netIpv4Register(driver: *mut c_void, mac: *const u8, mtu: u16) {
+    let mut nic = NIC {
+        driver,
+        ip: [0; IPV4_BYTE_SIZE],
+        subnet_mask: [0; IPV4_BYTE_SIZE],
+        server_ip: [0; IPV4_BYTE_SIZE],
+        mac: [0; MAC_BYTE_SIZE],
+        mtu: mtu as usize,
+    };
+    unsafe { ptr::copy_nonoverlapping(mac, nic.mac.as_mut_ptr(), MAC_BYTE_SIZE) };
+    NICS.lock().unwrap().push(nic);
+}
+
+// New IPv4 addresses (host byte order) for the NIC with that MAC
+#[no_mangle]
+pub extern "C" fn netIpv4Configure(mac: *const u8, ip: u32, mask: u32, gateway: u32) {
+    let mut hwaddr = [0u8; MAC_BYTE_SIZE];
+    unsafe { ptr::copy_nonoverlapping(mac, hwaddr.as_mut_ptr(), MAC_BYTE_SIZE) };
+    if let Some(nic) = NICS.lock().unwrap().iter_mut().find(|nic| nic.mac == hwaddr) {
+        nic.ip = ip.to_be_bytes();
+        nic.subnet_mask = mask.to_be_bytes();
+        nic.server_ip = gateway.to_be_bytes();
+    }
+}
+
+// The NIC a driver handed a frame up from
+pub(crate) fn net_ipv4_nic_of(driver: *mut c_void) -> Option<NIC> {
+    NICS.lock().unwrap().iter().find(|nic| nic.driver == driver).copied()
 }
 
-// Placeholder current task
-static mut CURRENT_TASK: *const Task = ptr::null();
+// The NIC to send to `dest` from: the one owning `src`, or for 0 the one on
+// the destination's subnet, then the first with a gateway
+pub(crate) fn net_ipv4_route(dest: u32, src: u32) -> Option<NIC> {
+    let nics = NICS.lock().unwrap();
+    let configured = || nics.iter().filter(|nic| nic.ip != [0; IPV4_BYTE_SIZE]);
+    if src != 0 {
+        return configured().find(|nic| nic.ip == src.to_be_bytes()).copied();
+    }
+    configured()
+        .find(|nic| !ipv4_needs_routing(&nic.ip, &dest.to_be_bytes(), &nic.subnet_mask))
+        .or_else(|| configured().find(|nic| nic.server_ip != [0; IPV4_BYTE_SIZE]))
+        .copied()
+}
 
-// Placeholder Task struct
-struct Task;
+// Whether `dest` is 127/8 or one of our own addresses
+pub(crate) fn net_ipv4_is_local(dest: u32) -> bool {
+    dest >> 24 == 127 || NICS.lock().unwrap().iter().any(|nic| nic.ip == dest.to_be_bytes())
+}
 
 // Utility: checksum calculation
 fn ipv4_checksum(buf: &[u8]) -> u16 {
@@ -89,7 +157,7 @@ fn ipv4_needs_routing(local: &[u8; 4], dest: &[u8; 4], mask: &[u8; 4]) -> bool {
 }
 
 // Handle incoming IPv4 packets
-fn net_ipv4_handle(nic: &NIC, packet: &[u8]) {
+pub(crate) fn net_ipv4_handle(nic: &NIC, packet: &[u8]) {
     if packet.len() < NET_IPv4_CARRY + mem::size_of::<IPv4Header>() {
         println!("[net::ipv4] Drop: Too small");
         return;
@@ -143,7 +211,7 @@ fn net_ipv4_handle(nic: &NIC, packet: &[u8]) {
 
     match ipv4.protocol {
         IPV4_PROTOCOL_UDP => net_udp_handle(nic, packet),
-        IPV4_PROTOCOL_TCP => (),  // TCP handling not implemented yet
+        IPV4_PROTOCOL_TCP => net_tcp_handle(nic, packet),
         IPV4_PROTOCOL_ICMP => (), // ICMP handling not implemented yet
         _ => println!("[net::ipv4] Drop: Unhandled protocol"),
     }
@@ -156,8 +224,15 @@ fn ipv4_init_buffer(buf: &mut [u8]) {
     ipv4.set_ihl((mem::size_of::<IPv4Header>() / 4) as u8);
 }
 
+// Buffer for net_ipv4_send() with `payload` bytes at NET_IPv4_PAYLOAD
+pub(crate) fn ipv4_alloc_buffer(payload: usize) -> Vec<u8> {
+    let mut buf = vec![0; NET_IPv4_PAYLOAD + payload];
+    ipv4_init_buffer(&mut buf);
+    buf
+}
+
 // Send IPv4 packet
-fn net_ipv4_send(nic: &NIC, packet: &mut [u8], protocol: u8, dest_ip: &[u8; 4]) {
+pub(crate) fn net_ipv4_send(nic: &NIC, packet: &mut [u8], protocol: u8, dest_ip: &[u8; 4]) {
     assert!(packet.len() >= NET_IPv4_CARRY + mem::size_of::<IPv4Header>());
     let ipv4 = unsafe { &mut *(packet[NET_IPv4_CARRY..].as_mut_ptr() as *mut IPv4Header) };
     assert!(ipv4.ihl() as usize == mem::size_of::<IPv4Header>() / 4);
@@ -194,12 +269,19 @@ fn net_ipv4_send(nic: &NIC, packet: &mut [u8], protocol: u8, dest_ip: &[u8; 4])
 
     let mut dest_mac = [0u8; MAC_BYTE_SIZE];
     unsafe {
-        if CURRENT_TASK != NET_HELPER_TASK {
+        if currentTask != NET_HELPER_TASK {
+            let start = Instant::now();
             while !net_arp_translate(nic, &route_ip, &mut dest_mac) {
-                hand_control();
+                if start.elapsed() > IPV4_ARP_WAIT {
+                    println!("[net::ipv4] Drop: No ARP reply");
+                    return;
+                }
+                handControl();
             }
-        } else {
-            panic!("NET_HELPER_TASK path not implemented");
+        } else if !net_arp_translate(nic, &route_ip, &mut dest_mac) {
+            // the helper would wait on the very reply it has to process
+            println!("[net::ipv4] Drop: No ARP entry yet");
+            return;
         }
     }
 
@@ -211,11 +293,25 @@ fn net_udp_handle(_nic: &NIC, _packet: &[u8]) {
     println!("[net::udp] Packet handled");
 }
 
-// Placeholder ARP functions
-fn net_arp_translate(_nic: &NIC, _ip: &[u8; 4], _mac: &mut [u8; 6]) -> bool {
-    true
-}
+// TCP handler, the segment goes to the connection or listener it's for
+fn net_tcp_handle(_nic: &NIC, packet: &[u8]) {
+    if packet.len() < NET_IPv4_CARRY + mem::size_of::<IPv4Header>() {
+        println!("[net::tcp] Drop: Too small");
+        return;
+    }
 
-// Placeholder functions
-fn hand_control() {}
-fn net_eth_send(_nic: &NIC, _packet: &mut [u8], _dest_mac: &[u8; 6], _ethertype: u16) {}
+    // the wire decides both ends, don't trust either
+    let ipv4 = unsafe { &*(packet[NET_IPv4_CARRY..].as_ptr() as *const IPv4Header) };
+    let start = NET_IPv4_CARRY + ipv4.ihl() as usize * 4;
+    let end = (NET_IPv4_CARRY + u16::from_be(ipv4.length) as usize).min(packet.len());
+    if ipv4.ihl() < 5 || start > end {
+        println!("[net::tcp] Drop: Invalid IPv4 header or length");
+        return;
+    }
+    let src = u32::from_be_bytes(ipv4.src_address);
+    let dest = u32::from_be_bytes(ipv4.dest_address);
+
+    if !crate::tcp::tcp_input(src, dest, &packet[start..end]) {
+        println!("[net::tcp] Drop: No connection or listener");
+    }
+}
@@ -0,0 +1,1705 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::hash::Hasher;
+use std::io::{Error, Result};
+use std::os::raw::{c_int, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::ipv4::{ipv4_alloc_buffer, net_ipv4_is_local, net_ipv4_route, net_ipv4_send, NET_IPv4_PAYLOAD};
+use crate::netports::NetPorts;
+use crate::timer::timer_ticks;
+
+//
+// TCP for the Rust stack (RFC 9293): the state machine, SYN cookies once a
+// listener's SYN queue is full, RFC 6298 retransmission timers, NewReno
+// congestion control (RFC 5681/6582) helped along by SACK (RFC 2018),
+// window scaling & timestamps (RFC 7323) and TIME_WAIT.
+//
+// Segments come and go through the in-tree eth/ipv4 layers, lwIP never
+// sees them: tcp_input() gets whatever ipv4.rs hands up from the net helper
+// thread, and what goes out is collected while TCP is locked and passed to
+// net_ipv4_send() after (or straight back to input for our own addresses).
+// A kernel thread runs the timers. Addresses are in host byte order
+// throughout.
+//
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+const TCP_HEADER: usize = 20;
+const IP_PROTO_TCP: u8 = 6;
+
+const OPT_END: u8 = 0;
+const OPT_NOP: u8 = 1;
+const OPT_MSS: u8 = 2;
+const OPT_WSCALE: u8 = 3;
+const OPT_SACK_PERMITTED: u8 = 4;
+const OPT_SACK: u8 = 5;
+const OPT_TIMESTAMP: u8 = 8;
+
+const DEFAULT_MSS: u32 = 536;
+// 1500 byte ethernet MTU minus both headers
+const LOCAL_MSS: u16 = 1460;
+const RECV_BUFFER: usize = 256 * 1024;
+const SEND_BUFFER: usize = 256 * 1024;
+// enough for RECV_BUFFER to fit in the 16 bit window field
+const RECV_WSCALE: u8 = 3;
+const MAX_WSCALE: u8 = 14;
+const MAX_SACK_BLOCKS: usize = 3;
+const OUT_OF_ORDER_SEGMENTS: usize = 64;
+
+// RFC 6298 2.1, 2.4 & 5.5, the clock granularity is the tick below
+const RTO_INITIAL: u64 = 1000;
+const RTO_MIN: u64 = 1000;
+const RTO_MAX: u64 = 60_000;
+const TICK: u64 = 100;
+const SYN_RETRIES: u32 = 6;
+const DATA_RETRIES: u32 = 15;
+const DUPACK_THRESHOLD: u32 = 3;
+// RFC 6928
+const INITIAL_WINDOW_SEGMENTS: u32 = 10;
+
+const MSL: u64 = 30_000;
+// how long an orphaned FIN_WAIT_2 waits for the peer's FIN
+const FIN_TIMEOUT: u64 = 60_000;
+
+// a cookie's counter moves every 64s, the last two are accepted
+const COOKIE_PERIOD: u64 = 64_000;
+const COOKIE_MSS: [u16; 8] = [216, 536, 1024, 1220, 1300, 1400, 1440, 1460];
+// low timestamp bits that carry a cookie's window scale & SACK
+const COOKIE_TS_BITS: u32 = 5;
+const COOKIE_NO_WSCALE: u32 = 0xf;
+
+fn errno(code: c_int) -> Error {
+    Error::from_raw_os_error(code)
+}
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+fn seq_max(a: u32, b: u32) -> u32 {
+    if seq_lt(a, b) { b } else { a }
+}
+
+// ==========================
+// Segments
+// ==========================
+
+#[derive(Clone, Default)]
+struct Options {
+    mss: Option<u16>,
+    wscale: Option<u8>,
+    sack_permitted: bool,
+    sack: Vec<(u32, u32)>,
+    // (TSval, TSecr)
+    timestamp: Option<(u32, u32)>,
+}
+
+struct Segment<'a> {
+    sport: u16,
+    dport: u16,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    options: Options,
+    payload: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// Sequence space taken: the payload, plus one each for SYN & FIN
+    fn len(&self) -> u32 {
+        self.payload.len() as u32 + (self.flags & TCP_SYN != 0) as u32 + (self.flags & TCP_FIN != 0) as u32
+    }
+}
+
+fn checksum(src: u32, dst: u32, segment: &[u8]) -> u16 {
+    let mut sum: u32 = (src >> 16) + (src & 0xffff) + (dst >> 16) + (dst & 0xffff);
+    sum += IP_PROTO_TCP as u32 + segment.len() as u32;
+    for word in segment.chunks(2) {
+        sum += (word[0] as u32) << 8 | word.get(1).copied().unwrap_or(0) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn parse_options(mut raw: &[u8]) -> Options {
+    let mut options = Options::default();
+    while let Some(&kind) = raw.first() {
+        match kind {
+            OPT_END => break,
+            OPT_NOP => {
+                raw = &raw[1..];
+                continue;
+            }
+            _ => {}
+        }
+        let Some(&len) = raw.get(1) else {
+            break;
+        };
+        let len = len as usize;
+        if len < 2 || len > raw.len() {
+            break;
+        }
+        let data = &raw[2..len];
+        match (kind, data.len()) {
+            (OPT_MSS, 2) => options.mss = Some(u16::from_be_bytes([data[0], data[1]])),
+            (OPT_WSCALE, 1) => options.wscale = Some(data[0].min(MAX_WSCALE)),
+            (OPT_SACK_PERMITTED, 0) => options.sack_permitted = true,
+            (OPT_SACK, n) if n % 8 == 0 => {
+                for block in data.chunks_exact(8) {
+                    let left = u32::from_be_bytes(block[0..4].try_into().unwrap());
+                    let right = u32::from_be_bytes(block[4..8].try_into().unwrap());
+                    options.sack.push((left, right));
+                }
+            }
+            (OPT_TIMESTAMP, 8) => {
+                let value = u32::from_be_bytes(data[0..4].try_into().unwrap());
+                let echo = u32::from_be_bytes(data[4..8].try_into().unwrap());
+                options.timestamp = Some((value, echo));
+            }
+            _ => {}
+        }
+        raw = &raw[len..];
+    }
+    options
+}
+
+/// A segment addressed from `src` to `dst`, None when it's malformed or
+/// the checksum doesn't add up
+fn parse_segment(src: u32, dst: u32, raw: &[u8]) -> Option<Segment<'_>> {
+    if raw.len() < TCP_HEADER {
+        return None;
+    }
+    let offset = (raw[12] >> 4) as usize * 4;
+    if offset < TCP_HEADER || offset > raw.len() || checksum(src, dst, raw) != 0 {
+        return None;
+    }
+    Some(Segment {
+        sport: u16::from_be_bytes([raw[0], raw[1]]),
+        dport: u16::from_be_bytes([raw[2], raw[3]]),
+        seq: u32::from_be_bytes(raw[4..8].try_into().unwrap()),
+        ack: u32::from_be_bytes(raw[8..12].try_into().unwrap()),
+        flags: raw[13],
+        window: u16::from_be_bytes([raw[14], raw[15]]),
+        options: parse_options(&raw[TCP_HEADER..offset]),
+        payload: &raw[offset..],
+    })
+}
+
+fn build_options(options: &Options) -> Vec<u8> {
+    let mut raw = Vec::new();
+    if let Some(mss) = options.mss {
+        raw.extend_from_slice(&[OPT_MSS, 4]);
+        raw.extend_from_slice(&mss.to_be_bytes());
+    }
+    if let Some(wscale) = options.wscale {
+        raw.extend_from_slice(&[OPT_NOP, OPT_WSCALE, 3, wscale]);
+    }
+    if options.sack_permitted {
+        raw.extend_from_slice(&[OPT_NOP, OPT_NOP, OPT_SACK_PERMITTED, 2]);
+    }
+    if let Some((value, echo)) = options.timestamp {
+        raw.extend_from_slice(&[OPT_NOP, OPT_NOP, OPT_TIMESTAMP, 10]);
+        raw.extend_from_slice(&value.to_be_bytes());
+        raw.extend_from_slice(&echo.to_be_bytes());
+    }
+    if !options.sack.is_empty() {
+        raw.extend_from_slice(&[OPT_NOP, OPT_NOP, OPT_SACK, 2 + 8 * options.sack.len() as u8]);
+        for (left, right) in &options.sack {
+            raw.extend_from_slice(&left.to_be_bytes());
+            raw.extend_from_slice(&right.to_be_bytes());
+        }
+    }
+    raw
+}
+
+/// A datagram for the outbox: (source, destination, TCP segment)
+type Outgoing = (u32, u32, Vec<u8>);
+
+#[allow(clippy::too_many_arguments)]
+fn build_segment(
+    src: (u32, u16),
+    dst: (u32, u16),
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    options: &Options,
+    payload: &[u8],
+) -> Outgoing {
+    let options = build_options(options);
+    let offset = TCP_HEADER + options.len();
+    let mut raw = Vec::with_capacity(offset + payload.len());
+    raw.extend_from_slice(&src.1.to_be_bytes());
+    raw.extend_from_slice(&dst.1.to_be_bytes());
+    raw.extend_from_slice(&seq.to_be_bytes());
+    raw.extend_from_slice(&ack.to_be_bytes());
+    raw.push((offset as u8 / 4) << 4);
+    raw.push(flags);
+    raw.extend_from_slice(&window.to_be_bytes());
+    raw.extend_from_slice(&[0; 4]);
+    raw.extend_from_slice(&options);
+    raw.extend_from_slice(payload);
+    let sum = checksum(src.0, dst.0, &raw);
+    raw[16..18].copy_from_slice(&sum.to_be_bytes());
+    (src.0, dst.0, raw)
+}
+
+/// RST in answer to a segment nothing here wants (RFC 9293 3.10.7.1)
+fn reset_for(local: (u32, u16), remote: (u32, u16), seg: &Segment) -> Option<Outgoing> {
+    if seg.flags & TCP_RST != 0 {
+        return None;
+    }
+    let none = Options::default();
+    Some(if seg.flags & TCP_ACK != 0 {
+        build_segment(local, remote, seg.ack, 0, TCP_RST, 0, &none, &[])
+    } else {
+        build_segment(local, remote, 0, seg.seq.wrapping_add(seg.len()), TCP_RST | TCP_ACK, 0, &none, &[])
+    })
+}
+
+// ==========================
+// Connections
+// ==========================
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TcpState {
+    Closed,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+impl TcpState {
+    fn synchronized(self) -> bool {
+        !matches!(self, TcpState::Closed | TcpState::SynSent | TcpState::SynReceived)
+    }
+
+    /// Whether our FIN went out already
+    fn fin_sent(self) -> bool {
+        matches!(
+            self,
+            TcpState::FinWait1 | TcpState::FinWait2 | TcpState::Closing | TcpState::LastAck | TcpState::TimeWait
+        )
+    }
+}
+
+/// (local address, local port, remote address, remote port)
+type Quad = (u32, u16, u32, u16);
+
+struct Tcb {
+    state: TcpState,
+    local: (u32, u16),
+    remote: (u32, u16),
+    // the listener it gets accepted from, while it hasn't been
+    listener: Option<u64>,
+    // the socket is gone, the connection only finishes closing
+    orphaned: bool,
+    error: Option<c_int>,
+
+    iss: u32,
+    snd_una: u32,
+    snd_nxt: u32,
+    // the highest snd_nxt so far, retransmissions pull snd_nxt back
+    snd_max: u32,
+    snd_wnd: u32,
+    snd_wl1: u32,
+    snd_wl2: u32,
+    snd_wscale: u8,
+    // everything from snd_una on, sent or not
+    send_buf: VecDeque<u8>,
+    fin_queued: bool,
+    mss: u32,
+    nodelay: bool,
+
+    rcv_nxt: u32,
+    rcv_wscale: u8,
+    recv_buf: VecDeque<u8>,
+    // segments past rcv_nxt, sorted, not overlapping
+    out_of_order: Vec<(u32, Vec<u8>)>,
+    // the peer's FIN, if it came before the data in front of it
+    fin_seq: Option<u32>,
+    fin_received: bool,
+    ack_pending: bool,
+
+    sack_permitted: bool,
+    timestamps: bool,
+    ts_recent: u32,
+    // the ranges above snd_una the peer holds already
+    scoreboard: Vec<(u32, u32)>,
+
+    srtt: Option<u64>,
+    rttvar: u64,
+    rto: u64,
+    // Karn's algorithm without timestamps: one segment timed at a time,
+    // never a retransmitted one
+    rtt_sample: Option<(u32, u64)>,
+    rto_deadline: Option<u64>,
+    retries: u32,
+
+    cwnd: u32,
+    ssthresh: u32,
+    dupacks: u32,
+    // NewReno: in fast recovery until this is acknowledged
+    recover: Option<u32>,
+    // next hole to retransmit during recovery
+    rexmit_next: u32,
+
+    // TIME_WAIT, orphaned FIN_WAIT_2 & zero window probes
+    timeout: Option<u64>,
+    persist_deadline: Option<u64>,
+}
+
+impl Tcb {
+    fn new(state: TcpState, local: (u32, u16), remote: (u32, u16), iss: u32) -> Tcb {
+        Tcb {
+            state,
+            local,
+            remote,
+            listener: None,
+            orphaned: false,
+            error: None,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            snd_wl1: 0,
+            snd_wl2: 0,
+            snd_wscale: 0,
+            send_buf: VecDeque::new(),
+            fin_queued: false,
+            mss: DEFAULT_MSS,
+            nodelay: false,
+            rcv_nxt: 0,
+            rcv_wscale: 0,
+            recv_buf: VecDeque::new(),
+            out_of_order: Vec::new(),
+            fin_seq: None,
+            fin_received: false,
+            ack_pending: false,
+            sack_permitted: false,
+            timestamps: false,
+            ts_recent: 0,
+            scoreboard: Vec::new(),
+            srtt: None,
+            rttvar: 0,
+            rto: RTO_INITIAL,
+            rtt_sample: None,
+            rto_deadline: None,
+            retries: 0,
+            cwnd: 0,
+            ssthresh: u32::MAX,
+            dupacks: 0,
+            recover: None,
+            rexmit_next: iss,
+            timeout: None,
+            persist_deadline: None,
+        }
+    }
+
+    /// What the peer offered in its SYN, against what we'd do
+    fn negotiate(&mut self, options: &Options) {
+        self.mss = options.mss.map_or(DEFAULT_MSS, |mss| mss.clamp(64, LOCAL_MSS) as u32);
+        if let Some(wscale) = options.wscale {
+            self.snd_wscale = wscale;
+            self.rcv_wscale = RECV_WSCALE;
+        } else {
+            self.snd_wscale = 0;
+            self.rcv_wscale = 0;
+        }
+        self.sack_permitted = options.sack_permitted;
+        if let Some((value, _)) = options.timestamp {
+            self.timestamps = true;
+            self.ts_recent = value;
+        }
+        self.cwnd = INITIAL_WINDOW_SEGMENTS * self.mss;
+    }
+
+    fn in_flight(&self) -> u32 {
+        self.snd_nxt.wrapping_sub(self.snd_una)
+    }
+
+    fn outstanding(&self) -> u32 {
+        self.snd_max.wrapping_sub(self.snd_una)
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.state.fin_sent() && self.snd_una == self.snd_max && self.send_buf.is_empty()
+    }
+
+    fn recv_window(&self) -> u32 {
+        RECV_BUFFER.saturating_sub(self.recv_buf.len()) as u32
+    }
+
+    fn advertised_window(&self, syn: bool) -> u16 {
+        // the window in a SYN is never scaled
+        let shift = if syn { 0 } else { self.rcv_wscale };
+        (self.recv_window() >> shift).min(u16::MAX as u32) as u16
+    }
+
+    fn header_options(&self, now: u64) -> Options {
+        let mut options = Options::default();
+        if self.timestamps {
+            options.timestamp = Some((now as u32, self.ts_recent));
+        }
+        if self.sack_permitted {
+            options.sack = self
+                .out_of_order
+                .iter()
+                .rev()
+                .take(MAX_SACK_BLOCKS)
+                .map(|(seq, data)| (*seq, seq.wrapping_add(data.len() as u32)))
+                .collect();
+        }
+        options
+    }
+
+    fn syn_options(&self, now: u64, answer: Option<&Options>) -> Options {
+        let mut options = Options { mss: Some(LOCAL_MSS), ..Default::default() };
+        // a SYN offers everything, a SYN-ACK only what the SYN offered
+        let offer = |has: bool| answer.map_or(true, |_| has);
+        if offer(answer.map_or(false, |a| a.wscale.is_some())) {
+            options.wscale = Some(RECV_WSCALE);
+        }
+        if offer(answer.map_or(false, |a| a.sack_permitted)) {
+            options.sack_permitted = true;
+        }
+        if offer(answer.map_or(false, |a| a.timestamp.is_some())) {
+            options.timestamp = Some((now as u32, self.ts_recent));
+        }
+        options
+    }
+
+    fn segment(&self, seq: u32, flags: u8, now: u64, payload: &[u8]) -> Outgoing {
+        let options = self.header_options(now);
+        let ack = if self.state == TcpState::SynSent && flags & TCP_ACK == 0 { 0 } else { self.rcv_nxt };
+        build_segment(self.local, self.remote, seq, ack, flags, self.advertised_window(false), &options, payload)
+    }
+
+    fn syn_segment(&self, now: u64, answer: Option<&Options>) -> Outgoing {
+        let options = self.syn_options(now, answer);
+        let (flags, ack) = if answer.is_some() { (TCP_SYN | TCP_ACK, self.rcv_nxt) } else { (TCP_SYN, 0) };
+        build_segment(self.local, self.remote, self.iss, ack, flags, self.advertised_window(true), &options, &[])
+    }
+
+    fn arm_rto(&mut self, now: u64) {
+        self.rto_deadline = Some(now + self.rto);
+    }
+
+    /// RFC 6298 2.2 & 2.3
+    fn rtt_update(&mut self, rtt: u64) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap();
+        self.rto = (srtt + TICK.max(4 * self.rttvar)).clamp(RTO_MIN, RTO_MAX);
+    }
+
+    fn sacked(&self, seq: u32) -> Option<u32> {
+        self.scoreboard
+            .iter()
+            .find(|&&(left, right)| seq_le(left, seq) && seq_lt(seq, right))
+            .map(|&(_, right)| right)
+    }
+
+    /// Merges SACK blocks in (only those between snd_una and snd_max count)
+    fn sack_update(&mut self, blocks: &[(u32, u32)]) {
+        for &(left, right) in blocks {
+            if !seq_lt(left, right) || seq_lt(left, self.snd_una) || seq_lt(self.snd_max, right) {
+                continue;
+            }
+            self.scoreboard.push((left, right));
+        }
+        self.scoreboard.retain(|&(_, right)| seq_lt(self.snd_una, right));
+        self.scoreboard.sort_by_key(|&(left, _)| left.wrapping_sub(self.snd_una));
+        let mut merged: Vec<(u32, u32)> = Vec::new();
+        for (left, right) in self.scoreboard.drain(..) {
+            match merged.last_mut() {
+                Some(last) if seq_le(left, last.1) => last.1 = seq_max(last.1, right),
+                _ => merged.push((seq_max(left, self.snd_una), right)),
+            }
+        }
+        self.scoreboard = merged;
+    }
+
+    /// Takes in-order payload, holds out-of-order payload for later
+    fn receive(&mut self, seq: u32, mut payload: &[u8]) {
+        let mut seq = seq;
+        // trim what we already have
+        if seq_lt(seq, self.rcv_nxt) {
+            let skip = self.rcv_nxt.wrapping_sub(seq) as usize;
+            if skip >= payload.len() {
+                return;
+            }
+            payload = &payload[skip..];
+            seq = self.rcv_nxt;
+        }
+        // and what doesn't fit the window
+        let room = self.recv_window().saturating_sub(seq.wrapping_sub(self.rcv_nxt)) as usize;
+        payload = &payload[..payload.len().min(room)];
+        if payload.is_empty() {
+            return;
+        }
+
+        if seq != self.rcv_nxt {
+            if self.out_of_order.len() < OUT_OF_ORDER_SEGMENTS {
+                self.out_of_order.push((seq, payload.to_vec()));
+                self.coalesce_out_of_order();
+            }
+            return;
+        }
+
+        self.recv_buf.extend(payload);
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(payload.len() as u32);
+        while let Some(index) = self.out_of_order.iter().position(|(s, _)| seq_le(*s, self.rcv_nxt)) {
+            let (s, data) = self.out_of_order.remove(index);
+            let skip = self.rcv_nxt.wrapping_sub(s) as usize;
+            if skip < data.len() {
+                self.recv_buf.extend(&data[skip..]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add((data.len() - skip) as u32);
+            }
+        }
+    }
+
+    fn coalesce_out_of_order(&mut self) {
+        let base = self.rcv_nxt;
+        self.out_of_order.sort_by_key(|(seq, _)| seq.wrapping_sub(base));
+        let mut merged: Vec<(u32, Vec<u8>)> = Vec::new();
+        for (seq, data) in self.out_of_order.drain(..) {
+            if let Some((last_seq, last)) = merged.last_mut() {
+                let end = last_seq.wrapping_add(last.len() as u32);
+                if seq_le(seq, end) {
+                    let skip = end.wrapping_sub(seq) as usize;
+                    if skip < data.len() {
+                        last.extend_from_slice(&data[skip..]);
+                    }
+                    continue;
+                }
+            }
+            merged.push((seq, data));
+        }
+        self.out_of_order = merged;
+    }
+}
+
+struct Listener {
+    addr: u32,
+    port: u16,
+    backlog: usize,
+    // established, waiting for accept()
+    accept_queue: VecDeque<u64>,
+    // half-open, in SYN_RECEIVED
+    syn_queue: usize,
+}
+
+struct Tcp {
+    connections: BTreeMap<u64, Tcb>,
+    by_quad: BTreeMap<Quad, u64>,
+    listeners: BTreeMap<u64, Listener>,
+    next_id: u64,
+    ports: Option<NetPorts>,
+    secret: [u64; 2],
+    outbox: Vec<Outgoing>,
+}
+
+static TCP: Mutex<Tcp> = Mutex::new(Tcp {
+    connections: BTreeMap::new(),
+    by_quad: BTreeMap::new(),
+    listeners: BTreeMap::new(),
+    next_id: 1,
+    ports: None,
+    secret: [0; 2],
+    outbox: Vec::new(),
+});
+
+static TIMER_ARMED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    fn rand() -> u64;
+    fn taskCreateKernel(entry: u64, arg: u64) -> *mut c_void;
+    fn taskNameKernel(task: *mut c_void, name: *const u8, len: usize);
+    fn sleep(ms: u32);
+}
+
+impl Tcp {
+    fn ports(&mut self) -> &mut NetPorts {
+        self.ports.get_or_insert_with(NetPorts::new)
+    }
+
+    /// RFC 6528: a clock plus a keyed hash of the connection
+    fn initial_sequence(&mut self, quad: Quad, now: u64) -> u32 {
+        self.hash(quad, 0).wrapping_add((now * 250) as u32)
+    }
+
+    fn hash(&mut self, quad: Quad, extra: u32) -> u32 {
+        if self.secret == [0; 2] {
+            self.secret = unsafe { [rand(), rand()] };
+        }
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(self.secret[0]);
+        hasher.write_u32(quad.0);
+        hasher.write_u16(quad.1);
+        hasher.write_u32(quad.2);
+        hasher.write_u16(quad.3);
+        hasher.write_u32(extra);
+        hasher.write_u64(self.secret[1]);
+        hasher.finish() as u32
+    }
+
+    fn insert(&mut self, tcb: Tcb) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.by_quad.insert((tcb.local.0, tcb.local.1, tcb.remote.0, tcb.remote.1), id);
+        self.connections.insert(id, tcb);
+        id
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(tcb) = self.connections.remove(&id) {
+            self.by_quad.remove(&(tcb.local.0, tcb.local.1, tcb.remote.0, tcb.remote.1));
+            if let Some(listener) = tcb.listener.and_then(|l| self.listeners.get_mut(&l)) {
+                listener.accept_queue.retain(|&c| c != id);
+                if tcb.state == TcpState::SynReceived {
+                    listener.syn_queue = listener.syn_queue.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// A connection that the socket can't see anymore and that's done
+    fn reap(&mut self, id: u64) {
+        let Some(tcb) = self.connections.get(&id) else {
+            return;
+        };
+        let unaccepted = tcb.listener.is_some();
+        if tcb.state == TcpState::Closed && (tcb.orphaned || unaccepted) {
+            self.remove(id);
+        }
+    }
+
+    fn listener_for(&self, addr: u32, port: u16) -> Option<u64> {
+        let mut wildcard = None;
+        for (&id, l) in &self.listeners {
+            if l.port == port {
+                if l.addr == addr {
+                    return Some(id);
+                }
+                if l.addr == 0 {
+                    wildcard = Some(id);
+                }
+            }
+        }
+        wildcard
+    }
+
+    // ==========================
+    // SYN cookies
+    // ==========================
+
+    fn cookie_counter(now: u64) -> u32 {
+        (now / COOKIE_PERIOD) as u32
+    }
+
+    /// counter (5 bits) | MSS index (3 bits) | keyed hash (24 bits)
+    fn cookie(&mut self, quad: Quad, peer_isn: u32, mss: u16, now: u64) -> u32 {
+        let counter = Self::cookie_counter(now) & 0x1f;
+        let index = COOKIE_MSS.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+        let hash = self.hash(quad, peer_isn ^ counter.rotate_left(27)) & 0x00ff_ffff;
+        counter << 27 | index << 24 | hash
+    }
+
+    /// The MSS a returning ACK's cookie was minted with
+    fn check_cookie(&mut self, quad: Quad, peer_isn: u32, cookie: u32, now: u64) -> Option<u16> {
+        let counter = cookie >> 27;
+        let current = Self::cookie_counter(now) & 0x1f;
+        if current.wrapping_sub(counter) & 0x1f > 1 {
+            return None;
+        }
+        let hash = self.hash(quad, peer_isn ^ counter.rotate_left(27)) & 0x00ff_ffff;
+        if hash != cookie & 0x00ff_ffff {
+            return None;
+        }
+        Some(COOKIE_MSS[((cookie >> 24) & 0x7) as usize])
+    }
+
+    // ==========================
+    // Input
+    // ==========================
+
+    fn input(&mut self, src: u32, dst: u32, seg: &Segment, now: u64) -> bool {
+        let quad = (dst, seg.dport, src, seg.sport);
+        if let Some(&id) = self.by_quad.get(&quad) {
+            self.segment_arrives(id, seg, now);
+            self.output(id, now);
+            self.reap(id);
+            return true;
+        }
+        if let Some(listener) = self.listener_for(dst, seg.dport) {
+            self.listen_input(listener, quad, seg, now);
+            return true;
+        }
+        self.outbox.extend(reset_for((dst, seg.dport), (src, seg.sport), seg));
+        false
+    }
+
+    fn listen_input(&mut self, listener: u64, quad: Quad, seg: &Segment, now: u64) {
+        let local = (quad.0, quad.1);
+        let remote = (quad.2, quad.3);
+        if seg.flags & TCP_RST != 0 {
+            return;
+        }
+
+        if seg.flags & TCP_ACK != 0 {
+            // only a SYN cookie coming back makes sense here
+            if seg.flags & TCP_SYN == 0 {
+                if let Some(id) = self.cookie_ack(listener, quad, seg, now) {
+                    self.segment_arrives(id, seg, now);
+                    self.output(id, now);
+                    return;
+                }
+            }
+            self.outbox.extend(reset_for(local, remote, seg));
+            return;
+        }
+        if seg.flags & TCP_SYN == 0 {
+            return;
+        }
+
+        let l = &self.listeners[&listener];
+        if l.accept_queue.len() >= l.backlog {
+            return;
+        }
+
+        if l.syn_queue >= l.backlog {
+            // queue full: answer without keeping any state
+            let mss = seg.options.mss.unwrap_or(DEFAULT_MSS as u16).min(LOCAL_MSS);
+            let cookie = self.cookie(quad, seg.seq, mss, now);
+            let mut tcb = Tcb::new(TcpState::SynReceived, local, remote, cookie);
+            tcb.rcv_nxt = seg.seq.wrapping_add(1);
+            tcb.negotiate(&seg.options);
+            let mut options = tcb.syn_options(now, Some(&seg.options));
+            if let Some(ts) = options.timestamp.as_mut() {
+                // comes back as the ACK's TSecr
+                let wscale = seg.options.wscale.map_or(COOKIE_NO_WSCALE, |w| w as u32);
+                let sack = seg.options.sack_permitted as u32;
+                ts.0 = (ts.0 & !((1 << COOKIE_TS_BITS) - 1)) | sack << 4 | wscale;
+            }
+            let window = tcb.advertised_window(true);
+            self.outbox.push(build_segment(
+                local,
+                remote,
+                cookie,
+                tcb.rcv_nxt,
+                TCP_SYN | TCP_ACK,
+                window,
+                &options,
+                &[],
+            ));
+            return;
+        }
+
+        let iss = self.initial_sequence(quad, now);
+        let mut tcb = Tcb::new(TcpState::SynReceived, local, remote, iss);
+        tcb.listener = Some(listener);
+        tcb.rcv_nxt = seg.seq.wrapping_add(1);
+        tcb.snd_nxt = iss.wrapping_add(1);
+        tcb.snd_max = tcb.snd_nxt;
+        tcb.snd_wnd = seg.window as u32;
+        tcb.snd_wl1 = seg.seq;
+        tcb.negotiate(&seg.options);
+        self.outbox.push(tcb.syn_segment(now, Some(&seg.options)));
+        tcb.arm_rto(now);
+        self.listeners.get_mut(&listener).unwrap().syn_queue += 1;
+        self.insert(tcb);
+    }
+
+    /// The final ACK of a handshake that only lived in a SYN cookie
+    fn cookie_ack(&mut self, listener: u64, quad: Quad, seg: &Segment, now: u64) -> Option<u64> {
+        let l = &self.listeners[&listener];
+        if l.accept_queue.len() >= l.backlog {
+            return None;
+        }
+        let peer_isn = seg.seq.wrapping_sub(1);
+        let cookie = seg.ack.wrapping_sub(1);
+        let mss = self.check_cookie(quad, peer_isn, cookie, now)?;
+
+        // what the SYN offered came back in our own echoed timestamp
+        let mut offered = Options { mss: Some(mss), ..Default::default() };
+        if let Some((value, echo)) = seg.options.timestamp {
+            let wscale = echo & 0xf;
+            if wscale != COOKIE_NO_WSCALE {
+                offered.wscale = Some(wscale as u8);
+            }
+            offered.sack_permitted = echo & 0x10 != 0;
+            offered.timestamp = Some((value, 0));
+        }
+
+        let mut tcb = Tcb::new(TcpState::SynReceived, (quad.0, quad.1), (quad.2, quad.3), cookie);
+        tcb.listener = Some(listener);
+        tcb.rcv_nxt = seg.seq;
+        tcb.snd_nxt = cookie.wrapping_add(1);
+        tcb.snd_max = tcb.snd_nxt;
+        tcb.snd_wl1 = peer_isn;
+        tcb.negotiate(&offered);
+        // the SYN_RECEIVED it never had, segment_arrives() moves it on
+        self.listeners.get_mut(&listener).unwrap().syn_queue += 1;
+        Some(self.insert(tcb))
+    }
+
+    /// RFC 9293 3.10.7.3 & 3.10.7.4, for everything past LISTEN
+    fn segment_arrives(&mut self, id: u64, seg: &Segment, now: u64) {
+        let tcb = self.connections.get_mut(&id).unwrap();
+
+        if tcb.state == TcpState::SynSent {
+            Self::syn_sent_input(tcb, seg, now, &mut self.outbox);
+            return;
+        }
+
+        // first, is it in the window at all (plus PAWS, RFC 7323 5)
+        let len = seg.len();
+        let window = tcb.recv_window();
+        let in_window = |seq: u32| seq_le(tcb.rcv_nxt, seq) && seq_lt(seq, tcb.rcv_nxt.wrapping_add(window));
+        let acceptable = match (len, window) {
+            (0, 0) => seg.seq == tcb.rcv_nxt,
+            (0, _) => in_window(seg.seq),
+            (_, 0) => false,
+            _ => in_window(seg.seq) || in_window(seg.seq.wrapping_add(len - 1)),
+        };
+        let stale = tcb.timestamps
+            && seg.flags & TCP_RST == 0
+            && seg.options.timestamp.map_or(false, |(value, _)| seq_lt(value, tcb.ts_recent));
+        if stale {
+            tcb.ack_pending = true;
+            return;
+        }
+        if !acceptable {
+            // a zero window still takes ACKs, URG & RST
+            let ack_only = window == 0 && seg.seq == tcb.rcv_nxt;
+            if !ack_only {
+                if seg.flags & TCP_RST == 0 {
+                    tcb.ack_pending = true;
+                }
+                return;
+            }
+        }
+
+        // RST, RFC 5961 3.2: exactly at rcv_nxt resets, elsewhere in the
+        // window only gets a challenge ACK
+        if seg.flags & TCP_RST != 0 {
+            if seg.seq != tcb.rcv_nxt {
+                tcb.ack_pending = true;
+                return;
+            }
+            let passive = tcb.state == TcpState::SynReceived && tcb.listener.is_some();
+            if !passive && tcb.state != TcpState::TimeWait {
+                tcb.error = Some(if tcb.state == TcpState::SynReceived { libc::ECONNREFUSED } else { libc::ECONNRESET });
+            }
+            Self::close_tcb(tcb);
+            return;
+        }
+
+        // SYN in a synchronized state, RFC 5961 4.2
+        if seg.flags & TCP_SYN != 0 {
+            tcb.ack_pending = true;
+            return;
+        }
+        if seg.flags & TCP_ACK == 0 {
+            return;
+        }
+
+        // timestamps to echo from now on (RFC 7323 4.3)
+        if let Some((value, _)) = seg.options.timestamp {
+            if tcb.timestamps && seq_le(seg.seq, tcb.rcv_nxt) {
+                tcb.ts_recent = value;
+            }
+        }
+
+        if tcb.state == TcpState::SynReceived {
+            if !(seq_lt(tcb.snd_una, seg.ack) && seq_le(seg.ack, tcb.snd_max)) {
+                self.outbox.extend(reset_for(tcb.local, tcb.remote, seg));
+                return;
+            }
+            tcb.state = TcpState::Established;
+            tcb.snd_una = tcb.snd_una.wrapping_add(1);
+            tcb.snd_wnd = (seg.window as u32) << tcb.snd_wscale;
+            tcb.snd_wl1 = seg.seq;
+            tcb.snd_wl2 = seg.ack;
+            tcb.rto_deadline = None;
+            tcb.retries = 0;
+            if let Some(l) = tcb.listener {
+                if let Some(listener) = self.listeners.get_mut(&l) {
+                    listener.syn_queue = listener.syn_queue.saturating_sub(1);
+                    listener.accept_queue.push_back(id);
+                }
+            }
+        }
+        let tcb = self.connections.get_mut(&id).unwrap();
+
+        if tcb.state == TcpState::TimeWait {
+            // a retransmitted FIN: ACK it again and start over
+            if seg.flags & TCP_FIN != 0 {
+                tcb.ack_pending = true;
+                tcb.timeout = Some(now + 2 * MSL);
+            }
+            return;
+        }
+
+        Self::ack_input(tcb, seg, now, &mut self.outbox);
+        if tcb.state == TcpState::Closed {
+            return;
+        }
+
+        // the payload, while the peer can still send any
+        if matches!(tcb.state, TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2) {
+            if !seg.payload.is_empty() {
+                let offset = (seg.flags & TCP_SYN != 0) as u32;
+                tcb.receive(seg.seq.wrapping_add(offset), seg.payload);
+                tcb.ack_pending = true;
+            }
+            if seg.flags & TCP_FIN != 0 {
+                tcb.fin_seq = Some(seg.seq.wrapping_add(seg.payload.len() as u32));
+                tcb.ack_pending = true;
+            }
+        }
+
+        if tcb.fin_seq == Some(tcb.rcv_nxt) && !tcb.fin_received {
+            tcb.fin_received = true;
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(1);
+            tcb.ack_pending = true;
+            match tcb.state {
+                TcpState::Established => tcb.state = TcpState::CloseWait,
+                TcpState::FinWait1 if tcb.fin_acked() => Self::enter_time_wait(tcb, now),
+                TcpState::FinWait1 => tcb.state = TcpState::Closing,
+                TcpState::FinWait2 => Self::enter_time_wait(tcb, now),
+                _ => {}
+            }
+        }
+    }
+
+    fn syn_sent_input(tcb: &mut Tcb, seg: &Segment, now: u64, outbox: &mut Vec<Outgoing>) {
+        let ack_ok = seg.flags & TCP_ACK != 0;
+        if ack_ok && (seq_le(seg.ack, tcb.iss) || seq_lt(tcb.snd_max, seg.ack)) {
+            outbox.extend(reset_for(tcb.local, tcb.remote, seg));
+            return;
+        }
+        if seg.flags & TCP_RST != 0 {
+            if ack_ok {
+                tcb.error = Some(libc::ECONNREFUSED);
+                Self::close_tcb(tcb);
+            }
+            return;
+        }
+        if seg.flags & TCP_SYN == 0 {
+            return;
+        }
+
+        tcb.rcv_nxt = seg.seq.wrapping_add(1);
+        tcb.negotiate(&seg.options);
+        if tcb.retries > 0 {
+            // the SYN was retransmitted, RFC 5681 3.1 wants one segment
+            tcb.cwnd = tcb.mss;
+        }
+        tcb.snd_wnd = seg.window as u32;
+        tcb.snd_wl1 = seg.seq;
+        tcb.snd_wl2 = seg.ack;
+
+        if ack_ok {
+            tcb.snd_una = seg.ack;
+            tcb.state = TcpState::Established;
+            tcb.rto_deadline = None;
+            if tcb.retries == 0 {
+                if let Some((_, sent)) = tcb.rtt_sample.take() {
+                    tcb.rtt_update(now.saturating_sub(sent));
+                }
+            }
+            tcb.retries = 0;
+            tcb.rtt_sample = None;
+            tcb.ack_pending = true;
+        } else {
+            // simultaneous open
+            tcb.state = TcpState::SynReceived;
+            outbox.push(tcb.syn_segment(now, Some(&seg.options)));
+        }
+    }
+
+    /// RFC 9293 3.10.7.4 "fifth, check the ACK field", with the RTT
+    /// estimate, NewReno and SACK riding along
+    fn ack_input(tcb: &mut Tcb, seg: &Segment, now: u64, outbox: &mut Vec<Outgoing>) {
+        if seq_lt(tcb.snd_max, seg.ack) {
+            tcb.ack_pending = true;
+            return;
+        }
+        if tcb.sack_permitted && !seg.options.sack.is_empty() {
+            tcb.sack_update(&seg.options.sack);
+        }
+
+        let window = (seg.window as u32) << tcb.snd_wscale;
+        if seq_lt(tcb.snd_una, seg.ack) {
+            let acked = seg.ack.wrapping_sub(tcb.snd_una);
+            // the FIN has a sequence number but no byte in send_buf
+            let data = (acked as usize).min(tcb.send_buf.len());
+            tcb.send_buf.drain(..data);
+            tcb.snd_una = seg.ack;
+            if seq_lt(tcb.snd_nxt, tcb.snd_una) {
+                tcb.snd_nxt = tcb.snd_una;
+            }
+            tcb.scoreboard.retain(|&(_, right)| seq_lt(seg.ack, right));
+            tcb.retries = 0;
+
+            // RTT: timestamps time every ACK, otherwise Karn's one sample
+            match seg.options.timestamp {
+                Some((_, echo)) if tcb.timestamps && echo != 0 => {
+                    tcb.rtt_update((now as u32).wrapping_sub(echo) as u64);
+                }
+                _ => {
+                    if let Some((seq, sent)) = tcb.rtt_sample {
+                        if seq_lt(seq, seg.ack) {
+                            tcb.rtt_update(now.saturating_sub(sent));
+                            tcb.rtt_sample = None;
+                        }
+                    }
+                }
+            }
+
+            match tcb.recover {
+                Some(recover) if seq_lt(seg.ack, recover) => {
+                    // partial ACK: the next hole goes out right away and
+                    // the window deflates by what left (RFC 6582 3.2 5)
+                    tcb.cwnd = tcb.cwnd.saturating_sub(acked).saturating_add(tcb.mss);
+                    tcb.rexmit_next = seq_max(tcb.rexmit_next, seg.ack);
+                    Self::retransmit_hole(tcb, now, outbox);
+                }
+                Some(_) => {
+                    // full ACK: out of recovery
+                    tcb.cwnd = tcb.ssthresh.min(tcb.outstanding().max(tcb.mss) + tcb.mss);
+                    tcb.recover = None;
+                }
+                None if tcb.cwnd < tcb.ssthresh => tcb.cwnd += acked.min(tcb.mss),
+                None => tcb.cwnd += (tcb.mss * tcb.mss / tcb.cwnd.max(1)).max(1),
+            }
+            tcb.dupacks = 0;
+
+            tcb.rto_deadline = if tcb.outstanding() > 0 { Some(now + tcb.rto) } else { None };
+        } else if seg.ack == tcb.snd_una
+            && seg.payload.is_empty()
+            && seg.flags & (TCP_SYN | TCP_FIN) == 0
+            && window == tcb.snd_wnd
+            && tcb.outstanding() > 0
+        {
+            // a duplicate ACK (RFC 5681 2)
+            tcb.dupacks += 1;
+            if tcb.recover.is_some() {
+                tcb.cwnd += tcb.mss;
+            } else if tcb.dupacks == DUPACK_THRESHOLD {
+                tcb.ssthresh = (tcb.outstanding() / 2).max(2 * tcb.mss);
+                tcb.cwnd = tcb.ssthresh + DUPACK_THRESHOLD * tcb.mss;
+                tcb.recover = Some(tcb.snd_max);
+                tcb.rexmit_next = tcb.snd_una;
+                tcb.rtt_sample = None;
+                Self::retransmit_hole(tcb, now, outbox);
+            }
+        }
+
+        // window update (RFC 9293 3.10.7.4)
+        if seq_lt(tcb.snd_wl1, seg.seq) || (tcb.snd_wl1 == seg.seq && seq_le(tcb.snd_wl2, seg.ack)) {
+            tcb.snd_wnd = window;
+            tcb.snd_wl1 = seg.seq;
+            tcb.snd_wl2 = seg.ack;
+            if window > 0 {
+                tcb.persist_deadline = None;
+            }
+        }
+
+        if tcb.fin_acked() {
+            match tcb.state {
+                TcpState::FinWait1 => {
+                    tcb.state = TcpState::FinWait2;
+                    if tcb.orphaned {
+                        tcb.timeout = Some(now + FIN_TIMEOUT);
+                    }
+                }
+                TcpState::Closing => Self::enter_time_wait(tcb, now),
+                TcpState::LastAck => Self::close_tcb(tcb),
+                _ => {}
+            }
+        }
+    }
+
+    /// The first byte from rexmit_next on that the peer doesn't have
+    fn retransmit_hole(tcb: &mut Tcb, now: u64, outbox: &mut Vec<Outgoing>) {
+        let mut seq = seq_max(tcb.rexmit_next, tcb.snd_una);
+        while let Some(right) = tcb.sacked(seq) {
+            seq = right;
+        }
+        if !seq_lt(seq, tcb.snd_max) {
+            return;
+        }
+        let offset = seq.wrapping_sub(tcb.snd_una) as usize;
+        let mut end = offset + tcb.mss as usize;
+        // stop at the next SACKed block
+        if let Some(&(left, _)) = tcb.scoreboard.iter().find(|&&(left, _)| seq_lt(seq, left)) {
+            end = end.min(left.wrapping_sub(tcb.snd_una) as usize);
+        }
+        let end = end.min(tcb.send_buf.len());
+        if offset < end {
+            let payload: Vec<u8> = tcb.send_buf.range(offset..end).copied().collect();
+            outbox.push(tcb.segment(seq, TCP_ACK | TCP_PSH, now, &payload));
+            tcb.rexmit_next = seq.wrapping_add(payload.len() as u32);
+        } else if tcb.state.fin_sent() && offset == tcb.send_buf.len() {
+            outbox.push(tcb.segment(seq, TCP_ACK | TCP_FIN, now, &[]));
+            tcb.rexmit_next = seq.wrapping_add(1);
+        }
+        tcb.ack_pending = false;
+        tcb.rto_deadline = Some(now + tcb.rto);
+    }
+
+    fn enter_time_wait(tcb: &mut Tcb, now: u64) {
+        tcb.state = TcpState::TimeWait;
+        tcb.timeout = Some(now + 2 * MSL);
+        tcb.rto_deadline = None;
+        tcb.persist_deadline = None;
+    }
+
+    fn close_tcb(tcb: &mut Tcb) {
+        tcb.state = TcpState::Closed;
+        tcb.rto_deadline = None;
+        tcb.persist_deadline = None;
+        tcb.timeout = None;
+        tcb.ack_pending = false;
+    }
+
+    // ==========================
+    // Output
+    // ==========================
+
+    /// Sends what the windows allow: new data (Nagle permitting), the FIN
+    /// once everything before it went, or at least the pending ACK
+    fn output(&mut self, id: u64, now: u64) {
+        let Some(tcb) = self.connections.get_mut(&id) else {
+            return;
+        };
+        if tcb.state == TcpState::SynReceived {
+            // SYN-ACK again, offering what was agreed on the first time
+            if tcb.ack_pending {
+                let agreed = Options {
+                    wscale: (tcb.rcv_wscale != 0).then_some(tcb.snd_wscale),
+                    sack_permitted: tcb.sack_permitted,
+                    timestamp: tcb.timestamps.then_some((tcb.ts_recent, 0)),
+                    ..Default::default()
+                };
+                self.outbox.push(tcb.syn_segment(now, Some(&agreed)));
+                tcb.ack_pending = false;
+            }
+            return;
+        }
+        if !tcb.state.synchronized() {
+            return;
+        }
+
+        // during recovery, holes first
+        if tcb.recover.is_some() {
+            while tcb.in_flight() < tcb.cwnd && seq_lt(tcb.rexmit_next, tcb.snd_max) {
+                let before = tcb.rexmit_next;
+                Self::retransmit_hole(tcb, now, &mut self.outbox);
+                if tcb.rexmit_next == before {
+                    break;
+                }
+            }
+        }
+
+        let may_send_data = matches!(tcb.state, TcpState::Established | TcpState::CloseWait);
+        let window = tcb.snd_wnd.min(tcb.cwnd);
+        let mut sent = false;
+        while may_send_data {
+            let offset = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+            let unsent = tcb.send_buf.len().saturating_sub(offset);
+            if unsent == 0 {
+                break;
+            }
+            let room = window.saturating_sub(tcb.in_flight()) as usize;
+            let len = unsent.min(room).min(tcb.mss as usize);
+            if len == 0 {
+                // zero window: probe it once the persist timer says so
+                if tcb.snd_wnd == 0 && tcb.in_flight() == 0 && tcb.persist_deadline.is_none() {
+                    tcb.persist_deadline = Some(now + tcb.rto);
+                }
+                break;
+            }
+            // Nagle (RFC 896): a small segment waits for the one in flight
+            if len < tcb.mss as usize && !tcb.nodelay && tcb.in_flight() > 0 && !tcb.fin_queued {
+                break;
+            }
+
+            let payload: Vec<u8> = tcb.send_buf.range(offset..offset + len).copied().collect();
+            let last = offset + len == tcb.send_buf.len();
+            let flags = TCP_ACK | if last { TCP_PSH } else { 0 };
+            let seq = tcb.snd_nxt;
+            self.outbox.push(tcb.segment(seq, flags, now, &payload));
+            tcb.snd_nxt = tcb.snd_nxt.wrapping_add(len as u32);
+            if seq_lt(tcb.snd_max, tcb.snd_nxt) {
+                // new data: time it if nothing else is being timed
+                if tcb.rtt_sample.is_none() && !tcb.timestamps {
+                    tcb.rtt_sample = Some((seq, now));
+                }
+                tcb.snd_max = tcb.snd_nxt;
+            }
+            if tcb.rto_deadline.is_none() {
+                tcb.arm_rto(now);
+            }
+            sent = true;
+        }
+
+        // the FIN follows the last byte
+        let all_sent = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize >= tcb.send_buf.len();
+        if tcb.fin_queued && all_sent && matches!(tcb.state, TcpState::Established | TcpState::CloseWait) {
+            let seq = tcb.snd_nxt;
+            self.outbox.push(tcb.segment(seq, TCP_ACK | TCP_FIN, now, &[]));
+            tcb.snd_nxt = tcb.snd_nxt.wrapping_add(1);
+            tcb.snd_max = seq_max(tcb.snd_max, tcb.snd_nxt);
+            tcb.state = if tcb.state == TcpState::CloseWait { TcpState::LastAck } else { TcpState::FinWait1 };
+            if tcb.rto_deadline.is_none() {
+                tcb.arm_rto(now);
+            }
+            sent = true;
+        }
+
+        if !sent && tcb.ack_pending {
+            let seq = tcb.snd_nxt;
+            self.outbox.push(tcb.segment(seq, TCP_ACK, now, &[]));
+        }
+        tcb.ack_pending = false;
+    }
+
+    // ==========================
+    // Timers
+    // ==========================
+
+    fn tick(&mut self, now: u64) {
+        let ids: Vec<u64> = self.connections.keys().copied().collect();
+        for id in ids {
+            let tcb = self.connections.get_mut(&id).unwrap();
+
+            if tcb.timeout.map_or(false, |t| now >= t) {
+                // TIME_WAIT is over, or the orphan's peer never sent a FIN
+                Self::close_tcb(tcb);
+                self.reap(id);
+                continue;
+            }
+
+            if tcb.persist_deadline.map_or(false, |t| now >= t) {
+                // one byte past the window (RFC 9293 3.8.6.1)
+                let offset = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+                if let Some(&byte) = tcb.send_buf.get(offset) {
+                    let seq = tcb.snd_nxt;
+                    self.outbox.push(tcb.segment(seq, TCP_ACK, now, &[byte]));
+                }
+                tcb.rto = (tcb.rto * 2).min(RTO_MAX);
+                tcb.persist_deadline = Some(now + tcb.rto);
+            }
+
+            if tcb.rto_deadline.map_or(false, |t| now >= t) {
+                self.retransmit_timeout(id, now);
+                self.reap(id);
+            }
+        }
+    }
+
+    /// RFC 6298 5.4-5.7, with RFC 5681 3.1's loss window
+    fn retransmit_timeout(&mut self, id: u64, now: u64) {
+        let tcb = self.connections.get_mut(&id).unwrap();
+        tcb.retries += 1;
+        let limit = if tcb.state.synchronized() { DATA_RETRIES } else { SYN_RETRIES };
+        if tcb.retries > limit {
+            tcb.error = Some(libc::ETIMEDOUT);
+            self.outbox.push(tcb.segment(tcb.snd_nxt, TCP_RST | TCP_ACK, now, &[]));
+            Self::close_tcb(tcb);
+            return;
+        }
+
+        tcb.rto = (tcb.rto * 2).min(RTO_MAX);
+        tcb.rtt_sample = None;
+        match tcb.state {
+            TcpState::SynSent => {
+                self.outbox.push(tcb.syn_segment(now, None));
+                tcb.arm_rto(now);
+            }
+            TcpState::SynReceived => {
+                tcb.ack_pending = true;
+                tcb.arm_rto(now);
+                self.output(id, now);
+            }
+            _ => {
+                tcb.ssthresh = (tcb.outstanding() / 2).max(2 * tcb.mss);
+                tcb.cwnd = tcb.mss;
+                tcb.recover = None;
+                tcb.dupacks = 0;
+                // the peer may have thrown away what it SACKed (RFC 2018 8)
+                tcb.scoreboard.clear();
+                tcb.snd_nxt = tcb.snd_una;
+                tcb.rexmit_next = tcb.snd_una;
+                Self::retransmit_hole(tcb, now, &mut self.outbox);
+                tcb.snd_nxt = seq_max(tcb.snd_nxt, tcb.rexmit_next);
+                tcb.arm_rto(now);
+            }
+        }
+    }
+
+    fn take_outbox(&mut self) -> Vec<Outgoing> {
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+// ==========================
+// IPv4 glue
+// ==========================
+
+/// The source address for `dst`: 127.0.0.1 on loopback, `dst` itself for
+/// one of our own addresses, else the address of the NIC it leaves through
+fn route_source(dst: u32) -> Option<u32> {
+    if dst >> 24 == 127 {
+        return Some(0x7f00_0001);
+    }
+    if net_ipv4_is_local(dst) {
+        return Some(dst);
+    }
+    net_ipv4_route(dst, 0).map(|nic| u32::from_be_bytes(nic.ip))
+}
+
+/// Hands segments to ipv4.rs, the ones for a local address go straight
+/// back into input (along with whatever that answers)
+fn transmit(outgoing: Vec<Outgoing>) {
+    let mut queue = VecDeque::from(outgoing);
+    while let Some((src, dst, segment)) = queue.pop_front() {
+        if net_ipv4_is_local(dst) {
+            let Some(seg) = parse_segment(src, dst, &segment) else {
+                continue;
+            };
+            let mut tcp = TCP.lock().unwrap();
+            tcp.input(src, dst, &seg, timer_ticks());
+            queue.extend(tcp.take_outbox());
+            continue;
+        }
+        let Some(nic) = net_ipv4_route(dst, src) else {
+            continue;
+        };
+        let mut packet = ipv4_alloc_buffer(segment.len());
+        packet[NET_IPv4_PAYLOAD..].copy_from_slice(&segment);
+        net_ipv4_send(&nic, &mut packet, IP_PROTO_TCP, &dst.to_be_bytes());
+    }
+}
+
+/// Runs `f` on the TCP state, then sends whatever it queued up
+fn with_tcp<T>(f: impl FnOnce(&mut Tcp, u64) -> T) -> T {
+    arm_timer();
+    let (result, outgoing) = {
+        let mut tcp = TCP.lock().unwrap();
+        let result = f(&mut tcp, timer_ticks());
+        (result, tcp.take_outbox())
+    };
+    transmit(outgoing);
+    result
+}
+
+/// Starts tcpTimer() the first time TCP gets used
+fn arm_timer() {
+    if !TIMER_ARMED.swap(true, Ordering::SeqCst) {
+        static NAME: &[u8] = b"tcptimer";
+        unsafe {
+            let task = taskCreateKernel(tcpTimer as u64, 0);
+            taskNameKernel(task, NAME.as_ptr(), NAME.len());
+        }
+    }
+}
+
+/// Kernel thread, runs every connection's timers each tick
+extern "C" fn tcpTimer() {
+    loop {
+        unsafe { sleep(TICK as u32) };
+        let outgoing = {
+            let mut tcp = TCP.lock().unwrap();
+            tcp.tick(timer_ticks());
+            tcp.take_outbox()
+        };
+        transmit(outgoing);
+    }
+}
+
+/// An IPv4 TCP segment for a local address, from ipv4.rs on the net helper
+/// thread. False when no connection or listener wanted it (it got a RST).
+pub(crate) fn tcp_input(src: u32, dst: u32, raw: &[u8]) -> bool {
+    let Some(seg) = parse_segment(src, dst, raw) else {
+        return false;
+    };
+    let (claimed, outgoing) = {
+        let mut tcp = TCP.lock().unwrap();
+        let claimed = tcp.input(src, dst, &seg, timer_ticks());
+        (claimed, tcp.take_outbox())
+    };
+    transmit(outgoing);
+    claimed
+}
+
+// ==========================
+// Socket side
+// ==========================
+
+/// A local port for `addr`: `port` itself, or an ephemeral one for 0
+pub(crate) fn tcp_port_reserve(port: u16) -> Result<u16> {
+    with_tcp(|tcp, _| {
+        if port == 0 {
+            return Ok(tcp.ports().gen());
+        }
+        if tcp.ports().mark_safe(port) { Ok(port) } else { Err(errno(libc::EADDRINUSE)) }
+    })
+}
+
+pub(crate) fn tcp_port_release(port: u16) {
+    with_tcp(|tcp, _| {
+        if tcp.ports().is_allocated(port) {
+            tcp.ports().free(port);
+        }
+    })
+}
+
+pub(crate) fn tcp_listen(addr: u32, port: u16, backlog: usize) -> Result<u64> {
+    with_tcp(|tcp, _| {
+        if tcp.listeners.values().any(|l| l.port == port && (l.addr == addr || l.addr == 0 || addr == 0)) {
+            return Err(errno(libc::EADDRINUSE));
+        }
+        let id = tcp.next_id;
+        tcp.next_id += 1;
+        tcp.listeners.insert(
+            id,
+            Listener { addr, port, backlog: backlog.clamp(1, 4096), accept_queue: VecDeque::new(), syn_queue: 0 },
+        );
+        Ok(id)
+    })
+}
+
+/// Closes a listener and resets whatever was waiting on it
+pub(crate) fn tcp_unlisten(listener: u64) {
+    with_tcp(|tcp, now| {
+        if tcp.listeners.remove(&listener).is_none() {
+            return;
+        }
+        let children: Vec<u64> =
+            tcp.connections.iter().filter(|(_, c)| c.listener == Some(listener)).map(|(&id, _)| id).collect();
+        for id in children {
+            let tcb = tcp.connections.get_mut(&id).unwrap();
+            let reset = tcb.segment(tcb.snd_nxt, TCP_RST | TCP_ACK, now, &[]);
+            tcp.outbox.push(reset);
+            tcp.remove(id);
+        }
+    })
+}
+
+pub(crate) fn tcp_set_backlog(listener: u64, backlog: usize) {
+    with_tcp(|tcp, _| {
+        if let Some(l) = tcp.listeners.get_mut(&listener) {
+            l.backlog = backlog.clamp(1, 4096);
+        }
+    })
+}
+
+/// The next established connection, EAGAIN when there's none yet
+pub(crate) fn tcp_accept(listener: u64) -> Result<u64> {
+    with_tcp(|tcp, _| {
+        let l = tcp.listeners.get_mut(&listener).ok_or(errno(libc::EINVAL))?;
+        let id = l.accept_queue.pop_front().ok_or(errno(libc::EAGAIN))?;
+        if let Some(tcb) = tcp.connections.get_mut(&id) {
+            tcb.listener = None;
+        }
+        Ok(id)
+    })
+}
+
+pub(crate) fn tcp_acceptable(listener: u64) -> bool {
+    TCP.lock().unwrap().listeners.get(&listener).map_or(false, |l| !l.accept_queue.is_empty())
+}
+
+/// Sends the SYN. `local_addr` 0 picks the outgoing interface's address.
+pub(crate) fn tcp_connect(local_addr: u32, local_port: u16, remote: (u32, u16)) -> Result<u64> {
+    with_tcp(|tcp, now| {
+        let src = match local_addr {
+            0 => route_source(remote.0).ok_or(errno(libc::ENETUNREACH))?,
+            addr => addr,
+        };
+        let quad = (src, local_port, remote.0, remote.1);
+        if tcp.by_quad.contains_key(&quad) {
+            return Err(errno(libc::EADDRNOTAVAIL));
+        }
+        let iss = tcp.initial_sequence(quad, now);
+        let mut tcb = Tcb::new(TcpState::SynSent, (src, local_port), remote, iss);
+        tcb.snd_nxt = iss.wrapping_add(1);
+        tcb.snd_max = tcb.snd_nxt;
+        tcb.rtt_sample = Some((iss, now));
+        tcp.outbox.push(tcb.syn_segment(now, None));
+        tcb.arm_rto(now);
+        Ok(tcp.insert(tcb))
+    })
+}
+
+/// Queues what fits in the send buffer, EAGAIN when nothing does
+pub(crate) fn tcp_send(id: u64, data: &[u8]) -> Result<usize> {
+    with_tcp(|tcp, now| {
+        let tcb = tcp.connections.get_mut(&id).ok_or(errno(libc::ENOTCONN))?;
+        if let Some(error) = tcb.error.take() {
+            return Err(errno(error));
+        }
+        match tcb.state {
+            TcpState::SynSent | TcpState::SynReceived => return Err(errno(libc::EAGAIN)),
+            TcpState::Established | TcpState::CloseWait if !tcb.fin_queued => {}
+            _ => return Err(errno(libc::EPIPE)),
+        }
+        let room = SEND_BUFFER.saturating_sub(tcb.send_buf.len());
+        if room == 0 {
+            return Err(errno(libc::EAGAIN));
+        }
+        let len = data.len().min(room);
+        tcb.send_buf.extend(&data[..len]);
+        tcp.output(id, now);
+        Ok(len)
+    })
+}
+
+/// Ok(0) at end of stream, EAGAIN while there's nothing to read yet
+pub(crate) fn tcp_recv(id: u64, buf: &mut [u8], peek: bool) -> Result<usize> {
+    with_tcp(|tcp, now| {
+        let tcb = tcp.connections.get_mut(&id).ok_or(errno(libc::ENOTCONN))?;
+        if tcb.recv_buf.is_empty() {
+            if let Some(error) = tcb.error.take() {
+                return Err(errno(error));
+            }
+            if tcb.fin_received || tcb.state == TcpState::Closed {
+                return Ok(0);
+            }
+            return Err(errno(libc::EAGAIN));
+        }
+
+        let window_before = tcb.recv_window();
+        let len = buf.len().min(tcb.recv_buf.len());
+        for (dst, src) in buf[..len].iter_mut().zip(tcb.recv_buf.iter()) {
+            *dst = *src;
+        }
+        if !peek {
+            tcb.recv_buf.drain(..len);
+            // a window that opened up by a segment or more gets announced
+            // (RFC 9293 3.8.6.2.2)
+            let opened = tcb.recv_window() - window_before;
+            if (window_before < tcb.mss && opened >= tcb.mss) || opened >= RECV_BUFFER as u32 / 2 {
+                tcb.ack_pending = true;
+                tcp.output(id, now);
+            }
+        }
+        Ok(len)
+    })
+}
+
+/// SHUT_WR sends the FIN after whatever is still buffered
+pub(crate) fn tcp_shutdown(id: u64, read: bool, write: bool) -> Result<()> {
+    with_tcp(|tcp, now| {
+        let tcb = tcp.connections.get_mut(&id).ok_or(errno(libc::ENOTCONN))?;
+        if read {
+            tcb.recv_buf.clear();
+        }
+        if write && !tcb.fin_queued {
+            match tcb.state {
+                TcpState::SynSent => Tcp::close_tcb(tcb),
+                _ => tcb.fin_queued = true,
+            }
+        }
+        tcp.output(id, now);
+        Ok(())
+    })
+}
+
+/// The socket's gone. Unread data means an abortive close (RST) like
+/// Linux does, otherwise a FIN and the connection lingers until done.
+pub(crate) fn tcp_close(id: u64) {
+    with_tcp(|tcp, now| {
+        let Some(tcb) = tcp.connections.get_mut(&id) else {
+            return;
+        };
+        tcb.orphaned = true;
+        if !tcb.recv_buf.is_empty() && tcb.state.synchronized() && tcb.state != TcpState::TimeWait {
+            let reset = tcb.segment(tcb.snd_nxt, TCP_RST | TCP_ACK, now, &[]);
+            tcp.outbox.push(reset);
+            Tcp::close_tcb(tcb);
+        } else {
+            match tcb.state {
+                TcpState::SynSent | TcpState::Closed => Tcp::close_tcb(tcb),
+                TcpState::FinWait2 => tcb.timeout = Some(now + FIN_TIMEOUT),
+                _ => tcb.fin_queued = true,
+            }
+            tcp.output(id, now);
+        }
+        tcp.reap(id);
+    })
+}
+
+pub(crate) fn tcp_set_nodelay(id: u64, nodelay: bool) {
+    with_tcp(|tcp, now| {
+        if let Some(tcb) = tcp.connections.get_mut(&id) {
+            tcb.nodelay = nodelay;
+            tcp.output(id, now);
+        }
+    })
+}
+
+/// What poll() wants to know about a connection
+pub(crate) struct TcpStatus {
+    pub state: TcpState,
+    pub readable: bool,
+    pub writable: bool,
+    pub available: usize,
+    pub error: Option<c_int>,
+    pub local: (u32, u16),
+    pub remote: (u32, u16),
+}
+
+pub(crate) fn tcp_status(id: u64) -> Option<TcpStatus> {
+    let tcp = TCP.lock().unwrap();
+    let tcb = tcp.connections.get(&id)?;
+    Some(TcpStatus {
+        state: tcb.state,
+        readable: !tcb.recv_buf.is_empty() || tcb.fin_received || tcb.state == TcpState::Closed,
+        writable: matches!(tcb.state, TcpState::Established | TcpState::CloseWait)
+            && !tcb.fin_queued
+            && tcb.send_buf.len() < SEND_BUFFER,
+        available: tcb.recv_buf.len(),
+        error: tcb.error,
+        local: tcb.local,
+        remote: tcb.remote,
+    })
+}
+
+/// The pending error, cleared like SO_ERROR does
+pub(crate) fn tcp_take_error(id: u64) -> Option<c_int> {
+    TCP.lock().unwrap().connections.get_mut(&id).and_then(|tcb| tcb.error.take())
+}
@@ -0,0 +1,503 @@
+use std::io::{Error, Result};
+use std::os::raw::{c_int, c_uint, c_void};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::bootloader::boot_option;
+use crate::ipv4::net_ipv4_is_local;
+use crate::tcp::*;
+use crate::timer::timer_ticks;
+
+//
+// AF_INET SOCK_STREAM sockets on the native TCP (tcp.rs). Which stack serves
+// them is picked at boot: net.tcp=native on the kernel command line moves
+// them here, net.tcp=lwip (the default) keeps them on lwIP's sockets.
+// AF_INET6 always stays with lwIP. With native on, every IPv4 TCP segment
+// takes the in-tree eth/ipv4 path to tcp.rs and lwIP never sees it.
+//
+
+const AF_INET: c_int = 2;
+
+const SOL_SOCKET: c_int = 1;
+const IPPROTO_TCP: c_int = 6;
+
+const SO_REUSEADDR: c_int = 2;
+const SO_TYPE: c_int = 3;
+const SO_ERROR: c_int = 4;
+const SO_SNDBUF: c_int = 7;
+const SO_RCVBUF: c_int = 8;
+const SO_KEEPALIVE: c_int = 9;
+const SO_REUSEPORT: c_int = 15;
+const SO_RCVTIMEO: c_int = 20;
+const SO_SNDTIMEO: c_int = 21;
+const SO_ACCEPTCONN: c_int = 30;
+const SO_PROTOCOL: c_int = 38;
+const SO_DOMAIN: c_int = 39;
+
+const TCP_NODELAY: c_int = 1;
+
+const SHUT_RD: c_int = 0;
+const SHUT_WR: c_int = 1;
+const SHUT_RDWR: c_int = 2;
+
+const MSG_PEEK: c_int = 0x02;
+
+// what tcp.rs buffers each way
+const TCP_BUFFER: c_int = 256 * 1024;
+const DEFAULT_BACKLOG: usize = 128;
+
+lazy_static::lazy_static! {
+    static ref NATIVE: bool = match boot_option("net.tcp") {
+        Some("native") => true,
+        Some("lwip") | None => false,
+        Some(_) => {
+            println!("[net::tcp] Unknown net.tcp= stack, staying on lwIP");
+            false
+        }
+    };
+}
+
+/// Whether AF_INET stream sockets use the native TCP, per net.tcp= at boot
+pub fn tcp_native() -> bool {
+    *NATIVE
+}
+
+fn errno(code: c_int) -> Error {
+    Error::from_raw_os_error(code)
+}
+
+extern "C" {
+    static currentTask: *mut c_void;
+    fn signalsPendingQuick(task: *mut c_void) -> bool;
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct sockaddr_in_linux {
+    pub sin_family: u16,
+    pub sin_port: u16,
+    pub sin_addr: u32,
+    pub sin_zero: [u8; 8],
+}
+
+impl sockaddr_in_linux {
+    fn of((addr, port): (u32, u16)) -> sockaddr_in_linux {
+        sockaddr_in_linux { sin_family: AF_INET as u16, sin_port: port.to_be(), sin_addr: addr.to_be(), ..Default::default() }
+    }
+
+    /// (address, port) in host order
+    fn read(addr: *const sockaddr_in_linux, len: c_uint) -> Result<(u32, u16)> {
+        if addr.is_null() || (len as usize) < std::mem::size_of::<sockaddr_in_linux>() {
+            return Err(errno(libc::EINVAL));
+        }
+        let addr = unsafe { ptr::read_unaligned(addr) };
+        if addr.sin_family as c_int != AF_INET {
+            return Err(errno(libc::EAFNOSUPPORT));
+        }
+        Ok((u32::from_be(addr.sin_addr), u16::from_be(addr.sin_port)))
+    }
+
+    pub fn write(&self, addr: *mut sockaddr_in_linux, len: &mut c_uint) {
+        let copy = (*len as usize).min(std::mem::size_of::<sockaddr_in_linux>());
+        unsafe { ptr::copy_nonoverlapping(self as *const sockaddr_in_linux as *const u8, addr as *mut u8, copy) };
+        *len = std::mem::size_of::<sockaddr_in_linux>() as c_uint;
+    }
+}
+
+#[repr(C)]
+struct timeval_linux {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+unsafe fn sockopt_read_int(optval: *const c_void, optlen: c_uint) -> Result<c_int> {
+    match optlen {
+        0 => Err(errno(libc::EINVAL)),
+        1..=3 => Ok(*(optval as *const u8) as c_int),
+        _ => Ok(ptr::read_unaligned(optval as *const c_int)),
+    }
+}
+
+unsafe fn sockopt_write<T>(optval: *mut c_void, optlen: &mut c_uint, value: &T) {
+    let len = (*optlen as usize).min(std::mem::size_of::<T>());
+    ptr::copy_nonoverlapping(value as *const T as *const u8, optval as *mut u8, len);
+    *optlen = len as c_uint;
+}
+
+#[derive(Clone, Copy)]
+enum Endpoint {
+    Unbound,
+    Bound,
+    Listening(u64),
+    Connected(u64),
+}
+
+struct TcpSocketState {
+    endpoint: Endpoint,
+    // bound address, port 0 until there is one
+    local: (u32, u16),
+    // the port reservation this socket holds, accepted sockets share
+    // their listener's
+    reserved: Option<u16>,
+    reuseaddr: bool,
+    keepalive: bool,
+    nodelay: bool,
+    // milliseconds, 0 blocks forever
+    rcvtimeo: u64,
+    sndtimeo: u64,
+}
+
+struct TcpInner {
+    state: Mutex<TcpSocketState>,
+}
+
+impl Drop for TcpInner {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        match state.endpoint {
+            Endpoint::Listening(listener) => tcp_unlisten(listener),
+            Endpoint::Connected(id) => tcp_close(id),
+            Endpoint::Unbound | Endpoint::Bound => {}
+        }
+        if let Some(port) = state.reserved {
+            tcp_port_release(port);
+        }
+    }
+}
+
+/// Duplicates share the connection, it closes with the last of them
+#[derive(Clone)]
+pub struct TcpSocket {
+    inner: Arc<TcpInner>,
+}
+
+impl TcpSocket {
+    pub fn new(ty: c_int, protocol: c_int) -> Result<TcpSocket> {
+        if ty != libc::SOCK_STREAM {
+            return Err(errno(libc::ESOCKTNOSUPPORT));
+        }
+        if protocol != 0 && protocol != IPPROTO_TCP {
+            return Err(errno(libc::EPROTONOSUPPORT));
+        }
+        Ok(Self::with(Endpoint::Unbound, (0, 0), None))
+    }
+
+    fn with(endpoint: Endpoint, local: (u32, u16), reserved: Option<u16>) -> TcpSocket {
+        TcpSocket {
+            inner: Arc::new(TcpInner {
+                state: Mutex::new(TcpSocketState {
+                    endpoint,
+                    local,
+                    reserved,
+                    reuseaddr: false,
+                    keepalive: false,
+                    nodelay: false,
+                    rcvtimeo: 0,
+                    sndtimeo: 0,
+                }),
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, TcpSocketState> {
+        self.inner.state.lock().unwrap()
+    }
+
+    fn connection(&self) -> Result<u64> {
+        match self.state().endpoint {
+            Endpoint::Connected(id) => Ok(id),
+            _ => Err(errno(libc::ENOTCONN)),
+        }
+    }
+
+    /// Retries `f` while it says EAGAIN, unless `nonblock`, the timeout
+    /// (SO_RCVTIMEO/SO_SNDTIMEO) or a pending signal (EINTR) says otherwise
+    fn block<T>(nonblock: bool, timeout: u64, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+        let deadline = if timeout == 0 { None } else { Some(timer_ticks() + timeout) };
+        loop {
+            match f() {
+                Err(e) if e.raw_os_error() == Some(libc::EAGAIN) => {
+                    if nonblock || deadline.map_or(false, |deadline| timer_ticks() >= deadline) {
+                        return Err(e);
+                    }
+                    if unsafe { signalsPendingQuick(currentTask) } {
+                        return Err(errno(libc::EINTR));
+                    }
+                    thread::yield_now();
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Reserves the port, 0 picks an ephemeral one
+    fn bind_to(state: &mut TcpSocketState, addr: u32, port: u16) -> Result<()> {
+        let reserved = match tcp_port_reserve(port) {
+            Ok(port) => Some(port),
+            // the port's still held by a connection winding down
+            Err(_) if port != 0 && state.reuseaddr => None,
+            Err(e) => return Err(e),
+        };
+        state.reserved = reserved;
+        state.local = (addr, reserved.unwrap_or(port));
+        state.endpoint = Endpoint::Bound;
+        Ok(())
+    }
+
+    pub fn bind(&self, addr: *const sockaddr_in_linux, len: c_uint) -> Result<()> {
+        let (addr, port) = sockaddr_in_linux::read(addr, len)?;
+        if addr != 0 && !net_ipv4_is_local(addr) {
+            return Err(errno(libc::EADDRNOTAVAIL));
+        }
+
+        let mut state = self.state();
+        if !matches!(state.endpoint, Endpoint::Unbound) {
+            return Err(errno(libc::EINVAL));
+        }
+        Self::bind_to(&mut state, addr, port)
+    }
+
+    pub fn listen(&self, backlog: c_int) -> Result<()> {
+        let backlog = if backlog <= 0 { DEFAULT_BACKLOG } else { backlog as usize };
+        let mut state = self.state();
+        match state.endpoint {
+            Endpoint::Listening(listener) => {
+                tcp_set_backlog(listener, backlog);
+                return Ok(());
+            }
+            Endpoint::Connected(_) => return Err(errno(libc::EINVAL)),
+            Endpoint::Unbound => Self::bind_to(&mut state, 0, 0)?,
+            Endpoint::Bound => {}
+        }
+        let listener = tcp_listen(state.local.0, state.local.1, backlog)?;
+        state.endpoint = Endpoint::Listening(listener);
+        Ok(())
+    }
+
+    /// The next connection and its peer
+    pub fn accept(&self, nonblock: bool) -> Result<(TcpSocket, sockaddr_in_linux)> {
+        let (listener, timeout, nodelay) = {
+            let state = self.state();
+            let Endpoint::Listening(listener) = state.endpoint else {
+                return Err(errno(libc::EINVAL));
+            };
+            (listener, state.rcvtimeo, state.nodelay)
+        };
+
+        let id = Self::block(nonblock, timeout, || tcp_accept(listener))?;
+        let status = tcp_status(id).ok_or_else(|| errno(libc::ECONNABORTED))?;
+        // TCP_NODELAY carries over from the listener, like on Linux
+        if nodelay {
+            tcp_set_nodelay(id, true);
+        }
+        let socket = Self::with(Endpoint::Connected(id), status.local, None);
+        socket.state().nodelay = nodelay;
+        Ok((socket, sockaddr_in_linux::of(status.remote)))
+    }
+
+    /// Non-blocking sockets get EINPROGRESS and find out through poll() &
+    /// SO_ERROR, like on Linux
+    pub fn connect(&self, addr: *const sockaddr_in_linux, len: c_uint, nonblock: bool) -> Result<()> {
+        let remote = sockaddr_in_linux::read(addr, len)?;
+        if remote.0 == 0 || remote.1 == 0 {
+            return Err(errno(libc::ECONNREFUSED));
+        }
+
+        let (id, timeout) = {
+            let mut state = self.state();
+            match state.endpoint {
+                Endpoint::Connected(id) => {
+                    return match tcp_status(id).map(|status| status.state) {
+                        Some(TcpState::SynSent | TcpState::SynReceived) => Err(errno(libc::EALREADY)),
+                        _ => Err(errno(libc::EISCONN)),
+                    };
+                }
+                Endpoint::Listening(_) => return Err(errno(libc::EINVAL)),
+                Endpoint::Unbound => Self::bind_to(&mut state, 0, 0)?,
+                Endpoint::Bound => {}
+            }
+            let id = tcp_connect(state.local.0, state.local.1, remote)?;
+            if state.nodelay {
+                tcp_set_nodelay(id, true);
+            }
+            state.endpoint = Endpoint::Connected(id);
+            (id, state.sndtimeo)
+        };
+
+        if nonblock {
+            return Err(errno(libc::EINPROGRESS));
+        }
+        Self::block(false, timeout, || match tcp_status(id) {
+            Some(status) if status.state == TcpState::SynSent || status.state == TcpState::SynReceived => {
+                Err(errno(libc::EAGAIN))
+            }
+            Some(status) if status.state == TcpState::Closed => {
+                Err(errno(tcp_take_error(id).unwrap_or(libc::ECONNREFUSED)))
+            }
+            _ => Ok(()),
+        })
+        .map_err(|e| {
+            if e.raw_os_error() == Some(libc::EAGAIN) { errno(libc::ETIMEDOUT) } else { e }
+        })
+    }
+
+    /// Blocking sockets return once all of `buf` is queued
+    pub fn send(&self, buf: &[u8], nonblock: bool) -> Result<usize> {
+        let id = self.connection()?;
+        let timeout = self.state().sndtimeo;
+        let mut sent = 0;
+        loop {
+            match Self::block(nonblock, timeout, || tcp_send(id, &buf[sent..])) {
+                Ok(len) => sent += len,
+                Err(_) if sent > 0 => return Ok(sent),
+                Err(e) => return Err(e),
+            }
+            if sent == buf.len() || nonblock {
+                return Ok(sent);
+            }
+        }
+    }
+
+    pub fn recv(&self, buf: &mut [u8], flags: c_int, nonblock: bool) -> Result<usize> {
+        let id = self.connection()?;
+        let timeout = self.state().rcvtimeo;
+        Self::block(nonblock, timeout, || tcp_recv(id, buf, flags & MSG_PEEK != 0))
+    }
+
+    pub fn shutdown(&self, how: c_int) -> Result<()> {
+        let (read, write) = match how {
+            SHUT_RD => (true, false),
+            SHUT_WR => (false, true),
+            SHUT_RDWR => (true, true),
+            _ => return Err(errno(libc::EINVAL)),
+        };
+        tcp_shutdown(self.connection()?, read, write)
+    }
+
+    pub fn getsockname(&self, addr: *mut sockaddr_in_linux, len: &mut c_uint) -> Result<()> {
+        let state = self.state();
+        let local = match state.endpoint {
+            Endpoint::Connected(id) => tcp_status(id).map_or(state.local, |status| status.local),
+            _ => state.local,
+        };
+        sockaddr_in_linux::of(local).write(addr, len);
+        Ok(())
+    }
+
+    pub fn getpeername(&self, addr: *mut sockaddr_in_linux, len: &mut c_uint) -> Result<()> {
+        let status = tcp_status(self.connection()?).ok_or_else(|| errno(libc::ENOTCONN))?;
+        if matches!(status.state, TcpState::SynSent | TcpState::SynReceived | TcpState::Closed) {
+            return Err(errno(libc::ENOTCONN));
+        }
+        sockaddr_in_linux::of(status.remote).write(addr, len);
+        Ok(())
+    }
+
+    pub fn setsockopt(&self, level: c_int, optname: c_int, optval: *const c_void, optlen: c_uint) -> Result<()> {
+        if optval.is_null() {
+            return Err(errno(libc::EFAULT));
+        }
+        let mut state = self.state();
+
+        match (level, optname) {
+            (SOL_SOCKET, SO_REUSEADDR | SO_REUSEPORT) => {
+                state.reuseaddr = unsafe { sockopt_read_int(optval, optlen)? } != 0;
+            }
+            // there's no keepalive timer, only remember it was asked for
+            (SOL_SOCKET, SO_KEEPALIVE) => {
+                state.keepalive = unsafe { sockopt_read_int(optval, optlen)? } != 0;
+            }
+            // the buffers are fixed size
+            (SOL_SOCKET, SO_SNDBUF | SO_RCVBUF) => {
+                unsafe { sockopt_read_int(optval, optlen)? };
+            }
+            (SOL_SOCKET, SO_RCVTIMEO | SO_SNDTIMEO) => {
+                if (optlen as usize) < std::mem::size_of::<timeval_linux>() {
+                    return Err(errno(libc::EINVAL));
+                }
+                let tv = unsafe { ptr::read_unaligned(optval as *const timeval_linux) };
+                if tv.tv_sec < 0 || !(0..1_000_000).contains(&tv.tv_usec) {
+                    return Err(errno(libc::EDOM));
+                }
+
+                let ms = tv.tv_sec as u64 * 1000 + (tv.tv_usec as u64).div_ceil(1000);
+                if optname == SO_RCVTIMEO { state.rcvtimeo = ms } else { state.sndtimeo = ms }
+            }
+            (IPPROTO_TCP, TCP_NODELAY) => {
+                state.nodelay = unsafe { sockopt_read_int(optval, optlen)? } != 0;
+                if let Endpoint::Connected(id) = state.endpoint {
+                    tcp_set_nodelay(id, state.nodelay);
+                }
+            }
+            _ => return Err(errno(libc::ENOPROTOOPT)),
+        }
+        Ok(())
+    }
+
+    pub fn getsockopt(&self, level: c_int, optname: c_int, optval: *mut c_void, optlen: &mut c_uint) -> Result<()> {
+        if optval.is_null() {
+            return Err(errno(libc::EFAULT));
+        }
+        let state = self.state();
+
+        let value: c_int = match (level, optname) {
+            (SOL_SOCKET, SO_TYPE) => libc::SOCK_STREAM,
+            (SOL_SOCKET, SO_PROTOCOL) => IPPROTO_TCP,
+            (SOL_SOCKET, SO_DOMAIN) => AF_INET,
+            // reading clears it
+            (SOL_SOCKET, SO_ERROR) => match state.endpoint {
+                Endpoint::Connected(id) => tcp_take_error(id).unwrap_or(0),
+                _ => 0,
+            },
+            (SOL_SOCKET, SO_REUSEADDR | SO_REUSEPORT) => state.reuseaddr as c_int,
+            (SOL_SOCKET, SO_KEEPALIVE) => state.keepalive as c_int,
+            (SOL_SOCKET, SO_SNDBUF | SO_RCVBUF) => TCP_BUFFER,
+            (SOL_SOCKET, SO_ACCEPTCONN) => matches!(state.endpoint, Endpoint::Listening(_)) as c_int,
+            (SOL_SOCKET, SO_RCVTIMEO | SO_SNDTIMEO) => {
+                let ms = if optname == SO_RCVTIMEO { state.rcvtimeo } else { state.sndtimeo };
+                let tv = timeval_linux { tv_sec: (ms / 1000) as i64, tv_usec: ((ms % 1000) * 1000) as i64 };
+                unsafe { sockopt_write(optval, optlen, &tv) };
+                return Ok(());
+            }
+            (IPPROTO_TCP, TCP_NODELAY) => state.nodelay as c_int,
+            _ => return Err(errno(libc::ENOPROTOOPT)),
+        };
+        unsafe { sockopt_write(optval, optlen, &value) };
+        Ok(())
+    }
+
+    /// Bytes waiting to be read, FIONREAD
+    pub fn available(&self) -> Result<usize> {
+        match self.state().endpoint {
+            Endpoint::Connected(id) => Ok(tcp_status(id).map_or(0, |status| status.available)),
+            Endpoint::Listening(_) => Err(errno(libc::EINVAL)),
+            _ => Ok(0),
+        }
+    }
+
+    /// Also true once there's an error or the peer is done sending, so
+    /// the next call reports it
+    pub fn readable(&self) -> bool {
+        match self.state().endpoint {
+            Endpoint::Listening(listener) => tcp_acceptable(listener),
+            Endpoint::Connected(id) => {
+                tcp_status(id).map_or(true, |status| status.readable || status.error.is_some())
+            }
+            _ => false,
+        }
+    }
+
+    pub fn writable(&self) -> bool {
+        match self.state().endpoint {
+            Endpoint::Connected(id) => tcp_status(id).map_or(true, |status| {
+                status.writable || status.error.is_some() || status.state == TcpState::Closed
+            }),
+            _ => false,
+        }
+    }
+
+    pub fn duplicate(&self) -> TcpSocket {
+        self.clone()
+    }
+}
@@ -1,6 +1,7 @@
 use crate::*;
 use super::socket::*;
 use super::pair::*;
+use super::linux::syscalls_net::*;
 
 #[no_mangle]
 pub static unixSocketHandlers: VfsHandlers = VfsHandlers {
@@ -11,6 +12,10 @@ pub static unixSocketHandlers: VfsHandlers = VfsHandlers {
     accept: Some(unixSocketAccept),
     connect: Some(unixSocketConnect),
     getpeername: Some(unixSocketGetpeername),
+    getsockname: Some(unixSocketGetsockname),
+    getsockopts: Some(unixSocketGetsockopts),
+    setsockopts: Some(unixSocketSetsockopts),
+    shutdown: Some(unixSocketShutdown),
     recvmsg: Some(unixSocketRecvmsg),
     sendmsg: Some(unixSocketSendmsg),
     duplicate: Some(unixSocketDuplicate),
@@ -24,9 +29,71 @@ pub static unixAcceptHandlers: VfsHandlers = VfsHandlers {
     sendto: Some(unixSocketAcceptSendto),
     recvfrom: Some(unixSocketAcceptRecvfrom),
     recvmsg: Some(unixSocketAcceptRecvmsg),
+    sendmsg: Some(unixSocketAcceptSendmsg),
     getpeername: Some(unixSocketAcceptGetpeername),
+    getsockname: Some(unixSocketAcceptGetsockname),
+    getsockopts: Some(unixSocketAcceptGetsockopts),
+    setsockopts: Some(unixSocketAcceptSetsockopts),
+    shutdown: Some(unixSocketAcceptShutdown),
     duplicate: Some(unixSocketAcceptDuplicate),
     close: Some(unixSocketAcceptClose),
     reportKey: Some(unixSocketAcceptReportKey),
     internalPoll: Some(unixSocketAcceptInternalPoll),
 };
+
+#[no_mangle]
+pub static TCP_HANDLERS: VfsHandlers = VfsHandlers {
+    sendto: Some(tcp_socket_sendto),
+    recvfrom: Some(tcp_socket_recvfrom),
+    bind: Some(tcp_socket_bind),
+    listen: Some(tcp_socket_listen),
+    accept: Some(tcp_socket_accept),
+    connect: Some(tcp_socket_connect),
+    getpeername: Some(tcp_socket_getpeername),
+    getsockname: Some(tcp_socket_getsockname),
+    getsockopts: Some(tcp_socket_getsockopts),
+    setsockopts: Some(tcp_socket_setsockopts),
+    shutdown: Some(tcp_socket_shutdown),
+    recvmsg: Some(tcp_socket_recvmsg),
+    sendmsg: Some(tcp_socket_sendmsg),
+    ioctl: Some(tcp_socket_ioctl),
+    duplicate: Some(tcp_socket_duplicate),
+    close: Some(tcp_socket_close),
+    internalPoll: Some(tcp_socket_poll),
+};
+
+#[no_mangle]
+pub static NETLINK_HANDLERS: VfsHandlers = VfsHandlers {
+    sendto: Some(netlink_sendto),
+    recvfrom: Some(netlink_recvfrom),
+    bind: Some(netlink_bind),
+    connect: Some(netlink_connect),
+    getpeername: Some(netlink_getpeername),
+    getsockname: Some(netlink_getsockname),
+    getsockopts: Some(netlink_getsockopts),
+    setsockopts: Some(netlink_setsockopts),
+    recvmsg: Some(netlink_recvmsg),
+    sendmsg: Some(netlink_sendmsg),
+    ioctl: Some(netlink_ioctl),
+    duplicate: Some(netlink_duplicate),
+    close: Some(netlink_close),
+    internalPoll: Some(netlink_poll),
+};
+
+// setsockopts carries SO_ATTACH_FILTER and PACKET_RX_RING, mmap hands out the ring
+#[no_mangle]
+pub static PACKET_HANDLERS: VfsHandlers = VfsHandlers {
+    sendto: Some(packet_sendto),
+    recvfrom: Some(packet_recvfrom),
+    bind: Some(packet_bind),
+    getsockname: Some(packet_getsockname),
+    getsockopts: Some(packet_getsockopts),
+    setsockopts: Some(packet_setsockopts),
+    recvmsg: Some(packet_recvmsg),
+    sendmsg: Some(packet_sendmsg),
+    ioctl: Some(packet_ioctl),
+    mmap: Some(packet_mmap),
+    duplicate: Some(packet_duplicate),
+    close: Some(packet_close),
+    internalPoll: Some(packet_poll),
+};
@@ -1,240 +1,197 @@
-use std::io::{self, Read, Write};
-use std::sync::{Arc, Mutex};
+#![no_std]
 
-// Simulated framebuffer and keyboard task structures
-struct Fb {
-    width: usize,
-    height: usize,
+use core::ptr::null_mut;
+
+use crate::tty::*;
+
+//
+// Standard input/output handlers, backed by the console tty
+//
+
+//
+// Constants
+//
+
+const S_IFCHR: u32 = 0o020000;
+const S_IRUSR: u32 = 0o400;
+const S_IWUSR: u32 = 0o200;
+
+const ENODEV: isize = 19;
+
+#[inline]
+const fn err(code: isize) -> usize {
+    (!code + 1) as usize
+}
+
+//
+// External kernel APIs
+//
+
+#[repr(C)]
+pub struct Framebuffer {
+    pub virt: *mut u8,
+    pub phys: usize,
+    pub width: usize,
+    pub height: usize,
 }
 
-struct Termios {
-    lflag: u32,
+extern "C" {
+    static fb: Framebuffer;
+
+    fn vtConsoleWrite(index: usize, buff: *const u8, len: usize) -> usize;
+    fn vtIoctl(index: usize, request: u64, arg: *mut u8) -> usize;
+    fn vtFlushReplies(index: usize);
+    fn consoleCols() -> u32;
+    fn consoleRows() -> u32;
+    fn rand() -> u64;
+    fn debugf(fmt: *const u8, ...);
+
+    fn sessionSetCtty(task: *mut Task, tty: *mut Tty, steal: bool) -> usize;
 }
 
-struct Task {
-    id: usize,
-    term: Termios,
-    tmp_recv: usize,
-    state_waiting_input: bool,
+#[repr(C)]
+pub struct Stat {
+    pub st_dev: u64,
+    pub st_ino: u64,
+    pub st_mode: u32,
+    pub st_nlink: u32,
+    pub st_uid: u32,
+    pub st_gid: u32,
+    pub st_rdev: u64,
+    pub st_blksize: u64,
+    pub st_size: u64,
+    pub st_blocks: u64,
+    pub st_atime: u64,
+    pub st_mtime: u64,
+    pub st_ctime: u64,
 }
 
-static FB: Fb = Fb {
-    width: 800,
-    height: 600,
-};
+//
+// Console tty (tty1 of the virtual terminals)
+//
+
+#[no_mangle]
+pub static mut consoleTty: Tty = unsafe { core::mem::zeroed() };
+
+unsafe extern "C" fn consoleTtyOutput(_tty: *mut Tty, buff: *const u8, len: usize) -> usize {
+    vtConsoleWrite(0, buff, len)
+}
+
+// /dev/tty for sessions controlled by the console
+unsafe extern "C" fn consoleTtyReopen(_tty: *mut Tty, fd: *mut OpenFile) -> usize {
+    (*fd).handlers = &stdio as *const VfsHandlers as *const core::ffi::c_void;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn initiateConsoleTty() {
+    ttyInit(&mut consoleTty, Some(consoleTtyOutput), None, null_mut());
+    consoleTty.reopen = Some(consoleTtyReopen);
+    consoleTty.win.ws_row = consoleRows() as u16;
+    consoleTty.win.ws_col = consoleCols() as u16;
+    consoleTty.win.ws_xpixel = fb.width as u16;
+    consoleTty.win.ws_ypixel = fb.height as u16;
+}
+
+// Makes a freshly spawned task the leader of a new session, controlled by the
+// console (what getty would do)
+#[no_mangle]
+pub unsafe extern "C" fn consoleTtyAttach(task: *mut Task) {
+    (*task).sid = (*task).tgid;
+    (*task).pgid = (*task).tgid;
+    (*task).ctrlTty = null_mut();
+    sessionSetCtty(task, &mut consoleTty, true);
+}
+
+//
+// Handlers
+//
+
+#[no_mangle]
+pub unsafe extern "C" fn readHandler(fd: *mut OpenFile, input: *mut u8, limit: usize) -> usize {
+    vtFlushReplies(0);
+    ttyRead(&mut consoleTty, fd, input, limit)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn writeHandler(_fd: *mut OpenFile, out: *mut u8, limit: usize) -> usize {
+    // the console can always take more, so this never comes back short
+    // (unless a background job gets stopped by TOSTOP)
+    ttyWrite(&mut consoleTty, out, limit)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ioctlHandler(_fd: *mut OpenFile, request: u64, arg: *mut u8) -> usize {
+    // VT_* and KD* requests land here too when tty1 is the caller's terminal
+    vtIoctl(0, request, arg)
+}
 
-lazy_static::lazy_static! {
-    static ref CURRENT_TASK: Arc<Mutex<Task>> = Arc::new(Mutex::new(Task {
-        id: 1,
-        term: Termios { lflag: 0 },
-        tmp_recv: 0,
-        state_waiting_input: false,
-    }));
-}
-
-struct Winsize {
-    ws_row: usize,
-    ws_col: usize,
-    ws_xpixel: usize,
-    ws_ypixel: usize,
-}
-
-struct OpenFile {
-    // In kernel this holds more info; here minimal
-}
-
-struct Stat {
-    st_dev: u64,
-    st_ino: u64,
-    st_mode: u32,
-    st_nlink: u32,
-    st_uid: u32,
-    st_gid: u32,
-    st_rdev: u64,
-    st_blksize: u64,
-    st_size: u64,
-    st_blocks: u64,
-    st_atime: u64,
-    st_mtime: u64,
-    st_ctime: u64,
-}
-
-// ------------------------- Handlers -------------------------
-
-fn read_handler(_fd: &OpenFile, buffer: &mut [u8]) -> io::Result<usize> {
-    // simulate blocking keyboard read
-    let mut task = CURRENT_TASK.lock().unwrap();
-
-    task.state_waiting_input = true;
-
-    // simulate reading input
-    let limit = buffer.len();
-    let mut kernel_buf = vec![0u8; limit];
-    // kbTaskRead would fill this buffer
-    for i in 0..limit {
-        kernel_buf[i] = b'a'; // mock input
-    }
-
-    // simulate end of input
-    task.tmp_recv = limit;
-    task.state_waiting_input = false;
-
-    // copy to caller buffer
-    buffer[..task.tmp_recv].copy_from_slice(&kernel_buf[..task.tmp_recv]);
-
-    // add newline if ICANON is set (mock behavior)
-    if task.term.lflag & 0x02 != 0 && task.tmp_recv < limit {
-        buffer[task.tmp_recv] = b'\n';
-        task.tmp_recv += 1;
-    }
-
-    Ok(task.tmp_recv)
-}
-
-fn write_handler(_fd: &OpenFile, buffer: &[u8]) -> io::Result<usize> {
-    for &b in buffer {
-        print!("{}", b as char);
-    }
-    Ok(buffer.len())
-}
-
-fn ioctl_handler(_fd: &OpenFile, request: u64, arg: &mut [u8]) -> i32 {
-    match request {
-        0x5413 => {
-            // TIOCGWINSZ
-            let win: &mut Winsize =
-                unsafe { &mut *(arg.as_mut_ptr() as *mut Winsize) };
-            win.ws_row = FB.height / 16;
-            win.ws_col = FB.width / 8;
-            win.ws_xpixel = FB.width;
-            win.ws_ypixel = FB.height;
-            0
-        }
-        0x540a => 0, // dummy
-        0x5401 => {
-            // TCGETS
-            let term: &mut Termios =
-                unsafe { &mut *(arg.as_mut_ptr() as *mut Termios) };
-            let task = CURRENT_TASK.lock().unwrap();
-            *term = task.term;
-            0
-        }
-        0x5402 | 0x5403 | 0x5404 => {
-            // TCSETS, TCSETSW, TCSETSF
-            let term: &Termios =
-                unsafe { &*(arg.as_ptr() as *const Termios) };
-            let mut task = CURRENT_TASK.lock().unwrap();
-            task.term = *term;
-            0
-        }
-        0x540f => {
-            // TIOCGPGRP
-            let pid: &mut u32 = unsafe { &mut *(arg.as_mut_ptr() as *mut u32) };
-            let task = CURRENT_TASK.lock().unwrap();
-            *pid = task.id as u32;
-            0
-        }
-        _ => -1,
-    }
-}
-
-fn mmap_handler(
+#[no_mangle]
+pub unsafe extern "C" fn mmapHandler(
     _addr: usize,
     _length: usize,
     _prot: i32,
     _flags: i32,
-    _fd: &OpenFile,
+    _fd: *mut OpenFile,
     _pgoffset: usize,
-) -> isize {
-    eprintln!("[io::mmap] FATAL! Tried to mmap on stdio!");
-    -1
-}
-
-fn stat_handler(_fd: &OpenFile, target: &mut Stat) -> i32 {
-    target.st_dev = 420;
-    target.st_ino = rand::random();
-    target.st_mode = 0o20660; // S_IFCHR | S_IRUSR | S_IWUSR
-    target.st_nlink = 1;
-    target.st_uid = 0;
-    target.st_gid = 0;
-    target.st_rdev = 34830;
-    target.st_blksize = 0x1000;
-    target.st_size = 0;
-    target.st_blocks = (target.st_size + 511) / 512;
-    target.st_atime = 69;
-    target.st_mtime = 69;
-    target.st_ctime = 69;
+) -> usize {
+    debugf(b"[io::mmap] FATAL! Tried to mmap on stdio!\n\0".as_ptr());
+    err(ENODEV)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn statHandler(_fd: *mut OpenFile, target: *mut Stat) -> usize {
+    (*target).st_dev = 420;
+    (*target).st_ino = rand();
+    (*target).st_mode = S_IFCHR | S_IRUSR | S_IWUSR;
+    (*target).st_nlink = 1;
+    (*target).st_uid = 0;
+    (*target).st_gid = 0;
+    (*target).st_rdev = 34830;
+    (*target).st_blksize = 0x1000;
+    (*target).st_size = 0;
+    (*target).st_blocks = 0;
+    (*target).st_atime = 69;
+    (*target).st_mtime = 69;
+    (*target).st_ctime = 69;
     0
 }
 
-static mut IO_SWITCH: bool = false;
-
-fn internal_poll_handler(_fd: &OpenFile, events: i32) -> i32 {
-    let mut revents = 0;
-    unsafe {
-        if events & 0x001 != 0 && IO_SWITCH {
-            revents |= 0x001; // EPOLLIN
-        }
-        if events & 0x004 != 0 {
-            revents |= 0x004; // EPOLLOUT
-        }
-        IO_SWITCH = !IO_SWITCH;
-    }
-    revents
+#[no_mangle]
+pub unsafe extern "C" fn internalPollHandler(_fd: *mut OpenFile, events: i32) -> i32 {
+    vtFlushReplies(0);
+    ttyPoll(&mut consoleTty, events)
 }
 
-fn report_key_handler(_fd: &OpenFile) -> usize {
-    69
+#[no_mangle]
+pub unsafe extern "C" fn reportKeyHandler(_fd: *mut OpenFile) -> usize {
+    consoleTty.pollKey
 }
 
-// ---------------------- VfsHandlers Struct ----------------------
+//
+// Registration
+//
 
-struct VfsHandlers {
-    read: fn(&OpenFile, &mut [u8]) -> io::Result<usize>,
-    write: fn(&OpenFile, &[u8]) -> io::Result<usize>,
-    ioctl: fn(&OpenFile, u64, &mut [u8]) -> i32,
-    mmap: fn(usize, usize, i32, i32, &OpenFile, usize) -> isize,
-    stat: fn(&OpenFile, &mut Stat) -> i32,
-    internal_poll: fn(&OpenFile, i32) -> i32,
-    report_key: fn(&OpenFile) -> usize,
+#[repr(C)]
+pub struct VfsHandlers {
+    pub read: Option<unsafe extern "C" fn(*mut OpenFile, *mut u8, usize) -> usize>,
+    pub write: Option<unsafe extern "C" fn(*mut OpenFile, *mut u8, usize) -> usize>,
+    pub ioctl: Option<unsafe extern "C" fn(*mut OpenFile, u64, *mut u8) -> usize>,
+    pub mmap: Option<
+        unsafe extern "C" fn(usize, usize, i32, i32, *mut OpenFile, usize) -> usize,
+    >,
+    pub stat: Option<unsafe extern "C" fn(*mut OpenFile, *mut Stat) -> usize>,
+    pub internalPoll: Option<unsafe extern "C" fn(*mut OpenFile, i32) -> i32>,
+    pub reportKey: Option<unsafe extern "C" fn(*mut OpenFile) -> usize>,
 }
 
-static STDIO: VfsHandlers = VfsHandlers {
-    read: read_handler,
-    write: write_handler,
-    ioctl: ioctl_handler,
-    mmap: mmap_handler,
-    stat: stat_handler,
-    internal_poll: internal_poll_handler,
-    report_key: report_key_handler,
+#[no_mangle]
+pub static stdio: VfsHandlers = VfsHandlers {
+    read: Some(readHandler),
+    write: Some(writeHandler),
+    ioctl: Some(ioctlHandler),
+    mmap: Some(mmapHandler),
+    stat: Some(statHandler),
+    internalPoll: Some(internalPollHandler),
+    reportKey: Some(reportKeyHandler),
 };
-
-// ---------------------- Example Usage ----------------------
-
-fn main() {
-    let fd = OpenFile {};
-    let mut buf = [0u8; 32];
-
-    let n = (STDIO.read)(&fd, &mut buf).unwrap();
-    println!("Read {} bytes: {:?}", n, &buf[..n]);
-
-    (STDIO.write)(&fd, b"Hello from Rust stdio!\n").unwrap();
-
-    let mut stat = Stat {
-        st_dev: 0,
-        st_ino: 0,
-        st_mode: 0,
-        st_nlink: 0,
-        st_uid: 0,
-        st_gid: 0,
-        st_rdev: 0,
-        st_blksize: 0,
-        st_size: 0,
-        st_blocks: 0,
-        st_atime: 0,
-        st_mtime: 0,
-        st_ctime: 0,
-    };
-    (STDIO.stat)(&fd, &mut stat);
-    println!("Stat: st_dev={}, st_mode={:#o}", stat.st_dev, stat.st_mode);
-}
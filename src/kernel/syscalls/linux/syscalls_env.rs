@@ -58,18 +58,47 @@ pub fn syscall_getpgid() -> usize {
     task.pgid
 }
 
-pub fn syscall_setpgid(pid: Option<usize>, pgid: usize) -> Result<(), usize> {
+extern "C" {
+    fn pgrpSet(caller: *mut Task, pid: i32, pgid: i32) -> usize;
+    fn sessionCreate(task: *mut Task) -> usize;
+}
+
+/// Job control helpers hand back kernel-style negative error codes
+fn jobctl_result(ret: usize) -> Result<usize, usize> {
+    if (ret as isize) < 0 {
+        Err((!ret).wrapping_add(1))
+    } else {
+        Ok(ret)
+    }
+}
+
+pub fn syscall_setpgid(pid: usize, pgid: usize) -> Result<(), usize> {
     let task = current_task();
     let mut task = task.lock().unwrap();
 
-    let target_pid = pid.unwrap_or(task.id);
-    let target_task = task_get(target_pid).ok_or(EPERM)?;
-    let mut target_task = target_task.lock().unwrap();
-
-    target_task.pgid = pgid;
+    jobctl_result(unsafe { pgrpSet(&mut *task, pid as i32, pgid as i32) })?;
     Ok(())
 }
 
+pub fn syscall_getsid(pid: usize) -> Result<usize, usize> {
+    if pid == 0 {
+        let task = current_task();
+        let task = task.lock().unwrap();
+        return Ok(task.sid);
+    }
+
+    let target = task_get(pid).ok_or(ESRCH)?;
+    let target = target.lock().unwrap();
+    Ok(target.sid)
+}
+
+pub fn syscall_setsid() -> Result<usize, usize> {
+    let task = current_task();
+    let mut task = task.lock().unwrap();
+
+    jobctl_result(unsafe { sessionCreate(&mut *task) })
+}
+
 pub fn syscall_getcwd(buf: &mut [u8]) -> Result<usize, usize> {
     let task = current_task();
     let task = task.lock().unwrap();
@@ -103,9 +132,20 @@ pub fn syscall_fchdir(fd: usize) -> Result<(), usize> {
     syscall_chdir(dirname)
 }
 
+const RLIMIT_CORE: usize = 4;
+const RLIMIT_NOFILE: usize = 7;
+
 pub fn syscall_getrlimit(resource: usize) -> Result<RLimit, usize> {
     match resource {
-        7 => {
+        RLIMIT_CORE => {
+            let task = current_task();
+            let task = task.lock().unwrap();
+            Ok(RLimit {
+                rlim_cur: task.info_signals.rlimit_core_soft,
+                rlim_max: task.info_signals.rlimit_core_hard,
+            })
+        }
+        RLIMIT_NOFILE => {
             let task = current_task();
             let task = task.lock().unwrap();
             Ok(RLimit {
@@ -117,6 +157,45 @@ pub fn syscall_getrlimit(resource: usize) -> Result<RLimit, usize> {
     }
 }
 
+/// Only RLIMIT_CORE can be changed for now
+pub fn syscall_setrlimit(resource: usize, limit: &RLimit) -> Result<(), usize> {
+    if limit.rlim_cur > limit.rlim_max {
+        return Err(EINVAL);
+    }
+
+    match resource {
+        RLIMIT_CORE => {
+            let task = current_task();
+            let mut task = task.lock().unwrap();
+            // we're always root, so raising the hard limit is fine
+            task.info_signals.rlimit_core_soft = limit.rlim_cur;
+            task.info_signals.rlimit_core_hard = limit.rlim_max;
+            Ok(())
+        }
+        _ => Err(ENOSYS),
+    }
+}
+
+pub fn syscall_prlimit64(
+    pid: usize,
+    resource: usize,
+    new_limit: Option<&RLimit>,
+    old_limit: Option<&mut RLimit>,
+) -> Result<(), usize> {
+    let task = current_task();
+    if pid != 0 && pid != task.lock().unwrap().tgid {
+        return Err(ESRCH);
+    }
+
+    if let Some(old_limit) = old_limit {
+        *old_limit = syscall_getrlimit(resource)?;
+    }
+    if let Some(new_limit) = new_limit {
+        syscall_setrlimit(resource, new_limit)?;
+    }
+    Ok(())
+}
+
 pub fn syscall_getuid() -> usize { 0 }
 pub fn syscall_geteuid() -> usize { 0 }
 pub fn syscall_getgid() -> usize { 0 }
@@ -194,6 +273,8 @@ pub fn syscalls_reg_env() {
     register_syscall(SYSCALL_GETCWD, syscall_getcwd);
     register_syscall(SYSCALL_CHDIR, syscall_chdir);
     register_syscall(SYSCALL_GETRLIMIT, syscall_getrlimit);
+    register_syscall(SYSCALL_SETRLIMIT, syscall_setrlimit);
+    register_syscall(SYSCALL_PRLIMIT64, syscall_prlimit64);
     register_syscall(SYSCALL_GETUID, syscall_getuid);
     register_syscall(SYSCALL_GETEUID, syscall_geteuid);
     register_syscall(SYSCALL_GETGID, syscall_getgid);
@@ -204,6 +285,7 @@ pub fn syscalls_reg_env() {
     register_syscall(SYSCALL_SETUID, syscall_setuid);
     register_syscall(SYSCALL_SETGID, syscall_setgid);
     register_syscall(SYSCALL_SETSID, syscall_setsid);
+    register_syscall(SYSCALL_GETSID, syscall_getsid);
     register_syscall(SYSCALL_PRCTL, syscall_prctl);
     register_syscall(SYSCALL_SET_TID_ADDR, syscall_set_tid_address);
     register_syscall(SYSCALL_GET_TID, syscall_gettid);
@@ -8,6 +8,22 @@ use crate::util::*;
 use crate::poll::*;
 use crate::timer::*;
 use crate::linux::*;
+use crate::vfs::{MountPoint, Statfs, MOUNTS, MS_BIND, MS_NODEV};
+
+const AT_REMOVEDIR: u32 = 0x200;
+const UTIME_NOW: i64 = (1 << 30) - 1;
+const UTIME_OMIT: i64 = (1 << 30) - 2;
+
+/// The mountpoint a path lands on, symlinks aren't followed
+fn path_mount(task: &Task, path: &str) -> Result<Arc<MountPoint>, usize> {
+    let cwd = task.info_fs.lock().unwrap().cwd.clone();
+    MOUNTS.determine_mount_point(&fs_sanitize(&cwd, path)).ok_or(ENOENT)
+}
+
+/// EROFS for paths on read-only mounts
+fn fs_writable(task: &Task, path: &str) -> Result<(), usize> {
+    path_mount(task, path)?.check_writable()
+}
 
 pub fn syscall_read(fd: usize, buf: &mut [u8]) -> Result<usize, usize> {
     if buf.is_empty() { return Ok(0); }
@@ -26,7 +42,27 @@ pub fn syscall_write(fd: usize, buf: &[u8]) -> Result<usize, usize> {
 pub fn syscall_open(filename: &str, flags: u32, mode: u32) -> Result<usize, usize> {
     if filename.is_empty() { return Err(EFAULT); }
     let task = current_task();
-    fs_user_open(&task, filename, flags, mode)
+
+    let mnt = path_mount(&task, filename)?;
+    if flags & (O_WRONLY | O_RDWR | O_CREAT | O_TRUNC) != 0 {
+        mnt.check_writable()?;
+    }
+    if mnt.flags() & MS_NODEV != 0 {
+        let mut st = Stat::default();
+        if fs_stat_by_filename(&task, filename, &mut st)
+            && matches!(st.mode & S_IFMT, S_IFCHR | S_IFBLK)
+        {
+            return Err(EACCES);
+        }
+    }
+
+    let fd = fs_user_open(&task, filename, flags, mode)?;
+    if let Some(file) = fs_user_get_node(&task, fd) {
+        file.mount_pin = MOUNTS.pin(&file.mount_point);
+        let cwd = task.info_fs.lock().unwrap().cwd.clone();
+        file.path = Some(fs_sanitize(&cwd, filename));
+    }
+    Ok(fd)
 }
 
 pub fn syscall_close(fd: usize) -> Result<usize, usize> {
@@ -108,6 +144,7 @@ pub fn syscall_writev(fd: usize, iov: &[IoVec]) -> Result<usize, usize> {
 
 pub fn syscall_mkdir(path: &str, mode: u32) -> Result<usize, usize> {
     let task = current_task();
+    fs_writable(&task, path)?;
     let mut fs_lock = task.info_fs.lock().unwrap();
     let mode = mode & !fs_lock.umask;
     fs_mkdir(&task, path, mode)
@@ -115,9 +152,87 @@ pub fn syscall_mkdir(path: &str, mode: u32) -> Result<usize, usize> {
 
 pub fn syscall_unlink(path: &str) -> Result<usize, usize> {
     let task = current_task();
+    fs_writable(&task, path)?;
     fs_unlink(&task, path, false)
 }
 
+pub fn syscall_rmdir(path: &str) -> Result<usize, usize> {
+    let task = current_task();
+    fs_writable(&task, path)?;
+    fs_unlink(&task, path, true)
+}
+
+pub fn syscall_rename(oldpath: &str, newpath: &str) -> Result<usize, usize> {
+    let task = current_task();
+    fs_writable(&task, newpath)?;
+    fs_rename(&task, oldpath, newpath)
+}
+
+pub fn syscall_link(oldpath: &str, newpath: &str) -> Result<usize, usize> {
+    let task = current_task();
+    fs_writable(&task, newpath)?;
+    fs_link(&task, oldpath, newpath)
+}
+
+pub fn syscall_symlink(target: &str, linkpath: &str) -> Result<usize, usize> {
+    let task = current_task();
+    fs_writable(&task, linkpath)?;
+    fs_symlink(&task, target, linkpath)
+}
+
+pub fn syscall_truncate(path: &str, length: isize) -> Result<usize, usize> {
+    if length < 0 { return Err(EINVAL); }
+    let task = current_task();
+    fs_writable(&task, path)?;
+    fs_truncate(&task, path, length as usize)
+}
+
+pub fn syscall_ftruncate(fd: usize, length: isize) -> Result<usize, usize> {
+    if length < 0 { return Err(EINVAL); }
+    let task = current_task();
+    let file = fs_user_get_node(&task, fd).ok_or(EBADF)?;
+    file.mount_point.check_writable()?;
+    let truncate = file.handlers.as_ref().and_then(|h| h.truncate).ok_or(EINVAL)?;
+    let _lock = file.lock_operations.lock().unwrap();
+    truncate(file, length as usize)
+}
+
+pub fn syscall_chmod(path: &str, mode: u32) -> Result<usize, usize> {
+    let task = current_task();
+    fs_writable(&task, path)?;
+    fs_chmod(&task, path, mode)
+}
+
+pub fn syscall_fchmod(fd: usize, mode: u32) -> Result<usize, usize> {
+    let task = current_task();
+    let file = fs_user_get_node(&task, fd).ok_or(EBADF)?;
+    file.mount_point.check_writable()?;
+    let dirname = file.dirname.as_ref().ok_or(EBADF)?;
+    let path = format!("{}{}", file.mount_point.prefix, dirname);
+    fs_chmod(&task, &path, mode)
+}
+
+pub fn syscall_chown(path: &str, uid: u32, gid: u32) -> Result<usize, usize> {
+    let task = current_task();
+    fs_writable(&task, path)?;
+    fs_chown(&task, path, uid, gid, true)
+}
+
+pub fn syscall_lchown(path: &str, uid: u32, gid: u32) -> Result<usize, usize> {
+    let task = current_task();
+    fs_writable(&task, path)?;
+    fs_chown(&task, path, uid, gid, false)
+}
+
+pub fn syscall_fchown(fd: usize, uid: u32, gid: u32) -> Result<usize, usize> {
+    let task = current_task();
+    let file = fs_user_get_node(&task, fd).ok_or(EBADF)?;
+    file.mount_point.check_writable()?;
+    let dirname = file.dirname.as_ref().ok_or(EBADF)?;
+    let path = format!("{}{}", file.mount_point.prefix, dirname);
+    fs_chown(&task, &path, uid, gid, true)
+}
+
 pub fn syscall_umask(mask: u32) -> u32 {
     let task = current_task();
     let mut fs = task.info_fs.lock().unwrap();
@@ -148,6 +263,98 @@ pub fn syscall_mkdirat(dirfd: usize, pathname: &str, mode: u32) -> Result<usize,
     syscall_mkdir(&resolved, mode)
 }
 
+pub fn syscall_renameat(olddirfd: usize, oldpath: &str, newdirfd: usize, newpath: &str) -> Result<usize, usize> {
+    let old_resolved = at_resolve_pathname(olddirfd, oldpath)?;
+    let new_resolved = at_resolve_pathname(newdirfd, newpath)?;
+    syscall_rename(&old_resolved, &new_resolved)
+}
+
+pub fn syscall_unlinkat(dirfd: usize, pathname: &str, flags: u32) -> Result<usize, usize> {
+    let resolved = at_resolve_pathname(dirfd, pathname)?;
+    let task = current_task();
+    fs_writable(&task, &resolved)?;
+    fs_unlink(&task, &resolved, flags & AT_REMOVEDIR != 0)
+}
+
+pub fn syscall_symlinkat(target: &str, newdirfd: usize, linkpath: &str) -> Result<usize, usize> {
+    let resolved = at_resolve_pathname(newdirfd, linkpath)?;
+    syscall_symlink(target, &resolved)
+}
+
+pub fn syscall_fchmodat(dirfd: usize, pathname: &str, mode: u32) -> Result<usize, usize> {
+    let resolved = at_resolve_pathname(dirfd, pathname)?;
+    syscall_chmod(&resolved, mode)
+}
+
+/// Timestamps are kept with a one second granularity
+pub fn syscall_utimensat(dirfd: usize, pathname: Option<&str>, times: Option<&[Timespec; 2]>, flags: u32) -> Result<usize, usize> {
+    let task = current_task();
+    let path = match pathname {
+        Some(pathname) => at_resolve_pathname(dirfd, pathname)?,
+        // futimens()
+        None => {
+            let file = fs_user_get_node(&task, dirfd).ok_or(EBADF)?;
+            let dirname = file.dirname.as_ref().ok_or(EBADF)?;
+            format!("{}{}", file.mount_point.prefix, dirname)
+        }
+    };
+
+    fs_writable(&task, &path)?;
+
+    let now = timer_boot_unix() + timer_ticks() / 1000;
+    let pick = |ts: &Timespec| match ts.tv_nsec {
+        UTIME_NOW => now,
+        UTIME_OMIT => u64::MAX,
+        _ => ts.tv_sec as u64,
+    };
+    let (atime, mtime) = match times {
+        Some(times) => (pick(&times[0]), pick(&times[1])),
+        None => (now, now),
+    };
+
+    fs_utimens(&task, &path, atime, mtime, flags & AT_SYMLINK_NOFOLLOW == 0)
+}
+
+pub fn syscall_mount(source: Option<&str>, target: &str, fs_type: Option<&str>, flags: u64, data: Option<&str>) -> Result<usize, usize> {
+    let task = current_task();
+    let cwd = task.info_fs.lock().unwrap().cwd.clone();
+    let target = fs_sanitize(&cwd, target);
+
+    let mut st = Stat::default();
+    if !fs_stat_by_filename(&task, &target, &mut st) { return Err(ENOENT); }
+    if !st.is_dir { return Err(ENOTDIR); }
+
+    // bind mount sources are paths as well
+    let source = match source {
+        Some(source) if flags & MS_BIND != 0 => Some(fs_sanitize(&cwd, source)),
+        source => source.map(String::from),
+    };
+    MOUNTS.mount_user(source.as_deref(), &target, fs_type, flags, data)?;
+    Ok(0)
+}
+
+pub fn syscall_umount2(target: &str, flags: u32) -> Result<usize, usize> {
+    let task = current_task();
+    let cwd = task.info_fs.lock().unwrap().cwd.clone();
+    MOUNTS.unmount(&fs_sanitize(&cwd, target), flags)?;
+    Ok(0)
+}
+
+pub fn syscall_statfs(path: &str, buf: &mut Statfs) -> Result<usize, usize> {
+    let task = current_task();
+    let mut st = Stat::default();
+    if !fs_stat_by_filename(&task, path, &mut st) { return Err(ENOENT); }
+    MOUNTS.statfs(&path_mount(&task, path)?, buf)?;
+    Ok(0)
+}
+
+pub fn syscall_fstatfs(fd: usize, buf: &mut Statfs) -> Result<usize, usize> {
+    let task = current_task();
+    let file = fs_user_get_node(&task, fd).ok_or(EBADF)?;
+    MOUNTS.statfs(&file.mount_point, buf)?;
+    Ok(0)
+}
+
 // --- Registration ---
 pub fn syscall_reg_fs() {
     register_syscall(SYSCALL_READ, syscall_read);
@@ -166,4 +373,24 @@ pub fn syscall_reg_fs() {
     register_syscall(SYSCALL_READV, syscall_readv);
     register_syscall(SYSCALL_WRITEV, syscall_writev);
     register_syscall(SYSCALL_MKDIRAT, syscall_mkdirat);
+    register_syscall(SYSCALL_RMDIR, syscall_rmdir);
+    register_syscall(SYSCALL_UNLINKAT, syscall_unlinkat);
+    register_syscall(SYSCALL_RENAME, syscall_rename);
+    register_syscall(SYSCALL_RENAMEAT, syscall_renameat);
+    register_syscall(SYSCALL_LINK, syscall_link);
+    register_syscall(SYSCALL_SYMLINK, syscall_symlink);
+    register_syscall(SYSCALL_SYMLINKAT, syscall_symlinkat);
+    register_syscall(SYSCALL_TRUNCATE, syscall_truncate);
+    register_syscall(SYSCALL_FTRUNCATE, syscall_ftruncate);
+    register_syscall(SYSCALL_CHMOD, syscall_chmod);
+    register_syscall(SYSCALL_FCHMOD, syscall_fchmod);
+    register_syscall(SYSCALL_FCHMODAT, syscall_fchmodat);
+    register_syscall(SYSCALL_CHOWN, syscall_chown);
+    register_syscall(SYSCALL_FCHOWN, syscall_fchown);
+    register_syscall(SYSCALL_LCHOWN, syscall_lchown);
+    register_syscall(SYSCALL_UTIMENSAT, syscall_utimensat);
+    register_syscall(SYSCALL_MOUNT, syscall_mount);
+    register_syscall(SYSCALL_UMOUNT2, syscall_umount2);
+    register_syscall(SYSCALL_STATFS, syscall_statfs);
+    register_syscall(SYSCALL_FSTATFS, syscall_fstatfs);
 }
@@ -9,6 +9,11 @@ use crate::fs::*;
 // Constants
 const PAGE_SIZE: usize = 0x1000;
 
+extern "C" {
+    fn taskInfoPdMappingAdd(target: *mut TaskInfoPagedir, start: usize, end: usize, offset: usize, path: *const u8);
+    fn taskInfoPdMappingRemove(target: *mut TaskInfoPagedir, start: usize, end: usize);
+}
+
 bitflags::bitflags! {
     pub struct MmapFlags: u32 {
         const MAP_FIXED     = 0x10;
@@ -53,6 +58,8 @@ pub fn syscall_mmap(
             if end > task.info_pd.mmap_end {
                 task.info_pd.mmap_end = end;
             }
+            // whatever file was mapped there is gone
+            taskInfoPdMappingRemove(task.info_pd.as_ptr(), addr, end);
             task.info_pd.unlock();
 
             for i in 0..pages {
@@ -79,9 +86,28 @@ pub fn syscall_mmap(
         if fd != -1 {
             let file = task.get_file(fd).ok_or(EBADF)?;
             if let Some(handler) = file.handlers.mmap {
+                // whatever file was mapped there is gone, before the handler
+                // gets to register the new mapping itself
+                if flags.contains(MmapFlags::MAP_FIXED) {
+                    task.info_pd.lock();
+                    taskInfoPdMappingRemove(task.info_pd.as_ptr(), addr, addr + length_aligned);
+                    task.info_pd.unlock();
+                }
+
                 file.lock_operations();
                 let res = handler(addr, length_aligned, prot, flags, file, pgoffset);
                 file.unlock_operations();
+
+                // remembered for the NT_FILE note of core dumps
+                if (res as isize) >= 0 {
+                    if let Some(path) = &file.path {
+                        let mut cpath = path.clone();
+                        cpath.push('\0');
+                        task.info_pd.lock();
+                        taskInfoPdMappingAdd(task.info_pd.as_ptr(), res, res + length_aligned, pgoffset, cpath.as_ptr());
+                        task.info_pd.unlock();
+                    }
+                }
                 return Ok(res);
             } else {
                 return Err(ENOSYS);
@@ -110,6 +136,10 @@ pub fn syscall_munmap(task: &mut Task, addr: usize, len: usize) -> Result<(), i3
 
     let pages = (len + PAGE_SIZE - 1) / PAGE_SIZE;
 
+    task.info_pd.lock();
+    unsafe { taskInfoPdMappingRemove(task.info_pd.as_ptr(), addr, addr + pages * PAGE_SIZE) };
+    task.info_pd.unlock();
+
     for i in 0..pages {
         unsafe {
             let phys = virtual_to_physical(addr + i * PAGE_SIZE);
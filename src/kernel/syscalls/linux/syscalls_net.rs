@@ -2,24 +2,50 @@ use crate::task::*;
 use crate::fs::*;
 use crate::unix_socket::*;
 use crate::socket::*;
+use crate::netlink::*;
+use crate::packet::*;
+use crate::tcp_socket::*;
+use crate::paging::*;
 use crate::system::*;
 use crate::timer::*;
 use crate::lwip::*; // bindings to lwIP
 
 use core::ptr::null_mut;
 
+use super::syscalls_mem::{MmapFlags, ProtFlags};
+
 // ==========================
 // sockaddr conversion
 // ==========================
+
+// Linux leads with a u16 family, lwIP with a u8 length and a u8 family. Past
+// those two bytes sockaddr_in and sockaddr_in6 look the same for both, so
+// v4-mapped addresses & scope ids pass through untouched.
+const SOCKADDR_IN_LEN: u32 = 16;
+const SOCKADDR_IN6_LEN: u32 = 28;
+
 pub fn sockaddr_linux_to_lwip(addr: &mut sockaddr_linux, addrlen: u32) -> u16 {
     let initial_family = addr.sa_family;
-    addr.sa_len = addrlen;
-    addr.sa_family = AF_INET as u16; // convert to lwIP style
+    let len = match initial_family as i32 {
+        AF_INET => SOCKADDR_IN_LEN.min(addrlen),
+        AF_INET6 => SOCKADDR_IN6_LEN.min(addrlen),
+        _ => addrlen.min(u8::MAX as u32),
+    };
+
+    let raw = addr as *mut sockaddr_linux as *mut u8;
+    unsafe {
+        *raw = len as u8;
+        *raw.add(1) = initial_family as u8; // AF_INET & AF_INET6 match lwIP's
+    }
     initial_family
 }
 
+// lwIP says which family it filled in (an AF_INET6 socket can hand out
+// v4-mapped peers), `initial_family` only covers untouched buffers
 pub fn sockaddr_lwip_to_linux(addr: &mut sockaddr_linux, initial_family: u16) {
-    addr.sa_family = initial_family;
+    let raw = addr as *mut sockaddr_linux as *mut u8;
+    let lwip_family = unsafe { *raw.add(1) } as u16;
+    addr.sa_family = if lwip_family != 0 { lwip_family } else { initial_family };
 }
 
 // ==========================
@@ -29,42 +55,71 @@ pub fn syscall_socket(task: &mut Task, mut family: i32, mut ty: i32, protocol: i
     match family {
         AF_UNIX => Ok(unix_socket_open(task, ty, protocol)?),
 
-        AF_INET6 => {
-            let socket_fd = fs_user_open(task, "/dev/null", O_RDWR, 0)?;
-            let file = fs_user_get_node(task, socket_fd).ok_or(ENOSYS)?;
-            file.handlers = &SOCKET_V6_HANDLERS;
-            Ok(socket_fd)
-        }
-
-        AF_INET => {
+        // lwIP is dual-stack, AF_INET6 sockets take v4-mapped addresses
+        // unless IPV6_V6ONLY gets set
+        AF_INET | AF_INET6 => {
             let cloexec = (ty & SOCK_CLOEXEC) != 0;
             let nonblock = (ty & SOCK_NONBLOCK) != 0;
             ty &= !(SOCK_CLOEXEC | SOCK_NONBLOCK);
 
+            // net.tcp=native at boot: IPv4 TCP runs on the kernel's own stack
+            if family == AF_INET && ty == SOCK_STREAM && tcp_native() {
+                let tcp = TcpSocket::new(ty, protocol)
+                    .map_err(|e| e.raw_os_error().unwrap_or(EINVAL))?;
+
+                return socket_open(task, cloexec, nonblock, &TCP_HANDLERS, Box::new(tcp));
+            }
+
             let lwip_fd = lwip_socket(family, ty, protocol).map_err(|e| -e)?;
             assert!(lwip_fcntl(lwip_fd, F_SETFL, O_NONBLOCK) == 0);
 
-            let socket_fd = fs_user_open(task, "/dev/stdout", O_RDWR, 0)?;
-            let socket_node = fs_user_get_node(task, socket_fd).ok_or(-1)?;
+            socket_open(task, cloexec, nonblock, &SOCKET_HANDLERS, Box::new(UserSocket::new(lwip_fd, family)))
+        }
 
-            if cloexec { socket_node.close_on_exec = true; }
-            if nonblock { socket_node.flags |= O_NONBLOCK; }
+        AF_NETLINK => {
+            let cloexec = (ty & SOCK_CLOEXEC) != 0;
+            let nonblock = (ty & SOCK_NONBLOCK) != 0;
+            ty &= !(SOCK_CLOEXEC | SOCK_NONBLOCK);
+            if ty != SOCK_RAW && ty != SOCK_DGRAM {
+                return Err(ESOCKTNOSUPPORT);
+            }
 
-            socket_node.handlers = &SOCKET_HANDLERS;
+            let netlink = NetlinkSocket::new(protocol, task.tgid as u32)
+                .map_err(|e| e.raw_os_error().unwrap_or(EINVAL))?;
 
-            let user_socket = Box::into_raw(Box::new(UserSocket {
-                lwip_fd,
-                socket_instances: 1,
-            }));
-            socket_node.dir = user_socket as *mut _;
+            socket_open(task, cloexec, nonblock, &NETLINK_HANDLERS, Box::new(netlink))
+        }
 
-            Ok(socket_fd)
+        AF_PACKET => {
+            let cloexec = (ty & SOCK_CLOEXEC) != 0;
+            let nonblock = (ty & SOCK_NONBLOCK) != 0;
+            ty &= !(SOCK_CLOEXEC | SOCK_NONBLOCK);
+
+            let packet = PacketSocket::new(ty, protocol)
+                .map_err(|e| e.raw_os_error().unwrap_or(EINVAL))?;
+
+            socket_open(task, cloexec, nonblock, &PACKET_HANDLERS, Box::new(packet))
         }
 
         _ => Err(ENOSYS),
     }
 }
 
+/// A socket is a /dev/stdout fd with its handlers swapped out, `state` is
+/// what they find in `dir`
+fn socket_open<T>(task: &mut Task, cloexec: bool, nonblock: bool, handlers: &'static VfsHandlers, state: Box<T>) -> Result<usize, i32> {
+    let socket_fd = fs_user_open(task, "/dev/stdout", O_RDWR, 0)?;
+    let socket_node = fs_user_get_node(task, socket_fd).ok_or(-1)?;
+
+    if cloexec { socket_node.close_on_exec = true; }
+    if nonblock { socket_node.flags |= O_NONBLOCK; }
+
+    socket_node.handlers = handlers;
+    socket_node.dir = Box::into_raw(state) as *mut _;
+
+    Ok(socket_fd)
+}
+
 // ==========================
 // Generic syscall helper
 // ==========================
@@ -105,6 +160,10 @@ pub fn syscall_getpeername(task: &mut Task, fd: i32, addr: &mut sockaddr_linux,
     dispatch_file_handler!(task, fd, getpeername, addr, len)
 }
 
+pub fn syscall_shutdown(task: &mut Task, fd: i32, how: i32) -> Result<usize, i32> {
+    dispatch_file_handler!(task, fd, shutdown, how)
+}
+
 pub fn syscall_socketpair(task: &mut Task, family: i32, ty: i32, protocol: i32, sv: &mut [i32;2]) -> Result<usize, i32> {
     match family {
         AF_UNIX => unix_socket_pair(ty, protocol, sv),
@@ -116,6 +175,10 @@ pub fn syscall_getsockopt(task: &mut Task, fd: i32, level: i32, optname: i32, op
     dispatch_file_handler!(task, fd, getsockopts, level, optname, optval, socklen)
 }
 
+pub fn syscall_setsockopt(task: &mut Task, fd: i32, level: i32, optname: i32, optval: *const u8, socklen: u32) -> Result<usize, i32> {
+    dispatch_file_handler!(task, fd, setsockopts, level, optname, optval, socklen)
+}
+
 pub fn syscall_sendto(task: &mut Task, fd: i32, buff: *const u8, len: usize, flags: i32, addr: &sockaddr_linux, addrlen: socklen_t) -> Result<usize, i32> {
     dispatch_file_handler!(task, fd, sendto, buff, len, flags, addr, addrlen)
 }
@@ -132,6 +195,555 @@ pub fn syscall_recvmsg(task: &mut Task, fd: i32, msg: &mut msghdr_linux, flags:
     dispatch_file_handler!(task, fd, recvmsg, msg, flags)
 }
 
+// ==========================
+// Socket ioctls (SOCKET_HANDLERS.ioctl)
+// ==========================
+pub fn socket_ioctl(file: &mut OpenFile, request: u64, arg: *mut u8) -> Result<usize, i32> {
+    // the lwIP socket itself always stays non-blocking, FIONBIO only flips
+    // the file's flag just like fcntl(F_SETFL) would
+    if request == FIONBIO {
+        if arg.is_null() {
+            return Err(EFAULT);
+        }
+        if unsafe { core::ptr::read_unaligned(arg as *const i32) } != 0 {
+            file.flags |= O_NONBLOCK;
+        } else {
+            file.flags &= !O_NONBLOCK;
+        }
+        return Ok(0);
+    }
+
+    if is_interface_ioctl(request) {
+        return interface_ioctl(request, arg as *mut _).map_err(|e| e.raw_os_error().unwrap_or(EIO));
+    }
+
+    let user_socket = unsafe { &*(file.dir as *const UserSocket) };
+    user_socket
+        .ioctl(request, arg as *mut _)
+        .map_err(|e| e.raw_os_error().unwrap_or(EIO))
+}
+
+// ==========================
+// Netlink sockets (NETLINK_HANDLERS)
+// ==========================
+fn netlink_socket(file: &OpenFile) -> &NetlinkSocket {
+    unsafe { &*(file.dir as *const NetlinkSocket) }
+}
+
+fn netlink_errno(e: std::io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+fn netlink_write_source(addr: *mut sockaddr_linux, addrlen: *mut socklen_t) {
+    if addr.is_null() || addrlen.is_null() {
+        return;
+    }
+    let source = NetlinkSocket::source();
+    unsafe {
+        let len = (*addrlen as usize).min(core::mem::size_of::<sockaddr_nl>());
+        core::ptr::copy_nonoverlapping(&source as *const sockaddr_nl as *const u8, addr as *mut u8, len);
+        *addrlen = core::mem::size_of::<sockaddr_nl>() as socklen_t;
+    }
+}
+
+fn netlink_destination(addr: *const sockaddr_linux, addrlen: socklen_t) -> Result<Option<sockaddr_nl>, i32> {
+    if addr.is_null() || addrlen == 0 {
+        return Ok(None);
+    }
+    if (addrlen as usize) < core::mem::size_of::<sockaddr_nl>() {
+        return Err(EINVAL);
+    }
+    Ok(Some(unsafe { core::ptr::read_unaligned(addr as *const sockaddr_nl) }))
+}
+
+pub fn netlink_bind(file: &mut OpenFile, addr: &sockaddr_linux, len: usize) -> Result<usize, i32> {
+    netlink_socket(file)
+        .bind(addr as *const sockaddr_linux as *const sockaddr_nl, len as u32)
+        .map_err(netlink_errno)?;
+    Ok(0)
+}
+
+// netlink "connect" only ever targets the kernel, nothing to remember
+pub fn netlink_connect(_file: &mut OpenFile, addr: &sockaddr_linux, len: usize) -> Result<usize, i32> {
+    netlink_destination(addr, len as socklen_t)?;
+    Ok(0)
+}
+
+pub fn netlink_getsockname(file: &mut OpenFile, addr: &mut sockaddr_linux, len: &mut socklen_t) -> Result<usize, i32> {
+    netlink_socket(file)
+        .getsockname(addr as *mut sockaddr_linux as *mut sockaddr_nl, len)
+        .map_err(netlink_errno)?;
+    Ok(0)
+}
+
+pub fn netlink_getpeername(_file: &mut OpenFile, addr: &mut sockaddr_linux, len: &mut socklen_t) -> Result<usize, i32> {
+    netlink_write_source(addr, len);
+    Ok(0)
+}
+
+pub fn netlink_sendto(file: &mut OpenFile, buff: *const u8, len: usize, _flags: i32, addr: &sockaddr_linux, addrlen: socklen_t) -> Result<usize, i32> {
+    let dest = netlink_destination(addr, addrlen)?;
+    let buf = unsafe { core::slice::from_raw_parts(buff, len) };
+    netlink_socket(file).send(buf, dest.as_ref()).map_err(netlink_errno)
+}
+
+pub fn netlink_recvfrom(file: &mut OpenFile, buff: *mut u8, len: usize, flags: i32, addr: &mut sockaddr_linux, addrlen: &mut socklen_t) -> Result<usize, i32> {
+    let nonblock = file.flags & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0;
+    let buf = unsafe { core::slice::from_raw_parts_mut(buff, len) };
+    let (copied, full) = netlink_socket(file)
+        .recv(buf, flags & MSG_PEEK != 0, nonblock)
+        .map_err(netlink_errno)?;
+    netlink_write_source(addr, addrlen);
+    Ok(if flags & MSG_TRUNC != 0 { full } else { copied })
+}
+
+pub fn netlink_sendmsg(file: &mut OpenFile, msg: &msghdr_linux, _flags: i32) -> Result<usize, i32> {
+    let dest = netlink_destination(msg.msg_name as *const sockaddr_linux, msg.msg_namelen as socklen_t)?;
+
+    // requests must arrive whole, so gather them first
+    let mut buf = Vec::new();
+    let iov = unsafe { core::slice::from_raw_parts(msg.msg_iov, msg.msg_iovlen as usize) };
+    for vec in iov {
+        buf.extend_from_slice(unsafe { core::slice::from_raw_parts(vec.iov_base as *const u8, vec.iov_len) });
+    }
+
+    netlink_socket(file).send(&buf, dest.as_ref()).map_err(netlink_errno)
+}
+
+pub fn netlink_recvmsg(file: &mut OpenFile, msg: &mut msghdr_linux, flags: i32) -> Result<usize, i32> {
+    let nonblock = file.flags & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0;
+    let iov = unsafe { core::slice::from_raw_parts(msg.msg_iov, msg.msg_iovlen as usize) };
+    let total: usize = iov.iter().map(|vec| vec.iov_len).sum();
+
+    let mut buf = vec![0u8; total];
+    let (copied, full) = netlink_socket(file)
+        .recv(&mut buf, flags & MSG_PEEK != 0, nonblock)
+        .map_err(netlink_errno)?;
+
+    let mut offset = 0;
+    for vec in iov {
+        if offset >= copied {
+            break;
+        }
+        let chunk = vec.iov_len.min(copied - offset);
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr().add(offset), vec.iov_base as *mut u8, chunk) };
+        offset += chunk;
+    }
+
+    if !msg.msg_name.is_null() {
+        let mut namelen = msg.msg_namelen as socklen_t;
+        netlink_write_source(msg.msg_name as *mut sockaddr_linux, &mut namelen);
+        msg.msg_namelen = namelen as _;
+    }
+    msg.msg_controllen = 0;
+    msg.msg_flags = if full > copied { MSG_TRUNC } else { 0 };
+
+    Ok(if flags & MSG_TRUNC != 0 { full } else { copied })
+}
+
+pub fn netlink_getsockopts(file: &mut OpenFile, level: i32, optname: i32, optval: *mut u8, socklen: &mut u32) -> Result<usize, i32> {
+    netlink_socket(file)
+        .getsockopt(level, optname, optval as *mut _, socklen)
+        .map_err(netlink_errno)?;
+    Ok(0)
+}
+
+pub fn netlink_setsockopts(file: &mut OpenFile, level: i32, optname: i32, optval: *const u8, socklen: u32) -> Result<usize, i32> {
+    netlink_socket(file)
+        .setsockopt(level, optname, optval as *const _, socklen)
+        .map_err(netlink_errno)?;
+    Ok(0)
+}
+
+pub fn netlink_ioctl(file: &mut OpenFile, request: u64, arg: *mut u8) -> Result<usize, i32> {
+    if request == FIONBIO {
+        return socket_ioctl(file, request, arg);
+    }
+    if !is_interface_ioctl(request) {
+        return Err(ENOTTY);
+    }
+    interface_ioctl(request, arg as *mut _).map_err(netlink_errno)
+}
+
+pub fn netlink_poll(file: &mut OpenFile, events: i32) -> i32 {
+    let mut revents = events & EPOLLOUT;
+    if netlink_socket(file).readable() {
+        revents |= events & EPOLLIN;
+    }
+    revents
+}
+
+pub fn netlink_duplicate(original: &mut OpenFile, orphan: &mut OpenFile) -> bool {
+    let copy = netlink_socket(original).duplicate();
+    orphan.dir = Box::into_raw(Box::new(copy)) as *mut _;
+    true
+}
+
+pub fn netlink_close(file: &mut OpenFile) -> bool {
+    drop(unsafe { Box::from_raw(file.dir as *mut NetlinkSocket) });
+    true
+}
+
+// ==========================
+// Packet sockets (PACKET_HANDLERS)
+// ==========================
+const PAGE_SIZE: usize = 0x1000;
+
+fn packet_socket(file: &OpenFile) -> &PacketSocket {
+    unsafe { &*(file.dir as *const PacketSocket) }
+}
+
+fn packet_errno(e: std::io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+fn packet_write_source(source: &sockaddr_ll, addr: *mut sockaddr_linux, addrlen: *mut socklen_t) {
+    if addr.is_null() || addrlen.is_null() {
+        return;
+    }
+    unsafe {
+        let len = (*addrlen as usize).min(core::mem::size_of::<sockaddr_ll>());
+        core::ptr::copy_nonoverlapping(source as *const sockaddr_ll as *const u8, addr as *mut u8, len);
+        *addrlen = core::mem::size_of::<sockaddr_ll>() as socklen_t;
+    }
+}
+
+fn packet_destination(addr: *const sockaddr_linux, addrlen: socklen_t) -> Result<Option<sockaddr_ll>, i32> {
+    if addr.is_null() || addrlen == 0 {
+        return Ok(None);
+    }
+    if (addrlen as usize) < core::mem::size_of::<sockaddr_ll>() {
+        return Err(EINVAL);
+    }
+    Ok(Some(unsafe { core::ptr::read_unaligned(addr as *const sockaddr_ll) }))
+}
+
+pub fn packet_bind(file: &mut OpenFile, addr: &sockaddr_linux, len: usize) -> Result<usize, i32> {
+    packet_socket(file)
+        .bind(addr as *const sockaddr_linux as *const sockaddr_ll, len as u32)
+        .map_err(packet_errno)?;
+    Ok(0)
+}
+
+pub fn packet_getsockname(file: &mut OpenFile, addr: &mut sockaddr_linux, len: &mut socklen_t) -> Result<usize, i32> {
+    packet_socket(file)
+        .getsockname(addr as *mut sockaddr_linux as *mut sockaddr_ll, len)
+        .map_err(packet_errno)?;
+    Ok(0)
+}
+
+pub fn packet_sendto(file: &mut OpenFile, buff: *const u8, len: usize, _flags: i32, addr: &sockaddr_linux, addrlen: socklen_t) -> Result<usize, i32> {
+    let dest = packet_destination(addr, addrlen)?;
+    let buf = unsafe { core::slice::from_raw_parts(buff, len) };
+    packet_socket(file).send(buf, dest.as_ref()).map_err(packet_errno)
+}
+
+pub fn packet_recvfrom(file: &mut OpenFile, buff: *mut u8, len: usize, flags: i32, addr: &mut sockaddr_linux, addrlen: &mut socklen_t) -> Result<usize, i32> {
+    let nonblock = file.flags & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0;
+    let buf = unsafe { core::slice::from_raw_parts_mut(buff, len) };
+    let (copied, full, source) = packet_socket(file)
+        .recv(buf, flags & MSG_PEEK != 0, nonblock)
+        .map_err(packet_errno)?;
+    packet_write_source(&source, addr, addrlen);
+    Ok(if flags & MSG_TRUNC != 0 { full } else { copied })
+}
+
+pub fn packet_sendmsg(file: &mut OpenFile, msg: &msghdr_linux, _flags: i32) -> Result<usize, i32> {
+    let dest = packet_destination(msg.msg_name as *const sockaddr_linux, msg.msg_namelen as socklen_t)?;
+
+    // one frame per call, so gather it first
+    let mut buf = Vec::new();
+    let iov = unsafe { core::slice::from_raw_parts(msg.msg_iov, msg.msg_iovlen as usize) };
+    for vec in iov {
+        buf.extend_from_slice(unsafe { core::slice::from_raw_parts(vec.iov_base as *const u8, vec.iov_len) });
+    }
+
+    packet_socket(file).send(&buf, dest.as_ref()).map_err(packet_errno)
+}
+
+pub fn packet_recvmsg(file: &mut OpenFile, msg: &mut msghdr_linux, flags: i32) -> Result<usize, i32> {
+    let nonblock = file.flags & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0;
+    let iov = unsafe { core::slice::from_raw_parts(msg.msg_iov, msg.msg_iovlen as usize) };
+    let total: usize = iov.iter().map(|vec| vec.iov_len).sum();
+
+    let mut buf = vec![0u8; total];
+    let (copied, full, source) = packet_socket(file)
+        .recv(&mut buf, flags & MSG_PEEK != 0, nonblock)
+        .map_err(packet_errno)?;
+
+    let mut offset = 0;
+    for vec in iov {
+        if offset >= copied {
+            break;
+        }
+        let chunk = vec.iov_len.min(copied - offset);
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr().add(offset), vec.iov_base as *mut u8, chunk) };
+        offset += chunk;
+    }
+
+    if !msg.msg_name.is_null() {
+        let mut namelen = msg.msg_namelen as socklen_t;
+        packet_write_source(&source, msg.msg_name as *mut sockaddr_linux, &mut namelen);
+        msg.msg_namelen = namelen as _;
+    }
+    msg.msg_controllen = 0;
+    msg.msg_flags = if full > copied { MSG_TRUNC } else { 0 };
+
+    Ok(if flags & MSG_TRUNC != 0 { full } else { copied })
+}
+
+pub fn packet_getsockopts(file: &mut OpenFile, level: i32, optname: i32, optval: *mut u8, socklen: &mut u32) -> Result<usize, i32> {
+    packet_socket(file)
+        .getsockopt(level, optname, optval as *mut _, socklen)
+        .map_err(packet_errno)?;
+    Ok(0)
+}
+
+pub fn packet_setsockopts(file: &mut OpenFile, level: i32, optname: i32, optval: *const u8, socklen: u32) -> Result<usize, i32> {
+    packet_socket(file)
+        .setsockopt(level, optname, optval as *const _, socklen)
+        .map_err(packet_errno)?;
+    Ok(0)
+}
+
+// the interface ioctls work on any socket, libpcap & udhcpc use their
+// packet socket for SIOCGIFINDEX/SIOCGIFHWADDR
+pub fn packet_ioctl(file: &mut OpenFile, request: u64, arg: *mut u8) -> Result<usize, i32> {
+    netlink_ioctl(file, request, arg)
+}
+
+/// PACKET_RX_RING shared with userspace, mapped past the task's mmap area
+/// like anonymous memory
+pub fn packet_mmap(_addr: usize, length: usize, _prot: ProtFlags, _flags: MmapFlags, file: &mut OpenFile, pgoffset: usize) -> usize {
+    let (ring, size) = match packet_socket(file).ring_region() {
+        Ok(region) => region,
+        Err(e) => return -(packet_errno(e) as isize) as usize,
+    };
+    if pgoffset != 0 || length > size {
+        return -(EINVAL as isize) as usize;
+    }
+
+    unsafe {
+        let task = &mut *current_task();
+        task.info_pd.lock();
+        let base = task.info_pd.mmap_end;
+        task.info_pd.mmap_end += length;
+        task.info_pd.unlock();
+
+        for offset in (0..length).step_by(PAGE_SIZE) {
+            let phys = VirtualToPhysical(ring as usize + offset);
+            virtual_map(base + offset, phys, PF_RW | PF_USER);
+        }
+        base
+    }
+}
+
+pub fn packet_poll(file: &mut OpenFile, events: i32) -> i32 {
+    let mut revents = events & EPOLLOUT;
+    if packet_socket(file).readable() {
+        revents |= events & EPOLLIN;
+    }
+    revents
+}
+
+pub fn packet_duplicate(original: &mut OpenFile, orphan: &mut OpenFile) -> bool {
+    let copy = packet_socket(original).duplicate();
+    orphan.dir = Box::into_raw(Box::new(copy)) as *mut _;
+    true
+}
+
+pub fn packet_close(file: &mut OpenFile) -> bool {
+    drop(unsafe { Box::from_raw(file.dir as *mut PacketSocket) });
+    true
+}
+
+// ==========================
+// TCP sockets (TCP_HANDLERS)
+// ==========================
+fn tcp_socket(file: &OpenFile) -> &TcpSocket {
+    unsafe { &*(file.dir as *const TcpSocket) }
+}
+
+fn tcp_errno(e: std::io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+fn tcp_nonblock(file: &OpenFile, flags: i32) -> bool {
+    file.flags & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0
+}
+
+pub fn tcp_socket_bind(file: &mut OpenFile, addr: &sockaddr_linux, len: usize) -> Result<usize, i32> {
+    tcp_socket(file)
+        .bind(addr as *const sockaddr_linux as *const sockaddr_in_linux, len as u32)
+        .map_err(tcp_errno)?;
+    Ok(0)
+}
+
+pub fn tcp_socket_connect(file: &mut OpenFile, addr: &sockaddr_linux, len: usize) -> Result<usize, i32> {
+    let nonblock = tcp_nonblock(file, 0);
+    tcp_socket(file)
+        .connect(addr as *const sockaddr_linux as *const sockaddr_in_linux, len as u32, nonblock)
+        .map_err(tcp_errno)?;
+    Ok(0)
+}
+
+pub fn tcp_socket_listen(file: &mut OpenFile, backlog: i32) -> Result<usize, i32> {
+    tcp_socket(file).listen(backlog).map_err(tcp_errno)?;
+    Ok(0)
+}
+
+pub fn tcp_socket_accept(file: &mut OpenFile, addr: &mut sockaddr_linux, len: &mut u32) -> Result<usize, i32> {
+    let nonblock = tcp_nonblock(file, 0);
+    let (connection, peer) = tcp_socket(file).accept(nonblock).map_err(tcp_errno)?;
+
+    let task = unsafe { &mut *current_task() };
+    let socket_fd = socket_open(task, false, false, &TCP_HANDLERS, Box::new(connection))?;
+
+    if !(addr as *mut sockaddr_linux).is_null() {
+        peer.write(addr as *mut sockaddr_linux as *mut sockaddr_in_linux, len);
+    }
+    Ok(socket_fd)
+}
+
+pub fn tcp_socket_getsockname(file: &mut OpenFile, addr: &mut sockaddr_linux, len: &mut socklen_t) -> Result<usize, i32> {
+    tcp_socket(file)
+        .getsockname(addr as *mut sockaddr_linux as *mut sockaddr_in_linux, len)
+        .map_err(tcp_errno)?;
+    Ok(0)
+}
+
+pub fn tcp_socket_getpeername(file: &mut OpenFile, addr: &mut sockaddr_linux, len: &mut socklen_t) -> Result<usize, i32> {
+    tcp_socket(file)
+        .getpeername(addr as *mut sockaddr_linux as *mut sockaddr_in_linux, len)
+        .map_err(tcp_errno)?;
+    Ok(0)
+}
+
+pub fn tcp_socket_shutdown(file: &mut OpenFile, how: i32) -> Result<usize, i32> {
+    tcp_socket(file).shutdown(how).map_err(tcp_errno)?;
+    Ok(0)
+}
+
+// the destination of a connected stream is already known, any given is
+// ignored like on Linux
+pub fn tcp_socket_sendto(file: &mut OpenFile, buff: *const u8, len: usize, flags: i32, _addr: &sockaddr_linux, _addrlen: socklen_t) -> Result<usize, i32> {
+    let nonblock = tcp_nonblock(file, flags);
+    let buf = unsafe { core::slice::from_raw_parts(buff, len) };
+    tcp_socket(file).send(buf, nonblock).map_err(tcp_errno)
+}
+
+pub fn tcp_socket_recvfrom(file: &mut OpenFile, buff: *mut u8, len: usize, flags: i32, addr: &mut sockaddr_linux, addrlen: &mut socklen_t) -> Result<usize, i32> {
+    let nonblock = tcp_nonblock(file, flags);
+    let buf = unsafe { core::slice::from_raw_parts_mut(buff, len) };
+    let copied = tcp_socket(file).recv(buf, flags, nonblock).map_err(tcp_errno)?;
+    if !(addr as *mut sockaddr_linux).is_null() {
+        // errors only if not connected, and then nothing was read either
+        let _ = tcp_socket(file).getpeername(addr as *mut sockaddr_linux as *mut sockaddr_in_linux, addrlen);
+    }
+    Ok(copied)
+}
+
+pub fn tcp_socket_sendmsg(file: &mut OpenFile, msg: &msghdr_linux, flags: i32) -> Result<usize, i32> {
+    // a stream, so one send for all of it keeps the segments full
+    let mut buf = Vec::new();
+    let iov = unsafe { core::slice::from_raw_parts(msg.msg_iov, msg.msg_iovlen as usize) };
+    for vec in iov {
+        buf.extend_from_slice(unsafe { core::slice::from_raw_parts(vec.iov_base as *const u8, vec.iov_len) });
+    }
+
+    let nonblock = tcp_nonblock(file, flags);
+    tcp_socket(file).send(&buf, nonblock).map_err(tcp_errno)
+}
+
+pub fn tcp_socket_recvmsg(file: &mut OpenFile, msg: &mut msghdr_linux, flags: i32) -> Result<usize, i32> {
+    let nonblock = tcp_nonblock(file, flags);
+    let iov = unsafe { core::slice::from_raw_parts(msg.msg_iov, msg.msg_iovlen as usize) };
+    let total: usize = iov.iter().map(|vec| vec.iov_len).sum();
+
+    let mut buf = vec![0u8; total];
+    let copied = tcp_socket(file).recv(&mut buf, flags, nonblock).map_err(tcp_errno)?;
+
+    let mut offset = 0;
+    for vec in iov {
+        if offset >= copied {
+            break;
+        }
+        let chunk = vec.iov_len.min(copied - offset);
+        unsafe { core::ptr::copy_nonoverlapping(buf.as_ptr().add(offset), vec.iov_base as *mut u8, chunk) };
+        offset += chunk;
+    }
+
+    if !msg.msg_name.is_null() {
+        let mut namelen = msg.msg_namelen as socklen_t;
+        if tcp_socket(file).getpeername(msg.msg_name as *mut sockaddr_in_linux, &mut namelen).is_ok() {
+            msg.msg_namelen = namelen as _;
+        }
+    }
+    msg.msg_controllen = 0;
+    msg.msg_flags = 0;
+
+    Ok(copied)
+}
+
+pub fn tcp_socket_getsockopts(file: &mut OpenFile, level: i32, optname: i32, optval: *mut u8, socklen: &mut u32) -> Result<usize, i32> {
+    tcp_socket(file)
+        .getsockopt(level, optname, optval as *mut _, socklen)
+        .map_err(tcp_errno)?;
+    Ok(0)
+}
+
+pub fn tcp_socket_setsockopts(file: &mut OpenFile, level: i32, optname: i32, optval: *const u8, socklen: u32) -> Result<usize, i32> {
+    tcp_socket(file)
+        .setsockopt(level, optname, optval as *const _, socklen)
+        .map_err(tcp_errno)?;
+    Ok(0)
+}
+
+pub fn tcp_socket_ioctl(file: &mut OpenFile, request: u64, arg: *mut u8) -> Result<usize, i32> {
+    match request {
+        FIONREAD => {
+            if arg.is_null() {
+                return Err(EFAULT);
+            }
+            let available = tcp_socket(file).available().map_err(tcp_errno)?;
+            unsafe { core::ptr::write_unaligned(arg as *mut i32, available as i32) };
+            Ok(0)
+        }
+        // urgent data isn't supported, so never at the mark
+        SIOCATMARK => {
+            if arg.is_null() {
+                return Err(EFAULT);
+            }
+            unsafe { core::ptr::write_unaligned(arg as *mut i32, 0) };
+            Ok(0)
+        }
+        _ => netlink_ioctl(file, request, arg),
+    }
+}
+
+pub fn tcp_socket_poll(file: &mut OpenFile, events: i32) -> i32 {
+    let socket = tcp_socket(file);
+    let mut revents = 0;
+    if socket.readable() {
+        revents |= events & EPOLLIN;
+    }
+    if socket.writable() {
+        revents |= events & EPOLLOUT;
+    }
+    revents
+}
+
+pub fn tcp_socket_duplicate(original: &mut OpenFile, orphan: &mut OpenFile) -> bool {
+    let copy = tcp_socket(original).duplicate();
+    orphan.dir = Box::into_raw(Box::new(copy)) as *mut _;
+    true
+}
+
+pub fn tcp_socket_close(file: &mut OpenFile) -> bool {
+    drop(unsafe { Box::from_raw(file.dir as *mut TcpSocket) });
+    true
+}
+
 // ==========================
 // Register all network syscalls
 // ==========================
@@ -147,6 +759,8 @@ pub fn syscalls_reg_net() {
     register_syscall(SYSCALL_SENDMSG, syscall_sendmsg as usize);
     register_syscall(SYSCALL_LISTEN, syscall_listen as usize);
     register_syscall(SYSCALL_GETSOCKOPT, syscall_getsockopt as usize);
+    register_syscall(SYSCALL_SETSOCKOPT, syscall_setsockopt as usize);
     register_syscall(SYSCALL_GETPEERNAME, syscall_getpeername as usize);
     register_syscall(SYSCALL_GETSOCKNAME, syscall_getsockname as usize);
+    register_syscall(SYSCALL_SHUTDOWN, syscall_shutdown as usize);
 }
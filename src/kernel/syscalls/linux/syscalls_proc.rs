@@ -8,6 +8,8 @@ use crate::syscalls::*;
 use crate::system::*;
 use crate::task::*;
 use crate::util::*;
+use crate::vfs::{MOUNTS, MS_NOEXEC};
+use core::ffi::c_void;
 use core::ptr;
 
 // ==========================
@@ -168,6 +170,12 @@ pub fn syscall_execve(filename: &str, argv: &[&str], envp: &[&str]) -> Result<us
     assert!(!argv.is_empty());
 
     let filename_sanitized = fs_sanitize(current_task().info_fs.cwd, filename);
+    if MOUNTS
+        .determine_mount_point(&filename_sanitized)
+        .map_or(false, |mnt| mnt.flags() & MS_NOEXEC != 0)
+    {
+        return Err(EACCES);
+    }
     let mut buff = vec![0u8; 256];
 
     // pre-scan the file
@@ -202,7 +210,7 @@ pub fn syscall_execve(filename: &str, argv: &[&str], envp: &[&str]) -> Result<us
     ret.parent = current_task().parent;
     ret.pgid = current_task().pgid;
     ret.sid = current_task().sid;
-    ret.ctrl_pty = current_task().ctrl_pty;
+    ret.ctrl_tty = current_task().ctrl_tty;
 
     task_create_finish(ret);
     task_kill(current_task().id, 0);
@@ -213,30 +221,135 @@ pub fn syscall_execve(filename: &str, argv: &[&str], envp: &[&str]) -> Result<us
 // Syscall: exit_task
 // ==========================
 pub fn syscall_exit_task(return_code: i32) {
-    task_kill(current_task().id, return_code);
+    // exit status, as wait4() hands it out
+    task_kill(current_task().id, ((return_code & 0xff) << 8) as u16);
 }
 
 // ==========================
 // Syscall: wait4
 // ==========================
-pub fn syscall_wait4(pid: i32, wstatus: Option<&mut i32>, _options: i32, _ru: Option<&mut RUsage>) -> Result<usize, i32> {
+
+// Task::jobctl (multitasking/jobctl.rs)
+const JOBCTL_SIGNAL_MASK: u32 = 0xff;
+const JOBCTL_STOPPED: u32 = 1 << 8;
+const JOBCTL_CONTINUED: u32 = 1 << 9;
+
+fn wait4_matches(pid: i32, caller_pgid: i32, child_pid: usize, child_pgid: i32) -> bool {
+    match pid {
+        -1 => true,
+        0 => child_pgid == caller_pgid,
+        p if p < -1 => child_pgid == -p,
+        p => child_pid == p as usize,
+    }
+}
+
+/// Backs out of the wait syscall_wait4() sets up before scanning
+unsafe fn wait4_done(me: &mut Task) {
+    me.state = TaskState::Ready;
+    spinlock_release(&me.lock_child_term);
+    asm!("sti");
+}
+
+pub fn syscall_wait4(pid: i32, wstatus: Option<&mut i32>, options: i32, _ru: Option<&mut RUsage>) -> Result<usize, i32> {
+    if options & !(WNOHANG | WUNTRACED | WCONTINUED | WNOWAIT) != 0 {
+        return Err(EINVAL);
+    }
     asm!("sti"); // enable interrupts
 
-    if current_task().children_terminated_amnt == 0 {
-        let mut amnt = 0;
-        let browse = first_task();
+    let me = current_task();
+    loop {
+        // waiting before looking, so a child exiting or stopping mid-scan
+        // still wakes us (taskKill() / jobctl only wake a waiting parent).
+        // No preemption from here to hand_control(), the scheduler lets go
+        // of lock_child_term once we're off the CPU.
+        asm!("cli");
+        spinlock_acquire(&me.lock_child_term);
+        if pid > 0 {
+            me.waiting_for_pid = pid as u64;
+            me.state = TaskState::WaitingChildSpecific;
+        } else {
+            me.state = TaskState::WaitingChild;
+        }
+
+        // stopped / continued children get reported first
+        let mut children = 0;
+        let mut browse = first_task();
         while let Some(task) = browse {
-            if task.state == TaskState::Ready && task.parent == Some(current_task()) {
-                amnt += 1;
+            browse = task.next;
+            if task.parent != Some(me) || task.id != task.tgid || task.state == TaskState::Dead {
+                continue;
+            }
+            if !wait4_matches(pid, me.pgid, task.id, task.pgid) {
+                continue;
             }
+            children += 1;
+
+            let report = task.jobctl;
+            let status = if report & JOBCTL_STOPPED != 0 && options & WUNTRACED != 0 {
+                (((report & JOBCTL_SIGNAL_MASK) as i32) << 8) | 0x7f
+            } else if report & JOBCTL_CONTINUED != 0 && options & WCONTINUED != 0 {
+                0xffff
+            } else {
+                continue;
+            };
+
+            if options & WNOWAIT == 0 {
+                task.jobctl = 0;
+            }
+            wait4_done(me);
+            if let Some(wstatus) = wstatus {
+                *wstatus = status;
+            }
+            return Ok(task.id);
         }
-        if amnt == 0 {
+
+        // then the ones that already exited
+        let mut found = None;
+        let mut info = me.ds_child_terminated.first_object();
+        while let Some(killed) = info {
+            if wait4_matches(pid, me.pgid, killed.pid, killed.pgid) {
+                found = Some(killed);
+                break;
+            }
+            info = killed.next();
+        }
+        if let Some(killed) = found {
+            let child = killed.pid;
+            let status = killed.ret as i32;
+            if options & WNOWAIT == 0 {
+                me.ds_child_terminated.remove(killed);
+                me.children_terminated_amnt -= 1;
+            }
+            wait4_done(me);
+
+            if let Some(wstatus) = wstatus {
+                *wstatus = status;
+            }
+            return Ok(child);
+        }
+
+        if children == 0 {
+            wait4_done(me);
             return Err(ECHILD);
         }
-    }
+        if options & WNOHANG != 0 {
+            wait4_done(me);
+            return Ok(0);
+        }
 
-    // TODO: implement full wait4 logic
-    Ok(0) // simplified
+        me.spinlock_queue_entry = &me.lock_child_term as *const _ as *mut c_void;
+        hand_control();
+        if !me.spinlock_queue_entry.is_null() {
+            // woken before we got off the CPU, the lock is still ours
+            me.spinlock_queue_entry = ptr::null_mut();
+            spinlock_release(&me.lock_child_term);
+        }
+        asm!("sti");
+
+        if signals_pending_quick(me) {
+            return Err(EINTR);
+        }
+    }
 }
 
 // ==========================
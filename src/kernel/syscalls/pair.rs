@@ -1,15 +1,555 @@
+use core::cmp::min;
+use core::ptr::null_mut;
+
 use crate::*;
 use super::types::*;
 
-pub unsafe fn unix_socket_allocate_pair() -> *mut UnixSocketPair {
+const CMSG_HDR_LEN: usize = core::mem::size_of::<cmsghdr_linux>();
+
+const fn cmsg_align(len: usize) -> usize {
+    (len + 7) & !7
+}
+
+/* credentials, everything runs as root so only the pid says anything */
+
+pub unsafe fn unix_current_cred() -> UnixCred {
+    UnixCred {
+        pid: (*currentTask).tgid,
+        uid: 0,
+        gid: 0,
+    }
+}
+
+/* sockaddr_un <-> UnixAddr */
+
+pub unsafe fn unix_addr_read(addr: *const sockaddr_un_linux, len: usize) -> Result<UnixAddr, usize> {
+    if addr.is_null() || len < 2 || (*addr).sun_family != AF_UNIX as u16 {
+        return Err(ERR(EINVAL));
+    }
+
+    let mut out = UnixAddr::unnamed();
+    let path_len = min(len - 2, UNIX_PATH_MAX);
+    core::ptr::copy_nonoverlapping((*addr).sun_path.as_ptr(), out.path.as_mut_ptr(), path_len);
+
+    // pathnames stop at the first NUL, abstract names take the whole length
+    out.len = if path_len > 0 && out.path[0] != 0 {
+        out.path[..path_len].iter().position(|c| *c == 0).unwrap_or(path_len)
+    } else {
+        path_len
+    };
+    Ok(out)
+}
+
+pub unsafe fn unix_addr_write(addr: *mut sockaddr_un_linux, len: *mut u32, src: &UnixAddr) {
+    if addr.is_null() || len.is_null() {
+        return;
+    }
+
+    // pathnames get their NUL back, abstract ones are reported as is
+    let full = 2 + src.len + if src.is_unnamed() || src.is_abstract() { 0 } else { 1 };
+    let mut out = sockaddr_un_linux {
+        sun_family: AF_UNIX as u16,
+        sun_path: [0; UNIX_PATH_MAX],
+    };
+    out.sun_path[..src.len].copy_from_slice(src.name());
+
+    let copy = min(*len as usize, min(full, core::mem::size_of::<sockaddr_un_linux>()));
+    core::ptr::copy_nonoverlapping(&out as *const _ as *const u8, addr as *mut u8, copy);
+    *len = full as u32;
+}
+
+/* iovecs */
+
+pub unsafe fn unix_iov_len(msg: *const msghdr_linux) -> Result<usize, usize> {
+    let mut total: usize = 0;
+    for i in 0..(*msg).msg_iovlen {
+        let iov = (*msg).msg_iov.add(i);
+        total = total.checked_add((*iov).iov_len).ok_or(ERR(EINVAL))?;
+    }
+    Ok(total)
+}
+
+// copies between the iovecs (starting `skip` bytes in) and a flat buffer
+unsafe fn unix_iov_copy(msg: *const msghdr_linux, mut skip: usize, buff: *mut u8, len: usize, to_iov: bool) {
+    let mut done = 0;
+    for i in 0..(*msg).msg_iovlen {
+        if done == len {
+            break;
+        }
+
+        let iov = (*msg).msg_iov.add(i);
+        if skip >= (*iov).iov_len {
+            skip -= (*iov).iov_len;
+            continue;
+        }
+
+        let base = ((*iov).iov_base as *mut u8).add(skip);
+        let chunk = min((*iov).iov_len - skip, len - done);
+        if to_iov {
+            core::ptr::copy_nonoverlapping(buff.add(done), base, chunk);
+        } else {
+            core::ptr::copy_nonoverlapping(base, buff.add(done), chunk);
+        }
+
+        done += chunk;
+        skip = 0;
+    }
+}
+
+/* messages */
+
+pub unsafe fn unix_message_free(message: *mut UnixMessage) {
+    for i in 0..(*message).fd_count {
+        fsKernelClose(*(*message).fds.add(i));
+    }
+
+    free((*message).fds as _);
+    free((*message).data as _);
+    free(message as _);
+}
+
+unsafe fn unix_message_alloc(from: &UnixAddr) -> *mut UnixMessage {
+    let message = calloc(core::mem::size_of::<UnixMessage>(), 1) as *mut UnixMessage;
+    if message.is_null() {
+        return null_mut();
+    }
+    (*message).cred = unix_current_cred();
+    (*message).from = *from;
+    message
+}
+
+// whether `file` is a socket whose receive side sits behind `lock`. In-flight
+// fds aren't garbage collected, so one of those queued behind that same lock
+// would keep its own receiver open forever once userspace closes it
+unsafe fn unix_file_behind(file: *mut OpenFile, lock: *mut Spinlock) -> bool {
+    if core::ptr::eq((*file).handlers, &unixAcceptHandlers) {
+        let pair = (*file).dir as *mut UnixSocketPair;
+        return core::ptr::eq(&(*pair).LOCK_PAIR, lock);
+    }
+    if core::ptr::eq((*file).handlers, &unixSocketHandlers) {
+        let sock = (*file).dir as *mut UnixSocket;
+        let pair = (*sock).pair;
+        return core::ptr::eq(&(*sock).LOCK_SOCK, lock) || (!pair.is_null() && core::ptr::eq(&(*pair).LOCK_PAIR, lock));
+    }
+    false
+}
+
+// SCM_RIGHTS & SCM_CREDENTIALS off a sendmsg(), parsed before anything is
+// queued so a bad fd doesn't leave half a message behind
+unsafe fn unix_message_control(msg: *const msghdr_linux, message: *mut UnixMessage, lock: *mut Spinlock) -> usize {
+    let control = (*msg).msg_control as *const u8;
+    let controllen = (*msg).msg_controllen;
+    if control.is_null() || controllen == 0 {
+        return 0;
+    }
+
+    let mut offset = 0;
+    while offset + CMSG_HDR_LEN <= controllen {
+        let hdr = control.add(offset) as *const cmsghdr_linux;
+        let cmsg_len = (*hdr).cmsg_len;
+        if cmsg_len < CMSG_HDR_LEN || offset + cmsg_len > controllen {
+            return ERR(EINVAL);
+        }
+
+        let data = control.add(offset + CMSG_HDR_LEN);
+        let data_len = cmsg_len - CMSG_HDR_LEN;
+        if (*hdr).cmsg_level != SOL_SOCKET {
+            return ERR(EINVAL);
+        }
+
+        match (*hdr).cmsg_type {
+            SCM_RIGHTS => {
+                let count = data_len / 4;
+                if (*message).fd_count + count > UNIX_SCM_MAX_FD {
+                    return ERR(EINVAL);
+                }
+
+                let fds = realloc(
+                    (*message).fds as _,
+                    ((*message).fd_count + count) * core::mem::size_of::<*mut OpenFile>(),
+                ) as *mut *mut OpenFile;
+                // the old array (and whatever it holds) stays the caller's
+                if fds.is_null() {
+                    return ERR(ENOMEM);
+                }
+                (*message).fds = fds;
+
+                for i in 0..count {
+                    let fd = core::ptr::read_unaligned((data as *const i32).add(i));
+                    let node = fsUserGetNode(currentTask, fd);
+                    if node.is_null() {
+                        return ERR(EBADF);
+                    }
+                    if unix_file_behind(node, lock) {
+                        return ERR(ETOOMANYREFS);
+                    }
+                    *fds.add((*message).fd_count) = fsUserDuplicateNodeUnsafe(node);
+                    (*message).fd_count += 1;
+                }
+            }
+            SCM_CREDENTIALS => {
+                if data_len < core::mem::size_of::<UnixCred>() {
+                    return ERR(EINVAL);
+                }
+                // root may claim anything, which is all we have
+                (*message).cred = core::ptr::read_unaligned(data as *const UnixCred);
+            }
+            _ => return ERR(EINVAL),
+        }
+
+        offset += cmsg_align(cmsg_len);
+    }
+
+    0
+}
+
+// ancillary data for recvmsg(): credentials if asked for, then the fds which
+// get installed into the receiver here
+unsafe fn unix_control_build(
+    msg: *mut msghdr_linux,
+    cred: Option<UnixCred>,
+    fds: *mut *mut OpenFile,
+    fd_count: usize,
+    flags: i32,
+) {
+    let control = (*msg).msg_control as *mut u8;
+    let cap = if control.is_null() { 0 } else { (*msg).msg_controllen };
+    let mut used = 0;
+
+    if let Some(cred) = cred {
+        let len = CMSG_HDR_LEN + core::mem::size_of::<UnixCred>();
+        if used + len <= cap {
+            let hdr = control.add(used) as *mut cmsghdr_linux;
+            (*hdr).cmsg_len = len;
+            (*hdr).cmsg_level = SOL_SOCKET;
+            (*hdr).cmsg_type = SCM_CREDENTIALS;
+            core::ptr::write_unaligned(control.add(used + CMSG_HDR_LEN) as *mut UnixCred, cred);
+            used += cmsg_align(len);
+        } else {
+            (*msg).msg_flags |= MSG_CTRUNC as u32;
+        }
+    }
+
+    if fd_count > 0 {
+        let room = cap.saturating_sub(used).saturating_sub(CMSG_HDR_LEN) / 4;
+        let data = control.wrapping_add(used + CMSG_HDR_LEN) as *mut i32;
+        let mut installed = 0;
+
+        for i in 0..fd_count {
+            let file = *fds.add(i);
+            if installed < room {
+                let fd = fsUserDuplicateNode(currentTask, file, -1);
+                if !RET_IS_ERR(fd) {
+                    if flags & MSG_CMSG_CLOEXEC != 0 {
+                        (*fsUserGetNode(currentTask, fd as i32)).close_on_exec = true;
+                    }
+                    core::ptr::write_unaligned(data.add(installed), fd as i32);
+                    installed += 1;
+                }
+            }
+            fsKernelClose(file);
+        }
+
+        if installed < fd_count {
+            (*msg).msg_flags |= MSG_CTRUNC as u32;
+        }
+        if installed > 0 {
+            let hdr = control.add(used) as *mut cmsghdr_linux;
+            (*hdr).cmsg_len = CMSG_HDR_LEN + installed * 4;
+            (*hdr).cmsg_level = SOL_SOCKET;
+            (*hdr).cmsg_type = SCM_RIGHTS;
+            used += cmsg_align((*hdr).cmsg_len);
+        }
+    }
+
+    free(fds as _);
+    (*msg).msg_controllen = min(used, cap);
+}
+
+/* queues */
+
+pub unsafe fn unix_queue_push(queue: *mut UnixQueue, message: *mut UnixMessage) {
+    (*message).next = null_mut();
+    if (*queue).tail.is_null() {
+        (*queue).head = message;
+    } else {
+        (*(*queue).tail).next = message;
+    }
+    (*queue).tail = message;
+    (*queue).bytes += (*message).len;
+}
+
+unsafe fn unix_queue_pop(queue: *mut UnixQueue) -> *mut UnixMessage {
+    let message = (*queue).head;
+    if !message.is_null() {
+        (*queue).head = (*message).next;
+        if (*queue).head.is_null() {
+            (*queue).tail = null_mut();
+        }
+        (*queue).bytes -= (*message).len - (*message).offset;
+    }
+    message
+}
+
+pub unsafe fn unix_queue_flush(queue: *mut UnixQueue) {
+    loop {
+        let message = unix_queue_pop(queue);
+        if message.is_null() {
+            break;
+        }
+        unix_message_free(message);
+    }
+}
+
+pub unsafe fn unix_queue_room(queue: *const UnixQueue) -> usize {
+    (*queue).size.saturating_sub((*queue).bytes)
+}
+
+/// sendmsg() into `queue` (guarded by `lock`). Streams get split up to
+/// whatever room there is, datagrams & seqpackets go in whole or not at all.
+/// Ancillary data rides along with the first chunk.
+pub unsafe fn unix_queue_send(
+    lock: *mut Spinlock,
+    queue: *mut UnixQueue,
+    stream: bool,
+    msg: *const msghdr_linux,
+    flags: i32,
+    nonblock: bool,
+    from: &UnixAddr,
+) -> usize {
+    let total = match unix_iov_len(msg) {
+        Ok(total) => total,
+        Err(e) => return e,
+    };
+    if !stream && total > (*queue).size {
+        return ERR(EMSGSIZE);
+    }
+
+    let mut first = unix_message_alloc(from);
+    if first.is_null() {
+        return ERR(ENOMEM);
+    }
+    let ret = unix_message_control(msg, first, lock);
+    if RET_IS_ERR(ret) {
+        unix_message_free(first);
+        return ret;
+    }
+
+    let mut sent = 0;
+    loop {
+        spinlockAcquire(lock);
+
+        if (*queue).rd_shut || (*queue).wr_shut {
+            spinlockRelease(lock);
+            if !first.is_null() {
+                unix_message_free(first);
+            }
+            if sent > 0 {
+                return sent;
+            }
+            if flags & MSG_NOSIGNAL == 0 {
+                taskSignalSend(currentTask, SIGPIPE as usize);
+            }
+            return ERR(EPIPE);
+        }
+
+        let room = unix_queue_room(queue);
+        let chunk = if stream { min(room, total - sent) } else { total };
+        let fits = if stream { chunk > 0 || total == 0 } else { room >= total };
+
+        if fits {
+            let message = if first.is_null() { unix_message_alloc(from) } else { first };
+            first = null_mut();
+            if message.is_null() {
+                spinlockRelease(lock);
+                return if sent > 0 { sent } else { ERR(ENOMEM) };
+            }
+
+            (*message).data = malloc(chunk) as *mut u8;
+            (*message).len = chunk;
+            unix_iov_copy(msg, sent, (*message).data, chunk, false);
+            unix_queue_push(queue, message);
+
+            sent += chunk;
+            if sent == total {
+                spinlockRelease(lock);
+                return sent;
+            }
+        }
+
+        spinlockRelease(lock);
+
+        if nonblock || signalsPendingQuick(currentTask) {
+            if !first.is_null() {
+                unix_message_free(first);
+            }
+            if sent > 0 {
+                return sent;
+            }
+            return if nonblock { ERR(EWOULDBLOCK) } else { ERR(EINTR) };
+        }
+        handControl();
+    }
+}
+
+/// recvmsg() off `queue` (guarded by `lock`). Streams read across message
+/// boundaries but never past one carrying fds, or different credentials
+/// when those are being passed; everything else is one message per call.
+pub unsafe fn unix_queue_recv(
+    lock: *mut Spinlock,
+    queue: *mut UnixQueue,
+    stream: bool,
+    msg: *mut msghdr_linux,
+    flags: i32,
+    nonblock: bool,
+    passcred: bool,
+) -> usize {
+    let want = match unix_iov_len(msg) {
+        Ok(want) => want,
+        Err(e) => return e,
+    };
+    let peek = flags & MSG_PEEK != 0;
+    (*msg).msg_flags = 0;
+
+    loop {
+        spinlockAcquire(lock);
+        if !(*queue).head.is_null() {
+            break;
+        }
+        if (*queue).wr_shut || (*queue).rd_shut {
+            spinlockRelease(lock);
+            (*msg).msg_controllen = 0;
+            return 0;
+        }
+        spinlockRelease(lock);
+
+        if nonblock {
+            return ERR(EWOULDBLOCK);
+        }
+        if signalsPendingQuick(currentTask) {
+            return ERR(EINTR);
+        }
+        handControl();
+    }
+
+    // holding the lock, with at least one message queued
+    let head = (*queue).head;
+    let cred = (*head).cred;
+    let from = (*head).from;
+
+    let mut copied = 0;
+    let mut reported = (*head).len - (*head).offset;
+    if stream {
+        let mut cursor = head;
+        let mut offset = (*head).offset;
+        while !cursor.is_null() && copied < want {
+            if cursor != head && ((*cursor).fd_count > 0 || (passcred && (*cursor).cred.pid != cred.pid)) {
+                break;
+            }
+
+            let chunk = min((*cursor).len - offset, want - copied);
+            unix_iov_copy(msg, copied, (*cursor).data.add(offset), chunk, true);
+            copied += chunk;
+
+            cursor = (*cursor).next;
+            if !cursor.is_null() {
+                offset = (*cursor).offset;
+            }
+        }
+        reported = copied;
+    } else {
+        copied = min((*head).len, want);
+        unix_iov_copy(msg, 0, (*head).data, copied, true);
+        if (*head).len > want {
+            (*msg).msg_flags |= MSG_TRUNC as u32;
+        }
+    }
+
+    // the fds only ever leave with a real read
+    let mut fds: *mut *mut OpenFile = null_mut();
+    let mut fd_count = 0;
+    if !peek {
+        fds = (*head).fds;
+        fd_count = (*head).fd_count;
+        (*head).fds = null_mut();
+        (*head).fd_count = 0;
+
+        if stream {
+            // zero-length messages (only there for their fds) go too
+            let mut left = copied;
+            loop {
+                let current = (*queue).head;
+                if current.is_null() {
+                    break;
+                }
+
+                let chunk = min((*current).len - (*current).offset, left);
+                (*current).offset += chunk;
+                (*queue).bytes -= chunk;
+                left -= chunk;
+
+                if (*current).offset != (*current).len {
+                    break;
+                }
+                unix_message_free(unix_queue_pop(queue));
+                if left == 0 {
+                    break;
+                }
+            }
+        } else {
+            unix_message_free(unix_queue_pop(queue));
+        }
+    }
+
+    spinlockRelease(lock);
+
+    if !(*msg).msg_name.is_null() {
+        let mut namelen = (*msg).msg_namelen as u32;
+        unix_addr_write((*msg).msg_name as *mut sockaddr_un_linux, &mut namelen, &from);
+        (*msg).msg_namelen = namelen as i32;
+    }
+    unix_control_build(msg, if passcred { Some(cred) } else { None }, fds, fd_count, flags);
+
+    if !stream && flags & MSG_TRUNC != 0 {
+        reported
+    } else {
+        copied
+    }
+}
+
+pub unsafe fn unix_queue_poll(rx: *const UnixQueue, tx: *const UnixQueue, stream: bool, events: i32) -> i32 {
+    let mut revents = 0;
+    if events & EPOLLIN != 0 && (!(*rx).head.is_null() || (*rx).wr_shut) {
+        revents |= EPOLLIN;
+    }
+    let writable = if stream {
+        unix_queue_room(tx) > UNIX_SOCK_POLL_EXTRA
+    } else {
+        unix_queue_room(tx) > 0
+    };
+    if events & EPOLLOUT != 0 && (writable || (*tx).rd_shut) {
+        revents |= EPOLLOUT;
+    }
+    if (*rx).wr_shut {
+        revents |= EPOLLRDHUP & events;
+    }
+    if (*rx).wr_shut && (*tx).rd_shut {
+        revents |= EPOLLHUP;
+    }
+    revents
+}
+
+/* pairs */
+
+pub unsafe fn unix_socket_allocate_pair(ty: i32) -> *mut UnixSocketPair {
     let pair = calloc(core::mem::size_of::<UnixSocketPair>(), 1)
         as *mut UnixSocketPair;
 
-    (*pair).client_buff_size = UNIX_SOCK_BUFF_DEFAULT;
-    (*pair).server_buff_size = UNIX_SOCK_BUFF_DEFAULT;
-
-    CircularAllocate(&mut (*pair).client_buff, (*pair).client_buff_size);
-    CircularAllocate(&mut (*pair).server_buff, (*pair).server_buff_size);
+    (*pair).ty = ty;
+    (*pair).client_queue = UnixQueue::new(UNIX_SOCK_BUFF_DEFAULT);
+    (*pair).server_queue = UnixQueue::new(UNIX_SOCK_BUFF_DEFAULT);
+    (*pair).client_addr = UnixAddr::unnamed();
+    (*pair).server_addr = UnixAddr::unnamed();
 
     pair
 }
@@ -17,12 +557,54 @@ pub unsafe fn unix_socket_allocate_pair() -> *mut UnixSocketPair {
 pub unsafe fn unix_socket_free_pair(pair: *mut UnixSocketPair) {
     assert!((*pair).client_fds == 0 && (*pair).server_fds == 0);
 
-    CircularFree(&mut (*pair).client_buff);
-    CircularFree(&mut (*pair).server_buff);
-    free((*pair).filename as _);
+    unix_queue_flush(&mut (*pair).client_queue);
+    unix_queue_flush(&mut (*pair).server_queue);
     free(pair as _);
 }
 
+// one side of a pair
+pub unsafe fn unix_pair_queues(pair: *mut UnixSocketPair, server: bool) -> (*mut UnixQueue, *mut UnixQueue) {
+    if server {
+        (&mut (*pair).server_queue, &mut (*pair).client_queue)
+    } else {
+        (&mut (*pair).client_queue, &mut (*pair).server_queue)
+    }
+}
+
+pub unsafe fn unix_pair_shutdown(pair: *mut UnixSocketPair, server: bool, how: i32) -> usize {
+    if how != SHUT_RD && how != SHUT_WR && how != SHUT_RDWR {
+        return ERR(EINVAL);
+    }
+
+    let (rx, tx) = unix_pair_queues(pair, server);
+    spinlockAcquire(&mut (*pair).LOCK_PAIR);
+    if how != SHUT_WR {
+        (*rx).rd_shut = true;
+    }
+    if how != SHUT_RD {
+        (*tx).wr_shut = true;
+    }
+    spinlockRelease(&mut (*pair).LOCK_PAIR);
+    0
+}
+
+// the last fd of one side is gone: EOF & EPIPE for the other, and the pair
+// goes away once neither side (nor a listener's backlog) holds it anymore
+pub unsafe fn unix_pair_side_closed(pair: *mut UnixSocketPair, server: bool) {
+    let (rx, tx) = unix_pair_queues(pair, server);
+
+    spinlockAcquire(&mut (*pair).LOCK_PAIR);
+    (*rx).rd_shut = true;
+    (*tx).wr_shut = true;
+    unix_queue_flush(rx);
+    let dead = (*pair).established && (*pair).client_fds == 0 && (*pair).server_fds == 0;
+    spinlockRelease(&mut (*pair).LOCK_PAIR);
+
+    if dead {
+        unix_socket_free_pair(pair);
+    }
+}
+
 /* accept() fd creation */
 
 pub unsafe fn unix_socket_accept_create(pair: *mut UnixSocketPair) -> *mut OpenFile {
@@ -34,3 +616,242 @@ pub unsafe fn unix_socket_accept_create(pair: *mut UnixSocketPair) -> *mut OpenF
     (*node).handlers = &unixAcceptHandlers;
     node
 }
+
+/* accept()ed side handlers */
+
+unsafe fn unix_accept_nonblock(fd: *mut OpenFile, flags: i32) -> bool {
+    (*fd).flags & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptSendmsg(fd: *mut OpenFile, msg: *const msghdr_linux, flags: i32) -> usize {
+    let pair = (*fd).dir as *mut UnixSocketPair;
+    let (_, tx) = unix_pair_queues(pair, true);
+    let from = (*pair).server_addr;
+    unix_queue_send(
+        &mut (*pair).LOCK_PAIR,
+        tx,
+        (*pair).ty == SOCK_STREAM,
+        msg,
+        flags,
+        unix_accept_nonblock(fd, flags),
+        &from,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptRecvmsg(fd: *mut OpenFile, msg: *mut msghdr_linux, flags: i32) -> usize {
+    let pair = (*fd).dir as *mut UnixSocketPair;
+    let (rx, _) = unix_pair_queues(pair, true);
+    unix_queue_recv(
+        &mut (*pair).LOCK_PAIR,
+        rx,
+        (*pair).ty == SOCK_STREAM,
+        msg,
+        flags,
+        unix_accept_nonblock(fd, flags),
+        (*pair).server_passcred,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptSendto(
+    fd: *mut OpenFile,
+    buff: *mut u8,
+    len: usize,
+    flags: i32,
+    _addr: *mut sockaddr_linux,
+    _addrlen: u32,
+) -> usize {
+    let mut iov = iovec { iov_base: buff as _, iov_len: len };
+    let msg = unix_msghdr_flat(&mut iov, null_mut(), 0);
+    unixSocketAcceptSendmsg(fd, &msg, flags)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptRecvfrom(
+    fd: *mut OpenFile,
+    buff: *mut u8,
+    len: usize,
+    flags: i32,
+    addr: *mut sockaddr_linux,
+    addrlen: *mut u32,
+) -> usize {
+    let mut iov = iovec { iov_base: buff as _, iov_len: len };
+    let mut msg = unix_msghdr_flat(&mut iov, addr as _, if addrlen.is_null() { 0 } else { *addrlen });
+    let ret = unixSocketAcceptRecvmsg(fd, &mut msg, flags);
+    if !RET_IS_ERR(ret) && !addrlen.is_null() {
+        *addrlen = msg.msg_namelen as u32;
+    }
+    ret
+}
+
+// sendto()/recvfrom() are sendmsg()/recvmsg() with one iovec & no control
+pub fn unix_msghdr_flat(iov: *mut iovec, name: *mut core::ffi::c_void, namelen: u32) -> msghdr_linux {
+    msghdr_linux {
+        msg_name: name,
+        msg_namelen: namelen as i32,
+        msg_iov: iov,
+        msg_iovlen: 1,
+        msg_control: null_mut(),
+        msg_controllen: 0,
+        msg_flags: 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptGetpeername(fd: *mut OpenFile, addr: *mut sockaddr_linux, len: *mut u32) -> usize {
+    let pair = (*fd).dir as *mut UnixSocketPair;
+    unix_addr_write(addr as _, len, &(*pair).client_addr);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptGetsockname(fd: *mut OpenFile, addr: *mut sockaddr_linux, len: *mut u32) -> usize {
+    let pair = (*fd).dir as *mut UnixSocketPair;
+    unix_addr_write(addr as _, len, &(*pair).server_addr);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptShutdown(fd: *mut OpenFile, how: i32) -> usize {
+    unix_pair_shutdown((*fd).dir as *mut UnixSocketPair, true, how)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptGetsockopts(
+    fd: *mut OpenFile,
+    level: i32,
+    optname: i32,
+    optval: *mut u8,
+    socklen: *mut u32,
+) -> usize {
+    let pair = (*fd).dir as *mut UnixSocketPair;
+    unix_sockopt_get(
+        (*pair).ty,
+        (*pair).server_passcred,
+        Some((*pair).client_cred),
+        (*pair).server_queue.size,
+        level,
+        optname,
+        optval,
+        socklen,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptSetsockopts(
+    fd: *mut OpenFile,
+    level: i32,
+    optname: i32,
+    optval: *const u8,
+    socklen: u32,
+) -> usize {
+    let pair = (*fd).dir as *mut UnixSocketPair;
+    let mut passcred = (*pair).server_passcred;
+    let ret = unix_sockopt_set(&mut passcred, level, optname, optval, socklen);
+    (*pair).server_passcred = passcred;
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptDuplicate(original: *mut OpenFile, _orphan: *mut OpenFile) -> bool {
+    let pair = (*original).dir as *mut UnixSocketPair;
+    spinlockAcquire(&mut (*pair).LOCK_PAIR);
+    (*pair).server_fds += 1;
+    spinlockRelease(&mut (*pair).LOCK_PAIR);
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptClose(fd: *mut OpenFile) -> bool {
+    let pair = (*fd).dir as *mut UnixSocketPair;
+    spinlockAcquire(&mut (*pair).LOCK_PAIR);
+    (*pair).server_fds -= 1;
+    let last = (*pair).server_fds == 0;
+    spinlockRelease(&mut (*pair).LOCK_PAIR);
+
+    if last {
+        unix_pair_side_closed(pair, true);
+    }
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptReportKey(fd: *mut OpenFile) -> usize {
+    (*fd).dir as usize
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAcceptInternalPoll(fd: *mut OpenFile, events: i32) -> i32 {
+    let pair = (*fd).dir as *mut UnixSocketPair;
+    let (rx, tx) = unix_pair_queues(pair, true);
+    spinlockAcquire(&mut (*pair).LOCK_PAIR);
+    let revents = unix_queue_poll(rx, tx, (*pair).ty == SOCK_STREAM, events);
+    spinlockRelease(&mut (*pair).LOCK_PAIR);
+    revents
+}
+
+/* socket options, shared by both sides */
+
+pub unsafe fn unix_sockopt_get(
+    ty: i32,
+    passcred: bool,
+    peer: Option<UnixCred>,
+    buff_size: usize,
+    level: i32,
+    optname: i32,
+    optval: *mut u8,
+    socklen: *mut u32,
+) -> usize {
+    if level != SOL_SOCKET {
+        return ERR(ENOPROTOOPT);
+    }
+    if optval.is_null() || socklen.is_null() {
+        return ERR(EFAULT);
+    }
+
+    let int = |value: i32| {
+        let len = min(*socklen as usize, 4);
+        core::ptr::copy_nonoverlapping(&value as *const i32 as *const u8, optval, len);
+        *socklen = len as u32;
+        0
+    };
+
+    match optname {
+        SO_TYPE => int(ty),
+        SO_DOMAIN => int(AF_UNIX),
+        SO_ERROR => int(0),
+        SO_PASSCRED => int(passcred as i32),
+        SO_SNDBUF | SO_RCVBUF => int(buff_size as i32),
+        SO_PEERCRED => {
+            // unconnected sockets report the "nobody" credentials
+            let cred = peer.unwrap_or(UnixCred { pid: 0, uid: u32::MAX, gid: u32::MAX });
+            let len = min(*socklen as usize, core::mem::size_of::<UnixCred>());
+            core::ptr::copy_nonoverlapping(&cred as *const UnixCred as *const u8, optval, len);
+            *socklen = len as u32;
+            0
+        }
+        _ => ERR(ENOPROTOOPT),
+    }
+}
+
+pub unsafe fn unix_sockopt_set(passcred: &mut bool, level: i32, optname: i32, optval: *const u8, socklen: u32) -> usize {
+    if level != SOL_SOCKET {
+        return ERR(ENOPROTOOPT);
+    }
+    if optval.is_null() || socklen < 4 {
+        return ERR(EINVAL);
+    }
+
+    let value = core::ptr::read_unaligned(optval as *const i32);
+    match optname {
+        SO_PASSCRED => {
+            *passcred = value != 0;
+            0
+        }
+        // fixed size buffers, accepted & ignored
+        SO_SNDBUF | SO_RCVBUF => 0,
+        _ => ERR(ENOPROTOOPT),
+    }
+}
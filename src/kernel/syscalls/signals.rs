@@ -213,3 +213,154 @@ pub fn signals_pending_quick(task: &Task) -> bool {
     }
     false
 }
+
+extern "C" {
+    fn taskStop(task: *mut Task, signal: usize);
+    fn taskContinue(task: *mut Task);
+    fn pgrpIsOrphaned(pgid: i32) -> bool;
+    fn coredumpWrite(task: *mut Task, regs: *const AsmPassedInterrupt, signal: usize) -> bool;
+}
+
+
+const TASK_STATE_STOPPED: i32 = 11;
+
+/// Carry out the default action of a signal without a user handler.
+/// Returns the wait status to terminate the task with, if it has to die.
+/// `regs` is where it got interrupted, for the core dump.
+pub unsafe fn signals_default_action(
+    task: &mut Task,
+    regs: &AsmPassedInterrupt,
+    signal: usize,
+) -> Option<u16> {
+    match SIGNAL_INTERNAL_DECISIONS[signal] {
+        SignalInternal::Ign => None,
+        SignalInternal::Cont => {
+            // normally already continued when it was sent
+            taskContinue(task);
+            None
+        }
+        SignalInternal::Stop => {
+            // terminal generated stops are discarded for orphaned groups
+            if signal != SIGSTOP as usize && pgrpIsOrphaned(task.pgid as i32) {
+                return None;
+            }
+            // whoever delivers it gets off the CPU (or never puts it on)
+            taskStop(task, signal);
+            None
+        }
+        SignalInternal::Term => Some(signal as u16),
+        SignalInternal::Core => {
+            // WCOREDUMP is only reported when a dump was actually written
+            if coredumpWrite(task, regs, signal) {
+                Some(signal as u16 | 0x80)
+            } else {
+                Some(signal as u16)
+            }
+        }
+    }
+}
+
+/// Deliver what's pending for a task about to return to userspace, `regs`
+/// being the frame it returns with. Signals left at SIG_DFL get their default
+/// action here, ignored ones are dropped. Ones with a user handler stay
+/// pending: there are no user signal frames yet to run the handler on, so
+/// until there are, the task keeps seeing them (a blocking wait returns
+/// EINTR) rather than have them silently disappear.
+/// Core dumps need the task's own context (its live FPU state, interrupts
+/// on for the file writes), so without `in_context` signals dumping by
+/// default are left pending for the task's next kernel entry.
+/// Returns false if the task got stopped or killed and mustn't run.
+pub unsafe fn signals_pending_handle(
+    task: &mut Task,
+    regs: &mut AsmPassedInterrupt,
+    in_context: bool,
+) -> bool {
+    if task.kernel_task {
+        return true;
+    }
+
+    for i in 1..NSIG {
+        let pending = task.sig_pending_list.load(Ordering::SeqCst) & !task.sig_block_list;
+        // SIGKILL and SIGSTOP can't be blocked
+        let forced = task.sig_pending_list.load(Ordering::SeqCst)
+            & ((1 << SIGKILL) | (1 << SIGSTOP));
+        if ((pending | forced) & (1 << i)) == 0 {
+            continue;
+        }
+
+        let action = &task.info_signals.signals[i];
+        let user_handler = core::mem::transmute::<usize, SignalHandler>(action.sa_handler.load(Ordering::SeqCst));
+        let uncatchable = i == SIGKILL as usize || i == SIGSTOP as usize;
+        if !in_context
+            && user_handler == SignalHandler::Default
+            && matches!(SIGNAL_INTERNAL_DECISIONS[i], SignalInternal::Core)
+        {
+            continue;
+        }
+        if !uncatchable && user_handler != SignalHandler::Default && user_handler != SignalHandler::Ignore {
+            continue;
+        }
+        task.sig_pending_list.fetch_and(!(1 << i), Ordering::SeqCst);
+        if !uncatchable && user_handler == SignalHandler::Ignore {
+            continue;
+        }
+
+        if let Some(status) = signals_default_action(task, regs, i) {
+            task_kill(task.id, status);
+            return false;
+        }
+        if task.state as i32 == TASK_STATE_STOPPED {
+            return false;
+        }
+    }
+    true
+}
+
+/// Syscall return path: the task is the one running, so a stop takes it off
+/// the CPU right away
+#[no_mangle]
+pub unsafe extern "C" fn signalsPendingHandleSys(
+    task: *mut Task,
+    _rsp: *mut u64,
+    registers: *mut AsmPassedInterrupt,
+) {
+    while !signals_pending_handle(&mut *task, &mut *registers, true) {
+        // resumes here after SIGCONT (or dies on SIGKILL)
+        hand_control();
+    }
+}
+
+/// Scheduler path: `task` is about to be switched to with its saved frame,
+/// false tells the scheduler to pick someone else
+#[no_mangle]
+pub unsafe extern "C" fn signalsPendingHandleSched(task: *mut Task) -> bool {
+    signals_pending_handle(&mut *task, &mut (*task).registers, false)
+}
+
+/// Interrupt entry from userspace, `registers` being the interrupted frame.
+/// Picks up what the scheduler left pending, before anything can switch away
+#[no_mangle]
+pub unsafe extern "C" fn signalsPendingHandleIrq(registers: *mut AsmPassedInterrupt) {
+    let task = current_task();
+    if task.kernel_task || !signals_pending_quick(task) {
+        return;
+    }
+
+    // same as a syscall return, the dump has files to write
+    core::arch::asm!("sti");
+    signalsPendingHandleSys(task, core::ptr::null_mut(), registers);
+    core::arch::asm!("cli");
+}
+
+/// A CPU exception raised in userspace. There's no way to run a user
+/// handler yet (no signal frames), and returning would just fault again, so
+/// the default action is forced whatever the disposition: it gets the
+/// faulting frame for the core dump
+#[no_mangle]
+pub unsafe extern "C" fn signalsFault(registers: *mut AsmPassedInterrupt, signal: usize) {
+    let task = current_task();
+    // the dump has files to write, and we never return here
+    core::arch::asm!("sti");
+    let status = signals_default_action(task, &*registers, signal).unwrap_or(signal as u16);
+    task_kill(task.id, status);
+}
@@ -1,25 +1,654 @@
+use core::ptr::null_mut;
+
 use crate::*;
 use super::types::*;
 use super::pair::*;
 
+const UNIX_BACKLOG_MAX: usize = 128;
+
+// autobind() names: "\0" followed by 5 hex digits, like Linux
+static mut UNIX_AUTOBIND_NEXT: u32 = 0;
+
 pub unsafe fn unix_socket_open(task: *mut Task, ty: i32, proto: i32) -> usize {
-    if (ty & 1) == 0 {
-        return ERR(ENOSYS);
+    let kind = ty & SOCK_TYPE_MASK;
+    if kind != SOCK_STREAM && kind != SOCK_DGRAM && kind != SOCK_SEQPACKET {
+        return ERR(ESOCKTNOSUPPORT);
+    }
+    if proto != 0 {
+        return ERR(EPROTONOSUPPORT);
     }
 
     let fd = fsUserOpen(task, b"/dev/null\0".as_ptr(), O_RDWR, 0);
     assert!(!RET_IS_ERR(fd));
 
     let node = fsUserGetNode(task, fd);
+    if ty & SOCK_CLOEXEC != 0 {
+        (*node).close_on_exec = true;
+    }
+    if ty & SOCK_NONBLOCK != 0 {
+        (*node).flags |= O_NONBLOCK;
+    }
 
     spinlockAcquire(&mut LOCK_LL_UNIX_SOCKET);
     let sock = LinkedListAllocate(&mut dsUnixSocket, core::mem::size_of::<UnixSocket>())
         as *mut UnixSocket;
     spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
 
+    (*sock).ty = kind;
     (*sock).times_opened = 1;
+    (*sock).bind_addr = UnixAddr::unnamed();
+    (*sock).cred = UnixCred {
+        pid: (*task).tgid,
+        uid: 0,
+        gid: 0,
+    };
+    (*sock).dgram_queue = UnixQueue::new(UNIX_SOCK_BUFF_DEFAULT);
     (*node).dir = sock as _;
     (*node).handlers = &unixSocketHandlers;
 
     fd
 }
+
+/* lookups & lifetime */
+
+// a live socket bound to `addr`, returned with a reference held
+unsafe fn unix_socket_lookup(addr: &UnixAddr) -> *mut UnixSocket {
+    spinlockAcquire(&mut LOCK_LL_UNIX_SOCKET);
+    let mut browse = dsUnixSocket.firstObject as *mut UnixSocket;
+    while !browse.is_null() {
+        if (*browse).times_opened > 0
+            && !(*browse).bind_addr.is_unnamed()
+            && (*browse).bind_addr.name() == addr.name()
+        {
+            (*browse).refs += 1;
+            break;
+        }
+        browse = (*browse)._ll.next as *mut UnixSocket;
+    }
+    spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
+    browse
+}
+
+// caller holds LOCK_LL_UNIX_SOCKET
+unsafe fn unix_socket_reap(sock: *mut UnixSocket) {
+    if (*sock).times_opened == 0 && (*sock).refs == 0 {
+        LinkedListRemove(&mut dsUnixSocket, core::mem::size_of::<UnixSocket>(), sock as _);
+    }
+}
+
+unsafe fn unix_socket_put(sock: *mut UnixSocket) {
+    spinlockAcquire(&mut LOCK_LL_UNIX_SOCKET);
+    (*sock).refs -= 1;
+    unix_socket_reap(sock);
+    spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
+}
+
+unsafe fn unix_socket_nonblock(fd: *mut OpenFile, flags: i32) -> bool {
+    (*fd).flags & O_NONBLOCK != 0 || flags & MSG_DONTWAIT != 0
+}
+
+unsafe fn unix_socket_connection(sock: *mut UnixSocket) -> bool {
+    (*sock).ty == SOCK_STREAM || (*sock).ty == SOCK_SEQPACKET
+}
+
+/* bind / listen / connect / accept */
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketBind(fd: *mut OpenFile, addr: *mut sockaddr_linux, len: usize) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    let mut name = match unix_addr_read(addr as _, len) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+
+    spinlockAcquire(&mut LOCK_LL_UNIX_SOCKET);
+    if !(*sock).bind_addr.is_unnamed() {
+        spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
+        return ERR(EINVAL);
+    }
+
+    if name.is_unnamed() {
+        // autobind: first free "\0xxxxx"
+        loop {
+            let id = UNIX_AUTOBIND_NEXT & 0xfffff;
+            UNIX_AUTOBIND_NEXT = UNIX_AUTOBIND_NEXT.wrapping_add(1);
+
+            name.path[0] = 0;
+            for i in 0..5 {
+                name.path[5 - i] = b"0123456789abcdef"[((id >> (i * 4)) & 0xf) as usize];
+            }
+            name.len = 6;
+
+            let mut browse = dsUnixSocket.firstObject as *mut UnixSocket;
+            while !browse.is_null() && (*browse).bind_addr.name() != name.name() {
+                browse = (*browse)._ll.next as *mut UnixSocket;
+            }
+            if browse.is_null() {
+                break;
+            }
+        }
+    } else {
+        let mut browse = dsUnixSocket.firstObject as *mut UnixSocket;
+        while !browse.is_null() {
+            if (*browse).times_opened > 0 && (*browse).bind_addr.name() == name.name() {
+                spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
+                return ERR(EADDRINUSE);
+            }
+            browse = (*browse)._ll.next as *mut UnixSocket;
+        }
+    }
+
+    (*sock).bind_addr = name;
+    spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketListen(fd: *mut OpenFile, backlog: i32) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    if !unix_socket_connection(sock) {
+        return ERR(EOPNOTSUPP);
+    }
+    if (*sock).bind_addr.is_unnamed() || !(*sock).pair.is_null() {
+        return ERR(EINVAL);
+    }
+
+    let conn_max = (backlog.max(1) as usize).min(UNIX_BACKLOG_MAX);
+
+    spinlockAcquire(&mut (*sock).LOCK_SOCK);
+    if (*sock).backlog.is_null() {
+        (*sock).backlog = calloc(core::mem::size_of::<*mut UnixSocketPair>(), UNIX_BACKLOG_MAX)
+            as *mut *mut UnixSocketPair;
+    }
+    // shrinking below what's already queued just stops new ones
+    (*sock).conn_max = conn_max;
+    spinlockRelease(&mut (*sock).LOCK_SOCK);
+
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketConnect(fd: *mut OpenFile, addr: *mut sockaddr_linux, len: u32) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+
+    // datagram sockets can be disconnected with AF_UNSPEC
+    if !unix_socket_connection(sock) && !addr.is_null() && len >= 2 && (*addr).sa_family == AF_UNSPEC as u16 {
+        let old = core::mem::replace(&mut (*sock).peer, null_mut());
+        if !old.is_null() {
+            unix_socket_put(old);
+        }
+        return 0;
+    }
+
+    let name = match unix_addr_read(addr as _, len as usize) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+    if name.is_unnamed() {
+        return ERR(EINVAL);
+    }
+
+    let target = unix_socket_lookup(&name);
+    if target.is_null() {
+        return if name.is_abstract() { ERR(ECONNREFUSED) } else { ERR(ENOENT) };
+    }
+    if (*target).ty != (*sock).ty {
+        unix_socket_put(target);
+        return ERR(EPROTOTYPE);
+    }
+
+    if !unix_socket_connection(sock) {
+        // the reference moves into `peer`
+        let old = core::mem::replace(&mut (*sock).peer, target);
+        if !old.is_null() {
+            unix_socket_put(old);
+        }
+        return 0;
+    }
+
+    if !(*sock).pair.is_null() {
+        unix_socket_put(target);
+        return ERR(EISCONN);
+    }
+    if !(*sock).backlog.is_null() {
+        unix_socket_put(target);
+        return ERR(EINVAL);
+    }
+
+    // like Linux, connect() is done once the backlog has us, accept() or not
+    let pair = unix_socket_allocate_pair((*sock).ty);
+    (*pair).client_fds = 1;
+    (*pair).client_cred = unix_current_cred();
+    (*pair).client_addr = (*sock).bind_addr;
+
+    loop {
+        spinlockAcquire(&mut (*target).LOCK_SOCK);
+        if (*target).times_opened == 0 || (*target).backlog.is_null() {
+            spinlockRelease(&mut (*target).LOCK_SOCK);
+            unix_socket_put(target);
+            (*pair).client_fds = 0;
+            unix_socket_free_pair(pair);
+            return ERR(ECONNREFUSED);
+        }
+
+        if (*target).conn_curr < (*target).conn_max {
+            (*pair).server_cred = (*target).cred;
+            (*pair).server_addr = (*target).bind_addr;
+            *(*target).backlog.add((*target).conn_curr) = pair;
+            (*target).conn_curr += 1;
+            spinlockRelease(&mut (*target).LOCK_SOCK);
+            break;
+        }
+        spinlockRelease(&mut (*target).LOCK_SOCK);
+
+        let ret = if (*fd).flags & O_NONBLOCK != 0 {
+            ERR(EAGAIN)
+        } else if signalsPendingQuick(currentTask) {
+            ERR(EINTR)
+        } else {
+            handControl();
+            continue;
+        };
+        unix_socket_put(target);
+        (*pair).client_fds = 0;
+        unix_socket_free_pair(pair);
+        return ret;
+    }
+
+    (*sock).pair = pair;
+    unix_socket_put(target);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketAccept(fd: *mut OpenFile, addr: *mut sockaddr_linux, len: *mut u32) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    if (*sock).backlog.is_null() {
+        return ERR(EINVAL);
+    }
+
+    let pair = loop {
+        spinlockAcquire(&mut (*sock).LOCK_SOCK);
+        if (*sock).conn_curr > 0 {
+            let pair = *(*sock).backlog;
+            (*sock).conn_curr -= 1;
+            core::ptr::copy((*sock).backlog.add(1), (*sock).backlog, (*sock).conn_curr);
+            spinlockRelease(&mut (*sock).LOCK_SOCK);
+            break pair;
+        }
+        spinlockRelease(&mut (*sock).LOCK_SOCK);
+
+        if (*fd).flags & O_NONBLOCK != 0 {
+            return ERR(EWOULDBLOCK);
+        }
+        if signalsPendingQuick(currentTask) {
+            return ERR(EINTR);
+        }
+        handControl();
+    };
+
+    spinlockAcquire(&mut (*pair).LOCK_PAIR);
+    (*pair).server_fds = 1;
+    (*pair).established = true;
+    (*pair).server_passcred = (*sock).passcred;
+    spinlockRelease(&mut (*pair).LOCK_PAIR);
+
+    let node = unix_socket_accept_create(pair);
+    unix_addr_write(addr as _, len, &(*pair).client_addr);
+    (*node).id as usize
+}
+
+/* data */
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketSendmsg(fd: *mut OpenFile, msg: *const msghdr_linux, flags: i32) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    let nonblock = unix_socket_nonblock(fd, flags);
+
+    if unix_socket_connection(sock) {
+        let pair = (*sock).pair;
+        if pair.is_null() {
+            return ERR(ENOTCONN);
+        }
+        let (_, tx) = unix_pair_queues(pair, false);
+        let from = (*pair).client_addr;
+        return unix_queue_send(&mut (*pair).LOCK_PAIR, tx, (*sock).ty == SOCK_STREAM, msg, flags, nonblock, &from);
+    }
+
+    if (*sock).dgram_wr_shut {
+        if flags & MSG_NOSIGNAL == 0 {
+            taskSignalSend(currentTask, SIGPIPE as usize);
+        }
+        return ERR(EPIPE);
+    }
+
+    let target = if !(*msg).msg_name.is_null() {
+        let name = match unix_addr_read((*msg).msg_name as _, (*msg).msg_namelen as usize) {
+            Ok(name) => name,
+            Err(e) => return e,
+        };
+        let target = unix_socket_lookup(&name);
+        if target.is_null() {
+            return if name.is_abstract() { ERR(ECONNREFUSED) } else { ERR(ENOENT) };
+        }
+        target
+    } else {
+        spinlockAcquire(&mut LOCK_LL_UNIX_SOCKET);
+        let target = (*sock).peer;
+        if !target.is_null() {
+            (*target).refs += 1;
+        }
+        spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
+        if target.is_null() {
+            return ERR(ENOTCONN);
+        }
+        target
+    };
+
+    let ret = if (*target).ty != SOCK_DGRAM {
+        ERR(EPROTOTYPE)
+    } else {
+        let from = (*sock).bind_addr;
+        let ret = unix_queue_send(
+            &mut (*target).LOCK_SOCK,
+            &mut (*target).dgram_queue,
+            false,
+            msg,
+            flags | MSG_NOSIGNAL,
+            nonblock,
+            &from,
+        );
+        // the other end went away (or shut reading down)
+        if ret == ERR(EPIPE) { ERR(ECONNREFUSED) } else { ret }
+    };
+
+    unix_socket_put(target);
+    ret
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketRecvmsg(fd: *mut OpenFile, msg: *mut msghdr_linux, flags: i32) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    let nonblock = unix_socket_nonblock(fd, flags);
+
+    if unix_socket_connection(sock) {
+        let pair = (*sock).pair;
+        if pair.is_null() {
+            return ERR(ENOTCONN);
+        }
+        let (rx, _) = unix_pair_queues(pair, false);
+        return unix_queue_recv(&mut (*pair).LOCK_PAIR, rx, (*sock).ty == SOCK_STREAM, msg, flags, nonblock, (*sock).passcred);
+    }
+
+    unix_queue_recv(
+        &mut (*sock).LOCK_SOCK,
+        &mut (*sock).dgram_queue,
+        false,
+        msg,
+        flags,
+        nonblock,
+        (*sock).passcred,
+    )
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketSendto(
+    fd: *mut OpenFile,
+    buff: *mut u8,
+    len: usize,
+    flags: i32,
+    addr: *mut sockaddr_linux,
+    addrlen: u32,
+) -> usize {
+    let mut iov = iovec { iov_base: buff as _, iov_len: len };
+    let msg = unix_msghdr_flat(&mut iov, addr as _, addrlen);
+    unixSocketSendmsg(fd, &msg, flags)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketRecvfrom(
+    fd: *mut OpenFile,
+    buff: *mut u8,
+    len: usize,
+    flags: i32,
+    addr: *mut sockaddr_linux,
+    addrlen: *mut u32,
+) -> usize {
+    let mut iov = iovec { iov_base: buff as _, iov_len: len };
+    let mut msg = unix_msghdr_flat(&mut iov, addr as _, if addrlen.is_null() { 0 } else { *addrlen });
+    let ret = unixSocketRecvmsg(fd, &mut msg, flags);
+    if !RET_IS_ERR(ret) && !addrlen.is_null() {
+        *addrlen = msg.msg_namelen as u32;
+    }
+    ret
+}
+
+/* names, shutdown & options */
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketGetsockname(fd: *mut OpenFile, addr: *mut sockaddr_linux, len: *mut u32) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    unix_addr_write(addr as _, len, &(*sock).bind_addr);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketGetpeername(fd: *mut OpenFile, addr: *mut sockaddr_linux, len: *mut u32) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    if !(*sock).pair.is_null() {
+        unix_addr_write(addr as _, len, &(*(*sock).pair).server_addr);
+    } else if !(*sock).peer.is_null() {
+        unix_addr_write(addr as _, len, &(*(*sock).peer).bind_addr);
+    } else {
+        return ERR(ENOTCONN);
+    }
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketShutdown(fd: *mut OpenFile, how: i32) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    if unix_socket_connection(sock) {
+        if (*sock).pair.is_null() {
+            return ERR(ENOTCONN);
+        }
+        return unix_pair_shutdown((*sock).pair, false, how);
+    }
+
+    if how != SHUT_RD && how != SHUT_WR && how != SHUT_RDWR {
+        return ERR(EINVAL);
+    }
+    spinlockAcquire(&mut (*sock).LOCK_SOCK);
+    if how != SHUT_WR {
+        (*sock).dgram_queue.rd_shut = true;
+    }
+    if how != SHUT_RD {
+        (*sock).dgram_wr_shut = true;
+    }
+    spinlockRelease(&mut (*sock).LOCK_SOCK);
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketGetsockopts(
+    fd: *mut OpenFile,
+    level: i32,
+    optname: i32,
+    optval: *mut u8,
+    socklen: *mut u32,
+) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    let peer = if !(*sock).pair.is_null() {
+        Some((*(*sock).pair).server_cred)
+    } else if !(*sock).peer.is_null() {
+        Some((*(*sock).peer).cred)
+    } else {
+        None
+    };
+    let buff_size = if (*sock).pair.is_null() {
+        (*sock).dgram_queue.size
+    } else {
+        (*(*sock).pair).client_queue.size
+    };
+    unix_sockopt_get((*sock).ty, (*sock).passcred, peer, buff_size, level, optname, optval, socklen)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketSetsockopts(
+    fd: *mut OpenFile,
+    level: i32,
+    optname: i32,
+    optval: *const u8,
+    socklen: u32,
+) -> usize {
+    let sock = (*fd).dir as *mut UnixSocket;
+    unix_sockopt_set(&mut (*sock).passcred, level, optname, optval, socklen)
+}
+
+/* fd lifetime & polling */
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketDuplicate(original: *mut OpenFile, _orphan: *mut OpenFile) -> bool {
+    let sock = (*original).dir as *mut UnixSocket;
+    spinlockAcquire(&mut (*sock).LOCK_SOCK);
+    (*sock).times_opened += 1;
+    spinlockRelease(&mut (*sock).LOCK_SOCK);
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketClose(fd: *mut OpenFile) -> bool {
+    let sock = (*fd).dir as *mut UnixSocket;
+
+    spinlockAcquire(&mut (*sock).LOCK_SOCK);
+    (*sock).times_opened -= 1;
+    if (*sock).times_opened > 0 {
+        spinlockRelease(&mut (*sock).LOCK_SOCK);
+        return true;
+    }
+
+    // nobody is reading anymore: datagram senders get ECONNREFUSED
+    (*sock).dgram_queue.rd_shut = true;
+    unix_queue_flush(&mut (*sock).dgram_queue);
+
+    let backlog = core::mem::replace(&mut (*sock).backlog, null_mut());
+    let pending = core::mem::replace(&mut (*sock).conn_curr, 0);
+    spinlockRelease(&mut (*sock).LOCK_SOCK);
+
+    // connections nobody will accept() anymore
+    if !backlog.is_null() {
+        for i in 0..pending {
+            let pair = *backlog.add(i);
+            spinlockAcquire(&mut (*pair).LOCK_PAIR);
+            (*pair).established = true;
+            spinlockRelease(&mut (*pair).LOCK_PAIR);
+            unix_pair_side_closed(pair, true);
+        }
+        free(backlog as _);
+    }
+
+    let pair = core::mem::replace(&mut (*sock).pair, null_mut());
+    if !pair.is_null() {
+        spinlockAcquire(&mut (*pair).LOCK_PAIR);
+        (*pair).client_fds -= 1;
+        spinlockRelease(&mut (*pair).LOCK_PAIR);
+        unix_pair_side_closed(pair, false);
+    }
+
+    let peer = core::mem::replace(&mut (*sock).peer, null_mut());
+    if !peer.is_null() {
+        unix_socket_put(peer);
+    }
+
+    spinlockAcquire(&mut LOCK_LL_UNIX_SOCKET);
+    unix_socket_reap(sock);
+    spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketReportKey(fd: *mut OpenFile) -> usize {
+    (*fd).dir as usize
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn unixSocketInternalPoll(fd: *mut OpenFile, events: i32) -> i32 {
+    let sock = (*fd).dir as *mut UnixSocket;
+
+    if !(*sock).backlog.is_null() {
+        return if (*sock).conn_curr > 0 { events & EPOLLIN } else { 0 };
+    }
+
+    let pair = (*sock).pair;
+    if !pair.is_null() {
+        let (rx, tx) = unix_pair_queues(pair, false);
+        spinlockAcquire(&mut (*pair).LOCK_PAIR);
+        let revents = unix_queue_poll(rx, tx, (*sock).ty == SOCK_STREAM, events);
+        spinlockRelease(&mut (*pair).LOCK_PAIR);
+        return revents;
+    }
+
+    if unix_socket_connection(sock) {
+        return 0;
+    }
+
+    let mut revents = 0;
+    spinlockAcquire(&mut (*sock).LOCK_SOCK);
+    if events & EPOLLIN != 0 && (!(*sock).dgram_queue.head.is_null() || (*sock).dgram_queue.rd_shut) {
+        revents |= EPOLLIN;
+    }
+    spinlockRelease(&mut (*sock).LOCK_SOCK);
+
+    // a peer's queue filling up isn't tracked, sends block instead
+    if events & EPOLLOUT != 0 && !(*sock).dgram_wr_shut {
+        revents |= EPOLLOUT;
+    }
+    revents
+}
+
+/* socketpair() */
+
+pub unsafe fn unix_socket_pair(ty: i32, protocol: i32, sv: &mut [i32; 2]) -> Result<usize, i32> {
+    let errno = |ret: usize| (ret as isize).wrapping_neg() as i32;
+
+    let fd0 = unix_socket_open(currentTask, ty, protocol);
+    if RET_IS_ERR(fd0) {
+        return Err(errno(fd0));
+    }
+    let node0 = fsUserGetNode(currentTask, fd0);
+    let sock0 = (*node0).dir as *mut UnixSocket;
+
+    if (*sock0).ty == SOCK_DGRAM {
+        let fd1 = unix_socket_open(currentTask, ty, protocol);
+        assert!(!RET_IS_ERR(fd1));
+        let sock1 = (*fsUserGetNode(currentTask, fd1)).dir as *mut UnixSocket;
+
+        spinlockAcquire(&mut LOCK_LL_UNIX_SOCKET);
+        (*sock0).peer = sock1;
+        (*sock1).peer = sock0;
+        (*sock0).refs += 1;
+        (*sock1).refs += 1;
+        spinlockRelease(&mut LOCK_LL_UNIX_SOCKET);
+
+        sv[0] = fd0 as i32;
+        sv[1] = fd1 as i32;
+        return Ok(0);
+    }
+
+    // the second end is the "accept()ed" side of the very same pair
+    let pair = unix_socket_allocate_pair((*sock0).ty);
+    (*pair).client_fds = 1;
+    (*pair).server_fds = 1;
+    (*pair).established = true;
+    (*pair).client_cred = unix_current_cred();
+    (*pair).server_cred = unix_current_cred();
+    (*sock0).pair = pair;
+
+    let node1 = unix_socket_accept_create(pair);
+    (*node1).close_on_exec = (*node0).close_on_exec;
+    (*node1).flags |= (*node0).flags & O_NONBLOCK;
+
+    sv[0] = fd0 as i32;
+    sv[1] = (*node1).id as i32;
+    Ok(0)
+}
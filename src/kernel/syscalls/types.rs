@@ -1,30 +1,182 @@
 use core::ptr::null_mut;
 use crate::*;
 
-pub const UNIX_SOCK_BUFF_DEFAULT: usize = 4096;
+// big enough for a 64KiB datagram, streams just get split up
+pub const UNIX_SOCK_BUFF_DEFAULT: usize = 65536;
 pub const UNIX_SOCK_POLL_EXTRA: usize = 128;
 
+pub const UNIX_PATH_MAX: usize = 108;
+pub const UNIX_SCM_MAX_FD: usize = 253;
+
+pub const SOCK_TYPE_MASK: i32 = 0xf;
+
+pub const SCM_RIGHTS: i32 = 1;
+pub const SCM_CREDENTIALS: i32 = 2;
+
+pub const SOL_SOCKET: i32 = 1;
+pub const SO_TYPE: i32 = 3;
+pub const SO_ERROR: i32 = 4;
+pub const SO_SNDBUF: i32 = 7;
+pub const SO_RCVBUF: i32 = 8;
+pub const SO_PASSCRED: i32 = 16;
+pub const SO_PEERCRED: i32 = 17;
+pub const SO_DOMAIN: i32 = 39;
+
+pub const EPOLLIN: i32 = 0x001;
+pub const EPOLLOUT: i32 = 0x004;
+pub const EPOLLHUP: i32 = 0x010;
+pub const EPOLLRDHUP: i32 = 0x2000;
+
+pub const MSG_PEEK: i32 = 0x2;
+pub const MSG_CTRUNC: i32 = 0x8;
+pub const MSG_TRUNC: i32 = 0x20;
+pub const MSG_DONTWAIT: i32 = 0x40;
+pub const MSG_NOSIGNAL: i32 = 0x4000;
+pub const MSG_CMSG_CLOEXEC: i32 = 0x40000000;
+
+pub const SHUT_RD: i32 = 0;
+pub const SHUT_WR: i32 = 1;
+pub const SHUT_RDWR: i32 = 2;
+
+/// struct sockaddr_un
+#[repr(C)]
+pub struct sockaddr_un_linux {
+    pub sun_family: u16,
+    pub sun_path: [u8; UNIX_PATH_MAX],
+}
+
+/// struct cmsghdr, data follows aligned to 8
+#[repr(C)]
+pub struct cmsghdr_linux {
+    pub cmsg_len: usize,
+    pub cmsg_level: i32,
+    pub cmsg_type: i32,
+}
+
+/// An AF_UNIX name. `len == 0` is unnamed, a leading `\0` in `path` makes it
+/// abstract (no filesystem involvement, the full `len` bytes are the name)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct UnixAddr {
+    pub len: usize,
+    pub path: [u8; UNIX_PATH_MAX],
+}
+
+impl UnixAddr {
+    pub const fn unnamed() -> UnixAddr {
+        UnixAddr { len: 0, path: [0; UNIX_PATH_MAX] }
+    }
+
+    pub fn is_unnamed(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_abstract(&self) -> bool {
+        self.len > 0 && self.path[0] == 0
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.path[..self.len]
+    }
+}
+
+/// struct ucred
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct UnixCred {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// One sendmsg() worth of data. Streams may consume it over several reads
+/// (`offset`), datagrams and seqpackets always take it out whole.
+#[repr(C)]
+pub struct UnixMessage {
+    pub next: *mut UnixMessage,
+
+    pub data: *mut u8,
+    pub len: usize,
+    pub offset: usize,
+
+    // SCM_RIGHTS in flight: detached duplicates, owned by the message
+    pub fds: *mut *mut OpenFile,
+    pub fd_count: usize,
+
+    pub cred: UnixCred,
+    pub from: UnixAddr,
+}
+
+/// One direction of traffic. `wr_shut` means no more data is coming (EOF),
+/// `rd_shut` that nobody will read it anymore (EPIPE)
+#[repr(C)]
+pub struct UnixQueue {
+    pub head: *mut UnixMessage,
+    pub tail: *mut UnixMessage,
+
+    pub bytes: usize,
+    pub size: usize,
+
+    pub wr_shut: bool,
+    pub rd_shut: bool,
+}
+
+impl UnixQueue {
+    pub const fn new(size: usize) -> UnixQueue {
+        UnixQueue {
+            head: null_mut(),
+            tail: null_mut(),
+            bytes: 0,
+            size,
+            wr_shut: false,
+            rd_shut: false,
+        }
+    }
+}
+
+/// A connection (SOCK_STREAM/SOCK_SEQPACKET). The client side is the
+/// UnixSocket that connect()ed (or the first of a socketpair()), the server
+/// side the accept()ed fd.
 #[repr(C)]
 pub struct UnixSocketPair {
     pub LOCK_PAIR: Spinlock,
+    pub ty: i32,
+
     pub client_fds: usize,
     pub server_fds: usize,
     pub established: bool,
 
-    pub client_buff: CircularBuffer,
-    pub server_buff: CircularBuffer,
-    pub client_buff_size: usize,
-    pub server_buff_size: usize,
+    // named after who reads from them
+    pub client_queue: UnixQueue,
+    pub server_queue: UnixQueue,
+
+    // SO_PEERCRED, captured at connect() / socketpair()
+    pub client_cred: UnixCred,
+    pub server_cred: UnixCred,
+
+    // SO_PASSCRED of the accept()ed side (the client's lives in UnixSocket)
+    pub server_passcred: bool,
 
-    pub filename: *mut u8,
+    // what each side was bound to, for getsockname()/getpeername() and the
+    // sender of received messages
+    pub client_addr: UnixAddr,
+    pub server_addr: UnixAddr,
 }
 
 #[repr(C)]
 pub struct UnixSocket {
+    pub _ll: LLheader, // dsUnixSocket
+
     pub LOCK_SOCK: Spinlock,
+    pub ty: i32,
     pub times_opened: usize,
+    // kernel side references (lookups in flight, connect()ed datagram
+    // peers), the socket outlives its last fd until these are gone
+    pub refs: usize,
 
-    pub bind_addr: *mut u8,
+    pub bind_addr: UnixAddr,
+    pub passcred: bool,
+    pub cred: UnixCred,
 
     pub conn_max: usize,
     pub conn_curr: usize,
@@ -32,10 +184,25 @@ pub struct UnixSocket {
     pub accept_would_block: bool,
 
     pub pair: *mut UnixSocketPair,
+
+    // SOCK_DGRAM: own receive queue & connect()ed default destination
+    pub dgram_queue: UnixQueue,
+    pub dgram_wr_shut: bool,
+    pub peer: *mut UnixSocket,
 }
 
 /* global list */
 extern "C" {
     pub static mut dsUnixSocket: LinkedList;
     pub static mut LOCK_LL_UNIX_SOCKET: Spinlock;
+
+    pub fn LinkedListRemove(list: *mut LinkedList, size: usize, target: *mut core::ffi::c_void) -> bool;
+
+    // in-flight SCM_RIGHTS: a duplicate that lives outside any fd table,
+    // later installed into the receiver's
+    pub fn fsUserDuplicateNodeUnsafe(original: *mut OpenFile) -> *mut OpenFile;
+    pub fn fsUserDuplicateNode(task: *mut Task, original: *mut OpenFile, suggested: i32) -> usize;
+    pub fn fsKernelClose(file: *mut OpenFile) -> bool;
+
+    pub fn taskSignalSend(task: *mut Task, signal: usize);
 }
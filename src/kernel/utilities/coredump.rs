@@ -0,0 +1,895 @@
+#![no_std]
+#![allow(non_snake_case)]
+#![allow(non_camel_case_types)]
+
+use core::ffi::c_void;
+use core::mem::{size_of, zeroed};
+use core::ptr::{copy_nonoverlapping, null_mut};
+
+//
+// ELF core dumps, written when a signal's default action is Core. The layout
+// follows what Linux (binfmt_elf) produces, so host gdb can load them:
+//   Ehdr | PT_NOTE + one PT_LOAD per user region | notes | page aligned memory
+//
+
+//
+// Constants
+//
+
+const PAGE_SIZE: usize = 4096;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const EV_CURRENT: u32 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRFPREG: u32 = 2;
+const NT_PRPSINFO: u32 = 3;
+const NT_AUXV: u32 = 6;
+const NT_FILE: u32 = 0x4649_4c45;
+
+// PagingUserRegions() flags
+const PAGING_RW: u64 = 1 << 1;
+const PAGING_NX: u64 = 1 << 63;
+
+const O_WRONLY: u32 = 0o1;
+const O_CREAT: u32 = 0o100;
+const O_TRUNC: u32 = 0o1000;
+
+const AUXV_SAVED_MAX: usize = 48;
+
+const TASK_STATE_DEAD: u8 = 0;
+
+pub const CORE_PATTERN_MAX: usize = 128;
+const CORE_PATH_MAX: usize = 256;
+
+//
+// External kernel APIs
+//
+
+#[repr(C)]
+pub struct OpenFile {
+    _priv: u8,
+}
+
+#[repr(C)]
+pub struct SpinlockCnt {
+    _priv: u32,
+}
+
+#[repr(C)]
+pub struct Bootloader {
+    pub hhdmOffset: usize,
+}
+
+pub type PagingRegionCallback =
+    unsafe extern "C" fn(start: usize, end: usize, flags: u64, ctx: *mut c_void);
+
+extern "C" {
+    static bootloader: Bootloader;
+    static timerTicks: u64;
+    static timerBootUnix: u64;
+
+    static mut firstTask: *mut Task;
+    static mut currentTask: *mut Task;
+    static mut TASK_LL_MODIFY: SpinlockCnt;
+
+    fn debugf(fmt: *const u8, ...);
+
+    fn calloc(n: usize, size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+
+    fn spinlockCntReadAcquire(lock: *mut SpinlockCnt);
+    fn spinlockCntReadRelease(lock: *mut SpinlockCnt);
+
+    fn fsKernelOpen(path: *const u8, flags: u32, mode: u32) -> *mut OpenFile;
+    fn fsWrite(file: *mut OpenFile, buff: *const u8, limit: usize) -> usize;
+    fn fsKernelClose(file: *mut OpenFile);
+
+    fn PagingUserRegions(pagedir: *mut u64, callback: PagingRegionCallback, ctx: *mut c_void);
+    fn VirtualToPhysicalL(pagedir: *mut u64, virt: usize) -> usize;
+}
+
+//
+// Tasks (partial)
+//
+
+// include/isr.h
+#[repr(C)]
+pub struct AsmPassedInterrupt {
+    pub ds: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub interrupt: u64,
+    pub error: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub usermode_rsp: u64,
+    pub usermode_ss: u64,
+}
+
+#[repr(C)]
+pub struct TaskInfoFs {
+    pub utilizedBy: u32,
+    pub cwd: *mut u8,
+}
+
+#[repr(C)]
+pub struct LLheader {
+    pub next: *mut LLheader,
+}
+
+#[repr(C)]
+pub struct FileMapping {
+    pub _ll: LLheader,
+
+    pub start: usize,
+    pub end: usize,
+    pub offset: usize,
+    pub path: *mut u8,
+}
+
+#[repr(C)]
+pub struct LLcontrol {
+    pub signature1: u64,
+    pub signature2: u64,
+    pub structSize: u32,
+    pub LOCK_LL: u32,
+    pub firstObject: *mut LLheader,
+}
+
+#[repr(C)]
+pub struct TaskInfoPagedir {
+    pub pagedir: *mut u64,
+    pub savedAuxv: [u64; AUXV_SAVED_MAX],
+    pub dsFileMapping: LLcontrol,
+}
+
+#[repr(C)]
+pub struct TaskInfoSignal {
+    pub rlimitCoreSoft: usize,
+    pub rlimitCoreHard: usize,
+}
+
+#[repr(C)]
+pub struct Task {
+    pub id: u64,
+    pub pgid: i32,
+    pub tgid: i32,
+    pub sid: i32,
+    pub kernel_task: bool,
+    pub state: u8,
+
+    pub registers: AsmPassedInterrupt,
+
+    pub fsbase: u64,
+    pub gsbase: u64,
+
+    pub cmdline: *mut u8,
+    pub cmdlineLen: usize,
+    pub execname: *mut u8,
+
+    pub sigBlockList: u64,
+    pub sigPendingList: u64,
+
+    pub infoFs: *mut TaskInfoFs,
+    pub infoPd: *mut TaskInfoPagedir,
+    pub infoSignals: *mut TaskInfoSignal,
+
+    pub fpuenv: [u8; 512],
+
+    pub parent: *mut Task,
+    pub next: *mut Task,
+}
+
+//
+// ELF structures
+//
+
+#[repr(C)]
+struct Elf64_Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+#[repr(C)]
+struct Elf64_Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+#[repr(C)]
+struct Elf64_Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+// struct user_regs_struct (x86_64)
+#[repr(C)]
+struct UserRegs {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    rbp: u64,
+    rbx: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rax: u64,
+    rcx: u64,
+    rdx: u64,
+    rsi: u64,
+    rdi: u64,
+    orig_rax: u64,
+    rip: u64,
+    cs: u64,
+    eflags: u64,
+    rsp: u64,
+    ss: u64,
+    fs_base: u64,
+    gs_base: u64,
+    ds: u64,
+    es: u64,
+    fs: u64,
+    gs: u64,
+}
+
+#[repr(C)]
+struct ElfSiginfo {
+    si_signo: i32,
+    si_code: i32,
+    si_errno: i32,
+}
+
+#[repr(C)]
+struct Timeval {
+    tv_sec: i64,
+    tv_usec: i64,
+}
+
+// struct elf_prstatus, 336 bytes
+#[repr(C)]
+struct ElfPrstatus {
+    pr_info: ElfSiginfo,
+    pr_cursig: i16,
+    pr_sigpend: u64,
+    pr_sighold: u64,
+    pr_pid: i32,
+    pr_ppid: i32,
+    pr_pgrp: i32,
+    pr_sid: i32,
+    pr_utime: Timeval,
+    pr_stime: Timeval,
+    pr_cutime: Timeval,
+    pr_cstime: Timeval,
+    pr_reg: UserRegs,
+    pr_fpvalid: i32,
+}
+
+// struct elf_prpsinfo, 136 bytes
+#[repr(C)]
+struct ElfPrpsinfo {
+    pr_state: u8,
+    pr_sname: u8,
+    pr_zomb: u8,
+    pr_nice: i8,
+    pr_flag: u64,
+    pr_uid: u32,
+    pr_gid: u32,
+    pr_pid: i32,
+    pr_ppid: i32,
+    pr_pgrp: i32,
+    pr_sid: i32,
+    pr_fname: [u8; 16],
+    pr_psargs: [u8; 80],
+}
+
+//
+// core_pattern (/proc/sys/kernel/core_pattern)
+//
+
+static mut corePattern: [u8; CORE_PATTERN_MAX] = {
+    let mut pattern = [0u8; CORE_PATTERN_MAX];
+    pattern[0] = b'c';
+    pattern[1] = b'o';
+    pattern[2] = b'r';
+    pattern[3] = b'e';
+    pattern
+};
+
+#[no_mangle]
+pub unsafe extern "C" fn coredumpPatternGet(out: *mut u8, max: usize) -> usize {
+    let len = strlen(corePattern.as_ptr()).min(max);
+    copy_nonoverlapping(corePattern.as_ptr(), out, len);
+    len
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn coredumpPatternSet(input: *const u8, len: usize) -> usize {
+    // echo appends a newline
+    let mut len = len;
+    while len > 0 && (*input.add(len - 1) == b'\n' || *input.add(len - 1) == 0) {
+        len -= 1;
+    }
+    let len = len.min(CORE_PATTERN_MAX - 1);
+
+    copy_nonoverlapping(input, corePattern.as_mut_ptr(), len);
+    corePattern[len] = 0;
+    len
+}
+
+//
+// Helpers
+//
+
+unsafe fn strlen(s: *const u8) -> usize {
+    let mut len = 0;
+    while *s.add(len) != 0 {
+        len += 1;
+    }
+    len
+}
+
+#[inline]
+const fn align(value: usize, to: usize) -> usize {
+    (value + to - 1) & !(to - 1)
+}
+
+// basename of the executable, like the kernel's comm
+unsafe fn taskComm(task: *mut Task, out: &mut [u8; 16]) {
+    let exec = (*task).execname;
+    if exec.is_null() {
+        return;
+    }
+    let len = strlen(exec);
+    let mut start = 0;
+    for i in 0..len {
+        if *exec.add(i) == b'/' {
+            start = i + 1;
+        }
+    }
+    let len = (len - start).min(15);
+    copy_nonoverlapping(exec.add(start), out.as_mut_ptr(), len);
+}
+
+struct PathBuilder {
+    buff: [u8; CORE_PATH_MAX],
+    len: usize,
+}
+
+impl PathBuilder {
+    fn push(&mut self, c: u8) {
+        if self.len < CORE_PATH_MAX - 1 {
+            self.buff[self.len] = c;
+            self.len += 1;
+        }
+    }
+
+    unsafe fn pushStr(&mut self, s: *const u8) {
+        let mut i = 0;
+        while *s.add(i) != 0 {
+            self.push(*s.add(i));
+            i += 1;
+        }
+    }
+
+    fn pushNum(&mut self, mut num: u64) {
+        let mut digits = [0u8; 20];
+        let mut cnt = 0;
+        loop {
+            digits[cnt] = b'0' + (num % 10) as u8;
+            cnt += 1;
+            num /= 10;
+            if num == 0 {
+                break;
+            }
+        }
+        for i in (0..cnt).rev() {
+            self.push(digits[i]);
+        }
+    }
+}
+
+// Expands core_pattern (%p %i %e %s %t %h %%); relative patterns end up in
+// the crashing task's working directory like on Linux
+unsafe fn coredumpPath(task: *mut Task, signal: usize, path: &mut PathBuilder) -> bool {
+    let pattern = corePattern.as_ptr();
+    if *pattern == 0 || *pattern == b'|' {
+        // piping to a helper isn't supported
+        return false;
+    }
+
+    if *pattern != b'/' {
+        path.pushStr((*(*task).infoFs).cwd);
+        if path.len == 0 || path.buff[path.len - 1] != b'/' {
+            path.push(b'/');
+        }
+    }
+
+    let mut i = 0;
+    while *pattern.add(i) != 0 {
+        let c = *pattern.add(i);
+        i += 1;
+        if c != b'%' {
+            path.push(c);
+            continue;
+        }
+
+        let spec = *pattern.add(i);
+        if spec == 0 {
+            break;
+        }
+        i += 1;
+        match spec {
+            b'%' => path.push(b'%'),
+            b'p' | b'P' => path.pushNum((*task).tgid as u64),
+            b'i' | b'I' => path.pushNum((*task).id),
+            b's' => path.pushNum(signal as u64),
+            b't' => path.pushNum(timerBootUnix + timerTicks / 1000),
+            b'h' => path.pushStr(b"cavOS\0".as_ptr()),
+            b'e' => {
+                let mut comm = [0u8; 16];
+                taskComm(task, &mut comm);
+                path.pushStr(comm.as_ptr());
+            }
+            _ => {}
+        }
+    }
+
+    path.buff[path.len] = 0;
+    true
+}
+
+//
+// Output, cut off at RLIMIT_CORE
+//
+
+struct CoreWriter {
+    file: *mut OpenFile,
+    written: usize,
+    limit: usize,
+}
+
+impl CoreWriter {
+    unsafe fn write(&mut self, buff: *const u8, len: usize) -> bool {
+        let len = len.min(self.limit - self.written);
+        if len == 0 {
+            return false;
+        }
+        let ret = fsWrite(self.file, buff, len);
+        if ret != len {
+            return false;
+        }
+        self.written += len;
+        true
+    }
+
+    unsafe fn zeroes(&mut self, mut len: usize) -> bool {
+        let zero = [0u8; 256];
+        while len > 0 {
+            let chunk = len.min(zero.len());
+            if !self.write(zero.as_ptr(), chunk) {
+                return false;
+            }
+            len -= chunk;
+        }
+        true
+    }
+}
+
+//
+// Memory regions
+//
+
+#[derive(Copy, Clone)]
+struct CoreRegion {
+    start: usize,
+    end: usize,
+    flags: u64,
+}
+
+struct CoreRegions {
+    list: *mut CoreRegion,
+    count: usize,
+    max: usize,
+}
+
+unsafe extern "C" fn coredumpRegionCb(start: usize, end: usize, flags: u64, ctx: *mut c_void) {
+    let regions = ctx as *mut CoreRegions;
+    if !(*regions).list.is_null() && (*regions).count < (*regions).max {
+        *(*regions).list.add((*regions).count) = CoreRegion { start, end, flags };
+    }
+    (*regions).count += 1;
+}
+
+//
+// Notes
+//
+
+struct NoteBuilder {
+    buff: *mut u8,
+    len: usize,
+}
+
+impl NoteBuilder {
+    fn size(descsz: usize) -> usize {
+        // "CORE\0" padded to 8
+        size_of::<Elf64_Nhdr>() + 8 + align(descsz, 4)
+    }
+
+    unsafe fn push(&mut self, ntype: u32, desc: *const u8, descsz: usize) {
+        let nhdr = self.buff.add(self.len) as *mut Elf64_Nhdr;
+        (*nhdr).n_namesz = 5;
+        (*nhdr).n_descsz = descsz as u32;
+        (*nhdr).n_type = ntype;
+        self.len += size_of::<Elf64_Nhdr>();
+
+        copy_nonoverlapping(b"CORE\0\0\0\0".as_ptr(), self.buff.add(self.len), 8);
+        self.len += 8;
+
+        copy_nonoverlapping(desc, self.buff.add(self.len), descsz);
+        self.len += align(descsz, 4);
+    }
+}
+
+unsafe fn coredumpPrstatus(
+    task: *mut Task,
+    regs: *const AsmPassedInterrupt,
+    signal: usize,
+) -> ElfPrstatus {
+    let mut status: ElfPrstatus = zeroed();
+    status.pr_info.si_signo = signal as i32;
+    status.pr_cursig = signal as i16;
+    status.pr_sigpend = (*task).sigPendingList;
+    status.pr_sighold = (*task).sigBlockList;
+    status.pr_pid = (*task).id as i32;
+    status.pr_ppid = if (*task).parent.is_null() {
+        0
+    } else {
+        (*(*task).parent).tgid
+    };
+    status.pr_pgrp = (*task).pgid;
+    status.pr_sid = (*task).sid;
+
+    let r = &mut status.pr_reg;
+    r.r15 = (*regs).r15;
+    r.r14 = (*regs).r14;
+    r.r13 = (*regs).r13;
+    r.r12 = (*regs).r12;
+    r.rbp = (*regs).rbp;
+    r.rbx = (*regs).rbx;
+    r.r11 = (*regs).r11;
+    r.r10 = (*regs).r10;
+    r.r9 = (*regs).r9;
+    r.r8 = (*regs).r8;
+    r.rax = (*regs).rax;
+    r.rcx = (*regs).rcx;
+    r.rdx = (*regs).rdx;
+    r.rsi = (*regs).rsi;
+    r.rdi = (*regs).rdi;
+    r.orig_rax = u64::MAX;
+    r.rip = (*regs).rip;
+    r.cs = (*regs).cs;
+    r.eflags = (*regs).rflags;
+    r.rsp = (*regs).usermode_rsp;
+    r.ss = (*regs).usermode_ss;
+    r.fs_base = (*task).fsbase;
+    r.gs_base = (*task).gsbase;
+    r.ds = (*regs).ds;
+    r.es = (*regs).ds;
+
+    status.pr_fpvalid = 1;
+    status
+}
+
+unsafe fn coredumpPrpsinfo(task: *mut Task) -> ElfPrpsinfo {
+    let mut info: ElfPrpsinfo = zeroed();
+    info.pr_sname = b'R';
+    info.pr_pid = (*task).tgid;
+    info.pr_ppid = if (*task).parent.is_null() {
+        0
+    } else {
+        (*(*task).parent).tgid
+    };
+    info.pr_pgrp = (*task).pgid;
+    info.pr_sid = (*task).sid;
+
+    taskComm(task, &mut info.pr_fname);
+
+    // arguments are \0 separated in cmdline
+    if !(*task).cmdline.is_null() {
+        let len = (*task).cmdlineLen.min(info.pr_psargs.len() - 1);
+        for i in 0..len {
+            let c = *(*task).cmdline.add(i);
+            info.pr_psargs[i] = if c == 0 { b' ' } else { c };
+        }
+        if len > 0 && info.pr_psargs[len - 1] == b' ' {
+            info.pr_psargs[len - 1] = 0;
+        }
+    }
+
+    info
+}
+
+// count, page size, (start, end, file page offset) triplets, then the paths
+unsafe fn coredumpFileNote(pd: *mut TaskInfoPagedir, out: *mut u8) -> usize {
+    let mut count = 0u64;
+    let mut namesLen = 0;
+    let mut browse = (*pd).dsFileMapping.firstObject as *mut FileMapping;
+    while !browse.is_null() {
+        // a mapping nobody named (yet) has nothing to say
+        if !(*browse).path.is_null() {
+            count += 1;
+            namesLen += strlen((*browse).path) + 1;
+        }
+        browse = (*browse)._ll.next as *mut FileMapping;
+    }
+
+    let size = 16 + count as usize * 24 + namesLen;
+    if out.is_null() {
+        return size;
+    }
+
+    let header = out as *mut u64;
+    *header = count;
+    *header.add(1) = PAGE_SIZE as u64;
+
+    let mut triplet = header.add(2);
+    let mut names = out.add(16 + count as usize * 24);
+    browse = (*pd).dsFileMapping.firstObject as *mut FileMapping;
+    while !browse.is_null() {
+        if (*browse).path.is_null() {
+            browse = (*browse)._ll.next as *mut FileMapping;
+            continue;
+        }
+        *triplet = (*browse).start as u64;
+        *triplet.add(1) = (*browse).end as u64;
+        *triplet.add(2) = ((*browse).offset / PAGE_SIZE) as u64;
+        triplet = triplet.add(3);
+
+        let len = strlen((*browse).path) + 1;
+        copy_nonoverlapping((*browse).path, names, len);
+        names = names.add(len);
+        browse = (*browse)._ll.next as *mut FileMapping;
+    }
+
+    size
+}
+
+//
+// Dumping
+//
+
+// Writes a core file for `task`, which got `signal` while at `regs`. Returns
+// whether one was produced (WCOREDUMP). Only from the task's own kernel
+// entry with interrupts on, the file writes may have to wait.
+#[no_mangle]
+pub unsafe extern "C" fn coredumpWrite(
+    task: *mut Task,
+    regs: *const AsmPassedInterrupt,
+    signal: usize,
+) -> bool {
+    let limit = (*(*task).infoSignals).rlimitCoreSoft;
+    if (*task).kernel_task || limit == 0 {
+        return false;
+    }
+
+    let mut path = PathBuilder {
+        buff: [0; CORE_PATH_MAX],
+        len: 0,
+    };
+    if !coredumpPath(task, signal, &mut path) {
+        return false;
+    }
+
+    let pd = (*task).infoPd;
+
+    // memory layout
+    let mut regions = CoreRegions {
+        list: null_mut(),
+        count: 0,
+        max: 0,
+    };
+    PagingUserRegions((*pd).pagedir, coredumpRegionCb, &mut regions as *mut _ as *mut c_void);
+    regions.max = regions.count;
+    regions.count = 0;
+    regions.list = calloc(regions.max.max(1), size_of::<CoreRegion>()) as *mut CoreRegion;
+    PagingUserRegions((*pd).pagedir, coredumpRegionCb, &mut regions as *mut _ as *mut c_void);
+    let regionCnt = regions.count.min(regions.max);
+
+    // the rest of the thread group goes in as additional threads
+    spinlockCntReadAcquire(&mut TASK_LL_MODIFY);
+    let mut threads = 0;
+    let mut browse = firstTask;
+    while !browse.is_null() {
+        if browse != task && (*browse).tgid == (*task).tgid && (*browse).state != TASK_STATE_DEAD {
+            threads += 1;
+        }
+        browse = (*browse).next;
+    }
+
+    let mut auxvLen = 0;
+    while auxvLen + 2 <= AUXV_SAVED_MAX && (*pd).savedAuxv[auxvLen] != 0 {
+        auxvLen += 2;
+    }
+    auxvLen = (auxvLen + 2).min(AUXV_SAVED_MAX) * size_of::<u64>(); // AT_NULL too
+
+    let fileLen = coredumpFileNote(pd, null_mut());
+    let notesLen = (1 + threads) * NoteBuilder::size(size_of::<ElfPrstatus>())
+        + (1 + threads) * NoteBuilder::size(512)
+        + NoteBuilder::size(size_of::<ElfPrpsinfo>())
+        + NoteBuilder::size(auxvLen)
+        + NoteBuilder::size(fileLen);
+
+    let mut notes = NoteBuilder {
+        buff: calloc(1, notesLen),
+        len: 0,
+    };
+
+    // crashing thread first, gdb treats it as the current one. We run in its
+    // own context (never from the scheduler), so its FPU state is the live
+    // one and only gets saved by the next switch: save it now
+    if task == currentTask {
+        core::arch::asm!("fxsave [{}]", in(reg) (*task).fpuenv.as_mut_ptr());
+    }
+
+    let status = coredumpPrstatus(task, regs, signal);
+    notes.push(NT_PRSTATUS, &status as *const _ as *const u8, size_of::<ElfPrstatus>());
+    let info = coredumpPrpsinfo(task);
+    notes.push(NT_PRPSINFO, &info as *const _ as *const u8, size_of::<ElfPrpsinfo>());
+    notes.push(NT_AUXV, (*pd).savedAuxv.as_ptr() as *const u8, auxvLen);
+    let file = calloc(1, fileLen);
+    coredumpFileNote(pd, file);
+    notes.push(NT_FILE, file, fileLen);
+    free(file);
+    notes.push(NT_PRFPREG, (*task).fpuenv.as_ptr(), 512);
+
+    browse = firstTask;
+    let mut added = 0;
+    while !browse.is_null() && added < threads {
+        if browse != task && (*browse).tgid == (*task).tgid && (*browse).state != TASK_STATE_DEAD {
+            let status = coredumpPrstatus(browse, &(*browse).registers, 0);
+            notes.push(NT_PRSTATUS, &status as *const _ as *const u8, size_of::<ElfPrstatus>());
+            notes.push(NT_PRFPREG, (*browse).fpuenv.as_ptr(), 512);
+            added += 1;
+        }
+        browse = (*browse).next;
+    }
+    spinlockCntReadRelease(&mut TASK_LL_MODIFY);
+
+    // headers
+    let phnum = 1 + regionCnt;
+    let phdrsLen = phnum * size_of::<Elf64_Phdr>();
+    let notesOffset = size_of::<Elf64_Ehdr>() + phdrsLen;
+    let dataOffset = align(notesOffset + notes.len, PAGE_SIZE);
+
+    let mut ehdr: Elf64_Ehdr = zeroed();
+    ehdr.e_ident[..4].copy_from_slice(b"\x7fELF");
+    ehdr.e_ident[4] = ELFCLASS64;
+    ehdr.e_ident[5] = ELFDATA2LSB;
+    ehdr.e_ident[6] = EV_CURRENT as u8;
+    ehdr.e_type = ET_CORE;
+    ehdr.e_machine = EM_X86_64;
+    ehdr.e_version = EV_CURRENT;
+    ehdr.e_phoff = size_of::<Elf64_Ehdr>() as u64;
+    ehdr.e_ehsize = size_of::<Elf64_Ehdr>() as u16;
+    ehdr.e_phentsize = size_of::<Elf64_Phdr>() as u16;
+    ehdr.e_phnum = phnum as u16;
+
+    let phdrs = calloc(phnum, size_of::<Elf64_Phdr>()) as *mut Elf64_Phdr;
+    (*phdrs).p_type = PT_NOTE;
+    (*phdrs).p_offset = notesOffset as u64;
+    (*phdrs).p_filesz = notes.len as u64;
+    (*phdrs).p_align = 4;
+
+    let mut offset = dataOffset;
+    for i in 0..regionCnt {
+        let region = *regions.list.add(i);
+        let size = region.end - region.start;
+        let phdr = phdrs.add(1 + i);
+        (*phdr).p_type = PT_LOAD;
+        (*phdr).p_flags = PF_R
+            | if region.flags & PAGING_RW != 0 { PF_W } else { 0 }
+            | if region.flags & PAGING_NX == 0 { PF_X } else { 0 };
+        (*phdr).p_offset = offset as u64;
+        (*phdr).p_vaddr = region.start as u64;
+        (*phdr).p_filesz = size as u64;
+        (*phdr).p_memsz = size as u64;
+        (*phdr).p_align = PAGE_SIZE as u64;
+        offset += size;
+    }
+
+    // out it goes
+    let mut produced = false;
+    let fd = fsKernelOpen(path.buff.as_ptr(), O_WRONLY | O_CREAT | O_TRUNC, 0o600);
+    if fd.is_null() {
+        debugf(b"[coredump] Couldn't create %s\n\0".as_ptr(), path.buff.as_ptr());
+    } else {
+        let mut out = CoreWriter {
+            file: fd,
+            written: 0,
+            limit,
+        };
+
+        let mut ok = out.write(&ehdr as *const _ as *const u8, size_of::<Elf64_Ehdr>())
+            && out.write(phdrs as *const u8, phdrsLen)
+            && out.write(notes.buff, notes.len)
+            && out.zeroes(dataOffset - notesOffset - notes.len);
+
+        // straight from the physical pages, the page directory isn't ours
+        let mut i = 0;
+        while ok && i < regionCnt {
+            let region = *regions.list.add(i);
+            let mut virt = region.start;
+            while ok && virt < region.end {
+                let phys = VirtualToPhysicalL((*pd).pagedir, virt);
+                ok = if phys == 0 {
+                    out.zeroes(PAGE_SIZE)
+                } else {
+                    out.write((phys + bootloader.hhdmOffset) as *const u8, PAGE_SIZE)
+                };
+                virt += PAGE_SIZE;
+            }
+            i += 1;
+        }
+
+        fsKernelClose(fd);
+        // a dump cut short by RLIMIT_CORE still counts, like on Linux
+        produced = out.written > 0;
+        debugf(
+            b"[coredump] Dumped %ld bytes of pid %ld to %s\n\0".as_ptr(),
+            out.written,
+            (*task).tgid as i64,
+            path.buff.as_ptr(),
+        );
+    }
+
+    free(phdrs as *mut u8);
+    free(notes.buff);
+    free(regions.list as *mut u8);
+    produced
+}
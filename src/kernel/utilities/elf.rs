@@ -36,6 +36,9 @@ const PF_RW: u64 = 1 << 1;
 
 const BLOCK_SIZE: usize = 512;
 
+// file backed segments remembered per executable (NT_FILE in core dumps)
+const ELF_MAPPINGS_MAX: usize = 32;
+
 /* ================= ELF STRUCTS ================= */
 
 #[repr(C)]
@@ -73,7 +76,12 @@ pub struct Elf64_Phdr {
 #[repr(C)]
 pub struct OpenFile;
 #[repr(C)]
-pub struct Task;
+pub struct Task {
+    pub id: u64,
+    pub infoPd: *mut TaskInfoPagedir,
+}
+#[repr(C)]
+pub struct TaskInfoPagedir;
 #[repr(C)]
 pub struct PageDirectory;
 
@@ -108,6 +116,13 @@ extern "C" {
     ) -> *mut Task;
 
     fn taskCreateFinish(task: *mut Task);
+    fn taskInfoPdMappingAdd(
+        target: *mut TaskInfoPagedir,
+        start: size_t,
+        end: size_t,
+        offset: size_t,
+        path: *const u8,
+    );
     fn stackGenerateUser(
         task: *mut Task,
         argc: u32,
@@ -183,6 +198,35 @@ unsafe fn elf_process_load(phdr: &Elf64_Phdr, image: *mut u8, base: size_t) {
     }
 }
 
+#[derive(Copy, Clone)]
+struct ElfMapping {
+    start: size_t,
+    end: size_t,
+    offset: size_t,
+    path: *const u8,
+}
+
+struct ElfMappings {
+    list: [ElfMapping; ELF_MAPPINGS_MAX],
+    count: usize,
+}
+
+impl ElfMappings {
+    fn push(&mut self, phdr: &Elf64_Phdr, base: size_t, path: *const u8) {
+        if self.count >= ELF_MAPPINGS_MAX || phdr.p_filesz == 0 {
+            return;
+        }
+        let start = base + phdr.p_vaddr as size_t;
+        self.list[self.count] = ElfMapping {
+            start: start & !0xFFF,
+            end: (start + phdr.p_filesz as size_t + 0xFFF) & !0xFFF,
+            offset: phdr.p_offset as size_t & !0xFFF,
+            path,
+        };
+        self.count += 1;
+    }
+}
+
 /* ================= ELF EXEC ================= */
 
 pub unsafe fn elfExecute(
@@ -223,6 +267,16 @@ pub unsafe fn elfExecute(
     let interp_base = 0x1000_0000_0000usize;
     let mut exec_base = 0usize;
 
+    let mut mappings = ElfMappings {
+        list: [ElfMapping {
+            start: 0,
+            end: 0,
+            offset: 0,
+            path: ptr::null(),
+        }; ELF_MAPPINGS_MAX],
+        count: 0,
+    };
+
     for i in 0..ehdr.e_phnum {
         let phdr = &*((image as usize
             + ehdr.e_phoff as usize
@@ -251,6 +305,7 @@ pub unsafe fn elfExecute(
                         as *const Elf64_Phdr);
                 if iph.p_type == PT_LOAD {
                     elf_process_load(iph, buf, interp_base);
+                    mappings.push(iph, interp_base, path);
                 }
             }
 
@@ -267,6 +322,7 @@ pub unsafe fn elfExecute(
         }
 
         elf_process_load(phdr, image, exec_base);
+        mappings.push(phdr, exec_base, filepath);
     }
 
     ChangePageDirectory(old_pd);
@@ -292,6 +348,17 @@ pub unsafe fn elfExecute(
         exec_base,
     );
 
+    // paths are copied, so this has to happen before image is gone
+    for mapping in &mappings.list[..mappings.count] {
+        taskInfoPdMappingAdd(
+            (*task).infoPd,
+            mapping.start,
+            mapping.end,
+            mapping.offset,
+            mapping.path,
+        );
+    }
+
     VirtualFree(image, pages);
 
     if startup {
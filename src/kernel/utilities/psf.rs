@@ -1,4 +1,5 @@
 #![allow(dead_code)]
+#![allow(non_snake_case)]
 
 use core::mem::size_of;
 use core::ptr;
@@ -11,9 +12,6 @@ extern "C" {
 
     fn drawPixel(x: u32, y: u32, r: u32, g: u32, b: u32);
 
-    static mut psf: *mut PSF1Font;
-    static bg_color: [u32; 3];
-
     // VFS / memory
     fn fsKernelOpen(path: *const u8, flags: u32, mode: u32) -> *mut OpenFile;
     fn fsKernelClose(file: *mut OpenFile);
@@ -27,6 +25,7 @@ extern "C" {
 // ONLY included here (same rule as C)
 extern "C" {
     static u_vga16_psf: [u8; 0];
+    static u_vga16_psf_len: u32;
 }
 
 //
@@ -35,6 +34,28 @@ extern "C" {
 pub const PSF1_MAGIC: u16 = 0x0436;
 pub const PSF1_MODE512: u8 = 0x01;
 pub const PSF1_MODEHASTAB: u8 = 0x02;
+pub const PSF1_MODESEQ: u8 = 0x04;
+pub const PSF1_SEPARATOR: u16 = 0xFFFF;
+pub const PSF1_STARTSEQ: u16 = 0xFFFE;
+
+pub const PSF2_MAGIC: u32 = 0x864a_b572;
+pub const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+pub const PSF2_SEPARATOR: u8 = 0xFF;
+pub const PSF2_STARTSEQ: u8 = 0xFE;
+
+// Bigger than that isn't a console font
+pub const PSF_MAX_WIDTH: u32 = 64;
+pub const PSF_MAX_HEIGHT: u32 = 128;
+pub const PSF_MAX_GLYPHS: u32 = 65536;
+
+// The built-in font is loaded before there's a heap, its unicode table goes
+// here
+const PSF_DEFAULT_UNICODE_MAX: usize = 4096;
+
+// What's drawn for code points the font doesn't have
+const REPLACEMENT_CHARACTER: u32 = 0xFFFD;
+
+const O_RDONLY: u32 = 0;
 
 //
 // PSF structures
@@ -46,11 +67,16 @@ pub struct PSF1Header {
     pub height: u8,
 }
 
-// Convenience alias: header followed by glyph data
 #[repr(C)]
-pub struct PSF1Font {
-    pub header: PSF1Header,
-    // glyphs follow immediately
+pub struct PSF2Header {
+    pub magic: u32,
+    pub version: u32,
+    pub headersize: u32,
+    pub flags: u32,
+    pub length: u32,
+    pub charsize: u32,
+    pub height: u32,
+    pub width: u32,
 }
 
 #[repr(C)]
@@ -58,45 +84,364 @@ pub struct OpenFile {
     _private: [u8; 0],
 }
 
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct UnicodePair {
+    pub codepoint: u32,
+    pub glyph: u32,
+}
+
+// The font in use, in whichever format it came from. Rows are
+// bytesPerRow wide, most significant bit first.
+#[repr(C)]
+pub struct Font {
+    pub width: u32,
+    pub height: u32,
+    pub count: u32,
+    pub bytesPerRow: u32,
+    pub charsize: u32,
+    pub glyphs: *mut u8,
+    pub ownsGlyphs: bool,
+
+    // sorted by code point; without one, code points index glyphs directly
+    pub unicode: *mut UnicodePair,
+    pub unicodeLen: usize,
+    pub ownsUnicode: bool,
+
+    // glyph of every ASCII character, and of the replacement
+    pub ascii: [u32; 128],
+    pub replacement: u32,
+}
+
+#[no_mangle]
+pub static mut psfFont: Font = Font {
+    width: 8,
+    height: 16,
+    count: 0,
+    bytesPerRow: 1,
+    charsize: 16,
+    glyphs: ptr::null_mut(),
+    ownsGlyphs: false,
+    unicode: ptr::null_mut(),
+    unicodeLen: 0,
+    ownsUnicode: false,
+    ascii: [0; 128],
+    replacement: 0,
+};
+
+static mut DEFAULT_UNICODE: [UnicodePair; PSF_DEFAULT_UNICODE_MAX] =
+    [UnicodePair { codepoint: 0, glyph: 0 }; PSF_DEFAULT_UNICODE_MAX];
+
+//
+// Glyph lookup
+//
+unsafe fn psf_lookup(codepoint: u32) -> Option<u32> {
+    if psfFont.unicode.is_null() {
+        return if codepoint < psfFont.count { Some(codepoint) } else { None };
+    }
+
+    let map = core::slice::from_raw_parts(psfFont.unicode, psfFont.unicodeLen);
+    map.binary_search_by_key(&codepoint, |pair| pair.codepoint)
+        .ok()
+        .map(|at| map[at].glyph)
+}
+
+// Called whenever the glyphs or the unicode table change
+unsafe fn psf_rebuild_cache() {
+    psfFont.replacement = psf_lookup(REPLACEMENT_CHARACTER)
+        .or_else(|| psf_lookup(b'?' as u32))
+        .unwrap_or(0);
+    for c in 0..128u32 {
+        psfFont.ascii[c as usize] = psf_lookup(c).unwrap_or(psfFont.replacement);
+    }
+}
+
+pub unsafe fn psf_glyph_index(codepoint: u32) -> u32 {
+    if codepoint < 128 {
+        return psfFont.ascii[codepoint as usize];
+    }
+    psf_lookup(codepoint).unwrap_or(psfFont.replacement)
+}
+
+//
+// Unicode tables
+//
+// owned tables were malloc()ed and get freed when replaced
+unsafe fn psf_unicode_set(pairs: *mut UnicodePair, len: usize, owned: bool) {
+    if psfFont.ownsUnicode && !psfFont.unicode.is_null() {
+        free(psfFont.unicode as *mut u8);
+    }
+
+    if !pairs.is_null() {
+        let map = core::slice::from_raw_parts_mut(pairs, len);
+        map.sort_unstable_by_key(|pair| pair.codepoint);
+    }
+    psfFont.unicode = pairs;
+    psfFont.unicodeLen = len;
+    psfFont.ownsUnicode = owned;
+    psf_rebuild_cache();
+}
+
+// PSF1: per glyph, u16 code points up to PSF1_SEPARATOR. Sequences (after
+// PSF1_STARTSEQ) can't be drawn with a single glyph, so they're skipped.
+// Fills in up to max pairs, returns how many there are.
+unsafe fn psf1_parse_unicode(table: *const u8, end: *const u8, count: u32, out: *mut UnicodePair, max: usize) -> usize {
+    let mut at = table;
+    let mut entries = 0usize;
+    let mut glyph = 0u32;
+    let mut inSequence = false;
+    while glyph < count && at.add(2) <= end {
+        let value = ptr::read_unaligned(at as *const u16);
+        at = at.add(2);
+        match value {
+            PSF1_SEPARATOR => {
+                glyph += 1;
+                inSequence = false;
+            }
+            PSF1_STARTSEQ => inSequence = true,
+            _ if inSequence => {}
+            _ => {
+                if entries < max {
+                    *out.add(entries) = UnicodePair {
+                        codepoint: value as u32,
+                        glyph,
+                    };
+                }
+                entries += 1;
+            }
+        }
+    }
+    entries
+}
+
+// Decodes one UTF-8 sequence of a PSF2 table, None when malformed
+unsafe fn psf2_decode(at: &mut *const u8, end: *const u8) -> Option<u32> {
+    let lead = **at;
+    let (len, init) = match lead {
+        0x00..=0x7F => (1, lead as u32),
+        0xC0..=0xDF => (2, (lead & 0x1F) as u32),
+        0xE0..=0xEF => (3, (lead & 0x0F) as u32),
+        0xF0..=0xF7 => (4, (lead & 0x07) as u32),
+        _ => {
+            *at = at.add(1);
+            return None;
+        }
+    };
+    if at.add(len) > end {
+        *at = end;
+        return None;
+    }
+
+    let mut codepoint = init;
+    for i in 1..len {
+        let byte = *at.add(i);
+        if byte & 0xC0 != 0x80 {
+            *at = at.add(i);
+            return None;
+        }
+        codepoint = (codepoint << 6) | (byte & 0x3F) as u32;
+    }
+    *at = at.add(len);
+    Some(codepoint)
+}
+
+// PSF2: per glyph, UTF-8 strings up to PSF2_SEPARATOR, sequences after
+// PSF2_STARTSEQ
+unsafe fn psf2_parse_unicode(table: *const u8, end: *const u8, count: u32, out: *mut UnicodePair, max: usize) -> usize {
+    let mut at = table;
+    let mut entries = 0usize;
+    let mut glyph = 0u32;
+    let mut inSequence = false;
+    while glyph < count && at < end {
+        match *at {
+            PSF2_SEPARATOR => {
+                at = at.add(1);
+                glyph += 1;
+                inSequence = false;
+            }
+            PSF2_STARTSEQ => {
+                at = at.add(1);
+                inSequence = true;
+            }
+            _ => {
+                let decoded = psf2_decode(&mut at, end);
+                if let (Some(codepoint), false) = (decoded, inSequence) {
+                    if entries < max {
+                        *out.add(entries) = UnicodePair { codepoint, glyph };
+                    }
+                    entries += 1;
+                }
+            }
+        }
+    }
+    entries
+}
+
+type UnicodeParser = unsafe fn(*const u8, *const u8, u32, *mut UnicodePair, usize) -> usize;
+
+// Into the static table for the built-in font, a malloc()ed one otherwise
+unsafe fn psf_unicode_load(parse: UnicodeParser, table: *const u8, end: *const u8, count: u32, builtin: bool) {
+    if builtin {
+        let out = DEFAULT_UNICODE.as_mut_ptr();
+        let len = parse(table, end, count, out, PSF_DEFAULT_UNICODE_MAX);
+        psf_unicode_set(out, len.min(PSF_DEFAULT_UNICODE_MAX), false);
+        return;
+    }
+
+    let len = parse(table, end, count, ptr::null_mut(), 0);
+    let out = if len > 0 {
+        malloc((len * size_of::<UnicodePair>()) as u32) as *mut UnicodePair
+    } else {
+        ptr::null_mut()
+    };
+    if out.is_null() {
+        psf_unicode_set(ptr::null_mut(), 0, false);
+        return;
+    }
+    parse(table, end, count, out, len);
+    psf_unicode_set(out, len, true);
+}
+
 //
 // PSF loading
 //
-pub unsafe fn psf_load(buffer: *mut u8) -> bool {
+
+// Takes over the glyphs as the console font, the caller takes care of the
+// unicode table. They're copied in, unless they're the built-in font's (no
+// heap yet, and it's around for good anyway).
+pub unsafe fn psf_set_glyphs(glyphs: *const u8, count: u32, width: u32, height: u32, charsize: u32, builtin: bool) -> bool {
+    if width == 0 || width > PSF_MAX_WIDTH || height == 0 || height > PSF_MAX_HEIGHT {
+        return false;
+    }
+    if count == 0 || count > PSF_MAX_GLYPHS {
+        return false;
+    }
+
+    let bytesPerRow = (width + 7) / 8;
+    if charsize < bytesPerRow * height {
+        return false;
+    }
+
+    // stored tightly packed, whatever the source's stride
+    let packed = bytesPerRow * height;
+    let stored = if builtin && charsize == packed {
+        glyphs as *mut u8
+    } else {
+        let copy = malloc(count * packed);
+        if copy.is_null() {
+            return false;
+        }
+        for glyph in 0..count {
+            ptr::copy_nonoverlapping(
+                glyphs.add((glyph * charsize) as usize),
+                copy.add((glyph * packed) as usize),
+                packed as usize,
+            );
+        }
+        copy
+    };
+
+    if psfFont.ownsGlyphs && !psfFont.glyphs.is_null() {
+        free(psfFont.glyphs);
+    }
+    psfFont.width = width;
+    psfFont.height = height;
+    psfFont.count = count;
+    psfFont.bytesPerRow = bytesPerRow;
+    psfFont.charsize = packed;
+    psfFont.glyphs = stored;
+    psfFont.ownsGlyphs = stored as *const u8 != glyphs;
+    true
+}
+
+unsafe fn psf1_load(buffer: *mut u8, len: usize, builtin: bool) -> bool {
     let header = buffer as *const PSF1Header;
+    let count: u32 = if (*header).mode & PSF1_MODE512 != 0 { 512 } else { 256 };
+    let height = (*header).height as u32;
+    let glyphs = buffer.add(size_of::<PSF1Header>());
+    let glyphsEnd = size_of::<PSF1Header>() + (count * height) as usize;
+    if glyphsEnd > len {
+        debugf(b"[console] Truncated PSF1 font!\n\0".as_ptr());
+        return false;
+    }
 
-    if (*header).magic != PSF1_MAGIC {
-        debugf(
-            b"[console] Invalid PSF magic! Only PSF1 is supported{0x0436} supplied{%04X}\n\0"
-                .as_ptr(),
-            (*header).magic as u32,
-        );
+    if !psf_set_glyphs(glyphs, count, 8, height, height, builtin) {
         return false;
     }
 
-    if ((*header).mode & PSF1_MODE512 == 0) &&
-       ((*header).mode & PSF1_MODEHASTAB == 0)
-    {
+    if (*header).mode & (PSF1_MODEHASTAB | PSF1_MODESEQ) != 0 {
+        psf_unicode_load(psf1_parse_unicode, buffer.add(glyphsEnd), buffer.add(len), count, builtin);
+    } else {
+        psf_unicode_set(ptr::null_mut(), 0, false);
+    }
+    true
+}
+
+unsafe fn psf2_load(buffer: *mut u8, len: usize, builtin: bool) -> bool {
+    let header = buffer as *const PSF2Header;
+    let count = (*header).length;
+    let charsize = (*header).charsize;
+    let glyphsStart = (*header).headersize as usize;
+    let glyphsEnd = glyphsStart + count as usize * charsize as usize;
+    if glyphsStart < size_of::<PSF2Header>() || glyphsEnd > len {
+        debugf(b"[console] Truncated PSF2 font!\n\0".as_ptr());
+        return false;
+    }
+
+    if !psf_set_glyphs(
+        buffer.add(glyphsStart),
+        count,
+        (*header).width,
+        (*header).height,
+        charsize,
+        builtin,
+    ) {
         debugf(
-            b"[console] Invalid PSF mode! No unicode table found... mode{%02X}\n\0"
-                .as_ptr(),
-            (*header).mode as u32,
+            b"[console] Unsupported PSF2 font: dim(xy){%dx%d} glyphs{%d}\n\0".as_ptr(),
+            (*header).width,
+            (*header).height,
+            count,
         );
         return false;
     }
 
-    psf = buffer as *mut PSF1Font;
+    if (*header).flags & PSF2_HAS_UNICODE_TABLE != 0 {
+        psf_unicode_load(psf2_parse_unicode, buffer.add(glyphsEnd), buffer.add(len), count, builtin);
+    } else {
+        psf_unicode_set(ptr::null_mut(), 0, false);
+    }
+    true
+}
 
-    debugf(
-        b"[console] Initiated with font: dim(xy){%dx%d}\n\0".as_ptr(),
-        8u32,
-        (*header).height as u32,
-    );
+// Unless builtin, nothing points into the buffer afterwards
+pub unsafe fn psf_load(buffer: *mut u8, len: usize, builtin: bool) -> bool {
+    let loaded = if len >= size_of::<PSF2Header>() && ptr::read_unaligned(buffer as *const u32) == PSF2_MAGIC {
+        psf2_load(buffer, len, builtin)
+    } else if len >= size_of::<PSF1Header>() && ptr::read_unaligned(buffer as *const u16) == PSF1_MAGIC {
+        psf1_load(buffer, len, builtin)
+    } else {
+        debugf(
+            b"[console] Invalid PSF magic! Only PSF1 and PSF2 are supported supplied{%08X}\n\0"
+                .as_ptr(),
+            ptr::read_unaligned(buffer as *const u32),
+        );
+        false
+    };
 
-    true
+    if loaded {
+        debugf(
+            b"[console] Initiated with font: dim(xy){%dx%d} glyphs{%d} unicode{%d}\n\0".as_ptr(),
+            psfFont.width,
+            psfFont.height,
+            psfFont.count,
+            psfFont.unicodeLen as u32,
+        );
+    }
+    loaded
 }
 
 pub unsafe fn psf_load_defaults() -> bool {
-    psf_load(u_vga16_psf.as_ptr() as *mut u8)
+    psf_load(u_vga16_psf.as_ptr() as *mut u8, u_vga16_psf_len as usize, true)
 }
 
 pub unsafe fn psf_load_from_file(path: *const u8) -> bool {
@@ -116,10 +461,8 @@ pub unsafe fn psf_load_from_file(path: *const u8) -> bool {
     fsRead(file, out, filesize);
     fsKernelClose(file);
 
-    let res = psf_load(out);
-    if !res {
-        free(out);
-    }
+    let res = psf_load(out, filesize as usize, false);
+    free(out);
 
     res
 }
@@ -127,37 +470,122 @@ pub unsafe fn psf_load_from_file(path: *const u8) -> bool {
 //
 // Character rendering
 //
-pub unsafe fn psf_putc(
-    c: u8,
-    x: u32,
-    y: u32,
-    r: u32,
-    g: u32,
-    b: u32,
-) {
-    let header = &(*psf).header;
-
-    let glyph_base = (psf as usize)
-        + size_of::<PSF1Header>()
-        + (c as usize * header.height as usize);
-
-    let targ = glyph_base as *const u8;
-
-    for i in 0..header.height {
-        let row = *targ.add(i as usize);
-        for j in 0..8 {
-            // NOT little endian (same as C)
-            if row & (1 << (8 - j)) != 0 {
-                drawPixel(x + j as u32, y + i as u32, r, g, b);
+pub unsafe fn psf_put_glyph(glyph: u32, x: u32, y: u32, fg: u32, bg: u32) {
+    let glyph = if glyph < psfFont.count { glyph } else { psfFont.replacement };
+    let targ = psfFont.glyphs.add((glyph * psfFont.charsize) as usize);
+
+    let (fr, fgr, fb) = (fg >> 16 & 0xff, fg >> 8 & 0xff, fg & 0xff);
+    let (br, bgr, bb) = (bg >> 16 & 0xff, bg >> 8 & 0xff, bg & 0xff);
+
+    for i in 0..psfFont.height {
+        let row = targ.add((i * psfFont.bytesPerRow) as usize);
+        for j in 0..psfFont.width {
+            // most significant bit first
+            let byte = *row.add((j / 8) as usize);
+            if byte & (0x80 >> (j % 8)) != 0 {
+                drawPixel(x + j, y + i, fr, fgr, fb);
             } else {
-                drawPixel(
-                    x + j as u32,
-                    y + i as u32,
-                    bg_color[0],
-                    bg_color[1],
-                    bg_color[2],
-                );
+                drawPixel(x + j, y + i, br, bgr, bb);
             }
         }
     }
 }
+
+//
+// C interface
+//
+#[no_mangle]
+pub unsafe extern "C" fn psfLoadDefaults() -> bool {
+    psf_load_defaults()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psfLoadFromFile(path: *const u8) -> bool {
+    psf_load_from_file(path)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psfGlyphWidth() -> u32 {
+    psfFont.width
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psfGlyphHeight() -> u32 {
+    psfFont.height
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn psfGlyphCount() -> u32 {
+    psfFont.count
+}
+
+// Draws a code point, with colors as 0xRRGGBB
+#[no_mangle]
+pub unsafe extern "C" fn psfPutC(codepoint: u32, x: u32, y: u32, fg: u32, bg: u32) {
+    if psfFont.glyphs.is_null() {
+        return;
+    }
+    psf_put_glyph(psf_glyph_index(codepoint), x, y, fg, bg);
+}
+
+// Fonts from userspace (KDFONTOP / PIO_FONT), with charsize bytes per glyph.
+// The unicode table goes back to a direct mapping until PIO_UNIMAP.
+#[no_mangle]
+pub unsafe extern "C" fn psfSetFont(glyphs: *const u8, count: u32, width: u32, height: u32, charsize: u32) -> bool {
+    if !psf_set_glyphs(glyphs, count, width, height, charsize, false) {
+        return false;
+    }
+    psf_unicode_set(ptr::null_mut(), 0, false);
+    true
+}
+
+// Copies glyphs out with charsize bytes each (zero padded), returns how many
+#[no_mangle]
+pub unsafe extern "C" fn psfGetFont(out: *mut u8, max: u32, charsize: u32) -> u32 {
+    let count = psfFont.count.min(max);
+    if charsize < psfFont.charsize {
+        return 0;
+    }
+    for glyph in 0..count {
+        let dst = out.add((glyph * charsize) as usize);
+        ptr::write_bytes(dst, 0, charsize as usize);
+        ptr::copy_nonoverlapping(
+            psfFont.glyphs.add((glyph * psfFont.charsize) as usize),
+            dst,
+            psfFont.charsize as usize,
+        );
+    }
+    count
+}
+
+// PIO_UNIMAPCLR
+#[no_mangle]
+pub unsafe extern "C" fn psfUnicodeClear() {
+    psf_unicode_set(ptr::null_mut(), 0, false);
+}
+
+// PIO_UNIMAP, adds to whatever is already there
+#[no_mangle]
+pub unsafe extern "C" fn psfUnicodeAdd(pairs: *const UnicodePair, len: usize) -> bool {
+    let total = psfFont.unicodeLen + len;
+    let merged = malloc((total * size_of::<UnicodePair>()) as u32) as *mut UnicodePair;
+    if merged.is_null() {
+        return false;
+    }
+    if !psfFont.unicode.is_null() {
+        ptr::copy_nonoverlapping(psfFont.unicode, merged, psfFont.unicodeLen);
+    }
+    ptr::copy_nonoverlapping(pairs, merged.add(psfFont.unicodeLen), len);
+    psf_unicode_set(merged, total, true);
+    true
+}
+
+// GIO_UNIMAP, entries from onwards. Returns the full length either way.
+#[no_mangle]
+pub unsafe extern "C" fn psfUnicodeGet(out: *mut UnicodePair, from: usize, max: usize) -> usize {
+    if from < psfFont.unicodeLen {
+        let len = (psfFont.unicodeLen - from).min(max);
+        ptr::copy_nonoverlapping(psfFont.unicode.add(from), out, len);
+    }
+    psfFont.unicodeLen
+}
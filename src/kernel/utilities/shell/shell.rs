@@ -58,6 +58,7 @@ extern "C" {
     ) -> *mut Task;
 
     fn taskCreateFinish(task: *mut Task);
+    fn consoleTtyAttach(task: *mut Task);
     fn handControl();
 
     // fs
@@ -272,6 +273,9 @@ pub extern "C" fn run(
         (*fsUserGetNode(task, stdout)).id = 1;
         (*fsUserGetNode(task, stderr)).id = 2;
 
+        // so shells get job control on the console
+        consoleTtyAttach(task);
+
         taskCreateFinish(task);
 
         if wait {